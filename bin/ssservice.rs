@@ -10,7 +10,7 @@
 use std::{env, path::Path, process::ExitCode};
 
 use clap::Command;
-use shadowsocks_rust::service::{genkey, local, manager, server};
+use shadowsocks_rust::service::{bench, check, genkey, local, manager, server};
 
 fn main() -> ExitCode {
     let app = Command::new("shadowsocks")
@@ -40,6 +40,14 @@ fn main() -> ExitCode {
             genkey::define_command_line_options(Command::new("genkey"))
                 .about("Generate shadowsocks encryption key for method"),
         )
+        .subcommand(
+            bench::define_command_line_options(Command::new("bench"))
+                .about("Measure throughput and latency of a server started with --bench-responder"),
+        )
+        .subcommand(
+            check::define_command_line_options(Command::new("check"))
+                .about("Check connectivity of servers step by step, reporting latency and failures of each step"),
+        )
         .get_matches();
 
     match matches.subcommand() {
@@ -47,6 +55,8 @@ fn main() -> ExitCode {
         Some(("server", matches)) => server::main(matches),
         Some(("manager", matches)) => manager::main(matches),
         Some(("genkey", matches)) => genkey::main(matches),
+        Some(("bench", matches)) => bench::main(matches),
+        Some(("check", matches)) => check::main(matches),
         _ => unreachable!("expecting a subcommand"),
     }
 }
@@ -3,65 +3,81 @@
 //! SS-URI = "ss://" userinfo "@" hostname ":" port [ "/" ] [ "?" plugin ] [ "#" tag ]
 //! userinfo = websafe-base64-encode-utf8(method  ":" password)
 
-use std::process::ExitCode;
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
 use clap::{Arg, ArgAction, Command, ValueHint};
-use qrcode::{QrCode, types::Color};
 
 use shadowsocks_service::{
     config::{Config, ConfigType, ServerInstanceConfig},
+    import::{merge_servers, parse_server_urls},
+    qrcode::ServerQrCode,
     shadowsocks::config::ServerConfig,
 };
 
 /// shadowsocks version
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-const BLACK: &str = "\x1b[40m  \x1b[0m";
-const WHITE: &str = "\x1b[47m  \x1b[0m";
-
-fn print_qrcode(encoded: &str) {
-    let qrcode = QrCode::new(encoded.as_bytes()).unwrap();
+/// Where QR codes are rendered
+struct QrCodeOutput {
+    terminal: bool,
+    png_path: Option<PathBuf>,
+    png_scale: usize,
+}
 
-    for _ in 0..qrcode.width() + 2 {
-        print!("{WHITE}");
+impl QrCodeOutput {
+    fn is_enabled(&self) -> bool {
+        self.terminal || self.png_path.is_some()
     }
-    println!();
-
-    for y in 0..qrcode.width() {
-        print!("{WHITE}");
-        for x in 0..qrcode.width() {
-            let color = match qrcode[(x, y)] {
-                Color::Light => WHITE,
-                Color::Dark => BLACK,
-            };
 
-            print!("{color}");
+    /// Render QR code of `encoded`, PNG images of multiple URLs are suffixed with `index`
+    fn render(&self, encoded: &str, index: Option<usize>) {
+        let qrcode = ServerQrCode::from_url(encoded).unwrap();
+
+        if self.terminal {
+            print!("{}", qrcode.to_ansi());
         }
-        println!("{WHITE}");
-    }
 
-    for _ in 0..qrcode.width() + 2 {
-        print!("{WHITE}");
+        if let Some(ref path) = self.png_path {
+            let path = match index {
+                None => path.clone(),
+                Some(index) => indexed_path(path, index),
+            };
+            qrcode.write_png(&path, self.png_scale).unwrap();
+            eprintln!("QR code saved to {}", path.display());
+        }
     }
-    println!();
 }
 
-fn encode(filename: &str, need_qrcode: bool) {
+fn indexed_path(path: &Path, index: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, index, ext.to_string_lossy()),
+        None => format!("{stem}-{index}"),
+    };
+    path.with_file_name(file_name)
+}
+
+fn encode(filename: &str, qrcode: &QrCodeOutput) {
     let config = Config::load_from_file(filename, ConfigType::Server).unwrap();
+    let multiple = config.server.len() > 1;
 
-    for svr in config.server {
+    for (index, svr) in config.server.iter().enumerate() {
         let encoded = svr.config.to_url();
 
         println!("{encoded}");
 
-        if need_qrcode {
-            let encoded = svr.config.to_qrcode_url();
-            print_qrcode(&encoded);
+        if qrcode.is_enabled() {
+            qrcode.render(&encoded, if multiple { Some(index) } else { None });
         }
     }
 }
 
-fn decode(encoded: &str, need_qrcode: bool) {
+fn decode(encoded: &str, qrcode: &QrCodeOutput) {
     let svrconfig = ServerConfig::from_url(encoded).unwrap();
 
     let mut config = Config::new(ConfigType::Server);
@@ -69,13 +85,64 @@ fn decode(encoded: &str, need_qrcode: bool) {
 
     println!("{config}");
 
-    if need_qrcode {
-        print_qrcode(encoded);
+    if qrcode.is_enabled() {
+        qrcode.render(encoded, None);
+    }
+}
+
+fn import(source: &str, merge_path: Option<&Path>, output_path: Option<&Path>) -> ExitCode {
+    let text = if source == "-" {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text).map(|_| text)
+    } else {
+        fs::read_to_string(source)
+    };
+    let text = match text {
+        Ok(t) => t,
+        Err(err) => {
+            eprintln!("failed to read {source}, {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let imported = parse_server_urls(&text);
+    for (line, err) in &imported.skipped {
+        eprintln!("skipped {line}, {err}");
+    }
+    if imported.servers.is_empty() {
+        eprintln!("no servers found in {source}");
+        return ExitCode::FAILURE;
     }
+
+    let mut config = match merge_path {
+        Some(path) => match Config::load_from_file(path, ConfigType::Local) {
+            Ok(c) => c,
+            Err(err) => {
+                eprintln!("failed to load {}, {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Config::new(ConfigType::Local),
+    };
+
+    let merged = merge_servers(&mut config, imported.servers);
+    eprintln!("{} servers added, {} servers updated", merged.added, merged.updated);
+
+    match output_path {
+        Some(path) => {
+            if let Err(err) = fs::write(path, format!("{config}\n")) {
+                eprintln!("failed to write {}, {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        }
+        None => println!("{config}"),
+    }
+
+    ExitCode::SUCCESS
 }
 
 #[cfg(feature = "utility-url-outline")]
-fn decode_outline(remote: &str, need_qrcode: bool) {
+fn decode_outline(remote: &str, qrcode: &QrCodeOutput) {
     // Protect from using http and other non-ssconf links in reqwest call
     if !remote.starts_with("ssconf") {
         println!("Incorrect link format");
@@ -90,8 +157,8 @@ fn decode_outline(remote: &str, need_qrcode: bool) {
 
     println!("{config}");
 
-    if need_qrcode {
-        print_qrcode(remote);
+    if qrcode.is_enabled() {
+        qrcode.render(remote, None);
     }
 }
 
@@ -106,7 +173,7 @@ fn main() -> ExitCode {
                 .action(ArgAction::Set)
                 .value_hint(ValueHint::FilePath)
                 .conflicts_with("DECODE_CONFIG_PATH")
-                .required_unless_present_any(["DECODE_CONFIG_PATH", "OUTLINE_CONFIG_URL"])
+                .required_unless_present_any(["DECODE_CONFIG_PATH", "OUTLINE_CONFIG_URL", "IMPORT_SOURCE"])
                 .help("Encode the server configuration in the provided JSON file"),
         )
         .arg(
@@ -115,15 +182,60 @@ fn main() -> ExitCode {
                 .long("decode")
                 .action(ArgAction::Set)
                 .value_hint(ValueHint::FilePath)
-                .required_unless_present_any(["ENCODE_CONFIG_PATH", "OUTLINE_CONFIG_URL"])
+                .required_unless_present_any(["ENCODE_CONFIG_PATH", "OUTLINE_CONFIG_URL", "IMPORT_SOURCE"])
                 .help("Decode the server configuration from the provided ShadowSocks URL"),
         )
+        .arg(
+            Arg::new("IMPORT_SOURCE")
+                .short('i')
+                .long("import")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::FilePath)
+                .conflicts_with_all(["ENCODE_CONFIG_PATH", "DECODE_CONFIG_PATH"])
+                .required_unless_present_any(["ENCODE_CONFIG_PATH", "DECODE_CONFIG_PATH", "OUTLINE_CONFIG_URL"])
+                .help("Import servers from a file (\"-\" for stdin) of ShadowSocks URLs, or of a base64 encoded subscription"),
+        )
+        .arg(
+            Arg::new("MERGE_CONFIG_PATH")
+                .long("merge")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::FilePath)
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("IMPORT_SOURCE")
+                .help("Merge imported servers into the configuration file, servers with the same address are replaced"),
+        )
+        .arg(
+            Arg::new("OUTPUT_PATH")
+                .long("output")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::FilePath)
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("IMPORT_SOURCE")
+                .help("Write the configuration with imported servers to the file, instead of stdout"),
+        )
         .arg(
             Arg::new("QRCODE")
                 .short('c')
                 .long("qrcode")
                 .action(ArgAction::SetTrue)
                 .help("Generate the QRCode with the provided configuration"),
+        )
+        .arg(
+            Arg::new("QRCODE_PNG_PATH")
+                .long("qrcode-png")
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::FilePath)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Save the QRCode as a PNG image, suffixed with indexes if there are multiple servers"),
+        )
+        .arg(
+            Arg::new("QRCODE_PNG_SCALE")
+                .long("qrcode-png-scale")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u8).range(1..))
+                .default_value("8")
+                .requires("QRCODE_PNG_PATH")
+                .help("Pixels of each module in the PNG image"),
         );
 
     if cfg!(feature = "utility-url-outline") {
@@ -132,28 +244,40 @@ fn main() -> ExitCode {
                 .short('o')
                 .long("outline")
                 .value_hint(ValueHint::Url)
-                .required_unless_present_any(["ENCODE_CONFIG_PATH", "DECODE_CONFIG_PATH"])
+                .required_unless_present_any(["ENCODE_CONFIG_PATH", "DECODE_CONFIG_PATH", "IMPORT_SOURCE"])
                 .help("Fetch and decode config from ssconf URL used by Outline"),
         );
     }
 
     let matches = app.get_matches();
 
-    let need_qrcode = matches.get_flag("QRCODE");
+    let qrcode = QrCodeOutput {
+        terminal: matches.get_flag("QRCODE"),
+        png_path: matches.get_one::<PathBuf>("QRCODE_PNG_PATH").cloned(),
+        png_scale: *matches.get_one::<u8>("QRCODE_PNG_SCALE").expect("qrcode-png-scale") as usize,
+    };
 
     if let Some(file) = matches.get_one::<String>("ENCODE_CONFIG_PATH") {
-        encode(file, need_qrcode);
+        encode(file, &qrcode);
         return ExitCode::SUCCESS;
     }
 
     if let Some(encoded) = matches.get_one::<String>("DECODE_CONFIG_PATH") {
-        decode(encoded, need_qrcode);
+        decode(encoded, &qrcode);
         return ExitCode::SUCCESS;
     }
 
+    if let Some(source) = matches.get_one::<String>("IMPORT_SOURCE") {
+        return import(
+            source,
+            matches.get_one::<PathBuf>("MERGE_CONFIG_PATH").map(PathBuf::as_path),
+            matches.get_one::<PathBuf>("OUTPUT_PATH").map(PathBuf::as_path),
+        );
+    }
+
     #[cfg(feature = "utility-url-outline")]
     if let Some(remote) = matches.get_one::<String>("OUTLINE_CONFIG_URL") {
-        decode_outline(remote, need_qrcode);
+        decode_outline(remote, &qrcode);
         return ExitCode::SUCCESS;
     }
 
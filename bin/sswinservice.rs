@@ -1,11 +1,13 @@
 use std::{
+    env,
     ffi::OsString,
     future::Future,
+    process::ExitCode,
     sync::atomic::{AtomicU32, Ordering},
     time::Duration,
 };
 
-use clap::Command;
+use clap::{Arg, ArgAction, ArgMatches, Command};
 use log::{error, info};
 use shadowsocks_rust::{
     error::ShadowsocksResult,
@@ -14,12 +16,20 @@ use shadowsocks_rust::{
 use tokio::{runtime::Runtime, sync::oneshot};
 use windows_service::{
     define_windows_service,
-    service::{ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType},
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode, ServiceInfo,
+        ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
     service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
     service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
 };
 
 const SERVICE_NAME: &str = "ssservice";
+const SERVICE_DISPLAY_NAME: &str = "Shadowsocks Service";
+const SERVICE_DESCRIPTION: &str = "A fast tunnel proxy that helps you bypass firewalls. (https://shadowsocks.org)";
+// Time for in-flight connections to finish after being stopped
+const SERVICE_STOP_TIMEOUT: Duration = Duration::from_secs(5);
 const SERVICE_EXIT_CODE_ARGUMENT_ERROR: u32 = 100;
 const SERVICE_EXIT_CODE_EXITED_UNEXPECTLY: u32 = 101;
 const SERVICE_EXIT_CODE_CREATE_FAILED: u32 = 102;
@@ -36,14 +46,15 @@ fn set_service_status(
     let next_status = ServiceStatus {
         service_type: ServiceType::OWN_PROCESS,
         current_state,
-        controls_accepted: if current_state == ServiceState::StartPending {
+        controls_accepted: if matches!(current_state, ServiceState::StartPending | ServiceState::StopPending) {
             ServiceControlAccept::empty()
         } else {
-            ServiceControlAccept::STOP
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
         },
         exit_code,
-        checkpoint: if matches!(current_state, ServiceState::Running | ServiceState::Stopped) {
-            SERVICE_STATE_CHECKPOINT.fetch_add(1, Ordering::AcqRel)
+        // Checkpoint is only meaningful for pending states, and must be increased during a pending operation
+        checkpoint: if matches!(current_state, ServiceState::StartPending | ServiceState::StopPending) {
+            SERVICE_STATE_CHECKPOINT.fetch_add(1, Ordering::AcqRel) + 1
         } else {
             0
         },
@@ -91,6 +102,17 @@ where
                 }
             });
 
+            // Give connections a chance to finish before being killed
+            set_service_status(
+                &status_handle,
+                ServiceState::StopPending,
+                ServiceExitCode::Win32(0),
+                SERVICE_STOP_TIMEOUT * 2,
+            )?;
+            runtime.shutdown_timeout(SERVICE_STOP_TIMEOUT);
+            #[cfg(feature = "logging")]
+            shadowsocks_rust::logging::shutdown();
+
             // Report stopped state
             set_service_status(
                 &status_handle,
@@ -126,7 +148,8 @@ fn service_main(arguments: Vec<OsString>) -> Result<(), windows_service::Error>
     let mut stop_sender_opt = Some(stop_sender);
     let event_handler = move |control_event| -> ServiceControlHandlerResult {
         match control_event {
-            ServiceControl::Stop => {
+            // System shutdown is handled as Stop, there are about 20 seconds before being killed
+            ServiceControl::Stop | ServiceControl::Shutdown => {
                 if let Some(stop_sender) = stop_sender_opt.take() {
                     let _ = stop_sender.send(());
                 }
@@ -201,7 +224,134 @@ fn service_entry(arguments: Vec<OsString>) {
 
 define_windows_service!(ffi_service_entry, service_entry);
 
-fn main() -> Result<(), windows_service::Error> {
-    service_dispatcher::start(SERVICE_NAME, ffi_service_entry)?;
+fn install_service(matches: &ArgMatches) -> Result<(), windows_service::Error> {
+    let name = matches.get_one::<String>("SERVICE_NAME").expect("service name");
+    let display_name = matches.get_one::<String>("DISPLAY_NAME").expect("display name");
+
+    let mut launch_arguments: Vec<OsString> = matches
+        .get_many::<OsString>("ARGS")
+        .expect("service arguments")
+        .cloned()
+        .collect();
+    // There is no console for services, logs are reported to Event Log unless other outputs are set
+    if !launch_arguments
+        .iter()
+        .any(|arg| arg == "--log-event-log" || arg == "--log-syslog" || arg == "--log-file" || arg == "--log-config")
+    {
+        launch_arguments.push("--log-event-log".into());
+        launch_arguments.push(name.into());
+    }
+
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE)?;
+    let info = ServiceInfo {
+        name: name.into(),
+        display_name: display_name.into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: if matches.get_flag("MANUAL_START") {
+            ServiceStartType::OnDemand
+        } else {
+            ServiceStartType::AutoStart
+        },
+        error_control: ServiceErrorControl::Normal,
+        executable_path: env::current_exe().map_err(windows_service::Error::Winapi)?,
+        launch_arguments,
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)?;
+    service.set_description(SERVICE_DESCRIPTION)?;
+
+    println!("service {name} is installed");
     Ok(())
 }
+
+fn uninstall_service(matches: &ArgMatches) -> Result<(), windows_service::Error> {
+    let name = matches.get_one::<String>("SERVICE_NAME").expect("service name");
+
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(name, ServiceAccess::DELETE)?;
+    // Service is removed after it is stopped and all handles are closed
+    service.delete()?;
+
+    println!("service {name} is marked for deletion");
+    Ok(())
+}
+
+fn service_name_arg() -> Arg {
+    Arg::new("SERVICE_NAME")
+        .long("service-name")
+        .num_args(1)
+        .action(ArgAction::Set)
+        .default_value(SERVICE_NAME)
+        .help("Name of the service")
+}
+
+fn main() -> ExitCode {
+    // Started by the Service Control Manager, unless it is called with `install` or `uninstall` from a console
+    let is_console = env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "install" || arg == "uninstall");
+    if !is_console {
+        return match service_dispatcher::start(SERVICE_NAME, ffi_service_entry) {
+            Ok(..) => ExitCode::SUCCESS,
+            Err(err) => {
+                eprintln!("failed to start service dispatcher, error: {err}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let app = Command::new("shadowsocks service")
+        .version(shadowsocks_rust::VERSION)
+        .about("Install or uninstall shadowsocks as a Windows service")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("install")
+                .about("Install the service, run as Administrator")
+                .arg(service_name_arg())
+                .arg(
+                    Arg::new("DISPLAY_NAME")
+                        .long("display-name")
+                        .num_args(1)
+                        .action(ArgAction::Set)
+                        .default_value(SERVICE_DISPLAY_NAME)
+                        .help("Display name of the service"),
+                )
+                .arg(
+                    Arg::new("MANUAL_START")
+                        .long("manual-start")
+                        .action(ArgAction::SetTrue)
+                        .help("Start the service on demand, instead of automatically at boot"),
+                )
+                .arg(
+                    Arg::new("ARGS")
+                        .num_args(1..)
+                        .required(true)
+                        .trailing_var_arg(true)
+                        .allow_hyphen_values(true)
+                        .value_parser(clap::value_parser!(OsString))
+                        .help("Arguments of the service, like: server -c C:\\ssserver.json"),
+                ),
+        )
+        .subcommand(
+            Command::new("uninstall")
+                .about("Uninstall the service, run as Administrator")
+                .arg(service_name_arg()),
+        );
+
+    let matches = app.get_matches();
+    let result = match matches.subcommand() {
+        Some(("install", matches)) => install_service(matches),
+        Some(("uninstall", matches)) => uninstall_service(matches),
+        _ => unreachable!("subcommand is required"),
+    };
+
+    match result {
+        Ok(..) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
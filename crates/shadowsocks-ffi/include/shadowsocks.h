@@ -0,0 +1,112 @@
+/*
+ * C bindings of shadowsocks, built from the `shadowsocks-ffi` crate.
+ *
+ * Functions returning NULL or -1 store the message of the error, which could
+ * be fetched by ss_last_error() on the same thread. Panics are caught, and
+ * reported as errors the same way.
+ */
+
+#ifndef SHADOWSOCKS_H
+#define SHADOWSOCKS_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Config type of local servers, `sslocal` */
+#define SS_CONFIG_TYPE_LOCAL 0
+/* Config type of remote servers, `ssserver` */
+#define SS_CONFIG_TYPE_SERVER 1
+
+/* Log levels passed to ss_log_callback */
+#define SS_LOG_ERROR 1
+#define SS_LOG_WARN 2
+#define SS_LOG_INFO 3
+#define SS_LOG_DEBUG 4
+#define SS_LOG_TRACE 5
+
+/* A service running in background */
+typedef struct SsService SsService;
+
+/*
+ * Protects an outbound socket `fd` from VPN routing, returns 0 if succeeded.
+ * Only supported on Linux and Android. Called from threads of the service.
+ */
+typedef int (*ss_protect_callback)(void *ctx, int fd);
+
+/*
+ * Receives a log line, which is only valid during the call.
+ * Called from threads of services.
+ */
+typedef void (*ss_log_callback)(void *ctx, int level, const char *line);
+
+/*
+ * Starts a service with the JSON `config` of `config_type`, returns NULL if
+ * failed. `protect` is optional. The returned service must be released by
+ * ss_service_stop().
+ */
+SsService *ss_service_start(const char *config, int config_type, ss_protect_callback protect, void *protect_ctx);
+
+/*
+ * Restarts `service` with the JSON `config`, keeping its config type and
+ * protect callback. Returns 0 if succeeded, -1 if failed.
+ */
+int ss_service_reload(SsService *service, const char *config);
+
+/*
+ * Stops and releases `service`. Returns 0 if it exited successfully, -1 if it
+ * has exited with an error.
+ */
+int ss_service_stop(SsService *service);
+
+/* Returns 1 if `service` is still running, 0 if exited */
+int ss_service_is_running(const SsService *service);
+
+/*
+ * Statistics of all services running in this process in JSON, returns NULL if
+ * failed. The returned string must be released by ss_string_free().
+ */
+char *ss_stats_json(void);
+
+/* Releases a string returned by this library */
+void ss_string_free(char *s);
+
+/*
+ * Message of the last error occurred on the calling thread, NULL if there are
+ * no errors. Valid until the next call of this library on the same thread.
+ */
+const char *ss_last_error(void);
+
+/*
+ * Installs `callback` for receiving log lines of `max_level` and more severe
+ * ones. Logging is turned off with `max_level <= 0` or a NULL callback.
+ * Returns -1 if another logger was installed in this process.
+ */
+int ss_set_log_callback(ss_log_callback callback, void *ctx, int max_level);
+
+/*
+ * Bytes of memory allocated by this library, returns -1 if it is built without
+ * feature `memory-accounting`.
+ */
+int64_t ss_memory_allocated(void);
+
+/*
+ * Sets the soft limit of memory allocated by this library in bytes, 0 for
+ * unlimited. Local servers refuse new TCP connections while the usage is over
+ * the limit. Returns -1 if it is built without feature `memory-accounting`.
+ */
+int ss_set_memory_limit(uint64_t limit);
+
+/*
+ * Releases memory held by caches of all services, like DNS caches. Could be
+ * called when the system reports memory pressure.
+ */
+void ss_trim_memory(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SHADOWSOCKS_H */
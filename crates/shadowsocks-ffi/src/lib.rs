@@ -0,0 +1,467 @@
+//! C bindings of shadowsocks
+//!
+//! This crate builds a dynamic (`cdylib`) and a static (`staticlib`) library, exporting functions declared in
+//! `include/shadowsocks.h`, for embedding local and remote servers in applications written in other languages, like
+//! iOS `NetworkExtension`s and Android NDK libraries.
+//!
+//! Every service runs on its own tokio runtime. Functions of a service block the calling thread until the operation
+//! finishes, so they must not be called from callbacks, which are running on threads of the runtime.
+//!
+//! Functions returning an error (`NULL` or `-1`) store the message of it, which could be fetched by
+//! [`ss_last_error`] on the same thread. Panics are caught, and reported as errors the same way.
+//!
+//! Processes with hard memory limits, like iOS packet tunnel extensions, should set `"memory_profile": "constrained"`
+//! in configs, and enable feature `memory-accounting` for [`ss_set_memory_limit`].
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, c_char, c_int, c_void},
+    io::{self, ErrorKind},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+
+use shadowsocks_service::{
+    ServiceHandle,
+    config::{Config, ConfigType, MemoryProfile},
+};
+use tokio::runtime::{Builder, Runtime};
+
+pub use self::logger::LogCallback;
+
+#[cfg(feature = "memory-accounting")]
+mod allocator;
+mod logger;
+
+#[cfg(feature = "memory-accounting")]
+#[global_allocator]
+static GLOBAL: allocator::CountingAllocator = allocator::CountingAllocator;
+
+/// Config type of local servers, `sslocal`
+pub const SS_CONFIG_TYPE_LOCAL: c_int = 0;
+/// Config type of remote servers, `ssserver`
+pub const SS_CONFIG_TYPE_SERVER: c_int = 1;
+
+/// Worker threads of runtimes of services with `"memory_profile": "constrained"`
+const CONSTRAINED_WORKER_THREADS: usize = 2;
+
+/// Callback protecting outbound sockets from VPN routing, returns 0 if succeeded
+///
+/// It is called with every outbound socket before connecting, except the ones connecting to loopback addresses. Only
+/// supported on Linux and Android, sockets created by `NEPacketTunnelProvider` are already excluded from the tunnel on
+/// iOS and macOS.
+pub type ProtectCallback = extern "C" fn(ctx: *mut c_void, fd: c_int) -> c_int;
+
+#[derive(Clone, Copy)]
+struct ProtectHook {
+    callback: ProtectCallback,
+    ctx: *mut c_void,
+}
+
+// Applications are required to make their callbacks thread-safe
+unsafe impl Send for ProtectHook {}
+unsafe impl Sync for ProtectHook {}
+
+/// A service running in background, created by [`ss_service_start`]
+pub struct SsService {
+    runtime: Runtime,
+    handle: ServiceHandle,
+    config_type: ConfigType,
+    protect: Option<ProtectHook>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error<S: Into<String>>(err: S) {
+    let err = err.into().replace('\0', "\\0");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(err).ok());
+}
+
+/// Run body `f` of an exported function, returns `on_panic` if it panicked
+///
+/// Unwinding into the caller's frames is undefined behavior, panics are reported as errors instead. Builds with
+/// `panic = "abort"`, like the workspace's release profile, abort the process before reaching here.
+fn ffi_guard<R, F>(on_panic: R, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(r) => r,
+        Err(payload) => {
+            let msg = match payload.downcast_ref::<&str>() {
+                Some(msg) => *msg,
+                None => match payload.downcast_ref::<String>() {
+                    Some(msg) => msg.as_str(),
+                    None => "unknown error",
+                },
+            };
+            set_last_error(format!("panicked, {msg}"));
+            on_panic
+        }
+    }
+}
+
+unsafe fn str_from_c<'a>(s: *const c_char, name: &str) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(format!("{name} is NULL"));
+    }
+    unsafe { CStr::from_ptr(s) }
+        .to_str()
+        .map_err(|err| format!("{name} is not valid UTF-8, {err}"))
+}
+
+fn load_config(config: &str, config_type: ConfigType, protect: Option<ProtectHook>) -> Result<Config, String> {
+    let mut config = Config::load_from_str(config, config_type).map_err(|err| format!("invalid config, {err}"))?;
+    config
+        .check_integrity()
+        .map_err(|err| format!("invalid config, {err}"))?;
+
+    if let Some(protect) = protect {
+        cfg_protect(&mut config, protect)?;
+    }
+
+    Ok(config)
+}
+
+#[cfg(all(any(target_os = "linux", target_os = "android"), feature = "socket-protect"))]
+fn cfg_protect(config: &mut Config, protect: ProtectHook) -> Result<(), String> {
+    use shadowsocks_service::shadowsocks::net::SocketProtect;
+
+    config.outbound_socket_protect = Some(SocketProtect::callback(move |fd| {
+        // Captures the whole hook, raw pointers are not `Send`
+        let protect = protect;
+        match (protect.callback)(protect.ctx, fd) {
+            0 => Ok(()),
+            code => Err(io::Error::new(
+                ErrorKind::Other,
+                format!("protect callback failed with {code}"),
+            )),
+        }
+    }));
+    Ok(())
+}
+
+#[cfg(not(all(any(target_os = "linux", target_os = "android"), feature = "socket-protect")))]
+fn cfg_protect(_config: &mut Config, _protect: ProtectHook) -> Result<(), String> {
+    Err("protect callback is not supported on this platform".to_owned())
+}
+
+fn start(config: &str, config_type: c_int, protect: Option<ProtectHook>) -> Result<SsService, String> {
+    let config_type = match config_type {
+        SS_CONFIG_TYPE_LOCAL => ConfigType::Local,
+        SS_CONFIG_TYPE_SERVER => ConfigType::Server,
+        t => return Err(format!("unsupported config type {t}")),
+    };
+    let config = load_config(config, config_type, protect)?;
+
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all().thread_name("shadowsocks-worker");
+    if config.memory_profile == MemoryProfile::Constrained {
+        // Each worker has its own stack and buffers, one per CPU core by default
+        builder.worker_threads(CONSTRAINED_WORKER_THREADS);
+    }
+    let runtime = builder
+        .build()
+        .map_err(|err| format!("failed to create runtime, {err}"))?;
+
+    let handle = runtime
+        .block_on(async move {
+            match config_type {
+                #[cfg(feature = "local")]
+                ConfigType::Local => shadowsocks_service::start_local(config).await,
+                #[cfg(feature = "server")]
+                ConfigType::Server => shadowsocks_service::start_server(config).await,
+                _ => Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!("{config_type:?} service is not enabled in this library"),
+                )),
+            }
+        })
+        .map_err(|err| format!("failed to start service, {err}"))?;
+
+    Ok(SsService {
+        runtime,
+        handle,
+        config_type,
+        protect,
+    })
+}
+
+/// Start a service with the JSON `config` of `config_type`, returns `NULL` if failed
+///
+/// `protect` is optional (`NULL`), it is called with `protect_ctx` from threads of the service. The returned service
+/// must be released by [`ss_service_stop`].
+///
+/// # Safety
+///
+/// `config` must be a NUL-terminated string, `protect` and `protect_ctx` must be safe to be called from other threads
+/// until the service is stopped.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ss_service_start(
+    config: *const c_char,
+    config_type: c_int,
+    protect: Option<ProtectCallback>,
+    protect_ctx: *mut c_void,
+) -> *mut SsService {
+    ffi_guard(ptr::null_mut(), || {
+        let config = match unsafe { str_from_c(config, "config") } {
+            Ok(c) => c,
+            Err(err) => {
+                set_last_error(err);
+                return ptr::null_mut();
+            }
+        };
+        let protect = protect.map(|callback| ProtectHook {
+            callback,
+            ctx: protect_ctx,
+        });
+
+        match start(config, config_type, protect) {
+            Ok(service) => Box::into_raw(Box::new(service)),
+            Err(err) => {
+                set_last_error(err);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Restart `service` with the JSON `config`, returns 0 if succeeded, -1 if failed
+///
+/// The config type and the protect callback are kept. `service` is left stopped if the new config couldn't be started,
+/// but it still has to be released by [`ss_service_stop`].
+///
+/// # Safety
+///
+/// `service` must be returned by [`ss_service_start`] and not stopped, `config` must be a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ss_service_reload(service: *mut SsService, config: *const c_char) -> c_int {
+    ffi_guard(-1, || {
+        let service = match unsafe { service.as_mut() } {
+            Some(s) => s,
+            None => {
+                set_last_error("service is NULL");
+                return -1;
+            }
+        };
+
+        let result = unsafe { str_from_c(config, "config") }
+            .and_then(|config| load_config(config, service.config_type, service.protect))
+            .and_then(|config| {
+                service
+                    .runtime
+                    .block_on(service.handle.reload(config))
+                    .map_err(|err| format!("failed to reload service, {err}"))
+            });
+
+        match result {
+            Ok(()) => 0,
+            Err(err) => {
+                set_last_error(err);
+                -1
+            }
+        }
+    })
+}
+
+/// Stop and release `service`, returns 0 if it exited successfully, -1 if it has exited with an error
+///
+/// # Safety
+///
+/// `service` must be returned by [`ss_service_start`], it couldn't be used after this call.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ss_service_stop(service: *mut SsService) -> c_int {
+    ffi_guard(-1, || {
+        if service.is_null() {
+            return 0;
+        }
+
+        let mut service = unsafe { Box::from_raw(service) };
+        let result = service.runtime.block_on(service.handle.shutdown());
+        drop(service);
+
+        match result {
+            Ok(()) => 0,
+            Err(err) => {
+                set_last_error(format!("service exited with error, {err}"));
+                -1
+            }
+        }
+    })
+}
+
+/// Check if `service` is still running, returns 1 if running, 0 if exited
+///
+/// # Safety
+///
+/// `service` must be returned by [`ss_service_start`] and not stopped.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ss_service_is_running(service: *const SsService) -> c_int {
+    ffi_guard(0, || match unsafe { service.as_ref() } {
+        Some(service) => c_int::from(!service.handle.is_finished()),
+        None => 0,
+    })
+}
+
+/// Statistics of all services running in this process in JSON, returns `NULL` if failed
+///
+/// The returned string must be released by [`ss_string_free`].
+#[unsafe(no_mangle)]
+pub extern "C" fn ss_stats_json() -> *mut c_char {
+    ffi_guard(ptr::null_mut(), || {
+        let snapshot = shadowsocks_service::stats::snapshot();
+        match serde_json::to_string(&snapshot) {
+            Ok(json) => CString::new(json).map_or(ptr::null_mut(), CString::into_raw),
+            Err(err) => {
+                set_last_error(format!("failed to serialize statistics, {err}"));
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Release a string returned by this library
+///
+/// # Safety
+///
+/// `s` must be returned by functions of this library, like [`ss_stats_json`], and released only once.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ss_string_free(s: *mut c_char) {
+    ffi_guard((), || {
+        if !s.is_null() {
+            drop(unsafe { CString::from_raw(s) });
+        }
+    })
+}
+
+/// Message of the last error occurred on the calling thread, `NULL` if there are no errors
+///
+/// The returned string is valid until the next call of this library on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn ss_last_error() -> *const c_char {
+    ffi_guard(ptr::null(), || {
+        LAST_ERROR.with(|last| match *last.borrow() {
+            Some(ref err) => err.as_ptr(),
+            None => ptr::null(),
+        })
+    })
+}
+
+/// Install `callback` for receiving log lines of `max_level` and more severe ones, returns 0 if succeeded, -1 if failed
+///
+/// Levels are 1 (error), 2 (warn), 3 (info), 4 (debug) and 5 (trace), logging is turned off with `max_level <= 0` or a
+/// `NULL` callback. It fails if another logger was installed in this process. The callback is called with `ctx` from
+/// threads of services, and the line is only valid during the call.
+///
+/// # Safety
+///
+/// `callback` and `ctx` must be safe to be called from other threads until another callback is installed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ss_set_log_callback(
+    callback: Option<LogCallback>,
+    ctx: *mut c_void,
+    max_level: c_int,
+) -> c_int {
+    ffi_guard(-1, || {
+        if logger::set_log_callback(callback, ctx, max_level) {
+            0
+        } else {
+            set_last_error("another logger has been installed in this process");
+            -1
+        }
+    })
+}
+
+/// Bytes of memory allocated by this library, returns -1 if feature `memory-accounting` is not enabled
+#[unsafe(no_mangle)]
+pub extern "C" fn ss_memory_allocated() -> i64 {
+    ffi_guard(-1, || {
+        #[cfg(feature = "memory-accounting")]
+        {
+            i64::try_from(allocator::allocated()).unwrap_or(i64::MAX)
+        }
+        #[cfg(not(feature = "memory-accounting"))]
+        {
+            -1
+        }
+    })
+}
+
+/// Set the soft limit of memory allocated by this library in bytes, 0 for unlimited, returns 0 if succeeded, -1 if failed
+///
+/// Local servers refuse new TCP connections while the usage is over the limit. It requires feature
+/// `memory-accounting`.
+#[unsafe(no_mangle)]
+pub extern "C" fn ss_set_memory_limit(limit: u64) -> c_int {
+    ffi_guard(-1, || {
+        #[cfg(feature = "memory-accounting")]
+        {
+            use shadowsocks_service::memory;
+
+            memory::set_memory_usage_fn(allocator::allocated);
+            memory::set_memory_limit(match limit {
+                0 => None,
+                limit => Some(usize::try_from(limit).unwrap_or(usize::MAX)),
+            });
+            0
+        }
+        #[cfg(not(feature = "memory-accounting"))]
+        {
+            let _ = limit;
+            set_last_error("memory limit requires feature \"memory-accounting\"");
+            -1
+        }
+    })
+}
+
+/// Release memory held by caches of all services running in this process, like DNS caches
+///
+/// It could be called when the system reports memory pressure.
+#[unsafe(no_mangle)]
+pub extern "C" fn ss_trim_memory() {
+    ffi_guard((), || {
+        shadowsocks_service::stats::clear_caches();
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn start_with_invalid_config() {
+        let config = CString::new("{ invalid").unwrap();
+        let service = unsafe { ss_service_start(config.as_ptr(), SS_CONFIG_TYPE_LOCAL, None, ptr::null_mut()) };
+        assert!(service.is_null());
+
+        let err = unsafe { CStr::from_ptr(ss_last_error()) };
+        assert!(err.to_str().unwrap().starts_with("invalid config"));
+    }
+
+    #[cfg(not(feature = "memory-accounting"))]
+    #[test]
+    fn memory_limit_requires_accounting() {
+        assert_eq!(ss_memory_allocated(), -1);
+        assert_eq!(ss_set_memory_limit(1024), -1);
+    }
+
+    #[test]
+    fn stats_json() {
+        let json = ss_stats_json();
+        assert!(!json.is_null());
+        let value: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(json) }.to_str().unwrap()).unwrap();
+        assert!(value.is_object());
+        unsafe { ss_string_free(json) };
+    }
+
+    #[test]
+    fn panic_as_error() {
+        let code = ffi_guard(-1, || -> c_int { panic!("test panic") });
+        assert_eq!(code, -1);
+
+        let err = unsafe { CStr::from_ptr(ss_last_error()) };
+        assert_eq!(err.to_str().unwrap(), "panicked, test panic");
+    }
+}
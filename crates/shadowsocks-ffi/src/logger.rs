@@ -0,0 +1,86 @@
+//! Forwarding log lines to the callback of applications
+
+use std::{
+    ffi::{CString, c_char, c_int, c_void},
+    sync::{OnceLock, RwLock},
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Callback receiving log lines, `line` is only valid during the call
+pub type LogCallback = extern "C" fn(ctx: *mut c_void, level: c_int, line: *const c_char);
+
+struct LogSink {
+    callback: LogCallback,
+    ctx: *mut c_void,
+}
+
+// Applications are required to make their callbacks thread-safe
+unsafe impl Send for LogSink {}
+unsafe impl Sync for LogSink {}
+
+static LOG_SINK: RwLock<Option<LogSink>> = RwLock::new(None);
+static LOGGER_INSTALLED: OnceLock<bool> = OnceLock::new();
+
+struct CallbackLogger;
+
+impl Log for CallbackLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let sink = LOG_SINK.read().unwrap();
+        if let Some(ref sink) = *sink {
+            let line = format!("[{}] {}", record.target(), record.args());
+            // Interior NULs couldn't be represented in C strings
+            let line = CString::new(line.replace('\0', "\\0")).expect("log line with NUL");
+            (sink.callback)(sink.ctx, level_to_c(record.level()), line.as_ptr());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn level_to_c(level: Level) -> c_int {
+    match level {
+        Level::Error => 1,
+        Level::Warn => 2,
+        Level::Info => 3,
+        Level::Debug => 4,
+        Level::Trace => 5,
+    }
+}
+
+fn level_from_c(level: c_int) -> LevelFilter {
+    match level {
+        l if l <= 0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Install `callback` for receiving log lines up to `max_level`, or remove it if `callback` is `None`
+///
+/// Returns `false` if another logger was installed in this process before.
+pub fn set_log_callback(callback: Option<LogCallback>, ctx: *mut c_void, max_level: c_int) -> bool {
+    let installed = *LOGGER_INSTALLED.get_or_init(|| log::set_logger(&CallbackLogger).is_ok());
+    if !installed {
+        return false;
+    }
+
+    *LOG_SINK.write().unwrap() = callback.map(|callback| LogSink { callback, ctx });
+    log::set_max_level(match callback {
+        Some(..) => level_from_c(max_level),
+        None => LevelFilter::Off,
+    });
+
+    true
+}
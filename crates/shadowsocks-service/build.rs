@@ -0,0 +1,11 @@
+fn main() {
+    #[cfg(feature = "manager-grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/manager.proto");
+
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/manager.proto"], &["proto"])
+            .expect("compile proto/manager.proto");
+    }
+}
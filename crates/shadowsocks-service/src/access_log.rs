@@ -0,0 +1,229 @@
+//! Connection access log
+//!
+//! One line is recorded for each completed TCP relay, with its client, target, server, bytes, duration and close reason:
+//!
+//! ```plain
+//! client=127.0.0.1:50124 target=example.com:443 server=1.2.3.4:8388 up=1024 down=40960 duration=1.503s reason=eof
+//! ```
+//!
+//! Lines are appended to a file prefixed with `time=<unix timestamp>`, or logged with target [`ACCESS_LOG_TARGET`]
+//! if path is not configured. The file is written by a dedicated thread, so relays never wait for the disk, and lines
+//! are dropped if the writer falls too far behind.
+//!
+//! Domain names are requested by clients, so whitespaces, quotes and control characters in them are escaped as
+//! `\u{..}` to keep them from forging fields or lines.
+
+use std::{
+    fmt::{self, Display, Write as _},
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error, info};
+use once_cell::sync::OnceCell;
+use shadowsocks::{ServerAddr, relay::Address};
+
+use crate::config::AccessLogConfig;
+
+/// Target of access log records, if access log is not written to a file
+pub const ACCESS_LOG_TARGET: &str = "shadowsocks_service::access";
+
+// Lines waiting for the writer thread
+const ACCESS_LOG_CHANNEL_SIZE: usize = 4096;
+
+enum AccessLogWriter {
+    Log,
+    File(SyncSender<String>),
+}
+
+static ACCESS_LOG: OnceCell<AccessLogWriter> = OnceCell::new();
+
+/// Enable access log, only the first call takes effect
+pub fn init(config: &AccessLogConfig) -> io::Result<()> {
+    if is_enabled() {
+        return Ok(());
+    }
+
+    let writer = match config.path {
+        None => AccessLogWriter::Log,
+        Some(ref path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let (tx, rx) = mpsc::sync_channel(ACCESS_LOG_CHANNEL_SIZE);
+            thread::Builder::new()
+                .name("access-log".to_owned())
+                .spawn(move || write_lines(file, rx))?;
+            AccessLogWriter::File(tx)
+        }
+    };
+    let _ = ACCESS_LOG.set(writer);
+    Ok(())
+}
+
+fn write_lines(file: File, rx: Receiver<String>) {
+    let mut writer = BufWriter::new(file);
+    while let Ok(line) = rx.recv() {
+        // Write lines queued meanwhile, and then flush them together
+        let mut result = writer.write_all(line.as_bytes());
+        while result.is_ok() {
+            match rx.try_recv() {
+                Ok(line) => result = writer.write_all(line.as_bytes()),
+                Err(..) => break,
+            }
+        }
+        if let Err(err) = result.and_then(|_| writer.flush()) {
+            error!("failed to write access log, error: {}", err);
+        }
+    }
+}
+
+/// Check if access log is enabled
+pub fn is_enabled() -> bool {
+    ACCESS_LOG.get().is_some()
+}
+
+/// Why a relay was closed
+#[derive(Debug, Clone, Copy)]
+pub enum CloseReason<'a> {
+    /// Both sides closed normally
+    Eof,
+    /// Relay failed with an error
+    Error(&'a io::Error),
+}
+
+impl Display for CloseReason<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            CloseReason::Eof => f.write_str("eof"),
+            CloseReason::Error(err) => {
+                f.write_char('"')?;
+                write_escaped(f, &err.to_string().replace('"', "'"), false)?;
+                f.write_char('"')
+            }
+        }
+    }
+}
+
+/// A completed relay
+pub struct AccessLogEntry<'a> {
+    /// Address of the client
+    pub client: SocketAddr,
+    /// Target address requested by the client
+    pub target: &'a Address,
+    /// Server relayed through by local, or the listening server of server. `None` if bypassed
+    pub server: Option<&'a ServerAddr>,
+    /// Bytes sent by the client
+    pub up: u64,
+    /// Bytes sent to the client
+    pub down: u64,
+    /// Duration since the relay was established
+    pub duration: Duration,
+    /// Why the relay was closed
+    pub reason: CloseReason<'a>,
+}
+
+impl Display for AccessLogEntry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "client={} target=", self.client)?;
+        match *self.target {
+            Address::SocketAddress(sa) => write!(f, "{sa}")?,
+            Address::DomainNameAddress(ref dname, port) => {
+                write_escaped(f, dname, true)?;
+                write!(f, ":{port}")?;
+            }
+        }
+        f.write_str(" server=")?;
+        match self.server {
+            Some(server) => write!(f, "{server}")?,
+            None => f.write_str("-")?,
+        }
+        write!(
+            f,
+            " up={} down={} duration={:.3}s reason={}",
+            self.up,
+            self.down,
+            self.duration.as_secs_f64(),
+            self.reason
+        )
+    }
+}
+
+// Escape characters that could forge lines, and also fields if `escape_spaces`
+fn write_escaped(f: &mut fmt::Formatter<'_>, s: &str, escape_spaces: bool) -> fmt::Result {
+    for c in s.chars() {
+        if c == '\\' {
+            f.write_str("\\\\")?;
+        } else if c.is_control() || (escape_spaces && (c.is_whitespace() || c == '"')) {
+            write!(f, "\\u{{{:x}}}", c as u32)?;
+        } else {
+            f.write_char(c)?;
+        }
+    }
+    Ok(())
+}
+
+/// Record a completed relay, `f` won't be called if access log is not enabled
+pub fn record<'a, F>(f: F)
+where
+    F: FnOnce() -> AccessLogEntry<'a>,
+{
+    match ACCESS_LOG.get() {
+        None => {}
+        Some(AccessLogWriter::Log) => info!(target: ACCESS_LOG_TARGET, "{}", f()),
+        Some(AccessLogWriter::File(tx)) => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            let line = format!("time={:.3} {}\n", now.as_secs_f64(), f());
+            match tx.try_send(line) {
+                Ok(..) => {}
+                Err(TrySendError::Full(..)) => debug!("access log dropped a record, writer is busy"),
+                Err(TrySendError::Disconnected(..)) => error!("failed to write access log, writer exited"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format_entry() {
+        let target = Address::DomainNameAddress("example.com".to_owned(), 443);
+        let err = io::Error::new(io::ErrorKind::Other, "connection \"reset\"");
+        let entry = AccessLogEntry {
+            client: "127.0.0.1:50124".parse().unwrap(),
+            target: &target,
+            server: None,
+            up: 1024,
+            down: 40960,
+            duration: Duration::from_millis(1503),
+            reason: CloseReason::Error(&err),
+        };
+        assert_eq!(
+            entry.to_string(),
+            "client=127.0.0.1:50124 target=example.com:443 server=- up=1024 down=40960 duration=1.503s reason=\"connection 'reset'\""
+        );
+    }
+
+    #[test]
+    fn escape_entry() {
+        let target = Address::DomainNameAddress("evil.com\ntime=0 client=1.2.3.4 x\\".to_owned(), 80);
+        let err = io::Error::new(io::ErrorKind::Other, "bad\r\nline");
+        let entry = AccessLogEntry {
+            client: "127.0.0.1:50124".parse().unwrap(),
+            target: &target,
+            server: None,
+            up: 0,
+            down: 0,
+            duration: Duration::ZERO,
+            reason: CloseReason::Error(&err),
+        };
+        assert_eq!(
+            entry.to_string(),
+            "client=127.0.0.1:50124 target=evil.com\\u{a}time=0\\u{20}client=1.2.3.4\\u{20}x\\\\:80 server=- up=0 down=0 duration=0.000s reason=\"bad\\u{d}\\u{a}line\""
+        );
+    }
+}
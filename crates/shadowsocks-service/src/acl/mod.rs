@@ -7,7 +7,7 @@ use std::{
     collections::HashSet,
     fmt,
     fs::File,
-    io::{self, BufRead, BufReader, Error, ErrorKind},
+    io::{self, BufRead, BufReader, Error},
     net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
     str,
@@ -21,8 +21,10 @@ use regex::bytes::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
 
 use shadowsocks::{context::Context, relay::socks5::Address};
 
+pub use self::route::{Route, RoutingTable};
 use self::sub_domains_tree::SubDomainsTree;
 
+mod route;
 mod sub_domains_tree;
 
 /// Strategy mode that ACL is running
@@ -177,6 +179,41 @@ impl ParsingRules {
         }
     }
 
+    /// Add a rule line, which is a network, an IP address, a `|` or `||` domain, or a regex
+    fn add_rule(&mut self, line: &str) -> io::Result<()> {
+        if let Some(rule) = line.strip_prefix("||") {
+            return self.add_tree_rule(rule);
+        }
+
+        if let Some(rule) = line.strip_prefix('|') {
+            return self.add_set_rule(rule);
+        }
+
+        match line.parse::<IpNet>() {
+            Ok(IpNet::V4(v4)) => {
+                self.add_ipv4_rule(v4);
+            }
+            Ok(IpNet::V6(v6)) => {
+                self.add_ipv6_rule(v6);
+            }
+            Err(..) => {
+                // Maybe it is a pure IpAddr
+                match line.parse::<IpAddr>() {
+                    Ok(IpAddr::V4(v4)) => {
+                        self.add_ipv4_rule(v4);
+                    }
+                    Ok(IpAddr::V6(v6)) => {
+                        self.add_ipv6_rule(v6);
+                    }
+                    Err(..) => {
+                        self.add_regex_rule(line.to_owned());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn add_ipv4_rule(&mut self, rule: impl Into<Ipv4Net>) {
         let rule = rule.into();
         trace!("IPV4-RULE {}", rule);
@@ -256,10 +293,9 @@ impl ParsingRules {
             // Remove the last `.` of FQDN
             Ok(str.trim_end_matches('.'))
         } else {
-            Err(Error::new(
-                ErrorKind::Other,
-                format!("{} parsing error: Unicode not allowed here `{}`", self.name, str),
-            ))
+            Err(Error::from(shadowsocks::Error::Acl(
+                format!("{} parsing error: Unicode not allowed here `{}`", self.name, str).into(),
+            )))
         }
     }
 
@@ -269,7 +305,7 @@ impl ParsingRules {
             .size_limit(REGEX_SIZE_LIMIT)
             .unicode(false)
             .build()
-            .map_err(|err| Error::new(ErrorKind::Other, format!("{name} regex error: {err}")))
+            .map_err(|err| Error::from(shadowsocks::Error::Acl(format!("{name} regex error: {err}").into())))
     }
 
     fn into_rules(self) -> io::Result<Rules> {
@@ -379,16 +415,6 @@ impl AccessControl {
                 continue;
             }
 
-            if let Some(rule) = line.strip_prefix("||") {
-                curr.add_tree_rule(rule)?;
-                continue;
-            }
-
-            if let Some(rule) = line.strip_prefix('|') {
-                curr.add_set_rule(rule)?;
-                continue;
-            }
-
             match line {
                 "[reject_all]" | "[bypass_all]" => {
                     mode = Mode::WhiteList;
@@ -410,30 +436,7 @@ impl AccessControl {
                     curr = &mut proxy;
                     trace!("loading white_list / proxy_list");
                 }
-                _ => {
-                    match line.parse::<IpNet>() {
-                        Ok(IpNet::V4(v4)) => {
-                            curr.add_ipv4_rule(v4);
-                        }
-                        Ok(IpNet::V6(v6)) => {
-                            curr.add_ipv6_rule(v6);
-                        }
-                        Err(..) => {
-                            // Maybe it is a pure IpAddr
-                            match line.parse::<IpAddr>() {
-                                Ok(IpAddr::V4(v4)) => {
-                                    curr.add_ipv4_rule(v4);
-                                }
-                                Ok(IpAddr::V6(v6)) => {
-                                    curr.add_ipv6_rule(v6);
-                                }
-                                Err(..) => {
-                                    curr.add_regex_rule(line.to_owned());
-                                }
-                            }
-                        }
-                    }
-                }
+                _ => curr.add_rule(line)?,
             }
         }
 
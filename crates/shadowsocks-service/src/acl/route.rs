@@ -0,0 +1,144 @@
+//! Routing table for choosing servers by target addresses
+//!
+//! Each route has a list of rules in the same forms of ACL rules, and a server name.
+//! Routes are matched in order, and targets that don't match any routes are left to the balancer.
+//!
+//! ```plain
+//! ||netflix.com      => "us-1"
+//! (^|\.)co\.jp$      => "jp-2"
+//! 203.0.113.0/24     => "jp-2"
+//! ```
+
+use std::{io, net::IpAddr};
+
+use shadowsocks::relay::socks5::Address;
+
+use super::{AccessControl, ParsingRules, Rules};
+
+/// A route of targets to a server
+#[derive(Debug, Clone)]
+pub struct Route {
+    server: String,
+    rule_lines: Vec<String>,
+    rules: Rules,
+}
+
+impl Route {
+    /// Create a route of targets matching `rule_lines` to `server`
+    ///
+    /// `server` is the `id`, `remarks` or address of a server
+    pub fn new(server: String, rule_lines: Vec<String>) -> io::Result<Route> {
+        let mut parsing = ParsingRules::new("route");
+        for line in &rule_lines {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if !line.is_ascii() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("route rule {line} containing non-ASCII characters"),
+                ));
+            }
+            parsing.add_rule(line)?;
+        }
+
+        Ok(Route {
+            server,
+            rule_lines,
+            rules: parsing.into_rules()?,
+        })
+    }
+
+    /// Name of the server
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    /// Rules of this route
+    pub fn rule_lines(&self) -> &[String] {
+        &self.rule_lines
+    }
+
+    fn check_ip_matched(&self, ip: &IpAddr) -> bool {
+        self.rules.check_ip_matched(ip)
+    }
+
+    fn check_ascii_host_matched(&self, host: &str) -> bool {
+        self.rules.check_host_matched(host)
+    }
+}
+
+/// Routes consulted before the balancer
+#[derive(Debug, Clone, Default)]
+pub struct RoutingTable {
+    routes: Vec<Route>,
+}
+
+impl RoutingTable {
+    /// Create an empty routing table
+    pub fn new() -> RoutingTable {
+        RoutingTable::default()
+    }
+
+    /// Append a route, which has lower priority than the existing routes
+    pub fn add_route(&mut self, route: Route) {
+        self.routes.push(route);
+    }
+
+    /// All routes, in order
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// Check if there are no routes
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Find the server of `addr`, `None` if it doesn't match any routes
+    ///
+    /// Domain names are not resolved, so they are only matched by domain rules.
+    pub fn route(&self, addr: &Address) -> Option<&str> {
+        let route = match *addr {
+            Address::SocketAddress(ref saddr) => {
+                let ip = saddr.ip().to_canonical();
+                self.routes.iter().find(|r| r.check_ip_matched(&ip))
+            }
+            Address::DomainNameAddress(ref host, ..) => {
+                let host = AccessControl::convert_to_ascii(host);
+                self.routes.iter().find(|r| r.check_ascii_host_matched(&host))
+            }
+        };
+        route.map(Route::server)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn route_by_rules() {
+        let mut table = RoutingTable::new();
+        table.add_route(Route::new("us-1".to_owned(), vec!["||netflix.com".to_owned()]).unwrap());
+        table.add_route(
+            Route::new(
+                "jp-2".to_owned(),
+                vec!["(^|\\.)co\\.jp$".to_owned(), "203.0.113.0/24".to_owned()],
+            )
+            .unwrap(),
+        );
+
+        let domain = |host: &str| Address::DomainNameAddress(host.to_owned(), 443);
+        assert_eq!(table.route(&domain("www.Netflix.com")), Some("us-1"));
+        assert_eq!(table.route(&domain("netflix.com.")), Some("us-1"));
+        assert_eq!(table.route(&domain("example.co.jp")), Some("jp-2"));
+        assert_eq!(table.route(&domain("example.com")), None);
+
+        let ip = |ip: &str| Address::SocketAddress(ip.parse().unwrap());
+        assert_eq!(table.route(&ip("203.0.113.7:443")), Some("jp-2"));
+        assert_eq!(table.route(&ip("[::ffff:203.0.113.7]:443")), Some("jp-2"));
+        assert_eq!(table.route(&ip("198.51.100.1:443")), None);
+    }
+}
@@ -0,0 +1,131 @@
+//! HTTP server of admin APIs
+
+use std::{future::Future, io, net::SocketAddr, time::Duration};
+
+use bytes::Bytes;
+use futures::{StreamExt, stream::FuturesUnordered};
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    Request, Response, StatusCode,
+    body::Incoming,
+    header::{self, HeaderValue},
+    server::conn::http1,
+    service,
+};
+use log::{debug, error, trace, warn};
+use serde_json::{Value as JsonValue, json};
+use shadowsocks::net::TcpListener;
+use tokio::{net::TcpStream, time};
+
+use crate::net::tokio_rt::TokioIo;
+
+use super::check_bearer_token;
+
+/// Maximum size of request body
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Serve HTTP requests accepted by `listener` with `handler` until error occurs
+///
+/// Requests must carry `Authorization: Bearer <token>` if `token` is set, others are rejected before `handler`.
+pub async fn serve<H, F>(name: &str, listener: TcpListener, token: Option<&str>, handler: H) -> io::Result<()>
+where
+    H: Fn(Request<Incoming>, SocketAddr) -> F,
+    F: Future<Output = Response<Full<Bytes>>>,
+{
+    let handler = &handler;
+    let mut connections = FuturesUnordered::new();
+
+    loop {
+        tokio::select! {
+            r = listener.accept() => {
+                let (stream, peer_addr) = match r {
+                    Ok(s) => s,
+                    Err(err) => {
+                        error!("{} accept failed with error: {}", name, err);
+
+                        // Accepting fails repeatedly if file descriptors are exhausted, retry later while serving
+                        // accepted connections, which may release their descriptors
+                        let delay = time::sleep(Duration::from_secs(1));
+                        tokio::pin!(delay);
+                        loop {
+                            tokio::select! {
+                                _ = &mut delay => break,
+                                Some(..) = connections.next(), if !connections.is_empty() => {}
+                            }
+                        }
+                        continue;
+                    }
+                };
+
+                trace!("{} accepted client from {}", name, peer_addr);
+                connections.push(serve_connection(name, token, handler, stream, peer_addr));
+            }
+            Some(..) = connections.next(), if !connections.is_empty() => {}
+        }
+    }
+}
+
+async fn serve_connection<H, F>(name: &str, token: Option<&str>, handler: &H, stream: TcpStream, peer_addr: SocketAddr)
+where
+    H: Fn(Request<Incoming>, SocketAddr) -> F,
+    F: Future<Output = Response<Full<Bytes>>>,
+{
+    let io = TokioIo::new(stream);
+    let service = service::service_fn(move |req| async move {
+        if let Some(token) = token {
+            if !check_authorization(&req, token) {
+                warn!(
+                    "{} {} {} from {} unauthorized",
+                    name,
+                    req.method(),
+                    req.uri().path(),
+                    peer_addr
+                );
+                return Ok::<_, hyper::Error>(make_error(StatusCode::UNAUTHORIZED, "unauthorized"));
+            }
+        }
+
+        debug!("{} {} {} from {}", name, req.method(), req.uri().path(), peer_addr);
+        Ok(handler(req, peer_addr).await)
+    });
+
+    if let Err(err) = http1::Builder::new()
+        .keep_alive(true)
+        .serve_connection(io, service)
+        .await
+    {
+        debug!("{} connection {} failed with error: {}", name, peer_addr, err);
+    }
+}
+
+fn check_authorization(req: &Request<Incoming>, token: &str) -> bool {
+    let value = req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    check_bearer_token(value, token)
+}
+
+/// Read the whole request body, at most 64KiB
+pub async fn read_body(req: Request<Incoming>) -> Result<Bytes, Response<Full<Bytes>>> {
+    let body = match http_body_util::Limited::new(req.into_body(), MAX_BODY_SIZE)
+        .collect()
+        .await
+    {
+        Ok(b) => b.to_bytes(),
+        Err(err) => return Err(make_error(StatusCode::BAD_REQUEST, &err.to_string())),
+    };
+    Ok(body)
+}
+
+/// Make a JSON response
+pub fn make_json(status: StatusCode, value: &JsonValue) -> Response<Full<Bytes>> {
+    let body = serde_json::to_vec(value).expect("serialize json");
+    let mut rsp = Response::new(Full::new(Bytes::from(body)));
+    *rsp.status_mut() = status;
+    rsp.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    rsp
+}
+
+/// Make a JSON response of error, `{"error": "..."}`
+pub fn make_error(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    make_json(status, &json!({ "error": message }))
+}
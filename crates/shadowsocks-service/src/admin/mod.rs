@@ -0,0 +1,42 @@
+//! Shared parts of admin APIs
+//!
+//! HTTP admin APIs of Local and Manager are served by the same accept loop, and all admin APIs, including the gRPC API
+//! of Manager, are protected by the same `Authorization: Bearer <token>` check.
+
+#[cfg(any(feature = "local-admin", feature = "manager-admin"))]
+pub mod http;
+
+/// Check the value of an `Authorization` header (or metadata), which should be `Bearer <token>`
+pub fn check_bearer_token(value: Option<&str>, token: &str) -> bool {
+    let value = match value {
+        Some(v) => v,
+        None => return false,
+    };
+
+    let provided = match value.strip_prefix("Bearer ") {
+        Some(t) => t.trim().as_bytes(),
+        None => return false,
+    };
+
+    // Compare without short-circuit
+    let expected = token.as_bytes();
+    if provided.len() != expected.len() {
+        return false;
+    }
+    provided.iter().zip(expected).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bearer_token() {
+        assert!(check_bearer_token(Some("Bearer secret"), "secret"));
+        assert!(check_bearer_token(Some("Bearer  secret "), "secret"));
+        assert!(!check_bearer_token(Some("Bearer secreT"), "secret"));
+        assert!(!check_bearer_token(Some("Bearer secret1"), "secret"));
+        assert!(!check_bearer_token(Some("Basic secret"), "secret"));
+        assert!(!check_bearer_token(None, "secret"));
+    }
+}
@@ -0,0 +1,430 @@
+//! End-to-end throughput self-test
+//!
+//! Servers with the bench responder enabled serve targets of the domain [`BENCH_DOMAIN`] in process, like the classic
+//! inetd services: port 7 echoes data back, port 9 discards data, and port 19 sends data continuously. [`run_bench`]
+//! drives synthetic TCP and UDP traffic through a server to them, measuring throughput and latency of the whole path,
+//! including ciphers and plugins, without depending on any other hosts.
+
+use std::io::{self, ErrorKind};
+#[cfg(feature = "local")]
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "local")]
+use serde::Serialize;
+use shadowsocks::relay::socks5::Address;
+#[cfg(feature = "local")]
+use shadowsocks::{
+    ProxyClientStream, ProxySocket, ServerConfig,
+    config::ServerType,
+    context::{Context, SharedContext},
+    net::ConnectOpts,
+    plugin::{Plugin, PluginMode},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(feature = "local")]
+use tokio::{sync::Notify, time};
+
+#[cfg(feature = "local")]
+use crate::stats::{HistogramStats, LatencyHistogram};
+
+/// Domain name of targets served by the bench responder
+pub const BENCH_DOMAIN: &str = "bench.shadowsocks.invalid";
+/// Port of the echo target, data is sent back
+pub const BENCH_ECHO_PORT: u16 = 7;
+/// Port of the discard target, data is thrown away, and bytes received are sent back as a big-endian `u64` at EOF
+pub const BENCH_DISCARD_PORT: u16 = 9;
+/// Port of the chargen target, data is sent continuously until the connection is closed
+pub const BENCH_CHARGEN_PORT: u16 = 19;
+
+const BENCH_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Check if `addr` is a target of the bench responder
+pub fn is_bench_target(addr: &Address) -> bool {
+    matches!(*addr, Address::DomainNameAddress(ref domain, _) if domain == BENCH_DOMAIN)
+}
+
+/// Serve a TCP connection to a target of the bench responder
+pub async fn serve_tcp<S>(addr: &Address, stream: &mut S) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; BENCH_BUFFER_SIZE];
+    match addr.port() {
+        BENCH_ECHO_PORT => loop {
+            let n = stream.read(&mut buffer).await?;
+            if n == 0 {
+                return stream.shutdown().await;
+            }
+            stream.write_all(&buffer[..n]).await?;
+        },
+        BENCH_DISCARD_PORT => {
+            let mut received = 0u64;
+            loop {
+                let n = stream.read(&mut buffer).await?;
+                if n == 0 {
+                    break;
+                }
+                received += n as u64;
+            }
+            stream.write_all(&received.to_be_bytes()).await?;
+            stream.shutdown().await
+        }
+        BENCH_CHARGEN_PORT => loop {
+            // Stops when the client closes the connection
+            stream.write_all(&buffer).await?;
+        },
+        port => Err(io::Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("bench responder doesn't serve port {port}"),
+        )),
+    }
+}
+
+/// Options of [`run_bench`]
+#[cfg(feature = "local")]
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// Server to test
+    pub server: ServerConfig,
+    /// Duration of each throughput test
+    pub duration: Duration,
+    /// Concurrent TCP connections of throughput tests
+    pub connections: usize,
+    /// Round trips of the latency test
+    pub round_trips: usize,
+    /// Test UDP relay, the server has to be in `tcp_and_udp` mode
+    pub udp: bool,
+    /// Size of UDP payloads
+    pub udp_payload_size: usize,
+    /// Options of connecting to the server
+    pub connect_opts: ConnectOpts,
+}
+
+#[cfg(feature = "local")]
+impl BenchConfig {
+    /// Test `server` with the default options, 10 seconds with 1 connection, TCP only
+    pub fn new(server: ServerConfig) -> BenchConfig {
+        BenchConfig {
+            server,
+            duration: Duration::from_secs(10),
+            connections: 1,
+            round_trips: 100,
+            udp: false,
+            udp_payload_size: 1200,
+            connect_opts: ConnectOpts::default(),
+        }
+    }
+}
+
+/// Results of [`run_bench`]
+#[cfg(feature = "local")]
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    /// Round trip time of small messages over TCP
+    pub tcp_latency: HistogramStats,
+    /// Bytes per second from client to server, counted by the server
+    pub upload_bps: f64,
+    /// Bytes per second from server to client
+    pub download_bps: f64,
+    /// UDP results, if tested
+    pub udp: Option<UdpBenchReport>,
+    /// CPU time of this process divided by wall time (1.0 for a fully busy core), if supported by the platform
+    ///
+    /// Only the client side is measured, CPU usage of the server has to be watched on the server.
+    pub cpu_usage: Option<f64>,
+}
+
+/// Results of the UDP test of [`run_bench`]
+#[cfg(feature = "local")]
+#[derive(Debug, Clone, Serialize)]
+pub struct UdpBenchReport {
+    /// Packets sent
+    pub sent: u64,
+    /// Packets echoed back
+    pub received: u64,
+    /// Payload bytes per second echoed back
+    pub bps: f64,
+    /// Round trip time of packets
+    pub latency: HistogramStats,
+}
+
+#[cfg(feature = "local")]
+fn format_bps(bps: f64) -> String {
+    format!("{:.2} Mbit/s", bps * 8.0 / 1_000_000.0)
+}
+
+#[cfg(feature = "local")]
+fn format_latency(stats: &HistogramStats) -> String {
+    let ms = |v: Option<u64>| v.map_or_else(|| "-".to_owned(), |v| format!("{:.2}ms", v as f64 / 1000.0));
+    format!(
+        "p50 {} p90 {} p99 {} ({} samples)",
+        ms(stats.p50_micros),
+        ms(stats.p90_micros),
+        ms(stats.p99_micros),
+        stats.count
+    )
+}
+
+#[cfg(feature = "local")]
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "TCP latency:  {}", format_latency(&self.tcp_latency))?;
+        writeln!(f, "TCP upload:   {}", format_bps(self.upload_bps))?;
+        writeln!(f, "TCP download: {}", format_bps(self.download_bps))?;
+        if let Some(ref udp) = self.udp {
+            let loss = if udp.sent > 0 {
+                (udp.sent - udp.received.min(udp.sent)) as f64 * 100.0 / udp.sent as f64
+            } else {
+                0.0
+            };
+            writeln!(
+                f,
+                "UDP echo:     {}, {}/{} packets ({:.2}% lost)",
+                format_bps(udp.bps),
+                udp.received,
+                udp.sent,
+                loss
+            )?;
+            writeln!(f, "UDP latency:  {}", format_latency(&udp.latency))?;
+        }
+        match self.cpu_usage {
+            Some(usage) => write!(f, "Client CPU:   {:.1}%", usage * 100.0),
+            None => write!(f, "Client CPU:   -"),
+        }
+    }
+}
+
+/// CPU time used by this process
+#[cfg(all(feature = "local", unix))]
+fn process_cpu_time() -> Option<Duration> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    if ret != 0 {
+        return None;
+    }
+
+    let to_duration = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    Some(to_duration(usage.ru_utime) + to_duration(usage.ru_stime))
+}
+
+#[cfg(all(feature = "local", not(unix)))]
+fn process_cpu_time() -> Option<Duration> {
+    None
+}
+
+/// Drive synthetic traffic through `config.server` to its bench responder, and report the results
+///
+/// Plugins of the server are started for the test, and stopped after it.
+#[cfg(feature = "local")]
+pub async fn run_bench(config: BenchConfig) -> io::Result<BenchReport> {
+    let context = Context::new_shared(ServerType::Local);
+
+    let mut svr_cfg = config.server.clone();
+    let plugins = Plugin::start_chain(svr_cfg.plugins(), svr_cfg.addr(), PluginMode::Client)?;
+    if let Some(plugin) = plugins.first() {
+        svr_cfg.set_plugin_addr(plugin.local_addr().into());
+    }
+
+    let started = Instant::now();
+    let cpu_started = process_cpu_time();
+
+    let tcp_latency = bench_tcp_latency(&context, &svr_cfg, &config).await?;
+    let upload_bps = bench_tcp_upload(&context, &svr_cfg, &config).await?;
+    let download_bps = bench_tcp_download(&context, &svr_cfg, &config).await?;
+    let udp = if config.udp {
+        Some(bench_udp(&context, &config).await?)
+    } else {
+        None
+    };
+
+    let cpu_usage = match (cpu_started, process_cpu_time()) {
+        (Some(start), Some(end)) => Some((end - start).as_secs_f64() / started.elapsed().as_secs_f64()),
+        _ => None,
+    };
+
+    drop(plugins);
+
+    Ok(BenchReport {
+        tcp_latency,
+        upload_bps,
+        download_bps,
+        udp,
+        cpu_usage,
+    })
+}
+
+#[cfg(feature = "local")]
+async fn connect_bench(
+    context: &SharedContext,
+    svr_cfg: &ServerConfig,
+    config: &BenchConfig,
+    port: u16,
+) -> io::Result<ProxyClientStream<shadowsocks::net::TcpStream>> {
+    let target = Address::DomainNameAddress(BENCH_DOMAIN.to_owned(), port);
+    ProxyClientStream::connect_with_opts(context.clone(), svr_cfg, target, &config.connect_opts).await
+}
+
+#[cfg(feature = "local")]
+async fn bench_tcp_latency(
+    context: &SharedContext,
+    svr_cfg: &ServerConfig,
+    config: &BenchConfig,
+) -> io::Result<HistogramStats> {
+    let mut stream = connect_bench(context, svr_cfg, config, BENCH_ECHO_PORT).await?;
+
+    let histogram = LatencyHistogram::new();
+    let message = [0x5au8; 64];
+    let mut echoed = [0u8; 64];
+    for _ in 0..config.round_trips {
+        let start = Instant::now();
+        stream.write_all(&message).await?;
+        stream.read_exact(&mut echoed).await?;
+        histogram.record(start.elapsed());
+    }
+
+    Ok(histogram.snapshot())
+}
+
+#[cfg(feature = "local")]
+async fn bench_tcp_upload(context: &SharedContext, svr_cfg: &ServerConfig, config: &BenchConfig) -> io::Result<f64> {
+    let mut tasks = Vec::with_capacity(config.connections);
+    let start = Instant::now();
+    for _ in 0..config.connections.max(1) {
+        let mut stream = connect_bench(context, svr_cfg, config, BENCH_DISCARD_PORT).await?;
+        let duration = config.duration;
+        tasks.push(tokio::spawn(async move {
+            let buffer = vec![0u8; BENCH_BUFFER_SIZE];
+            let deadline = Instant::now() + duration;
+            while Instant::now() < deadline {
+                stream.write_all(&buffer).await?;
+            }
+            stream.shutdown().await?;
+
+            // Bytes received by the server
+            stream.read_u64().await
+        }));
+    }
+
+    let mut received = 0u64;
+    for task in tasks {
+        received += task.await.map_err(io::Error::other)??;
+    }
+    Ok(received as f64 / start.elapsed().as_secs_f64())
+}
+
+#[cfg(feature = "local")]
+async fn bench_tcp_download(context: &SharedContext, svr_cfg: &ServerConfig, config: &BenchConfig) -> io::Result<f64> {
+    let received = Arc::new(AtomicU64::new(0));
+
+    let mut tasks = Vec::with_capacity(config.connections);
+    for _ in 0..config.connections.max(1) {
+        let mut stream = connect_bench(context, svr_cfg, config, BENCH_CHARGEN_PORT).await?;
+        let received = received.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut buffer = vec![0u8; BENCH_BUFFER_SIZE];
+            loop {
+                let n = stream.read(&mut buffer).await?;
+                if n == 0 {
+                    return Ok::<_, io::Error>(());
+                }
+                received.fetch_add(n as u64, Ordering::Relaxed);
+            }
+        }));
+    }
+
+    let start = Instant::now();
+    time::sleep(config.duration).await;
+    let bytes = received.load(Ordering::Relaxed);
+    let elapsed = start.elapsed();
+
+    for task in tasks {
+        // Closes connections
+        task.abort();
+    }
+
+    Ok(bytes as f64 / elapsed.as_secs_f64())
+}
+
+#[cfg(feature = "local")]
+async fn bench_udp(context: &SharedContext, config: &BenchConfig) -> io::Result<UdpBenchReport> {
+    // Packets in flight, the others wait for echoes, so the server isn't flooded
+    const WINDOW: u64 = 64;
+    // Packets in flight are assumed to be lost after this duration without any echoes
+    const LOSS_TIMEOUT: Duration = Duration::from_millis(200);
+
+    // UDP doesn't go through plugins
+    let socket = ProxySocket::connect_with_opts(context.clone(), &config.server, &config.connect_opts).await?;
+    let target = Address::DomainNameAddress(BENCH_DOMAIN.to_owned(), BENCH_ECHO_PORT);
+
+    let histogram = LatencyHistogram::new();
+    let received = AtomicU64::new(0);
+    let received_bytes = AtomicU64::new(0);
+    let echoed = Notify::new();
+    let start = Instant::now();
+
+    let sender = async {
+        let mut payload = vec![0u8; config.udp_payload_size.max(16)];
+        let mut sent = 0u64;
+        let mut lost = 0u64;
+        while start.elapsed() < config.duration {
+            while (sent - lost).saturating_sub(received.load(Ordering::Relaxed)) >= WINDOW {
+                if time::timeout(LOSS_TIMEOUT, echoed.notified()).await.is_err() {
+                    lost = sent.saturating_sub(received.load(Ordering::Relaxed));
+                }
+            }
+
+            // Timestamp for round trip time
+            let micros = start.elapsed().as_micros() as u64;
+            payload[..8].copy_from_slice(&micros.to_be_bytes());
+            socket.send(&target, &payload).await?;
+            sent += 1;
+        }
+        Ok::<_, io::Error>(sent)
+    };
+
+    let receiver = async {
+        let mut buffer = vec![0u8; 65536];
+        loop {
+            let n = match socket.recv(&mut buffer).await {
+                Ok((n, ..)) => n,
+                Err(err) => return Err::<(), io::Error>(err.into()),
+            };
+            if n >= 8 {
+                let mut micros = [0u8; 8];
+                micros.copy_from_slice(&buffer[..8]);
+                let sent_at = Duration::from_micros(u64::from_be_bytes(micros));
+                histogram.record(start.elapsed().saturating_sub(sent_at));
+            }
+            received.fetch_add(1, Ordering::Relaxed);
+            received_bytes.fetch_add(n as u64, Ordering::Relaxed);
+            echoed.notify_one();
+        }
+    };
+
+    tokio::pin!(receiver);
+    let sent = tokio::select! {
+        r = sender => r?,
+        r = &mut receiver => {
+            r?;
+            unreachable!("receiver never finishes");
+        }
+    };
+
+    // Wait for echoes still in flight
+    let _ = time::timeout(LOSS_TIMEOUT, &mut receiver).await;
+    let elapsed = start.elapsed();
+
+    Ok(UdpBenchReport {
+        sent,
+        received: received.load(Ordering::Relaxed),
+        bps: received_bytes.load(Ordering::Relaxed) as f64 / elapsed.as_secs_f64(),
+        latency: histogram.snapshot(),
+    })
+}
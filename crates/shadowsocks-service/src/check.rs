@@ -0,0 +1,494 @@
+//! Connectivity check of servers
+//!
+//! [`run_check`] walks through every step of using a server, resolving its domain name, connecting to it, completing
+//! the encrypted handshake, and fetching an HTTP URL through it, recording latency of each step, so the broken step
+//! of a server is reported instead of guessed.
+
+use std::{
+    fmt::{self, Write as _},
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use byte_string::ByteStr;
+use futures::future;
+use serde::Serialize;
+use shadowsocks::{
+    ProxyClientStream, ServerAddr, ServerConfig,
+    config::ServerType,
+    context::{Context, SharedContext},
+    net::{ConnectOpts, TcpStream as OutboundTcpStream},
+    plugin::{Plugin, PluginMode},
+    relay::socks5::Address,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    time,
+};
+
+use crate::config::BalancerCheckUrl;
+
+/// Maximum bytes of responses read by the fetch step
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024;
+
+/// Options of [`run_check`]
+#[derive(Debug, Clone)]
+pub struct CheckConfig {
+    /// Servers to check, simultaneously
+    pub servers: Vec<ServerConfig>,
+    /// URL fetched through servers, only `http://` URLs are supported
+    pub url: BalancerCheckUrl,
+    /// Timeout of each step
+    pub timeout: Duration,
+    /// Options of connecting to servers
+    pub connect_opts: ConnectOpts,
+}
+
+impl CheckConfig {
+    /// Check `servers` by fetching `http://www.gstatic.com/generate_204`, with 10 seconds timeout of each step
+    pub fn new(servers: Vec<ServerConfig>) -> CheckConfig {
+        CheckConfig {
+            servers,
+            url: BalancerCheckUrl::default(),
+            timeout: Duration::from_secs(10),
+            connect_opts: ConnectOpts::default(),
+        }
+    }
+}
+
+/// Steps of checking a server, in order
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStep {
+    /// Resolving domain name of the server
+    Dns,
+    /// Connecting to the server, or to its plugin
+    Connect,
+    /// Sending the encrypted request, until the first byte of response is decrypted
+    ///
+    /// The server has to decrypt the request and connect to the target before responding, so failures of this step
+    /// are usually caused by mismatched passwords or methods, or by targets unreachable from the server.
+    Handshake,
+    /// Reading the rest of the response, and checking its status
+    Fetch,
+}
+
+impl CheckStep {
+    const ALL: [CheckStep; 4] = [
+        CheckStep::Dns,
+        CheckStep::Connect,
+        CheckStep::Handshake,
+        CheckStep::Fetch,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            CheckStep::Dns => "dns",
+            CheckStep::Connect => "connect",
+            CheckStep::Handshake => "handshake",
+            CheckStep::Fetch => "fetch",
+        }
+    }
+}
+
+impl fmt::Display for CheckStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Result of a step
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckStepReport {
+    /// The step
+    pub step: CheckStep,
+    /// Latency of the step, `None` if it was skipped
+    pub latency_micros: Option<u64>,
+    /// Error of the step, steps after a failed one are not reported
+    pub error: Option<String>,
+}
+
+/// Results of checking a server
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckReport {
+    /// Address of the server
+    pub server: String,
+    /// Remarks of the server
+    pub remarks: Option<String>,
+    /// Resolved addresses of the server
+    pub addresses: Vec<SocketAddr>,
+    /// Results of steps, in order
+    pub steps: Vec<CheckStepReport>,
+    /// Status code of the fetched response
+    pub status: Option<u16>,
+}
+
+impl CheckReport {
+    fn new(svr_cfg: &ServerConfig) -> CheckReport {
+        CheckReport {
+            server: svr_cfg.addr().to_string(),
+            remarks: svr_cfg.remarks().filter(|r| !r.is_empty()).map(ToOwned::to_owned),
+            addresses: Vec::new(),
+            steps: Vec::with_capacity(CheckStep::ALL.len()),
+            status: None,
+        }
+    }
+
+    /// Check if all steps succeeded
+    pub fn is_ok(&self) -> bool {
+        self.steps.len() == CheckStep::ALL.len() && self.steps.iter().all(|s| s.error.is_none())
+    }
+
+    fn step(&self, step: CheckStep) -> Option<&CheckStepReport> {
+        self.steps.iter().find(|s| s.step == step)
+    }
+
+    fn record<T>(&mut self, step: CheckStep, start: Instant, result: io::Result<T>) -> Option<T> {
+        let latency_micros = Some(start.elapsed().as_micros() as u64);
+        match result {
+            Ok(value) => {
+                self.steps.push(CheckStepReport {
+                    step,
+                    latency_micros,
+                    error: None,
+                });
+                Some(value)
+            }
+            Err(err) => {
+                self.steps.push(CheckStepReport {
+                    step,
+                    latency_micros,
+                    error: Some(err.to_string()),
+                });
+                None
+            }
+        }
+    }
+}
+
+/// Format `reports` as a table, with errors listed below it
+pub fn format_table(reports: &[CheckReport]) -> String {
+    let mut rows = Vec::with_capacity(reports.len() + 1);
+
+    let mut header = vec!["SERVER".to_owned()];
+    header.extend(CheckStep::ALL.iter().map(|s| s.name().to_uppercase()));
+    header.push("RESULT".to_owned());
+    rows.push(header);
+
+    for report in reports {
+        let mut row = Vec::with_capacity(CheckStep::ALL.len() + 2);
+        row.push(match report.remarks {
+            Some(ref remarks) => format!("{} ({})", report.server, remarks),
+            None => report.server.clone(),
+        });
+        for step in CheckStep::ALL {
+            row.push(match report.step(step) {
+                Some(CheckStepReport { error: Some(..), .. }) => "FAILED".to_owned(),
+                Some(CheckStepReport {
+                    latency_micros: Some(micros),
+                    ..
+                }) => format!("{:.2}ms", *micros as f64 / 1000.0),
+                _ => "-".to_owned(),
+            });
+        }
+        row.push(if report.is_ok() { "OK" } else { "FAILED" }.to_owned());
+        rows.push(row);
+    }
+
+    let mut widths = vec![0; rows[0].len()];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut table = String::new();
+    for row in &rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        let _ = writeln!(table, "{}", line.trim_end());
+    }
+
+    for report in reports {
+        for step in &report.steps {
+            if let Some(ref err) = step.error {
+                let _ = writeln!(table, "{} {} failed: {}", report.server, step.step, err);
+            }
+        }
+    }
+
+    table
+}
+
+/// Check all `config.servers` simultaneously, and report results in the same order
+///
+/// Plugins of servers are started for the check, and stopped after it. Failures are reported in results, errors are
+/// only returned if the check couldn't be run.
+pub async fn run_check(config: CheckConfig) -> io::Result<Vec<CheckReport>> {
+    if config.url.https {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            "only http:// URLs could be fetched by connectivity checks",
+        ));
+    }
+
+    let context = Context::new_shared(ServerType::Local);
+    let checks = config
+        .servers
+        .iter()
+        .map(|svr_cfg| check_server(&context, svr_cfg, &config));
+    Ok(future::join_all(checks).await)
+}
+
+async fn check_server(context: &SharedContext, svr_cfg: &ServerConfig, config: &CheckConfig) -> CheckReport {
+    let mut report = CheckReport::new(svr_cfg);
+
+    // DNS
+    let start = Instant::now();
+    let addresses = match *svr_cfg.addr() {
+        ServerAddr::SocketAddr(addr) => {
+            report.steps.push(CheckStepReport {
+                step: CheckStep::Dns,
+                latency_micros: None,
+                error: None,
+            });
+            Some(vec![addr])
+        }
+        ServerAddr::DomainName(ref domain, port) => {
+            let result = with_timeout(config.timeout, async {
+                let addrs = context.dns_resolve(domain, port).await?.collect::<Vec<_>>();
+                if addrs.is_empty() {
+                    return Err(io::Error::new(
+                        ErrorKind::Other,
+                        format!("{domain} resolved no addresses"),
+                    ));
+                }
+                Ok(addrs)
+            })
+            .await;
+            report.record(CheckStep::Dns, start, result)
+        }
+    };
+    let Some(addresses) = addresses else {
+        return report;
+    };
+    report.addresses = addresses.clone();
+
+    // Plugins connect to the server by themselves, the connect step only measures connecting to the first of them
+    let mut svr_cfg = svr_cfg.clone();
+    let plugins = match start_plugins(&mut svr_cfg).await {
+        Ok(p) => p,
+        Err(err) => {
+            report.record::<()>(CheckStep::Connect, Instant::now(), Err(err));
+            return report;
+        }
+    };
+
+    let start = Instant::now();
+    let result = with_timeout(config.timeout, connect_server(context, &svr_cfg, &addresses, config)).await;
+    let Some(stream) = report.record(CheckStep::Connect, start, result) else {
+        return report;
+    };
+
+    let target = match config.url.host.parse::<IpAddr>() {
+        Ok(ip) => Address::SocketAddress(SocketAddr::new(ip, config.url.port)),
+        Err(..) => Address::DomainNameAddress(config.url.host.clone(), config.url.port),
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nAccept: */*\r\n\r\n",
+        config.url.path,
+        config.url.host_header()
+    );
+
+    // Header of the encrypted request is sent with the HTTP request
+    let stream = ProxyClientStream::from_stream(context.clone(), stream, &svr_cfg, target);
+    let mut reader = BufReader::new(stream);
+
+    let start = Instant::now();
+    let result = with_timeout(config.timeout, async {
+        reader.get_mut().write_all(request.as_bytes()).await?;
+        if reader.fill_buf().await?.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "server closed the connection without responding, check password and method, or if the server could access the URL",
+            ));
+        }
+        Ok(())
+    })
+    .await;
+    if report.record(CheckStep::Handshake, start, result).is_none() {
+        return report;
+    }
+
+    let start = Instant::now();
+    let result = with_timeout(config.timeout, read_response(&mut reader, &config.url)).await;
+    report.status = report.record(CheckStep::Fetch, start, result);
+
+    drop(plugins);
+    report
+}
+
+async fn start_plugins(svr_cfg: &mut ServerConfig) -> io::Result<Vec<Plugin>> {
+    let mut plugins = Plugin::start_chain(svr_cfg.plugins(), svr_cfg.addr(), PluginMode::Client)?;
+    if let Some(plugin) = plugins.first() {
+        svr_cfg.set_plugin_addr(plugin.local_addr().into());
+    }
+
+    for plugin in &mut plugins {
+        let timeout = plugin.config().plugin_supervision.startup_timeout;
+        if !plugin.check_started(timeout).await? {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!(
+                    "plugin \"{}\" isn't listening on {} after {:?}",
+                    plugin.config().plugin,
+                    plugin.local_addr(),
+                    timeout
+                ),
+            ));
+        }
+    }
+
+    Ok(plugins)
+}
+
+async fn connect_server(
+    context: &SharedContext,
+    svr_cfg: &ServerConfig,
+    addresses: &[SocketAddr],
+    config: &CheckConfig,
+) -> io::Result<OutboundTcpStream> {
+    let external_addr = svr_cfg.tcp_external_addr();
+    if external_addr != svr_cfg.addr() {
+        return OutboundTcpStream::connect_server_with_opts(context, external_addr, &config.connect_opts).await;
+    }
+
+    // Connects the resolved addresses in order, they are not resolved again
+    let port = svr_cfg.hopping_addr().map(|addr| addr.port());
+    let mut last_err = None;
+    for addr in addresses {
+        let mut addr = *addr;
+        if let Some(port) = port {
+            addr.set_port(port);
+        }
+
+        match OutboundTcpStream::connect_server_with_opts(context, &ServerAddr::SocketAddr(addr), &config.connect_opts)
+            .await
+        {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(io::Error::new(err.kind(), format!("connect {addr}, {err}"))),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| io::Error::new(ErrorKind::Other, "no addresses to connect")))
+}
+
+async fn read_response<R>(reader: &mut BufReader<R>, url: &BalancerCheckUrl) -> io::Result<u16>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut status_line = Vec::new();
+    reader.read_until(b'\n', &mut status_line).await?;
+
+    let mut headers = [httparse::EMPTY_HEADER; 1];
+    let mut response = httparse::Response::new(&mut headers);
+    let _ = response.parse(&status_line);
+    let status = match response.code {
+        Some(code) => code,
+        None => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unexpected response from {}, {:?}", url, ByteStr::new(&status_line)),
+            ));
+        }
+    };
+
+    // The connection is closed by the target after the response
+    let mut rest = Vec::new();
+    reader.take(MAX_RESPONSE_SIZE).read_to_end(&mut rest).await?;
+
+    if !(200..=299).contains(&status) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{url} responded with status {status}"),
+        ));
+    }
+
+    Ok(status)
+}
+
+async fn with_timeout<F, T>(timeout: Duration, fut: F) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    match time::timeout(timeout, fut).await {
+        Ok(result) => result,
+        Err(..) => Err(io::Error::new(
+            ErrorKind::TimedOut,
+            format!("timed out after {timeout:?}"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format_table_failures() {
+        let ok = CheckReport {
+            server: "127.0.0.1:8388".to_owned(),
+            remarks: None,
+            addresses: vec!["127.0.0.1:8388".parse().unwrap()],
+            steps: vec![
+                CheckStepReport {
+                    step: CheckStep::Dns,
+                    latency_micros: None,
+                    error: None,
+                },
+                CheckStepReport {
+                    step: CheckStep::Connect,
+                    latency_micros: Some(1500),
+                    error: None,
+                },
+                CheckStepReport {
+                    step: CheckStep::Handshake,
+                    latency_micros: Some(20_000),
+                    error: None,
+                },
+                CheckStepReport {
+                    step: CheckStep::Fetch,
+                    latency_micros: Some(250),
+                    error: None,
+                },
+            ],
+            status: Some(204),
+        };
+        let failed = CheckReport {
+            server: "example.com:8388".to_owned(),
+            remarks: Some("backup".to_owned()),
+            addresses: Vec::new(),
+            steps: vec![CheckStepReport {
+                step: CheckStep::Dns,
+                latency_micros: Some(3000),
+                error: Some("no record".to_owned()),
+            }],
+            status: None,
+        };
+        assert!(ok.is_ok());
+        assert!(!failed.is_ok());
+
+        let table = format_table(&[ok, failed]);
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("SERVER"));
+        assert!(lines[1].contains("1.50ms") && lines[1].ends_with("OK"));
+        assert!(lines[2].starts_with("example.com:8388 (backup)") && lines[2].ends_with("FAILED"));
+        assert_eq!(lines[3], "example.com:8388 dns failed: no record");
+    }
+}
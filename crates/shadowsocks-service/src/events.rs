@@ -0,0 +1,214 @@
+//! Service events
+//!
+//! Events happened in services are broadcasted to all subscribers in the same process,
+//! for example, the gRPC `Events` stream of manager and webhooks.
+
+use std::{
+    fmt::{self, Display},
+    net::{IpAddr, SocketAddr},
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events buffered for each subscriber, older events will be dropped for slow subscribers
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+static EVENTS: Lazy<broadcast::Sender<Event>> = Lazy::new(|| broadcast::channel(EVENT_CHANNEL_CAPACITY).0);
+
+/// Event of services
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A server started serving
+    ServerStarted { server: String },
+    /// A server exited
+    ServerStopped { server: String },
+    /// A server was added to manager
+    ServerAdded { server_port: u16 },
+    /// A server was removed from manager
+    ServerRemoved { server_port: u16 },
+    /// A TCP tunnel was established by a server
+    ConnectionOpened {
+        server: String,
+        peer_addr: SocketAddr,
+        target: String,
+    },
+    /// A TCP tunnel of a server was closed
+    ConnectionClosed {
+        server: String,
+        peer_addr: SocketAddr,
+        target: String,
+        /// Bytes sent by client
+        rx: u64,
+        /// Bytes sent to client
+        tx: u64,
+    },
+    /// Repeated nonces (iv/salt) were detected by a server
+    ReplayDetected {
+        server: String,
+        /// Detected since the previous event
+        count: u64,
+        /// Detected since the server started
+        total: u64,
+    },
+    /// Requests rejected by the replay filter were received from a client
+    ///
+    /// Published on the 1st, 2nd, 4th, 8th... replay of the client.
+    ReplaySource {
+        server: String,
+        peer_ip: IpAddr,
+        /// Replays recorded from the client
+        count: u64,
+    },
+    /// Traffic quota of a server, or one of its users was exceeded
+    QuotaExceeded {
+        server: String,
+        /// `None` for the quota of the whole server
+        user: Option<String>,
+    },
+    /// A client failed TCP handshakes repeatedly
+    HandshakeFailures {
+        server: String,
+        peer_ip: IpAddr,
+        /// Failures in the recent window
+        count: u32,
+    },
+    /// A client was banned for failing TCP handshakes repeatedly
+    ClientBanned {
+        server: String,
+        peer_ip: IpAddr,
+        /// Duration of the ban in seconds
+        duration: u64,
+    },
+    /// Plugin subprocess of a server exited
+    PluginExited {
+        server: String,
+        plugin: String,
+        status: String,
+    },
+    /// Balancer switched to another server
+    BalancerSwitched {
+        /// `tcp` or `udp`
+        protocol: &'static str,
+        from: String,
+        to: String,
+    },
+}
+
+impl Event {
+    /// Name of this kind of event, which is the `event` field of its JSON form
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Event::ServerStarted { .. } => "server_started",
+            Event::ServerStopped { .. } => "server_stopped",
+            Event::ServerAdded { .. } => "server_added",
+            Event::ServerRemoved { .. } => "server_removed",
+            Event::ConnectionOpened { .. } => "connection_opened",
+            Event::ConnectionClosed { .. } => "connection_closed",
+            Event::ReplayDetected { .. } => "replay_detected",
+            Event::ReplaySource { .. } => "replay_source",
+            Event::QuotaExceeded { .. } => "quota_exceeded",
+            Event::HandshakeFailures { .. } => "handshake_failures",
+            Event::ClientBanned { .. } => "client_banned",
+            Event::PluginExited { .. } => "plugin_exited",
+            Event::BalancerSwitched { .. } => "balancer_switched",
+        }
+    }
+}
+
+/// Names of all kinds of events
+pub const EVENT_KINDS: &[&str] = &[
+    "server_started",
+    "server_stopped",
+    "server_added",
+    "server_removed",
+    "connection_opened",
+    "connection_closed",
+    "replay_detected",
+    "replay_source",
+    "quota_exceeded",
+    "handshake_failures",
+    "client_banned",
+    "plugin_exited",
+    "balancer_switched",
+];
+
+impl Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Event::ServerStarted { ref server } => write!(f, "server {server} started"),
+            Event::ServerStopped { ref server } => write!(f, "server {server} stopped"),
+            Event::ServerAdded { server_port } => write!(f, "server port {server_port} added"),
+            Event::ServerRemoved { server_port } => write!(f, "server port {server_port} removed"),
+            Event::ConnectionOpened {
+                ref server,
+                ref peer_addr,
+                ref target,
+            } => write!(f, "server {server} tunnel {peer_addr} <-> {target} opened"),
+            Event::ConnectionClosed {
+                ref server,
+                ref peer_addr,
+                ref target,
+                rx,
+                tx,
+            } => write!(
+                f,
+                "server {server} tunnel {peer_addr} <-> {target} closed, L2R {rx} bytes, R2L {tx} bytes"
+            ),
+            Event::ReplayDetected {
+                ref server,
+                count,
+                total,
+            } => write!(f, "server {server} detected {count} repeated nonces, {total} in total"),
+            Event::ReplaySource {
+                ref server,
+                ref peer_ip,
+                count,
+            } => write!(f, "server {server} received {count} replayed requests from {peer_ip}"),
+            Event::QuotaExceeded { ref server, user: None } => write!(f, "server {server} traffic quota exceeded"),
+            Event::QuotaExceeded {
+                ref server,
+                user: Some(ref user),
+            } => write!(f, "server {server} user {user} traffic quota exceeded"),
+            Event::HandshakeFailures {
+                ref server,
+                ref peer_ip,
+                count,
+            } => write!(f, "server {server} client {peer_ip} failed {count} handshakes"),
+            Event::ClientBanned {
+                ref server,
+                ref peer_ip,
+                duration,
+            } => write!(f, "server {server} client {peer_ip} banned for {duration}s"),
+            Event::PluginExited {
+                ref server,
+                ref plugin,
+                ref status,
+            } => write!(f, "server {server} plugin {plugin} exited, {status}"),
+            Event::BalancerSwitched {
+                protocol,
+                ref from,
+                ref to,
+            } => write!(f, "{protocol} balancer switched from {from} to {to}"),
+        }
+    }
+}
+
+/// Subscribe to events published after this call
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    EVENTS.subscribe()
+}
+
+/// Publish an event
+///
+/// `f` won't be called if there is no subscribers.
+pub fn publish<F>(f: F)
+where
+    F: FnOnce() -> Event,
+{
+    if EVENTS.receiver_count() > 0 {
+        let _ = EVENTS.send(f());
+    }
+}
@@ -0,0 +1,638 @@
+//! NetFlow v9 / IPFIX export of relayed flows
+//!
+//! Completed relays are exported to a [NetFlow v9](https://www.rfc-editor.org/rfc/rfc3954) or
+//! [IPFIX](https://www.rfc-editor.org/rfc/rfc7011) collector by UDP, as unidirectional flow records. A TCP relay is
+//! exported when it is closed, as two records: client to target with bytes sent by the client, and target to client
+//! with bytes sent to the client. A UDP association is exported when it is closed, as two records for each target
+//! that the client sent packets to or received packets from.
+//!
+//! Records have these fields (IPFIX Information Elements / NetFlow v9 field types):
+//!
+//! - `sourceIPv4Address` / `sourceIPv6Address`, `sourceTransportPort`
+//! - `destinationIPv4Address` / `destinationIPv6Address`, `destinationTransportPort`
+//! - `protocolIdentifier`, TCP (6) or UDP (17)
+//! - `octetDeltaCount`, `packetDeltaCount`
+//! - `flowStartMilliseconds`, `flowEndMilliseconds` of IPFIX, or `FIRST_SWITCHED`, `LAST_SWITCHED` of NetFlow v9
+//!
+//! TCP relays don't see segments on the wire, so packets of TCP relays are the reads and writes of the relayed
+//! stream. Targets of domain names are exported with the address actually connected if it is known, otherwise with
+//! an unspecified address.
+//!
+//! Templates are sent with the first message, and again every 60 seconds as RFC 7011 requires for UDP.
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    pin::Pin,
+    sync::Mutex,
+    task::{self, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{BufMut, BytesMut};
+use log::{debug, error};
+use once_cell::sync::OnceCell;
+use shadowsocks::relay::Address;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::config::{FlowExportConfig, FlowExportVersion};
+
+const NETFLOW_V9_VERSION: u16 = 9;
+const NETFLOW_V9_TEMPLATE_FLOWSET_ID: u16 = 0;
+const IPFIX_VERSION: u16 = 10;
+const IPFIX_TEMPLATE_SET_ID: u16 = 2;
+const IPV4_TEMPLATE_ID: u16 = 256;
+const IPV6_TEMPLATE_ID: u16 = 257;
+const TEMPLATE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+// UDP flows kept by an association, all of them are exported early if there are more
+const MAX_UDP_FLOWS: usize = 1024;
+
+// (field type, length) of templates, IPFIX Information Element IDs are the same as NetFlow v9 field types
+const IPV4_FIELDS: [(u16, u16); 7] = [
+    (8, 4),  // sourceIPv4Address, IPV4_SRC_ADDR
+    (7, 2),  // sourceTransportPort, L4_SRC_PORT
+    (12, 4), // destinationIPv4Address, IPV4_DST_ADDR
+    (11, 2), // destinationTransportPort, L4_DST_PORT
+    (4, 1),  // protocolIdentifier, PROTOCOL
+    (1, 8),  // octetDeltaCount, IN_BYTES
+    (2, 8),  // packetDeltaCount, IN_PKTS
+];
+const IPV6_FIELDS: [(u16, u16); 7] = [
+    (27, 16), // sourceIPv6Address, IPV6_SRC_ADDR
+    (7, 2),   // sourceTransportPort, L4_SRC_PORT
+    (28, 16), // destinationIPv6Address, IPV6_DST_ADDR
+    (11, 2),  // destinationTransportPort, L4_DST_PORT
+    (4, 1),   // protocolIdentifier, PROTOCOL
+    (1, 8),   // octetDeltaCount, IN_BYTES
+    (2, 8),   // packetDeltaCount, IN_PKTS
+];
+// Milliseconds since UNIX epoch
+const IPFIX_TIME_FIELDS: [(u16, u16); 2] = [
+    (152, 8), // flowStartMilliseconds
+    (153, 8), // flowEndMilliseconds
+];
+// Milliseconds of the exporter's uptime
+const NETFLOW_V9_TIME_FIELDS: [(u16, u16); 2] = [
+    (22, 4), // FIRST_SWITCHED
+    (21, 4), // LAST_SWITCHED
+];
+
+struct ExporterState {
+    // Number of data records sent for IPFIX, or number of messages sent for NetFlow v9
+    sequence: u32,
+    template_sent: Option<Instant>,
+}
+
+struct FlowExporter {
+    socket: UdpSocket,
+    version: FlowExportVersion,
+    observation_domain: u32,
+    started: Instant,
+    state: Mutex<ExporterState>,
+}
+
+static FLOW_EXPORTER: OnceCell<FlowExporter> = OnceCell::new();
+
+/// Enable flow export, only the first call takes effect
+pub fn init(config: &FlowExportConfig) -> io::Result<()> {
+    let bind_addr: SocketAddr = if config.collector.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.connect(config.collector)?;
+    // Never blocks relays, records are dropped if the socket buffer is full
+    socket.set_nonblocking(true)?;
+
+    let _ = FLOW_EXPORTER.set(FlowExporter {
+        socket,
+        version: config.version,
+        observation_domain: config.observation_domain,
+        started: Instant::now(),
+        state: Mutex::new(ExporterState {
+            sequence: 0,
+            template_sent: None,
+        }),
+    });
+    Ok(())
+}
+
+/// Check if flow export is enabled
+pub fn is_enabled() -> bool {
+    FLOW_EXPORTER.get().is_some()
+}
+
+/// Transport protocol of a flow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowProtocol {
+    Tcp,
+    Udp,
+}
+
+impl FlowProtocol {
+    fn protocol_identifier(self) -> u8 {
+        match self {
+            FlowProtocol::Tcp => 6,
+            FlowProtocol::Udp => 17,
+        }
+    }
+}
+
+/// A completed relay
+pub struct FlowRecord<'a> {
+    /// Transport protocol relayed
+    pub protocol: FlowProtocol,
+    /// Address of the client
+    pub client: SocketAddr,
+    /// Target address requested by the client
+    pub target: &'a Address,
+    /// Address actually connected, used if `target` is a domain name
+    pub remote: Option<SocketAddr>,
+    /// Bytes sent by the client
+    pub up: u64,
+    /// Packets sent by the client
+    pub up_packets: u64,
+    /// Bytes sent to the client
+    pub down: u64,
+    /// Packets sent to the client
+    pub down_packets: u64,
+    /// Duration from the first packet to the last packet of the relay
+    pub duration: Duration,
+    /// Time since the last packet of the relay
+    pub idle: Duration,
+}
+
+/// Export a completed relay, `f` won't be called if export is not enabled
+pub fn record<'a, F>(f: F)
+where
+    F: FnOnce() -> FlowRecord<'a>,
+{
+    let exporter = match FLOW_EXPORTER.get() {
+        Some(e) => e,
+        None => return,
+    };
+
+    let record = f();
+    let clock = ExportClock {
+        now: SystemTime::now(),
+        uptime: exporter.started.elapsed(),
+    };
+
+    let message = {
+        let mut state = exporter.state.lock().unwrap();
+
+        let with_template = match state.template_sent {
+            Some(sent) => sent.elapsed() >= TEMPLATE_REFRESH_INTERVAL,
+            None => true,
+        };
+        if with_template {
+            state.template_sent = Some(Instant::now());
+        }
+
+        let message = encode_message(
+            exporter.version,
+            &record,
+            clock,
+            state.sequence,
+            exporter.observation_domain,
+            with_template,
+        );
+        state.sequence = match exporter.version {
+            FlowExportVersion::NetFlowV9 => state.sequence.wrapping_add(1),
+            FlowExportVersion::Ipfix => state.sequence.wrapping_add(2),
+        };
+        message
+    };
+
+    if let Err(err) = exporter.socket.send(&message) {
+        if err.kind() == ErrorKind::WouldBlock {
+            debug!("flow export dropped a record, socket buffer is full");
+        } else {
+            error!("failed to export flow record, error: {}", err);
+        }
+    }
+}
+
+/// Stream of a TCP relay counting packets, each non-empty read or write is a packet
+pub struct PacketCounter<S> {
+    stream: S,
+    read_packets: u64,
+    write_packets: u64,
+}
+
+impl<S> PacketCounter<S> {
+    pub fn new(stream: S) -> PacketCounter<S> {
+        PacketCounter {
+            stream,
+            read_packets: 0,
+            write_packets: 0,
+        }
+    }
+
+    /// Packets read from the stream
+    pub fn read_packets(&self) -> u64 {
+        self.read_packets
+    }
+
+    /// Packets written to the stream
+    pub fn write_packets(&self) -> u64 {
+        self.write_packets
+    }
+}
+
+impl<S> AsyncRead for PacketCounter<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(..)) = result {
+            if buf.filled().len() > filled {
+                this.read_packets += 1;
+            }
+        }
+        result
+    }
+}
+
+impl<S> AsyncWrite for PacketCounter<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 {
+                this.write_packets += 1;
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+struct UdpFlow {
+    first: Instant,
+    last: Instant,
+    up: u64,
+    up_packets: u64,
+    down: u64,
+    down_packets: u64,
+}
+
+/// UDP flows of an association, keyed by target addresses
+///
+/// Packets are counted only if export is enabled, and flows are exported by [`UdpFlows::export`] when the
+/// association is closed.
+#[derive(Default)]
+pub struct UdpFlows {
+    flows: HashMap<Address, UdpFlow>,
+}
+
+impl UdpFlows {
+    pub fn new() -> UdpFlows {
+        UdpFlows::default()
+    }
+
+    /// Count a packet of `n` bytes sent by `client` to `target`
+    pub fn sent(&mut self, client: SocketAddr, target: &Address, n: usize) {
+        if let Some(flow) = self.flow_mut(client, target) {
+            flow.up += n as u64;
+            flow.up_packets += 1;
+        }
+    }
+
+    /// Count a packet of `n` bytes sent to `client` from `target`
+    pub fn received(&mut self, client: SocketAddr, target: &Address, n: usize) {
+        if let Some(flow) = self.flow_mut(client, target) {
+            flow.down += n as u64;
+            flow.down_packets += 1;
+        }
+    }
+
+    fn flow_mut(&mut self, client: SocketAddr, target: &Address) -> Option<&mut UdpFlow> {
+        if !is_enabled() {
+            return None;
+        }
+
+        let now = Instant::now();
+        if !self.flows.contains_key(target) {
+            if self.flows.len() >= MAX_UDP_FLOWS {
+                self.export(client);
+            }
+            self.flows.insert(
+                target.clone(),
+                UdpFlow {
+                    first: now,
+                    last: now,
+                    up: 0,
+                    up_packets: 0,
+                    down: 0,
+                    down_packets: 0,
+                },
+            );
+        }
+
+        let flow = self.flows.get_mut(target).expect("flow inserted");
+        flow.last = now;
+        Some(flow)
+    }
+
+    /// Export and clear all flows of `client`
+    pub fn export(&mut self, client: SocketAddr) {
+        for (target, flow) in self.flows.drain() {
+            record(|| FlowRecord {
+                protocol: FlowProtocol::Udp,
+                client,
+                target: &target,
+                remote: None,
+                up: flow.up,
+                up_packets: flow.up_packets,
+                down: flow.down,
+                down_packets: flow.down_packets,
+                duration: flow.last - flow.first,
+                idle: flow.last.elapsed(),
+            });
+        }
+    }
+}
+
+// Time of the exporter when a message is encoded
+#[derive(Clone, Copy)]
+struct ExportClock {
+    now: SystemTime,
+    uptime: Duration,
+}
+
+fn unix_millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+fn template_fields(
+    version: FlowExportVersion,
+    fields: &'static [(u16, u16); 7],
+) -> impl Iterator<Item = &'static (u16, u16)> {
+    let time_fields = match version {
+        FlowExportVersion::NetFlowV9 => &NETFLOW_V9_TIME_FIELDS,
+        FlowExportVersion::Ipfix => &IPFIX_TIME_FIELDS,
+    };
+    fields.iter().chain(time_fields.iter())
+}
+
+fn encode_message(
+    version: FlowExportVersion,
+    record: &FlowRecord<'_>,
+    clock: ExportClock,
+    sequence: u32,
+    observation_domain: u32,
+    with_template: bool,
+) -> BytesMut {
+    let client = SocketAddr::new(record.client.ip().to_canonical(), record.client.port());
+    let target = match *record.target {
+        Address::SocketAddress(sa) => SocketAddr::new(sa.ip().to_canonical(), sa.port()),
+        Address::DomainNameAddress(_, port) => match record.remote {
+            Some(remote) => SocketAddr::new(remote.ip().to_canonical(), remote.port()),
+            None => {
+                let unspecified = match client {
+                    SocketAddr::V4(..) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    SocketAddr::V6(..) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                };
+                SocketAddr::new(unspecified, port)
+            }
+        },
+    };
+
+    let mut buf = BytesMut::with_capacity(256);
+
+    // Message Header, length (IPFIX) or count (NetFlow v9) is filled at last
+    let now_secs = (unix_millis(clock.now) / 1000) as u32;
+    match version {
+        FlowExportVersion::NetFlowV9 => {
+            buf.put_u16(NETFLOW_V9_VERSION);
+            buf.put_u16(0);
+            buf.put_u32(clock.uptime.as_millis() as u32);
+            buf.put_u32(now_secs);
+            buf.put_u32(sequence);
+            buf.put_u32(observation_domain);
+        }
+        FlowExportVersion::Ipfix => {
+            buf.put_u16(IPFIX_VERSION);
+            buf.put_u16(0);
+            buf.put_u32(now_secs);
+            buf.put_u32(sequence);
+            buf.put_u32(observation_domain);
+        }
+    }
+
+    let mut record_count = 0u16;
+
+    if with_template {
+        let set_start = buf.len();
+        buf.put_u16(match version {
+            FlowExportVersion::NetFlowV9 => NETFLOW_V9_TEMPLATE_FLOWSET_ID,
+            FlowExportVersion::Ipfix => IPFIX_TEMPLATE_SET_ID,
+        });
+        buf.put_u16(0);
+        for (template_id, fields) in [(IPV4_TEMPLATE_ID, &IPV4_FIELDS), (IPV6_TEMPLATE_ID, &IPV6_FIELDS)] {
+            buf.put_u16(template_id);
+            buf.put_u16((fields.len() + 2) as u16);
+            for &(id, len) in template_fields(version, fields) {
+                buf.put_u16(id);
+                buf.put_u16(len);
+            }
+            record_count += 1;
+        }
+        let set_len = (buf.len() - set_start) as u16;
+        buf[set_start + 2..set_start + 4].copy_from_slice(&set_len.to_be_bytes());
+    }
+
+    let is_ipv4 = client.is_ipv4() && target.is_ipv4();
+
+    let set_start = buf.len();
+    buf.put_u16(if is_ipv4 { IPV4_TEMPLATE_ID } else { IPV6_TEMPLATE_ID });
+    buf.put_u16(0);
+    for (src, dst, octets, packets) in [
+        (client, target, record.up, record.up_packets),
+        (target, client, record.down, record.down_packets),
+    ] {
+        put_ip(&mut buf, src.ip(), is_ipv4);
+        buf.put_u16(src.port());
+        put_ip(&mut buf, dst.ip(), is_ipv4);
+        buf.put_u16(dst.port());
+        buf.put_u8(record.protocol.protocol_identifier());
+        buf.put_u64(octets);
+        buf.put_u64(packets);
+        match version {
+            FlowExportVersion::NetFlowV9 => {
+                let end = clock.uptime.saturating_sub(record.idle);
+                let start = end.saturating_sub(record.duration);
+                buf.put_u32(start.as_millis() as u32);
+                buf.put_u32(end.as_millis() as u32);
+            }
+            FlowExportVersion::Ipfix => {
+                let end = unix_millis(clock.now).saturating_sub(record.idle.as_millis() as u64);
+                let start = end.saturating_sub(record.duration.as_millis() as u64);
+                buf.put_u64(start);
+                buf.put_u64(end);
+            }
+        }
+        record_count += 1;
+    }
+    // FlowSets of NetFlow v9 are padded to 4 bytes boundary
+    if version == FlowExportVersion::NetFlowV9 {
+        while (buf.len() - set_start) % 4 != 0 {
+            buf.put_u8(0);
+        }
+    }
+    let set_len = (buf.len() - set_start) as u16;
+    buf[set_start + 2..set_start + 4].copy_from_slice(&set_len.to_be_bytes());
+
+    let header_field = match version {
+        FlowExportVersion::NetFlowV9 => record_count,
+        FlowExportVersion::Ipfix => buf.len() as u16,
+    };
+    buf[2..4].copy_from_slice(&header_field.to_be_bytes());
+    buf
+}
+
+fn put_ip(buf: &mut BytesMut, ip: IpAddr, is_ipv4: bool) {
+    match ip {
+        IpAddr::V4(ip) if is_ipv4 => buf.put_slice(&ip.octets()),
+        IpAddr::V4(ip) => buf.put_slice(&ip.to_ipv6_mapped().octets()),
+        IpAddr::V6(ip) => buf.put_slice(&ip.octets()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_clock() -> ExportClock {
+        ExportClock {
+            now: UNIX_EPOCH + Duration::from_millis(1_700_000_000_000),
+            uptime: Duration::from_secs(3600),
+        }
+    }
+
+    fn test_record(target: &Address, protocol: FlowProtocol) -> FlowRecord<'_> {
+        FlowRecord {
+            protocol,
+            client: "[::ffff:127.0.0.1]:50124".parse().unwrap(),
+            target,
+            remote: None,
+            up: 1024,
+            up_packets: 3,
+            down: 40960,
+            down_packets: 30,
+            duration: Duration::from_millis(1500),
+            idle: Duration::from_millis(500),
+        }
+    }
+
+    #[test]
+    fn encode_ipfix_ipv4_records() {
+        let target = Address::SocketAddress("93.184.216.34:443".parse().unwrap());
+        let record = test_record(&target, FlowProtocol::Tcp);
+
+        let message = encode_message(FlowExportVersion::Ipfix, &record, test_clock(), 7, 1, false);
+        // Header, set header, 2 records of 45 bytes
+        assert_eq!(message.len(), 16 + 4 + 2 * 45);
+        assert_eq!(&message[0..4], &[0, 10, 0, 110]);
+        assert_eq!(&message[4..8], &1_700_000_000u32.to_be_bytes());
+        assert_eq!(&message[8..12], &7u32.to_be_bytes());
+        assert_eq!(&message[16..20], &[1, 0, 0, 94]);
+        // client -> target
+        assert_eq!(&message[20..26], &[127, 0, 0, 1, 0xc3, 0xcc]);
+        assert_eq!(&message[26..32], &[93, 184, 216, 34, 0x01, 0xbb]);
+        assert_eq!(message[32], 6);
+        assert_eq!(&message[33..41], &1024u64.to_be_bytes());
+        assert_eq!(&message[41..49], &3u64.to_be_bytes());
+        assert_eq!(&message[49..57], &1_699_999_998_000u64.to_be_bytes());
+        assert_eq!(&message[57..65], &1_699_999_999_500u64.to_be_bytes());
+        // target -> client
+        assert_eq!(&message[65..71], &[93, 184, 216, 34, 0x01, 0xbb]);
+        assert_eq!(&message[78..86], &40960u64.to_be_bytes());
+        assert_eq!(&message[86..94], &30u64.to_be_bytes());
+
+        let message = encode_message(FlowExportVersion::Ipfix, &record, test_clock(), 0, 1, true);
+        // Template set has 2 templates of 9 fields
+        assert_eq!(message.len(), 16 + (4 + 2 * (4 + 9 * 4)) + 4 + 2 * 45);
+        assert_eq!(&message[16..20], &[0, 2, 0, 84]);
+    }
+
+    #[test]
+    fn encode_netflow_v9_ipv4_records() {
+        let target = Address::SocketAddress("8.8.8.8:53".parse().unwrap());
+        let record = test_record(&target, FlowProtocol::Udp);
+
+        let message = encode_message(FlowExportVersion::NetFlowV9, &record, test_clock(), 7, 1, false);
+        // Header, FlowSet header, 2 records of 37 bytes, padded to 4 bytes
+        assert_eq!(message.len(), 20 + 4 + 2 * 37 + 2);
+        // Version and count of records
+        assert_eq!(&message[0..4], &[0, 9, 0, 2]);
+        assert_eq!(&message[4..8], &3_600_000u32.to_be_bytes());
+        assert_eq!(&message[8..12], &1_700_000_000u32.to_be_bytes());
+        assert_eq!(&message[12..16], &7u32.to_be_bytes());
+        assert_eq!(&message[16..20], &1u32.to_be_bytes());
+        assert_eq!(&message[20..24], &[1, 0, 0, 80]);
+        // client -> target
+        assert_eq!(&message[24..30], &[127, 0, 0, 1, 0xc3, 0xcc]);
+        assert_eq!(&message[30..36], &[8, 8, 8, 8, 0, 53]);
+        assert_eq!(message[36], 17);
+        assert_eq!(&message[37..45], &1024u64.to_be_bytes());
+        assert_eq!(&message[45..53], &3u64.to_be_bytes());
+        assert_eq!(&message[53..57], &3_598_000u32.to_be_bytes());
+        assert_eq!(&message[57..61], &3_599_500u32.to_be_bytes());
+        assert_eq!(&message[98..100], &[0, 0]);
+
+        let message = encode_message(FlowExportVersion::NetFlowV9, &record, test_clock(), 0, 1, true);
+        // 2 templates and 2 data records
+        assert_eq!(&message[0..4], &[0, 9, 0, 4]);
+        assert_eq!(&message[20..24], &[0, 0, 0, 84]);
+        assert_eq!(message.len(), 20 + 84 + 4 + 2 * 37 + 2);
+    }
+
+    #[test]
+    fn encode_ipv6_records() {
+        let target = Address::SocketAddress("[2001:db8::1]:443".parse().unwrap());
+        let record = test_record(&target, FlowProtocol::Tcp);
+
+        let message = encode_message(FlowExportVersion::Ipfix, &record, test_clock(), 0, 1, false);
+        // IPv4 client is exported as IPv4-mapped IPv6 address
+        assert_eq!(&message[16..18], &IPV6_TEMPLATE_ID.to_be_bytes());
+        assert_eq!(&message[20..36], &Ipv4Addr::LOCALHOST.to_ipv6_mapped().octets());
+        assert_eq!(message.len(), 16 + 4 + 2 * 69);
+    }
+
+    #[tokio::test]
+    async fn packet_counter() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, server) = tokio::io::duplex(64);
+        let mut server = PacketCounter::new(server);
+        let mut client = client;
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(server.read(&mut buf).await.unwrap(), 5);
+        server.write_all(b"world").await.unwrap();
+        server.write_all(b"!").await.unwrap();
+        drop(client);
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+
+        assert_eq!(server.read_packets(), 1);
+        assert_eq!(server.write_packets(), 2);
+    }
+}
@@ -0,0 +1,197 @@
+//! Control handle of services embedded in applications
+//!
+//! [`start_local`] and [`start_server`] bind all listeners of the service and spawn it on the current runtime,
+//! returning a [`ServiceHandle`] that could wait for, shutdown, or reload it with another configuration, instead of
+//! awaiting [`run_local`](crate::run_local) or [`run_server`](crate::run_server) which never returns.
+//!
+//! ```no_run
+//! # async fn example(config: shadowsocks_service::config::Config) -> std::io::Result<()> {
+//! let mut handle = shadowsocks_service::start_local(config).await?;
+//! println!("{:?}", handle.stats());
+//! handle.shutdown().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Services are shutdown with [`Config::cancellation_token`], which stops all of their connections, applications
+//! could also cancel it (or a clone of [`ServiceHandle::cancellation_token`]) for tying services to their own lifetimes.
+
+use std::{
+    io::{self, ErrorKind},
+    time::Duration,
+};
+
+use log::{trace, warn};
+use shadowsocks::context::CancellationToken;
+use tokio::{task::JoinHandle, time};
+
+use crate::{
+    config::{Config, ConfigType},
+    stats::{self, StatsSnapshot},
+};
+
+// Listeners of the stopped service are closed asynchronously after its tasks are aborted
+const REBIND_RETRY_TIMES: usize = 10;
+const REBIND_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+// Services are aborted if they couldn't exit in time after cancelled
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Handle of a service running in background
+///
+/// The service is aborted and its cancellation token is cancelled when the handle is dropped.
+pub struct ServiceHandle {
+    config_type: ConfigType,
+    cancellation_token: CancellationToken,
+    task: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl ServiceHandle {
+    /// Type of the running service
+    pub fn config_type(&self) -> ConfigType {
+        self.config_type
+    }
+
+    /// Token for shutting down the service
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancellation_token
+    }
+
+    /// Check if the service has exited
+    pub fn is_finished(&self) -> bool {
+        match self.task {
+            Some(ref task) => task.is_finished(),
+            None => true,
+        }
+    }
+
+    /// Wait until the service exits, returns its result
+    ///
+    /// Returns `Ok(())` immediately if the service has already exited, or it was shutdown.
+    pub async fn wait(&mut self) -> io::Result<()> {
+        let task = match self.task {
+            Some(ref mut task) => task,
+            None => return Ok(()),
+        };
+
+        let result = task.await;
+        self.task = None;
+
+        match result {
+            Ok(res) => res,
+            Err(err) if err.is_cancelled() => Ok(()),
+            Err(err) => Err(io::Error::new(ErrorKind::Other, err)),
+        }
+    }
+
+    /// Shutdown the service, and wait until it exits
+    ///
+    /// The service is aborted if it doesn't exit in 10 seconds. Returns the error of the service if it has already
+    /// exited with one.
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.cancellation_token.cancel();
+
+        match time::timeout(SHUTDOWN_TIMEOUT, self.wait()).await {
+            Ok(result) => result,
+            Err(..) => {
+                warn!(
+                    "{:?} service didn't exit in {:?} after shutdown, aborting",
+                    self.config_type, SHUTDOWN_TIMEOUT
+                );
+                if let Some(ref task) = self.task {
+                    task.abort();
+                }
+                self.wait().await
+            }
+        }
+    }
+
+    /// Shutdown the service, and start it again with `config`
+    ///
+    /// `config.cancellation_token` is replaced with a new one if it was cancelled, for example it is shared with the
+    /// service being shutdown. The handle is left without any running service if `config` couldn't be started.
+    pub async fn reload(&mut self, mut config: Config) -> io::Result<()> {
+        if let Err(err) = self.shutdown().await {
+            warn!(
+                "{:?} service exited with error before reloading, error: {}",
+                self.config_type, err
+            );
+        }
+
+        if config.cancellation_token.is_cancelled() {
+            config.cancellation_token = CancellationToken::new();
+        }
+
+        let mut retry_times = 0;
+        loop {
+            match start(config.clone()).await {
+                Ok(handle) => {
+                    *self = handle;
+                    return Ok(());
+                }
+                Err(err) if err.kind() == ErrorKind::AddrInUse && retry_times < REBIND_RETRY_TIMES => {
+                    trace!("listeners of the stopped service are not closed yet, error: {}", err);
+                    retry_times += 1;
+                    time::sleep(REBIND_RETRY_INTERVAL).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Snapshot of statistics
+    ///
+    /// Statistics are collected from all services running in the current process.
+    pub fn stats(&self) -> StatsSnapshot {
+        stats::snapshot()
+    }
+}
+
+impl Drop for ServiceHandle {
+    fn drop(&mut self) {
+        self.cancellation_token.cancel();
+        if let Some(ref task) = self.task {
+            task.abort();
+        }
+    }
+}
+
+async fn start(config: Config) -> io::Result<ServiceHandle> {
+    match config.config_type {
+        #[cfg(feature = "local")]
+        ConfigType::Local => start_local(config).await,
+        #[cfg(feature = "server")]
+        ConfigType::Server => start_server(config).await,
+        config_type => Err(io::Error::new(
+            ErrorKind::Other,
+            format!("{config_type:?} service couldn't be started with a handle"),
+        )),
+    }
+}
+
+/// Bind all listeners of a local server with `config`, and run it in background
+#[cfg(feature = "local")]
+pub async fn start_local(config: Config) -> io::Result<ServiceHandle> {
+    let config_type = config.config_type;
+    let cancellation_token = config.cancellation_token.clone();
+    let server = crate::local::Server::new(config).await?;
+
+    Ok(ServiceHandle {
+        config_type,
+        cancellation_token,
+        task: Some(tokio::spawn(server.run())),
+    })
+}
+
+/// Bind all listeners of a server with `config`, and run it in background
+#[cfg(feature = "server")]
+pub async fn start_server(config: Config) -> io::Result<ServiceHandle> {
+    let config_type = config.config_type;
+    let cancellation_token = config.cancellation_token.clone();
+    let server = crate::server::start(config).await?;
+
+    Ok(ServiceHandle {
+        config_type,
+        cancellation_token,
+        task: Some(tokio::spawn(server)),
+    })
+}
@@ -0,0 +1,121 @@
+//! Listener handover for upgrading binaries without closing listeners
+//!
+//! 1. The new process connects to the handover socket of the old process, and receives its inbound listeners.
+//! 2. Servers of the new process take the listeners received instead of binding new ones, and then the new process
+//!    listens on the handover socket for the next upgrade.
+//! 3. The old process stops accepting after listeners are sent, and exits after its connections are finished, or
+//!    after `drain_timeout`.
+//!
+//! Listeners of plugins are not handed over, plugins are restarted by the new process. UDP associations of the old
+//! process are closed when it stops accepting.
+
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    os::unix::{fs::PermissionsExt, net::UnixStream as StdUnixStream},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+use shadowsocks::net::handover;
+use tokio::{net::UnixListener, task, time};
+
+use crate::{config::HandoverConfig, net::ConnectionStat};
+
+/// Receive listeners from the process listening on `config.path`
+///
+/// Returns the number of listeners received, 0 if there is no process listening.
+pub fn take_over(config: &HandoverConfig) -> io::Result<usize> {
+    let stream = match StdUnixStream::connect(&config.path) {
+        Ok(s) => s,
+        // Not started, or the socket is left by a process exited
+        Err(err) if matches!(err.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+    let count = handover::receive_listeners(&stream)?;
+    info!(
+        "took over {} listeners from the process listening on {}",
+        count,
+        config.path.display()
+    );
+    Ok(count)
+}
+
+/// Handover socket, waiting for the next process
+pub struct HandoverListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl HandoverListener {
+    /// Listen on `config.path`, must be called after servers are bound
+    pub fn bind(config: &HandoverConfig) -> io::Result<HandoverListener> {
+        let unused = handover::close_unused_listeners();
+        if unused > 0 {
+            warn!("closed {} listeners taken over but not used by any servers", unused);
+        }
+
+        // The old process is still listening on it, but it won't accept anymore
+        match fs::remove_file(&config.path) {
+            Ok(..) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        let listener = UnixListener::bind(&config.path)?;
+        // Only processes of the same user could take over listeners, peers are also checked when handing over
+        fs::set_permissions(&config.path, fs::Permissions::from_mode(0o600))?;
+
+        Ok(HandoverListener {
+            listener,
+            path: config.path.clone(),
+        })
+    }
+
+    /// Wait until listeners are sent to a new process
+    pub async fn wait_for_successor(self) -> io::Result<()> {
+        info!("shadowsocks handover listening on {}", self.path.display());
+
+        loop {
+            let (stream, _) = match self.listener.accept().await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("handover accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let stream = stream.into_std()?;
+            stream.set_nonblocking(false)?;
+
+            match task::spawn_blocking(move || handover::send_listeners(&stream)).await? {
+                Ok(count) => {
+                    info!("handed over {} listeners to the new process", count);
+                    return Ok(());
+                }
+                // The new process may be exited, keep serving
+                Err(err) => error!("failed to hand over listeners, error: {}", err),
+            }
+        }
+    }
+}
+
+/// Wait until all TCP connections are finished, or `timeout`
+pub async fn drain(connection_stats: &[Arc<ConnectionStat>], timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let connections: usize = connection_stats.iter().map(|s| s.tcp_connections()).sum();
+        if connections == 0 {
+            info!("all connections are finished after handover");
+            return;
+        }
+        if Instant::now() >= deadline {
+            warn!("{} connections are still active after handover, closing", connections);
+            return;
+        }
+        time::sleep(Duration::from_secs(1)).await;
+    }
+}
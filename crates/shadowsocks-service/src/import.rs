@@ -0,0 +1,162 @@
+//! Importing servers shared as URLs
+//!
+//! Servers are usually shared as SIP002 `ss://` URLs, pasted one per line, or as base64 encoded subscription bodies
+//! of such lines. [`parse_server_urls`] accepts both, and [`merge_servers`] adds the parsed servers to a [`Config`].
+
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD},
+};
+use shadowsocks::config::ServerConfig;
+
+use crate::config::{Config, ServerInstanceConfig};
+
+/// Servers parsed by [`parse_server_urls`]
+#[derive(Debug, Clone, Default)]
+pub struct ImportedServers {
+    /// Servers, in the same order as their URLs
+    pub servers: Vec<ServerConfig>,
+    /// Lines that are not valid `ss://` URLs, with the reasons
+    pub skipped: Vec<(String, String)>,
+}
+
+/// Parse servers from `text`, `ss://` URLs separated by whitespaces, or a base64 encoded body of them
+///
+/// Blank lines and lines starting with `#` or `//` are ignored. URLs of other schemes, like `vmess://` in mixed
+/// subscriptions, and invalid `ss://` URLs are reported in [`ImportedServers::skipped`].
+pub fn parse_server_urls(text: &str) -> ImportedServers {
+    let decoded;
+    let text = match decode_subscription(text) {
+        Some(d) => {
+            decoded = d;
+            decoded.as_str()
+        }
+        None => text,
+    };
+
+    let mut imported = ImportedServers::default();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+            continue;
+        }
+
+        for url in line.split_whitespace() {
+            match ServerConfig::from_url(url) {
+                Ok(svr_cfg) => imported.servers.push(svr_cfg),
+                Err(err) => imported.skipped.push((url.to_owned(), err.to_string())),
+            }
+        }
+    }
+
+    imported
+}
+
+/// Decode `text` if it is a base64 encoded subscription body, in any of the standard or URL-safe alphabets
+fn decode_subscription(text: &str) -> Option<String> {
+    if text.contains("://") {
+        return None;
+    }
+
+    // Long bodies are wrapped into lines by some providers
+    let encoded = text.split_whitespace().collect::<String>();
+    if encoded.is_empty() {
+        return None;
+    }
+
+    for engine in [&STANDARD, &STANDARD_NO_PAD, &URL_SAFE, &URL_SAFE_NO_PAD] {
+        if let Ok(decoded) = engine.decode(&encoded) {
+            return String::from_utf8(decoded).ok();
+        }
+    }
+    None
+}
+
+/// Numbers of servers merged by [`merge_servers`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergedServers {
+    /// Servers appended to the config
+    pub added: usize,
+    /// Servers replacing ones in the config with the same address
+    pub updated: usize,
+}
+
+/// Merge `servers` into `config`
+///
+/// Servers with the same address as ones in `config` replace them, keeping their per-server options, like ACLs and
+/// outbound binding, so re-importing an updated subscription changes passwords and plugins in place. The other
+/// servers are appended.
+pub fn merge_servers(config: &mut Config, servers: Vec<ServerConfig>) -> MergedServers {
+    let mut merged = MergedServers::default();
+    for svr_cfg in servers {
+        match config.server.iter_mut().find(|s| s.config.addr() == svr_cfg.addr()) {
+            Some(instance) => {
+                instance.config = svr_cfg;
+                merged.updated += 1;
+            }
+            None => {
+                config.server.push(ServerInstanceConfig::with_server_config(svr_cfg));
+                merged.added += 1;
+            }
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ConfigType;
+
+    const URL_A: &str = "ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@127.0.0.1:8388#tokyo";
+    const URL_B: &str = "ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@example.com:8389/?plugin=obfs-local%3Bobfs%3Dhttp";
+
+    #[test]
+    fn parse_plain_urls() {
+        let text = format!("# shared servers\n{URL_A}\r\n\nvmess://abcdef\n  {URL_B}\n");
+        let imported = parse_server_urls(&text);
+
+        assert_eq!(imported.servers.len(), 2);
+        assert_eq!(imported.servers[0].remarks(), Some("tokyo"));
+        assert_eq!(
+            imported.servers[1].plugin().map(|p| p.plugin.as_str()),
+            Some("obfs-local")
+        );
+        assert_eq!(imported.skipped.len(), 1);
+        assert_eq!(imported.skipped[0].0, "vmess://abcdef");
+    }
+
+    #[test]
+    fn parse_base64_subscription() {
+        let body = STANDARD.encode(format!("{URL_A}\n{URL_B}\n"));
+        // Wrapped body
+        let (head, tail) = body.split_at(20);
+        let imported = parse_server_urls(&format!("{head}\n{tail}\n"));
+        assert_eq!(imported.servers.len(), 2);
+        assert!(imported.skipped.is_empty());
+
+        let imported = parse_server_urls(&URL_SAFE_NO_PAD.encode(URL_B));
+        assert_eq!(imported.servers.len(), 1);
+    }
+
+    #[test]
+    fn merge_by_address() {
+        let mut config = Config::new(ConfigType::Local);
+        let imported = parse_server_urls(URL_A);
+        assert_eq!(
+            merge_servers(&mut config, imported.servers),
+            MergedServers { added: 1, updated: 0 }
+        );
+
+        let imported = parse_server_urls(&format!(
+            "{}\n{URL_B}",
+            URL_A.replace("YWVzLTI1Ni1nY206cGFzc3dvcmQ", "YWVzLTI1Ni1nY206bmV3")
+        ));
+        assert_eq!(
+            merge_servers(&mut config, imported.servers),
+            MergedServers { added: 1, updated: 1 }
+        );
+        assert_eq!(config.server.len(), 2);
+        assert_eq!(config.server[0].config.password(), "new");
+    }
+}
@@ -47,30 +47,67 @@
 //! addresses.
 //!
 //! Of course, you can also use `cargo install` to install binaries.
+//!
+//! ## Embedding
+//!
+//! Applications could start services on their own runtimes with [`start_local`] and [`start_server`], which return
+//! a [`ServiceHandle`] for waiting, shutting down and reloading. See [`handle`] for details.
 
 use std::time::Duration;
 
+#[cfg(any(feature = "local", feature = "server"))]
+pub use self::handle::ServiceHandle;
 #[cfg(feature = "local")]
-pub use self::local::run as run_local;
+pub use self::{handle::start_local, local::run as run_local};
 
 #[cfg(feature = "manager")]
 pub use self::manager::run as run_manager;
 #[cfg(feature = "server")]
-pub use self::server::run as run_server;
+pub use self::{handle::start_server, server::run as run_server};
 pub use shadowsocks;
 
+pub mod access_log;
 pub mod acl;
+#[cfg(any(feature = "local-admin", feature = "manager-admin", feature = "manager-grpc"))]
+mod admin;
+#[cfg(any(feature = "local", feature = "server"))]
+pub mod bench;
+#[cfg(feature = "local")]
+pub mod check;
 pub mod config;
 mod dns;
+pub mod events;
+pub mod flow_export;
+#[cfg(any(feature = "local", feature = "server"))]
+pub mod handle;
+#[cfg(all(unix, feature = "server"))]
+pub mod handover;
+pub mod import;
 #[cfg(feature = "local")]
 pub mod local;
+pub mod log_control;
 #[cfg(feature = "manager")]
 pub mod manager;
+pub mod memory;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod metrics_sink;
 pub mod net;
+#[cfg(unix)]
+pub mod privilege;
+#[cfg(feature = "qrcode")]
+pub mod qrcode;
+#[cfg(any(target_os = "linux", target_os = "openbsd"))]
+pub mod sandbox;
+#[cfg(target_os = "linux")]
+pub mod sd_notify;
 #[cfg(feature = "server")]
 pub mod server;
+pub mod stats;
 mod sys;
 mod utils;
+#[cfg(feature = "webhook")]
+pub mod webhook;
 
 /// Default UDP association's expire duration
 #[allow(dead_code)]
@@ -0,0 +1,176 @@
+//! HTTP admin API of Local
+//!
+//! | Method   | Path            | Description                                           |
+//! |----------|-----------------|-------------------------------------------------------|
+//! | `GET`    | `/servers`      | List servers of balancer, with their scores           |
+//! | `GET`    | `/balancer`     | Servers currently selected for TCP and UDP            |
+//! | `GET`    | `/balancer/pin` | Current pinned server, `null` if chosen automatically |
+//! | `PUT`    | `/balancer/pin` | Pin balancer to a server, `{"server": "..."}`         |
+//! | `DELETE` | `/balancer/pin` | Release the pinned server                             |
+//! | `GET`    | `/snapshot`     | Aggregated statistics of this process                 |
+//! | `GET`    | `/domains`      | Traffic by destination domains, `?limit=N` (100)      |
+//! | `DELETE` | `/domains`      | Reset traffic of destination domains                  |
+//!
+//! Servers are referred by their `id`, `remarks` or address.
+//!
+//! Requests must carry `Authorization: Bearer <token>` if `token` is configured.
+
+use std::{io, net::SocketAddr, ptr};
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{Method, Request, Response, StatusCode, body::Incoming};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{Value as JsonValue, json};
+use shadowsocks::net::TcpListener;
+
+use crate::{
+    admin::{
+        self,
+        http::{make_error, make_json, read_body},
+    },
+    config::LocalAdminConfig,
+    stats,
+};
+
+use super::{
+    domain_stat::DOMAIN_TRAFFIC_CAPACITY,
+    loadbalancing::{PingBalancer, ServerScore},
+};
+
+/// Number of domains returned by `GET /domains` without `limit`
+const DOMAINS_DEFAULT_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+struct PinRequest {
+    server: String,
+}
+
+/// Serve the HTTP admin API until error occurs
+pub(super) async fn serve(balancer: PingBalancer, config: LocalAdminConfig) -> io::Result<()> {
+    let listener = TcpListener::bind_with_opts(&config.addr, Default::default()).await?;
+    info!(
+        "shadowsocks local admin API listening on {}",
+        listener.local_addr().expect("listener.local_addr")
+    );
+
+    if config.token.is_none() {
+        warn!("local admin API is not protected by token, anyone who can access it could control the balancer");
+    }
+
+    let token = config.token.as_deref();
+    let balancer = &balancer;
+    admin::http::serve("local admin", listener, token, move |req, peer_addr| {
+        handle_request(balancer, peer_addr, req)
+    })
+    .await
+}
+
+async fn handle_request(
+    balancer: &PingBalancer,
+    peer_addr: SocketAddr,
+    req: Request<Incoming>,
+) -> Response<Full<Bytes>> {
+    let method = req.method().clone();
+    let path = req.uri().path().trim_end_matches('/').to_owned();
+    let segments = path.split('/').skip(1).collect::<Vec<_>>();
+
+    match (method, segments.as_slice()) {
+        (Method::GET, ["servers"]) => {
+            let best_tcp_server = balancer.current_tcp_server();
+            let best_udp_server = balancer.current_udp_server();
+
+            let mut list = Vec::new();
+            for server in balancer.servers() {
+                let svr_cfg = server.server_config();
+                list.push(json!({
+                    "id": svr_cfg.id(),
+                    "remarks": svr_cfg.remarks(),
+                    "address": svr_cfg.addr().to_string(),
+                    "tcp": score_json(server.tcp_score(), ptr::eq(server, best_tcp_server.as_ref())).await,
+                    "udp": score_json(server.udp_score(), ptr::eq(server, best_udp_server.as_ref())).await,
+                    "pinned": balancer.is_pinned(server),
+                }));
+            }
+            make_json(StatusCode::OK, &json!(list))
+        }
+        (Method::GET, ["balancer"]) => {
+            let best_tcp_server = balancer.current_tcp_server();
+            let best_udp_server = balancer.current_udp_server();
+            make_json(
+                StatusCode::OK,
+                &json!({
+                    "tcp": best_tcp_server.server_config().addr().to_string(),
+                    "udp": best_udp_server.server_config().addr().to_string(),
+                    "pinned": balancer.pinned(),
+                }),
+            )
+        }
+        (Method::GET, ["balancer", "pin"]) => make_json(StatusCode::OK, &json!({ "server": balancer.pinned() })),
+        (Method::PUT, ["balancer", "pin"]) => {
+            let body = match read_body(req).await {
+                Ok(b) => b,
+                Err(rsp) => return rsp,
+            };
+
+            let pin_req = match serde_json::from_slice::<PinRequest>(&body) {
+                Ok(r) => r,
+                Err(err) => return make_error(StatusCode::BAD_REQUEST, &err.to_string()),
+            };
+
+            if !balancer.pin_server(&pin_req.server) {
+                return make_error(StatusCode::NOT_FOUND, "server not found");
+            }
+            info!("local admin pinned server {} from {}", pin_req.server, peer_addr);
+            make_json(StatusCode::OK, &json!({ "server": pin_req.server }))
+        }
+        (Method::DELETE, ["balancer", "pin"]) => {
+            balancer.unpin_server();
+            make_json(StatusCode::OK, &json!({ "server": null }))
+        }
+        (Method::GET, ["snapshot"]) => make_json(StatusCode::OK, &json!(stats::snapshot())),
+        (Method::GET, ["domains"]) => {
+            let limit = match query_limit(req.uri().query()) {
+                Ok(limit) => limit.unwrap_or(DOMAINS_DEFAULT_LIMIT).min(DOMAIN_TRAFFIC_CAPACITY),
+                Err(rsp) => return rsp,
+            };
+            make_json(StatusCode::OK, &json!(balancer.context().domain_traffic().top(limit)))
+        }
+        (Method::DELETE, ["domains"]) => {
+            balancer.context().domain_traffic().clear();
+            info!("local admin reset traffic of domains from {}", peer_addr);
+            make_json(StatusCode::OK, &json!([]))
+        }
+        (_, ["servers"] | ["balancer"] | ["balancer", "pin"] | ["snapshot"] | ["domains"]) => {
+            make_error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+        }
+        _ => make_error(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+async fn score_json(score: &ServerScore, selected: bool) -> JsonValue {
+    let stat = score.stat_data().await;
+    json!({
+        "score": score.score(),
+        "healthy": score.is_healthy(),
+        "selected": selected,
+        "last_latency": score.last_latency(),
+        "latency_ewma": stat.latency_ewma,
+        "latency_median": stat.latency_median,
+        "fail_rate": stat.fail_rate,
+        "failures": score.failures(),
+    })
+}
+
+fn query_limit(query: Option<&str>) -> Result<Option<usize>, Response<Full<Bytes>>> {
+    for pair in query.unwrap_or_default().split('&') {
+        if let Some(value) = pair.strip_prefix("limit=") {
+            return match value.parse::<usize>() {
+                Ok(limit) => Ok(Some(limit)),
+                Err(..) => Err(make_error(StatusCode::BAD_REQUEST, "invalid limit")),
+            };
+        }
+    }
+    Ok(None)
+}
@@ -1,14 +1,19 @@
 //! Shadowsocks Local Server Context
 
-use std::sync::Arc;
 #[cfg(feature = "local-dns")]
-use std::{net::IpAddr, time::Duration};
+use std::net::IpAddr;
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
 
 #[cfg(feature = "local-dns")]
 use lru_time_cache::LruCache;
 use shadowsocks::{
     config::ServerType,
-    context::{Context, SharedContext},
+    context::{CancellationToken, Context, SharedContext},
     dns_resolver::DnsResolver,
     net::{AcceptOpts, ConnectOpts},
     relay::Address,
@@ -17,11 +22,29 @@ use shadowsocks::{
 use tokio::sync::Mutex;
 #[cfg(feature = "local-fake-dns")]
 use tokio::sync::RwLock;
-
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
+use tokio::sync::Semaphore;
+
+use crate::{
+    acl::AccessControl,
+    config::SecurityConfig,
+    memory,
+    net::FlowStat,
+    stats::{DnsStats, LocalStats, MemoryStats},
+};
 
 #[cfg(feature = "local-fake-dns")]
 use super::fake_dns::manager::FakeDnsManager;
+use super::{
+    domain_stat::{DOMAIN_TRAFFIC_SNAPSHOT_TOP, DomainTrafficTable},
+    flow_stat::FlowStatSink,
+    observer::{ConnectionObserver, ConnectionObservers, ObservedConnection},
+};
+
+#[cfg(feature = "local-dns")]
+const REVERSE_LOOKUP_CACHE_EXPIRE_DURATION: Duration = Duration::from_secs(3 * 24 * 60 * 60);
+// XXX: It should be enough for a normal user.
+#[cfg(feature = "local-dns")]
+const REVERSE_LOOKUP_CACHE_DEFAULT_CAPACITY: usize = 10240;
 
 /// Local Service Context
 #[derive(Clone)]
@@ -30,15 +53,27 @@ pub struct ServiceContext {
     connect_opts: ConnectOpts,
     accept_opts: AcceptOpts,
 
+    // DSCP of connections to servers, not in `connect_opts` which is also used by bypassed connections
+    server_dscp: Option<u8>,
+
     // Access Control
     acl: Option<Arc<AccessControl>>,
 
     // Flow statistic report
     flow_stat: Arc<FlowStat>,
+    flow_stat_sinks: Vec<Arc<dyn FlowStatSink>>,
+
+    // Connection lifecycle events
+    connection_observers: Arc<ConnectionObservers>,
+
+    // Limit of TCP connections relayed at the same time
+    connection_limit: Option<Arc<Semaphore>>,
 
     // For DNS relay's ACL domain name reverse lookup -- whether the IP shall be forwarded
     #[cfg(feature = "local-dns")]
     reverse_lookup_cache: Arc<Mutex<LruCache<IpAddr, bool>>>,
+    #[cfg(feature = "local-dns")]
+    reverse_lookup_cache_capacity: usize,
 
     #[cfg(feature = "local-fake-dns")]
     fake_dns_manager: Arc<RwLock<Vec<Arc<FakeDnsManager>>>>,
@@ -57,13 +92,19 @@ impl ServiceContext {
             context: Context::new_shared(ServerType::Local),
             connect_opts: ConnectOpts::default(),
             accept_opts: AcceptOpts::default(),
+            server_dscp: None,
             acl: None,
             flow_stat: Arc::new(FlowStat::new()),
+            flow_stat_sinks: Vec::new(),
+            connection_observers: Arc::new(ConnectionObservers::default()),
+            connection_limit: None,
             #[cfg(feature = "local-dns")]
             reverse_lookup_cache: Arc::new(Mutex::new(LruCache::with_expiry_duration_and_capacity(
-                Duration::from_secs(3 * 24 * 60 * 60),
-                10240, // XXX: It should be enough for a normal user.
+                REVERSE_LOOKUP_CACHE_EXPIRE_DURATION,
+                REVERSE_LOOKUP_CACHE_DEFAULT_CAPACITY,
             ))),
+            #[cfg(feature = "local-dns")]
+            reverse_lookup_cache_capacity: REVERSE_LOOKUP_CACHE_DEFAULT_CAPACITY,
             #[cfg(feature = "local-fake-dns")]
             fake_dns_manager: Arc::new(RwLock::new(Vec::new())),
         }
@@ -79,6 +120,17 @@ impl ServiceContext {
         self.context.as_ref()
     }
 
+    /// Share `token` for shutting down the service
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set cancellation token on a shared context");
+        context.set_cancellation_token(token)
+    }
+
+    /// Get the token for shutting down the service
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        self.context.cancellation_token()
+    }
+
     /// Set `ConnectOpts`
     pub fn set_connect_opts(&mut self, connect_opts: ConnectOpts) {
         self.connect_opts = connect_opts;
@@ -89,6 +141,16 @@ impl ServiceContext {
         &self.connect_opts
     }
 
+    /// Set DSCP of connections to servers which don't have their own
+    pub fn set_server_dscp(&mut self, dscp: Option<u8>) {
+        self.server_dscp = dscp;
+    }
+
+    /// DSCP of connections to servers which don't have their own
+    pub fn server_dscp(&self) -> Option<u8> {
+        self.server_dscp
+    }
+
     /// Set `AcceptOpts`
     pub fn set_accept_opts(&mut self, accept_opts: AcceptOpts) {
         self.accept_opts = accept_opts;
@@ -119,10 +181,67 @@ impl ServiceContext {
         self.flow_stat.as_ref()
     }
 
+    /// Add a sink receiving reports of the flow statistic
+    pub fn add_flow_stat_sink(&mut self, sink: Arc<dyn FlowStatSink>) {
+        self.flow_stat_sinks.push(sink);
+    }
+
+    /// Sinks receiving reports of the flow statistic
+    pub fn flow_stat_sinks(&self) -> &[Arc<dyn FlowStatSink>] {
+        &self.flow_stat_sinks
+    }
+
+    /// Add an observer of lifecycle events of TCP connections
+    ///
+    /// It could be added after servers are started, connections accepted before are not observed.
+    pub fn add_connection_observer(&self, observer: Arc<dyn ConnectionObserver>) {
+        self.connection_observers.add(observer);
+    }
+
+    /// Traffic of TCP connections aggregated by their destination domains
+    pub fn domain_traffic(&self) -> &DomainTrafficTable {
+        self.connection_observers.domains()
+    }
+
+    /// Set maximum number of TCP connections relayed at the same time, the others are refused
+    pub fn set_max_tcp_connections(&mut self, max: usize) {
+        self.connection_limit = Some(Arc::new(Semaphore::new(max)));
+    }
+
+    /// Start observing a connection accepted from `peer_addr`
+    ///
+    /// Fails if there are already `max_tcp_connections` connections, or memory usage is over the soft limit, the
+    /// connection should be closed then.
+    pub(crate) fn observe_connection(&self, peer_addr: SocketAddr) -> io::Result<ObservedConnection> {
+        if memory::is_over_limit() {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                "memory usage is over the limit, connection refused",
+            ));
+        }
+
+        let permit = match self.connection_limit {
+            Some(ref limit) => match limit.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(..) => {
+                    return Err(io::Error::new(
+                        ErrorKind::Other,
+                        "too many TCP connections, connection refused",
+                    ));
+                }
+            },
+            None => None,
+        };
+
+        Ok(self.connection_observers.observe(peer_addr, permit))
+    }
+
     /// Set customized DNS resolver
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set DNS resolver on a shared context");
-        context.set_dns_resolver(resolver)
+        context.set_dns_resolver(resolver);
+        // Resolvers are created with `connect_opts` of this context
+        context.set_dns_connect_opts(self.connect_opts.clone());
     }
 
     /// Get reference of DNS resolver
@@ -152,6 +271,16 @@ impl ServiceContext {
         }
     }
 
+    /// Set capacity of the reverse lookup cache, records cached are dropped
+    #[cfg(feature = "local-dns")]
+    pub fn set_reverse_lookup_cache_capacity(&mut self, capacity: usize) {
+        self.reverse_lookup_cache = Arc::new(Mutex::new(LruCache::with_expiry_duration_and_capacity(
+            REVERSE_LOOKUP_CACHE_EXPIRE_DURATION,
+            capacity,
+        )));
+        self.reverse_lookup_cache_capacity = capacity;
+    }
+
     /// Add a record to the reverse lookup cache
     #[cfg(feature = "local-dns")]
     pub async fn add_to_reverse_lookup_cache(&self, addr: IpAddr, forward: bool) {
@@ -185,12 +314,68 @@ impl ServiceContext {
         context.set_ipv6_first(ipv6_first);
     }
 
+    /// Set the interval of resolving domain names of remote servers again
+    pub fn set_server_resolve_interval(&mut self, interval: Duration) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set server_resolve_interval on a shared context");
+        context.set_server_resolve_interval(interval);
+    }
+
     /// Set security config
     pub fn set_security_config(&mut self, security: &SecurityConfig) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set security on a shared context");
         context.set_replay_attack_policy(security.replay_attack.policy);
     }
 
+    /// Set entries of the replay filter, instead of the default capacity
+    pub fn set_replay_filter_capacity(&mut self, entries: usize) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set replay filter on a shared context");
+        context.set_replay_filter_capacity(entries);
+    }
+
+    /// Clear the DNS cache and the reverse lookup cache
+    pub fn clear_caches(&self) {
+        self.context.dns_resolver().clear_cache();
+        self.context.clear_server_addr_cache();
+
+        #[cfg(feature = "local-dns")]
+        {
+            let reverse_lookup_cache = self.reverse_lookup_cache.clone();
+            tokio::spawn(async move {
+                reverse_lookup_cache.lock().await.clear();
+            });
+        }
+    }
+
+    /// Take a snapshot of statistics, without states of balancer
+    pub fn stats_snapshot(&self) -> LocalStats {
+        let stat = self.context.stat();
+
+        #[allow(unused_mut)]
+        let mut dns = DnsStats::new(stat);
+        #[cfg(feature = "local-dns")]
+        if let Ok(cache) = self.reverse_lookup_cache.try_lock() {
+            dns.cache_entries = Some(cache.len());
+        }
+
+        #[allow(unused_mut)]
+        let mut memory = MemoryStats::new(self.context_ref());
+        #[cfg(feature = "local-dns")]
+        {
+            memory.reverse_lookup_cache_capacity = Some(self.reverse_lookup_cache_capacity);
+        }
+
+        LocalStats {
+            tx: self.flow_stat.tx(),
+            rx: self.flow_stat.rx(),
+            tcp_connections: self.connection_observers.active() as u64,
+            replay_detected: stat.replay_detected(),
+            dns,
+            memory,
+            balancer: Vec::new(),
+            domains: self.domain_traffic().top(DOMAIN_TRAFFIC_SNAPSHOT_TOP),
+        }
+    }
+
     /// Set Fake DNS manager
     #[cfg(feature = "local-fake-dns")]
     pub async fn add_fake_dns_manager(&self, manager: Arc<FakeDnsManager>) {
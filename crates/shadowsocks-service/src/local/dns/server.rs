@@ -43,6 +43,7 @@ use crate::{
         loadbalancing::PingBalancer,
         net::{tcp::listener::create_standard_tcp_listener, udp::listener::create_standard_udp_listener},
     },
+    utils::spawn_cancellable,
 };
 
 use super::{client_cache::DnsClientCache, config::NameServerAddr};
@@ -229,6 +230,7 @@ impl DnsTcpServerBuilder {
         }
 
         Ok(DnsTcpServer {
+            context: self.context,
             listener,
             local_addr: self.local_addr,
             remote_addr: self.remote_addr,
@@ -239,6 +241,7 @@ impl DnsTcpServerBuilder {
 
 /// DNS TCP server instance
 pub struct DnsTcpServer {
+    context: Arc<ServiceContext>,
     listener: TcpListener,
     local_addr: Arc<NameServerAddr>,
     remote_addr: Arc<Address>,
@@ -270,13 +273,16 @@ impl DnsTcpServer {
                 }
             };
 
-            tokio::spawn(DnsTcpServer::handle_tcp_stream(
-                self.client.clone(),
-                stream,
-                peer_addr,
-                self.local_addr.clone(),
-                self.remote_addr.clone(),
-            ));
+            spawn_cancellable(
+                self.context.cancellation_token(),
+                DnsTcpServer::handle_tcp_stream(
+                    self.client.clone(),
+                    stream,
+                    peer_addr,
+                    self.local_addr.clone(),
+                    self.remote_addr.clone(),
+                ),
+            );
         }
     }
 
@@ -401,6 +407,7 @@ impl DnsUdpServerBuilder {
         }
 
         Ok(DnsUdpServer {
+            context: self.context,
             listener: Arc::new(socket),
             local_addr: self.local_addr,
             remote_addr: self.remote_addr,
@@ -411,6 +418,7 @@ impl DnsUdpServerBuilder {
 
 /// DNS UDP server instance
 pub struct DnsUdpServer {
+    context: Arc<ServiceContext>,
     listener: Arc<UdpSocket>,
     local_addr: Arc<NameServerAddr>,
     remote_addr: Arc<Address>,
@@ -453,14 +461,17 @@ impl DnsUdpServer {
                 }
             };
 
-            tokio::spawn(DnsUdpServer::handle_udp_packet(
-                self.client.clone(),
-                self.listener.clone(),
-                peer_addr,
-                message,
-                self.local_addr.clone(),
-                self.remote_addr.clone(),
-            ));
+            spawn_cancellable(
+                self.context.cancellation_token(),
+                DnsUdpServer::handle_udp_packet(
+                    self.client.clone(),
+                    self.listener.clone(),
+                    peer_addr,
+                    message,
+                    self.local_addr.clone(),
+                    self.remote_addr.clone(),
+                ),
+            );
         }
     }
 
@@ -0,0 +1,283 @@
+//! Traffic aggregated by destination domains
+//!
+//! Bytes of TCP connections relayed by local servers are added to the domains of their targets. Targets of `redir`
+//! and `tun` are IP addresses, so the SNI of the TLS ClientHello sent by clients is used instead, if any.
+//!
+//! The table keeps at most [`DOMAIN_TRAFFIC_CAPACITY`] domains. Bytes are scored with decay, halved every
+//! [`DOMAIN_TRAFFIC_HALF_LIFE`], and the domain with the lowest score is evicted for a new one, so the table follows
+//! the domains with the most recent traffic.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::stats::DomainTrafficStats;
+
+/// Maximum number of domains kept in [`DomainTrafficTable`]
+pub const DOMAIN_TRAFFIC_CAPACITY: usize = 1024;
+
+/// Scores of domains are halved after this duration
+pub const DOMAIN_TRAFFIC_HALF_LIFE: Duration = Duration::from_secs(60 * 60);
+
+/// Number of domains in snapshots of statistics
+pub const DOMAIN_TRAFFIC_SNAPSHOT_TOP: usize = 20;
+
+#[derive(Debug)]
+struct DomainTrafficEntry {
+    tx: u64,
+    rx: u64,
+    connections: u64,
+    score: f64,
+    updated: Instant,
+}
+
+impl DomainTrafficEntry {
+    fn score_at(&self, now: Instant, half_life: Duration) -> f64 {
+        let age = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.score * 0.5f64.powf(age / half_life.as_secs_f64())
+    }
+}
+
+/// Bounded table of traffic by destination domains
+#[derive(Debug)]
+pub struct DomainTrafficTable {
+    capacity: usize,
+    half_life: Duration,
+    entries: Mutex<HashMap<String, DomainTrafficEntry>>,
+}
+
+impl Default for DomainTrafficTable {
+    fn default() -> DomainTrafficTable {
+        DomainTrafficTable::new(DOMAIN_TRAFFIC_CAPACITY, DOMAIN_TRAFFIC_HALF_LIFE)
+    }
+}
+
+impl DomainTrafficTable {
+    /// Create a table keeping at most `capacity` domains, with scores halved every `half_life`
+    pub fn new(capacity: usize, half_life: Duration) -> DomainTrafficTable {
+        DomainTrafficTable {
+            capacity,
+            half_life,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Add bytes relayed to `domain`, `new_connection` if they are the first bytes of a connection
+    pub fn add(&self, domain: &str, tx: u64, rx: u64, new_connection: bool) {
+        self.add_at(domain, tx, rx, new_connection, Instant::now());
+    }
+
+    fn add_at(&self, domain: &str, tx: u64, rx: u64, new_connection: bool, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(domain) && entries.len() >= self.capacity {
+            let evicted = entries
+                .iter()
+                .min_by(|(_, a), (_, b)| {
+                    a.score_at(now, self.half_life)
+                        .total_cmp(&b.score_at(now, self.half_life))
+                })
+                .map(|(k, _)| k.clone());
+            if let Some(evicted) = evicted {
+                entries.remove(&evicted);
+            }
+        }
+
+        let entry = entries.entry(domain.to_owned()).or_insert_with(|| DomainTrafficEntry {
+            tx: 0,
+            rx: 0,
+            connections: 0,
+            score: 0.0,
+            updated: now,
+        });
+        entry.tx += tx;
+        entry.rx += rx;
+        if new_connection {
+            entry.connections += 1;
+        }
+        entry.score = entry.score_at(now, self.half_life) + (tx + rx) as f64;
+        entry.updated = now;
+    }
+
+    /// At most `n` domains with the highest scores, the busiest first
+    pub fn top(&self, n: usize) -> Vec<DomainTrafficStats> {
+        self.top_at(n, Instant::now())
+    }
+
+    fn top_at(&self, n: usize, now: Instant) -> Vec<DomainTrafficStats> {
+        let entries = self.entries.lock().unwrap();
+        let mut list = entries
+            .iter()
+            .map(|(domain, entry)| DomainTrafficStats {
+                domain: domain.clone(),
+                tx: entry.tx,
+                rx: entry.rx,
+                connections: entry.connections,
+                recent_bytes: entry.score_at(now, self.half_life) as u64,
+            })
+            .collect::<Vec<_>>();
+        drop(entries);
+
+        list.sort_by(|a, b| {
+            b.recent_bytes
+                .cmp(&a.recent_bytes)
+                .then_with(|| a.domain.cmp(&b.domain))
+        });
+        list.truncate(n);
+        list
+    }
+
+    /// Remove all domains
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if buf.len() < n {
+        return None;
+    }
+    let (head, tail) = buf.split_at(n);
+    *buf = tail;
+    Some(head)
+}
+
+fn take_u16(buf: &mut &[u8]) -> Option<usize> {
+    take(buf, 2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+}
+
+fn take_vec8<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = take(buf, 1)?[0] as usize;
+    take(buf, len)
+}
+
+fn take_vec16<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = take_u16(buf)?;
+    take(buf, len)
+}
+
+/// Server name in the SNI extension of a TLS ClientHello at the beginning of `data`
+///
+/// `data` could be truncated, the name is returned if extensions before it are complete.
+pub fn parse_tls_server_name(data: &[u8]) -> Option<String> {
+    let mut buf = data;
+
+    // TLSPlaintext, type handshake(22)
+    let record = take(&mut buf, 5)?;
+    if record[0] != 0x16 || record[1] != 0x03 {
+        return None;
+    }
+
+    // Handshake, type client_hello(1)
+    let handshake = take(&mut buf, 4)?;
+    if handshake[0] != 0x01 {
+        return None;
+    }
+
+    // legacy_version, random
+    take(&mut buf, 2 + 32)?;
+    // legacy_session_id
+    take_vec8(&mut buf)?;
+    // cipher_suites
+    take_vec16(&mut buf)?;
+    // legacy_compression_methods
+    take_vec8(&mut buf)?;
+
+    let len = take_u16(&mut buf)?;
+    let mut extensions = &buf[..len.min(buf.len())];
+    while !extensions.is_empty() {
+        let extension_type = take_u16(&mut extensions)?;
+        let mut extension = take_vec16(&mut extensions)?;
+        // server_name(0)
+        if extension_type != 0 {
+            continue;
+        }
+
+        let mut names = take_vec16(&mut extension)?;
+        while !names.is_empty() {
+            let name_type = take(&mut names, 1)?[0];
+            let name = take_vec16(&mut names)?;
+            // host_name(0)
+            if name_type == 0 {
+                return std::str::from_utf8(name).ok().map(|name| name.to_ascii_lowercase());
+            }
+        }
+        return None;
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn table_decay_and_eviction() {
+        let table = DomainTrafficTable::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+
+        table.add_at("a.example", 1000, 3000, true, now);
+        table.add_at("b.example", 100, 100, true, now);
+        table.add_at("b.example", 100, 100, false, now);
+
+        let top = table.top_at(10, now);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].domain, "a.example");
+        assert_eq!((top[0].tx, top[0].rx, top[0].connections), (1000, 3000, 1));
+        assert_eq!((top[1].tx, top[1].rx, top[1].connections), (200, 200, 1));
+
+        // a.example decayed to 4000 / 2^10, lower than b.example
+        let later = now + Duration::from_secs(600);
+        table.add_at("b.example", 0, 400, true, later);
+        table.add_at("c.example", 10, 10, true, later);
+
+        let top = table.top_at(10, later);
+        assert_eq!(
+            top.iter().map(|d| d.domain.as_str()).collect::<Vec<_>>(),
+            ["b.example", "c.example"]
+        );
+        assert_eq!(top[0].connections, 2);
+    }
+
+    #[test]
+    fn client_hello_server_name() {
+        let name = b"Example.COM";
+
+        let mut server_name = vec![0x00];
+        server_name.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        server_name.extend_from_slice(name);
+
+        let mut extensions = Vec::new();
+        // supported_versions
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        extensions.extend_from_slice(&[0x00, 0x00]);
+        extensions.extend_from_slice(&(server_name.len() as u16 + 2).to_be_bytes());
+        extensions.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&server_name);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0u8; 32]);
+        hello.extend_from_slice(&[0x00]);
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        hello.extend_from_slice(&[0x01, 0x00]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+
+        assert_eq!(parse_tls_server_name(&record).as_deref(), Some("example.com"));
+        assert_eq!(parse_tls_server_name(&record[..record.len() - 4]), None);
+        assert_eq!(parse_tls_server_name(b"GET / HTTP/1.1\r\n\r\n"), None);
+    }
+}
@@ -21,7 +21,7 @@ use tokio::{
     time,
 };
 
-use crate::local::context::ServiceContext;
+use crate::{local::context::ServiceContext, utils::spawn_cancellable};
 
 use super::{manager::FakeDnsManager, processor::handle_dns_request};
 
@@ -78,7 +78,7 @@ impl FakeDnsTcpServer {
 
             let context = self.context.clone();
             let manager = self.manager.clone();
-            tokio::spawn(async move {
+            spawn_cancellable(self.context.cancellation_token(), async move {
                 if let Err(err) = FakeDnsTcpServer::handle_client(context, peer_addr, stream, manager).await {
                     error!(
                         "failed to handle Fake DNS tcp client, peer: {}, err: {}",
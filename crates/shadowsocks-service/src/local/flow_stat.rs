@@ -0,0 +1,216 @@
+//! Flow statistic sinks of Local
+//!
+//! Bytes transferred by all local servers are counted in the shared [`FlowStat`] of [`ServiceContext`],
+//! and reported to every [`FlowStatSink`] added to the context periodically.
+//!
+//! Builtin sinks:
+//!
+//! - [`AndroidFlowStatSink`] - The protocol of shadowsocks-libev and shadowsocks-android
+//! - [`SnapshotFlowStatSink`] - Keeps the latest counters in memory, for GUIs embedding Local
+//! - [`StatsdFlowStatSink`] - Emits statsd counters with UDP
+//!
+//! [`ServiceContext`]: super::context::ServiceContext
+
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::future::{self, BoxFuture};
+use log::debug;
+use tokio::{io::AsyncWriteExt, net::UdpSocket, sync::OnceCell, time};
+
+use crate::{config::LocalFlowStatAddress, net::FlowStat};
+
+/// Interval of reporting to sinks, same as shadowsocks-libev
+const FLOW_STAT_REPORT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Counters of a report
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlowStatSnapshot {
+    /// Total transmitted bytes
+    pub tx: u64,
+    /// Total received bytes
+    pub rx: u64,
+}
+
+/// Receiver of flow statistic reports
+pub trait FlowStatSink: Send + Sync {
+    /// Report the total counters, called every 500 milliseconds
+    ///
+    /// Errors are logged and ignored, the next report will be sent as usual.
+    fn report(&self, snapshot: FlowStatSnapshot) -> BoxFuture<'_, io::Result<()>>;
+}
+
+/// Sends counters as two native-endian `u64` to a stream socket, connected for each report
+///
+/// This is the protocol of shadowsocks-libev's `--stat-path`, used by shadowsocks-android.
+#[derive(Debug, Clone)]
+pub struct AndroidFlowStatSink {
+    addr: LocalFlowStatAddress,
+}
+
+impl AndroidFlowStatSink {
+    /// Create a sink reporting to `addr`
+    pub fn new(addr: LocalFlowStatAddress) -> AndroidFlowStatSink {
+        AndroidFlowStatSink { addr }
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<()> {
+        match self.addr {
+            #[cfg(unix)]
+            LocalFlowStatAddress::UnixStreamPath(ref stat_path) => {
+                use tokio::net::UnixStream;
+
+                let mut stream = UnixStream::connect(stat_path).await?;
+                stream.write_all(buf).await
+            }
+            LocalFlowStatAddress::TcpStreamAddr(stat_addr) => {
+                use tokio::net::TcpStream;
+
+                let mut stream = TcpStream::connect(stat_addr).await?;
+                stream.write_all(buf).await
+            }
+        }
+    }
+}
+
+impl FlowStatSink for AndroidFlowStatSink {
+    fn report(&self, snapshot: FlowStatSnapshot) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            let mut buf = [0u8; 16];
+            buf[..8].copy_from_slice(&snapshot.tx.to_ne_bytes());
+            buf[8..].copy_from_slice(&snapshot.rx.to_ne_bytes());
+
+            match time::timeout(Duration::from_secs(1), self.send(&buf)).await {
+                Ok(r) => r,
+                Err(..) => Err(ErrorKind::TimedOut.into()),
+            }
+        })
+    }
+}
+
+/// Keeps the latest reported counters
+#[derive(Debug, Default)]
+pub struct SnapshotFlowStatSink {
+    snapshot: Mutex<FlowStatSnapshot>,
+}
+
+impl SnapshotFlowStatSink {
+    /// Create an empty sink
+    pub fn new() -> SnapshotFlowStatSink {
+        SnapshotFlowStatSink::default()
+    }
+
+    /// The latest reported counters
+    pub fn snapshot(&self) -> FlowStatSnapshot {
+        *self.snapshot.lock().unwrap()
+    }
+}
+
+impl FlowStatSink for SnapshotFlowStatSink {
+    fn report(&self, snapshot: FlowStatSnapshot) -> BoxFuture<'_, io::Result<()>> {
+        *self.snapshot.lock().unwrap() = snapshot;
+        Box::pin(future::ok(()))
+    }
+}
+
+/// Emits increments of counters to a statsd server, as `<prefix>.tx:<n>|c` and `<prefix>.rx:<n>|c`
+#[derive(Debug)]
+pub struct StatsdFlowStatSink {
+    addr: SocketAddr,
+    prefix: String,
+    last: Mutex<FlowStatSnapshot>,
+    // Bound on the first report, and reused by the following reports
+    socket: OnceCell<UdpSocket>,
+}
+
+impl StatsdFlowStatSink {
+    /// Create a sink emitting to the statsd server `addr`, with metric names prefixed by `prefix`
+    pub fn new(addr: SocketAddr, prefix: String) -> StatsdFlowStatSink {
+        StatsdFlowStatSink {
+            addr,
+            prefix,
+            last: Mutex::new(FlowStatSnapshot::default()),
+            socket: OnceCell::new(),
+        }
+    }
+}
+
+impl FlowStatSink for StatsdFlowStatSink {
+    fn report(&self, snapshot: FlowStatSnapshot) -> BoxFuture<'_, io::Result<()>> {
+        let last = {
+            let mut last = self.last.lock().unwrap();
+            let prev = *last;
+            *last = snapshot;
+            prev
+        };
+
+        let tx = snapshot.tx.saturating_sub(last.tx);
+        let rx = snapshot.rx.saturating_sub(last.rx);
+
+        Box::pin(async move {
+            if tx == 0 && rx == 0 {
+                return Ok(());
+            }
+
+            let payload = format!("{0}.tx:{1}|c\n{0}.rx:{2}|c", self.prefix, tx, rx);
+
+            let socket = self
+                .socket
+                .get_or_try_init(|| async {
+                    let bind_addr: SocketAddr = if self.addr.is_ipv4() {
+                        ([0, 0, 0, 0], 0).into()
+                    } else {
+                        ([0u16; 8], 0).into()
+                    };
+                    UdpSocket::bind(bind_addr).await
+                })
+                .await?;
+            socket.send_to(payload.as_bytes(), self.addr).await?;
+            Ok(())
+        })
+    }
+}
+
+/// Report `flow_stat` to `sinks` periodically, never returns
+pub(crate) async fn flow_report_task(flow_stat: Arc<FlowStat>, sinks: Vec<Arc<dyn FlowStatSink>>) -> io::Result<()> {
+    loop {
+        time::sleep(FLOW_STAT_REPORT_INTERVAL).await;
+
+        let snapshot = FlowStatSnapshot {
+            tx: flow_stat.tx(),
+            rx: flow_stat.rx(),
+        };
+
+        for result in future::join_all(sinks.iter().map(|sink| sink.report(snapshot))).await {
+            if let Err(err) = result {
+                debug!("send client flow statistic error: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn statsd_sink_emits_increments() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sink = StatsdFlowStatSink::new(server.local_addr().unwrap(), "ss".to_owned());
+
+        sink.report(FlowStatSnapshot { tx: 10, rx: 20 }).await.unwrap();
+        sink.report(FlowStatSnapshot { tx: 15, rx: 20 }).await.unwrap();
+
+        let mut buf = [0u8; 128];
+        let (n, first_addr) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ss.tx:10|c\nss.rx:20|c");
+        let (n, second_addr) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ss.tx:5|c\nss.rx:0|c");
+        // Reports are sent from the same socket
+        assert_eq!(first_addr, second_addr);
+    }
+}
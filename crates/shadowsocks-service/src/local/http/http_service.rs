@@ -12,12 +12,16 @@ use hyper::{
 use log::{debug, error, trace};
 use shadowsocks::relay::Address;
 
-use crate::local::{
-    context::ServiceContext,
-    http::{http_client::HttpClientError, tokio_rt::TokioIo},
-    loadbalancing::PingBalancer,
-    net::AutoProxyIo,
-    utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
+use crate::{
+    local::{
+        context::ServiceContext,
+        http::http_client::HttpClientError,
+        loadbalancing::PingBalancer,
+        net::AutoProxyIo,
+        utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
+    },
+    net::tokio_rt::TokioIo,
+    utils::spawn_cancellable,
 };
 
 use super::{
@@ -80,6 +84,15 @@ impl HttpService {
 
             debug!("HTTP CONNECT {}", host);
 
+            let mut observed = match self.context.observe_connection(self.peer_addr) {
+                Ok(o) => o,
+                Err(err) => {
+                    error!("HTTP CONNECT {} refused, {}", host, err);
+                    return make_service_unavailable();
+                }
+            };
+            observed.target_resolved(&host);
+
             // Connect to Shadowsocks' remote
             //
             // FIXME: What STATUS should I return for connection error?
@@ -99,7 +112,7 @@ impl HttpService {
             );
 
             let client_addr = self.peer_addr;
-            tokio::spawn(async move {
+            spawn_cancellable(self.context.cancellation_token(), async move {
                 match hyper::upgrade::on(req).await {
                     Ok(upgraded) => {
                         trace!("CONNECT tunnel upgrade success, {} <-> {}", client_addr, host);
@@ -114,11 +127,19 @@ impl HttpService {
                                     &mut stream,
                                     client_addr,
                                     &host,
+                                    &mut observed,
                                 )
                                 .await
                             }
                             None => {
-                                establish_tcp_tunnel_bypassed(&mut upgraded_io, &mut stream, client_addr, &host).await
+                                establish_tcp_tunnel_bypassed(
+                                    &mut upgraded_io,
+                                    &mut stream,
+                                    client_addr,
+                                    &host,
+                                    &mut observed,
+                                )
+                                .await
                             }
                         };
                     }
@@ -211,6 +232,13 @@ fn make_internal_server_error() -> Result<Response<BoxBody<Bytes, hyper::Error>>
         .unwrap())
 }
 
+fn make_service_unavailable() -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    Ok(Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .body(empty_body())
+        .unwrap())
+}
+
 fn get_extra_headers(headers: header::GetAll<HeaderValue>) -> Vec<String> {
     let mut extra_headers = Vec::new();
     for connection in headers {
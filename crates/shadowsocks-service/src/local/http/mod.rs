@@ -2,6 +2,8 @@
 //!
 //! https://www.ietf.org/rfc/rfc2068.txt
 
+#[cfg(any(feature = "local-http-native-tls", feature = "local-http-rustls"))]
+pub(crate) use self::http_stream::ProxyHttpStream;
 pub use self::{
     http_client::{HttpClient, HttpClientError},
     server::{Http, HttpBuilder, HttpConnectionHandler},
@@ -11,5 +13,4 @@ mod http_client;
 mod http_service;
 mod http_stream;
 pub mod server;
-mod tokio_rt;
 mod utils;
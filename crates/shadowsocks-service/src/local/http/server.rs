@@ -11,18 +11,31 @@ use tokio::{
     io::{AsyncRead, AsyncWrite},
     time,
 };
-
-use crate::local::{
-    context::ServiceContext, loadbalancing::PingBalancer, net::tcp::listener::create_standard_tcp_listener,
+#[cfg(feature = "local-tls")]
+use tokio_rustls::TlsAcceptor;
+use tracing::Instrument;
+
+#[cfg(feature = "local-tls")]
+use crate::{
+    config::LocalTlsConfig,
+    local::net::tcp::tls::{accept_tls, make_tls_acceptor},
+};
+use crate::{
+    local::{context::ServiceContext, loadbalancing::PingBalancer, net::tcp::listener::create_standard_tcp_listener},
+    log_control::connection_span,
+    net::tokio_rt::TokioIo,
+    utils::spawn_cancellable,
 };
 
-use super::{http_client::HttpClient, http_service::HttpService, tokio_rt::TokioIo};
+use super::{http_client::HttpClient, http_service::HttpService};
 
 /// HTTP Local server builder
 pub struct HttpBuilder {
     context: Arc<ServiceContext>,
     client_config: ServerAddr,
     balancer: PingBalancer,
+    #[cfg(feature = "local-tls")]
+    tls: Option<LocalTlsConfig>,
     #[cfg(target_os = "macos")]
     launchd_tcp_socket_name: Option<String>,
 }
@@ -44,11 +57,19 @@ impl HttpBuilder {
             context,
             client_config,
             balancer,
+            #[cfg(feature = "local-tls")]
+            tls: None,
             #[cfg(target_os = "macos")]
             launchd_tcp_socket_name: None,
         }
     }
 
+    /// Terminate TLS of clients, serving HTTPS proxy
+    #[cfg(feature = "local-tls")]
+    pub fn set_tls(&mut self, tls: LocalTlsConfig) {
+        self.tls = Some(tls);
+    }
+
     #[cfg(target_os = "macos")]
     pub fn set_launchd_tcp_socket_name(&mut self, n: String) {
         self.launchd_tcp_socket_name = Some(n);
@@ -56,6 +77,12 @@ impl HttpBuilder {
 
     /// Build HTTP server instance
     pub async fn build(self) -> io::Result<Http> {
+        #[cfg(feature = "local-tls")]
+        let tls_acceptor = match self.tls {
+            Some(ref tls) => Some(make_tls_acceptor(tls)?),
+            None => None,
+        };
+
         cfg_if::cfg_if! {
             if #[cfg(target_os = "macos")] {
                 let listener = match self.launchd_tcp_socket_name {
@@ -81,6 +108,8 @@ impl HttpBuilder {
             context: self.context,
             listener,
             balancer: self.balancer,
+            #[cfg(feature = "local-tls")]
+            tls_acceptor,
         })
     }
 }
@@ -90,6 +119,8 @@ pub struct Http {
     context: Arc<ServiceContext>,
     listener: TcpListener,
     balancer: PingBalancer,
+    #[cfg(feature = "local-tls")]
+    tls_acceptor: Option<TlsAcceptor>,
 }
 
 impl Http {
@@ -108,7 +139,7 @@ impl Http {
             self.listener.local_addr().expect("http local_addr")
         );
 
-        let handler = HttpConnectionHandler::new(self.context, self.balancer);
+        let handler = HttpConnectionHandler::new(self.context.clone(), self.balancer);
 
         loop {
             let (stream, peer_addr) = match self.listener.accept().await {
@@ -122,11 +153,32 @@ impl Http {
 
             trace!("HTTP accepted client from {}", peer_addr);
             let handler = handler.clone();
-            tokio::spawn(async move {
-                if let Err(err) = handler.serve_connection(stream, peer_addr).await {
-                    error!("HTTP connection {} handler failed with error: {}", peer_addr, err);
+            #[cfg(feature = "local-tls")]
+            let tls_acceptor = self.tls_acceptor.clone();
+            spawn_cancellable(
+                self.context.cancellation_token(),
+                async move {
+                    #[cfg(feature = "local-tls")]
+                    if let Some(acceptor) = tls_acceptor {
+                        let stream = match accept_tls(&acceptor, stream).await {
+                            Ok(s) => s,
+                            Err(err) => {
+                                error!("HTTP connection {} TLS handshake failed with error: {}", peer_addr, err);
+                                return;
+                            }
+                        };
+                        if let Err(err) = handler.serve_connection(stream, peer_addr).await {
+                            error!("HTTP connection {} handler failed with error: {}", peer_addr, err);
+                        }
+                        return;
+                    }
+
+                    if let Err(err) = handler.serve_connection(stream, peer_addr).await {
+                        error!("HTTP connection {} handler failed with error: {}", peer_addr, err);
+                    }
                 }
-            });
+                .instrument(connection_span(peer_addr)),
+            );
         }
     }
 }
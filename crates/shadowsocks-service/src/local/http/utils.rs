@@ -134,11 +134,9 @@ pub async fn connect_host(
             }
         },
         Some(balancer) => {
-            let server = balancer.best_tcp_server();
+            let (server, result) = AutoProxyClientStream::connect_balanced(context, balancer, host).await;
 
-            match AutoProxyClientStream::connect_with_opts(context, server.as_ref(), host, server.connect_opts_ref())
-                .await
-            {
+            match result {
                 Ok(s) => Ok((s, Some(server))),
                 Err(err) => {
                     error!(
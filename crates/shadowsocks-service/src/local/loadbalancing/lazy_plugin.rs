@@ -0,0 +1,246 @@
+//! Plugins started on demand
+//!
+//! Clients with many servers, like subscriptions, would start plugins of all of them at startup. With `lazy` of
+//! `plugin_supervision`, local addresses of plugins are reserved at startup, and held by sockets bound to them while
+//! plugins are not running, so other processes couldn't take them. Plugins are only started when their server is used
+//! for the first time. With `idle_timeout`, they are stopped after no connections have used them for
+//! that long, and started again by the next connection.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use futures::future;
+use log::{debug, info, trace, warn};
+use shadowsocks::{
+    config::ServerAddr,
+    plugin::{Plugin, PluginConfig, PluginMode, ReservedLocalAddr},
+};
+use spin::Mutex as SpinMutex;
+use tokio::{sync::Mutex, task::JoinHandle, time};
+
+/// Chained plugins of a server, started on demand
+#[derive(Debug)]
+pub struct LazyPlugins {
+    // Plugins in order from shadowsocks to network, with their remote and reserved local addresses
+    chain: Vec<(PluginConfig, ServerAddr, SocketAddr)>,
+    // Local addresses held while plugins are not running
+    reserved: SpinMutex<Vec<ReservedLocalAddr>>,
+    idle_timeout: Option<Duration>,
+    // Task supervising started plugins, plugins are stopped with it
+    task: Mutex<Option<JoinHandle<()>>>,
+    users: AtomicUsize,
+    last_used: SpinMutex<Instant>,
+}
+
+impl Drop for LazyPlugins {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.get_mut().take() {
+            task.abort();
+        }
+    }
+}
+
+impl LazyPlugins {
+    /// Reserve local addresses of external plugins in `plugins` working with `remote_addr`
+    ///
+    /// Returns `None` if there are no external plugins. Shadowsocks should work with `local_addr` of the result.
+    pub fn reserve(plugins: &[PluginConfig], remote_addr: &ServerAddr) -> io::Result<Option<LazyPlugins>> {
+        let external = plugins.iter().filter(|p| !p.is_builtin()).collect::<Vec<_>>();
+
+        let mut chain = Vec::with_capacity(external.len());
+        let mut reserved = Vec::with_capacity(external.len());
+        let mut remote_addr = remote_addr.clone();
+        for c in external.into_iter().rev() {
+            let local_addr = Plugin::reserve_local_port(c, &remote_addr)?;
+            chain.push((c.clone(), remote_addr, local_addr.addr()));
+            remote_addr = local_addr.addr().into();
+            reserved.push(local_addr);
+        }
+        chain.reverse();
+
+        let idle_timeout = match chain.first() {
+            None => return Ok(None),
+            Some((c, ..)) => c.plugin_supervision.idle_timeout,
+        };
+
+        Ok(Some(LazyPlugins {
+            chain,
+            reserved: SpinMutex::new(reserved),
+            idle_timeout,
+            task: Mutex::new(None),
+            users: AtomicUsize::new(0),
+            last_used: SpinMutex::new(Instant::now()),
+        }))
+    }
+
+    /// Local address of the first plugin, which shadowsocks connects to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.chain[0].2
+    }
+
+    /// Check if the first plugin relays UDP
+    pub fn enable_udp(&self) -> bool {
+        self.chain[0].0.plugin_mode.enable_udp()
+    }
+
+    /// Check if plugins are running
+    pub fn is_running(&self) -> bool {
+        match self.task.try_lock() {
+            Ok(task) => task.as_ref().is_some_and(|t| !t.is_finished()),
+            // Being started or stopped
+            Err(..) => false,
+        }
+    }
+
+    /// Start plugins if they are not running, plugins are kept running until the returned guard is dropped
+    pub async fn acquire(self: &Arc<Self>) -> io::Result<LazyPluginGuard> {
+        // Counted before checking the task, so idle plugins won't be stopped under the new user
+        self.users.fetch_add(1, Ordering::AcqRel);
+        let guard = LazyPluginGuard { plugins: self.clone() };
+
+        let mut task = self.task.lock().await;
+        if task.as_ref().is_some_and(|t| !t.is_finished()) {
+            return Ok(guard);
+        }
+
+        // Released for plugins to listen on
+        self.reserved.lock().clear();
+
+        let plugins = match self.start().await {
+            Ok(p) => p,
+            Err(err) => {
+                self.hold_local_addrs().await;
+                return Err(err);
+            }
+        };
+
+        info!("started plugins of server {} on demand", self.server_addr());
+
+        *task = Some(tokio::spawn(LazyPlugins::supervise(Arc::downgrade(self), plugins)));
+        Ok(guard)
+    }
+
+    async fn start(&self) -> io::Result<Vec<Plugin>> {
+        let mut plugins = Vec::with_capacity(self.chain.len());
+        for (c, remote_addr, local_addr) in self.chain.iter().rev() {
+            plugins.push(Plugin::start_on(c, remote_addr, *local_addr, PluginMode::Client)?);
+        }
+        plugins.reverse();
+
+        for plugin in &mut plugins {
+            let timeout = plugin.config().plugin_supervision.startup_timeout;
+            if !plugin.check_started(timeout).await? {
+                warn!(
+                    "plugin \"{}\" isn't listening on {} in {:?}",
+                    plugin.config().plugin,
+                    plugin.local_addr(),
+                    timeout
+                );
+            }
+        }
+
+        Ok(plugins)
+    }
+
+    async fn supervise(plugins: Weak<LazyPlugins>, started: Vec<Plugin>) {
+        let idle_timeout = match plugins.upgrade() {
+            Some(p) => p.idle_timeout,
+            None => return,
+        };
+
+        // Plugins are restarted by supervisors, and stopped when supervisors are dropped
+        let mut supervisors = Box::pin(future::join_all(
+            started.into_iter().map(|plugin| plugin.supervise(|_| {})),
+        ));
+
+        let idle_timeout = match idle_timeout {
+            Some(t) => t,
+            None => {
+                supervisors.await;
+                return;
+            }
+        };
+
+        loop {
+            tokio::select! {
+                _ = &mut supervisors => return,
+                _ = time::sleep(idle_timeout.min(Duration::from_secs(10))) => {}
+            }
+
+            let lazy = match plugins.upgrade() {
+                Some(p) => p,
+                None => return,
+            };
+
+            let mut task = lazy.task.lock().await;
+            if lazy.users.load(Ordering::Acquire) == 0 && lazy.last_used.lock().elapsed() >= idle_timeout {
+                debug!(
+                    "plugins of server {} idle for {:?}, stopping",
+                    lazy.server_addr(),
+                    idle_timeout
+                );
+
+                // Stopped before the next user could start them again
+                task.take();
+                drop(supervisors);
+                lazy.hold_local_addrs().await;
+                return;
+            }
+        }
+    }
+
+    /// Hold local addresses of plugins after they are stopped, plugins may take a while to exit and close listeners
+    async fn hold_local_addrs(&self) {
+        const MAX_RETRIES: usize = 20;
+
+        let mut reserved = Vec::with_capacity(self.chain.len());
+        for (c, _, local_addr) in self.chain.iter() {
+            let mut retries = 0;
+            loop {
+                match ReservedLocalAddr::reserve(*local_addr, c.plugin_mode) {
+                    Ok(r) => {
+                        reserved.push(r);
+                        break;
+                    }
+                    Err(err) if retries < MAX_RETRIES => {
+                        trace!("plugin local address {} is still in use, error: {}", local_addr, err);
+                        retries += 1;
+                        time::sleep(Duration::from_millis(100)).await;
+                    }
+                    Err(err) => {
+                        warn!(
+                            "failed to hold local address {} of plugin \"{}\", error: {}",
+                            local_addr, c.plugin, err
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+        *self.reserved.lock() = reserved;
+    }
+
+    fn server_addr(&self) -> &ServerAddr {
+        &self.chain[self.chain.len() - 1].1
+    }
+}
+
+/// Keeps lazily started plugins running
+#[derive(Debug)]
+pub struct LazyPluginGuard {
+    plugins: Arc<LazyPlugins>,
+}
+
+impl Drop for LazyPluginGuard {
+    fn drop(&mut self) {
+        *self.plugins.last_used.lock() = Instant::now();
+        self.plugins.users.fetch_sub(1, Ordering::AcqRel);
+    }
+}
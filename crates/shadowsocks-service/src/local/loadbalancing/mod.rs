@@ -2,9 +2,10 @@
 
 pub use self::{
     ping_balancer::{PingBalancer, PingBalancerBuilder, ServerType},
-    server_data::{ServerIdent, ServerScore},
+    server_data::{ServerIdent, ServerLatency, ServerScore},
 };
 
+pub mod lazy_plugin;
 pub mod ping_balancer;
 pub mod server_data;
 pub mod server_stat;
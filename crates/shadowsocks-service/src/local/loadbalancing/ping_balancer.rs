@@ -3,9 +3,10 @@
 use std::{
     cmp,
     fmt::{self, Debug, Display},
+    hash::{DefaultHasher, Hash, Hasher},
     io,
     iter::Iterator,
-    net::{Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::{
         Arc,
         atomic::{AtomicUsize, Ordering},
@@ -13,33 +14,41 @@ use std::{
     time::{Duration, Instant},
 };
 
-use arc_swap::ArcSwap;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use byte_string::ByteStr;
 use futures::future;
 use log::{debug, error, info, trace, warn};
+use rand::Rng;
 use shadowsocks::{
     ServerConfig,
     config::{Mode, ServerSource},
-    plugin::{Plugin, PluginMode},
+    plugin::{Plugin, PluginEvent, PluginMode},
     relay::{
         socks5::Address,
-        tcprelay::proxy_stream::ProxyClientStream,
         udprelay::{MAXIMUM_UDP_PAYLOAD_SIZE, options::UdpSocketControlData, proxy_socket::ProxySocket},
     },
 };
 use spin::Mutex as SpinMutex;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    sync::Notify,
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::{Notify, mpsc},
     task::JoinHandle,
     time,
 };
 
-use crate::{config::ServerInstanceConfig, local::context::ServiceContext};
+use crate::{
+    acl::RoutingTable,
+    config::{BalancerCheckUrl, BalancerStrategy, ServerInstanceConfig},
+    events::{self, Event},
+    local::{context::ServiceContext, net::ProxiedClientStream},
+    metrics_sink::{self, BALANCER_SCORE},
+    utils::spawn_cancellable,
+};
 
 use super::{
-    server_data::ServerIdent,
-    server_stat::{DEFAULT_CHECK_INTERVAL_SEC, DEFAULT_CHECK_TIMEOUT_SEC, Score},
+    lazy_plugin::LazyPlugins,
+    server_data::{ServerIdent, ServerScore},
+    server_stat::{DEFAULT_CHECK_INTERVAL_SEC, DEFAULT_CHECK_TIMEOUT_SEC, DEFAULT_SWITCH_THRESHOLD, Score},
 };
 
 const EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW: u32 = 67;
@@ -68,6 +77,11 @@ pub struct PingBalancerBuilder {
     max_server_rtt: Duration,
     check_interval: Duration,
     check_best_interval: Option<Duration>,
+    switch_threshold: f64,
+    strategy: BalancerStrategy,
+    check_url: BalancerCheckUrl,
+    routes: RoutingTable,
+    race: bool,
 }
 
 impl PingBalancerBuilder {
@@ -79,6 +93,11 @@ impl PingBalancerBuilder {
             max_server_rtt: Duration::from_secs(DEFAULT_CHECK_TIMEOUT_SEC),
             check_interval: Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC),
             check_best_interval: None,
+            switch_threshold: DEFAULT_SWITCH_THRESHOLD,
+            strategy: BalancerStrategy::default(),
+            check_url: BalancerCheckUrl::default(),
+            routes: RoutingTable::new(),
+            race: false,
         }
     }
 
@@ -104,6 +123,31 @@ impl PingBalancerBuilder {
         self.check_best_interval = Some(intv);
     }
 
+    /// Switch to another server only if its score is lower than the current best server's by this ratio
+    pub fn switch_threshold(&mut self, threshold: f64) {
+        self.switch_threshold = threshold;
+    }
+
+    /// Strategy of choosing server for each connection
+    pub fn strategy(&mut self, strategy: BalancerStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// URL fetched through servers for checking TCP availability
+    pub fn check_url(&mut self, check_url: BalancerCheckUrl) {
+        self.check_url = check_url;
+    }
+
+    /// Routes of targets to specific servers, consulted before choosing servers by strategy
+    pub fn routes(&mut self, routes: RoutingTable) {
+        self.routes = routes;
+    }
+
+    /// Race TCP connections to the best 2 servers, using whichever connects first
+    pub fn race(&mut self, race: bool) {
+        self.race = race;
+    }
+
     fn find_best_idx(servers: &[Arc<ServerIdent>], mode: Mode) -> (usize, usize) {
         if servers.is_empty() {
             trace!("init without any TCP and UDP servers");
@@ -172,6 +216,19 @@ impl PingBalancerBuilder {
             }
         }
 
+        for route in self.routes.routes() {
+            if !self
+                .servers
+                .iter()
+                .any(|s| server_name_matches(s.server_config(), route.server()))
+            {
+                warn!(
+                    "route to server {} doesn't match any servers, it will be ignored",
+                    route.server()
+                );
+            }
+        }
+
         let (shared_context, task_abortable) = PingBalancerContext::new(
             self.servers,
             self.context,
@@ -179,6 +236,9 @@ impl PingBalancerBuilder {
             self.max_server_rtt,
             self.check_interval,
             self.check_best_interval,
+            self.switch_threshold,
+            self.strategy,
+            Arc::new(self.check_url),
         )
         .await?;
 
@@ -186,14 +246,17 @@ impl PingBalancerBuilder {
             inner: Arc::new(PingBalancerInner {
                 context: ArcSwap::new(shared_context),
                 task_abortable: SpinMutex::new(task_abortable),
+                pinned: ArcSwapOption::empty(),
+                routes: self.routes,
+                race: self.race,
             }),
         })
     }
 }
 
 struct PingBalancerContextTask {
-    checker_abortable: JoinHandle<()>,
-    plugin_abortable: Option<JoinHandle<()>>,
+    checker_abortable: JoinHandle<Option<()>>,
+    plugin_abortable: Option<JoinHandle<Option<()>>>,
 }
 
 impl Drop for PingBalancerContextTask {
@@ -214,18 +277,254 @@ struct PingBalancerContext {
     max_server_rtt: Duration,
     check_interval: Duration,
     check_best_interval: Option<Duration>,
+    switch_threshold: f64,
+    strategy: BalancerStrategy,
+    check_url: Arc<BalancerCheckUrl>,
+    // Current weights of TCP and UDP servers for weighted round-robin
+    round_robin_weights: SpinMutex<(Vec<f64>, Vec<f64>)>,
     best_task_notify: Notify,
 }
 
 impl PingBalancerContext {
     fn best_tcp_server(&self) -> Arc<ServerIdent> {
         assert!(!self.is_empty(), "no available server");
-        self.servers[self.best_tcp_idx.load(Ordering::Relaxed)].clone()
+        let idx = self
+            .choose_weighted(ServerType::Tcp)
+            .unwrap_or_else(|| self.choose_best(ServerType::Tcp));
+        self.servers[idx].clone()
     }
 
     fn best_udp_server(&self) -> Arc<ServerIdent> {
         assert!(!self.is_empty(), "no available server");
-        self.servers[self.best_udp_idx.load(Ordering::Relaxed)].clone()
+        let idx = self
+            .choose_weighted(ServerType::Udp)
+            .unwrap_or_else(|| self.choose_best(ServerType::Udp));
+        self.servers[idx].clone()
+    }
+
+    /// Best server chosen by the latest checks, without choosing again
+    fn current_server(&self, server_type: ServerType) -> Arc<ServerIdent> {
+        assert!(!self.is_empty(), "no available server");
+        let idx = match server_type {
+            ServerType::Tcp => self.best_tcp_idx.load(Ordering::Acquire),
+            ServerType::Udp => self.best_udp_idx.load(Ordering::Acquire),
+        };
+        self.servers[idx].clone()
+    }
+
+    fn best_server_for(&self, server_type: ServerType, target: &Address) -> Arc<ServerIdent> {
+        assert!(!self.is_empty(), "no available server");
+        let idx = self
+            .choose_by_target(server_type, target)
+            .or_else(|| self.choose_weighted(server_type))
+            .unwrap_or_else(|| self.choose_best(server_type));
+        self.servers[idx].clone()
+    }
+
+    /// Choose the best server by the latest scores
+    ///
+    /// Scores are updated by failures of requests between checks, so the balancer could leave a failing
+    /// server before the next check.
+    fn choose_best(&self, server_type: ServerType) -> usize {
+        let best_idx = match server_type {
+            ServerType::Tcp => &self.best_tcp_idx,
+            ServerType::Udp => &self.best_udp_idx,
+        };
+
+        let old_best_idx = best_idx.load(Ordering::Acquire);
+        if !self.probing_required() {
+            return old_best_idx;
+        }
+
+        let new_best_idx = choose_best_server(self.choosing_scores(server_type), old_best_idx, self.switch_threshold);
+
+        if new_best_idx != old_best_idx
+            && best_idx
+                .compare_exchange(old_best_idx, new_best_idx, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            let from = ServerConfigFormatter::new(self.servers[old_best_idx].server_config()).to_string();
+            let to = ServerConfigFormatter::new(self.servers[new_best_idx].server_config()).to_string();
+            info!("switched best {} server from {} to {} (request)", server_type, from, to);
+            events::publish(|| Event::BalancerSwitched {
+                protocol: match server_type {
+                    ServerType::Tcp => "tcp",
+                    ServerType::Udp => "udp",
+                },
+                from,
+                to,
+            });
+        }
+        new_best_idx
+    }
+
+    /// Healthy TCP server with the lowest score other than `best`
+    fn runner_up_tcp_server(&self, best: &Arc<ServerIdent>) -> Option<Arc<ServerIdent>> {
+        self.servers
+            .iter()
+            .zip(self.choosing_scores(ServerType::Tcp))
+            .filter(|(s, score)| {
+                *score != u32::MAX
+                    && !Arc::ptr_eq(s, best)
+                    && PingBalancerContext::check_server_tcp_enabled(s.server_config())
+            })
+            .min_by_key(|(_, score)| *score)
+            .map(|(s, _)| s.clone())
+    }
+
+    /// Scores of servers for choosing the best server
+    ///
+    /// Backup servers are never preferred unless all primary servers are down.
+    fn choosing_scores(&self, server_type: ServerType) -> impl Iterator<Item = u32> + '_ {
+        let use_backup = self.use_backup_servers(server_type);
+        self.servers.iter().map(move |s| {
+            if s.server_instance_config().backup && !use_backup {
+                return u32::MAX;
+            }
+            match server_type {
+                ServerType::Tcp => effective_score(s.tcp_score()),
+                ServerType::Udp => effective_score(s.udp_score()),
+            }
+        })
+    }
+
+    /// Check if backup servers should be used, which is when there is no healthy primary server
+    fn use_backup_servers(&self, server_type: ServerType) -> bool {
+        !self.servers.iter().any(|s| {
+            let svr_cfg = s.server_config();
+            !s.server_instance_config().backup
+                && match server_type {
+                    ServerType::Tcp => {
+                        PingBalancerContext::check_server_tcp_enabled(svr_cfg) && s.tcp_score().is_healthy()
+                    }
+                    ServerType::Udp => {
+                        PingBalancerContext::check_server_udp_enabled(svr_cfg) && s.udp_score().is_healthy()
+                    }
+                }
+        })
+    }
+
+    fn server_weight(&self, server: &ServerIdent, server_type: ServerType, use_backup: bool) -> f64 {
+        if server.server_instance_config().backup && !use_backup {
+            return 0.0;
+        }
+
+        let svr_cfg = server.server_config();
+        match server_type {
+            ServerType::Tcp
+                if PingBalancerContext::check_server_tcp_enabled(svr_cfg) && server.tcp_score().is_healthy() =>
+            {
+                svr_cfg.weight().tcp_weight() as f64
+            }
+            ServerType::Udp
+                if PingBalancerContext::check_server_udp_enabled(svr_cfg) && server.udp_score().is_healthy() =>
+            {
+                svr_cfg.weight().udp_weight() as f64
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Choose a server in proportion to servers' weights, `None` if strategy is not weighted
+    fn choose_weighted(&self, server_type: ServerType) -> Option<usize> {
+        match self.strategy {
+            BalancerStrategy::Best | BalancerStrategy::DestinationHash => None,
+            BalancerStrategy::WeightedRandom => {
+                let use_backup = self.use_backup_servers(server_type);
+                let total: f64 = self
+                    .servers
+                    .iter()
+                    .map(|s| self.server_weight(s, server_type, use_backup))
+                    .sum();
+                if total <= 0.0 {
+                    return None;
+                }
+
+                let mut point = rand::rng().random_range(0.0..total);
+                for (idx, server) in self.servers.iter().enumerate() {
+                    let weight = self.server_weight(server, server_type, use_backup);
+                    if weight <= 0.0 {
+                        continue;
+                    }
+                    if point < weight {
+                        return Some(idx);
+                    }
+                    point -= weight;
+                }
+                None
+            }
+            BalancerStrategy::WeightedRoundRobin => {
+                // Smooth weighted round-robin, servers are interleaved instead of being chosen in bursts
+                let mut round_robin_weights = self.round_robin_weights.lock();
+                let current_weights = match server_type {
+                    ServerType::Tcp => &mut round_robin_weights.0,
+                    ServerType::Udp => &mut round_robin_weights.1,
+                };
+
+                let use_backup = self.use_backup_servers(server_type);
+                let mut total = 0.0;
+                let mut chosen: Option<usize> = None;
+                for (idx, server) in self.servers.iter().enumerate() {
+                    let weight = self.server_weight(server, server_type, use_backup);
+                    if weight <= 0.0 {
+                        continue;
+                    }
+
+                    current_weights[idx] += weight;
+                    total += weight;
+
+                    if chosen.is_none_or(|c| current_weights[idx] > current_weights[c]) {
+                        chosen = Some(idx);
+                    }
+                }
+
+                if let Some(c) = chosen {
+                    current_weights[c] -= total;
+                }
+                chosen
+            }
+        }
+    }
+
+    /// Choose a server by hash of `target`'s host, `None` if strategy is not `DestinationHash`
+    ///
+    /// Weighted rendezvous hashing, so when a server is down, only targets of that server
+    /// are moved to the other servers, and they move back after it is up again.
+    fn choose_by_target(&self, server_type: ServerType, target: &Address) -> Option<usize> {
+        if self.strategy != BalancerStrategy::DestinationHash {
+            return None;
+        }
+
+        // Port is ignored, different services of the same site should leave from the same server
+        let mut hasher = DefaultHasher::new();
+        match *target {
+            Address::SocketAddress(ref addr) => addr.ip().to_canonical().hash(&mut hasher),
+            Address::DomainNameAddress(ref host, _) => host.to_ascii_lowercase().hash(&mut hasher),
+        }
+        let target_hash = hasher.finish();
+
+        let use_backup = self.use_backup_servers(server_type);
+        let mut chosen: Option<(usize, f64)> = None;
+        for (idx, server) in self.servers.iter().enumerate() {
+            let weight = self.server_weight(server, server_type, use_backup);
+            if weight <= 0.0 {
+                continue;
+            }
+
+            // Servers are identified by their addresses, which are stable across reloads
+            let mut hasher = DefaultHasher::new();
+            target_hash.hash(&mut hasher);
+            server.server_config().addr().to_string().hash(&mut hasher);
+
+            // Uniform in (0, 1)
+            let point = ((hasher.finish() >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+            let score = -weight / point.ln();
+
+            if chosen.is_none_or(|(_, s)| score > s) {
+                chosen = Some((idx, score));
+            }
+        }
+        chosen.map(|(idx, _)| idx)
     }
 
     #[inline]
@@ -235,6 +534,7 @@ impl PingBalancerContext {
 }
 
 impl PingBalancerContext {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn new(
         mut servers: Vec<Arc<ServerIdent>>,
         context: Arc<ServiceContext>,
@@ -242,21 +542,53 @@ impl PingBalancerContext {
         max_server_rtt: Duration,
         check_interval: Duration,
         check_best_interval: Option<Duration>,
+        switch_threshold: f64,
+        strategy: BalancerStrategy,
+        check_url: Arc<BalancerCheckUrl>,
     ) -> io::Result<(Arc<PingBalancerContext>, PingBalancerContextTask)> {
         let plugin_abortable = {
             // Start plugins for TCP proxies
 
             let mut plugins = Vec::with_capacity(servers.len());
 
-            for server in &mut servers {
+            for (idx, server) in servers.iter_mut().enumerate() {
                 let server = Arc::get_mut(server).unwrap();
                 let svr_cfg = server.server_config_mut();
 
-                if let Some(p) = svr_cfg.plugin() {
-                    // Start Plugin Process
-                    let plugin = Plugin::start(p, svr_cfg.addr(), PluginMode::Client)?;
+                // Plugins started by the first connection to the server
+                if svr_cfg
+                    .plugins()
+                    .iter()
+                    .any(|p| !p.is_builtin() && p.plugin_supervision.lazy)
+                {
+                    if let Some(lazy) = LazyPlugins::reserve(svr_cfg.plugins(), svr_cfg.addr())? {
+                        debug!(
+                            "plugins of server {} will be started on demand on {}",
+                            ServerConfigFormatter::new(svr_cfg),
+                            lazy.local_addr()
+                        );
+                        svr_cfg.set_plugin_addr(lazy.local_addr().into());
+                        server.set_lazy_plugins(lazy);
+                    }
+                    continue;
+                }
+
+                // Builtin plugins run in process
+                let chain = Plugin::start_chain(svr_cfg.plugins(), svr_cfg.addr(), PluginMode::Client)?;
+                if let Some(plugin) = chain.first() {
                     svr_cfg.set_plugin_addr(plugin.local_addr().into());
-                    plugins.push(plugin);
+                }
+
+                for plugin in chain {
+                    let p = plugin.config();
+                    if svr_cfg.mode().enable_udp() && !p.plugin_mode.enable_udp() {
+                        warn!(
+                            "UDP of server {} bypasses plugin \"{}\", set `plugin_mode` to \"tcp_and_udp\" if it supports SIP003u",
+                            svr_cfg.addr(),
+                            p.plugin
+                        );
+                    }
+                    plugins.push((idx, plugin));
                 }
             }
 
@@ -268,36 +600,62 @@ impl PingBalancerContext {
 
                 let mut check_fut = Vec::with_capacity(plugins.len());
 
-                for plugin in &plugins {
-                    // 3 seconds is not a carefully selected value
-                    // I choose that because any values bigger will make me felt too long.
-                    check_fut.push(plugin.wait_started(Duration::from_secs(3)));
+                for (_, plugin) in &mut plugins {
+                    let timeout = plugin.config().plugin_supervision.startup_timeout;
+                    check_fut.push(plugin.check_started(timeout));
                 }
 
                 // Run all of them simutaneously
-                let _ = future::join_all(check_fut).await;
+                let results = future::join_all(check_fut).await;
+
+                // Plugins listening on Unix domain sockets are connected with them
+                #[cfg(unix)]
+                for (idx, plugin) in &plugins {
+                    if let Some(unix_addr) = plugin.unix_addr() {
+                        let server = Arc::get_mut(&mut servers[*idx]).unwrap();
+                        server.server_config_mut().set_plugin_unix_addr(unix_addr.to_owned());
+                    }
+                }
 
-                let plugin_abortable = tokio::spawn(async move {
-                    let mut vfut = Vec::with_capacity(plugins.len());
+                // Servers with plugins not listening are unusable until checks succeed
+                for ((idx, plugin), result) in plugins.iter().zip(results) {
+                    let server = &servers[*idx];
+                    let svr_cfg = server.server_config();
+                    match result {
+                        Ok(true) => continue,
+                        Ok(false) => warn!(
+                            "plugin of server {} isn't listening, marked down",
+                            ServerConfigFormatter::new(svr_cfg)
+                        ),
+                        Err(err) => error!(
+                            "plugin of server {} failed to start, marked down, {}",
+                            ServerConfigFormatter::new(svr_cfg),
+                            err
+                        ),
+                    }
 
-                    for plugin in plugins {
-                        vfut.push(async move {
-                            match plugin.join().await {
-                                Ok(status) => {
-                                    error!("plugin exited with status: {}", status);
-                                    Ok(())
-                                }
-                                Err(err) => {
-                                    error!("plugin exited with error: {}", err);
-                                    Err(err)
-                                }
-                            }
-                        });
+                    let plugin_mode = plugin.config().plugin_mode;
+                    if plugin_mode.enable_tcp() {
+                        server.tcp_score().mark_down().await;
+                    }
+                    if plugin_mode.enable_udp() {
+                        server.udp_score().mark_down().await;
                     }
+                }
+
+                let plugins = plugins
+                    .into_iter()
+                    .map(|(idx, plugin)| (servers[idx].clone(), plugin))
+                    .collect::<Vec<_>>();
 
-                    let _ = future::join_all(vfut).await;
+                let plugin_abortable = spawn_cancellable(context.cancellation_token(), async move {
+                    let mut vfut = Vec::with_capacity(plugins.len());
+
+                    for (server, plugin) in plugins {
+                        vfut.push(supervise_plugin(server, plugin));
+                    }
 
-                    panic!("all plugins are exited. all connections may fail, check your configuration");
+                    future::join_all(vfut).await;
                 });
 
                 Some(plugin_abortable)
@@ -306,6 +664,8 @@ impl PingBalancerContext {
 
         let (best_tcp_idx, best_udp_idx) = PingBalancerBuilder::find_best_idx(&servers, mode);
 
+        let round_robin_weights = SpinMutex::new((vec![0.0; servers.len()], vec![0.0; servers.len()]));
+
         let balancer_context = PingBalancerContext {
             servers,
             best_tcp_idx: AtomicUsize::new(best_tcp_idx),
@@ -315,6 +675,10 @@ impl PingBalancerContext {
             max_server_rtt,
             check_interval,
             check_best_interval,
+            switch_threshold,
+            strategy,
+            check_url,
+            round_robin_weights,
             best_task_notify: Notify::new(),
         };
 
@@ -324,7 +688,9 @@ impl PingBalancerContext {
 
         let checker_abortable = {
             let shared_context = shared_context.clone();
-            tokio::spawn(async move { shared_context.checker_task().await })
+            spawn_cancellable(shared_context.context.cancellation_token(), async move {
+                shared_context.checker_task().await
+            })
         };
 
         Ok((
@@ -404,6 +770,7 @@ impl PingBalancerContext {
                     server_type: ServerType::Tcp,
                     context: self.context.clone(),
                     max_server_rtt: self.max_server_rtt,
+                    check_url: self.check_url.clone(),
                 };
                 vfut_tcp.push(checker.check_update_score());
             }
@@ -414,6 +781,7 @@ impl PingBalancerContext {
                     server_type: ServerType::Udp,
                     context: self.context.clone(),
                     max_server_rtt: self.max_server_rtt,
+                    check_url: self.check_url.clone(),
                 };
                 vfut_udp.push(checker.check_update_score());
             }
@@ -440,15 +808,8 @@ impl PingBalancerContext {
         if self.mode.enable_tcp() && check_tcp {
             let old_best_idx = self.best_tcp_idx.load(Ordering::Acquire);
 
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.tcp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let switch_threshold = if first_run { 0.0 } else { self.switch_threshold };
+            let best_idx = choose_best_server(self.choosing_scores(ServerType::Tcp), old_best_idx, switch_threshold);
             self.best_tcp_idx.store(best_idx, Ordering::Release);
 
             if first_run {
@@ -462,6 +823,11 @@ impl PingBalancerContext {
                     ServerConfigFormatter::new(servers[old_best_idx].server_config()),
                     ServerConfigFormatter::new(servers[best_idx].server_config())
                 );
+                events::publish(|| Event::BalancerSwitched {
+                    protocol: "tcp",
+                    from: ServerConfigFormatter::new(servers[old_best_idx].server_config()).to_string(),
+                    to: ServerConfigFormatter::new(servers[best_idx].server_config()).to_string(),
+                });
             } else {
                 debug!(
                     "kept best TCP server {}",
@@ -473,15 +839,8 @@ impl PingBalancerContext {
         if self.mode.enable_udp() && check_udp {
             let old_best_idx = self.best_udp_idx.load(Ordering::Acquire);
 
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.udp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let switch_threshold = if first_run { 0.0 } else { self.switch_threshold };
+            let best_idx = choose_best_server(self.choosing_scores(ServerType::Udp), old_best_idx, switch_threshold);
             self.best_udp_idx.store(best_idx, Ordering::Release);
 
             if first_run {
@@ -495,6 +854,11 @@ impl PingBalancerContext {
                     ServerConfigFormatter::new(servers[old_best_idx].server_config()),
                     ServerConfigFormatter::new(servers[best_idx].server_config())
                 );
+                events::publish(|| Event::BalancerSwitched {
+                    protocol: "udp",
+                    from: ServerConfigFormatter::new(servers[old_best_idx].server_config()).to_string(),
+                    to: ServerConfigFormatter::new(servers[best_idx].server_config()).to_string(),
+                });
             } else {
                 debug!(
                     "kept best UDP server {}",
@@ -530,6 +894,7 @@ impl PingBalancerContext {
                 server_type: ServerType::Tcp,
                 context: self.context.clone(),
                 max_server_rtt: self.max_server_rtt,
+                check_url: self.check_url.clone(),
             };
             vfut.push(checker.check_update_score());
             check_tcp = true;
@@ -541,6 +906,7 @@ impl PingBalancerContext {
                 server_type: ServerType::Udp,
                 context: self.context.clone(),
                 max_server_rtt: self.max_server_rtt,
+                check_url: self.check_url.clone(),
             };
             vfut.push(checker.check_update_score());
             check_udp = true;
@@ -551,15 +917,11 @@ impl PingBalancerContext {
         if self.mode.enable_tcp() && check_tcp {
             let old_best_idx = self.best_tcp_idx.load(Ordering::Acquire);
 
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.tcp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let best_idx = choose_best_server(
+                self.choosing_scores(ServerType::Tcp),
+                old_best_idx,
+                self.switch_threshold,
+            );
             self.best_tcp_idx.store(best_idx, Ordering::Release);
 
             if best_idx != old_best_idx {
@@ -569,6 +931,11 @@ impl PingBalancerContext {
                         ServerConfigFormatter::new(servers[old_best_idx].server_config()),
                         ServerConfigFormatter::new(servers[best_idx].server_config())
                     );
+                    events::publish(|| Event::BalancerSwitched {
+                        protocol: "tcp",
+                        from: ServerConfigFormatter::new(servers[old_best_idx].server_config()).to_string(),
+                        to: ServerConfigFormatter::new(servers[best_idx].server_config()).to_string(),
+                    });
                 } else {
                     debug!(
                         "kept best TCP server {} (best check)",
@@ -581,15 +948,11 @@ impl PingBalancerContext {
         if self.mode.enable_udp() && check_udp {
             let old_best_idx = self.best_udp_idx.load(Ordering::Acquire);
 
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.udp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let best_idx = choose_best_server(
+                self.choosing_scores(ServerType::Udp),
+                old_best_idx,
+                self.switch_threshold,
+            );
             self.best_udp_idx.store(best_idx, Ordering::Release);
 
             if best_idx != old_best_idx {
@@ -599,6 +962,11 @@ impl PingBalancerContext {
                         ServerConfigFormatter::new(servers[old_best_idx].server_config()),
                         ServerConfigFormatter::new(servers[best_idx].server_config())
                     );
+                    events::publish(|| Event::BalancerSwitched {
+                        protocol: "udp",
+                        from: ServerConfigFormatter::new(servers[old_best_idx].server_config()).to_string(),
+                        to: ServerConfigFormatter::new(servers[best_idx].server_config()).to_string(),
+                    });
                 } else {
                     debug!(
                         "kept best UDP server {} (best check)",
@@ -667,9 +1035,104 @@ impl PingBalancerContext {
     }
 }
 
+/// Score of a server for choosing, servers that are down are never preferred
+fn effective_score(score: &ServerScore) -> u32 {
+    if score.is_healthy() { score.score() } else { u32::MAX }
+}
+
+/// Check if server could be referred by `name`, which is its `id`, `remarks` or address
+fn server_name_matches(svr_cfg: &ServerConfig, name: &str) -> bool {
+    svr_cfg.id() == Some(name) || svr_cfg.remarks() == Some(name) || svr_cfg.addr().to_string() == name
+}
+
+/// Choose the server with the lowest score
+///
+/// The current best server is kept unless another server's score is lower by `switch_threshold`,
+/// so the balancer won't flap between servers with similar latency.
+fn choose_best_server<I>(scores: I, old_best_idx: usize, switch_threshold: f64) -> usize
+where
+    I: Iterator<Item = u32>,
+{
+    let mut old_best_score = u32::MAX;
+    let mut best_idx = 0;
+    let mut best_score = u32::MAX;
+    for (idx, score) in scores.enumerate() {
+        if idx == old_best_idx {
+            old_best_score = score;
+        }
+        if score < best_score {
+            best_idx = idx;
+            best_score = score;
+        }
+    }
+
+    if best_idx != old_best_idx && (best_score as f64) > (old_best_score as f64) * (1.0 - switch_threshold) {
+        return old_best_idx;
+    }
+    best_idx
+}
+
+/// Keep plugin of `server` running, and mark `server` down while the plugin is restarting
+async fn supervise_plugin(server: Arc<ServerIdent>, plugin: Plugin) {
+    let plugin_mode = plugin.config().plugin_mode;
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let supervisor = plugin.supervise(move |event| {
+        let _ = tx.send(event);
+    });
+    tokio::pin!(supervisor);
+
+    // Events sent before supervisor returns are still handled, channel closes after that
+    let mut supervisor_finished = false;
+
+    loop {
+        let event = tokio::select! {
+            _ = &mut supervisor, if !supervisor_finished => {
+                supervisor_finished = true;
+                continue;
+            }
+            event = rx.recv() => match event {
+                Some(e) => e,
+                None => return,
+            },
+        };
+
+        match event {
+            PluginEvent::Exited(..) => {
+                warn!(
+                    "server {} marked down until its plugin is restarted",
+                    ServerConfigFormatter::new(server.server_config())
+                );
+                if plugin_mode.enable_tcp() {
+                    server.tcp_score().mark_down().await;
+                }
+                if plugin_mode.enable_udp() {
+                    server.udp_score().mark_down().await;
+                }
+            }
+            PluginEvent::Restarted => {
+                info!(
+                    "plugin of server {} restarted, waiting for checks to reinstate it",
+                    ServerConfigFormatter::new(server.server_config())
+                );
+            }
+            PluginEvent::GaveUp => {
+                error!(
+                    "plugin of server {} won't be restarted, server stays down",
+                    ServerConfigFormatter::new(server.server_config())
+                );
+            }
+        }
+    }
+}
+
 struct PingBalancerInner {
     context: ArcSwap<PingBalancerContext>,
     task_abortable: SpinMutex<PingBalancerContextTask>,
+    // Name of the server chosen manually, kept across `reset_servers`
+    pinned: ArcSwapOption<String>,
+    routes: RoutingTable,
+    race: bool,
 }
 
 impl Drop for PingBalancerInner {
@@ -694,15 +1157,182 @@ impl PingBalancer {
     /// Pick the best TCP server
     pub fn best_tcp_server(&self) -> Arc<ServerIdent> {
         let context = self.inner.context.load();
+        if let Some(server) = self.pinned_server(&context, ServerType::Tcp) {
+            return server;
+        }
         context.best_tcp_server()
     }
 
     /// Pick the best UDP server
     pub fn best_udp_server(&self) -> Arc<ServerIdent> {
         let context = self.inner.context.load();
+        if let Some(server) = self.pinned_server(&context, ServerType::Udp) {
+            return server;
+        }
         context.best_udp_server()
     }
 
+    /// Current best TCP server, for reporting
+    ///
+    /// Unlike `best_tcp_server`, it doesn't choose a server, so it never switches servers or moves weighted strategies.
+    pub fn current_tcp_server(&self) -> Arc<ServerIdent> {
+        let context = self.inner.context.load();
+        if let Some(server) = self.pinned_server(&context, ServerType::Tcp) {
+            return server;
+        }
+        context.current_server(ServerType::Tcp)
+    }
+
+    /// Current best UDP server, for reporting
+    ///
+    /// Unlike `best_udp_server`, it doesn't choose a server, so it never switches servers or moves weighted strategies.
+    pub fn current_udp_server(&self) -> Arc<ServerIdent> {
+        let context = self.inner.context.load();
+        if let Some(server) = self.pinned_server(&context, ServerType::Udp) {
+            return server;
+        }
+        context.current_server(ServerType::Udp)
+    }
+
+    /// Pick the best TCP server for connecting to `target`
+    ///
+    /// Servers are chosen by routes first, then by the strategy, for example, `DestinationHash`.
+    pub fn best_tcp_server_for(&self, target: &Address) -> Arc<ServerIdent> {
+        let context = self.inner.context.load();
+        if let Some(server) = self.routed_server(&context, ServerType::Tcp, target) {
+            return server;
+        }
+        if let Some(server) = self.pinned_server(&context, ServerType::Tcp) {
+            return server;
+        }
+        context.best_server_for(ServerType::Tcp, target)
+    }
+
+    /// Pick the best TCP server for connecting to `target`, and the runner-up to race with if racing is enabled
+    ///
+    /// Routed and pinned servers are never raced.
+    pub fn best_tcp_servers_for(&self, target: &Address) -> (Arc<ServerIdent>, Option<Arc<ServerIdent>>) {
+        let context = self.inner.context.load();
+        if let Some(server) = self.routed_server(&context, ServerType::Tcp, target) {
+            return (server, None);
+        }
+        if let Some(server) = self.pinned_server(&context, ServerType::Tcp) {
+            return (server, None);
+        }
+
+        let best = context.best_server_for(ServerType::Tcp, target);
+        if !self.inner.race {
+            return (best, None);
+        }
+        let runner_up = context.runner_up_tcp_server(&best);
+        (best, runner_up)
+    }
+
+    /// Pick the best UDP server for sending to `target`
+    ///
+    /// Servers are chosen by routes first, then by the strategy, for example, `DestinationHash`.
+    pub fn best_udp_server_for(&self, target: &Address) -> Arc<ServerIdent> {
+        let context = self.inner.context.load();
+        if let Some(server) = self.routed_server(&context, ServerType::Udp, target) {
+            return server;
+        }
+        if let Some(server) = self.pinned_server(&context, ServerType::Udp) {
+            return server;
+        }
+        context.best_server_for(ServerType::Udp, target)
+    }
+
+    /// Force the balancer to choose server `name`, which could be server's `id`, `remarks` or address
+    ///
+    /// The server is chosen even if it is unhealthy, until `unpin_server` is called.
+    /// Targets matching routes are still sent to their routed servers.
+    /// Returns `false` if there is no such server.
+    pub fn pin_server(&self, name: &str) -> bool {
+        let context = self.inner.context.load();
+        if !context
+            .servers
+            .iter()
+            .any(|s| server_name_matches(s.server_config(), name))
+        {
+            return false;
+        }
+
+        info!("balancer pinned to server {}", name);
+        self.inner.pinned.store(Some(Arc::new(name.to_owned())));
+        true
+    }
+
+    /// Release the pinned server, and choose servers automatically
+    pub fn unpin_server(&self) {
+        if self.inner.pinned.swap(None).is_some() {
+            info!("balancer released pinned server");
+        }
+    }
+
+    /// Name of the pinned server
+    pub fn pinned(&self) -> Option<String> {
+        self.inner.pinned.load().as_ref().map(|name| name.as_ref().clone())
+    }
+
+    /// Check if `server` is the pinned server
+    pub fn is_pinned(&self, server: &ServerIdent) -> bool {
+        let pinned = self.inner.pinned.load();
+        pinned
+            .as_ref()
+            .is_some_and(|name| server_name_matches(server.server_config(), name))
+    }
+
+    // Routed servers are chosen even if they are unhealthy, so targets won't leave from unexpected regions
+    fn routed_server(
+        &self,
+        context: &PingBalancerContext,
+        server_type: ServerType,
+        target: &Address,
+    ) -> Option<Arc<ServerIdent>> {
+        if self.inner.routes.is_empty() {
+            return None;
+        }
+
+        let name = self.inner.routes.route(target)?;
+        let server = context.servers.iter().find(|s| {
+            let svr_cfg = s.server_config();
+            let enabled = match server_type {
+                ServerType::Tcp => svr_cfg.mode().enable_tcp(),
+                ServerType::Udp => svr_cfg.mode().enable_udp(),
+            };
+            enabled && server_name_matches(svr_cfg, name)
+        });
+
+        if let Some(server) = server {
+            trace!(
+                "routed {} target {} to server {}",
+                server_type,
+                target,
+                ServerConfigFormatter::new(server.server_config())
+            );
+        }
+        server.cloned()
+    }
+
+    // Servers removed by `reset_servers` are not chosen, and the balancer falls back to automatic
+    fn pinned_server(&self, context: &PingBalancerContext, server_type: ServerType) -> Option<Arc<ServerIdent>> {
+        let pinned = self.inner.pinned.load();
+        let name = pinned.as_ref()?;
+
+        context
+            .servers
+            .iter()
+            .find(|s| {
+                let svr_cfg = s.server_config();
+                let enabled = match server_type {
+                    ServerType::Tcp => svr_cfg.mode().enable_tcp(),
+                    ServerType::Udp => svr_cfg.mode().enable_udp(),
+                };
+                enabled && server_name_matches(svr_cfg, name)
+            })
+            .cloned()
+    }
+
     /// Check if there is no available server
     #[inline]
     pub fn is_empty(&self) -> bool {
@@ -780,6 +1410,9 @@ impl PingBalancer {
             old_context.max_server_rtt,
             old_context.check_interval,
             old_context.check_best_interval,
+            old_context.switch_threshold,
+            old_context.strategy,
+            old_context.check_url.clone(),
         )
         .await?;
 
@@ -813,22 +1446,49 @@ struct PingChecker {
     server_type: ServerType,
     context: Arc<ServiceContext>,
     max_server_rtt: Duration,
+    check_url: Arc<BalancerCheckUrl>,
 }
 
 impl PingChecker {
     /// Checks server's score and update into `ServerScore<E>`
     async fn check_update_score(self) {
+        // Checks don't start plugins on demand, servers not in use keep their scores
+        if !self.server.plugins_running() {
+            trace!(
+                "balancer: skipped checking remote {} server {}, plugins not started",
+                self.server_type,
+                ServerConfigFormatter::new(self.server.server_config()),
+            );
+            return;
+        }
+
         let server_score = match self.server_type {
             ServerType::Tcp => self.server.tcp_score(),
             ServerType::Udp => self.server.udp_score(),
         };
 
+        let was_healthy = server_score.is_healthy();
+
         let (score, stat_data) = match self.check_delay().await {
             Ok(d) => server_score.push_score_fetch_statistic(Score::Latency(d)).await,
             // Penalty
             Err(..) => server_score.push_score_fetch_statistic(Score::Errored).await,
         };
 
+        match (was_healthy, server_score.is_healthy()) {
+            (true, false) => warn!(
+                "balancer: remote {} server {} is down",
+                self.server_type,
+                ServerConfigFormatter::new(self.server.server_config()),
+            ),
+            (false, true) => info!(
+                "balancer: remote {} server {} is up",
+                self.server_type,
+                ServerConfigFormatter::new(self.server.server_config()),
+            ),
+            _ => {}
+        }
+
         if stat_data.fail_rate > 0.8 {
             warn!(
                 "balancer: checked & updated remote {} server {} (score: {}), {:?}",
@@ -846,84 +1506,84 @@ impl PingChecker {
                 stat_data,
             );
         }
+
+        let server_addr = self.server.server_config().addr().to_string();
+        let protocol = match self.server_type {
+            ServerType::Tcp => "tcp",
+            ServerType::Udp => "udp",
+        };
+        metrics_sink::gauge(
+            &BALANCER_SCORE,
+            &[("server", &server_addr), ("protocol", protocol)],
+            f64::from(score),
+        );
     }
 
-    /// Detect TCP connectivity with Chromium [Network Portal Detection](https://www.chromium.org/chromium-os/chromiumos-design-docs/network-portal-detection)
-    #[allow(dead_code)]
-    async fn check_request_tcp_chromium(&self) -> io::Result<()> {
-        use std::io::{Error, ErrorKind};
+    /// Detect TCP connectivity by fetching `check_url` through the server
+    ///
+    /// Defaults to Chromium's [Network Portal Detection](https://www.chromium.org/chromium-os/chromiumos-design-docs/network-portal-detection)
+    async fn check_request_tcp(&self) -> io::Result<()> {
+        let check_url = &self.check_url;
 
-        const GET_BODY: &[u8] =
-            b"GET /generate_204 HTTP/1.1\r\nHost: clients3.google.com\r\nConnection: close\r\nAccept: */*\r\n\r\n";
+        let addr = match check_url.host.parse::<IpAddr>() {
+            Ok(ip) => Address::SocketAddress(SocketAddr::new(ip, check_url.port)),
+            Err(..) => Address::DomainNameAddress(check_url.host.clone(), check_url.port),
+        };
 
-        let addr = Address::DomainNameAddress("clients3.google.com".to_owned(), 80);
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nAccept: */*\r\n\r\n",
+            check_url.path,
+            check_url.host_header()
+        );
+
+        if check_url.https {
+            return self.check_request_tcp_https(addr, request.as_bytes()).await;
+        }
 
-        let mut stream = ProxyClientStream::connect_with_opts(
+        let stream = ProxiedClientStream::connect_with_opts_map(
             self.context.context(),
             self.server.server_config(),
             &addr,
             self.server.connect_opts_ref(),
+            |stream| stream,
         )
         .await?;
-        stream.write_all(GET_BODY).await?;
 
-        let mut reader = BufReader::new(stream);
-
-        let mut buf = Vec::new();
-        reader.read_until(b'\n', &mut buf).await?;
-
-        let mut headers = [httparse::EMPTY_HEADER; 1];
-        let mut response = httparse::Response::new(&mut headers);
-
-        if response.parse(&buf).is_ok() && matches!(response.code, Some(204)) {
-            return Ok(());
-        }
-
-        Err(Error::new(
-            ErrorKind::InvalidData,
-            format!(
-                "unexpected response from http://clients3.google.com/generate_204, {:?}",
-                ByteStr::new(&buf)
-            ),
-        ))
+        check_http_response(stream, request.as_bytes(), check_url).await
     }
 
-    /// Detect TCP connectivity with Firefox's http://detectportal.firefox.com/success.txt
-    async fn check_request_tcp_firefox(&self) -> io::Result<()> {
-        use std::io::{Error, ErrorKind};
-
-        const GET_BODY: &[u8] =
-            b"GET /success.txt HTTP/1.1\r\nHost: detectportal.firefox.com\r\nConnection: close\r\nAccept: */*\r\n\r\n";
-
-        let addr = Address::DomainNameAddress("detectportal.firefox.com".to_owned(), 80);
+    #[cfg(any(feature = "local-http-native-tls", feature = "local-http-rustls"))]
+    async fn check_request_tcp_https(&self, addr: Address, request: &[u8]) -> io::Result<()> {
+        use crate::{
+            local::{http::ProxyHttpStream, net::AutoProxyClientStream},
+            net::{FlowStat, MonProxyStream},
+        };
 
-        let mut stream = ProxyClientStream::connect_with_opts(
+        // Checks are not counted in the flow statistic of clients
+        let flow_stat = Arc::new(FlowStat::new());
+        let stream = ProxiedClientStream::connect_with_opts_map(
             self.context.context(),
             self.server.server_config(),
-            &addr,
+            addr,
             self.server.connect_opts_ref(),
+            |stream| MonProxyStream::from_stream(stream, flow_stat),
         )
         .await?;
-        stream.write_all(GET_BODY).await?;
-
-        let mut reader = BufReader::new(stream);
-
-        let mut buf = Vec::new();
-        reader.read_until(b'\n', &mut buf).await?;
 
-        let mut headers = [httparse::EMPTY_HEADER; 1];
-        let mut response = httparse::Response::new(&mut headers);
-
-        if response.parse(&buf).is_ok() && matches!(response.code, Some(200) | Some(204)) {
+        let stream = ProxyHttpStream::connect_https(AutoProxyClientStream::from(stream), &self.check_url.host).await?;
+        if stream.negotiated_http2() {
+            // TLS handshake with the target was completed through the server, good enough
             return Ok(());
         }
 
-        Err(Error::new(
-            ErrorKind::InvalidData,
-            format!(
-                "unexpected response from http://detectportal.firefox.com/success.txt, {:?}",
-                ByteStr::new(&buf)
-            ),
+        check_http_response(stream, request, &self.check_url).await
+    }
+
+    #[cfg(not(any(feature = "local-http-native-tls", feature = "local-http-rustls")))]
+    async fn check_request_tcp_https(&self, _addr: Address, _request: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "https check_url requires feature \"local-http-native-tls\" or \"local-http-rustls\"",
         ))
     }
 
@@ -975,7 +1635,7 @@ impl PingChecker {
 
     async fn check_request(&self) -> io::Result<()> {
         match self.server_type {
-            ServerType::Tcp => self.check_request_tcp_firefox().await,
+            ServerType::Tcp => self.check_request_tcp().await,
             ServerType::Udp => self.check_request_udp().await,
         }
     }
@@ -1028,6 +1688,33 @@ impl PingChecker {
     }
 }
 
+/// Send `request` and check if the response status is 2xx
+async fn check_http_response<S>(mut stream: S, request: &[u8], check_url: &BalancerCheckUrl) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    use std::io::{Error, ErrorKind};
+
+    stream.write_all(request).await?;
+
+    let mut reader = BufReader::new(stream);
+
+    let mut buf = Vec::new();
+    reader.read_until(b'\n', &mut buf).await?;
+
+    let mut headers = [httparse::EMPTY_HEADER; 1];
+    let mut response = httparse::Response::new(&mut headers);
+
+    if response.parse(&buf).is_ok() && matches!(response.code, Some(200..=299)) {
+        return Ok(());
+    }
+
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        format!("unexpected response from {}, {:?}", check_url, ByteStr::new(&buf)),
+    ))
+}
+
 struct ServerConfigFormatter<'a> {
     server_config: &'a ServerConfig,
 }
@@ -1067,3 +1754,271 @@ impl<'a> Iterator for PingServerIter<'a> {
         self.iter.next().map(AsRef::as_ref)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use shadowsocks::{config::ServerWeight, crypto::CipherKind};
+
+    use super::{super::server_stat::UP_AFTER_SUCCESSES, *};
+
+    fn test_server<F>(context: &Arc<ServiceContext>, port: u16, f: F) -> Arc<ServerIdent>
+    where
+        F: FnOnce(&mut ServerInstanceConfig),
+    {
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+            "test-password".to_owned(),
+            CipherKind::AES_128_GCM,
+        )
+        .unwrap();
+        let mut inst = ServerInstanceConfig::with_server_config(svr_cfg);
+        f(&mut inst);
+        Arc::new(ServerIdent::new(
+            context.clone(),
+            inst,
+            Duration::from_secs(1),
+            Duration::from_secs(30),
+        ))
+    }
+
+    fn tcp_weighted_server(context: &Arc<ServiceContext>, port: u16, weight: f32) -> Arc<ServerIdent> {
+        test_server(context, port, |s| {
+            let mut w = ServerWeight::new();
+            w.set_tcp_weight(weight);
+            s.config.set_weight(w);
+        })
+    }
+
+    fn test_balancer(
+        context: Arc<ServiceContext>,
+        servers: Vec<Arc<ServerIdent>>,
+        strategy: BalancerStrategy,
+    ) -> PingBalancerContext {
+        let round_robin_weights = SpinMutex::new((vec![0.0; servers.len()], vec![0.0; servers.len()]));
+        PingBalancerContext {
+            servers,
+            best_tcp_idx: AtomicUsize::new(0),
+            best_udp_idx: AtomicUsize::new(0),
+            context,
+            mode: Mode::TcpAndUdp,
+            max_server_rtt: Duration::from_secs(1),
+            check_interval: Duration::from_secs(10),
+            check_best_interval: None,
+            switch_threshold: 0.0,
+            strategy,
+            check_url: Arc::new(BalancerCheckUrl::default()),
+            round_robin_weights,
+            best_task_notify: Notify::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn runner_up_tcp_server() {
+        let context = Arc::new(ServiceContext::new());
+        let servers = vec![
+            test_server(&context, 8001, |_| {}),
+            test_server(&context, 8002, |_| {}),
+            test_server(&context, 8003, |_| {}),
+            test_server(&context, 8004, |s| s.config.set_mode(Mode::UdpOnly)),
+            test_server(&context, 8005, |s| s.backup = true),
+        ];
+        for (server, latency) in servers.iter().zip([100, 300, 200, 50, 10]) {
+            server.tcp_score().push_score(Score::Latency(latency)).await;
+        }
+        let balancer = test_balancer(context, servers.clone(), BalancerStrategy::Best);
+
+        // The lowest score other than the best, servers without TCP and backup servers are skipped
+        let runner_up = balancer.runner_up_tcp_server(&servers[0]).unwrap();
+        assert!(Arc::ptr_eq(&runner_up, &servers[2]));
+        let runner_up = balancer.runner_up_tcp_server(&servers[2]).unwrap();
+        assert!(Arc::ptr_eq(&runner_up, &servers[0]));
+
+        // Servers that are down are skipped
+        servers[2].tcp_score().mark_down().await;
+        let runner_up = balancer.runner_up_tcp_server(&servers[0]).unwrap();
+        assert!(Arc::ptr_eq(&runner_up, &servers[1]));
+
+        servers[1].tcp_score().mark_down().await;
+        assert!(balancer.runner_up_tcp_server(&servers[0]).is_none());
+    }
+
+    #[tokio::test]
+    async fn runner_up_tcp_server_backup() {
+        let context = Arc::new(ServiceContext::new());
+        let servers = vec![
+            test_server(&context, 8001, |_| {}),
+            test_server(&context, 8002, |s| s.backup = true),
+            test_server(&context, 8003, |s| s.backup = true),
+        ];
+        for (server, latency) in servers.iter().zip([100, 300, 200]) {
+            server.tcp_score().push_score(Score::Latency(latency)).await;
+        }
+        let balancer = test_balancer(context, servers.clone(), BalancerStrategy::Best);
+
+        assert!(balancer.runner_up_tcp_server(&servers[0]).is_none());
+
+        // Backup servers are used after all primary servers are down
+        servers[0].tcp_score().mark_down().await;
+        let runner_up = balancer.runner_up_tcp_server(&servers[2]).unwrap();
+        assert!(Arc::ptr_eq(&runner_up, &servers[1]));
+    }
+
+    #[tokio::test]
+    async fn choose_best_server_backup() {
+        let context = Arc::new(ServiceContext::new());
+        let servers = vec![
+            test_server(&context, 8001, |_| {}),
+            test_server(&context, 8002, |_| {}),
+            test_server(&context, 8003, |s| s.backup = true),
+        ];
+        for (server, latency) in servers.iter().zip([300, 200, 10]) {
+            server.tcp_score().push_score(Score::Latency(latency)).await;
+        }
+        let balancer = test_balancer(context, servers.clone(), BalancerStrategy::Best);
+        let best = || choose_best_server(balancer.choosing_scores(ServerType::Tcp), 0, 0.0);
+
+        // The backup server is never chosen while any primary server is healthy, even if it is faster
+        assert_eq!(best(), 1);
+        servers[1].tcp_score().mark_down().await;
+        assert_eq!(best(), 0);
+
+        // Chosen after all primary servers are down
+        servers[0].tcp_score().mark_down().await;
+        assert_eq!(best(), 2);
+
+        // And not anymore after a primary server recovers
+        for _ in 0..UP_AFTER_SUCCESSES {
+            servers[0].tcp_score().push_score(Score::Latency(300)).await;
+        }
+        assert_eq!(best(), 0);
+    }
+
+    #[test]
+    fn choose_weighted_round_robin() {
+        let context = Arc::new(ServiceContext::new());
+        let servers = vec![
+            tcp_weighted_server(&context, 8001, 1.0),
+            tcp_weighted_server(&context, 8002, 0.5),
+            tcp_weighted_server(&context, 8003, 0.5),
+        ];
+        let balancer = test_balancer(context, servers, BalancerStrategy::WeightedRoundRobin);
+
+        // Smooth weighted round-robin interleaves servers
+        let chosen = (0..8)
+            .map(|_| balancer.choose_weighted(ServerType::Tcp).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(chosen, [0, 1, 2, 0, 0, 1, 2, 0]);
+    }
+
+    #[tokio::test]
+    async fn choose_weighted_skips_unavailable() {
+        let context = Arc::new(ServiceContext::new());
+        let servers = vec![
+            tcp_weighted_server(&context, 8001, 1.0),
+            tcp_weighted_server(&context, 8002, 0.0),
+            test_server(&context, 8003, |s| s.config.set_mode(Mode::UdpOnly)),
+            test_server(&context, 8004, |s| s.backup = true),
+            tcp_weighted_server(&context, 8005, 1.0),
+        ];
+        servers[4].tcp_score().mark_down().await;
+
+        for strategy in [BalancerStrategy::WeightedRandom, BalancerStrategy::WeightedRoundRobin] {
+            let balancer = test_balancer(context.clone(), servers.clone(), strategy);
+            for _ in 0..16 {
+                assert_eq!(balancer.choose_weighted(ServerType::Tcp), Some(0));
+            }
+        }
+
+        // Backup servers are chosen after all primary servers are down
+        servers[0].tcp_score().mark_down().await;
+        let balancer = test_balancer(context.clone(), servers.clone(), BalancerStrategy::WeightedRoundRobin);
+        assert_eq!(balancer.choose_weighted(ServerType::Tcp), Some(3));
+
+        servers[3].tcp_score().mark_down().await;
+        assert_eq!(balancer.choose_weighted(ServerType::Tcp), None);
+    }
+
+    #[test]
+    fn choose_weighted_random() {
+        let context = Arc::new(ServiceContext::new());
+        let servers = vec![
+            tcp_weighted_server(&context, 8001, 1.0),
+            tcp_weighted_server(&context, 8002, 0.25),
+        ];
+        let balancer = test_balancer(context, servers, BalancerStrategy::WeightedRandom);
+
+        let mut counts = [0; 2];
+        for _ in 0..1000 {
+            counts[balancer.choose_weighted(ServerType::Tcp).unwrap()] += 1;
+        }
+        // 800 and 200 are expected
+        assert!((100..=300).contains(&counts[1]), "{counts:?}");
+    }
+
+    #[test]
+    fn choose_weighted_other_strategies() {
+        let context = Arc::new(ServiceContext::new());
+        let servers = vec![tcp_weighted_server(&context, 8001, 1.0)];
+        for strategy in [BalancerStrategy::Best, BalancerStrategy::DestinationHash] {
+            let balancer = test_balancer(context.clone(), servers.clone(), strategy);
+            assert_eq!(balancer.choose_weighted(ServerType::Tcp), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn choose_by_target_sticky() {
+        let context = Arc::new(ServiceContext::new());
+        let servers = (8001..8005)
+            .map(|port| test_server(&context, port, |_| {}))
+            .collect::<Vec<_>>();
+        let balancer = test_balancer(context, servers.clone(), BalancerStrategy::DestinationHash);
+
+        let targets = (0..32)
+            .map(|i| Address::DomainNameAddress(format!("site{i}.example.com"), 443))
+            .collect::<Vec<_>>();
+        let chosen = targets
+            .iter()
+            .map(|target| balancer.choose_by_target(ServerType::Tcp, target).unwrap())
+            .collect::<Vec<_>>();
+
+        // A target sticks to its server, port and case of the host are ignored
+        for (target, &idx) in targets.iter().zip(&chosen) {
+            assert_eq!(balancer.choose_by_target(ServerType::Tcp, target), Some(idx));
+        }
+        let same_site = Address::DomainNameAddress("SITE0.example.com".to_owned(), 80);
+        assert_eq!(balancer.choose_by_target(ServerType::Tcp, &same_site), Some(chosen[0]));
+
+        // Only targets of the server that is down are moved
+        let down = chosen[0];
+        servers[down].tcp_score().mark_down().await;
+        for (target, &idx) in targets.iter().zip(&chosen) {
+            let moved = balancer.choose_by_target(ServerType::Tcp, target).unwrap();
+            if idx == down {
+                assert_ne!(moved, down);
+            } else {
+                assert_eq!(moved, idx);
+            }
+        }
+
+        // And they are back after it recovers
+        for _ in 0..UP_AFTER_SUCCESSES {
+            servers[down].tcp_score().push_score(Score::Latency(100)).await;
+        }
+        assert!(servers[down].tcp_score().is_healthy());
+        for (target, &idx) in targets.iter().zip(&chosen) {
+            assert_eq!(balancer.choose_by_target(ServerType::Tcp, target), Some(idx));
+        }
+    }
+
+    #[test]
+    fn choose_best_server_hysteresis() {
+        // Lower by less than threshold, keep the current server
+        assert_eq!(choose_best_server([1000, 950].into_iter(), 0, 0.1), 0);
+        // Lower by more than threshold
+        assert_eq!(choose_best_server([1000, 850].into_iter(), 0, 0.1), 1);
+        // No hysteresis
+        assert_eq!(choose_best_server([1000, 950].into_iter(), 0, 0.0), 1);
+        // Current server doesn't exist anymore
+        assert_eq!(choose_best_server([1000, 950].into_iter(), 2, 0.1), 1);
+    }
+}
@@ -2,10 +2,11 @@
 
 use std::{
     fmt::{self, Debug},
+    io,
     net::SocketAddr,
     sync::{
         Arc,
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
     },
     time::Duration,
 };
@@ -13,16 +14,30 @@ use std::{
 use shadowsocks::{ServerConfig, net::ConnectOpts};
 use tokio::sync::Mutex;
 
-use crate::{config::ServerInstanceConfig, local::context::ServiceContext};
+use crate::{
+    config::ServerInstanceConfig,
+    local::context::ServiceContext,
+    stats::{ErrorCounters, LatencyHistogram, ServerLatencyStats},
+};
 
-use super::server_stat::{Score, ServerStat, ServerStatData};
+use super::{
+    lazy_plugin::{LazyPluginGuard, LazyPlugins},
+    ping_balancer::ServerType,
+    server_stat::{Score, ServerStat, ServerStatData},
+};
 
 /// Server's statistic score
 pub struct ServerScore {
     stat_data: Mutex<ServerStat>,
     score: AtomicU32,
+    healthy: AtomicBool,
+    last_latency: AtomicU32,
+    failures: AtomicU64,
 }
 
+// Marks `last_latency` as never probed successfully
+const NO_LATENCY: u32 = u32::MAX;
+
 impl ServerScore {
     /// Create a `ServerScore`
     pub fn new(user_weight: f32, max_server_rtt: Duration, check_window: Duration) -> ServerScore {
@@ -32,6 +47,9 @@ impl ServerScore {
         ServerScore {
             stat_data: Mutex::new(ServerStat::new(user_weight, max_server_rtt, check_window)),
             score: AtomicU32::new(u32::MAX),
+            healthy: AtomicBool::new(true),
+            last_latency: AtomicU32::new(NO_LATENCY),
+            failures: AtomicU64::new(0),
         }
     }
 
@@ -40,23 +58,56 @@ impl ServerScore {
         self.score.load(Ordering::Acquire)
     }
 
+    /// Check if server is up
+    ///
+    /// Servers are marked down after consecutive failures, and reinstated after consecutive successful checks.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Acquire)
+    }
+
+    /// Latency of the last successful probe (in millisec)
+    pub fn last_latency(&self) -> Option<u32> {
+        match self.last_latency.load(Ordering::Acquire) {
+            NO_LATENCY => None,
+            lat => Some(lat),
+        }
+    }
+
+    /// Total count of errors, including failed checks and failed requests
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, score: Score) {
+        match score {
+            Score::Latency(lat) => self.last_latency.store(lat.min(NO_LATENCY - 1), Ordering::Release),
+            Score::Errored => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
     /// Append a `Score` into statistic and recalculate score of the server
     pub async fn push_score(&self, score: Score) -> u32 {
-        let updated_score = {
+        self.record(score);
+        let (updated_score, healthy) = {
             let mut stat = self.stat_data.lock().await;
-            stat.push_score(score)
+            (stat.push_score(score), stat.is_healthy())
         };
         self.score.store(updated_score, Ordering::Release);
+        self.healthy.store(healthy, Ordering::Release);
         updated_score
     }
 
     /// Append a `Score` into statistic and recalculate score of the server
     pub async fn push_score_fetch_statistic(&self, score: Score) -> (u32, ServerStatData) {
-        let (updated_score, data) = {
+        self.record(score);
+        let (updated_score, data, healthy) = {
             let mut stat = self.stat_data.lock().await;
-            (stat.push_score(score), *stat.data())
+            (stat.push_score(score), *stat.data(), stat.is_healthy())
         };
         self.score.store(updated_score, Ordering::Release);
+        self.healthy.store(healthy, Ordering::Release);
         (updated_score, data)
     }
 
@@ -65,6 +116,12 @@ impl ServerScore {
         self.push_score(Score::Errored).await
     }
 
+    /// Mark server down without waiting for consecutive failures, such as its plugin exited
+    pub async fn mark_down(&self) {
+        self.stat_data.lock().await.mark_down();
+        self.healthy.store(false, Ordering::Release);
+    }
+
     /// Get statistic data
     pub async fn stat_data(&self) -> ServerStatData {
         *self.stat_data.lock().await.data()
@@ -73,7 +130,46 @@ impl ServerScore {
 
 impl Debug for ServerScore {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("ServerScore").field("score", &self.score()).finish()
+        f.debug_struct("ServerScore")
+            .field("score", &self.score())
+            .field("healthy", &self.is_healthy())
+            .field("last_latency", &self.last_latency())
+            .field("failures", &self.failures())
+            .finish()
+    }
+}
+
+/// Latency histograms of TCP connections relayed by a server
+#[derive(Debug, Default)]
+pub struct ServerLatency {
+    connect: LatencyHistogram,
+    handshake: LatencyHistogram,
+    first_byte: LatencyHistogram,
+}
+
+impl ServerLatency {
+    /// Record time spent in connecting to the server, or its plugin
+    pub fn record_connect(&self, latency: Duration) {
+        self.connect.record(latency);
+    }
+
+    /// Record time from sending the request header, to receiving the response from the server
+    pub fn record_handshake(&self, latency: Duration) {
+        self.handshake.record(latency);
+    }
+
+    /// Record time from starting to connect, to receiving the first byte of data
+    pub fn record_first_byte(&self, latency: Duration) {
+        self.first_byte.record(latency);
+    }
+
+    /// Take a snapshot of all histograms
+    pub fn snapshot(&self) -> ServerLatencyStats {
+        ServerLatencyStats {
+            connect: self.connect.snapshot(),
+            handshake: self.handshake.snapshot(),
+            first_byte: self.first_byte.snapshot(),
+        }
     }
 }
 
@@ -82,8 +178,11 @@ impl Debug for ServerScore {
 pub struct ServerIdent {
     tcp_score: ServerScore,
     udp_score: ServerScore,
+    latency: Arc<ServerLatency>,
+    errors: Arc<ErrorCounters>,
     svr_cfg: ServerInstanceConfig,
     connect_opts: ConnectOpts,
+    lazy_plugins: Option<Arc<LazyPlugins>>,
 }
 
 impl ServerIdent {
@@ -109,11 +208,16 @@ impl ServerIdent {
             connect_opts.bind_interface = Some(bind_interface.clone());
         }
 
+        connect_opts.dscp = svr_cfg.outbound_dscp.or(context.server_dscp());
+
         ServerIdent {
             tcp_score: ServerScore::new(svr_cfg.config.weight().tcp_weight(), max_server_rtt, check_window),
             udp_score: ServerScore::new(svr_cfg.config.weight().udp_weight(), max_server_rtt, check_window),
+            latency: Arc::new(ServerLatency::default()),
+            errors: Arc::new(ErrorCounters::new()),
             svr_cfg,
             connect_opts,
+            lazy_plugins: None,
         }
     }
 
@@ -140,4 +244,30 @@ impl ServerIdent {
     pub fn udp_score(&self) -> &ServerScore {
         &self.udp_score
     }
+
+    pub fn latency(&self) -> &Arc<ServerLatency> {
+        &self.latency
+    }
+
+    pub fn errors(&self) -> &Arc<ErrorCounters> {
+        &self.errors
+    }
+
+    /// Set plugins started on demand, the server's `plugin_addr` should be their `local_addr`
+    pub fn set_lazy_plugins(&mut self, plugins: LazyPlugins) {
+        self.lazy_plugins = Some(Arc::new(plugins));
+    }
+
+    /// Check if plugins are running, always `true` unless they are started on demand
+    pub fn plugins_running(&self) -> bool {
+        self.lazy_plugins.as_ref().is_none_or(|p| p.is_running())
+    }
+
+    /// Start plugins on demand for relaying `server_type`, they are kept running until the returned guard is dropped
+    pub async fn acquire_plugins(&self, server_type: ServerType) -> io::Result<Option<LazyPluginGuard>> {
+        match self.lazy_plugins {
+            Some(ref p) if matches!(server_type, ServerType::Tcp) || p.enable_udp() => p.acquire().await.map(Some),
+            _ => Ok(None),
+        }
+    }
 }
@@ -9,6 +9,14 @@ use std::{
 pub const DEFAULT_CHECK_INTERVAL_SEC: u64 = 10;
 /// Timeout of each check
 pub const DEFAULT_CHECK_TIMEOUT_SEC: u64 = 5; // A common connection timeout of 5 seconds.
+/// Switch to another server only if its score is 10% lower than the current best server
+pub const DEFAULT_SWITCH_THRESHOLD: f64 = 0.1;
+/// Smoothing factor of exponentially weighted moving averages, the higher, the more recent results weigh
+pub const EWMA_ALPHA: f64 = 0.3;
+/// Mark a server down after this many consecutive failures
+pub const DOWN_AFTER_FAILURES: u32 = 3;
+/// Reinstate a down server after this many consecutive successful checks
+pub const UP_AFTER_SUCCESSES: u32 = 2;
 
 /// Statistic score
 #[derive(Debug, Copy, Clone)]
@@ -35,6 +43,11 @@ pub struct ServerStatData {
     pub latency_mean: f64,
     /// Score's median absolute deviation
     pub latency_mad: u32,
+    /// Exponentially weighted moving average of latency (in millisec), failures count as the MAX RTT
+    pub latency_ewma: f64,
+    /// Exponentially weighted moving average of failures, including failed checks, lost UDP probes
+    /// and failed requests, in `[0, 1]`
+    pub fail_ewma: f64,
 }
 
 /// Statistic of a remote server
@@ -52,6 +65,13 @@ pub struct ServerStat {
     check_window: Duration,
     /// Statistic Data
     data: ServerStatData,
+    /// Whether moving averages have any sample
+    ewma_initialized: bool,
+    /// Consecutive failures and successes
+    consecutive_failures: u32,
+    consecutive_successes: u32,
+    /// Server is considered down after consecutive failures
+    healthy: bool,
 }
 
 fn max_latency_stdev(max_server_rtt: u32) -> f64 {
@@ -80,13 +100,19 @@ impl ServerStat {
                 latency_stdev: max_latency_stdev,
                 latency_mean: max_server_rtt as f64,
                 latency_mad: max_server_rtt,
+                latency_ewma: max_server_rtt as f64,
+                fail_ewma: 1.0,
             },
+            ewma_initialized: false,
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+            healthy: true,
         }
     }
 
     fn score(&self) -> u32 {
         // Normalize rtt
-        let nrtt = self.data.latency_median as f64 / self.max_server_rtt as f64;
+        let nrtt = self.data.latency_ewma / self.max_server_rtt as f64;
 
         // Normalize stdev
         // let nstdev = self.data.latency_stdev / self.max_latency_stdev;
@@ -102,13 +128,15 @@ impl ServerStat {
         // Just for avoiding divide by 0
         let user_weight = self.user_weight.max(f32::EPSILON);
 
-        // Score = (norm_lat * 1.0 + prop_err * 3.0 + (stdev || mad) * 1.0) / 5.0 / user_weight
+        // Score = (ewma_lat * 1.0 + ewma_err * 3.0 + (stdev || mad) * 1.0) / 5.0 / user_weight
+        //
+        // Moving averages react to recent results quicker than the median and the rate in the whole window.
         //
         // 1. The lower latency, the better
         // 2. The lower errored count, the better
         // 3. The lower latency's stdev / mad, the better
         // 4. The higher user's weight, the better
-        let score = (nrtt * SCORE_RTT_WEIGHT + self.data.fail_rate * SCORE_FAIL_WEIGHT + nmad * SCORE_MAD_WEIGHT)
+        let score = (nrtt * SCORE_RTT_WEIGHT + self.data.fail_ewma * SCORE_FAIL_WEIGHT + nmad * SCORE_MAD_WEIGHT)
             / (SCORE_RTT_WEIGHT + SCORE_FAIL_WEIGHT + SCORE_MAD_WEIGHT)
             / user_weight as f64;
 
@@ -119,6 +147,36 @@ impl ServerStat {
     pub fn push_score(&mut self, score: Score) -> u32 {
         let now = Instant::now();
 
+        let (latency, fail) = match score {
+            Score::Latency(lat) => (lat.min(self.max_server_rtt) as f64, 0.0),
+            Score::Errored => (self.max_server_rtt as f64, 1.0),
+        };
+        if self.ewma_initialized {
+            self.data.latency_ewma += EWMA_ALPHA * (latency - self.data.latency_ewma);
+            self.data.fail_ewma += EWMA_ALPHA * (fail - self.data.fail_ewma);
+        } else {
+            self.data.latency_ewma = latency;
+            self.data.fail_ewma = fail;
+            self.ewma_initialized = true;
+        }
+
+        match score {
+            Score::Errored => {
+                self.consecutive_failures += 1;
+                self.consecutive_successes = 0;
+                if self.consecutive_failures >= DOWN_AFTER_FAILURES {
+                    self.healthy = false;
+                }
+            }
+            Score::Latency(..) => {
+                self.consecutive_successes += 1;
+                self.consecutive_failures = 0;
+                if self.consecutive_successes >= UP_AFTER_SUCCESSES {
+                    self.healthy = true;
+                }
+            }
+        }
+
         self.latency_queue.push_back((score, now));
 
         // Removes stats that are not in the check window
@@ -210,4 +268,56 @@ impl ServerStat {
     pub fn data(&self) -> &ServerStatData {
         &self.data
     }
+
+    /// Check if server is up, which is not failed consecutively
+    pub fn is_healthy(&self) -> bool {
+        self.healthy
+    }
+
+    /// Mark server down immediately, it will be reinstated after consecutive successful checks
+    pub fn mark_down(&mut self) {
+        self.healthy = false;
+        self.consecutive_successes = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ewma_reacts_to_recent_failures() {
+        let mut stat = ServerStat::new(1.0, 5000, Duration::from_secs(600));
+
+        for _ in 0..20 {
+            stat.push_score(Score::Latency(100));
+        }
+        assert_eq!(stat.data().latency_ewma, 100.0);
+        assert_eq!(stat.data().fail_ewma, 0.0);
+        let good_score = stat.push_score(Score::Latency(100));
+
+        // A burst of failures weighs more than their rate in the whole window
+        stat.push_score(Score::Errored);
+        let failed_score = stat.push_score(Score::Errored);
+        assert!(stat.data().fail_ewma > 0.5);
+        assert!(stat.data().fail_ewma > stat.data().fail_rate);
+        assert!(failed_score > good_score);
+        assert!(stat.is_healthy());
+
+        stat.push_score(Score::Errored);
+        assert!(!stat.is_healthy());
+    }
+
+    #[test]
+    fn mark_down_until_checks_succeed() {
+        let mut stat = ServerStat::new(1.0, 5000, Duration::from_secs(600));
+        stat.push_score(Score::Latency(100));
+        stat.mark_down();
+        assert!(!stat.is_healthy());
+
+        stat.push_score(Score::Latency(100));
+        assert!(!stat.is_healthy());
+        stat.push_score(Score::Latency(100));
+        assert!(stat.is_healthy());
+    }
 }
@@ -3,28 +3,34 @@
 use std::{
     io::{self, ErrorKind},
     net::SocketAddr,
+    ptr,
     sync::Arc,
     time::Duration,
 };
 
 use futures::future;
-use log::trace;
+use log::{info, trace, warn};
 use shadowsocks::{
     config::Mode,
     net::{AcceptOpts, ConnectOpts},
+    relay::tcprelay::utils::{plain_read_buffer_size, set_plain_read_buffer_size_limit},
 };
 
-#[cfg(feature = "local-flow-stat")]
-use crate::{config::LocalFlowStatAddress, net::FlowStat};
+#[cfg(feature = "local-admin")]
+use crate::config::LocalAdminConfig;
 use crate::{
     config::{Config, ConfigType, ProtocolType},
     dns::build_dns_resolver,
+    net::FlowStat,
+    stats::{self, BalancerScoreStats, BalancerServerStats, StatsSnapshot, StatsSource},
     utils::ServerHandle,
 };
 
 use self::{
     context::ServiceContext,
-    loadbalancing::{PingBalancer, PingBalancerBuilder},
+    flow_stat::{AndroidFlowStatSink, FlowStatSink, StatsdFlowStatSink, flow_report_task},
+    loadbalancing::{PingBalancer, PingBalancerBuilder, ServerScore},
+    observer::ConnectionObserver,
 };
 
 #[cfg(feature = "local-dns")]
@@ -43,15 +49,20 @@ use self::tun::{Tun, TunBuilder};
 #[cfg(feature = "local-tunnel")]
 use self::tunnel::{Tunnel, TunnelBuilder};
 
+#[cfg(feature = "local-admin")]
+mod admin;
 pub mod context;
 #[cfg(feature = "local-dns")]
 pub mod dns;
+pub mod domain_stat;
 #[cfg(feature = "local-fake-dns")]
 pub mod fake_dns;
+pub mod flow_stat;
 #[cfg(feature = "local-http")]
 pub mod http;
 pub mod loadbalancing;
 pub mod net;
+pub mod observer;
 #[cfg(feature = "local-online-config")]
 pub mod online_config;
 #[cfg(feature = "local-redir")]
@@ -84,21 +95,40 @@ pub struct Server {
     redir_servers: Vec<Redir>,
     #[cfg(feature = "local-fake-dns")]
     fake_dns_servers: Vec<FakeDns>,
-    #[cfg(feature = "local-flow-stat")]
-    local_stat_addr: Option<LocalFlowStatAddress>,
-    #[cfg(feature = "local-flow-stat")]
     flow_stat: Arc<FlowStat>,
+    flow_stat_sinks: Vec<Arc<dyn FlowStatSink>>,
+    context: Arc<ServiceContext>,
     #[cfg(feature = "local-online-config")]
     online_config: Option<OnlineConfigService>,
+    #[cfg(feature = "metrics")]
+    metrics_addr: Option<SocketAddr>,
+    stats_source: Arc<LocalStatsSource>,
+    #[cfg(feature = "local-admin")]
+    admin: Option<LocalAdminConfig>,
+    #[cfg(unix)]
+    user: Option<String>,
+    #[cfg(unix)]
+    group: Option<String>,
+    #[cfg(any(target_os = "linux", target_os = "openbsd"))]
+    sandbox: bool,
 }
 
 impl Server {
     /// Create a shadowsocks local server
-    pub async fn new(config: Config) -> io::Result<Server> {
+    pub async fn new(mut config: Config) -> io::Result<Server> {
         assert!(config.config_type == ConfigType::Local && !config.local.is_empty());
 
+        config.apply_memory_profile();
         trace!("{:?}", config);
 
+        if let Some(size) = config.relay_buffer_size {
+            set_plain_read_buffer_size_limit(Some(size));
+        }
+
+        // Plugins are started while servers are built, before the process drops its own privileges
+        #[cfg(unix)]
+        crate::privilege::drop_plugin_privileges(config.user.as_deref(), config.group.as_deref())?;
+
         // Warning for Stream Ciphers
         // NOTE: This will only check servers in config.
         #[cfg(feature = "stream-cipher")]
@@ -123,9 +153,26 @@ impl Server {
             }
         }
 
+        if let Some(ref access_log) = config.access_log {
+            crate::access_log::init(access_log)?;
+        }
+        if let Some(ref flow_export) = config.flow_export {
+            crate::flow_export::init(flow_export)?;
+        }
+
         // Global ServiceContext template
         // Each Local instance will hold a copy of its fields
         let mut context = ServiceContext::new();
+        context.set_cancellation_token(config.cancellation_token.clone());
+
+        if let Some(ref proxy) = config.outbound_proxy {
+            if config.server.iter().any(|s| s.config.mode().enable_udp()) {
+                warn!(
+                    "UDP packets to servers are sent directly, only TCP connections are made through outbound proxy {}",
+                    proxy
+                );
+            }
+        }
 
         let mut connect_opts = ConnectOpts {
             #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -134,6 +181,12 @@ impl Server {
             #[cfg(target_os = "android")]
             vpn_protect_path: config.outbound_vpn_protect_path,
 
+            #[cfg(all(any(target_os = "linux", target_os = "android"), feature = "socket-protect"))]
+            socket_protect: config.outbound_socket_protect,
+
+            connector: config.outbound_connector,
+            outbound_proxy: config.outbound_proxy,
+
             bind_interface: config.outbound_bind_interface,
             bind_local_addr: config.outbound_bind_addr.map(|ip| SocketAddr::new(ip, 0)),
 
@@ -147,7 +200,12 @@ impl Server {
         connect_opts.tcp.mptcp = config.mptcp;
         connect_opts.udp.mtu = config.udp_mtu;
         connect_opts.udp.allow_fragmentation = config.outbound_udp_allow_fragmentation;
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        {
+            connect_opts.uring = crate::net::utils::create_uring_driver();
+        }
         context.set_connect_opts(connect_opts);
+        context.set_server_dscp(config.outbound_dscp);
 
         let mut accept_opts = AcceptOpts {
             ipv6_only: config.ipv6_only,
@@ -177,12 +235,38 @@ impl Server {
             context.set_ipv6_first(config.ipv6_first);
         }
 
+        if let Some(interval) = config.server_resolve_interval {
+            context.set_server_resolve_interval(interval);
+        }
+
         if let Some(acl) = config.acl {
             context.set_acl(Arc::new(acl));
         }
 
         context.set_security_config(&config.security);
 
+        if let Some(entries) = config.replay_filter_capacity {
+            context.set_replay_filter_capacity(entries);
+        }
+
+        #[cfg(feature = "local-dns")]
+        if let Some(capacity) = config.reverse_lookup_cache_capacity {
+            context.set_reverse_lookup_cache_capacity(capacity);
+        }
+
+        if let Some(max) = config.max_tcp_connections {
+            context.set_max_tcp_connections(max);
+        }
+
+        // For Android's flow statistic
+        if let Some(stat_addr) = config.local_stat_addr {
+            context.add_flow_stat_sink(Arc::new(AndroidFlowStatSink::new(stat_addr)));
+        }
+
+        if let Some(statsd) = config.local_stat_statsd {
+            context.add_flow_stat_sink(Arc::new(StatsdFlowStatSink::new(statsd.addr, statsd.prefix)));
+        }
+
         assert!(!config.local.is_empty(), "no valid local server configuration");
 
         // Create a service balancer for choosing between multiple servers
@@ -214,6 +298,20 @@ impl Server {
                 balancer_builder.check_best_interval(intv);
             }
 
+            if let Some(threshold) = config.balancer.switch_threshold {
+                balancer_builder.switch_threshold(threshold);
+            }
+
+            balancer_builder.strategy(config.balancer.strategy);
+
+            if let Some(check_url) = config.balancer.check_url {
+                balancer_builder.check_url(check_url);
+            }
+
+            balancer_builder.race(config.balancer.race);
+
+            balancer_builder.routes(config.routes);
+
             for server in config.server {
                 balancer_builder.add_server(server);
             }
@@ -236,10 +334,26 @@ impl Server {
             redir_servers: Vec::new(),
             #[cfg(feature = "local-fake-dns")]
             fake_dns_servers: Vec::new(),
-            #[cfg(feature = "local-flow-stat")]
-            local_stat_addr: config.local_stat_addr,
-            #[cfg(feature = "local-flow-stat")]
             flow_stat: context.flow_stat(),
+            flow_stat_sinks: context.flow_stat_sinks().to_vec(),
+            context: Arc::new(context.clone()),
+            #[cfg(feature = "metrics")]
+            metrics_addr: config.metrics_addr,
+            #[cfg(feature = "local-admin")]
+            admin: config.local_admin,
+            #[cfg(unix)]
+            user: config.user,
+            #[cfg(unix)]
+            group: config.group,
+            #[cfg(any(target_os = "linux", target_os = "openbsd"))]
+            sandbox: config.sandbox,
+            stats_source: {
+                let stats_source = Arc::new(LocalStatsSource {
+                    balancer: balancer.clone(),
+                });
+                stats::register(&stats_source);
+                stats_source
+            },
             #[cfg(feature = "local-online-config")]
             online_config: match config.online_config {
                 None => None,
@@ -295,6 +409,10 @@ impl Server {
                     if let Some(b) = local_config.udp_associate_addr {
                         server_builder.set_udp_associate_addr(b.clone());
                     }
+                    #[cfg(feature = "local-tls")]
+                    if let Some(tls) = local_config.tls {
+                        server_builder.set_tls(tls);
+                    }
 
                     #[cfg(target_os = "macos")]
                     if let Some(n) = local_config.launchd_tcp_socket_name {
@@ -330,6 +448,7 @@ impl Server {
                     if let Some(udp_addr) = local_config.udp_addr {
                         server_builder.set_udp_bind_addr(udp_addr);
                     }
+                    server_builder.set_proxy_protocol(local_config.forward_proxy_protocol);
 
                     #[cfg(target_os = "macos")]
                     if let Some(n) = local_config.launchd_tcp_socket_name {
@@ -353,6 +472,11 @@ impl Server {
                     #[allow(unused_mut)]
                     let mut builder = HttpBuilder::with_context(context.clone(), client_addr, balancer);
 
+                    #[cfg(feature = "local-tls")]
+                    if let Some(tls) = local_config.tls {
+                        builder.set_tls(tls);
+                    }
+
                     #[cfg(target_os = "macos")]
                     if let Some(n) = local_config.launchd_tcp_socket_name {
                         builder.set_launchd_tcp_socket_name(n);
@@ -378,6 +502,10 @@ impl Server {
                     server_builder.set_mode(local_config.mode);
                     server_builder.set_tcp_redir(local_config.tcp_redir);
                     server_builder.set_udp_redir(local_config.udp_redir);
+                    #[cfg(target_os = "linux")]
+                    if let Some(firewall) = local_config.redir_firewall {
+                        server_builder.set_firewall(firewall, local_config.redir_firewall_bypass);
+                    }
                     if let Some(udp_addr) = local_config.udp_addr {
                         server_builder.set_udp_bind_addr(udp_addr);
                     }
@@ -530,6 +658,20 @@ impl Server {
 
     /// Run local server
     pub async fn run(self) -> io::Result<()> {
+        // Keep reporting statistics until the server exits
+        let _stats_source = self.stats_source;
+
+        // All listeners are bound by `Server::new`, plugins started by then have switched users by
+        // `drop_plugin_privileges`
+        #[cfg(unix)]
+        crate::privilege::drop_privileges(self.user.as_deref(), self.group.as_deref())?;
+        #[cfg(any(target_os = "linux", target_os = "openbsd"))]
+        if self.sandbox {
+            crate::sandbox::enable()?;
+        }
+        #[cfg(target_os = "linux")]
+        crate::sd_notify::ready();
+
         let mut vfut = Vec::new();
 
         for svr in self.socks_servers {
@@ -566,11 +708,8 @@ impl Server {
             vfut.push(ServerHandle(tokio::spawn(svr.run())));
         }
 
-        #[cfg(feature = "local-flow-stat")]
-        if let Some(stat_addr) = self.local_stat_addr {
-            // For Android's flow statistic
-
-            let report_fut = flow_report_task(stat_addr, self.flow_stat);
+        if !self.flow_stat_sinks.is_empty() {
+            let report_fut = flow_report_task(self.flow_stat, self.flow_stat_sinks);
             vfut.push(ServerHandle(tokio::spawn(report_fut)));
         }
 
@@ -579,8 +718,39 @@ impl Server {
             vfut.push(ServerHandle(tokio::spawn(online_config.run())));
         }
 
-        let (res, ..) = future::select_all(vfut).await;
-        res
+        #[cfg(feature = "metrics")]
+        if let Some(metrics_addr) = self.metrics_addr {
+            vfut.push(ServerHandle(tokio::spawn(crate::metrics::serve(metrics_addr))));
+        }
+
+        #[cfg(feature = "local-admin")]
+        if let Some(admin) = self.admin {
+            vfut.push(ServerHandle(tokio::spawn(admin::serve(self.balancer.clone(), admin))));
+        }
+
+        // Servers are aborted when `vfut` is dropped
+        match self
+            .context
+            .cancellation_token()
+            .run_until_cancelled(future::select_all(vfut))
+            .await
+        {
+            Some((res, ..)) => res,
+            None => {
+                info!("local server is shutdown by cancellation");
+                Ok(())
+            }
+        }
+    }
+
+    /// Add a sink receiving reports of the flow statistic of all local servers
+    pub fn add_flow_stat_sink(&mut self, sink: Arc<dyn FlowStatSink>) {
+        self.flow_stat_sinks.push(sink);
+    }
+
+    /// Add an observer of lifecycle events of TCP connections relayed by all local servers
+    pub fn add_connection_observer(&self, observer: Arc<dyn ConnectionObserver>) {
+        self.context.add_connection_observer(observer);
     }
 
     /// Get the internal server balancer
@@ -630,79 +800,48 @@ impl Server {
     }
 }
 
-#[cfg(feature = "local-flow-stat")]
-async fn flow_report_task(stat_addr: LocalFlowStatAddress, flow_stat: Arc<FlowStat>) -> io::Result<()> {
-    use std::slice;
-
-    use log::debug;
-    use tokio::{io::AsyncWriteExt, time};
-
-    // Local flow statistic report RPC
-    let timeout = Duration::from_secs(1);
-
-    loop {
-        // keep it as libev's default, 0.5 seconds
-        time::sleep(Duration::from_millis(500)).await;
+/// Statistics of local server and its balancer
+struct LocalStatsSource {
+    balancer: PingBalancer,
+}
 
-        let tx = flow_stat.tx();
-        let rx = flow_stat.rx();
+impl StatsSource for LocalStatsSource {
+    fn collect(&self, snapshot: &mut StatsSnapshot) {
+        let mut stats = self.balancer.context().stats_snapshot();
+
+        let best_tcp_server = self.balancer.current_tcp_server();
+        let best_udp_server = self.balancer.current_udp_server();
+        let mut relay_buffer_size = 0;
+        for server in self.balancer.servers() {
+            relay_buffer_size = relay_buffer_size.max(plain_read_buffer_size(server.server_config().method()));
+            stats.balancer.push(BalancerServerStats {
+                server: server.server_config().addr().to_string(),
+                pinned: self.balancer.is_pinned(server),
+                tcp: score_stats(server.tcp_score(), ptr::eq(server, best_tcp_server.as_ref())),
+                udp: score_stats(server.udp_score(), ptr::eq(server, best_udp_server.as_ref())),
+                latency: server.latency().snapshot(),
+                errors: server.errors().snapshot(None),
+            });
+        }
 
-        let buf: [u64; 2] = [tx, rx];
-        let buf = unsafe { slice::from_raw_parts(buf.as_ptr() as *const _, 16) };
+        let tcp_connections = stats.tcp_connections;
+        stats.memory.set_relay_buffers(relay_buffer_size, tcp_connections);
 
-        match stat_addr {
-            #[cfg(unix)]
-            LocalFlowStatAddress::UnixStreamPath(ref stat_path) => {
-                use tokio::net::UnixStream;
-
-                let mut stream = match time::timeout(timeout, UnixStream::connect(stat_path)).await {
-                    Ok(Ok(s)) => s,
-                    Ok(Err(err)) => {
-                        debug!("send client flow statistic error: {}", err);
-                        continue;
-                    }
-                    Err(..) => {
-                        debug!("send client flow statistic error: timeout");
-                        continue;
-                    }
-                };
+        snapshot.local = Some(stats);
+    }
 
-                match time::timeout(timeout, stream.write_all(buf)).await {
-                    Ok(Ok(..)) => {}
-                    Ok(Err(err)) => {
-                        debug!("send client flow statistic error: {}", err);
-                    }
-                    Err(..) => {
-                        debug!("send client flow statistic error: timeout");
-                    }
-                }
-            }
-            LocalFlowStatAddress::TcpStreamAddr(stat_addr) => {
-                use tokio::net::TcpStream;
-
-                let mut stream = match time::timeout(timeout, TcpStream::connect(stat_addr)).await {
-                    Ok(Ok(s)) => s,
-                    Ok(Err(err)) => {
-                        debug!("send client flow statistic error: {}", err);
-                        continue;
-                    }
-                    Err(..) => {
-                        debug!("send client flow statistic error: timeout");
-                        continue;
-                    }
-                };
+    fn clear_caches(&self) {
+        self.balancer.context().clear_caches();
+    }
+}
 
-                match time::timeout(timeout, stream.write_all(buf)).await {
-                    Ok(Ok(..)) => {}
-                    Ok(Err(err)) => {
-                        debug!("send client flow statistic error: {}", err);
-                    }
-                    Err(..) => {
-                        debug!("send client flow statistic error: timeout");
-                    }
-                }
-            }
-        }
+fn score_stats(score: &ServerScore, selected: bool) -> BalancerScoreStats {
+    BalancerScoreStats {
+        score: score.score(),
+        healthy: score.is_healthy(),
+        selected,
+        last_latency: score.last_latency(),
+        failures: score.failures(),
     }
 }
 
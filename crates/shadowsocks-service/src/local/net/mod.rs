@@ -1,7 +1,7 @@
 //! Shadowsocks Local Network Utilities
 
 pub use self::{
-    tcp::{auto_proxy_io::AutoProxyIo, auto_proxy_stream::AutoProxyClientStream},
+    tcp::{auto_proxy_io::AutoProxyIo, auto_proxy_stream::AutoProxyClientStream, proxied_stream::ProxiedClientStream},
     udp::{UdpAssociationManager, UdpInboundWrite},
 };
 
@@ -1,35 +1,78 @@
 //! A `ProxyStream` that bypasses or proxies data through proxy server automatically
 
 use std::{
-    io::{self, IoSlice},
+    io::{self, ErrorKind, IoSlice},
     net::SocketAddr,
     pin::Pin,
     sync::Arc,
     task::{self, Poll},
+    time::Instant,
 };
 
+use futures::{
+    FutureExt,
+    future::{self, Either},
+};
+use log::trace;
 use pin_project::pin_project;
 use shadowsocks::{
     net::{ConnectOpts, TcpStream},
-    relay::{socks5::Address, tcprelay::proxy_stream::ProxyClientStream},
+    relay::{
+        socks5::Address,
+        tcprelay::{crypto_io, proxy_stream::ProxyClientStream},
+    },
 };
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use crate::{
-    local::{context::ServiceContext, loadbalancing::ServerIdent},
+    local::{
+        context::ServiceContext,
+        loadbalancing::{PingBalancer, ServerIdent, ServerLatency, ServerType, lazy_plugin::LazyPluginGuard},
+    },
     net::MonProxyStream,
+    stats::{ErrorClass, ErrorCounters},
 };
 
-use super::auto_proxy_io::AutoProxyIo;
+use super::{auto_proxy_io::AutoProxyIo, proxied_stream::ProxiedClientStream};
 
 /// Unified stream for bypassed and proxied connections
 #[allow(clippy::large_enum_variant)]
 #[pin_project(project = AutoProxyClientStreamProj)]
 pub enum AutoProxyClientStream {
-    Proxied(#[pin] ProxyClientStream<MonProxyStream<TcpStream>>),
+    Proxied(
+        #[pin] ProxiedClientStream<MonProxyStream<TcpStream>>,
+        Option<LatencyProbe>,
+        Option<Arc<ErrorCounters>>,
+        // Keeps plugins started on demand running
+        Option<LazyPluginGuard>,
+    ),
     Bypassed(#[pin] TcpStream),
 }
 
+/// Measures handshake and first byte latency of a proxied connection, until the first byte is received
+pub struct LatencyProbe {
+    latency: Arc<ServerLatency>,
+    connect_start: Instant,
+    connected: Instant,
+    first_write: Option<Instant>,
+}
+
+impl LatencyProbe {
+    fn on_write(&mut self) {
+        if self.first_write.is_none() {
+            self.first_write = Some(Instant::now());
+        }
+    }
+
+    fn on_first_byte(self) {
+        let now = Instant::now();
+        // Protocols that server speaks first don't write before the first read
+        let handshake_start = self.first_write.unwrap_or(self.connected);
+        self.latency.record_handshake(now - handshake_start);
+        self.latency.record_first_byte(now - self.connect_start);
+    }
+}
+
 impl AutoProxyClientStream {
     /// Connect to target `addr` via shadowsocks' server configured by `svr_cfg`
     pub async fn connect<A>(
@@ -61,6 +104,63 @@ impl AutoProxyClientStream {
         }
     }
 
+    /// Connect to target `addr` via the best TCP server of `balancer`, returns the server used
+    ///
+    /// With `balancer.race`, the best 2 servers are connected concurrently, and the first one connected (including
+    /// plugins, outbound proxies and TLS handshakes) is used. The other one keeps connecting in background, so its
+    /// failure is reported to the balancer, and is closed before any data is sent, so requests are never duplicated.
+    /// The best server is returned on errors.
+    pub async fn connect_balanced<A>(
+        context: Arc<ServiceContext>,
+        balancer: &PingBalancer,
+        addr: A,
+    ) -> (Arc<ServerIdent>, io::Result<AutoProxyClientStream>)
+    where
+        A: Into<Address>,
+    {
+        let addr = addr.into();
+        let (best, runner_up) = balancer.best_tcp_servers_for(&addr);
+        let runner_up = match runner_up {
+            Some(server) if !context.check_target_bypassed(&addr).await => server,
+            _ => {
+                let result =
+                    AutoProxyClientStream::connect_with_opts(context, &best, addr, best.connect_opts_ref()).await;
+                return (best, result);
+            }
+        };
+
+        let connect = |server: Arc<ServerIdent>| {
+            let context = context.clone();
+            let addr = addr.clone();
+            async move {
+                let stream =
+                    AutoProxyClientStream::connect_proxied_with_opts(context, &server, addr, server.connect_opts_ref())
+                        .await?;
+                Ok::<_, io::Error>((server, stream))
+            }
+            .boxed()
+        };
+
+        let (server, stream) = match future::select(connect(best.clone()), connect(runner_up)).await {
+            Either::Left((Ok(connected), losing)) | Either::Right((Ok(connected), losing)) => {
+                // Failures of the losing server are reported when its connect finishes
+                tokio::spawn(losing);
+                connected
+            }
+            Either::Left((Err(..), other)) | Either::Right((Err(..), other)) => match other.await {
+                Ok(connected) => connected,
+                Err(err) => return (best, Err(err)),
+            },
+        };
+
+        trace!(
+            "raced tcp connection to {} won by server {}",
+            addr,
+            server.server_config().addr()
+        );
+        (server, Ok(stream))
+    }
+
     /// Connect directly to target `addr`
     pub async fn connect_bypassed<A>(context: Arc<ServiceContext>, addr: A) -> io::Result<AutoProxyClientStream>
     where
@@ -119,7 +219,16 @@ impl AutoProxyClientStream {
             addr = mapped_addr;
         }
         let flow_stat = context.flow_stat();
-        let stream = match ProxyClientStream::connect_with_opts_map(
+        let connect_start = Instant::now();
+        let plugins = match server.acquire_plugins(ServerType::Tcp).await {
+            Ok(p) => p,
+            Err(err) => {
+                server.errors().record(ErrorClass::PluginUnavailable);
+                server.tcp_score().report_failure().await;
+                return Err(err);
+            }
+        };
+        let stream = match ProxiedClientStream::connect_with_opts_map(
             context.context(),
             server.server_config(),
             addr,
@@ -130,23 +239,47 @@ impl AutoProxyClientStream {
         {
             Ok(s) => s,
             Err(err) => {
+                let class = if err.kind() == ErrorKind::ConnectionRefused && server.server_config().plugin().is_some() {
+                    Some(ErrorClass::PluginUnavailable)
+                } else {
+                    ErrorClass::from_connect_error(&err)
+                };
+                if let Some(class) = class {
+                    server.errors().record(class);
+                }
+
                 server.tcp_score().report_failure().await;
                 return Err(err);
             }
         };
-        Ok(AutoProxyClientStream::Proxied(stream))
+
+        let connected = Instant::now();
+        server.latency().record_connect(connected - connect_start);
+
+        let probe = LatencyProbe {
+            latency: server.latency().clone(),
+            connect_start,
+            connected,
+            first_write: None,
+        };
+        Ok(AutoProxyClientStream::Proxied(
+            stream,
+            Some(probe),
+            Some(server.errors().clone()),
+            plugins,
+        ))
     }
 
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         match *self {
-            AutoProxyClientStream::Proxied(ref s) => s.get_ref().get_ref().local_addr(),
+            AutoProxyClientStream::Proxied(ref s, ..) => s.get_ref().get_ref().local_addr(),
             AutoProxyClientStream::Bypassed(ref s) => s.local_addr(),
         }
     }
 
     pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
         match *self {
-            AutoProxyClientStream::Proxied(ref s) => s.get_ref().get_ref().set_nodelay(nodelay),
+            AutoProxyClientStream::Proxied(ref s, ..) => s.get_ref().get_ref().set_nodelay(nodelay),
             AutoProxyClientStream::Bypassed(ref s) => s.set_nodelay(nodelay),
         }
     }
@@ -161,7 +294,19 @@ impl AutoProxyIo for AutoProxyClientStream {
 impl AsyncRead for AutoProxyClientStream {
     fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
         match self.project() {
-            AutoProxyClientStreamProj::Proxied(s) => s.poll_read(cx, buf),
+            AutoProxyClientStreamProj::Proxied(s, probe, errors, ..) => {
+                let filled = buf.filled().len();
+                let result = s.poll_read(cx, buf);
+                let decrypt_failed = matches!(result, Poll::Ready(Err(ref err)) if crypto_io::is_decrypt_error(err));
+                if let (true, Some(errors)) = (decrypt_failed, errors) {
+                    errors.record(ErrorClass::DecryptFailure);
+                }
+                let received = matches!(result, Poll::Ready(Ok(()))) && buf.filled().len() > filled;
+                if let Some(probe) = probe.take_if(|_| received) {
+                    probe.on_first_byte();
+                }
+                result
+            }
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_read(cx, buf),
         }
     }
@@ -170,21 +315,27 @@ impl AsyncRead for AutoProxyClientStream {
 impl AsyncWrite for AutoProxyClientStream {
     fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
         match self.project() {
-            AutoProxyClientStreamProj::Proxied(s) => s.poll_write(cx, buf),
+            AutoProxyClientStreamProj::Proxied(s, probe, ..) => {
+                let result = s.poll_write(cx, buf);
+                if let (Poll::Ready(Ok(..)), Some(probe)) = (&result, probe) {
+                    probe.on_write();
+                }
+                result
+            }
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_write(cx, buf),
         }
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
         match self.project() {
-            AutoProxyClientStreamProj::Proxied(s) => s.poll_flush(cx),
+            AutoProxyClientStreamProj::Proxied(s, ..) => s.poll_flush(cx),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_flush(cx),
         }
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
         match self.project() {
-            AutoProxyClientStreamProj::Proxied(s) => s.poll_shutdown(cx),
+            AutoProxyClientStreamProj::Proxied(s, ..) => s.poll_shutdown(cx),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_shutdown(cx),
         }
     }
@@ -195,7 +346,13 @@ impl AsyncWrite for AutoProxyClientStream {
         bufs: &[IoSlice<'_>],
     ) -> Poll<io::Result<usize>> {
         match self.project() {
-            AutoProxyClientStreamProj::Proxied(s) => s.poll_write_vectored(cx, bufs),
+            AutoProxyClientStreamProj::Proxied(s, probe, ..) => {
+                let result = s.poll_write_vectored(cx, bufs);
+                if let (Poll::Ready(Ok(..)), Some(probe)) = (&result, probe) {
+                    probe.on_write();
+                }
+                result
+            }
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_write_vectored(cx, bufs),
         }
     }
@@ -203,6 +360,12 @@ impl AsyncWrite for AutoProxyClientStream {
 
 impl From<ProxyClientStream<MonProxyStream<TcpStream>>> for AutoProxyClientStream {
     fn from(s: ProxyClientStream<MonProxyStream<TcpStream>>) -> Self {
-        AutoProxyClientStream::Proxied(s)
+        AutoProxyClientStream::Proxied(s.into(), None, None, None)
+    }
+}
+
+impl From<ProxiedClientStream<MonProxyStream<TcpStream>>> for AutoProxyClientStream {
+    fn from(s: ProxiedClientStream<MonProxyStream<TcpStream>>) -> Self {
+        AutoProxyClientStream::Proxied(s, None, None, None)
     }
 }
@@ -1,3 +1,8 @@
 pub mod auto_proxy_io;
 pub mod auto_proxy_stream;
 pub mod listener;
+pub mod proxied_stream;
+#[cfg(feature = "local-tls")]
+pub mod tls;
+#[cfg(feature = "local-trojan")]
+pub mod trojan;
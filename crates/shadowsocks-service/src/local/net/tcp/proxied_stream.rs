@@ -0,0 +1,137 @@
+//! Streams to targets through servers of all supported protocols
+
+use std::{
+    io::{self, ErrorKind, IoSlice},
+    pin::Pin,
+    task::{self, Poll},
+};
+
+use pin_project::pin_project;
+use shadowsocks::{
+    config::{ServerConfig, ServerProtocol},
+    context::SharedContext,
+    net::{ConnectOpts, TcpStream as OutboundTcpStream},
+    relay::{socks5::Address, tcprelay::proxy_stream::ProxyClientStream},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[cfg(feature = "local-trojan")]
+use super::trojan::TrojanClientStream;
+
+/// Stream to a target through a server, in the server's protocol
+#[allow(clippy::large_enum_variant)]
+#[pin_project(project = ProxiedClientStreamProj)]
+pub enum ProxiedClientStream<S> {
+    Shadowsocks(#[pin] ProxyClientStream<S>),
+    #[cfg(feature = "local-trojan")]
+    Trojan(#[pin] TrojanClientStream<S>),
+}
+
+impl<S> ProxiedClientStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Connect to target `addr` via server configured by `svr_cfg`, maps `TcpStream` to customized stream with `map_fn`
+    pub async fn connect_with_opts_map<A, F>(
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        addr: A,
+        opts: &ConnectOpts,
+        map_fn: F,
+    ) -> io::Result<ProxiedClientStream<S>>
+    where
+        A: Into<Address>,
+        F: FnOnce(OutboundTcpStream) -> S,
+    {
+        match *svr_cfg.protocol() {
+            ServerProtocol::Shadowsocks => {
+                ProxyClientStream::connect_with_opts_map(context, svr_cfg, addr, opts, map_fn)
+                    .await
+                    .map(ProxiedClientStream::Shadowsocks)
+            }
+            #[cfg(feature = "local-trojan")]
+            ServerProtocol::Trojan { .. } => {
+                TrojanClientStream::connect_with_opts_map(context, svr_cfg, addr, opts, map_fn)
+                    .await
+                    .map(ProxiedClientStream::Trojan)
+            }
+            #[cfg(not(feature = "local-trojan"))]
+            ServerProtocol::Trojan { .. } => {
+                let _ = (context, addr, opts, map_fn);
+                Err(io::Error::new(
+                    ErrorKind::Other,
+                    "trojan servers require feature \"local-trojan\"",
+                ))
+            }
+        }
+    }
+
+    /// Get reference to the underlying stream
+    pub fn get_ref(&self) -> &S {
+        match *self {
+            ProxiedClientStream::Shadowsocks(ref s) => s.get_ref(),
+            #[cfg(feature = "local-trojan")]
+            ProxiedClientStream::Trojan(ref s) => s.get_ref(),
+        }
+    }
+}
+
+impl<S> From<ProxyClientStream<S>> for ProxiedClientStream<S> {
+    fn from(s: ProxyClientStream<S>) -> Self {
+        ProxiedClientStream::Shadowsocks(s)
+    }
+}
+
+impl<S> AsyncRead for ProxiedClientStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.project() {
+            ProxiedClientStreamProj::Shadowsocks(s) => s.poll_read(cx, buf),
+            #[cfg(feature = "local-trojan")]
+            ProxiedClientStreamProj::Trojan(s) => s.poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for ProxiedClientStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.project() {
+            ProxiedClientStreamProj::Shadowsocks(s) => s.poll_write(cx, buf),
+            #[cfg(feature = "local-trojan")]
+            ProxiedClientStreamProj::Trojan(s) => s.poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.project() {
+            ProxiedClientStreamProj::Shadowsocks(s) => s.poll_flush(cx),
+            #[cfg(feature = "local-trojan")]
+            ProxiedClientStreamProj::Trojan(s) => s.poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.project() {
+            ProxiedClientStreamProj::Shadowsocks(s) => s.poll_shutdown(cx),
+            #[cfg(feature = "local-trojan")]
+            ProxiedClientStreamProj::Trojan(s) => s.poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.project() {
+            ProxiedClientStreamProj::Shadowsocks(s) => s.poll_write_vectored(cx, bufs),
+            #[cfg(feature = "local-trojan")]
+            ProxiedClientStreamProj::Trojan(s) => s.poll_write_vectored(cx, bufs),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! TLS of local listeners
+//!
+//! Clients connect to SOCKS and HTTP listeners with TLS, and could be required to present certificates.
+
+use std::{
+    io::{self, ErrorKind},
+    sync::Arc,
+    time::Duration,
+};
+
+use shadowsocks::net::tls::{load_certs, load_private_key};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time,
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{RootCertStore, ServerConfig, server::WebPkiClientVerifier},
+    server::TlsStream,
+};
+
+/// Clients must finish TLS handshakes in this duration after they are accepted
+pub const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+use crate::config::LocalTlsConfig;
+
+/// Create a `TlsAcceptor` from certificates of `config`
+pub fn make_tls_acceptor(config: &LocalTlsConfig) -> io::Result<TlsAcceptor> {
+    let certs = load_certs(&config.cert)?;
+    let key = load_private_key(&config.key)?;
+
+    let builder = ServerConfig::builder();
+    let builder = match config.client_ca {
+        Some(ref client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots.add(cert).map_err(|err| {
+                    io::Error::new(ErrorKind::InvalidInput, format!("{}: {err}", client_ca.display()))
+                })?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder
+        .with_single_cert(certs, key)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Handshake with a client accepted by a TLS listener, fails after [`TLS_HANDSHAKE_TIMEOUT`]
+pub async fn accept_tls<S>(acceptor: &TlsAcceptor, stream: S) -> io::Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(result) => result,
+        Err(..) => Err(io::Error::new(ErrorKind::TimedOut, "tls handshake timed out")),
+    }
+}
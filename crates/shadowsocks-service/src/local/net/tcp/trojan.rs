@@ -0,0 +1,233 @@
+//! Trojan protocol over TLS
+//!
+//! Clients send `hex(SHA224(password)) CRLF CMD ADDR CRLF` and payloads in a TLS stream, without any responses from
+//! servers. <https://trojan-gfw.github.io/trojan/protocol>
+
+use std::{
+    io::{self, ErrorKind},
+    pin::Pin,
+    sync::Arc,
+    task::{self, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::ready;
+use log::trace;
+use once_cell::sync::Lazy;
+use pin_project::pin_project;
+use sha2::{Digest, Sha224};
+use shadowsocks::{
+    config::{ServerAddr, ServerConfig, ServerProtocol},
+    context::SharedContext,
+    net::{ConnectOpts, TcpStream as OutboundTcpStream},
+    relay::socks5::Address,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time,
+};
+use tokio_rustls::{
+    TlsConnector,
+    client::TlsStream,
+    rustls::{ClientConfig, RootCertStore, pki_types::ServerName},
+};
+
+const TROJAN_CMD_CONNECT: u8 = 0x01;
+
+static TLS_CLIENT_CONFIG: Lazy<Arc<ClientConfig>> = Lazy::new(|| {
+    let mut store = RootCertStore::empty();
+    store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    let mut config = ClientConfig::builder()
+        .with_root_certificates(store)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Arc::new(config)
+});
+
+#[derive(Debug)]
+enum TrojanClientStreamWriteState {
+    Connect(Address),
+    Connecting(BytesMut, usize),
+    Connected,
+}
+
+/// A stream for sending / receiving data stream from remote server via trojan server
+#[pin_project]
+pub struct TrojanClientStream<S> {
+    #[pin]
+    stream: TlsStream<S>,
+    password_hash: [u8; 56],
+    writer_state: TrojanClientStreamWriteState,
+}
+
+impl<S> TrojanClientStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Connect to target `addr` via trojan server configured by `svr_cfg`, maps `TcpStream` to customized stream with `map_fn`
+    pub async fn connect_with_opts_map<A, F>(
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        addr: A,
+        opts: &ConnectOpts,
+        map_fn: F,
+    ) -> io::Result<TrojanClientStream<S>>
+    where
+        A: Into<Address>,
+        F: FnOnce(OutboundTcpStream) -> S,
+    {
+        let server_name = match *svr_cfg.protocol() {
+            ServerProtocol::Trojan { sni: Some(ref sni) } => sni.clone(),
+            _ => match *svr_cfg.addr() {
+                ServerAddr::SocketAddr(ref sa) => sa.ip().to_string(),
+                ServerAddr::DomainName(ref domain, ..) => domain.clone(),
+            },
+        };
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, format!("invalid trojan sni, {err}")))?;
+
+        let connect = async {
+            let stream = OutboundTcpStream::connect_server_with_opts(&context, svr_cfg.addr(), opts).await?;
+            TlsConnector::from(TLS_CLIENT_CONFIG.clone())
+                .connect(server_name, map_fn(stream))
+                .await
+        };
+
+        let stream = match svr_cfg.timeout() {
+            Some(d) => match time::timeout(d, connect).await {
+                Ok(r) => r?,
+                Err(..) => {
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        format!("connect {} timeout", svr_cfg.addr()),
+                    ));
+                }
+            },
+            None => connect.await?,
+        };
+
+        trace!("connected trojan remote {} with {:?}", svr_cfg.addr(), opts);
+
+        Ok(TrojanClientStream::from_stream(stream, svr_cfg, addr))
+    }
+
+    /// Create a `TrojanClientStream` with a TLS `stream` connected to a trojan server
+    pub fn from_stream<A>(stream: TlsStream<S>, svr_cfg: &ServerConfig, addr: A) -> TrojanClientStream<S>
+    where
+        A: Into<Address>,
+    {
+        TrojanClientStream {
+            stream,
+            password_hash: password_hash(svr_cfg.password()),
+            writer_state: TrojanClientStreamWriteState::Connect(addr.into()),
+        }
+    }
+
+    /// Get reference to the underlying stream
+    pub fn get_ref(&self) -> &S {
+        self.stream.get_ref().0
+    }
+
+    /// Get mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut S {
+        self.stream.get_mut().0
+    }
+}
+
+/// Hex of SHA224 of `password`, which identifies clients in requests
+fn password_hash(password: &str) -> [u8; 56] {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+
+    let digest = Sha224::digest(password.as_bytes());
+    let mut hash = [0u8; 56];
+    for (i, b) in digest.iter().enumerate() {
+        hash[i * 2] = HEX[(b >> 4) as usize];
+        hash[i * 2 + 1] = HEX[(b & 0x0f) as usize];
+    }
+    hash
+}
+
+fn make_request_buffer(password_hash: &[u8], addr: &Address, buf: &[u8]) -> BytesMut {
+    // Target Address should be sent with the first packet together, like shadowsocks
+    let mut buffer = BytesMut::with_capacity(password_hash.len() + 2 + 1 + addr.serialized_len() + 2 + buf.len());
+    buffer.put_slice(password_hash);
+    buffer.put_slice(b"\r\n");
+    buffer.put_u8(TROJAN_CMD_CONNECT);
+    addr.write_to_buf(&mut buffer);
+    buffer.put_slice(b"\r\n");
+    buffer.put_slice(buf);
+    buffer
+}
+
+impl<S> AsyncRead for TrojanClientStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    #[inline]
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for TrojanClientStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut this = self.project();
+
+        loop {
+            match this.writer_state {
+                TrojanClientStreamWriteState::Connect(addr) => {
+                    let buffer = make_request_buffer(&this.password_hash[..], addr, buf);
+                    *(this.writer_state) = TrojanClientStreamWriteState::Connecting(buffer, buf.len());
+                }
+                TrojanClientStreamWriteState::Connecting(buffer, len) => {
+                    while !buffer.is_empty() {
+                        let n = ready!(this.stream.as_mut().poll_write(cx, &buffer[..]))?;
+                        if n == 0 {
+                            return Err(ErrorKind::WriteZero.into()).into();
+                        }
+                        buffer.advance(n);
+                    }
+
+                    // Empty writes send the request, for protocols that servers speak first
+                    let len = *len;
+                    *(this.writer_state) = TrojanClientStreamWriteState::Connected;
+                    return Ok(len).into();
+                }
+                TrojanClientStreamWriteState::Connected => {
+                    return this.stream.poll_write(cx, buf);
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn request_header() {
+        let hash = password_hash("password");
+        assert_eq!(&hash[..], b"d63dc919e201d7bc4c825630d2cf25fdc93d4b2f0d46706d29038d01");
+
+        let addr = Address::DomainNameAddress("example.com".to_owned(), 443);
+        let buffer = make_request_buffer(&hash, &addr, b"GET");
+        assert_eq!(&buffer[56..59], b"\r\n\x01");
+        assert_eq!(&buffer[59..60], b"\x03");
+        assert!(buffer.ends_with(b"\x01\xbb\r\nGET"));
+    }
+}
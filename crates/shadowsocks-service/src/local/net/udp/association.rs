@@ -15,9 +15,10 @@ use log::{debug, error, trace, warn};
 use lru_time_cache::LruCache;
 use rand::{Rng, SeedableRng, rngs::SmallRng};
 use tokio::{sync::mpsc, task::JoinHandle, time};
+use tracing::Instrument;
 
 use shadowsocks::{
-    lookup_then,
+    ServerAddr, lookup_then,
     net::{AddrFamily, UdpSocket as ShadowUdpSocket},
     relay::{
         Address,
@@ -26,11 +27,17 @@ use shadowsocks::{
 };
 
 use crate::{
-    local::{context::ServiceContext, loadbalancing::PingBalancer},
+    flow_export::UdpFlows,
+    local::{
+        context::ServiceContext,
+        loadbalancing::{PingBalancer, ServerType, lazy_plugin::LazyPluginGuard},
+    },
+    log_control::{record_server_addr, record_target_addr, udp_association_span},
     net::{
         MonProxySocket, UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE, UDP_ASSOCIATION_SEND_CHANNEL_SIZE,
         packet_window::PacketWindowFilter,
     },
+    utils::spawn_cancellable,
 };
 
 /// Writer for sending packets back to client
@@ -137,7 +144,7 @@ struct UdpAssociation<W>
 where
     W: UdpInboundWrite + Send + Sync + Unpin + 'static,
 {
-    assoc_handle: JoinHandle<()>,
+    assoc_handle: JoinHandle<Option<()>>,
     sender: mpsc::Sender<(Address, Bytes)>,
     writer: PhantomData<W>,
 }
@@ -214,6 +221,10 @@ where
     bypassed_ipv4_socket: Option<ShadowUdpSocket>,
     bypassed_ipv6_socket: Option<ShadowUdpSocket>,
     proxied_socket: Option<MonProxySocket<ShadowUdpSocket>>,
+    // Server domain name, port and generation of its addresses when `proxied_socket` was connected
+    proxied_server_addr_generation: Option<(String, u16, u64)>,
+    // Keeps plugins started on demand running
+    proxied_plugins: Option<LazyPluginGuard>,
     keepalive_tx: mpsc::Sender<SocketAddr>,
     keepalive_flag: bool,
     balancer: PingBalancer,
@@ -222,6 +233,9 @@ where
     client_packet_id: u64,
     server_session: Option<ServerSessionContext>,
     server_session_expire_duration: Duration,
+    flows: UdpFlows,
+    // Target of the first packet was recorded in the association's span
+    target_addr_recorded: bool,
 }
 
 impl<W> Drop for UdpAssociationContext<W>
@@ -230,6 +244,7 @@ where
 {
     fn drop(&mut self) {
         debug!("udp association for {} is closed", self.peer_addr);
+        self.flows.export(self.peer_addr);
     }
 }
 
@@ -259,7 +274,7 @@ where
         balancer: PingBalancer,
         respond_writer: W,
         server_session_expire_duration: Duration,
-    ) -> (JoinHandle<()>, mpsc::Sender<(Address, Bytes)>) {
+    ) -> (JoinHandle<Option<()>>, mpsc::Sender<(Address, Bytes)>) {
         // Pending packets UDP_ASSOCIATION_SEND_CHANNEL_SIZE for each association should be good enough for a server.
         // If there are plenty of packets stuck in the channel, dropping excessive packets is a good way to protect the server from
         // being OOM.
@@ -271,6 +286,8 @@ where
             bypassed_ipv4_socket: None,
             bypassed_ipv6_socket: None,
             proxied_socket: None,
+            proxied_server_addr_generation: None,
+            proxied_plugins: None,
             keepalive_tx,
             keepalive_flag: false,
             balancer,
@@ -281,8 +298,13 @@ where
             client_packet_id: 0,
             server_session: None,
             server_session_expire_duration,
+            flows: UdpFlows::new(),
+            target_addr_recorded: false,
         };
-        let handle = tokio::spawn(async move { assoc.dispatch_packet(receiver).await });
+        let handle = spawn_cancellable(
+            assoc.context.cancellation_token(),
+            async move { assoc.dispatch_packet(receiver).await }.instrument(udp_association_span(peer_addr)),
+        );
 
         (handle, sender)
     }
@@ -426,6 +448,11 @@ where
     }
 
     async fn dispatch_received_packet(&mut self, target_addr: &Address, data: &[u8]) {
+        if !self.target_addr_recorded {
+            record_target_addr(target_addr);
+            self.target_addr_recorded = true;
+        }
+        self.flows.sent(self.peer_addr, target_addr, data.len());
         // Check if target should be bypassed. If so, send packets directly.
         let bypassed = self.balancer.is_empty() || self.context.check_target_bypassed(target_addr).await;
 
@@ -563,14 +590,38 @@ where
             }
         };
 
+        // The server was connected by its old addresses, like home servers on dynamic IPs
+        if let Some((ref dn, port, generation)) = self.proxied_server_addr_generation {
+            if self.proxied_socket.is_some()
+                && generation != self.context.context_ref().server_addr_generation(dn, port)
+            {
+                debug!(
+                    "{} -> {} (proxied) server addresses changed, socket reconnecting",
+                    self.peer_addr, target_addr
+                );
+                self.proxied_socket = None;
+            }
+        }
+
         let socket = match self.proxied_socket {
             Some(ref mut socket) => socket,
             None => {
                 // Create a new connection to proxy server
 
-                let server = self.balancer.best_udp_server();
+                let server = self.balancer.best_udp_server_for(target_addr);
                 let svr_cfg = server.server_config();
-
+                record_server_addr(svr_cfg.addr());
+
+                self.proxied_server_addr_generation = match *svr_cfg.addr() {
+                    ServerAddr::DomainName(ref dn, port) => Some((
+                        dn.clone(),
+                        port,
+                        self.context.context_ref().server_addr_generation(dn, port),
+                    )),
+                    ServerAddr::SocketAddr(..) => None,
+                };
+
+                self.proxied_plugins = server.acquire_plugins(ServerType::Udp).await?;
                 let socket =
                     ProxySocket::connect_with_opts(self.context.context(), svr_cfg, server.connect_opts_ref()).await?;
                 let socket = MonProxySocket::from_socket(socket, self.context.flow_stat());
@@ -613,6 +664,7 @@ where
 
         // Keep association alive in map
         self.keepalive_flag = true;
+        self.flows.received(self.peer_addr, addr, data.len());
 
         // Send back to client
         match self.respond_writer.send_to(self.peer_addr, addr, data).await {
@@ -0,0 +1,297 @@
+//! Lifecycle events of TCP connections relayed by local servers
+//!
+//! Observers added by [`ServiceContext::add_connection_observer`](super::context::ServiceContext::add_connection_observer)
+//! are called in order for each connection:
+//!
+//! 1. [`on_accepted`](ConnectionObserver::on_accepted), a client connection requesting a TCP relay was accepted
+//! 2. [`on_target_resolved`](ConnectionObserver::on_target_resolved), the target address was read from the handshake
+//! 3. [`on_established`](ConnectionObserver::on_established), the target was connected, directly or through a server
+//! 4. [`on_bytes`](ConnectionObserver::on_bytes), every time [`BYTES_MILESTONE`] bytes were relayed
+//! 5. [`on_closed`](ConnectionObserver::on_closed), always called, even if the connection was closed before relaying
+//!
+//! Callbacks are called on the tasks of connections, they should return quickly without blocking.
+//!
+//! Bytes of all connections, observed or not, are also added to the [`DomainTrafficTable`] of their destinations.
+
+use std::{
+    fmt::{self, Display},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    task::{self, Poll},
+};
+
+use shadowsocks::{config::ServerAddr, relay::socks5::Address};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::OwnedSemaphorePermit,
+};
+
+use super::domain_stat::{DomainTrafficTable, parse_tls_server_name};
+
+/// [`ConnectionObserver::on_bytes`] is called every time this number of bytes were relayed
+pub const BYTES_MILESTONE: u64 = 1024 * 1024;
+
+/// Observer of connection lifecycle events
+#[allow(unused_variables)]
+pub trait ConnectionObserver: Send + Sync {
+    /// A client connection requesting a TCP relay was accepted
+    fn on_accepted(&self, conn: &ConnectionInfo) {}
+
+    /// Target address of the connection was resolved from the handshake
+    fn on_target_resolved(&self, conn: &ConnectionInfo) {}
+
+    /// Connection to the target was established, `conn.server_addr` is `None` if bypassed
+    fn on_established(&self, conn: &ConnectionInfo) {}
+
+    /// Total bytes sent by the client (`up`) and to the client (`down`) reached another milestone
+    fn on_bytes(&self, conn: &ConnectionInfo, up: u64, down: u64) {}
+
+    /// Connection was closed
+    fn on_closed(&self, conn: &ConnectionInfo, reason: &ConnectionCloseReason, up: u64, down: u64) {}
+}
+
+/// Connection being observed
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// Unique ID of the connection in the current process
+    pub id: u64,
+    /// Address of the client
+    pub peer_addr: SocketAddr,
+    /// Target address requested by the client
+    pub target_addr: Option<Address>,
+    /// Server relaying the connection, `None` if bypassed or not established
+    pub server_addr: Option<ServerAddr>,
+}
+
+/// Reason of closing a connection
+#[derive(Debug, Clone)]
+pub enum ConnectionCloseReason {
+    /// Both sides closed normally
+    Eof,
+    /// Relay failed with an error
+    Error(String),
+    /// Closed before relaying, for example the handshake failed or the target couldn't be connected
+    Aborted,
+}
+
+impl Display for ConnectionCloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ConnectionCloseReason::Eof => f.write_str("eof"),
+            ConnectionCloseReason::Error(ref err) => f.write_str(err),
+            ConnectionCloseReason::Aborted => f.write_str("aborted"),
+        }
+    }
+}
+
+/// Observers of a local service
+#[derive(Default)]
+pub(crate) struct ConnectionObservers {
+    observers: RwLock<Vec<Arc<dyn ConnectionObserver>>>,
+    next_id: AtomicU64,
+    active: AtomicUsize,
+    domains: DomainTrafficTable,
+}
+
+impl ConnectionObservers {
+    pub fn add(&self, observer: Arc<dyn ConnectionObserver>) {
+        self.observers.write().unwrap().push(observer);
+    }
+
+    /// Number of connections not closed yet, observed or not
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Traffic of all connections, observed or not, by their destination domains
+    pub fn domains(&self) -> &DomainTrafficTable {
+        &self.domains
+    }
+
+    /// Start observing a connection accepted from `peer_addr`, `permit` of the connection limit is held until it is closed
+    pub fn observe(
+        self: &Arc<Self>,
+        peer_addr: SocketAddr,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> ObservedConnection {
+        let observed = !self.observers.read().unwrap().is_empty();
+        self.active.fetch_add(1, Ordering::Relaxed);
+
+        let conn = ObservedConnection {
+            observers: self.clone(),
+            observed,
+            info: ConnectionInfo {
+                id: self.next_id.fetch_add(1, Ordering::Relaxed),
+                peer_addr,
+                target_addr: None,
+                server_addr: None,
+            },
+            up: 0,
+            down: 0,
+            next_milestone: BYTES_MILESTONE,
+            server_name: None,
+            domain_up: 0,
+            domain_down: 0,
+            closed: false,
+            _permit: permit,
+        };
+        conn.notify(|o, info| o.on_accepted(info));
+        conn
+    }
+}
+
+/// Events of a connection, reported to observers
+///
+/// Connections accepted while there are no observers are not reported. [`ConnectionObserver::on_closed`] is called
+/// with [`ConnectionCloseReason::Aborted`] if it is dropped before [`ObservedConnection::closed`].
+pub(crate) struct ObservedConnection {
+    observers: Arc<ConnectionObservers>,
+    observed: bool,
+    info: ConnectionInfo,
+    up: u64,
+    down: u64,
+    next_milestone: u64,
+    server_name: Option<String>,
+    domain_up: u64,
+    domain_down: u64,
+    closed: bool,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ObservedConnection {
+    fn notify<F>(&self, f: F)
+    where
+        F: Fn(&dyn ConnectionObserver, &ConnectionInfo),
+    {
+        if self.observed {
+            for observer in self.observers.observers.read().unwrap().iter() {
+                f(observer.as_ref(), &self.info);
+            }
+        }
+    }
+
+    pub fn target_resolved(&mut self, target_addr: &Address) {
+        self.info.target_addr = Some(target_addr.clone());
+        self.notify(|o, info| o.on_target_resolved(info));
+    }
+
+    pub fn established(&mut self, server_addr: Option<&ServerAddr>) {
+        self.info.server_addr = server_addr.cloned();
+        self.notify(|o, info| o.on_established(info));
+    }
+
+    /// Take the server name from the TLS ClientHello in the first data sent by the client, if the target is an IP
+    fn sniff_server_name(&mut self, data: &[u8]) {
+        if let Some(Address::SocketAddress(..)) = self.info.target_addr {
+            self.server_name = parse_tls_server_name(data);
+        }
+    }
+
+    fn add_bytes(&mut self, up: u64, down: u64) {
+        self.up += up;
+        self.down += down;
+
+        let total = self.up + self.down;
+        if total >= self.next_milestone {
+            self.next_milestone = (total / BYTES_MILESTONE + 1) * BYTES_MILESTONE;
+            let (up, down) = (self.up, self.down);
+            self.notify(|o, info| o.on_bytes(info, up, down));
+            self.flush_domain_traffic();
+        }
+    }
+
+    /// Add bytes relayed since the last flush to the domain of the target
+    fn flush_domain_traffic(&mut self) {
+        let (up, down) = (self.up - self.domain_up, self.down - self.domain_down);
+        if up == 0 && down == 0 {
+            return;
+        }
+
+        let domain = match (&self.server_name, &self.info.target_addr) {
+            (Some(server_name), _) => server_name.clone(),
+            (None, Some(Address::DomainNameAddress(dname, _))) => dname.trim_end_matches('.').to_ascii_lowercase(),
+            (None, Some(Address::SocketAddress(saddr))) => saddr.ip().to_string(),
+            (None, None) => return,
+        };
+
+        let new_connection = self.domain_up == 0 && self.domain_down == 0;
+        self.observers.domains.add(&domain, up, down, new_connection);
+        self.domain_up = self.up;
+        self.domain_down = self.down;
+    }
+
+    pub fn closed(&mut self, reason: ConnectionCloseReason) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        self.flush_domain_traffic();
+
+        let (up, down) = (self.up, self.down);
+        self.notify(|o, info| o.on_closed(info, &reason, up, down));
+    }
+}
+
+impl Drop for ObservedConnection {
+    fn drop(&mut self) {
+        self.closed(ConnectionCloseReason::Aborted);
+        self.observers.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Client stream counting bytes relayed for an [`ObservedConnection`]
+pub(crate) struct ObservedStream<'a, S> {
+    stream: S,
+    conn: &'a mut ObservedConnection,
+}
+
+impl<'a, S> ObservedStream<'a, S> {
+    pub fn new(stream: S, conn: &'a mut ObservedConnection) -> ObservedStream<'a, S> {
+        ObservedStream { stream, conn }
+    }
+}
+
+impl<S> AsyncRead for ObservedStream<'_, S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(..)) = result {
+            if this.conn.up == 0 {
+                this.conn.sniff_server_name(&buf.filled()[filled..]);
+            }
+            this.conn.add_bytes((buf.filled().len() - filled) as u64, 0);
+        }
+        result
+    }
+}
+
+impl<S> AsyncWrite for ObservedStream<'_, S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            this.conn.add_bytes(0, n as u64);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
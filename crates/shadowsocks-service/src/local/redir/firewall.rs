@@ -0,0 +1,518 @@
+//! Automatic firewall rules for transparent proxy
+//!
+//! Installs the Netfilter rules that redirect traffic to the redir local server,
+//! and removes them when the server exits.
+
+use std::{
+    fmt::Write as _,
+    io::{self, ErrorKind, Write},
+    net::IpAddr,
+    process::{Command, Stdio},
+};
+
+use ipnet::IpNet;
+use log::{debug, error, info, warn};
+
+use crate::config::{RedirFirewallType, RedirType};
+
+/// Name of iptables chains and nftables table created by shadowsocks
+const CHAIN_NAME: &str = "SHADOWSOCKS";
+const NFT_TABLE_NAME: &str = "shadowsocks";
+
+/// Mark and routing table for TPROXY
+const TPROXY_MARK: u32 = 0x1;
+const TPROXY_ROUTE_TABLE: u32 = 100;
+
+/// Destinations that won't be redirected by default
+const DEFAULT_BYPASS_NETWORKS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Default destinations that won't be redirected
+pub fn default_bypass_networks() -> Vec<IpNet> {
+    DEFAULT_BYPASS_NETWORKS
+        .iter()
+        .map(|n| n.parse::<IpNet>().expect("default bypass network"))
+        .collect()
+}
+
+/// Parameters of the rules that will be installed
+#[derive(Debug, Clone)]
+pub struct RedirFirewallConfig {
+    pub firewall: RedirFirewallType,
+    pub bypass: Vec<IpNet>,
+    pub server_addrs: Vec<IpAddr>,
+    pub tcp: Option<(RedirType, u16)>,
+    pub udp: Option<(RedirType, u16)>,
+}
+
+/// Installed firewall rules, which will be removed when dropped
+#[derive(Debug)]
+pub struct RedirFirewall {
+    config: RedirFirewallConfig,
+}
+
+/// A program and its arguments
+type FirewallCommand = (&'static str, Vec<String>);
+
+fn command(program: &'static str, args: &[&str]) -> FirewallCommand {
+    (program, args.iter().map(|a| (*a).to_owned()).collect())
+}
+
+impl RedirFirewallConfig {
+    fn validate(&self) -> io::Result<()> {
+        if let Some((ty, _)) = self.tcp {
+            if !matches!(ty, RedirType::Redirect | RedirType::TProxy) {
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!("redir firewall doesn't support tcp_redir {ty}"),
+                ));
+            }
+        }
+        if let Some((ty, _)) = self.udp {
+            if ty != RedirType::TProxy {
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!("redir firewall doesn't support udp_redir {ty}"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn bypass_networks(&self, ipv6: bool) -> Vec<IpNet> {
+        let mut networks = Vec::new();
+        for net in &self.bypass {
+            if matches!(net, IpNet::V6(..)) == ipv6 {
+                networks.push(*net);
+            }
+        }
+        for addr in &self.server_addrs {
+            if addr.is_ipv6() == ipv6 {
+                networks.push(IpNet::from(*addr));
+            }
+        }
+        networks
+    }
+
+    fn uses_tproxy(&self) -> bool {
+        matches!(self.tcp, Some((RedirType::TProxy, _))) || self.udp.is_some()
+    }
+
+    fn iptables_commands(&self) -> Vec<FirewallCommand> {
+        let mut commands = Vec::new();
+
+        for (iptables, ipv6) in [("iptables", false), ("ip6tables", true)] {
+            let bypass = self.bypass_networks(ipv6);
+
+            for (table, ty) in [("nat", RedirType::Redirect), ("mangle", RedirType::TProxy)] {
+                let tcp_port = match self.tcp {
+                    Some((t, port)) if t == ty => Some(port),
+                    _ => None,
+                };
+                let udp_port = match self.udp {
+                    Some((t, port)) if t == ty => Some(port),
+                    _ => None,
+                };
+                if tcp_port.is_none() && udp_port.is_none() {
+                    continue;
+                }
+
+                commands.push(command(iptables, &["-w", "-t", table, "-N", CHAIN_NAME]));
+                for net in &bypass {
+                    let net = net.to_string();
+                    commands.push(command(
+                        iptables,
+                        &["-w", "-t", table, "-A", CHAIN_NAME, "-d", &net, "-j", "RETURN"],
+                    ));
+                }
+
+                for (proto, port) in [("tcp", tcp_port), ("udp", udp_port)] {
+                    let port = match port {
+                        Some(p) => p.to_string(),
+                        None => continue,
+                    };
+
+                    let mut args = vec!["-w", "-t", table, "-A", CHAIN_NAME, "-p", proto];
+                    let mark = format!("{TPROXY_MARK:#x}/{TPROXY_MARK:#x}");
+                    match ty {
+                        RedirType::Redirect => args.extend_from_slice(&["-j", "REDIRECT", "--to-ports", &port]),
+                        _ => args.extend_from_slice(&["-j", "TPROXY", "--on-port", &port, "--tproxy-mark", &mark]),
+                    }
+                    commands.push(command(iptables, &args));
+                }
+
+                commands.push(command(
+                    iptables,
+                    &["-w", "-t", table, "-A", "PREROUTING", "-j", CHAIN_NAME],
+                ));
+            }
+        }
+
+        commands
+    }
+
+    fn nftables_rules(&self) -> String {
+        let mut rules = String::new();
+
+        let _ = writeln!(rules, "table inet {NFT_TABLE_NAME} {{");
+
+        let mut bypass_rules = String::new();
+        for (family, ipv6) in [("ip", false), ("ip6", true)] {
+            let bypass = self.bypass_networks(ipv6);
+            if bypass.is_empty() {
+                continue;
+            }
+            let bypass = bypass.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ");
+            let _ = writeln!(bypass_rules, "        {family} daddr {{ {bypass} }} return");
+        }
+
+        if let Some((RedirType::Redirect, port)) = self.tcp {
+            let _ = writeln!(rules, "    chain prerouting_nat {{");
+            let _ = writeln!(
+                rules,
+                "        type nat hook prerouting priority dstnat; policy accept;"
+            );
+            rules.push_str(&bypass_rules);
+            let _ = writeln!(rules, "        meta l4proto tcp redirect to :{port}");
+            let _ = writeln!(rules, "    }}");
+        }
+
+        if self.uses_tproxy() {
+            let _ = writeln!(rules, "    chain prerouting_mangle {{");
+            let _ = writeln!(
+                rules,
+                "        type filter hook prerouting priority mangle; policy accept;"
+            );
+            rules.push_str(&bypass_rules);
+            if let Some((RedirType::TProxy, port)) = self.tcp {
+                let _ = writeln!(
+                    rules,
+                    "        meta l4proto tcp tproxy to :{port} meta mark set {TPROXY_MARK:#x} accept"
+                );
+            }
+            if let Some((_, port)) = self.udp {
+                let _ = writeln!(
+                    rules,
+                    "        meta l4proto udp tproxy to :{port} meta mark set {TPROXY_MARK:#x} accept"
+                );
+            }
+            let _ = writeln!(rules, "    }}");
+        }
+
+        let _ = writeln!(rules, "}}");
+
+        rules
+    }
+}
+
+impl RedirFirewall {
+    /// Install rules into the system's firewall
+    ///
+    /// Rules should be installed after the redir listeners are bound, so ports in `config` are the ones listening.
+    pub fn install(config: RedirFirewallConfig) -> io::Result<RedirFirewall> {
+        config.validate()?;
+
+        let firewall = RedirFirewall { config };
+
+        // Clean up rules left by a previous instance that didn't exit gracefully
+        firewall.uninstall();
+
+        let result = match firewall.config.firewall {
+            RedirFirewallType::Iptables => firewall.install_iptables(),
+            RedirFirewallType::Nftables => firewall.install_nftables(),
+        };
+
+        // firewall will be dropped and partially installed rules will be removed
+        result?;
+
+        info!(
+            "redir firewall rules installed with {}, bypassing {} networks and {} servers",
+            firewall.config.firewall,
+            firewall.config.bypass.len(),
+            firewall.config.server_addrs.len()
+        );
+
+        Ok(firewall)
+    }
+
+    fn install_iptables(&self) -> io::Result<()> {
+        for (program, args) in self.config.iptables_commands() {
+            let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+            run_command(program, &args)?;
+        }
+
+        if self.config.uses_tproxy() {
+            self.install_tproxy_route()?;
+        }
+
+        Ok(())
+    }
+
+    fn install_nftables(&self) -> io::Result<()> {
+        let rules = self.config.nftables_rules();
+        debug!("redir firewall nftables rules:\n{}", rules);
+
+        let mut child = Command::new("nft")
+            .args(["-f", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(rules.as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                format!(
+                    "nft exited with {}, {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+            ));
+        }
+
+        if self.config.uses_tproxy() {
+            self.install_tproxy_route()?;
+        }
+
+        Ok(())
+    }
+
+    fn install_tproxy_route(&self) -> io::Result<()> {
+        let mark = TPROXY_MARK.to_string();
+        let table = TPROXY_ROUTE_TABLE.to_string();
+
+        for (family, local) in [("-4", "0.0.0.0/0"), ("-6", "::/0")] {
+            run_command("ip", &[family, "rule", "add", "fwmark", &mark, "lookup", &table])?;
+            run_command(
+                "ip",
+                &[family, "route", "add", "local", local, "dev", "lo", "table", &table],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove all rules, errors are ignored because some of them may not exist
+    fn uninstall(&self) {
+        match self.config.firewall {
+            RedirFirewallType::Iptables => {
+                for iptables in ["iptables", "ip6tables"] {
+                    for table in ["nat", "mangle"] {
+                        let _ = run_command_quiet(iptables, &["-w", "-t", table, "-D", "PREROUTING", "-j", CHAIN_NAME]);
+                        let _ = run_command_quiet(iptables, &["-w", "-t", table, "-F", CHAIN_NAME]);
+                        let _ = run_command_quiet(iptables, &["-w", "-t", table, "-X", CHAIN_NAME]);
+                    }
+                }
+            }
+            RedirFirewallType::Nftables => {
+                let _ = run_command_quiet("nft", &["delete", "table", "inet", NFT_TABLE_NAME]);
+            }
+        }
+
+        if self.config.uses_tproxy() {
+            let mark = TPROXY_MARK.to_string();
+            let table = TPROXY_ROUTE_TABLE.to_string();
+
+            for (family, local) in [("-4", "0.0.0.0/0"), ("-6", "::/0")] {
+                let _ = run_command_quiet("ip", &[family, "rule", "del", "fwmark", &mark, "lookup", &table]);
+                let _ = run_command_quiet(
+                    "ip",
+                    &[family, "route", "del", "local", local, "dev", "lo", "table", &table],
+                );
+            }
+        }
+    }
+}
+
+impl Drop for RedirFirewall {
+    fn drop(&mut self) {
+        self.uninstall();
+        info!("redir firewall rules removed");
+    }
+}
+
+fn run_command(program: &str, args: &[&str]) -> io::Result<()> {
+    debug!("redir firewall: {} {}", program, args.join(" "));
+
+    let output = match Command::new(program).args(args).stdin(Stdio::null()).output() {
+        Ok(o) => o,
+        Err(err) => {
+            error!("redir firewall failed to execute {}, error: {}", program, err);
+            return Err(err);
+        }
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        warn!(
+            "redir firewall: {} {} exited with {}, {}",
+            program,
+            args.join(" "),
+            output.status,
+            stderr.trim()
+        );
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!("{} exited with {}, {}", program, output.status, stderr.trim()),
+        ));
+    }
+
+    Ok(())
+}
+
+fn run_command_quiet(program: &str, args: &[&str]) -> io::Result<()> {
+    let status = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            ErrorKind::Other,
+            format!("{program} exited with {status}"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Rules are only generated, a `RedirFirewall` would remove rules of the system when dropped
+    fn test_config(firewall: RedirFirewallType, tcp: RedirType) -> RedirFirewallConfig {
+        RedirFirewallConfig {
+            firewall,
+            bypass: vec!["10.0.0.0/8".parse().unwrap(), "fc00::/7".parse().unwrap()],
+            server_addrs: vec!["1.2.3.4".parse().unwrap()],
+            tcp: Some((tcp, 60080)),
+            udp: Some((RedirType::TProxy, 60081)),
+        }
+    }
+
+    fn has_command(commands: &[FirewallCommand], program: &str, args: &str) -> bool {
+        commands.iter().any(|(p, a)| *p == program && a.join(" ") == args)
+    }
+
+    #[test]
+    fn reject_unsupported_redir_types() {
+        let mut config = test_config(RedirFirewallType::Iptables, RedirType::Redirect);
+        assert!(config.validate().is_ok());
+
+        config.udp = Some((RedirType::Redirect, 60081));
+        assert!(config.validate().is_err());
+
+        config.udp = None;
+        config.tcp = Some((RedirType::NotSupported, 60080));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn iptables_redirect_tcp_tproxy_udp() {
+        let config = test_config(RedirFirewallType::Iptables, RedirType::Redirect);
+        let commands = config.iptables_commands();
+
+        assert!(has_command(&commands, "iptables", "-w -t nat -N SHADOWSOCKS"));
+        assert!(has_command(
+            &commands,
+            "iptables",
+            "-w -t nat -A SHADOWSOCKS -d 10.0.0.0/8 -j RETURN"
+        ));
+        assert!(has_command(
+            &commands,
+            "iptables",
+            "-w -t nat -A SHADOWSOCKS -d 1.2.3.4/32 -j RETURN"
+        ));
+        assert!(has_command(
+            &commands,
+            "iptables",
+            "-w -t nat -A SHADOWSOCKS -p tcp -j REDIRECT --to-ports 60080"
+        ));
+        assert!(has_command(
+            &commands,
+            "iptables",
+            "-w -t mangle -A SHADOWSOCKS -p udp -j TPROXY --on-port 60081 --tproxy-mark 0x1/0x1"
+        ));
+        assert!(has_command(
+            &commands,
+            "iptables",
+            "-w -t nat -A PREROUTING -j SHADOWSOCKS"
+        ));
+        assert!(has_command(
+            &commands,
+            "ip6tables",
+            "-w -t mangle -A SHADOWSOCKS -d fc00::/7 -j RETURN"
+        ));
+
+        // TCP isn't redirected in mangle, nor UDP in nat
+        assert!(
+            !commands
+                .iter()
+                .any(|(_, a)| a.contains(&"mangle".to_owned()) && a.contains(&"tcp".to_owned()))
+        );
+        assert!(
+            !commands
+                .iter()
+                .any(|(_, a)| a.contains(&"nat".to_owned()) && a.contains(&"udp".to_owned()))
+        );
+        // IPv4 networks are not added to ip6tables
+        assert!(
+            !commands
+                .iter()
+                .any(|(p, a)| *p == "ip6tables" && a.contains(&"10.0.0.0/8".to_owned()))
+        );
+
+        // Chains are created before they are referenced by PREROUTING
+        let created = commands
+            .iter()
+            .position(|(p, a)| *p == "iptables" && a.join(" ") == "-w -t nat -N SHADOWSOCKS")
+            .unwrap();
+        let referenced = commands
+            .iter()
+            .position(|(p, a)| *p == "iptables" && a.join(" ") == "-w -t nat -A PREROUTING -j SHADOWSOCKS")
+            .unwrap();
+        assert!(created < referenced);
+    }
+
+    #[test]
+    fn nftables_tproxy() {
+        let config = test_config(RedirFirewallType::Nftables, RedirType::TProxy);
+        let rules = config.nftables_rules();
+
+        assert!(rules.starts_with("table inet shadowsocks {\n"));
+        assert!(!rules.contains("prerouting_nat"));
+        assert!(rules.contains("        ip daddr { 10.0.0.0/8, 1.2.3.4/32 } return\n"));
+        assert!(rules.contains("        ip6 daddr { fc00::/7 } return\n"));
+        assert!(rules.contains("        meta l4proto tcp tproxy to :60080 meta mark set 0x1 accept\n"));
+        assert!(rules.contains("        meta l4proto udp tproxy to :60081 meta mark set 0x1 accept\n"));
+    }
+
+    #[test]
+    fn nftables_redirect() {
+        let mut config = test_config(RedirFirewallType::Nftables, RedirType::Redirect);
+        config.udp = None;
+        let rules = config.nftables_rules();
+
+        assert!(rules.contains("        type nat hook prerouting priority dstnat; policy accept;\n"));
+        assert!(rules.contains("        meta l4proto tcp redirect to :60080\n"));
+        assert!(!rules.contains("prerouting_mangle"));
+    }
+}
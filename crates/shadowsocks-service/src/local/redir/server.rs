@@ -3,6 +3,8 @@
 use std::{io, sync::Arc, time::Duration};
 
 use futures::{FutureExt, future};
+#[cfg(target_os = "linux")]
+use ipnet::IpNet;
 use shadowsocks::{ServerAddr, config::Mode};
 
 use crate::{
@@ -10,6 +12,11 @@ use crate::{
     local::{context::ServiceContext, loadbalancing::PingBalancer},
 };
 
+#[cfg(target_os = "linux")]
+use crate::config::RedirFirewallType;
+
+#[cfg(target_os = "linux")]
+use super::firewall::{RedirFirewall, RedirFirewallConfig, default_bypass_networks};
 use super::{tcprelay::RedirTcpServer, udprelay::RedirUdpServer};
 
 /// Transparent Proxy builder
@@ -23,6 +30,8 @@ pub struct RedirBuilder {
     client_addr: ServerAddr,
     udp_bind_addr: Option<ServerAddr>,
     balancer: PingBalancer,
+    #[cfg(target_os = "linux")]
+    firewall: Option<(RedirFirewallType, Option<Vec<IpNet>>)>,
 }
 
 impl RedirBuilder {
@@ -44,6 +53,8 @@ impl RedirBuilder {
             client_addr,
             udp_bind_addr: None,
             balancer,
+            #[cfg(target_os = "linux")]
+            firewall: None,
         }
     }
 
@@ -77,6 +88,57 @@ impl RedirBuilder {
         self.udp_bind_addr = Some(addr);
     }
 
+    /// Install firewall rules for redirecting traffic to this server automatically.
+    ///
+    /// Destinations in `bypass` and addresses of remote servers won't be redirected.
+    /// Uses a default list of private and reserved networks if `bypass` is `None`.
+    #[cfg(target_os = "linux")]
+    pub fn set_firewall(&mut self, firewall: RedirFirewallType, bypass: Option<Vec<IpNet>>) {
+        self.firewall = Some((firewall, bypass));
+    }
+
+    /// Install firewall rules redirecting to the bound ports `tcp_port` and `udp_port`
+    #[cfg(target_os = "linux")]
+    async fn install_firewall(
+        &self,
+        tcp_port: Option<u16>,
+        udp_port: Option<u16>,
+    ) -> io::Result<Option<RedirFirewall>> {
+        let (firewall, bypass) = match self.firewall {
+            Some((ref f, ref b)) => (*f, b.clone().unwrap_or_else(default_bypass_networks)),
+            None => return Ok(None),
+        };
+
+        // Traffic to remote servers must not be redirected, otherwise it loops back to us, including their extra
+        // addresses which are the same hosts
+        let mut server_addrs = Vec::new();
+        for server in self.balancer.servers() {
+            let svr_cfg = server.server_config();
+            for addr in std::iter::once(svr_cfg.addr()).chain(svr_cfg.extra_addrs()) {
+                match *addr {
+                    ServerAddr::SocketAddr(ref sa) => server_addrs.push(sa.ip()),
+                    ServerAddr::DomainName(ref dn, port) => {
+                        for sa in self.context.context().dns_resolve(dn, port).await? {
+                            server_addrs.push(sa.ip());
+                        }
+                    }
+                }
+            }
+        }
+        server_addrs.sort_unstable();
+        server_addrs.dedup();
+
+        let config = RedirFirewallConfig {
+            firewall,
+            bypass,
+            server_addrs,
+            tcp: tcp_port.map(|port| (self.tcp_redir, port)),
+            udp: udp_port.map(|port| (self.udp_redir, port)),
+        };
+
+        RedirFirewall::install(config).map(Some)
+    }
+
     pub async fn build(self) -> io::Result<Redir> {
         let mut tcp_server = None;
         if self.mode.enable_tcp() {
@@ -95,18 +157,37 @@ impl RedirBuilder {
             let udp_addr = self.udp_bind_addr.as_ref().unwrap_or(&self.client_addr);
 
             let server = RedirUdpServer::new(
-                self.context,
+                self.context.clone(),
                 self.udp_redir,
                 udp_addr,
                 self.udp_expiry_duration,
                 self.udp_capacity,
-                self.balancer,
+                self.balancer.clone(),
             )
             .await?;
             udp_server = Some(server);
         }
 
-        Ok(Redir { tcp_server, udp_server })
+        // Rules are installed after listeners are bound, a failed bind won't leave rules redirecting to nowhere
+        #[cfg(target_os = "linux")]
+        let firewall = {
+            let tcp_port = match tcp_server {
+                Some(ref s) => Some(s.local_addr()?.port()),
+                None => None,
+            };
+            let udp_port = match udp_server {
+                Some(ref s) => Some(s.local_addr()?.port()),
+                None => None,
+            };
+            self.install_firewall(tcp_port, udp_port).await?
+        };
+
+        Ok(Redir {
+            tcp_server,
+            udp_server,
+            #[cfg(target_os = "linux")]
+            firewall,
+        })
     }
 }
 
@@ -114,6 +195,8 @@ impl RedirBuilder {
 pub struct Redir {
     tcp_server: Option<RedirTcpServer>,
     udp_server: Option<RedirUdpServer>,
+    #[cfg(target_os = "linux")]
+    firewall: Option<RedirFirewall>,
 }
 
 impl Redir {
@@ -129,6 +212,10 @@ impl Redir {
 
     /// Start serving
     pub async fn run(self) -> io::Result<()> {
+        // Rules are removed when the server exits
+        #[cfg(target_os = "linux")]
+        let _firewall = self.firewall;
+
         let mut vfut = Vec::new();
 
         if let Some(tcp_server) = self.tcp_server {
@@ -13,6 +13,7 @@ use tokio::{
     net::{TcpListener, TcpStream},
     time,
 };
+use tracing::Instrument;
 
 use crate::{
     config::RedirType,
@@ -23,7 +24,9 @@ use crate::{
         redir::redir_ext::{TcpListenerRedirExt, TcpStreamRedirExt},
         utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
     },
+    log_control::connection_span,
     net::utils::to_ipv4_mapped,
+    utils::spawn_cancellable,
 };
 
 #[allow(unused_imports)]
@@ -39,18 +42,19 @@ async fn establish_client_tcp_redir(
     peer_addr: SocketAddr,
     addr: &Address,
 ) -> io::Result<()> {
+    let mut observed = context.observe_connection(peer_addr)?;
+    observed.target_resolved(addr);
+
     if balancer.is_empty() {
         let mut remote = AutoProxyClientStream::connect_bypassed(context, addr).await?;
-        return establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, addr).await;
+        return establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, addr, &mut observed).await;
     }
 
-    let server = balancer.best_tcp_server();
+    let (server, result) = AutoProxyClientStream::connect_balanced(context, &balancer, addr).await;
     let svr_cfg = server.server_config();
+    let mut remote = result?;
 
-    let mut remote =
-        AutoProxyClientStream::connect_with_opts(context, &server, addr, server.connect_opts_ref()).await?;
-
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, addr).await
+    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, addr, &mut observed).await
 }
 
 async fn handle_redir_client(
@@ -138,22 +142,26 @@ impl RedirTcpServer {
             let context = self.context.clone();
             let balancer = self.balancer.clone();
             let redir_ty = self.redir_ty;
-            tokio::spawn(async move {
-                let dst_addr = match socket.destination_addr(redir_ty) {
-                    Ok(d) => d,
-                    Err(err) => {
-                        error!(
-                            "TCP redirect couldn't get destination, peer: {}, error: {}",
-                            peer_addr, err
-                        );
-                        return;
+            spawn_cancellable(
+                self.context.cancellation_token(),
+                async move {
+                    let dst_addr = match socket.destination_addr(redir_ty) {
+                        Ok(d) => d,
+                        Err(err) => {
+                            error!(
+                                "TCP redirect couldn't get destination, peer: {}, error: {}",
+                                peer_addr, err
+                            );
+                            return;
+                        }
+                    };
+
+                    if let Err(err) = handle_redir_client(context, balancer, socket, peer_addr, dst_addr).await {
+                        debug!("TCP redirect client, error: {:?}", err);
                     }
-                };
-
-                if let Err(err) = handle_redir_client(context, balancer, socket, peer_addr, dst_addr).await {
-                    debug!("TCP redirect client, error: {:?}", err);
                 }
-            });
+                .instrument(connection_span(peer_addr)),
+            );
         }
     }
 }
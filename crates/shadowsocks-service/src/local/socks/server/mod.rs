@@ -5,6 +5,8 @@ use std::{io, sync::Arc, time::Duration};
 use futures::{FutureExt, future};
 use shadowsocks::{ServerAddr, config::Mode};
 
+#[cfg(feature = "local-tls")]
+use crate::config::LocalTlsConfig;
 use crate::local::{context::ServiceContext, loadbalancing::PingBalancer};
 
 pub use self::server::{SocksTcpServer, SocksTcpServerBuilder, SocksUdpServer};
@@ -29,6 +31,8 @@ pub struct SocksBuilder {
     socks5_auth: Socks5AuthConfig,
     client_config: ServerAddr,
     balancer: PingBalancer,
+    #[cfg(feature = "local-tls")]
+    tls: Option<LocalTlsConfig>,
     #[cfg(target_os = "macos")]
     launchd_tcp_socket_name: Option<String>,
     #[cfg(target_os = "macos")]
@@ -58,6 +62,8 @@ impl SocksBuilder {
             socks5_auth: Socks5AuthConfig::default(),
             client_config,
             balancer,
+            #[cfg(feature = "local-tls")]
+            tls: None,
             #[cfg(target_os = "macos")]
             launchd_tcp_socket_name: None,
             #[cfg(target_os = "macos")]
@@ -97,6 +103,14 @@ impl SocksBuilder {
         self.socks5_auth = p;
     }
 
+    /// Terminate TLS of TCP clients
+    ///
+    /// UDP relay is not affected, clients still send plain SOCKS5 UDP packets to it
+    #[cfg(feature = "local-tls")]
+    pub fn set_tls(&mut self, tls: LocalTlsConfig) {
+        self.tls = Some(tls);
+    }
+
     /// macOS launchd activate socket
     #[cfg(target_os = "macos")]
     pub fn set_launchd_tcp_socket_name(&mut self, n: String) {
@@ -150,6 +164,11 @@ impl SocksBuilder {
                 self.socks5_auth,
             );
 
+            #[cfg(feature = "local-tls")]
+            if let Some(tls) = self.tls {
+                builder.set_tls(tls);
+            }
+
             #[cfg(target_os = "macos")]
             if let Some(s) = self.launchd_tcp_socket_name {
                 builder.set_launchd_socket_name(s);
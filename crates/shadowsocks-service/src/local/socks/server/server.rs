@@ -2,13 +2,27 @@ use std::{io, net::SocketAddr, sync::Arc, time::Duration};
 
 use log::{error, info};
 use shadowsocks::{ServerAddr, config::Mode, net::TcpListener as ShadowTcpListener};
+#[cfg(any(feature = "local-socks4", feature = "local-http"))]
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::{net::TcpStream, time};
+#[cfg(feature = "local-tls")]
+use tokio_rustls::TlsAcceptor;
+use tracing::Instrument;
 
 #[cfg(feature = "local-http")]
 use crate::local::http::HttpConnectionHandler;
-use crate::local::{
-    context::ServiceContext, loadbalancing::PingBalancer, net::tcp::listener::create_standard_tcp_listener,
-    socks::config::Socks5AuthConfig,
+#[cfg(feature = "local-tls")]
+use crate::{
+    config::LocalTlsConfig,
+    local::net::tcp::tls::{accept_tls, make_tls_acceptor},
+};
+use crate::{
+    local::{
+        context::ServiceContext, loadbalancing::PingBalancer, net::tcp::listener::create_standard_tcp_listener,
+        socks::config::Socks5AuthConfig,
+    },
+    log_control::connection_span,
+    utils::spawn_cancellable,
 };
 
 #[cfg(feature = "local-socks4")]
@@ -22,6 +36,8 @@ pub struct SocksTcpServerBuilder {
     balancer: PingBalancer,
     mode: Mode,
     socks5_auth: Arc<Socks5AuthConfig>,
+    #[cfg(feature = "local-tls")]
+    tls: Option<LocalTlsConfig>,
     #[cfg(target_os = "macos")]
     launchd_socket_name: Option<String>,
 }
@@ -42,11 +58,19 @@ impl SocksTcpServerBuilder {
             balancer,
             mode,
             socks5_auth: Arc::new(socks5_auth),
+            #[cfg(feature = "local-tls")]
+            tls: None,
             #[cfg(target_os = "macos")]
             launchd_socket_name: None,
         }
     }
 
+    /// Terminate TLS of clients
+    #[cfg(feature = "local-tls")]
+    pub fn set_tls(&mut self, tls: LocalTlsConfig) {
+        self.tls = Some(tls);
+    }
+
     /// macOS launchd activate socket
     #[cfg(target_os = "macos")]
     pub fn set_launchd_socket_name(&mut self, n: String) {
@@ -54,6 +78,12 @@ impl SocksTcpServerBuilder {
     }
 
     pub async fn build(self) -> io::Result<SocksTcpServer> {
+        #[cfg(feature = "local-tls")]
+        let tls_acceptor = match self.tls {
+            Some(ref tls) => Some(make_tls_acceptor(tls)?),
+            None => None,
+        };
+
         cfg_if::cfg_if! {
             if #[cfg(target_os = "macos")] {
                 let listener = match self.launchd_socket_name {
@@ -80,6 +110,8 @@ impl SocksTcpServerBuilder {
             balancer: self.balancer,
             mode: self.mode,
             socks5_auth: self.socks5_auth,
+            #[cfg(feature = "local-tls")]
+            tls_acceptor,
         })
     }
 }
@@ -92,6 +124,8 @@ pub struct SocksTcpServer {
     balancer: PingBalancer,
     mode: Mode,
     socks5_auth: Arc<Socks5AuthConfig>,
+    #[cfg(feature = "local-tls")]
+    tls_acceptor: Option<TlsAcceptor>,
 }
 
 impl SocksTcpServer {
@@ -122,20 +156,25 @@ impl SocksTcpServer {
             let handler = SocksTcpHandler {
                 context: self.context.clone(),
                 udp_associate_addr: udp_associate_addr.clone(),
-                stream,
                 balancer: self.balancer.clone(),
                 peer_addr,
                 mode: self.mode,
                 socks5_auth: self.socks5_auth.clone(),
                 #[cfg(feature = "local-http")]
                 http_handler: http_handler.clone(),
+                #[cfg(feature = "local-tls")]
+                tls_acceptor: self.tls_acceptor.clone(),
             };
 
-            tokio::spawn(async move {
-                if let Err(err) = handler.handle_tcp_client().await {
-                    error!("socks5 tcp client handler error: {}", err);
+            spawn_cancellable(
+                self.context.cancellation_token(),
+                async move {
+                    if let Err(err) = handler.handle_tcp_client(stream).await {
+                        error!("socks5 tcp client handler error: {}", err);
+                    }
                 }
-            });
+                .instrument(connection_span(peer_addr)),
+            );
         }
     }
 }
@@ -143,18 +182,19 @@ impl SocksTcpServer {
 struct SocksTcpHandler {
     context: Arc<ServiceContext>,
     udp_associate_addr: Arc<ServerAddr>,
-    stream: TcpStream,
     balancer: PingBalancer,
     peer_addr: SocketAddr,
     mode: Mode,
     socks5_auth: Arc<Socks5AuthConfig>,
     #[cfg(feature = "local-http")]
     http_handler: HttpConnectionHandler,
+    #[cfg(feature = "local-tls")]
+    tls_acceptor: Option<TlsAcceptor>,
 }
 
 impl SocksTcpHandler {
     #[cfg(not(any(feature = "local-socks4", feature = "local-http")))]
-    async fn handle_tcp_client(self) -> io::Result<()> {
+    async fn handle_tcp_client(self, stream: TcpStream) -> io::Result<()> {
         let handler = Socks5TcpHandler::new(
             self.context,
             self.udp_associate_addr,
@@ -162,20 +202,50 @@ impl SocksTcpHandler {
             self.mode,
             self.socks5_auth,
         );
-        handler.handle_socks5_client(self.stream, self.peer_addr).await
+
+        #[cfg(feature = "local-tls")]
+        if let Some(acceptor) = self.tls_acceptor {
+            let stream = accept_tls(&acceptor, stream).await?;
+            return handler.handle_socks5_client(stream, self.peer_addr).await;
+        }
+
+        handler.handle_socks5_client(stream, self.peer_addr).await
     }
 
     #[cfg(any(feature = "local-socks4", feature = "local-http"))]
-    async fn handle_tcp_client(self) -> io::Result<()> {
+    async fn handle_tcp_client(self, stream: TcpStream) -> io::Result<()> {
         use std::io::ErrorKind;
 
+        #[cfg(feature = "local-tls")]
+        if let Some(ref acceptor) = self.tls_acceptor {
+            use tokio::io::{AsyncBufReadExt, BufReader};
+
+            // TLS streams couldn't be peeked, the version is kept in the buffer of BufReader instead
+            let mut stream = BufReader::new(accept_tls(acceptor, stream).await?);
+            let version = match stream.fill_buf().await?.first() {
+                Some(&version) => version,
+                None => return Err(ErrorKind::UnexpectedEof.into()),
+            };
+            return self.dispatch(stream, version).await;
+        }
+
         let mut version_buffer = [0u8; 1];
-        let n = self.stream.peek(&mut version_buffer).await?;
+        let n = stream.peek(&mut version_buffer).await?;
         if n == 0 {
             return Err(ErrorKind::UnexpectedEof.into());
         }
 
-        match version_buffer[0] {
+        self.dispatch(stream, version_buffer[0]).await
+    }
+
+    #[cfg(any(feature = "local-socks4", feature = "local-http"))]
+    async fn dispatch<S>(self, stream: S, version: u8) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        use std::io::ErrorKind;
+
+        match version {
             #[cfg(feature = "local-socks4")]
             0x04 => {
                 if self.socks5_auth.auth_required() {
@@ -183,7 +253,7 @@ impl SocksTcpHandler {
                     Err(io::Error::new(ErrorKind::Other, "SOCKS4 unsupported"))
                 } else {
                     let handler = Socks4TcpHandler::new(self.context, self.balancer, self.mode);
-                    handler.handle_socks4_client(self.stream, self.peer_addr).await
+                    handler.handle_socks4_client(stream, self.peer_addr).await
                 }
             }
 
@@ -195,7 +265,7 @@ impl SocksTcpHandler {
                     self.mode,
                     self.socks5_auth,
                 );
-                handler.handle_socks5_client(self.stream, self.peer_addr).await
+                handler.handle_socks5_client(stream, self.peer_addr).await
             }
 
             #[cfg(feature = "local-http")]
@@ -205,7 +275,7 @@ impl SocksTcpHandler {
                     Err(io::Error::new(ErrorKind::Other, "HTTP unsupported"))
                 } else {
                     // GET, HEAD, POST, PUT, DELETE, CONNECT, OPTIONS, TRACE, PATCH
-                    match self.http_handler.serve_connection(self.stream, self.peer_addr).await {
+                    match self.http_handler.serve_connection(stream, self.peer_addr).await {
                         Ok(..) => Ok(()),
                         Err(err) => {
                             error!("HTTP connection {} handler failed with error: {}", self.peer_addr, err);
@@ -8,10 +8,7 @@ use std::{
 
 use log::{debug, error, trace, warn};
 use shadowsocks::config::Mode;
-use tokio::{
-    io::{AsyncWriteExt, BufReader},
-    net::TcpStream,
-};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 
 use crate::local::{
     context::ServiceContext,
@@ -39,7 +36,10 @@ impl Socks4TcpHandler {
         }
     }
 
-    pub async fn handle_socks4_client(self, stream: TcpStream, peer_addr: SocketAddr) -> io::Result<()> {
+    pub async fn handle_socks4_client<S>(self, stream: S, peer_addr: SocketAddr) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         // 1. Handshake
 
         // NOTE: Wraps it with BufReader for reading NULL terminated information in HandshakeRequest
@@ -75,12 +75,15 @@ impl Socks4TcpHandler {
         }
     }
 
-    async fn handle_socks4_connect(
+    async fn handle_socks4_connect<S>(
         self,
-        mut stream: BufReader<TcpStream>,
+        mut stream: BufReader<S>,
         peer_addr: SocketAddr,
         target_addr: Address,
-    ) -> io::Result<()> {
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         if !self.mode.enable_tcp() {
             warn!("TCP CONNECT is disabled");
 
@@ -91,19 +94,24 @@ impl Socks4TcpHandler {
         }
 
         let target_addr = target_addr.into();
+        let mut observed = match self.context.observe_connection(peer_addr) {
+            Ok(o) => o,
+            Err(err) => {
+                warn!("CONNECT {} from {} refused, {}", target_addr, peer_addr, err);
+
+                let handshake_rsp = HandshakeResponse::new(ResultCode::RequestRejectedOrFailed);
+                handshake_rsp.write_to(&mut stream).await?;
+
+                return Ok(());
+            }
+        };
+        observed.target_resolved(&target_addr);
+
         let mut server_opt = None;
         let server_result = if self.balancer.is_empty() {
             AutoProxyClientStream::connect_bypassed(self.context, &target_addr).await
         } else {
-            let server = self.balancer.best_tcp_server();
-
-            let r = AutoProxyClientStream::connect_with_opts(
-                self.context,
-                &server,
-                &target_addr,
-                server.connect_opts_ref(),
-            )
-            .await;
+            let (server, r) = AutoProxyClientStream::connect_balanced(self.context, &self.balancer, &target_addr).await;
             server_opt = Some(server);
 
             r
@@ -145,9 +153,19 @@ impl Socks4TcpHandler {
         match server_opt {
             Some(server) => {
                 let svr_cfg = server.server_config();
-                establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, &target_addr).await
+                establish_tcp_tunnel(
+                    svr_cfg,
+                    &mut stream,
+                    &mut remote,
+                    peer_addr,
+                    &target_addr,
+                    &mut observed,
+                )
+                .await
+            }
+            None => {
+                establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, &target_addr, &mut observed).await
             }
-            None => establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, &target_addr).await,
         }
     }
 }
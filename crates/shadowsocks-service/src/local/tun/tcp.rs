@@ -38,6 +38,7 @@ use crate::{
         utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
     },
     net::utils::to_ipv4_mapped,
+    utils::spawn_cancellable,
 };
 
 use super::virt_device::{TokenBuffer, VirtTunDevice};
@@ -540,7 +541,7 @@ impl TcpTun {
             // establish a tunnel
             let context = self.context.clone();
             let balancer = self.balancer.clone();
-            tokio::spawn(async move {
+            spawn_cancellable(self.context.cancellation_token(), async move {
                 let connection = connection.await;
                 if let Err(err) = handle_redir_client(context, balancer, connection, src_addr, dst_addr).await {
                     error!("TCP tunnel failure, {} <-> {}, error: {}", src_addr, dst_addr, err);
@@ -579,17 +580,18 @@ async fn establish_client_tcp_redir(
     peer_addr: SocketAddr,
     addr: &Address,
 ) -> io::Result<()> {
+    let mut observed = context.observe_connection(peer_addr)?;
+    observed.target_resolved(addr);
+
     if balancer.is_empty() {
         let mut remote = AutoProxyClientStream::connect_bypassed(context, addr).await?;
-        return establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, addr).await;
+        return establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, addr, &mut observed).await;
     }
 
-    let server = balancer.best_tcp_server();
+    let (server, result) = AutoProxyClientStream::connect_balanced(context, &balancer, addr).await;
     let svr_cfg = server.server_config();
-
-    let mut remote =
-        AutoProxyClientStream::connect_with_opts(context, &server, addr, server.connect_opts_ref()).await?;
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, addr).await
+    let mut remote = result?;
+    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, addr, &mut observed).await
 }
 
 async fn handle_redir_client(
@@ -20,6 +20,7 @@ pub struct TunnelBuilder {
     udp_capacity: Option<usize>,
     client_addr: ServerAddr,
     udp_addr: Option<ServerAddr>,
+    proxy_protocol: bool,
     balancer: PingBalancer,
     #[cfg(target_os = "macos")]
     launchd_tcp_socket_name: Option<String>,
@@ -49,6 +50,7 @@ impl TunnelBuilder {
             udp_capacity: None,
             client_addr,
             udp_addr: None,
+            proxy_protocol: false,
             balancer,
             #[cfg(target_os = "macos")]
             launchd_tcp_socket_name: None,
@@ -77,6 +79,11 @@ impl TunnelBuilder {
         self.udp_addr = Some(addr);
     }
 
+    /// Send a PROXY protocol v2 header to the forward address ahead of every TCP connection
+    pub fn set_proxy_protocol(&mut self, proxy_protocol: bool) {
+        self.proxy_protocol = proxy_protocol;
+    }
+
     /// macOS launchd activate socket
     #[cfg(target_os = "macos")]
     pub fn set_launchd_tcp_socket_name(&mut self, n: String) {
@@ -92,13 +99,13 @@ impl TunnelBuilder {
     pub async fn build(self) -> io::Result<Tunnel> {
         let mut tcp_server = None;
         if self.mode.enable_tcp() {
-            #[allow(unused_mut)]
             let mut builder = TunnelTcpServerBuilder::new(
                 self.context.clone(),
                 self.client_addr.clone(),
                 self.balancer.clone(),
                 self.forward_addr.clone(),
             );
+            builder.set_proxy_protocol(self.proxy_protocol);
 
             #[cfg(target_os = "macos")]
             if let Some(s) = self.launchd_tcp_socket_name {
@@ -4,13 +4,19 @@ use std::{io, net::SocketAddr, sync::Arc, time::Duration};
 
 use log::{error, info, trace};
 use shadowsocks::{ServerAddr, net::TcpListener as ShadowTcpListener, relay::socks5::Address};
-use tokio::{net::TcpStream, time};
-
-use crate::local::{
-    context::ServiceContext,
-    loadbalancing::PingBalancer,
-    net::{AutoProxyClientStream, tcp::listener::create_standard_tcp_listener},
-    utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
+use tokio::{io::AsyncWriteExt, net::TcpStream, time};
+use tracing::Instrument;
+
+use crate::{
+    local::{
+        context::ServiceContext,
+        loadbalancing::PingBalancer,
+        net::{AutoProxyClientStream, tcp::listener::create_standard_tcp_listener},
+        utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
+    },
+    log_control::connection_span,
+    net::proxy_protocol::make_proxy_header_v2,
+    utils::spawn_cancellable,
 };
 
 pub struct TunnelTcpServerBuilder {
@@ -18,6 +24,7 @@ pub struct TunnelTcpServerBuilder {
     client_config: ServerAddr,
     balancer: PingBalancer,
     forward_addr: Address,
+    proxy_protocol: bool,
     #[cfg(target_os = "macos")]
     launchd_socket_name: Option<String>,
 }
@@ -34,11 +41,17 @@ impl TunnelTcpServerBuilder {
             client_config,
             balancer,
             forward_addr,
+            proxy_protocol: false,
             #[cfg(target_os = "macos")]
             launchd_socket_name: None,
         }
     }
 
+    /// Send a PROXY protocol v2 header to the forward address ahead of every connection
+    pub fn set_proxy_protocol(&mut self, proxy_protocol: bool) {
+        self.proxy_protocol = proxy_protocol;
+    }
+
     /// macOS launchd activate socket
     #[cfg(target_os = "macos")]
     pub fn set_launchd_socket_name(&mut self, n: String) {
@@ -70,6 +83,7 @@ impl TunnelTcpServerBuilder {
             listener,
             balancer: self.balancer,
             forward_addr: self.forward_addr,
+            proxy_protocol: self.proxy_protocol,
         })
     }
 }
@@ -80,6 +94,7 @@ pub struct TunnelTcpServer {
     listener: ShadowTcpListener,
     balancer: PingBalancer,
     forward_addr: Address,
+    proxy_protocol: bool,
 }
 
 impl TunnelTcpServer {
@@ -103,13 +118,18 @@ impl TunnelTcpServer {
                 }
             };
 
-            tokio::spawn(handle_tcp_client(
-                self.context.clone(),
-                stream,
-                self.balancer.clone(),
-                peer_addr,
-                forward_addr.clone(),
-            ));
+            spawn_cancellable(
+                self.context.cancellation_token(),
+                handle_tcp_client(
+                    self.context.clone(),
+                    stream,
+                    self.balancer.clone(),
+                    peer_addr,
+                    forward_addr.clone(),
+                    self.proxy_protocol,
+                )
+                .instrument(connection_span(peer_addr)),
+            );
         }
     }
 }
@@ -120,17 +140,31 @@ async fn handle_tcp_client(
     balancer: PingBalancer,
     peer_addr: SocketAddr,
     forward_addr: Arc<Address>,
+    proxy_protocol: bool,
 ) -> io::Result<()> {
     let forward_addr: &Address = &forward_addr;
 
+    // The backend sees the client connected to this tunnel
+    let proxy_header = if proxy_protocol {
+        Some(make_proxy_header_v2(peer_addr, stream.local_addr()?))
+    } else {
+        None
+    };
+
+    let mut observed = context.observe_connection(peer_addr)?;
+    observed.target_resolved(forward_addr);
+
     if balancer.is_empty() {
         trace!("establishing tcp tunnel {} <-> {} direct", peer_addr, forward_addr);
 
         let mut remote = AutoProxyClientStream::connect_bypassed(context, forward_addr).await?;
-        return establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, forward_addr).await;
+        if let Some(ref header) = proxy_header {
+            remote.write_all(header).await?;
+        }
+        return establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, forward_addr, &mut observed).await;
     }
 
-    let server = balancer.best_tcp_server();
+    let server = balancer.best_tcp_server_for(forward_addr);
     let svr_cfg = server.server_config();
     trace!(
         "establishing tcp tunnel {} <-> {} through sever {} (outbound: {})",
@@ -143,5 +177,16 @@ async fn handle_tcp_client(
     let mut remote =
         AutoProxyClientStream::connect_proxied_with_opts(context, &server, forward_addr, server.connect_opts_ref())
             .await?;
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, forward_addr).await
+    if let Some(ref header) = proxy_header {
+        remote.write_all(header).await?;
+    }
+    establish_tcp_tunnel(
+        svr_cfg,
+        &mut stream,
+        &mut remote,
+        peer_addr,
+        forward_addr,
+        &mut observed,
+    )
+    .await
 }
@@ -1,6 +1,10 @@
 //! Shadowsocks Local Utilities
 
-use std::{io, net::SocketAddr, time::Duration};
+use std::{
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
 use log::{debug, trace};
 use shadowsocks::{
@@ -12,7 +16,16 @@ use tokio::{
     time,
 };
 
-use crate::local::net::AutoProxyIo;
+use crate::{
+    access_log::{self, AccessLogEntry, CloseReason},
+    flow_export::{self, FlowProtocol, FlowRecord, PacketCounter},
+    local::{
+        net::AutoProxyIo,
+        observer::{ConnectionCloseReason, ObservedConnection, ObservedStream},
+    },
+    log_control::{record_server_addr, record_target_addr},
+    metrics_sink::{self, LOCAL_TUNNEL_DURATION, LOCAL_TUNNELS},
+};
 
 pub(crate) async fn establish_tcp_tunnel<P, S>(
     svr_cfg: &ServerConfig,
@@ -20,11 +33,15 @@ pub(crate) async fn establish_tcp_tunnel<P, S>(
     shadow: &mut S,
     peer_addr: SocketAddr,
     target_addr: &Address,
+    observed: &mut ObservedConnection,
 ) -> io::Result<()>
 where
     P: AsyncRead + AsyncWrite + Unpin,
     S: AsyncRead + AsyncWrite + AutoProxyIo + Unpin,
 {
+    record_target_addr(target_addr);
+    record_server_addr(svr_cfg.addr());
+
     if shadow.is_proxied() {
         debug!(
             "established tcp tunnel {} <-> {} through sever {} (outbound: {})",
@@ -34,9 +51,14 @@ where
             svr_cfg.addr(),
         );
     } else {
-        return establish_tcp_tunnel_bypassed(plain, shadow, peer_addr, target_addr).await;
+        return establish_tcp_tunnel_bypassed(plain, shadow, peer_addr, target_addr, observed).await;
     }
 
+    let established = Instant::now();
+    metrics_sink::counter(&LOCAL_TUNNELS, &[("route", "proxied")], 1);
+    observed.established(Some(svr_cfg.addr()));
+    let mut plain = PacketCounter::new(ObservedStream::new(plain, observed));
+
     // https://github.com/shadowsocks/shadowsocks-rust/issues/232
     //
     // Protocols like FTP, clients will wait for servers to send Welcome Message without sending anything.
@@ -44,9 +66,12 @@ where
     // Wait at most 500ms, and then sends handshake packet to remote servers.
     {
         let mut buffer = [0u8; 8192];
-        match time::timeout(Duration::from_millis(500), plain.read(&mut buffer)).await {
+        let first_read = time::timeout(Duration::from_millis(500), plain.read(&mut buffer)).await;
+        match first_read {
             Ok(Ok(0)) => {
                 // EOF. Just terminate right here.
+                drop(plain);
+                observed.closed(ConnectionCloseReason::Eof);
                 return Ok(());
             }
             Ok(Ok(n)) => {
@@ -66,20 +91,59 @@ where
         }
     }
 
-    match copy_encrypted_bidirectional(svr_cfg.method(), shadow, plain).await {
+    let result = copy_encrypted_bidirectional(svr_cfg.method(), shadow, &mut plain).await;
+    let (up_packets, down_packets) = (plain.read_packets(), plain.write_packets());
+    drop(plain);
+    let (up, down, reason) = match result {
         Ok((wn, rn)) => {
             trace!(
                 "tcp tunnel {} <-> {} (proxied) closed, L2R {} bytes, R2L {} bytes",
                 peer_addr, target_addr, rn, wn
             );
+            (rn, wn, CloseReason::Eof)
         }
-        Err(err) => {
+        Err(ref err) => {
             trace!(
                 "tcp tunnel {} <-> {} (proxied) closed with error: {}",
                 peer_addr, target_addr, err
             );
+            (0, 0, CloseReason::Error(err))
         }
-    }
+    };
+
+    access_log::record(|| AccessLogEntry {
+        client: peer_addr,
+        target: target_addr,
+        server: Some(svr_cfg.addr()),
+        up,
+        down,
+        duration: established.elapsed(),
+        reason,
+    });
+
+    observed.closed(match reason {
+        CloseReason::Eof => ConnectionCloseReason::Eof,
+        CloseReason::Error(err) => ConnectionCloseReason::Error(err.to_string()),
+    });
+
+    flow_export::record(|| FlowRecord {
+        protocol: FlowProtocol::Tcp,
+        client: peer_addr,
+        target: target_addr,
+        remote: None,
+        up,
+        up_packets,
+        down,
+        down_packets,
+        duration: established.elapsed(),
+        idle: Duration::ZERO,
+    });
+
+    metrics_sink::histogram(
+        &LOCAL_TUNNEL_DURATION,
+        &[("route", "proxied")],
+        established.elapsed().as_secs_f64(),
+    );
 
     Ok(())
 }
@@ -89,27 +153,72 @@ pub(crate) async fn establish_tcp_tunnel_bypassed<P, S>(
     shadow: &mut S,
     peer_addr: SocketAddr,
     target_addr: &Address,
+    observed: &mut ObservedConnection,
 ) -> io::Result<()>
 where
     P: AsyncRead + AsyncWrite + Unpin,
     S: AsyncRead + AsyncWrite + Unpin,
 {
+    record_target_addr(target_addr);
     debug!("established tcp tunnel {} <-> {} bypassed", peer_addr, target_addr);
 
-    match copy_bidirectional(plain, shadow).await {
+    let established = Instant::now();
+    metrics_sink::counter(&LOCAL_TUNNELS, &[("route", "bypassed")], 1);
+    observed.established(None);
+    let mut plain = PacketCounter::new(ObservedStream::new(plain, observed));
+    let result = copy_bidirectional(&mut plain, shadow).await;
+    let (up_packets, down_packets) = (plain.read_packets(), plain.write_packets());
+    drop(plain);
+    let (up, down, reason) = match result {
         Ok((rn, wn)) => {
             trace!(
                 "tcp tunnel {} <-> {} (bypassed) closed, L2R {} bytes, R2L {} bytes",
                 peer_addr, target_addr, rn, wn
             );
+            (rn, wn, CloseReason::Eof)
         }
-        Err(err) => {
+        Err(ref err) => {
             trace!(
                 "tcp tunnel {} <-> {} (bypassed) closed with error: {}",
                 peer_addr, target_addr, err
             );
+            (0, 0, CloseReason::Error(err))
         }
-    }
+    };
+
+    access_log::record(|| AccessLogEntry {
+        client: peer_addr,
+        target: target_addr,
+        server: None,
+        up,
+        down,
+        duration: established.elapsed(),
+        reason,
+    });
+
+    observed.closed(match reason {
+        CloseReason::Eof => ConnectionCloseReason::Eof,
+        CloseReason::Error(err) => ConnectionCloseReason::Error(err.to_string()),
+    });
+
+    flow_export::record(|| FlowRecord {
+        protocol: FlowProtocol::Tcp,
+        client: peer_addr,
+        target: target_addr,
+        remote: None,
+        up,
+        up_packets,
+        down,
+        down_packets,
+        duration: established.elapsed(),
+        idle: Duration::ZERO,
+    });
+
+    metrics_sink::histogram(
+        &LOCAL_TUNNEL_DURATION,
+        &[("route", "bypassed")],
+        established.elapsed().as_secs_f64(),
+    );
 
     Ok(())
 }
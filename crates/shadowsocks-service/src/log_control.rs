@@ -0,0 +1,78 @@
+//! Runtime log filter control
+//!
+//! Logging facilities are initialized by binaries, which could register a [`LogFilterControl`] here,
+//! so the log filter could be changed at runtime by services, for example, the HTTP admin API of manager.
+//!
+//! Connections and UDP associations are handled in spans created by [`connection_span`] and [`udp_association_span`],
+//! so all logs of one session could be correlated by structured logging or tracing exporters.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use once_cell::sync::OnceCell;
+
+/// Controller of the effective log filter
+pub trait LogFilterControl: Send + Sync {
+    /// Current filter directives, like `warn,shadowsocks=info`
+    fn current(&self) -> String;
+
+    /// Replace the filter with `directives`, like `info,shadowsocks::relay::udprelay=trace`
+    fn set(&self, directives: &str) -> io::Result<()>;
+}
+
+static CONTROL: OnceCell<Box<dyn LogFilterControl>> = OnceCell::new();
+
+/// Register the global log filter controller, only the first call takes effect
+pub fn set_log_filter_control<C: LogFilterControl + 'static>(control: C) {
+    let _ = CONTROL.set(Box::new(control));
+}
+
+/// Get the global log filter controller, `None` if the logging facility doesn't support it
+pub fn log_filter_control() -> Option<&'static dyn LogFilterControl> {
+    CONTROL.get().map(|c| c.as_ref())
+}
+
+/// Name of spans created by [`connection_span`] and [`udp_association_span`]
+pub const CONNECTION_SPAN_NAME: &str = "connection";
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+fn new_session_span(protocol: &'static str, peer_addr: SocketAddr) -> tracing::Span {
+    let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    tracing::info_span!(
+        "connection",
+        id,
+        protocol,
+        peer = %peer_addr,
+        target_addr = tracing::field::Empty,
+        server_addr = tracing::field::Empty,
+    )
+}
+
+/// Create a span for a TCP connection accepted from `peer_addr`
+///
+/// The span has fields `id`, `protocol`, `peer`, `target_addr` and `server_addr`.
+/// `target_addr` should be recorded after handshake, and `server_addr` after a server is chosen by local.
+pub fn connection_span(peer_addr: SocketAddr) -> tracing::Span {
+    new_session_span("tcp", peer_addr)
+}
+
+/// Create a span for a UDP association of `peer_addr`, with the same fields as [`connection_span`]
+///
+/// `target_addr` is the target of the first packet, and `server_addr` is the latest server of the association.
+pub fn udp_association_span(peer_addr: SocketAddr) -> tracing::Span {
+    new_session_span("udp", peer_addr)
+}
+
+/// Record target address of the connection handled in the current span
+pub fn record_target_addr<A: std::fmt::Display>(target_addr: A) {
+    tracing::Span::current().record("target_addr", tracing::field::display(target_addr));
+}
+
+/// Record the server chosen for the connection handled in the current span
+pub fn record_server_addr<A: std::fmt::Display>(server_addr: A) {
+    tracing::Span::current().record("server_addr", tracing::field::display(server_addr));
+}
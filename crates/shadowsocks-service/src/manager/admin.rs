@@ -0,0 +1,323 @@
+//! HTTP admin API of Manager
+//!
+//! | Method   | Path                            | Description                             |
+//! |----------|---------------------------------|-----------------------------------------|
+//! | `GET`    | `/servers`                      | List all servers                        |
+//! | `POST`   | `/servers`                      | Add a server, body is the same as `add` |
+//! | `DELETE` | `/servers/{port}`               | Remove the server listening on `port`   |
+//! | `POST`   | `/servers/{port}/quota/reset`   | Reset traffic quota of the server       |
+//! | `GET`    | `/servers/{port}/sessions`      | Live TCP sessions of the server         |
+//! | `DELETE` | `/servers/{port}/sessions/{id}` | Terminate a session                     |
+//! | `DELETE` | `/clients/{ip}/sessions`        | Terminate all sessions of a client IP   |
+//! | `GET`    | `/stats`                        | Traffic statistic of each server        |
+//! | `GET`    | `/users`                        | Traffic statistic of each user (EIH)    |
+//! | `GET`    | `/snapshot`                     | Aggregated statistics of this process   |
+//! | `GET`    | `/connections`                  | Active connections of each server       |
+//! | `GET`    | `/log`                          | Current log filter                      |
+//! | `PUT`    | `/log`                          | Replace log filter, `{"filter": "..."}` |
+//! | `POST`   | `/reload`                       | Reload ACL file into running servers    |
+//! | `POST`   | `/shutdown`                     | Close all servers and stop the manager  |
+//!
+//! Requests must carry `Authorization: Bearer <token>` if `token` is configured.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::UNIX_EPOCH,
+};
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{Method, Request, Response, StatusCode, body::Incoming};
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::{Value as JsonValue, json};
+use shadowsocks::{
+    manager::protocol::{AddRequest, RemoveRequest},
+    net::TcpListener,
+};
+
+use crate::{
+    admin::{
+        self,
+        http::{make_error, make_json, read_body},
+    },
+    config::ManagerAdminConfig,
+    log_control, stats,
+};
+
+use super::server::{Manager, ServerInstanceMode};
+
+#[derive(Deserialize)]
+struct LogFilterRequest {
+    filter: String,
+}
+
+/// Serve the HTTP admin API until error occurs
+pub(super) async fn serve(manager: &Manager, config: &ManagerAdminConfig) -> io::Result<()> {
+    let listener = TcpListener::bind_with_opts(&config.addr, Default::default()).await?;
+    info!(
+        "shadowsocks manager admin API listening on {}",
+        listener.local_addr().expect("listener.local_addr")
+    );
+
+    if config.token.is_none() {
+        warn!("manager admin API is not protected by token, anyone who can access it could control the manager");
+    }
+
+    let token = config.token.as_deref();
+    admin::http::serve("manager admin", listener, token, move |req, peer_addr| {
+        handle_request(manager, peer_addr, req)
+    })
+    .await
+}
+
+async fn handle_request(manager: &Manager, peer_addr: SocketAddr, req: Request<Incoming>) -> Response<Full<Bytes>> {
+    let method = req.method().clone();
+    let path = req.uri().path().trim_end_matches('/').to_owned();
+    let segments = path.split('/').skip(1).collect::<Vec<_>>();
+
+    match (method, segments.as_slice()) {
+        (Method::GET, ["servers"]) => {
+            let rsp = manager.handle_list().await;
+            make_json(StatusCode::OK, &json!(rsp.servers))
+        }
+        (Method::POST, ["servers"]) => {
+            let body = match read_body(req).await {
+                Ok(b) => b,
+                Err(rsp) => return rsp,
+            };
+
+            let add_req = match serde_json::from_slice::<AddRequest>(&body) {
+                Ok(r) => r,
+                Err(err) => return make_error(StatusCode::BAD_REQUEST, &err.to_string()),
+            };
+
+            match manager.handle_add(&add_req).await {
+                Ok(rsp) if rsp.0 == "ok" => make_json(StatusCode::OK, &json!({ "server_port": add_req.server_port })),
+                Ok(rsp) => make_error(StatusCode::BAD_REQUEST, &rsp.0),
+                Err(err) => {
+                    error!("add server_port: {} failed, error: {}", add_req.server_port, err);
+                    make_error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())
+                }
+            }
+        }
+        (Method::DELETE, ["servers", port]) => {
+            let server_port = match port.parse::<u16>() {
+                Ok(p) => p,
+                Err(..) => return make_error(StatusCode::BAD_REQUEST, "invalid port"),
+            };
+
+            if !manager.servers.lock().await.contains_key(&server_port) {
+                return make_error(StatusCode::NOT_FOUND, "server not found");
+            }
+
+            manager.handle_remove(&RemoveRequest { server_port }).await;
+            make_json(StatusCode::OK, &json!({ "server_port": server_port }))
+        }
+        (Method::POST, ["servers", port, "quota", "reset"]) => {
+            let server_port = match port.parse::<u16>() {
+                Ok(p) => p,
+                Err(..) => return make_error(StatusCode::BAD_REQUEST, "invalid port"),
+            };
+
+            match manager.reset_quota(server_port).await {
+                Ok(true) => make_json(StatusCode::OK, &json!({ "server_port": server_port })),
+                Ok(false) => make_error(StatusCode::NOT_FOUND, "server not found"),
+                Err(err) => {
+                    error!("reset quota of server_port: {} failed, error: {}", server_port, err);
+                    make_error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())
+                }
+            }
+        }
+        (Method::GET, ["servers", port, "sessions"]) => {
+            let server_port = match port.parse::<u16>() {
+                Ok(p) => p,
+                Err(..) => return make_error(StatusCode::BAD_REQUEST, "invalid port"),
+            };
+
+            let sessions = match manager.server_sessions(server_port).await {
+                Some(s) => s,
+                None => return make_error(StatusCode::NOT_FOUND, "server not found"),
+            };
+
+            let list = sessions
+                .list()
+                .into_iter()
+                .map(|s| {
+                    json!({
+                        "id": s.id,
+                        "peer_addr": s.peer_addr.to_string(),
+                        "target": s.target,
+                        "user": s.user,
+                        "established": s.established.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+                        "tx": s.tx,
+                        "rx": s.rx,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            make_json(StatusCode::OK, &JsonValue::Array(list))
+        }
+        (Method::DELETE, ["servers", port, "sessions", id]) => {
+            let server_port = match port.parse::<u16>() {
+                Ok(p) => p,
+                Err(..) => return make_error(StatusCode::BAD_REQUEST, "invalid port"),
+            };
+            let id = match id.parse::<u64>() {
+                Ok(i) => i,
+                Err(..) => return make_error(StatusCode::BAD_REQUEST, "invalid session id"),
+            };
+
+            match manager.server_sessions(server_port).await {
+                Some(sessions) if sessions.kill(id) => {
+                    info!(
+                        "manager admin killed session {} of server_port: {} from {}",
+                        id, server_port, peer_addr
+                    );
+                    make_json(StatusCode::OK, &json!({ "id": id }))
+                }
+                Some(..) => make_error(StatusCode::NOT_FOUND, "session not found"),
+                None => make_error(StatusCode::NOT_FOUND, "server not found"),
+            }
+        }
+        (Method::DELETE, ["clients", ip, "sessions"]) => {
+            let ip = match ip.parse::<IpAddr>() {
+                Ok(i) => i,
+                Err(..) => return make_error(StatusCode::BAD_REQUEST, "invalid ip"),
+            };
+
+            let killed = manager.kill_client_sessions(ip).await;
+            make_json(StatusCode::OK, &json!({ "killed": killed }))
+        }
+        (Method::GET, ["stats"]) => {
+            let servers = manager.servers.lock().await;
+
+            let mut stats = serde_json::Map::new();
+            for (port, server) in servers.iter() {
+                let stat = match server.mode {
+                    ServerInstanceMode::Builtin { ref flow_stat, .. } => json!({
+                        "tx": flow_stat.tx(),
+                        "rx": flow_stat.rx(),
+                        "total": server.flow_stat(),
+                    }),
+                    #[cfg(unix)]
+                    ServerInstanceMode::Standalone { .. } => json!({ "total": server.flow_stat() }),
+                };
+                stats.insert(port.to_string(), stat);
+            }
+
+            make_json(StatusCode::OK, &JsonValue::Object(stats))
+        }
+        (Method::GET, ["users"]) => {
+            let servers = manager.servers.lock().await;
+
+            let mut stats = serde_json::Map::new();
+            for (port, server) in servers.iter() {
+                let mut users = serde_json::Map::new();
+                match server.mode {
+                    ServerInstanceMode::Builtin { ref accounting, .. } => {
+                        for (name, traffic) in accounting.current().users {
+                            users.insert(
+                                name,
+                                json!({
+                                    "tx": traffic.tx,
+                                    "rx": traffic.rx,
+                                    "total": traffic.total(),
+                                }),
+                            );
+                        }
+                    }
+                    #[cfg(unix)]
+                    ServerInstanceMode::Standalone { .. } => {
+                        for (name, total) in server.user_stat() {
+                            users.insert(name, json!({ "total": total }));
+                        }
+                    }
+                }
+                stats.insert(port.to_string(), JsonValue::Object(users));
+            }
+
+            make_json(StatusCode::OK, &JsonValue::Object(stats))
+        }
+        (Method::GET, ["connections"]) => {
+            let servers = manager.servers.lock().await;
+
+            let mut connections = serde_json::Map::new();
+            for (port, server) in servers.iter() {
+                let conn = match server.mode {
+                    ServerInstanceMode::Builtin {
+                        ref connection_stat, ..
+                    } => json!({ "tcp": connection_stat.tcp_connections() }),
+                    // Standalone servers don't report their connections
+                    #[cfg(unix)]
+                    ServerInstanceMode::Standalone { .. } => JsonValue::Null,
+                };
+                connections.insert(port.to_string(), conn);
+            }
+
+            make_json(StatusCode::OK, &JsonValue::Object(connections))
+        }
+        (Method::GET, ["snapshot"]) => make_json(StatusCode::OK, &json!(stats::snapshot())),
+        (Method::GET, ["log"]) => match log_control::log_filter_control() {
+            Some(control) => make_json(StatusCode::OK, &json!({ "filter": control.current() })),
+            None => make_error(StatusCode::NOT_IMPLEMENTED, "log filter control is not supported"),
+        },
+        (Method::PUT, ["log"]) => {
+            let control = match log_control::log_filter_control() {
+                Some(c) => c,
+                None => return make_error(StatusCode::NOT_IMPLEMENTED, "log filter control is not supported"),
+            };
+
+            let body = match read_body(req).await {
+                Ok(b) => b,
+                Err(rsp) => return rsp,
+            };
+
+            let log_req = match serde_json::from_slice::<LogFilterRequest>(&body) {
+                Ok(r) => r,
+                Err(err) => return make_error(StatusCode::BAD_REQUEST, &err.to_string()),
+            };
+
+            match control.set(&log_req.filter) {
+                Ok(..) => {
+                    info!(
+                        "manager admin changed log filter to \"{}\" from {}",
+                        log_req.filter, peer_addr
+                    );
+                    make_json(StatusCode::OK, &json!({ "filter": control.current() }))
+                }
+                Err(err) => make_error(StatusCode::BAD_REQUEST, &err.to_string()),
+            }
+        }
+        (Method::POST, ["reload"]) => match manager.reload_acl().await {
+            Ok(..) => make_json(StatusCode::OK, &json!({})),
+            Err(err) => {
+                error!("manager admin reload failed, error: {}", err);
+                make_error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())
+            }
+        },
+        (Method::POST, ["shutdown"]) => {
+            info!("manager admin requested shutdown from {}", peer_addr);
+            manager.shutdown();
+            make_json(StatusCode::OK, &json!({}))
+        }
+        (
+            _,
+            ["servers"]
+            | ["servers", _]
+            | ["servers", _, "quota", "reset"]
+            | ["servers", _, "sessions"]
+            | ["servers", _, "sessions", _]
+            | ["clients", _, "sessions"]
+            | ["stats"]
+            | ["users"]
+            | ["connections"]
+            | ["snapshot"]
+            | ["log"]
+            | ["reload"]
+            | ["shutdown"],
+        ) => make_error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+        _ => make_error(StatusCode::NOT_FOUND, "not found"),
+    }
+}
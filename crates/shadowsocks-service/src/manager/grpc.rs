@@ -0,0 +1,491 @@
+//! gRPC management API of Manager
+//!
+//! Service definition is in `proto/manager.proto`. Operations are the same as the HTTP admin API,
+//! with an extra server-streaming `Events` RPC that carries events of servers in real time.
+//!
+//! Requests must carry `authorization: Bearer <token>` metadata if `token` is configured.
+
+use std::{
+    io::{self, ErrorKind},
+    net::IpAddr,
+    pin::Pin,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::{Stream, StreamExt};
+use log::{error, info, warn};
+use shadowsocks::manager::protocol::{self, RemoveRequest};
+use tokio_stream::wrappers::{BroadcastStream, errors::BroadcastStreamRecvError};
+use tonic::{Request, Response, Status, metadata::MetadataMap, transport::Server};
+
+use crate::{
+    admin::check_bearer_token,
+    config::ManagerGrpcConfig,
+    events::{self, Event},
+    log_control,
+};
+
+use super::server::{Manager, ServerInstanceMode};
+
+#[allow(clippy::all)]
+mod proto {
+    tonic::include_proto!("shadowsocks.manager");
+}
+
+use self::proto::{event::Event as EventKind, manager_server::ManagerServer};
+
+/// Serve the gRPC API until error occurs
+pub(super) async fn serve(manager: Arc<Manager>, config: &ManagerGrpcConfig) -> io::Result<()> {
+    info!("shadowsocks manager gRPC API listening on {}", config.addr);
+
+    let token = config.token.clone();
+    if token.is_none() {
+        warn!("manager gRPC API is not protected by token, anyone who can access it could control the manager");
+    }
+
+    let service = ManagerServer::with_interceptor(GrpcService { manager }, move |req: Request<()>| match token {
+        Some(ref token) if !check_authorization(req.metadata(), token) => Err(Status::unauthenticated("unauthorized")),
+        _ => Ok(req),
+    });
+
+    Server::builder()
+        .add_service(service)
+        .serve(config.addr)
+        .await
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))
+}
+
+fn check_authorization(metadata: &MetadataMap, token: &str) -> bool {
+    let value = metadata.get("authorization").and_then(|v| v.to_str().ok());
+    check_bearer_token(value, token)
+}
+
+fn parse_port(port: u32) -> Result<u16, Status> {
+    u16::try_from(port).map_err(|_| Status::invalid_argument("invalid port"))
+}
+
+struct GrpcService {
+    manager: Arc<Manager>,
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<proto::Event, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl proto::manager_server::Manager for GrpcService {
+    type EventsStream = EventStream;
+
+    async fn list_servers(
+        &self,
+        _: Request<proto::ListServersRequest>,
+    ) -> Result<Response<proto::ListServersResponse>, Status> {
+        let rsp = self.manager.handle_list().await;
+        let servers = rsp.servers.into_iter().map(proto::ServerConfig::from).collect();
+        Ok(Response::new(proto::ListServersResponse { servers }))
+    }
+
+    async fn add_server(
+        &self,
+        req: Request<proto::ServerConfig>,
+    ) -> Result<Response<proto::AddServerResponse>, Status> {
+        let add_req = protocol::ServerConfig::try_from(req.into_inner())?;
+
+        match self.manager.handle_add(&add_req).await {
+            Ok(rsp) if rsp.0 == "ok" => Ok(Response::new(proto::AddServerResponse {})),
+            Ok(rsp) => Err(Status::invalid_argument(rsp.0)),
+            Err(err) => {
+                error!("add server_port: {} failed, error: {}", add_req.server_port, err);
+                Err(Status::internal(err.to_string()))
+            }
+        }
+    }
+
+    async fn remove_server(
+        &self,
+        req: Request<proto::RemoveServerRequest>,
+    ) -> Result<Response<proto::RemoveServerResponse>, Status> {
+        let server_port = parse_port(req.into_inner().server_port)?;
+
+        if !self.manager.servers.lock().await.contains_key(&server_port) {
+            return Err(Status::not_found("server not found"));
+        }
+
+        self.manager.handle_remove(&RemoveRequest { server_port }).await;
+        Ok(Response::new(proto::RemoveServerResponse {}))
+    }
+
+    async fn list_sessions(
+        &self,
+        req: Request<proto::ListSessionsRequest>,
+    ) -> Result<Response<proto::ListSessionsResponse>, Status> {
+        let server_port = parse_port(req.into_inner().server_port)?;
+
+        let sessions = match self.manager.server_sessions(server_port).await {
+            Some(s) => s,
+            None => return Err(Status::not_found("server not found")),
+        };
+
+        let sessions = sessions
+            .list()
+            .into_iter()
+            .map(|s| proto::Session {
+                id: s.id,
+                peer_addr: s.peer_addr.to_string(),
+                target: s.target,
+                user: s.user,
+                established: s
+                    .established
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+                tx: s.tx,
+                rx: s.rx,
+            })
+            .collect();
+
+        Ok(Response::new(proto::ListSessionsResponse { sessions }))
+    }
+
+    async fn kill_session(
+        &self,
+        req: Request<proto::KillSessionRequest>,
+    ) -> Result<Response<proto::KillSessionResponse>, Status> {
+        let req = req.into_inner();
+        let server_port = parse_port(req.server_port)?;
+
+        match self.manager.server_sessions(server_port).await {
+            Some(sessions) if sessions.kill(req.id) => {
+                info!("manager gRPC killed session {} of server_port: {}", req.id, server_port);
+                Ok(Response::new(proto::KillSessionResponse {}))
+            }
+            Some(..) => Err(Status::not_found("session not found")),
+            None => Err(Status::not_found("server not found")),
+        }
+    }
+
+    async fn kill_client_sessions(
+        &self,
+        req: Request<proto::KillClientSessionsRequest>,
+    ) -> Result<Response<proto::KillClientSessionsResponse>, Status> {
+        let ip = match req.into_inner().ip.parse::<IpAddr>() {
+            Ok(i) => i,
+            Err(..) => return Err(Status::invalid_argument("invalid ip")),
+        };
+
+        let killed = self.manager.kill_client_sessions(ip).await;
+        Ok(Response::new(proto::KillClientSessionsResponse {
+            killed: killed as u64,
+        }))
+    }
+
+    async fn get_stats(&self, _: Request<proto::GetStatsRequest>) -> Result<Response<proto::GetStatsResponse>, Status> {
+        let servers = self.manager.servers.lock().await;
+
+        let mut stats = Vec::with_capacity(servers.len());
+        for (port, server) in servers.iter() {
+            let (tx, rx) = match server.mode {
+                ServerInstanceMode::Builtin { ref flow_stat, .. } => (Some(flow_stat.tx()), Some(flow_stat.rx())),
+                #[cfg(unix)]
+                ServerInstanceMode::Standalone { .. } => (None, None),
+            };
+
+            stats.push(proto::ServerStat {
+                server_port: u32::from(*port),
+                tx,
+                rx,
+                total: server.flow_stat(),
+            });
+        }
+
+        Ok(Response::new(proto::GetStatsResponse { servers: stats }))
+    }
+
+    async fn get_users(&self, _: Request<proto::GetUsersRequest>) -> Result<Response<proto::GetUsersResponse>, Status> {
+        let servers = self.manager.servers.lock().await;
+
+        let mut users = Vec::new();
+        for (port, server) in servers.iter() {
+            match server.mode {
+                ServerInstanceMode::Builtin { ref accounting, .. } => {
+                    for (name, traffic) in accounting.current().users {
+                        users.push(proto::UserStat {
+                            server_port: u32::from(*port),
+                            name,
+                            tx: Some(traffic.tx),
+                            rx: Some(traffic.rx),
+                            total: traffic.total(),
+                        });
+                    }
+                }
+                #[cfg(unix)]
+                ServerInstanceMode::Standalone { .. } => {
+                    for (name, total) in server.user_stat() {
+                        users.push(proto::UserStat {
+                            server_port: u32::from(*port),
+                            name,
+                            tx: None,
+                            rx: None,
+                            total,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(Response::new(proto::GetUsersResponse { users }))
+    }
+
+    async fn get_connections(
+        &self,
+        _: Request<proto::GetConnectionsRequest>,
+    ) -> Result<Response<proto::GetConnectionsResponse>, Status> {
+        let servers = self.manager.servers.lock().await;
+
+        let mut connections = Vec::with_capacity(servers.len());
+        for (port, server) in servers.iter() {
+            let (tcp, udp) = match server.mode {
+                ServerInstanceMode::Builtin {
+                    ref connection_stat, ..
+                } => (
+                    Some(connection_stat.tcp_connections() as u64),
+                    Some(connection_stat.udp_associations() as u64),
+                ),
+                // Standalone servers don't report their connections
+                #[cfg(unix)]
+                ServerInstanceMode::Standalone { .. } => (None, None),
+            };
+
+            connections.push(proto::ServerConnections {
+                server_port: u32::from(*port),
+                tcp,
+                udp,
+            });
+        }
+
+        Ok(Response::new(proto::GetConnectionsResponse { servers: connections }))
+    }
+
+    async fn reset_quota(
+        &self,
+        req: Request<proto::ResetQuotaRequest>,
+    ) -> Result<Response<proto::ResetQuotaResponse>, Status> {
+        let server_port = parse_port(req.into_inner().server_port)?;
+
+        match self.manager.reset_quota(server_port).await {
+            Ok(true) => Ok(Response::new(proto::ResetQuotaResponse {})),
+            Ok(false) => Err(Status::not_found("server not found")),
+            Err(err) => {
+                error!("reset quota of server_port: {} failed, error: {}", server_port, err);
+                Err(Status::internal(err.to_string()))
+            }
+        }
+    }
+
+    async fn get_log_filter(
+        &self,
+        _: Request<proto::GetLogFilterRequest>,
+    ) -> Result<Response<proto::LogFilter>, Status> {
+        match log_control::log_filter_control() {
+            Some(control) => Ok(Response::new(proto::LogFilter {
+                filter: control.current(),
+            })),
+            None => Err(Status::unimplemented("log filter control is not supported")),
+        }
+    }
+
+    async fn set_log_filter(&self, req: Request<proto::LogFilter>) -> Result<Response<proto::LogFilter>, Status> {
+        let control = match log_control::log_filter_control() {
+            Some(c) => c,
+            None => return Err(Status::unimplemented("log filter control is not supported")),
+        };
+
+        let filter = req.into_inner().filter;
+        match control.set(&filter) {
+            Ok(..) => {
+                info!("manager gRPC changed log filter to \"{}\"", filter);
+                Ok(Response::new(proto::LogFilter {
+                    filter: control.current(),
+                }))
+            }
+            Err(err) => Err(Status::invalid_argument(err.to_string())),
+        }
+    }
+
+    async fn reload(&self, _: Request<proto::ReloadRequest>) -> Result<Response<proto::ReloadResponse>, Status> {
+        match self.manager.reload_acl().await {
+            Ok(..) => Ok(Response::new(proto::ReloadResponse {})),
+            Err(err) => {
+                error!("manager gRPC reload failed, error: {}", err);
+                Err(Status::internal(err.to_string()))
+            }
+        }
+    }
+
+    async fn shutdown(
+        &self,
+        req: Request<proto::ShutdownRequest>,
+    ) -> Result<Response<proto::ShutdownResponse>, Status> {
+        match req.remote_addr() {
+            Some(addr) => info!("manager gRPC requested shutdown from {}", addr),
+            None => info!("manager gRPC requested shutdown"),
+        }
+        self.manager.shutdown();
+        Ok(Response::new(proto::ShutdownResponse {}))
+    }
+
+    async fn events(&self, _: Request<proto::EventsRequest>) -> Result<Response<Self::EventsStream>, Status> {
+        let stream = BroadcastStream::new(events::subscribe()).map(|r| {
+            let event = match r {
+                Ok(event) => EventKind::from(event),
+                Err(BroadcastStreamRecvError::Lagged(n)) => EventKind::Lagged(n),
+            };
+
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0);
+
+            Ok(proto::Event {
+                timestamp,
+                event: Some(event),
+            })
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+impl From<protocol::ServerConfig> for proto::ServerConfig {
+    fn from(c: protocol::ServerConfig) -> proto::ServerConfig {
+        proto::ServerConfig {
+            server_port: u32::from(c.server_port),
+            password: c.password,
+            method: c.method,
+            plugin: c.plugin,
+            plugin_opts: c.plugin_opts,
+            plugin_mode: c.plugin_mode,
+            mode: c.mode,
+            users: c
+                .users
+                .unwrap_or_default()
+                .into_iter()
+                .map(|u| proto::ServerUserConfig {
+                    name: u.name,
+                    password: u.password,
+                    quota: u.quota,
+                })
+                .collect(),
+            quota: c.quota,
+            quota_terminate: c.quota_terminate,
+            extra_ports: c.extra_ports.unwrap_or_default().into_iter().map(u32::from).collect(),
+        }
+    }
+}
+
+impl TryFrom<proto::ServerConfig> for protocol::ServerConfig {
+    type Error = Status;
+
+    fn try_from(c: proto::ServerConfig) -> Result<protocol::ServerConfig, Status> {
+        Ok(protocol::ServerConfig {
+            server_port: parse_port(c.server_port)?,
+            password: c.password,
+            method: c.method,
+            no_delay: None,
+            plugin: c.plugin,
+            plugin_opts: c.plugin_opts,
+            plugin_mode: c.plugin_mode,
+            mode: c.mode,
+            users: if c.users.is_empty() {
+                None
+            } else {
+                Some(
+                    c.users
+                        .into_iter()
+                        .map(|u| protocol::ServerUserConfig {
+                            name: u.name,
+                            password: u.password,
+                            quota: u.quota,
+                        })
+                        .collect(),
+                )
+            },
+            quota: c.quota,
+            quota_terminate: c.quota_terminate,
+            extra_ports: if c.extra_ports.is_empty() {
+                None
+            } else {
+                Some(c.extra_ports.into_iter().map(parse_port).collect::<Result<_, _>>()?)
+            },
+        })
+    }
+}
+
+impl From<Event> for EventKind {
+    fn from(event: Event) -> EventKind {
+        match event {
+            Event::ServerStarted { server } => EventKind::ServerStarted(proto::ServerStarted { server }),
+            Event::ServerStopped { server } => EventKind::ServerStopped(proto::ServerStopped { server }),
+            Event::ServerAdded { server_port } => EventKind::ServerAdded(proto::ServerAdded {
+                server_port: u32::from(server_port),
+            }),
+            Event::ServerRemoved { server_port } => EventKind::ServerRemoved(proto::ServerRemoved {
+                server_port: u32::from(server_port),
+            }),
+            Event::ConnectionOpened {
+                server,
+                peer_addr,
+                target,
+            } => EventKind::ConnectionOpened(proto::ConnectionOpened {
+                server,
+                peer_addr: peer_addr.to_string(),
+                target,
+            }),
+            Event::ConnectionClosed {
+                server,
+                peer_addr,
+                target,
+                rx,
+                tx,
+            } => EventKind::ConnectionClosed(proto::ConnectionClosed {
+                server,
+                peer_addr: peer_addr.to_string(),
+                target,
+                rx,
+                tx,
+            }),
+            Event::ReplayDetected { server, count, total } => {
+                EventKind::ReplayDetected(proto::ReplayDetected { server, count, total })
+            }
+            Event::QuotaExceeded { server, user } => EventKind::QuotaExceeded(proto::QuotaExceeded { server, user }),
+            Event::HandshakeFailures { server, peer_ip, count } => {
+                EventKind::HandshakeFailures(proto::HandshakeFailures {
+                    server,
+                    peer_ip: peer_ip.to_string(),
+                    count,
+                })
+            }
+            Event::ClientBanned {
+                server,
+                peer_ip,
+                duration,
+            } => EventKind::ClientBanned(proto::ClientBanned {
+                server,
+                peer_ip: peer_ip.to_string(),
+                duration,
+            }),
+            Event::ReplaySource { server, peer_ip, count } => EventKind::ReplaySource(proto::ReplaySource {
+                server,
+                peer_ip: peer_ip.to_string(),
+                count,
+            }),
+            Event::PluginExited { server, plugin, status } => {
+                EventKind::PluginExited(proto::PluginExited { server, plugin, status })
+            }
+            Event::BalancerSwitched { protocol, from, to } => EventKind::BalancerSwitched(proto::BalancerSwitched {
+                protocol: protocol.to_owned(),
+                from,
+                to,
+            }),
+        }
+    }
+}
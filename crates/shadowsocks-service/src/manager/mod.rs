@@ -4,25 +4,41 @@
 
 use std::{io, net::SocketAddr, sync::Arc};
 
-use log::trace;
-use shadowsocks::net::{AcceptOpts, ConnectOpts};
+use log::{error, trace};
+use shadowsocks::{
+    net::{AcceptOpts, ConnectOpts},
+    relay::tcprelay::utils::set_plain_read_buffer_size_limit,
+};
 
 use crate::{
     config::{Config, ConfigType},
     dns::build_dns_resolver,
-    server::SERVER_DEFAULT_KEEPALIVE_TIMEOUT,
+    server::{SERVER_DEFAULT_KEEPALIVE_TIMEOUT, allow_list::ClientAllowListMatcher},
 };
 
 pub use self::server::{Manager, ManagerBuilder};
 
+#[cfg(feature = "manager-admin")]
+mod admin;
+#[cfg(feature = "manager-grpc")]
+mod grpc;
 pub mod server;
 
 /// Starts a manager server
-pub async fn run(config: Config) -> io::Result<()> {
+pub async fn run(mut config: Config) -> io::Result<()> {
     assert_eq!(config.config_type, ConfigType::Manager);
 
+    config.apply_memory_profile();
     trace!("{:?}", config);
 
+    if let Some(size) = config.relay_buffer_size {
+        set_plain_read_buffer_size_limit(Some(size));
+    }
+
+    // Plugins are started while servers are built, before the process drops its own privileges
+    #[cfg(unix)]
+    crate::privilege::drop_plugin_privileges(config.user.as_deref(), config.group.as_deref())?;
+
     #[cfg(all(unix, not(target_os = "android")))]
     if let Some(nofile) = config.nofile {
         use crate::sys::set_nofile;
@@ -31,7 +47,15 @@ pub async fn run(config: Config) -> io::Result<()> {
         }
     }
 
+    if let Some(ref access_log) = config.access_log {
+        crate::access_log::init(access_log)?;
+    }
+    if let Some(ref flow_export) = config.flow_export {
+        crate::flow_export::init(flow_export)?;
+    }
+
     let mut manager_builder = ManagerBuilder::new(config.manager.expect("missing manager config"));
+    manager_builder.set_cancellation_token(config.cancellation_token.clone());
 
     let mut connect_opts = ConnectOpts {
         #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -40,8 +64,14 @@ pub async fn run(config: Config) -> io::Result<()> {
         #[cfg(target_os = "android")]
         vpn_protect_path: config.outbound_vpn_protect_path,
 
+        #[cfg(all(any(target_os = "linux", target_os = "android"), feature = "socket-protect"))]
+        socket_protect: config.outbound_socket_protect,
+
+        connector: config.outbound_connector,
+
         bind_local_addr: config.outbound_bind_addr.map(|ip| SocketAddr::new(ip, 0)),
         bind_interface: config.outbound_bind_interface,
+        dscp: config.outbound_dscp,
 
         ..Default::default()
     };
@@ -54,6 +84,10 @@ pub async fn run(config: Config) -> io::Result<()> {
     connect_opts.tcp.mptcp = config.mptcp;
     connect_opts.udp.mtu = config.udp_mtu;
     connect_opts.udp.allow_fragmentation = config.outbound_udp_allow_fragmentation;
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    {
+        connect_opts.uring = crate::net::utils::create_uring_driver();
+    }
 
     let mut accept_opts = AcceptOpts {
         ipv6_only: config.ipv6_only,
@@ -89,10 +123,53 @@ pub async fn run(config: Config) -> io::Result<()> {
         manager_builder.set_acl(Arc::new(acl));
     }
 
+    if let Some(ref allow_list) = config.client_allow_list {
+        manager_builder.set_client_allow_list(Arc::new(ClientAllowListMatcher::load(allow_list).await?));
+    }
+
+    manager_builder.set_proxy_protocol(config.proxy_protocol);
+    manager_builder.set_security_config(config.security);
+
+    if let Some(entries) = config.replay_filter_capacity {
+        manager_builder.set_replay_filter_capacity(entries);
+    }
+
+    // Subscribe before servers are added, so their events won't be missed
+    #[cfg(feature = "webhook")]
+    let _webhooks = if config.webhooks.is_empty() {
+        None
+    } else {
+        let webhooks = crate::webhook::WebhookDispatcher::new(config.webhooks);
+        Some(crate::utils::ServerHandle(tokio::spawn(webhooks.run())))
+    };
+
     let manager = manager_builder.build().await?;
 
     for svr_inst in config.server {
-        manager.add_server(svr_inst.config).await;
+        let addr = svr_inst.config.addr().clone();
+        if let Err(err) = manager.add_server_with_quota(svr_inst.config, svr_inst.quota).await {
+            error!("failed to add server {}, error: {}", addr, err);
+        }
+    }
+
+    // Manager and servers are listening, admin APIs are bound when manager starts serving. Plugins started by then
+    // have switched users by `drop_plugin_privileges`
+    #[cfg(unix)]
+    crate::privilege::drop_privileges(config.user.as_deref(), config.group.as_deref())?;
+    #[cfg(any(target_os = "linux", target_os = "openbsd"))]
+    if config.sandbox {
+        crate::sandbox::enable()?;
+    }
+    #[cfg(target_os = "linux")]
+    crate::sd_notify::ready();
+
+    #[cfg(feature = "metrics")]
+    if let Some(metrics_addr) = config.metrics_addr {
+        let metrics = crate::utils::ServerHandle(tokio::spawn(crate::metrics::serve(metrics_addr)));
+        tokio::select! {
+            r = manager.run() => return r,
+            r = metrics => return r,
+        }
     }
 
     manager.run().await
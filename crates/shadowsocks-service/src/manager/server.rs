@@ -2,47 +2,72 @@
 
 #[cfg(unix)]
 use std::path::PathBuf;
-use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
+use arc_swap::ArcSwapOption;
 use log::{error, info, trace};
 use shadowsocks::{
     ManagerListener, ServerAddr,
     config::{Mode, ServerConfig, ServerType, ServerUser, ServerUserManager},
-    context::{Context, SharedContext},
+    context::{CancellationToken, Context, SharedContext},
     crypto::CipherKind,
     dns_resolver::DnsResolver,
     manager::{
         datagram::ManagerSocketAddr,
         protocol::{
             self, AddRequest, AddResponse, ErrorResponse, ListResponse, ManagerRequest, PingResponse, RemoveRequest,
-            RemoveResponse, ServerUserConfig, StatRequest,
+            RemoveResponse, ServerUserConfig, StatRequest, UserStatRequest,
         },
     },
     net::{AcceptOpts, ConnectOpts},
     plugin::PluginConfig,
 };
-use tokio::{sync::Mutex, task::JoinHandle};
+use tokio::{
+    sync::{Mutex, Notify},
+    task::JoinHandle,
+};
 
 use crate::{
     acl::AccessControl,
-    config::{ManagerConfig, ManagerServerHost, ManagerServerMode, SecurityConfig},
-    net::FlowStat,
-    server::ServerBuilder,
+    config::{ManagerConfig, ManagerServerHost, ManagerServerMode, SecurityConfig, TrafficQuotaConfig},
+    events::{self, Event},
+    net::{ConnectionStat, FlowStat},
+    server::{
+        ServerBuilder, accounting::TrafficAccounting, allow_list::ClientAllowListMatcher, quota::TrafficQuota,
+        session::SessionRegistry,
+    },
 };
 
-enum ServerInstanceMode {
+pub(super) enum ServerInstanceMode {
     Builtin {
         flow_stat: Arc<FlowStat>,
+        #[cfg_attr(not(any(feature = "manager-admin", feature = "manager-grpc")), allow(dead_code))]
+        connection_stat: Arc<ConnectionStat>,
+        accounting: Arc<TrafficAccounting>,
+        #[cfg_attr(not(any(feature = "manager-admin", feature = "manager-grpc")), allow(dead_code))]
+        quota: Option<Arc<TrafficQuota>>,
+        #[cfg_attr(not(any(feature = "manager-admin", feature = "manager-grpc")), allow(dead_code))]
+        sessions: Arc<SessionRegistry>,
         abortable: JoinHandle<io::Result<()>>,
     },
 
     #[cfg(unix)]
-    Standalone { flow_stat: u64 },
+    Standalone {
+        flow_stat: u64,
+        user_stat: HashMap<String, u64>,
+    },
 }
 
-struct ServerInstance {
-    mode: ServerInstanceMode,
-    svr_cfg: ServerConfig,
+pub(super) struct ServerInstance {
+    pub(super) mode: ServerInstanceMode,
+    pub(super) svr_cfg: ServerConfig,
+    pub(super) quota: Option<TrafficQuotaConfig>,
 }
 
 impl Drop for ServerInstance {
@@ -55,11 +80,26 @@ impl Drop for ServerInstance {
 }
 
 impl ServerInstance {
-    fn flow_stat(&self) -> u64 {
+    pub(super) fn flow_stat(&self) -> u64 {
         match self.mode {
             ServerInstanceMode::Builtin { ref flow_stat, .. } => flow_stat.tx() + flow_stat.rx(),
             #[cfg(unix)]
-            ServerInstanceMode::Standalone { flow_stat } => flow_stat,
+            ServerInstanceMode::Standalone { flow_stat, .. } => flow_stat,
+        }
+    }
+
+    /// Total bytes transferred by each EIH user
+    #[cfg_attr(not(any(feature = "manager-admin", feature = "manager-grpc")), allow(dead_code))]
+    pub(super) fn user_stat(&self) -> HashMap<String, u64> {
+        match self.mode {
+            ServerInstanceMode::Builtin { ref accounting, .. } => accounting
+                .current()
+                .users
+                .into_iter()
+                .map(|(name, traffic)| (name, traffic.total()))
+                .collect(),
+            #[cfg(unix)]
+            ServerInstanceMode::Standalone { ref user_stat, .. } => user_stat.clone(),
         }
     }
 }
@@ -73,8 +113,11 @@ pub struct ManagerBuilder {
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
     acl: Option<Arc<AccessControl>>,
+    client_allow_list: Option<Arc<ClientAllowListMatcher>>,
+    proxy_protocol: bool,
     ipv6_first: bool,
     security: SecurityConfig,
+    replay_filter_capacity: Option<usize>,
 }
 
 impl ManagerBuilder {
@@ -93,8 +136,11 @@ impl ManagerBuilder {
             udp_expiry_duration: None,
             udp_capacity: None,
             acl: None,
+            client_allow_list: None,
+            proxy_protocol: false,
             ipv6_first: false,
             security: SecurityConfig::default(),
+            replay_filter_capacity: None,
         }
     }
 
@@ -123,6 +169,12 @@ impl ManagerBuilder {
         &self.svr_cfg
     }
 
+    /// Share `token` for shutting down the manager and all servers managed by it
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set cancellation token on a shared context");
+        context.set_cancellation_token(token)
+    }
+
     /// Get customized DNS resolver
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set DNS resolver on a shared context");
@@ -134,6 +186,16 @@ impl ManagerBuilder {
         self.acl = Some(acl);
     }
 
+    /// Set clients allowed to connect to servers, the others are dropped right after being accepted
+    pub fn set_client_allow_list(&mut self, allow_list: Arc<ClientAllowListMatcher>) {
+        self.client_allow_list = Some(allow_list);
+    }
+
+    /// Expect PROXY protocol headers ahead of accepted TCP connections of servers
+    pub fn set_proxy_protocol(&mut self, proxy_protocol: bool) {
+        self.proxy_protocol = proxy_protocol;
+    }
+
     /// Try to connect IPv6 addresses first if hostname could be resolved to both IPv4 and IPv6
     pub fn set_ipv6_first(&mut self, ipv6_first: bool) {
         self.ipv6_first = ipv6_first;
@@ -144,9 +206,15 @@ impl ManagerBuilder {
         self.security = security;
     }
 
+    /// Set entries of replay filters of servers, instead of the default capacity
+    pub fn set_replay_filter_capacity(&mut self, entries: usize) {
+        self.replay_filter_capacity = Some(entries);
+    }
+
     /// Build the manager server instance
     pub async fn build(self) -> io::Result<Manager> {
         let listener = ManagerListener::bind(&self.context, &self.svr_cfg.addr).await?;
+        let local_addr = listener.local_addr()?;
         Ok(Manager {
             context: self.context,
             servers: Mutex::new(HashMap::new()),
@@ -155,10 +223,15 @@ impl ManagerBuilder {
             accept_opts: self.accept_opts,
             udp_expiry_duration: self.udp_expiry_duration,
             udp_capacity: self.udp_capacity,
-            acl: self.acl,
+            acl: Arc::new(ArcSwapOption::new(self.acl)),
+            client_allow_list: self.client_allow_list,
+            proxy_protocol: self.proxy_protocol,
             ipv6_first: self.ipv6_first,
             security: self.security,
-            listener,
+            replay_filter_capacity: self.replay_filter_capacity,
+            listener: Mutex::new(listener),
+            local_addr,
+            shutdown: Notify::new(),
         })
     }
 }
@@ -166,16 +239,21 @@ impl ManagerBuilder {
 /// Manager server
 pub struct Manager {
     context: SharedContext,
-    servers: Mutex<HashMap<u16, ServerInstance>>,
+    pub(super) servers: Mutex<HashMap<u16, ServerInstance>>,
     svr_cfg: ManagerConfig,
     connect_opts: ConnectOpts,
     accept_opts: AcceptOpts,
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
-    acl: Option<Arc<AccessControl>>,
+    acl: Arc<ArcSwapOption<AccessControl>>,
+    client_allow_list: Option<Arc<ClientAllowListMatcher>>,
+    proxy_protocol: bool,
     ipv6_first: bool,
     security: SecurityConfig,
-    listener: ManagerListener,
+    replay_filter_capacity: Option<usize>,
+    listener: Mutex<ManagerListener>,
+    local_addr: ManagerSocketAddr,
+    shutdown: Notify,
 }
 
 impl Manager {
@@ -186,16 +264,86 @@ impl Manager {
 
     /// Manager server's listen address
     pub fn local_addr(&self) -> io::Result<ManagerSocketAddr> {
-        self.listener.local_addr()
+        Ok(self.local_addr.clone())
+    }
+
+    /// Stop the manager, `run()` will return after all managed servers are closed
+    pub fn shutdown(&self) {
+        self.shutdown.notify_one();
     }
 
     /// Start serving
-    pub async fn run(mut self) -> io::Result<()> {
-        let local_addr = self.listener.local_addr()?;
-        info!("shadowsocks manager server listening on {}", local_addr);
+    pub async fn run(self) -> io::Result<()> {
+        // Shared with services that require 'static, like gRPC API
+        Arc::new(self).serve().await
+    }
+
+    async fn serve(self: Arc<Self>) -> io::Result<()> {
+        info!("shadowsocks manager server listening on {}", self.local_addr);
+
+        let result = {
+            let serve_fut = self.serve_manager();
+            tokio::pin!(serve_fut);
+
+            cfg_if::cfg_if! {
+                if #[cfg(feature = "manager-admin")] {
+                    let admin_fut = async {
+                        match self.svr_cfg.admin {
+                            Some(ref admin) => super::admin::serve(&self, admin).await,
+                            None => futures::future::pending().await,
+                        }
+                    };
+                } else {
+                    let admin_fut = futures::future::pending::<io::Result<()>>();
+                }
+            }
+
+            cfg_if::cfg_if! {
+                if #[cfg(feature = "manager-grpc")] {
+                    let grpc_fut = async {
+                        match self.svr_cfg.grpc {
+                            Some(ref grpc) => super::grpc::serve(self.clone(), grpc).await,
+                            None => futures::future::pending().await,
+                        }
+                    };
+                } else {
+                    let grpc_fut = futures::future::pending::<io::Result<()>>();
+                }
+            }
+
+            tokio::select! {
+                r = &mut serve_fut => r,
+                r = admin_fut => r,
+                r = grpc_fut => r,
+                _ = self.shutdown.notified() => {
+                    info!("shadowsocks manager server is shutting down");
+                    Ok(())
+                }
+                _ = self.context.cancellation_token().cancelled() => {
+                    info!("shadowsocks manager server is shutdown by cancellation");
+                    Ok(())
+                }
+            }
+        };
+
+        // Close all managed servers
+        let mut servers = self.servers.lock().await;
+        #[cfg(unix)]
+        if self.svr_cfg.server_mode == ManagerServerMode::Standalone {
+            for port in servers.keys() {
+                self.kill_standalone_server(*port);
+            }
+        }
+        servers.clear();
+
+        result
+    }
+
+    async fn serve_manager(&self) -> io::Result<()> {
+        let mut listener = self.listener.lock().await;
 
         loop {
-            let (req, peer_addr) = match self.listener.recv_from().await {
+            let (req, peer_addr) = match listener.recv_from().await {
                 Ok(r) => r,
                 Err(err) => {
                     error!("manager recv_from error: {}", err);
@@ -208,41 +356,60 @@ impl Manager {
             match req {
                 ManagerRequest::Add(ref req) => match self.handle_add(req).await {
                     Ok(rsp) => {
-                        let _ = self.listener.send_to(&rsp, &peer_addr).await;
+                        let _ = listener.send_to(&rsp, &peer_addr).await;
                     }
                     Err(err) => {
                         error!("add server_port: {} failed, error: {}", req.server_port, err);
                         let rsp = ErrorResponse(err);
-                        let _ = self.listener.send_to(&rsp, &peer_addr).await;
+                        let _ = listener.send_to(&rsp, &peer_addr).await;
                     }
                 },
                 ManagerRequest::Remove(ref req) => {
                     let rsp = self.handle_remove(req).await;
-                    let _ = self.listener.send_to(&rsp, &peer_addr).await;
+                    let _ = listener.send_to(&rsp, &peer_addr).await;
                 }
                 ManagerRequest::List(..) => {
                     let rsp = self.handle_list().await;
-                    let _ = self.listener.send_to(&rsp, &peer_addr).await;
+                    let _ = listener.send_to(&rsp, &peer_addr).await;
                 }
                 ManagerRequest::Ping(..) => {
                     let rsp = self.handle_ping().await;
-                    let _ = self.listener.send_to(&rsp, &peer_addr).await;
+                    let _ = listener.send_to(&rsp, &peer_addr).await;
                 }
                 ManagerRequest::Stat(ref stat) => self.handle_stat(stat).await,
+                ManagerRequest::UserStat(ref stat) => self.handle_user_stat(stat).await,
             }
         }
     }
 
     /// Add a server programatically
-    pub async fn add_server(&self, svr_cfg: ServerConfig) {
+    ///
+    /// Server listening on the same port will be closed and replaced.
+    pub async fn add_server(&self, svr_cfg: ServerConfig) -> io::Result<()> {
+        self.add_server_with_quota(svr_cfg, None).await
+    }
+
+    /// Add a server with traffic quota programatically
+    ///
+    /// Server listening on the same port will be closed and replaced.
+    pub async fn add_server_with_quota(
+        &self,
+        svr_cfg: ServerConfig,
+        quota: Option<TrafficQuotaConfig>,
+    ) -> io::Result<()> {
+        let server_port = svr_cfg.addr().port();
+
         match self.svr_cfg.server_mode {
-            ManagerServerMode::Builtin => self.add_server_builtin(svr_cfg).await,
+            ManagerServerMode::Builtin => self.add_server_builtin(svr_cfg, quota).await?,
             #[cfg(unix)]
-            ManagerServerMode::Standalone => self.add_server_standalone(svr_cfg).await,
+            ManagerServerMode::Standalone => self.add_server_standalone(svr_cfg, quota).await?,
         }
+
+        events::publish(|| Event::ServerAdded { server_port });
+        Ok(())
     }
 
-    async fn add_server_builtin(&self, svr_cfg: ServerConfig) {
+    async fn add_server_builtin(&self, svr_cfg: ServerConfig, quota: Option<TrafficQuotaConfig>) -> io::Result<()> {
         // Each server should use a separate Context, but shares
         //
         // * AccessControlList
@@ -252,6 +419,7 @@ impl Manager {
         server_builder.set_connect_opts(self.connect_opts.clone());
         server_builder.set_accept_opts(self.accept_opts.clone());
         server_builder.set_dns_resolver(self.context.dns_resolver().clone());
+        server_builder.set_cancellation_token(self.context.cancellation_token().clone());
 
         if let Some(d) = self.udp_expiry_duration {
             server_builder.set_udp_expiry_duration(d);
@@ -261,8 +429,15 @@ impl Manager {
             server_builder.set_udp_capacity(c);
         }
 
-        if let Some(ref acl) = self.acl {
-            server_builder.set_acl(acl.clone());
+        // Replaced by `reload_acl` without restarting servers
+        server_builder.set_shared_acl(self.acl.clone());
+
+        if let Some(ref allow_list) = self.client_allow_list {
+            server_builder.set_client_allow_list(allow_list.clone());
+        }
+
+        if self.proxy_protocol {
+            server_builder.set_proxy_protocol(self.proxy_protocol);
         }
 
         if self.ipv6_first {
@@ -271,11 +446,35 @@ impl Manager {
 
         server_builder.set_security_config(&self.security);
 
+        if let Some(entries) = self.replay_filter_capacity {
+            server_builder.set_replay_filter_capacity(entries);
+        }
+
+        if let Some(ref quota) = quota {
+            server_builder.set_quota(quota.clone());
+        }
+
         let server_port = server_builder.server_config().addr().port();
 
+        let flow_stat = server_builder.flow_stat();
+        let connection_stat = server_builder.connection_stat();
+        let accounting = server_builder.accounting();
+        let quota_stat = server_builder.quota();
+
         let mut servers = self.servers.lock().await;
         // Close existed server
         if let Some(v) = servers.remove(&server_port) {
+            // Quota usage is kept, otherwise users exceeded their quotas would be allowed again by re-adding the server
+            if let ServerInstanceMode::Builtin {
+                quota: Some(ref previous),
+                ..
+            } = v.mode
+            {
+                if let Some(ref quota) = quota_stat {
+                    quota.carry_over(previous);
+                }
+            }
+
             info!(
                 "closed managed server listening on {}, inbound address {}",
                 v.svr_cfg.addr(),
@@ -283,24 +482,43 @@ impl Manager {
             );
         }
 
-        let flow_stat = server_builder.flow_stat();
+        let sessions = server_builder.sessions();
         let server = match server_builder.build().await {
             Ok(s) => s,
             Err(err) => {
                 error!("failed to start server ({}), error: {}", svr_cfg.addr(), err);
-                return;
+                return Err(err);
             }
         };
 
-        let abortable = tokio::spawn(async move { server.run().await });
+        let abortable = tokio::spawn(async move {
+            let result = server.run().await;
+            if let Err(ref err) = result {
+                error!(
+                    "managed server listening on port {} exited with error: {}",
+                    server_port, err
+                );
+            }
+            result
+        });
 
         servers.insert(
             server_port,
             ServerInstance {
-                mode: ServerInstanceMode::Builtin { flow_stat, abortable },
+                mode: ServerInstanceMode::Builtin {
+                    flow_stat,
+                    connection_stat,
+                    accounting,
+                    quota: quota_stat,
+                    sessions,
+                    abortable,
+                },
                 svr_cfg,
+                quota,
             },
         );
+
+        Ok(())
     }
 
     #[cfg(unix)]
@@ -354,7 +572,7 @@ impl Manager {
     }
 
     #[cfg(unix)]
-    async fn add_server_standalone(&self, svr_cfg: ServerConfig) {
+    async fn add_server_standalone(&self, svr_cfg: ServerConfig, quota: Option<TrafficQuotaConfig>) -> io::Result<()> {
         use std::{
             fs::{self, OpenOptions},
             io::Write,
@@ -369,7 +587,7 @@ impl Manager {
 
         // Check if working_directory exists
         if !self.svr_cfg.server_working_directory.exists() {
-            fs::create_dir_all(&self.svr_cfg.server_working_directory).expect("create working_directory");
+            fs::create_dir_all(&self.svr_cfg.server_working_directory)?;
         }
 
         let port = svr_cfg.addr().port();
@@ -389,6 +607,11 @@ impl Manager {
             outbound_bind_addr: None,
             outbound_bind_interface: None,
             outbound_udp_allow_fragmentation: None,
+            outbound_dscp: None,
+            quota: quota.clone(),
+            #[cfg(any(feature = "user-store-sqlite", feature = "user-store-redis"))]
+            user_store: None,
+            backup: false,
         };
 
         let mut config = Config::new(ConfigType::Server);
@@ -410,12 +633,12 @@ impl Manager {
                     config_file_path.display(),
                     err
                 );
-                return;
+                return Err(err);
             }
             Ok(mut file) => {
                 if let Err(err) = file.write_all(config_file_content.as_bytes()) {
                     error!("failed to write {}, error: {}", config_file_path.display(), err);
-                    return;
+                    return Err(err);
                 }
                 let _ = file.sync_data();
             }
@@ -434,7 +657,7 @@ impl Manager {
             .arg("--manager-addr")
             .arg(&manager_addr);
 
-        if let Some(ref acl) = self.acl {
+        if let Some(acl) = self.acl.load_full() {
             child_command.arg("--acl").arg(acl.file_path().to_str().expect("acl"));
         }
 
@@ -445,20 +668,107 @@ impl Manager {
                 "failed to spawn process of {}, error: {}",
                 self.svr_cfg.server_program, err
             );
-            return;
+            return Err(err);
         }
 
         // Greate. Record into the map
         servers.insert(
             port,
             ServerInstance {
-                mode: ServerInstanceMode::Standalone { flow_stat: 0 },
+                mode: ServerInstanceMode::Standalone {
+                    flow_stat: 0,
+                    user_stat: HashMap::new(),
+                },
                 svr_cfg,
+                quota,
             },
         );
+
+        Ok(())
+    }
+
+    /// Reload ACL from its file
+    ///
+    /// Builtin servers keep running, and new connections and packets are checked by the new rules. Standalone servers
+    /// are not restarted, they use the new rules once they are restarted.
+    pub async fn reload_acl(&self) -> io::Result<()> {
+        let acl = match self.acl.load_full() {
+            Some(acl) => acl,
+            None => return Ok(()),
+        };
+
+        let acl = AccessControl::load_from_file(acl.file_path())?;
+        info!("reloaded ACL from {}", acl.file_path().display());
+        self.acl.store(Some(Arc::new(acl)));
+
+        Ok(())
+    }
+
+    /// Reset traffic quota of the server listening on `port`
+    ///
+    /// Standalone servers will be restarted. Returns `false` if server doesn't exist.
+    #[cfg_attr(not(any(feature = "manager-admin", feature = "manager-grpc")), allow(dead_code))]
+    pub(super) async fn reset_quota(&self, port: u16) -> io::Result<bool> {
+        let (svr_cfg, quota) = {
+            let servers = self.servers.lock().await;
+            let server = match servers.get(&port) {
+                Some(s) => s,
+                None => return Ok(false),
+            };
+
+            match server.mode {
+                ServerInstanceMode::Builtin { ref quota, .. } => {
+                    if let Some(ref quota) = *quota {
+                        quota.reset();
+                    }
+                    return Ok(true);
+                }
+                #[cfg(unix)]
+                ServerInstanceMode::Standalone { .. } => {}
+            }
+
+            (server.svr_cfg.clone(), server.quota.clone())
+        };
+
+        // Restarted process starts counting from 0
+        self.add_server_with_quota(svr_cfg, quota).await?;
+        Ok(true)
+    }
+
+    /// Live TCP sessions of the server listening on `port`
+    ///
+    /// Returns `None` if server doesn't exist, or it is a standalone server, which doesn't report its sessions.
+    #[cfg_attr(not(any(feature = "manager-admin", feature = "manager-grpc")), allow(dead_code))]
+    pub(super) async fn server_sessions(&self, port: u16) -> Option<Arc<SessionRegistry>> {
+        let servers = self.servers.lock().await;
+        match servers.get(&port)?.mode {
+            ServerInstanceMode::Builtin { ref sessions, .. } => Some(sessions.clone()),
+            #[cfg(unix)]
+            ServerInstanceMode::Standalone { .. } => None,
+        }
+    }
+
+    /// Terminate live TCP sessions of client `ip` in all servers, returns number of sessions terminated
+    #[cfg_attr(not(any(feature = "manager-admin", feature = "manager-grpc")), allow(dead_code))]
+    pub(super) async fn kill_client_sessions(&self, ip: IpAddr) -> usize {
+        let servers = self.servers.lock().await;
+
+        let mut killed = 0;
+        for server in servers.values() {
+            match server.mode {
+                ServerInstanceMode::Builtin { ref sessions, .. } => killed += sessions.kill_client(ip),
+                #[cfg(unix)]
+                ServerInstanceMode::Standalone { .. } => {}
+            }
+        }
+
+        if killed > 0 {
+            info!("killed {} sessions of client {}", killed, ip);
+        }
+        killed
     }
 
-    async fn handle_add(&self, req: &AddRequest) -> io::Result<AddResponse> {
+    pub(super) async fn handle_add(&self, req: &AddRequest) -> io::Result<AddResponse> {
         let addr = match self.svr_cfg.server_host {
             ManagerServerHost::Domain(ref dname) => ServerAddr::DomainName(dname.clone(), req.server_port),
             ManagerServerHost::Ip(ip) => ServerAddr::SocketAddr(SocketAddr::new(ip, req.server_port)),
@@ -505,6 +815,7 @@ impl Manager {
                         }
                     },
                 },
+                plugin_supervision: Default::default(),
             };
             svr_cfg.set_plugin(p);
         } else if let Some(ref plugin) = self.svr_cfg.plugin {
@@ -526,10 +837,31 @@ impl Manager {
 
         svr_cfg.set_mode(mode.unwrap_or(self.svr_cfg.mode));
 
+        if let Some(ref extra_ports) = req.extra_ports {
+            let extra_addrs = extra_ports
+                .iter()
+                .map(|port| match self.svr_cfg.server_host {
+                    ManagerServerHost::Domain(ref dname) => ServerAddr::DomainName(dname.clone(), *port),
+                    ManagerServerHost::Ip(ip) => ServerAddr::SocketAddr(SocketAddr::new(ip, *port)),
+                })
+                .collect();
+            svr_cfg.set_extra_addrs(extra_addrs);
+        }
+
+        let mut quota = TrafficQuotaConfig {
+            server: req.quota,
+            users: HashMap::new(),
+            terminate: req.quota_terminate.unwrap_or(false),
+        };
+
         if let Some(ref users) = req.users {
             let mut user_manager = ServerUserManager::new();
 
             for user in users.iter() {
+                if let Some(user_quota) = user.quota {
+                    quota.users.insert(user.name.clone(), user_quota);
+                }
+
                 let user = match ServerUser::with_encoded_key(&user.name, &user.password) {
                     Ok(u) => u,
                     Err(..) => {
@@ -551,14 +883,19 @@ impl Manager {
             svr_cfg.set_user_manager(user_manager);
         }
 
-        self.add_server(svr_cfg).await;
+        let quota = if quota.is_empty() { None } else { Some(quota) };
+        self.add_server_with_quota(svr_cfg, quota).await?;
 
         Ok(AddResponse("ok".to_owned()))
     }
 
-    async fn handle_remove(&self, req: &RemoveRequest) -> RemoveResponse {
+    pub(super) async fn handle_remove(&self, req: &RemoveRequest) -> RemoveResponse {
         let mut servers = self.servers.lock().await;
-        servers.remove(&req.server_port);
+        if servers.remove(&req.server_port).is_some() {
+            events::publish(|| Event::ServerRemoved {
+                server_port: req.server_port,
+            });
+        }
 
         #[cfg(unix)]
         if self.svr_cfg.server_mode == ManagerServerMode::Standalone {
@@ -568,7 +905,7 @@ impl Manager {
         RemoveResponse("ok".to_owned())
     }
 
-    async fn handle_list(&self) -> ListResponse {
+    pub(super) async fn handle_list(&self) -> ListResponse {
         let instances = self.servers.lock().await;
 
         let mut servers = Vec::new();
@@ -584,6 +921,7 @@ impl Manager {
                     vu.push(ServerUserConfig {
                         name: user.name().to_owned(),
                         password: user.encoded_key(),
+                        quota: server.quota.as_ref().and_then(|q| q.users.get(user.name()).copied()),
                     });
                 }
 
@@ -593,13 +931,20 @@ impl Manager {
             let sc = protocol::ServerConfig {
                 server_port: svr_cfg.addr().port(),
                 password: svr_cfg.password().to_owned(),
-                method: None,
+                method: Some(svr_cfg.method().to_string()),
                 no_delay: None,
-                plugin: None,
-                plugin_opts: None,
-                plugin_mode: None,
-                mode: None,
+                plugin: svr_cfg.plugin().map(|p| p.plugin.clone()),
+                plugin_opts: svr_cfg.plugin().and_then(|p| p.plugin_opts.clone()),
+                plugin_mode: svr_cfg.plugin().map(|p| p.plugin_mode.to_string()),
+                mode: Some(svr_cfg.mode().to_string()),
                 users,
+                quota: server.quota.as_ref().and_then(|q| q.server),
+                quota_terminate: server.quota.as_ref().map(|q| q.terminate),
+                extra_ports: if svr_cfg.extra_addrs().is_empty() {
+                    None
+                } else {
+                    Some(svr_cfg.extra_addrs().iter().map(|a| a.port()).collect())
+                },
             };
             servers.push(sc);
         }
@@ -642,7 +987,7 @@ impl Manager {
                     ServerInstanceMode::Builtin { .. } => {
                         error!("received `stat` for port {} that is running a builtin server", *port)
                     }
-                    ServerInstanceMode::Standalone { ref mut flow_stat } => *flow_stat = *flow,
+                    ServerInstanceMode::Standalone { ref mut flow_stat, .. } => *flow_stat = *flow,
                 },
                 Entry::Vacant(vac) => {
                     // Read config from file
@@ -686,10 +1031,15 @@ impl Manager {
                             }
 
                             let svr_cfg = config.server[0].config.clone();
+                            let quota = config.server[0].quota.clone();
 
                             vac.insert(ServerInstance {
-                                mode: ServerInstanceMode::Standalone { flow_stat: *flow },
+                                mode: ServerInstanceMode::Standalone {
+                                    flow_stat: *flow,
+                                    user_stat: HashMap::new(),
+                                },
                                 svr_cfg,
+                                quota,
                             });
                         }
                     }
@@ -697,4 +1047,35 @@ impl Manager {
             }
         }
     }
+
+    #[cfg(not(unix))]
+    async fn handle_user_stat(&self, _: &UserStatRequest) {}
+
+    #[cfg(unix)]
+    async fn handle_user_stat(&self, stat: &UserStatRequest) {
+        // `user-stat` is only supported for Standalone mode
+        if self.svr_cfg.server_mode != ManagerServerMode::Standalone {
+            return;
+        }
+
+        let mut instances = self.servers.lock().await;
+
+        for (port, users) in stat.stat.iter() {
+            match instances.get_mut(port) {
+                Some(instance) => match instance.mode {
+                    ServerInstanceMode::Builtin { .. } => {
+                        error!(
+                            "received `user-stat` for port {} that is running a builtin server",
+                            *port
+                        )
+                    }
+                    ServerInstanceMode::Standalone { ref mut user_stat, .. } => user_stat.clone_from(users),
+                },
+                None => {
+                    // Instance will be created by the following `stat`
+                    trace!("received `user-stat` for unknown port {}", *port);
+                }
+            }
+        }
+    }
 }
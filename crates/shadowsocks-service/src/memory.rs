@@ -0,0 +1,52 @@
+//! Memory accounting hooks
+//!
+//! Processes with hard memory limits, like iOS packet tunnel extensions (about 50MB), are killed by the system once
+//! they exceed them. Applications embedding services could install a function reporting memory allocated by the
+//! process with [`set_memory_usage_fn`], usually counted by their global allocators, and a soft limit with
+//! [`set_memory_limit`]. Local servers refuse new TCP connections while the usage is over the limit, so relayed
+//! connections could finish, instead of the whole process being killed.
+
+use std::sync::{
+    RwLock,
+    atomic::{AtomicUsize, Ordering},
+};
+
+/// Function returning bytes of memory allocated by the current process
+pub type MemoryUsageFn = fn() -> usize;
+
+static MEMORY_USAGE_FN: RwLock<Option<MemoryUsageFn>> = RwLock::new(None);
+static MEMORY_LIMIT: AtomicUsize = AtomicUsize::new(0);
+
+/// Install `f` for reporting memory allocated by the current process, replacing the previous one
+pub fn set_memory_usage_fn(f: MemoryUsageFn) {
+    *MEMORY_USAGE_FN.write().unwrap() = Some(f);
+}
+
+/// Bytes of memory allocated by the current process, `None` if no functions were installed
+pub fn memory_usage() -> Option<usize> {
+    let f = (*MEMORY_USAGE_FN.read().unwrap())?;
+    Some(f())
+}
+
+/// Set the soft limit of memory usage in bytes, `None` for unlimited
+pub fn set_memory_limit(limit: Option<usize>) {
+    MEMORY_LIMIT.store(limit.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Soft limit of memory usage in bytes, `None` for unlimited
+pub fn memory_limit() -> Option<usize> {
+    match MEMORY_LIMIT.load(Ordering::Relaxed) {
+        0 => None,
+        limit => Some(limit),
+    }
+}
+
+/// Check if memory usage exceeds the soft limit
+///
+/// Always `false` if there is no limit, or no functions were installed for reporting the usage.
+pub fn is_over_limit() -> bool {
+    match memory_limit() {
+        Some(limit) => memory_usage().is_some_and(|usage| usage > limit),
+        None => false,
+    }
+}
@@ -0,0 +1,538 @@
+//! Prometheus metrics exporter
+//!
+//! A [`stats::snapshot`] of all registered statistic sources is taken when `GET /metrics` is requested, and encoded in the
+//! [Prometheus text exposition format](https://prometheus.io/docs/instrumenting/exposition_formats/), together with
+//! relay metrics collected by the [`PrometheusMetricsSink`] installed when the endpoint starts.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::Incoming,
+    header::{self, HeaderValue},
+    server::conn::http1,
+    service,
+};
+use log::{debug, error, info, trace};
+use shadowsocks::net::TcpListener;
+use tokio::time;
+
+use crate::{
+    metrics_sink::{self, Metric, MetricsSink},
+    net::tokio_rt::TokioIo,
+    stats::{self, DnsStats, LocalStats, MemoryStats, ServerStats},
+};
+
+static PROMETHEUS_SINK: OnceLock<Arc<PrometheusMetricsSink>> = OnceLock::new();
+
+/// Collect metrics from a snapshot of all registered statistic sources
+pub fn collect() -> String {
+    let snapshot = stats::snapshot();
+
+    let mut encoder = MetricsEncoder::new();
+    encoder.gauge(
+        "shadowsocks_uptime_seconds",
+        "Seconds since the first service started",
+        &[],
+        snapshot.uptime,
+    );
+    if let Some(memory_usage) = snapshot.memory_usage {
+        encoder.gauge(
+            "shadowsocks_memory_usage_bytes",
+            "Memory allocated by the process",
+            &[],
+            memory_usage as f64,
+        );
+    }
+    for (server, stats) in snapshot.servers.iter() {
+        encoder.server_stats(server, stats);
+    }
+    if let Some(ref stats) = snapshot.local {
+        encoder.local_stats(stats);
+    }
+    if let Some(sink) = PROMETHEUS_SINK.get() {
+        sink.encode_into(&mut encoder);
+    }
+    encoder.encode()
+}
+
+#[derive(Debug)]
+enum SinkValue {
+    Counter(u64),
+    Gauge(f64),
+    Histogram { sum: f64, count: u64 },
+}
+
+type SinkKey = (&'static str, Vec<(String, String)>);
+
+/// [`MetricsSink`] keeping relay metrics for the Prometheus endpoint
+///
+/// Histograms are exported as summaries without quantiles.
+#[derive(Debug, Default)]
+pub struct PrometheusMetricsSink {
+    values: Mutex<BTreeMap<SinkKey, (&'static str, SinkValue)>>,
+}
+
+impl PrometheusMetricsSink {
+    /// Create a sink without any values
+    pub fn new() -> PrometheusMetricsSink {
+        PrometheusMetricsSink::default()
+    }
+
+    fn update<F>(&self, metric: &Metric, labels: &[(&str, &str)], initial: SinkValue, f: F)
+    where
+        F: FnOnce(&mut SinkValue),
+    {
+        let labels = labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let mut values = self.values.lock().unwrap();
+        let (_, value) = values
+            .entry((metric.name, labels))
+            .or_insert_with(|| (metric.help, initial));
+        f(value);
+    }
+
+    /// Add all values to `encoder`
+    pub fn encode_into(&self, encoder: &mut MetricsEncoder) {
+        let values = self.values.lock().unwrap();
+        for ((name, labels), (help, value)) in values.iter() {
+            let labels = labels.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect::<Vec<_>>();
+            match *value {
+                SinkValue::Counter(v) => encoder.counter(name, help, &labels, v),
+                SinkValue::Gauge(v) => encoder.gauge(name, help, &labels, v),
+                SinkValue::Histogram { sum, count } => encoder.summary(name, help, &labels, sum, count),
+            }
+        }
+    }
+}
+
+impl MetricsSink for PrometheusMetricsSink {
+    fn counter(&self, metric: &Metric, labels: &[(&str, &str)], value: u64) {
+        self.update(metric, labels, SinkValue::Counter(0), |v| {
+            if let SinkValue::Counter(ref mut total) = *v {
+                *total += value;
+            }
+        });
+    }
+
+    fn gauge(&self, metric: &Metric, labels: &[(&str, &str)], value: f64) {
+        self.update(metric, labels, SinkValue::Gauge(value), |v| {
+            *v = SinkValue::Gauge(value)
+        });
+    }
+
+    fn histogram(&self, metric: &Metric, labels: &[(&str, &str)], value: f64) {
+        self.update(metric, labels, SinkValue::Histogram { sum: 0.0, count: 0 }, |v| {
+            if let SinkValue::Histogram {
+                ref mut sum,
+                ref mut count,
+            } = *v
+            {
+                *sum += value;
+                *count += 1;
+            }
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum MetricType {
+    Counter,
+    Gauge,
+    Summary,
+}
+
+impl MetricType {
+    fn name(self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Summary => "summary",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MetricFamily {
+    help: &'static str,
+    ty: MetricType,
+    samples: Vec<String>,
+}
+
+/// Encoder of Prometheus text format
+///
+/// Samples of the same metric are grouped together, no matter which source they came from.
+#[derive(Debug, Default)]
+pub struct MetricsEncoder {
+    families: BTreeMap<&'static str, MetricFamily>,
+}
+
+impl MetricsEncoder {
+    fn new() -> MetricsEncoder {
+        MetricsEncoder::default()
+    }
+
+    /// Add a sample of counter
+    pub fn counter(&mut self, name: &'static str, help: &'static str, labels: &[(&str, &str)], value: u64) {
+        self.sample(name, help, MetricType::Counter, "", labels, value as f64);
+    }
+
+    /// Add a sample of gauge
+    pub fn gauge(&mut self, name: &'static str, help: &'static str, labels: &[(&str, &str)], value: f64) {
+        self.sample(name, help, MetricType::Gauge, "", labels, value);
+    }
+
+    /// Add a sample of summary without quantiles
+    pub fn summary(&mut self, name: &'static str, help: &'static str, labels: &[(&str, &str)], sum: f64, count: u64) {
+        self.sample(name, help, MetricType::Summary, "_sum", labels, sum);
+        self.sample(name, help, MetricType::Summary, "_count", labels, count as f64);
+    }
+
+    /// Add metrics of a `shadowsocks` Context
+    pub fn context_stats(&mut self, labels: &[(&str, &str)], replay_detected: u64, dns: &DnsStats) {
+        self.counter(
+            "shadowsocks_replay_detected_total",
+            "Repeated nonce (iv/salt) detected",
+            labels,
+            replay_detected,
+        );
+        self.summary(
+            "shadowsocks_dns_resolve_duration_seconds",
+            "Time spent in DNS resolving",
+            labels,
+            dns.duration_micros as f64 / 1_000_000.0,
+            dns.queries,
+        );
+        self.counter(
+            "shadowsocks_dns_resolve_failures_total",
+            "Failed DNS resolving",
+            labels,
+            dns.failures,
+        );
+        if let Some(cache_entries) = dns.cache_entries {
+            self.gauge(
+                "shadowsocks_dns_reverse_lookup_cache_entries",
+                "Entries in the reverse lookup cache of local DNS relay",
+                labels,
+                cache_entries as f64,
+            );
+        }
+    }
+
+    /// Add metrics of memory consumers
+    pub fn memory_stats(&mut self, labels: &[(&str, &str)], memory: &MemoryStats) {
+        self.gauge(
+            "shadowsocks_replay_filter_bytes",
+            "Bytes of the bloom filter against replay attack",
+            labels,
+            memory.replay_filter_bytes as f64,
+        );
+        self.gauge(
+            "shadowsocks_relay_buffers_bytes",
+            "Estimated bytes of relay buffers of active TCP connections",
+            labels,
+            memory.relay_buffers_bytes as f64,
+        );
+    }
+
+    /// Add metrics of a server
+    pub fn server_stats(&mut self, server: &str, stats: &ServerStats) {
+        let labels = [("server", server)];
+
+        self.counter(
+            "shadowsocks_server_tx_bytes_total",
+            "Bytes sent to clients",
+            &labels,
+            stats.tx,
+        );
+        self.counter(
+            "shadowsocks_server_rx_bytes_total",
+            "Bytes received from clients",
+            &labels,
+            stats.rx,
+        );
+        self.gauge(
+            "shadowsocks_server_tcp_connections",
+            "Active TCP connections",
+            &labels,
+            stats.tcp_connections as f64,
+        );
+        self.gauge(
+            "shadowsocks_server_udp_associations",
+            "Active UDP associations",
+            &labels,
+            stats.udp_associations as f64,
+        );
+        self.counter(
+            "shadowsocks_server_handshake_failures_total",
+            "Failed TCP handshakes",
+            &labels,
+            stats.handshake_failures,
+        );
+
+        for (user, traffic) in stats.users.iter() {
+            let labels = [("server", server), ("user", user.as_str())];
+            self.counter(
+                "shadowsocks_user_tx_bytes_total",
+                "Bytes sent to each EIH user",
+                &labels,
+                traffic.tx,
+            );
+            self.counter(
+                "shadowsocks_user_rx_bytes_total",
+                "Bytes received from each EIH user",
+                &labels,
+                traffic.rx,
+            );
+        }
+
+        for (class, count) in stats.errors.classes() {
+            self.counter(
+                "shadowsocks_server_errors_total",
+                "Classified failures of each server",
+                &[("server", server), ("class", class)],
+                count,
+            );
+        }
+
+        self.context_stats(&labels, stats.replay_detected, &stats.dns);
+        self.memory_stats(&labels, &stats.memory);
+    }
+
+    /// Add metrics of local and its balancer
+    pub fn local_stats(&mut self, stats: &LocalStats) {
+        self.counter(
+            "shadowsocks_local_tx_bytes_total",
+            "Bytes sent to remote servers",
+            &[],
+            stats.tx,
+        );
+        self.counter(
+            "shadowsocks_local_rx_bytes_total",
+            "Bytes received from remote servers",
+            &[],
+            stats.rx,
+        );
+
+        self.gauge(
+            "shadowsocks_local_tcp_connections",
+            "Active TCP connections",
+            &[],
+            stats.tcp_connections as f64,
+        );
+
+        self.context_stats(&[], stats.replay_detected, &stats.dns);
+        self.memory_stats(&[], &stats.memory);
+
+        for server in stats.balancer.iter() {
+            for (protocol, score) in [("tcp", &server.tcp), ("udp", &server.udp)] {
+                let labels = [("server", server.server.as_str()), ("protocol", protocol)];
+                self.gauge(
+                    "shadowsocks_balancer_score",
+                    "Score of servers in balancer, lower is better",
+                    &labels,
+                    score.score as f64,
+                );
+                self.gauge(
+                    "shadowsocks_balancer_server_up",
+                    "Whether servers in balancer are up (1) or marked down by health checks (0)",
+                    &labels,
+                    if score.healthy { 1.0 } else { 0.0 },
+                );
+                self.gauge(
+                    "shadowsocks_balancer_server_selected",
+                    "Whether servers in balancer are currently selected (1) or not (0)",
+                    &labels,
+                    if score.selected { 1.0 } else { 0.0 },
+                );
+                if let Some(latency) = score.last_latency {
+                    self.gauge(
+                        "shadowsocks_balancer_latency_milliseconds",
+                        "Latency of the last successful check of servers in balancer",
+                        &labels,
+                        latency as f64,
+                    );
+                }
+                self.counter(
+                    "shadowsocks_balancer_failures_total",
+                    "Failed checks and requests of servers in balancer",
+                    &labels,
+                    score.failures,
+                );
+            }
+
+            // Failures of DNS and replay detections are counted by local, not by servers
+            for (class, count) in server.errors.classes() {
+                if class == "dns_failure" || class == "replay_detected" {
+                    continue;
+                }
+                self.counter(
+                    "shadowsocks_balancer_errors_total",
+                    "Classified failures of connections relayed by servers in balancer",
+                    &[("server", server.server.as_str()), ("class", class)],
+                    count,
+                );
+            }
+        }
+    }
+
+    fn sample(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        ty: MetricType,
+        suffix: &str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        let family = self.families.entry(name).or_insert_with(|| MetricFamily {
+            help,
+            ty,
+            samples: Vec::new(),
+        });
+
+        let mut sample = String::new();
+        sample.push_str(name);
+        sample.push_str(suffix);
+        if !labels.is_empty() {
+            sample.push('{');
+            for (idx, (key, value)) in labels.iter().enumerate() {
+                if idx > 0 {
+                    sample.push(',');
+                }
+                let _ = write!(sample, "{}=\"{}\"", key, escape_label_value(value));
+            }
+            sample.push('}');
+        }
+        let _ = write!(sample, " {value}");
+
+        family.samples.push(sample);
+    }
+
+    fn encode(self) -> String {
+        let mut output = String::new();
+        for (name, family) in self.families {
+            let _ = writeln!(output, "# HELP {} {}", name, family.help);
+            let _ = writeln!(output, "# TYPE {} {}", name, family.ty.name());
+            for sample in family.samples {
+                output.push_str(&sample);
+                output.push('\n');
+            }
+        }
+        output
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Serve `GET /metrics` on `addr`
+///
+/// A [`PrometheusMetricsSink`] is installed for relay metrics, unless the application has installed another sink.
+pub async fn serve(addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind_with_opts(&addr, Default::default()).await?;
+
+    let sink = PROMETHEUS_SINK.get_or_init(|| Arc::new(PrometheusMetricsSink::new()));
+    metrics_sink::set_default_metrics_sink(sink.clone());
+
+    info!(
+        "shadowsocks metrics listening on {}",
+        listener.local_addr().expect("listener.local_addr")
+    );
+
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(s) => s,
+            Err(err) => {
+                error!("metrics accept failed with error: {}", err);
+                time::sleep(time::Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        trace!("metrics accepted client from {}", peer_addr);
+
+        tokio::spawn(async move {
+            let io = TokioIo::new(stream);
+            let service = service::service_fn(|req| async move { Ok::<_, hyper::Error>(handle_request(req)) });
+
+            if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                debug!("metrics connection {} failed with error: {}", peer_addr, err);
+            }
+        });
+    }
+}
+
+fn handle_request(req: Request<Incoming>) -> Response<Full<Bytes>> {
+    let (status, body) = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => (StatusCode::OK, collect()),
+        (_, "/metrics") => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+        _ => (StatusCode::NOT_FOUND, String::new()),
+    };
+
+    let mut rsp = Response::new(Full::new(Bytes::from(body)));
+    *rsp.status_mut() = status;
+    rsp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+    );
+    rsp
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_grouped_samples() {
+        let mut encoder = MetricsEncoder::new();
+        encoder.counter("test_total", "Test counter", &[("server", "a")], 1);
+        encoder.gauge("test_gauge", "Test gauge", &[], 2.5);
+        encoder.counter("test_total", "Test counter", &[("server", "b\"")], 3);
+
+        assert_eq!(
+            encoder.encode(),
+            "# HELP test_gauge Test gauge\n\
+             # TYPE test_gauge gauge\n\
+             test_gauge 2.5\n\
+             # HELP test_total Test counter\n\
+             # TYPE test_total counter\n\
+             test_total{server=\"a\"} 1\n\
+             test_total{server=\"b\\\"\"} 3\n"
+        );
+    }
+
+    #[test]
+    fn prometheus_sink_values() {
+        let sink = PrometheusMetricsSink::new();
+        sink.counter(&metrics_sink::RELAY_CONNECTIONS, &[("protocol", "tcp")], 1);
+        sink.counter(&metrics_sink::RELAY_CONNECTIONS, &[("protocol", "tcp")], 2);
+        sink.histogram(&metrics_sink::RELAY_CONNECTION_DURATION, &[], 1.5);
+        sink.histogram(&metrics_sink::RELAY_CONNECTION_DURATION, &[], 0.5);
+
+        let mut encoder = MetricsEncoder::new();
+        sink.encode_into(&mut encoder);
+        let output = encoder.encode();
+
+        assert!(output.contains("shadowsocks_relay_connections_total{protocol=\"tcp\"} 3\n"));
+        assert!(output.contains("shadowsocks_relay_connection_duration_seconds_sum 2\n"));
+        assert!(output.contains("shadowsocks_relay_connection_duration_seconds_count 2\n"));
+    }
+}
@@ -0,0 +1,220 @@
+//! Pluggable backend of relay metrics
+//!
+//! Relays report events, like connections accepted and their durations, to the [`MetricsSink`] installed by
+//! [`set_metrics_sink`], so applications could route them into their own telemetry systems. Nothing is reported if no
+//! sinks are installed, except that the Prometheus endpoint installs a `PrometheusMetricsSink` for serving them.
+//!
+//! Metrics reported by relays:
+//!
+//! | Metric                                           | Type      | Labels               |
+//! |--------------------------------------------------|-----------|----------------------|
+//! | `shadowsocks_relay_connections_total`            | counter   | `protocol`           |
+//! | `shadowsocks_relay_connection_duration_seconds`  | histogram | `protocol`           |
+//! | `shadowsocks_relay_handshake_failures_total`     | counter   |                      |
+//! | `shadowsocks_relay_errors_total`                 | counter   | `class`              |
+//! | `shadowsocks_relay_local_tunnels_total`          | counter   | `route`              |
+//! | `shadowsocks_relay_local_tunnel_duration_seconds`| histogram | `route`              |
+//! | `shadowsocks_relay_balancer_score`               | gauge     | `server`, `protocol` |
+
+use std::{
+    fmt::{Display, Write},
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, RwLock},
+};
+
+use log::trace;
+
+/// Description of a metric
+#[derive(Debug, Clone, Copy)]
+pub struct Metric {
+    /// Name of the metric
+    pub name: &'static str,
+    /// Description of the metric
+    pub help: &'static str,
+}
+
+/// TCP connections and UDP associations accepted by servers
+pub const RELAY_CONNECTIONS: Metric = Metric {
+    name: "shadowsocks_relay_connections_total",
+    help: "TCP connections and UDP associations accepted by servers",
+};
+
+/// Durations of TCP connections and UDP associations accepted by servers
+pub const RELAY_CONNECTION_DURATION: Metric = Metric {
+    name: "shadowsocks_relay_connection_duration_seconds",
+    help: "Durations of TCP connections and UDP associations accepted by servers",
+};
+
+/// Failed TCP handshakes of servers
+pub const RELAY_HANDSHAKE_FAILURES: Metric = Metric {
+    name: "shadowsocks_relay_handshake_failures_total",
+    help: "Failed TCP handshakes of servers",
+};
+
+/// Classified failures
+pub const RELAY_ERRORS: Metric = Metric {
+    name: "shadowsocks_relay_errors_total",
+    help: "Classified failures of relays",
+};
+
+/// TCP tunnels established by local servers
+pub const LOCAL_TUNNELS: Metric = Metric {
+    name: "shadowsocks_relay_local_tunnels_total",
+    help: "TCP tunnels established by local servers",
+};
+
+/// Durations of TCP tunnels established by local servers
+pub const LOCAL_TUNNEL_DURATION: Metric = Metric {
+    name: "shadowsocks_relay_local_tunnel_duration_seconds",
+    help: "Durations of TCP tunnels established by local servers",
+};
+
+/// Scores of servers in balancer, updated by every check
+pub const BALANCER_SCORE: Metric = Metric {
+    name: "shadowsocks_relay_balancer_score",
+    help: "Scores of servers in balancer, lower is better",
+};
+
+/// Backend of metrics
+///
+/// Methods are called on tasks of relays, they should return quickly without blocking.
+pub trait MetricsSink: Send + Sync {
+    /// Add `value` to a counter
+    fn counter(&self, metric: &Metric, labels: &[(&str, &str)], value: u64);
+
+    /// Set a gauge to `value`
+    fn gauge(&self, metric: &Metric, labels: &[(&str, &str)], value: f64);
+
+    /// Record an observation of `value` in a histogram
+    fn histogram(&self, metric: &Metric, labels: &[(&str, &str)], value: f64);
+}
+
+/// Sink discarding all metrics
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopMetricsSink;
+
+impl MetricsSink for NoopMetricsSink {
+    fn counter(&self, _metric: &Metric, _labels: &[(&str, &str)], _value: u64) {}
+
+    fn gauge(&self, _metric: &Metric, _labels: &[(&str, &str)], _value: f64) {}
+
+    fn histogram(&self, _metric: &Metric, _labels: &[(&str, &str)], _value: f64) {}
+}
+
+static METRICS_SINK: RwLock<Option<Arc<dyn MetricsSink>>> = RwLock::new(None);
+
+/// Install `sink` for metrics of all services in the current process, replacing the previous one
+pub fn set_metrics_sink(sink: Arc<dyn MetricsSink>) {
+    *METRICS_SINK.write().unwrap() = Some(sink);
+}
+
+/// Install `sink` if there are no sinks installed
+#[cfg(feature = "metrics")]
+pub(crate) fn set_default_metrics_sink(sink: Arc<dyn MetricsSink>) {
+    let mut current = METRICS_SINK.write().unwrap();
+    if current.is_none() {
+        *current = Some(sink);
+    }
+}
+
+/// Add `value` to a counter of the installed sink
+pub(crate) fn counter(metric: &Metric, labels: &[(&str, &str)], value: u64) {
+    if let Some(ref sink) = *METRICS_SINK.read().unwrap() {
+        sink.counter(metric, labels, value);
+    }
+}
+
+/// Set a gauge of the installed sink
+pub(crate) fn gauge(metric: &Metric, labels: &[(&str, &str)], value: f64) {
+    if let Some(ref sink) = *METRICS_SINK.read().unwrap() {
+        sink.gauge(metric, labels, value);
+    }
+}
+
+/// Record an observation in a histogram of the installed sink
+pub(crate) fn histogram(metric: &Metric, labels: &[(&str, &str)], value: f64) {
+    if let Some(ref sink) = *METRICS_SINK.read().unwrap() {
+        sink.histogram(metric, labels, value);
+    }
+}
+
+/// Sink sending metrics to a statsd server
+///
+/// Labels are sent as tags in the DogStatsD format, like `name:1|c|#protocol:tcp`, which are supported by most
+/// statsd implementations. Metrics are dropped if they couldn't be sent immediately.
+#[derive(Debug)]
+pub struct StatsdMetricsSink {
+    socket: UdpSocket,
+    prefix: String,
+}
+
+impl StatsdMetricsSink {
+    /// Create a sink sending to the statsd server listening on `addr`
+    pub fn new(addr: SocketAddr) -> io::Result<StatsdMetricsSink> {
+        let bind_addr: SocketAddr = match addr {
+            SocketAddr::V4(..) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(..) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(StatsdMetricsSink {
+            socket,
+            prefix: String::new(),
+        })
+    }
+
+    /// Prepend `prefix` to names of all metrics
+    pub fn set_prefix<S: Into<String>>(&mut self, prefix: S) {
+        self.prefix = prefix.into();
+    }
+
+    fn send<V: Display>(&self, metric: &Metric, labels: &[(&str, &str)], value: V, ty: &str) {
+        let mut line = format!("{}{}:{}|{}", self.prefix, metric.name, value, ty);
+        for (idx, (key, value)) in labels.iter().enumerate() {
+            line.push_str(if idx == 0 { "|#" } else { "," });
+            let _ = write!(line, "{key}:{value}");
+        }
+
+        if let Err(err) = self.socket.send(line.as_bytes()) {
+            trace!("statsd metric {} dropped, error: {}", metric.name, err);
+        }
+    }
+}
+
+impl MetricsSink for StatsdMetricsSink {
+    fn counter(&self, metric: &Metric, labels: &[(&str, &str)], value: u64) {
+        self.send(metric, labels, value, "c");
+    }
+
+    fn gauge(&self, metric: &Metric, labels: &[(&str, &str)], value: f64) {
+        self.send(metric, labels, value, "g");
+    }
+
+    fn histogram(&self, metric: &Metric, labels: &[(&str, &str)], value: f64) {
+        self.send(metric, labels, value, "h");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn statsd_line_with_tags() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut sink = StatsdMetricsSink::new(server.local_addr().unwrap()).unwrap();
+        sink.set_prefix("app.");
+
+        sink.counter(&RELAY_ERRORS, &[("class", "acl_rejected"), ("server", "a")], 2);
+
+        let mut buf = [0u8; 256];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            &buf[..n],
+            b"app.shadowsocks_relay_errors_total:2|c|#class:acl_rejected,server:a"
+        );
+    }
+}
@@ -0,0 +1,49 @@
+//! Last activity of connections
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Last time a connection transferred data
+///
+/// Touched by every read and write, so it only keeps milliseconds since the connection was created in an atomic.
+#[derive(Debug)]
+pub struct ConnectionActivity {
+    created: Instant,
+    last_active: AtomicU64,
+}
+
+impl Default for ConnectionActivity {
+    fn default() -> Self {
+        ConnectionActivity {
+            created: Instant::now(),
+            last_active: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ConnectionActivity {
+    /// Create an activity of a connection which is active now
+    pub fn new() -> ConnectionActivity {
+        ConnectionActivity::default()
+    }
+
+    /// Mark the connection active now
+    #[inline]
+    pub fn touch(&self) {
+        let elapsed = self.created.elapsed().as_millis() as u64;
+        self.last_active.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Time when the connection was active
+    pub fn last_active(&self) -> Instant {
+        self.created + Duration::from_millis(self.last_active.load(Ordering::Relaxed))
+    }
+
+    /// Duration since the connection was active
+    pub fn idle(&self) -> Duration {
+        let last_active = Duration::from_millis(self.last_active.load(Ordering::Relaxed));
+        self.created.elapsed().saturating_sub(last_active)
+    }
+}
@@ -0,0 +1,171 @@
+//! Active connection statistic
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Instant,
+};
+
+use serde::Serialize;
+use shadowsocks::relay::Address;
+
+use crate::metrics_sink::{self, RELAY_CONNECTION_DURATION, RELAY_CONNECTIONS, RELAY_HANDSHAKE_FAILURES};
+
+/// Active connection statistic
+#[derive(Debug, Default)]
+pub struct ConnectionStat {
+    tcp: AtomicUsize,
+    udp: AtomicUsize,
+    handshake_failures: AtomicU64,
+    next_id: AtomicU64,
+    active: Mutex<HashMap<u64, ActiveEntry>>,
+}
+
+#[derive(Debug)]
+struct ActiveEntry {
+    kind: ConnectionKind,
+    peer_addr: SocketAddr,
+    target_addr: Option<Address>,
+    established: Instant,
+}
+
+/// An active TCP connection or UDP association in the connection table
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveConnection {
+    /// `tcp` or `udp`
+    pub protocol: &'static str,
+    /// Address of the client
+    pub peer_addr: SocketAddr,
+    /// Target address, `None` before the handshake is finished, or for UDP associations
+    pub target_addr: Option<String>,
+    /// Seconds since the connection was established
+    pub duration: f64,
+}
+
+impl ConnectionStat {
+    /// Create an empty connection statistic
+    pub fn new() -> ConnectionStat {
+        ConnectionStat::default()
+    }
+
+    /// Number of active TCP connections
+    pub fn tcp_connections(&self) -> usize {
+        self.tcp.load(Ordering::Relaxed)
+    }
+
+    /// Number of active UDP associations
+    pub fn udp_associations(&self) -> usize {
+        self.udp.load(Ordering::Relaxed)
+    }
+
+    /// Number of TCP handshakes failed
+    pub fn handshake_failures(&self) -> u64 {
+        self.handshake_failures.load(Ordering::Relaxed)
+    }
+
+    /// Count a new TCP connection from `peer_addr`, which will be released when the returned guard drops
+    pub fn tcp_guard(self: &Arc<Self>, peer_addr: SocketAddr) -> ConnectionGuard {
+        self.tcp.fetch_add(1, Ordering::AcqRel);
+        self.new_guard(ConnectionKind::Tcp, peer_addr)
+    }
+
+    /// Count a new UDP association from `peer_addr`, which will be released when the returned guard drops
+    pub fn udp_guard(self: &Arc<Self>, peer_addr: SocketAddr) -> ConnectionGuard {
+        self.udp.fetch_add(1, Ordering::AcqRel);
+        self.new_guard(ConnectionKind::Udp, peer_addr)
+    }
+
+    fn new_guard(self: &Arc<Self>, kind: ConnectionKind, peer_addr: SocketAddr) -> ConnectionGuard {
+        metrics_sink::counter(&RELAY_CONNECTIONS, &[("protocol", kind.as_str())], 1);
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.active.lock().unwrap().insert(
+            id,
+            ActiveEntry {
+                kind,
+                peer_addr,
+                target_addr: None,
+                established: Instant::now(),
+            },
+        );
+        ConnectionGuard {
+            stat: self.clone(),
+            kind,
+            id,
+        }
+    }
+
+    /// Active TCP connections and UDP associations, the oldest first
+    pub fn active_connections(&self) -> Vec<ActiveConnection> {
+        let active = self.active.lock().unwrap();
+        let mut entries = active.values().collect::<Vec<_>>();
+        entries.sort_by_key(|e| e.established);
+        entries
+            .into_iter()
+            .map(|e| ActiveConnection {
+                protocol: e.kind.as_str(),
+                peer_addr: e.peer_addr,
+                target_addr: e.target_addr.as_ref().map(ToString::to_string),
+                duration: e.established.elapsed().as_secs_f64(),
+            })
+            .collect()
+    }
+
+    /// Count a failed TCP handshake
+    pub fn add_handshake_failure(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+        metrics_sink::counter(&RELAY_HANDSHAKE_FAILURES, &[], 1);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ConnectionKind {
+    Tcp,
+    Udp,
+}
+
+impl ConnectionKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            ConnectionKind::Tcp => "tcp",
+            ConnectionKind::Udp => "udp",
+        }
+    }
+}
+
+/// Guard of an active TCP connection or UDP association
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    stat: Arc<ConnectionStat>,
+    kind: ConnectionKind,
+    id: u64,
+}
+
+impl ConnectionGuard {
+    /// Set the target address after the handshake is finished
+    pub fn set_target_addr(&self, target_addr: &Address) {
+        if let Some(entry) = self.stat.active.lock().unwrap().get_mut(&self.id) {
+            entry.target_addr = Some(target_addr.clone());
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(entry) = self.stat.active.lock().unwrap().remove(&self.id) {
+            metrics_sink::histogram(
+                &RELAY_CONNECTION_DURATION,
+                &[("protocol", self.kind.as_str())],
+                entry.established.elapsed().as_secs_f64(),
+            );
+        }
+        match self.kind {
+            ConnectionKind::Tcp => self.stat.tcp.fetch_sub(1, Ordering::AcqRel),
+            ConnectionKind::Udp => self.stat.udp.fetch_sub(1, Ordering::AcqRel),
+        };
+    }
+}
@@ -1,50 +1,142 @@
 //! Server flow statistic
+//!
+//! Flow statistics of servers and users are increased by every packet of every connection, from all worker threads.
+//! Counters are split into shards, each on its own cache line, and threads increase the shard of their own, so cores
+//! don't fight for the same cache line. Shards are summed on read.
 
-use std::sync::atomic::Ordering;
+use std::{
+    sync::{
+        OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+};
 
 #[cfg(target_has_atomic = "64")]
 type FlowCounter = std::sync::atomic::AtomicU64;
 #[cfg(not(target_has_atomic = "64"))]
 type FlowCounter = std::sync::atomic::AtomicU32;
 
-/// Connection flow statistic
-pub struct FlowStat {
+/// Maximum number of shards
+const MAX_SHARDS: usize = 16;
+
+// 128 bytes, adjacent cache lines are prefetched together on x86_64
+#[repr(align(128))]
+struct FlowShard {
     tx: FlowCounter,
     rx: FlowCounter,
 }
 
-impl Default for FlowStat {
+impl Default for FlowShard {
     fn default() -> Self {
-        FlowStat {
+        FlowShard {
             tx: FlowCounter::new(0),
             rx: FlowCounter::new(0),
         }
     }
 }
 
+/// Number of shards of shared statistics, the number of CPUs, at most `MAX_SHARDS`
+fn default_shards() -> usize {
+    static SHARDS: OnceLock<usize> = OnceLock::new();
+    *SHARDS.get_or_init(|| {
+        thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(MAX_SHARDS)
+    })
+}
+
+/// Shard of the current thread, threads are assigned in turn
+fn thread_shard() -> usize {
+    static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed);
+    }
+    SHARD.with(|shard| *shard)
+}
+
+/// Connection flow statistic
+pub struct FlowStat {
+    shards: Box<[FlowShard]>,
+}
+
+impl Default for FlowStat {
+    fn default() -> Self {
+        FlowStat::with_shards(default_shards())
+    }
+}
+
 impl FlowStat {
-    /// Create an empty flow statistic
+    /// Create an empty flow statistic, shared by connections
     pub fn new() -> FlowStat {
         FlowStat::default()
     }
 
+    /// Create an empty flow statistic with `shards` shards
+    ///
+    /// Statistics of one connection are increased by one task at a time, which needs only 1 shard.
+    pub fn with_shards(shards: usize) -> FlowStat {
+        FlowStat {
+            shards: (0..shards.max(1)).map(|_| FlowShard::default()).collect(),
+        }
+    }
+
+    #[inline]
+    fn shard(&self) -> &FlowShard {
+        if self.shards.len() == 1 {
+            return &self.shards[0];
+        }
+        &self.shards[thread_shard() % self.shards.len()]
+    }
+
     /// Transmitted bytes count
     pub fn tx(&self) -> u64 {
-        self.tx.load(Ordering::Relaxed) as _
+        self.shards.iter().map(|s| s.tx.load(Ordering::Relaxed) as u64).sum()
     }
 
     /// Increase transmitted bytes
     pub fn incr_tx(&self, n: u64) {
-        self.tx.fetch_add(n as _, Ordering::AcqRel);
+        self.shard().tx.fetch_add(n as _, Ordering::Relaxed);
     }
 
     /// Received bytes count
     pub fn rx(&self) -> u64 {
-        self.rx.load(Ordering::Relaxed) as _
+        self.shards.iter().map(|s| s.rx.load(Ordering::Relaxed) as u64).sum()
     }
 
     /// Increase received bytes
     pub fn incr_rx(&self, n: u64) {
-        self.rx.fetch_add(n as _, Ordering::AcqRel);
+        self.shard().rx.fetch_add(n as _, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn sum_of_shards() {
+        let stat = Arc::new(FlowStat::with_shards(4));
+
+        let threads = (0..8)
+            .map(|_| {
+                let stat = stat.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        stat.incr_tx(2);
+                        stat.incr_rx(1);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(stat.tx(), 16000);
+        assert_eq!(stat.rx(), 8000);
     }
 }
@@ -0,0 +1,197 @@
+//! Minimal HTTP client for pushing JSON to external services, fetching lists from them, and talking to gateways
+
+use std::{
+    io::{self, ErrorKind},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{Method, Request, Uri, client::conn::http1, header};
+use log::debug;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time,
+};
+
+use super::tokio_rt::TokioIo;
+
+/// Timeout of connecting to servers, including TLS handshakes
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Timeout of the whole request, from connecting to receiving the whole response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Maximum size of response bodies of POST requests
+const MAX_POST_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// POST `body` as `application/json` to `url`
+///
+/// `https://` is only supported with feature `webhook`.
+pub async fn post_json(url: &str, body: Vec<u8>) -> io::Result<()> {
+    request(
+        url,
+        Method::POST,
+        &[("content-type", "application/json")],
+        Bytes::from(body),
+        MAX_POST_RESPONSE_SIZE,
+    )
+    .await
+    .map(|_| ())
+}
+
+/// POST `body` with extra `headers` to `url`, returns the response body
+#[cfg(feature = "server-port-mapping")]
+pub async fn post(url: &str, headers: &[(&str, &str)], body: Vec<u8>) -> io::Result<Bytes> {
+    request(url, Method::POST, headers, Bytes::from(body), MAX_POST_RESPONSE_SIZE).await
+}
+
+/// GET `url`, returns the response body, which should be at most `max_size` bytes
+///
+/// `https://` is only supported with feature `webhook`.
+#[cfg(any(feature = "server-allow-list-url", feature = "server-port-mapping"))]
+pub async fn get(url: &str, max_size: usize) -> io::Result<Bytes> {
+    request(url, Method::GET, &[], Bytes::new(), max_size).await
+}
+
+async fn request(
+    url: &str,
+    method: Method,
+    headers: &[(&str, &str)],
+    body: Bytes,
+    max_size: usize,
+) -> io::Result<Bytes> {
+    match time::timeout(REQUEST_TIMEOUT, request_inner(url, method, headers, body, max_size)).await {
+        Ok(r) => r,
+        Err(..) => Err(io::Error::new(
+            ErrorKind::TimedOut,
+            format!("request to {url} timed out"),
+        )),
+    }
+}
+
+async fn request_inner(
+    url: &str,
+    method: Method,
+    headers: &[(&str, &str)],
+    body: Bytes,
+    max_size: usize,
+) -> io::Result<Bytes> {
+    let uri = match url.parse::<Uri>() {
+        Ok(u) => u,
+        Err(err) => return Err(io::Error::new(ErrorKind::InvalidInput, err)),
+    };
+
+    let (host, authority) = match (uri.host(), uri.authority()) {
+        (Some(h), Some(a)) => (h.trim_start_matches('[').trim_end_matches(']'), a.as_str()),
+        _ => return Err(io::Error::new(ErrorKind::InvalidInput, "missing host in URL")),
+    };
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+
+    let mut req = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(header::HOST, authority);
+    for &(name, value) in headers {
+        req = req.header(name, value);
+    }
+    let req = req
+        .body(Full::new(body))
+        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+
+    match uri.scheme_str() {
+        Some("http") | None => {
+            let stream = connect_timeout(TcpStream::connect((host, uri.port_u16().unwrap_or(80)))).await?;
+            send_request(stream, req, max_size).await
+        }
+        #[cfg(feature = "webhook")]
+        Some("https") => {
+            let stream = connect_timeout(async {
+                let stream = TcpStream::connect((host, uri.port_u16().unwrap_or(443))).await?;
+                connect_tls(stream, host).await
+            })
+            .await?;
+            send_request(stream, req, max_size).await
+        }
+        Some(scheme) => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("unsupported URL scheme \"{scheme}\""),
+        )),
+    }
+}
+
+async fn connect_timeout<F, S>(fut: F) -> io::Result<S>
+where
+    F: Future<Output = io::Result<S>>,
+{
+    match time::timeout(CONNECT_TIMEOUT, fut).await {
+        Ok(r) => r,
+        Err(..) => Err(ErrorKind::TimedOut.into()),
+    }
+}
+
+async fn send_request<S>(stream: S, req: Request<Full<Bytes>>, max_size: usize) -> io::Result<Bytes>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            debug!("http client connection failed with error: {}", err);
+        }
+    });
+
+    let rsp = sender
+        .send_request(req)
+        .await
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+
+    if !rsp.status().is_success() {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!("server responded with {}", rsp.status()),
+        ));
+    }
+
+    let body = http_body_util::Limited::new(rsp.into_body(), max_size)
+        .collect()
+        .await
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    Ok(body.to_bytes())
+}
+
+#[cfg(feature = "webhook")]
+async fn connect_tls(stream: TcpStream, domain: &str) -> io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    use std::sync::Arc;
+
+    use once_cell::sync::Lazy;
+    use tokio_rustls::{
+        TlsConnector,
+        rustls::{ClientConfig, RootCertStore, pki_types::ServerName},
+    };
+
+    static TLS_CONFIG: Lazy<Arc<ClientConfig>> = Lazy::new(|| {
+        let mut store = RootCertStore::empty();
+        store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+        let config = ClientConfig::builder()
+            .with_root_certificates(store)
+            .with_no_client_auth();
+        Arc::new(config)
+    });
+
+    let host = match ServerName::try_from(domain) {
+        Ok(n) => n,
+        Err(_) => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid dnsname \"{domain}\""),
+            ));
+        }
+    };
+
+    let connector = TlsConnector::from(TLS_CONFIG.clone());
+    connector.connect(host.to_owned(), stream).await
+}
@@ -1,13 +1,31 @@
 //! Shadowsocks Service Network Utilities
 
-pub use self::{flow::FlowStat, mon_socket::MonProxySocket, mon_stream::MonProxyStream};
+pub use self::{
+    activity::ConnectionActivity,
+    conn_stat::{ActiveConnection, ConnectionGuard, ConnectionStat},
+    flow::FlowStat,
+    mon_socket::MonProxySocket,
+    mon_stream::MonProxyStream,
+};
 
+pub mod activity;
+pub mod conn_stat;
 pub mod flow;
+#[cfg(any(
+    feature = "stats-report",
+    feature = "webhook",
+    feature = "server-allow-list-url",
+    feature = "server-port-mapping"
+))]
+pub(crate) mod http_client;
 #[cfg(target_os = "macos")]
 pub mod launch_activate_socket;
 pub mod mon_socket;
 pub mod mon_stream;
 pub mod packet_window;
+pub mod proxy_protocol;
+#[cfg(feature = "hyper")]
+pub(crate) mod tokio_rt;
 pub mod utils;
 
 /// Packet size for all UDP associations' send queue
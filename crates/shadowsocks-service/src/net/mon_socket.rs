@@ -4,6 +4,7 @@ use std::{io, net::SocketAddr, sync::Arc};
 
 use shadowsocks::{
     ProxySocket,
+    config::ServerUserManager,
     relay::{
         socks5::Address,
         udprelay::{DatagramReceive, DatagramSend, options::UdpSocketControlData},
@@ -150,4 +151,18 @@ where
 
         Ok((n, peer_addr, addr, control))
     }
+
+    /// Receive packet from Shadowsocks' UDP client, verified with `user_manager` instead of users of the socket
+    #[inline]
+    pub async fn recv_from_with_user_manager(
+        &self,
+        recv_buf: &mut [u8],
+        user_manager: Option<&ServerUserManager>,
+    ) -> io::Result<(usize, SocketAddr, Address, Option<UdpSocketControlData>)> {
+        let (n, peer_addr, addr, recv_n, control) =
+            self.socket.recv_from_with_user_manager(recv_buf, user_manager).await?;
+        self.flow_stat.incr_rx(recv_n as u64);
+
+        Ok((n, peer_addr, addr, control))
+    }
 }
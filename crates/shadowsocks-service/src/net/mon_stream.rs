@@ -10,7 +10,10 @@ use std::{
 use pin_project::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use super::flow::FlowStat;
+use super::{activity::ConnectionActivity, flow::FlowStat};
+
+/// Maximum size of data recorded by `MonProxyStream::start_recording`
+const MAX_RECORDED_SIZE: usize = 16 * 1024;
 
 /// Monitored `ProxyStream`
 #[pin_project]
@@ -18,12 +21,55 @@ pub struct MonProxyStream<S> {
     #[pin]
     stream: S,
     flow_stat: Arc<FlowStat>,
+    user_flow_stat: Option<Arc<FlowStat>>,
+    session_flow_stat: Option<Arc<FlowStat>>,
+    activity: Option<Arc<ConnectionActivity>>,
+    recorded: Option<Vec<u8>>,
 }
 
 impl<S> MonProxyStream<S> {
     #[inline]
     pub fn from_stream(stream: S, flow_stat: Arc<FlowStat>) -> MonProxyStream<S> {
-        MonProxyStream { stream, flow_stat }
+        MonProxyStream {
+            stream,
+            flow_stat,
+            user_flow_stat: None,
+            session_flow_stat: None,
+            activity: None,
+            recorded: None,
+        }
+    }
+
+    /// Keep a copy of data read from now on, at most 16KB
+    ///
+    /// Servers replay handshakes of clients to decoy servers if they couldn't be decrypted.
+    #[inline]
+    pub fn start_recording(&mut self) {
+        self.recorded = Some(Vec::new());
+    }
+
+    /// Stop recording and take the data recorded
+    #[inline]
+    pub fn take_recorded(&mut self) -> Vec<u8> {
+        self.recorded.take().unwrap_or_default()
+    }
+
+    /// Also record flow into `user_flow_stat` from now on
+    #[inline]
+    pub fn set_user_flow_stat(&mut self, user_flow_stat: Arc<FlowStat>) {
+        self.user_flow_stat = Some(user_flow_stat);
+    }
+
+    /// Also record flow into `session_flow_stat` from now on
+    #[inline]
+    pub fn set_session_flow_stat(&mut self, session_flow_stat: Arc<FlowStat>) {
+        self.session_flow_stat = Some(session_flow_stat);
+    }
+
+    /// Mark `activity` active by every read and write from now on
+    #[inline]
+    pub fn set_activity(&mut self, activity: Arc<ConnectionActivity>) {
+        self.activity = Some(activity);
     }
 
     #[inline]
@@ -49,11 +95,27 @@ where
     #[inline]
     fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
         let this = self.project();
+        let start = buf.filled().len();
         match this.stream.poll_read(cx, buf) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(Ok(())) => {
+                if let Some(recorded) = this.recorded {
+                    let data = &buf.filled()[start..];
+                    let remaining = MAX_RECORDED_SIZE.saturating_sub(recorded.len());
+                    recorded.extend_from_slice(&data[..data.len().min(remaining)]);
+                }
+
                 let n = buf.filled().len();
                 this.flow_stat.incr_rx(n as u64);
+                if let Some(user_flow_stat) = this.user_flow_stat {
+                    user_flow_stat.incr_rx(n as u64);
+                }
+                if let Some(session_flow_stat) = this.session_flow_stat {
+                    session_flow_stat.incr_rx(n as u64);
+                }
+                if let Some(activity) = this.activity {
+                    activity.touch();
+                }
                 Poll::Ready(Ok(()))
             }
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
@@ -72,6 +134,15 @@ where
             Poll::Pending => Poll::Pending,
             Poll::Ready(Ok(n)) => {
                 this.flow_stat.incr_tx(n as u64);
+                if let Some(user_flow_stat) = this.user_flow_stat {
+                    user_flow_stat.incr_tx(n as u64);
+                }
+                if let Some(session_flow_stat) = this.session_flow_stat {
+                    session_flow_stat.incr_tx(n as u64);
+                }
+                if let Some(activity) = this.activity {
+                    activity.touch();
+                }
                 Poll::Ready(Ok(n))
             }
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
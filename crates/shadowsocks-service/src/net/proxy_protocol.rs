@@ -0,0 +1,216 @@
+//! PROXY protocol of HAProxy
+//!
+//! Servers fronted by L4 load balancers, or TCP proxies of CDNs, only see addresses of the balancers. With
+//! `proxy_protocol`, balancers send a PROXY protocol header ahead of every connection, carrying the address of the
+//! real client, which is then used for ACLs, bans and accounting. Both version 1 (text) and version 2 (binary) are
+//! accepted.
+//!
+//! The other way around, connections forwarded to backends like web servers, by tunnels of `sslocal` or decoys of
+//! `ssserver`, could be prefixed with a version 2 header, so backends see addresses of the real clients.
+//!
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>
+
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Maximum length of version 1 headers, including CRLF
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+const V2_COMMAND_LOCAL: u8 = 0x0;
+const V2_COMMAND_PROXY: u8 = 0x1;
+const V2_FAMILY_INET: u8 = 0x1;
+const V2_FAMILY_INET6: u8 = 0x2;
+
+/// Version 2 header of a TCP connection from `src` to `dst`
+///
+/// IPv4 addresses are mapped to IPv6 if the other one is IPv6.
+pub fn make_proxy_header_v2(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let mut header = Vec::with_capacity(V2_SIGNATURE.len() + 4 + 36);
+    header.extend_from_slice(V2_SIGNATURE);
+    header.push(0x20 | V2_COMMAND_PROXY);
+
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            // TCP over IPv4
+            header.push((V2_FAMILY_INET << 4) | 0x1);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src_ip.octets());
+            header.extend_from_slice(&dst_ip.octets());
+        }
+        (src_ip, dst_ip) => {
+            // TCP over IPv6
+            header.push((V2_FAMILY_INET6 << 4) | 0x1);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&to_ipv6(src_ip).octets());
+            header.extend_from_slice(&to_ipv6(dst_ip).octets());
+        }
+    }
+
+    header.extend_from_slice(&src.port().to_be_bytes());
+    header.extend_from_slice(&dst.port().to_be_bytes());
+    header
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn invalid_header(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("invalid PROXY protocol header, {msg}"))
+}
+
+/// Read the PROXY protocol header at the beginning of `stream`
+///
+/// Returns the address of the real client, or `None` if the balancer didn't proxy a client, like health checks.
+/// Bytes after the header are left in `stream`.
+pub async fn read_proxy_header<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 16];
+    stream.read_exact(&mut header[..V1_PREFIX.len()]).await?;
+
+    if header[..V1_PREFIX.len()] == *V1_PREFIX {
+        return read_v1(stream).await;
+    }
+    if header[..V1_PREFIX.len()] != V2_SIGNATURE[..V1_PREFIX.len()] {
+        return Err(invalid_header("missing signature"));
+    }
+
+    stream.read_exact(&mut header[V1_PREFIX.len()..]).await?;
+    if header[..V2_SIGNATURE.len()] != *V2_SIGNATURE {
+        return Err(invalid_header("missing signature"));
+    }
+    if header[12] >> 4 != 2 {
+        return Err(invalid_header("unsupported version"));
+    }
+
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+
+    match header[12] & 0x0f {
+        V2_COMMAND_LOCAL => return Ok(None),
+        V2_COMMAND_PROXY => {}
+        _ => return Err(invalid_header("unsupported command")),
+    }
+
+    match header[13] >> 4 {
+        V2_FAMILY_INET => {
+            if payload.len() < 12 {
+                return Err(invalid_header("truncated addresses"));
+            }
+            let ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        V2_FAMILY_INET6 => {
+            if payload.len() < 36 {
+                return Err(invalid_header("truncated addresses"));
+            }
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&payload[..16]);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)))
+        }
+        // Unspecified, or UNIX sockets
+        _ => Ok(None),
+    }
+}
+
+async fn read_v1<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // Read byte by byte, bytes after the header belong to the client
+    let mut line = Vec::with_capacity(V1_MAX_LEN - V1_PREFIX.len());
+    loop {
+        if line.len() >= V1_MAX_LEN - V1_PREFIX.len() {
+            return Err(invalid_header("line too long"));
+        }
+        line.push(stream.read_u8().await?);
+        if line.ends_with(b"\r\n") {
+            line.truncate(line.len() - 2);
+            break;
+        }
+    }
+
+    let line = std::str::from_utf8(&line).map_err(|_| invalid_header("not utf-8"))?;
+    parse_v1(line)
+}
+
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let mut parts = line.split(' ');
+    match parts.next() {
+        Some("UNKNOWN") => return Ok(None),
+        Some("TCP4") | Some("TCP6") => {}
+        _ => return Err(invalid_header("unsupported protocol")),
+    }
+
+    let (src_ip, _dst_ip, src_port, _dst_port) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(a), Some(b), Some(c), Some(d)) => (a, b, c, d),
+        _ => return Err(invalid_header("missing addresses")),
+    };
+
+    let ip = src_ip
+        .parse::<IpAddr>()
+        .map_err(|_| invalid_header("invalid address"))?;
+    let port = src_port.parse::<u16>().map_err(|_| invalid_header("invalid port"))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_v1_header() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nDATA";
+        let addr = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(stream, b"DATA");
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut stream).await.unwrap(), None);
+
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+        assert!(read_proxy_header(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_v2_header() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x21, 0x00, 36]);
+        header.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        header.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        header.extend_from_slice(&56324u16.to_be_bytes());
+        header.extend_from_slice(&443u16.to_be_bytes());
+        header.extend_from_slice(b"DATA");
+
+        let mut stream = &header[..];
+        let addr = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(stream, b"DATA");
+
+        let header = make_proxy_header_v2("192.0.2.1:56324".parse().unwrap(), "[2001:db8::2]:443".parse().unwrap());
+        let mut stream = &header[..];
+        let addr = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("[::ffff:192.0.2.1]:56324".parse().unwrap()));
+        assert!(stream.is_empty());
+
+        // LOCAL command of health checks
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        let mut stream = &header[..];
+        assert_eq!(read_proxy_header(&mut stream).await.unwrap(), None);
+    }
+}
@@ -1,5 +1,6 @@
+#[cfg(feature = "local-http")]
+use std::future::Future;
 use std::{
-    future::Future,
     pin::Pin,
     task::{Context, Poll},
 };
@@ -137,9 +138,12 @@ where
     }
 }
 
+/// Executor of HTTP/2 connections of the HTTP client
+#[cfg(feature = "local-http")]
 #[derive(Clone)]
 pub struct TokioExecutor;
 
+#[cfg(feature = "local-http")]
 impl<F> hyper::rt::Executor<F> for TokioExecutor
 where
     F: Future + Send + 'static,
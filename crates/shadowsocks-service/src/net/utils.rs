@@ -5,6 +5,13 @@ use std::{
     net::{Ipv4Addr, Ipv6Addr},
 };
 
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use std::sync::Arc;
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use log::{info, warn};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use shadowsocks::net::UringDriver;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
 /// Consumes all data from `reader` and throws away until EOF
@@ -34,3 +41,18 @@ pub(crate) fn to_ipv4_mapped(ipv6: &Ipv6Addr) -> Option<Ipv4Addr> {
         _ => None,
     }
 }
+
+/// Create the io_uring driver of outbound TCP streams, `None` if io_uring is not available on this system
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub(crate) fn create_uring_driver() -> Option<Arc<UringDriver>> {
+    match UringDriver::new() {
+        Ok(driver) => {
+            info!("outbound TCP streams are relayed by io_uring");
+            Some(driver)
+        }
+        Err(err) => {
+            warn!("io_uring is not available, falling back to epoll, error: {}", err);
+            None
+        }
+    }
+}
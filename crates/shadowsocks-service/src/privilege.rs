@@ -0,0 +1,167 @@
+//! Dropping privileges after listeners are bound
+//!
+//! Listeners could be bound to privileged ports (< 1024) as root, and then the process switches to an unprivileged
+//! user and group before serving any traffic, like `-a` of shadowsocks-libev.
+//!
+//! Sockets bound afterwards, like servers added by the manager, are bound as the unprivileged user.
+//!
+//! Plugin subprocesses are started while listeners are being bound, so they are switched to the unprivileged user by
+//! themselves before exec, see [`drop_plugin_privileges`].
+
+use std::{
+    ffi::{CStr, CString},
+    io::{self, ErrorKind},
+};
+
+use log::info;
+use shadowsocks::plugin::PluginCredentials;
+
+struct User {
+    name: CString,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+fn find_user(user: &str) -> io::Result<User> {
+    let name = CString::new(user).map_err(|_| io::Error::new(ErrorKind::InvalidInput, "invalid user name"))?;
+
+    unsafe {
+        let mut pwd = match user.parse::<libc::uid_t>() {
+            Ok(uid) => libc::getpwuid(uid),
+            Err(..) => std::ptr::null_mut(),
+        };
+        if pwd.is_null() {
+            pwd = libc::getpwnam(name.as_ptr());
+        }
+        if pwd.is_null() {
+            return Err(io::Error::new(ErrorKind::NotFound, format!("user {user} not found")));
+        }
+
+        let pwd = &*pwd;
+        Ok(User {
+            name: CStr::from_ptr(pwd.pw_name).to_owned(),
+            uid: pwd.pw_uid,
+            gid: pwd.pw_gid,
+        })
+    }
+}
+
+fn find_group(group: &str) -> io::Result<libc::gid_t> {
+    let name = CString::new(group).map_err(|_| io::Error::new(ErrorKind::InvalidInput, "invalid group name"))?;
+
+    unsafe {
+        let mut grp = match group.parse::<libc::gid_t>() {
+            Ok(gid) => libc::getgrgid(gid),
+            Err(..) => std::ptr::null_mut(),
+        };
+        if grp.is_null() {
+            grp = libc::getgrnam(name.as_ptr());
+        }
+        if grp.is_null() {
+            return Err(io::Error::new(ErrorKind::NotFound, format!("group {group} not found")));
+        }
+
+        Ok((*grp).gr_gid)
+    }
+}
+
+fn check_errno(ret: libc::c_int, op: &str) -> io::Result<()> {
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        return Err(io::Error::new(err.kind(), format!("{op} failed, error: {err}")));
+    }
+    Ok(())
+}
+
+/// Resolve `user` and `group` to IDs, `None` if neither of them is set
+///
+/// The primary group is `group`, or the primary group of `user`. Supplementary groups are those of `user`, or only the
+/// primary group if `user` is not set.
+pub fn find_credentials(user: Option<&str>, group: Option<&str>) -> io::Result<Option<PluginCredentials>> {
+    if user.is_none() && group.is_none() {
+        return Ok(None);
+    }
+
+    let user = user.map(find_user).transpose()?;
+    let gid = match (group, &user) {
+        (Some(group), _) => find_group(group)?,
+        (None, Some(user)) => user.gid,
+        (None, None) => unreachable!("user or group is set"),
+    };
+    let groups = match user {
+        Some(ref user) => find_groups(user, gid)?,
+        None => vec![gid],
+    };
+
+    Ok(Some(PluginCredentials {
+        uid: user.map(|u| u.uid),
+        gid,
+        groups,
+    }))
+}
+
+fn find_groups(user: &User, gid: libc::gid_t) -> io::Result<Vec<libc::gid_t>> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let ret =
+            unsafe { libc::getgrouplist(user.name.as_ptr(), gid as _, groups.as_mut_ptr() as *mut _, &mut count) };
+        if ret >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+
+        // Buffer is too small, `count` is the required size on some platforms
+        if groups.len() >= 65536 {
+            return Err(io::Error::new(ErrorKind::Other, "getgrouplist failed, too many groups"));
+        }
+        let len = groups.len().max(count as usize) * 2;
+        groups.resize(len, 0);
+    }
+}
+
+/// Switch plugin subprocesses started afterwards to `user` and `group`
+///
+/// Plugins are started while servers are being built, before [`drop_privileges`] could be called, so this must be
+/// called before building servers, otherwise plugins facing the internet would keep running as root.
+pub fn drop_plugin_privileges(user: Option<&str>, group: Option<&str>) -> io::Result<()> {
+    shadowsocks::plugin::set_plugin_credentials(find_credentials(user, group)?);
+    Ok(())
+}
+
+/// Switch to `user` and `group`, does nothing if neither of them is set
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> io::Result<()> {
+    // Resolve all of them first, databases may not be readable after switching
+    let credentials = match find_credentials(user, group)? {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+
+    unsafe {
+        // Groups must be changed first, because it is not permitted anymore after setuid
+        check_errno(
+            libc::setgroups(credentials.groups.len() as _, credentials.groups.as_ptr()),
+            "setgroups",
+        )?;
+        check_errno(libc::setgid(credentials.gid), "setgid")?;
+
+        if let Some(uid) = credentials.uid {
+            check_errno(libc::setuid(uid), "setuid")?;
+
+            // Privileges shouldn't be regained
+            if uid != 0 && libc::setuid(0) == 0 {
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    "privileges could be regained after setuid",
+                ));
+            }
+        }
+    }
+
+    info!(
+        "dropped privileges, running as uid {}, gid {}",
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() }
+    );
+    Ok(())
+}
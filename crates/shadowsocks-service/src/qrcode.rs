@@ -0,0 +1,163 @@
+//! QR codes of servers
+//!
+//! Servers are mostly shared to phones by scanning QR codes of their SIP002 URLs. QR codes could be rendered for
+//! terminals with ANSI colors, or as PNG images, without any external tools.
+
+use std::{
+    fs,
+    io::{self, ErrorKind, Write},
+    path::Path,
+};
+
+use ::qrcode::{QrCode, types::Color};
+use flate2::{Compression, Crc, write::ZlibEncoder};
+use shadowsocks::config::ServerConfig;
+
+const ANSI_DARK: &str = "\x1b[40m  \x1b[0m";
+const ANSI_LIGHT: &str = "\x1b[47m  \x1b[0m";
+
+/// Light modules around QR codes in PNG images, as the specification requires
+const PNG_QUIET_ZONE: usize = 4;
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// QR code of a server's SIP002 URL
+pub struct ServerQrCode {
+    code: QrCode,
+}
+
+impl ServerQrCode {
+    /// Encode SIP002 URL of `svr_cfg`
+    pub fn new(svr_cfg: &ServerConfig) -> io::Result<ServerQrCode> {
+        ServerQrCode::from_url(&svr_cfg.to_url())
+    }
+
+    /// Encode `url`, fails if it is too long for a QR code
+    pub fn from_url(url: &str) -> io::Result<ServerQrCode> {
+        match QrCode::new(url.as_bytes()) {
+            Ok(code) => Ok(ServerQrCode { code }),
+            Err(err) => Err(io::Error::new(ErrorKind::InvalidInput, err.to_string())),
+        }
+    }
+
+    /// Number of modules in each side, without quiet zones
+    pub fn width(&self) -> usize {
+        self.code.width()
+    }
+
+    fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.code[(x, y)] == Color::Dark
+    }
+
+    /// Render for terminals with ANSI background colors, each module is 2 characters wide
+    pub fn to_ansi(&self) -> String {
+        let width = self.width();
+        let mut output = String::new();
+
+        let border = ANSI_LIGHT.repeat(width + 2);
+        output.push_str(&border);
+        output.push('\n');
+
+        for y in 0..width {
+            output.push_str(ANSI_LIGHT);
+            for x in 0..width {
+                output.push_str(if self.is_dark(x, y) { ANSI_DARK } else { ANSI_LIGHT });
+            }
+            output.push_str(ANSI_LIGHT);
+            output.push('\n');
+        }
+
+        output.push_str(&border);
+        output.push('\n');
+        output
+    }
+
+    /// Render as a grayscale PNG image, each module is `scale` pixels wide
+    pub fn to_png(&self, scale: usize) -> Vec<u8> {
+        let scale = scale.max(1);
+        let modules = self.width() + PNG_QUIET_ZONE * 2;
+        let size = modules * scale;
+
+        // Each row starts with filter type 0 (None)
+        let mut pixels = Vec::with_capacity((size + 1) * size);
+        for py in 0..size {
+            pixels.push(0);
+            for px in 0..size {
+                let (x, y) = (px / scale, py / scale);
+                let dark = x >= PNG_QUIET_ZONE
+                    && y >= PNG_QUIET_ZONE
+                    && x < modules - PNG_QUIET_ZONE
+                    && y < modules - PNG_QUIET_ZONE
+                    && self.is_dark(x - PNG_QUIET_ZONE, y - PNG_QUIET_ZONE);
+                pixels.push(if dark { 0x00 } else { 0xff });
+            }
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&pixels).expect("zlib to Vec");
+        let compressed = encoder.finish().expect("zlib to Vec");
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&(size as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(size as u32).to_be_bytes());
+        // Bit depth 8, color type 0 (grayscale), compression, filter and interlace methods 0
+        ihdr.extend_from_slice(&[8, 0, 0, 0, 0]);
+
+        let mut png = Vec::with_capacity(PNG_SIGNATURE.len() + compressed.len() + 3 * 12 + ihdr.len());
+        png.extend_from_slice(&PNG_SIGNATURE);
+        write_png_chunk(&mut png, b"IHDR", &ihdr);
+        write_png_chunk(&mut png, b"IDAT", &compressed);
+        write_png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    /// Write a PNG image to `path`, each module is `scale` pixels wide
+    pub fn write_png<P: AsRef<Path>>(&self, path: P, scale: usize) -> io::Result<()> {
+        fs::write(path, self.to_png(scale))
+    }
+}
+
+fn write_png_chunk(png: &mut Vec<u8>, ty: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(ty);
+    png.extend_from_slice(data);
+
+    let mut crc = Crc::new();
+    crc.update(ty);
+    crc.update(data);
+    png.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+
+    #[test]
+    fn png_image() {
+        let code = ServerQrCode::from_url("ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@127.0.0.1:8388").unwrap();
+        let scale = 3;
+        let size = (code.width() + PNG_QUIET_ZONE * 2) * scale;
+
+        let png = code.to_png(scale);
+        assert_eq!(&png[..8], &PNG_SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(u32::from_be_bytes(png[16..20].try_into().unwrap()) as usize, size);
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]));
+
+        let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let mut pixels = Vec::new();
+        ZlibDecoder::new(&png[41..41 + idat_len])
+            .read_to_end(&mut pixels)
+            .unwrap();
+        assert_eq!(pixels.len(), (size + 1) * size);
+
+        // Quiet zone, then the dark top-left corner of the finder pattern
+        let row = (PNG_QUIET_ZONE * scale) * (size + 1);
+        assert_eq!(pixels[row + 1], 0xff);
+        assert_eq!(pixels[row + 1 + PNG_QUIET_ZONE * scale], 0x00);
+    }
+}
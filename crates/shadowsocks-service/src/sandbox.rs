@@ -0,0 +1,307 @@
+//! Sandboxing after initialization
+//!
+//! When `sandbox` is enabled, the process is restricted after listeners are bound and privileges are dropped:
+//!
+//! - Linux (x86_64, aarch64): a seccomp-bpf filter allowing only system calls that relays need. The process is
+//!   killed by the kernel if any other system call is made. Filters are applied to all threads.
+//! - OpenBSD: `pledge("stdio rpath wpath cpath inet dns unix")`. `unveil` is not applied, because configuration,
+//!   ACL and log files could be reopened after starting.
+//!
+//! Processes couldn't be executed in sandbox, so plugins that exit couldn't be restarted, and the manager couldn't
+//! start servers with plugins. Running plugins could still be stopped and reaped.
+
+use std::io;
+
+use log::info;
+
+/// Restrict the current process, must be called after all initializations are done
+pub fn enable() -> io::Result<()> {
+    sys::enable()?;
+    info!("sandbox enabled");
+    Ok(())
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod sys {
+    use std::io::{self, ErrorKind};
+
+    use libc::{
+        BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W, SECCOMP_FILTER_FLAG_TSYNC, SECCOMP_RET_ALLOW,
+        SECCOMP_RET_KILL_PROCESS, SECCOMP_SET_MODE_FILTER, c_long, sock_filter, sock_fprog,
+    };
+
+    // Offsets of `struct seccomp_data`
+    const SECCOMP_DATA_NR_OFFSET: u32 = 0;
+    const SECCOMP_DATA_ARCH_OFFSET: u32 = 4;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    // Sockets, event loops (including io_uring), memory, threads, time, signals, child processes (stopping and reaping
+    // plugins) and files (configuration reloading, log rotation)
+    const ALLOWED_SYSCALLS: &[c_long] = &[
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_readv,
+        libc::SYS_writev,
+        libc::SYS_pread64,
+        libc::SYS_pwrite64,
+        libc::SYS_close,
+        libc::SYS_openat,
+        libc::SYS_newfstatat,
+        libc::SYS_fstat,
+        libc::SYS_statx,
+        libc::SYS_lseek,
+        libc::SYS_fsync,
+        libc::SYS_fdatasync,
+        libc::SYS_getdents64,
+        libc::SYS_renameat2,
+        libc::SYS_unlinkat,
+        libc::SYS_fcntl,
+        libc::SYS_ioctl,
+        libc::SYS_dup,
+        libc::SYS_dup3,
+        libc::SYS_pipe2,
+        libc::SYS_socket,
+        libc::SYS_socketpair,
+        libc::SYS_bind,
+        libc::SYS_listen,
+        libc::SYS_accept,
+        libc::SYS_accept4,
+        libc::SYS_connect,
+        libc::SYS_shutdown,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_setsockopt,
+        libc::SYS_getsockopt,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmsg,
+        libc::SYS_sendmmsg,
+        libc::SYS_recvmmsg,
+        libc::SYS_epoll_create1,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_epoll_pwait2,
+        libc::SYS_eventfd2,
+        libc::SYS_io_uring_setup,
+        libc::SYS_io_uring_enter,
+        libc::SYS_io_uring_register,
+        libc::SYS_ppoll,
+        libc::SYS_pselect6,
+        libc::SYS_futex,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_brk,
+        libc::SYS_clone,
+        libc::SYS_clone3,
+        libc::SYS_set_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_prctl,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_getuid,
+        libc::SYS_geteuid,
+        libc::SYS_getgid,
+        libc::SYS_getegid,
+        libc::SYS_tgkill,
+        libc::SYS_kill,
+        libc::SYS_wait4,
+        libc::SYS_waitid,
+        libc::SYS_pidfd_open,
+        libc::SYS_pidfd_send_signal,
+        libc::SYS_uname,
+        libc::SYS_sysinfo,
+        libc::SYS_getrandom,
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_getres,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_nanosleep,
+        libc::SYS_gettimeofday,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_restart_syscall,
+        libc::SYS_prlimit64,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_open,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_stat,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_lstat,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_access,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_rename,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_renameat,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_getrlimit,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_unlink,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_poll,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_epoll_wait,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_pipe,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_arch_prctl,
+    ];
+
+    const fn stmt(code: u32, k: u32) -> sock_filter {
+        sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    const fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+        sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    fn build_filter() -> Vec<sock_filter> {
+        let mut filter = vec![
+            // System call numbers are different between architectures
+            stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_ARCH_OFFSET),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+            stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_NR_OFFSET),
+        ];
+        for &nr in ALLOWED_SYSCALLS {
+            filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr as u32, 0, 1));
+            filter.push(stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+        }
+        filter.push(stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS));
+        filter
+    }
+
+    pub fn enable() -> io::Result<()> {
+        install(&mut build_filter())
+    }
+
+    fn install(filter: &mut [sock_filter]) -> io::Result<()> {
+        let prog = sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+
+        unsafe {
+            // Required for unprivileged processes to install filters
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // TSYNC applies the filter to all threads of runtimes, which are already started
+            let ret = libc::syscall(
+                libc::SYS_seccomp,
+                SECCOMP_SET_MODE_FILTER,
+                SECCOMP_FILTER_FLAG_TSYNC,
+                &prog as *const sock_fprog,
+            );
+            match ret {
+                0 => Ok(()),
+                -1 => Err(io::Error::last_os_error()),
+                tid => Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!("seccomp filter couldn't be applied to thread {tid}"),
+                )),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn filter_size() {
+            let filter = build_filter();
+            assert_eq!(filter.len(), 5 + 2 * ALLOWED_SYSCALLS.len());
+            assert!(filter.len() <= libc::BPF_MAXINSNS as usize);
+        }
+
+        /// Run `f` in a forked process with the filter installed, returns its wait status
+        fn run_filtered<F: FnOnce()>(f: F) -> libc::c_int {
+            // Built before forking, allocations are not safe in children of multi-threaded processes
+            let mut filter = build_filter();
+            unsafe {
+                let pid = libc::fork();
+                assert!(pid >= 0, "fork failed");
+                if pid == 0 {
+                    if install(&mut filter).is_err() {
+                        libc::_exit(2);
+                    }
+                    f();
+                    libc::_exit(0);
+                }
+
+                let mut status = 0;
+                assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+                status
+            }
+        }
+
+        #[test]
+        fn filtered_process() {
+            // Stopping and reaping plugins
+            let status = run_filtered(|| unsafe {
+                let mut status = 0;
+                libc::kill(libc::getpid(), 0);
+                libc::waitpid(-1, &mut status, libc::WNOHANG);
+            });
+            assert!(libc::WIFEXITED(status), "status {status}");
+            assert_eq!(libc::WEXITSTATUS(status), 0);
+
+            // Killed by any other system call
+            let status = run_filtered(|| unsafe {
+                libc::syscall(libc::SYS_ptrace, libc::PTRACE_TRACEME, 0, 0, 0);
+            });
+            assert!(libc::WIFSIGNALED(status), "status {status}");
+            assert_eq!(libc::WTERMSIG(status), libc::SIGSYS);
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", not(any(target_arch = "x86_64", target_arch = "aarch64"))))]
+mod sys {
+    use std::io::{self, ErrorKind};
+
+    pub fn enable() -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "sandbox is only supported on x86_64 and aarch64",
+        ))
+    }
+}
+
+#[cfg(target_os = "openbsd")]
+mod sys {
+    use std::{io, ptr};
+
+    pub fn enable() -> io::Result<()> {
+        let promises = c"stdio rpath wpath cpath inet dns unix";
+        if unsafe { libc::pledge(promises.as_ptr(), ptr::null()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+//! systemd service notifications
+//!
+//! States are sent to `NOTIFY_SOCKET` if the service is started by systemd with `Type=notify` or `Type=notify-reload`:
+//!
+//! - `READY=1` when all listeners are bound, and watchdog pings are started if `WatchdogSec=` is set
+//! - `RELOADING=1` when servers are reloading from the configuration file, then `READY=1` after reloaded
+//! - `STOPPING=1` when the process is exiting by signals
+//!
+//! <https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html>
+
+use std::{
+    env, io,
+    os::{linux::net::SocketAddrExt, unix::net::UnixDatagram},
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use log::{debug, error};
+use tokio::time;
+
+static WATCHDOG_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Send `state` to systemd, returns `false` if the service is not started with `NOTIFY_SOCKET`
+pub fn notify(state: &str) -> io::Result<bool> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(p) => p,
+        None => return Ok(false),
+    };
+
+    let addr = match path.as_encoded_bytes().strip_prefix(b"@") {
+        Some(name) => std::os::unix::net::SocketAddr::from_abstract_name(name)?,
+        None => std::os::unix::net::SocketAddr::from_pathname(&path)?,
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(true)
+}
+
+fn notify_or_log(state: &str) {
+    if let Err(err) = notify(state) {
+        error!("failed to notify systemd {:?}, error: {}", state, err);
+    }
+}
+
+/// Notify systemd that the service is ready, and start watchdog pings if it is required
+///
+/// Must be called in a tokio runtime.
+pub fn ready() {
+    notify_or_log("READY=1");
+
+    match watchdog_interval() {
+        Some(interval) if !WATCHDOG_STARTED.swap(true, Ordering::AcqRel) => {
+            debug!("systemd watchdog enabled, ping interval {:?}", interval);
+            tokio::spawn(watchdog_task(interval));
+        }
+        _ => {}
+    }
+}
+
+/// Notify systemd that the service is reloading its configuration, [`ready`] should be called after reloaded
+pub fn reloading() {
+    // MONOTONIC_USEC is required by Type=notify-reload
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    let monotonic_usec = ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000;
+
+    notify_or_log(&format!("RELOADING=1\nMONOTONIC_USEC={monotonic_usec}"));
+}
+
+/// Notify systemd that the service is stopping
+pub fn stopping() {
+    notify_or_log("STOPPING=1");
+}
+
+fn watchdog_interval() -> Option<Duration> {
+    let pid = env::var("WATCHDOG_PID").ok().and_then(|p| p.parse::<u32>().ok());
+    if pid.is_some_and(|pid| pid != process::id()) {
+        return None;
+    }
+
+    let usec = env::var("WATCHDOG_USEC").ok().and_then(|u| u.parse::<u64>().ok())?;
+    if usec == 0 {
+        return None;
+    }
+
+    // Pings at half of the timeout, as sd_watchdog_enabled(3) recommends
+    Some(Duration::from_micros(usec / 2))
+}
+
+async fn watchdog_task(interval: Duration) {
+    let mut interval = time::interval(interval);
+    loop {
+        interval.tick().await;
+        notify_or_log("WATCHDOG=1");
+    }
+}
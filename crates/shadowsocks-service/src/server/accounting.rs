@@ -0,0 +1,120 @@
+//! Per-user traffic accounting
+//!
+//! Traffic of connections authenticated by AEAD-2022 Extensible Identity Headers (EIH) is
+//! accounted to each user. Traffic of a whole server is still recorded in its `FlowStat`.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use log::trace;
+use tokio::time;
+
+use crate::net::FlowStat;
+
+/// Interval of taking snapshots
+pub const ACCOUNTING_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Traffic of a user
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UserTraffic {
+    /// Bytes sent to the user
+    pub tx: u64,
+    /// Bytes received from the user
+    pub rx: u64,
+}
+
+impl UserTraffic {
+    /// Total bytes transferred
+    pub fn total(&self) -> u64 {
+        self.tx + self.rx
+    }
+}
+
+/// Traffic of all users at a point of time
+#[derive(Debug, Clone)]
+pub struct TrafficSnapshot {
+    /// Time of this snapshot
+    pub time: SystemTime,
+    /// Traffic of each user, keyed by user name
+    pub users: HashMap<String, UserTraffic>,
+}
+
+impl Default for TrafficSnapshot {
+    fn default() -> TrafficSnapshot {
+        TrafficSnapshot {
+            time: SystemTime::now(),
+            users: HashMap::new(),
+        }
+    }
+}
+
+/// Per-user traffic accounting of a server
+#[derive(Debug, Default)]
+pub struct TrafficAccounting {
+    users: Mutex<HashMap<String, Arc<FlowStat>>>,
+    snapshot: Mutex<Arc<TrafficSnapshot>>,
+}
+
+impl TrafficAccounting {
+    /// Create an empty accounting
+    pub fn new() -> TrafficAccounting {
+        TrafficAccounting::default()
+    }
+
+    /// Get the flow statistic of `user`, created if not exists
+    pub fn user_flow_stat(&self, user: &str) -> Arc<FlowStat> {
+        let mut users = self.users.lock().unwrap();
+        if let Some(flow_stat) = users.get(user) {
+            return flow_stat.clone();
+        }
+
+        let flow_stat = Arc::new(FlowStat::new());
+        users.insert(user.to_owned(), flow_stat.clone());
+        flow_stat
+    }
+
+    /// Collect current traffic of all users
+    pub fn current(&self) -> TrafficSnapshot {
+        let users = self.users.lock().unwrap();
+        TrafficSnapshot {
+            time: SystemTime::now(),
+            users: users
+                .iter()
+                .map(|(name, flow_stat)| {
+                    (
+                        name.clone(),
+                        UserTraffic {
+                            tx: flow_stat.tx(),
+                            rx: flow_stat.rx(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// The latest snapshot taken periodically
+    pub fn snapshot(&self) -> Arc<TrafficSnapshot> {
+        self.snapshot.lock().unwrap().clone()
+    }
+
+    /// Take a snapshot of current traffic
+    pub fn take_snapshot(&self) -> Arc<TrafficSnapshot> {
+        let snapshot = Arc::new(self.current());
+        *self.snapshot.lock().unwrap() = snapshot.clone();
+        snapshot
+    }
+
+    /// Take snapshots every `ACCOUNTING_SNAPSHOT_INTERVAL`
+    pub async fn run_snapshot(self: Arc<Self>) {
+        let mut interval = time::interval(ACCOUNTING_SNAPSHOT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let snapshot = self.take_snapshot();
+            trace!("traffic accounting snapshot of {} users", snapshot.users.len());
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Allowed clients of servers
+//!
+//! Private servers whose users connect from known networks could drop all the other clients right after accepting
+//! them, before reading any handshakes.
+
+use std::{
+    io::{self, ErrorKind},
+    net::IpAddr,
+};
+
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use iprange::IpRange;
+
+use crate::config::ClientAllowList;
+
+/// Maximum size of lists fetched from URLs
+#[cfg(feature = "server-allow-list-url")]
+const MAX_FETCH_SIZE: usize = 16 * 1024 * 1024;
+
+/// Networks of allowed clients
+#[derive(Debug)]
+pub struct ClientAllowListMatcher {
+    ipv4: IpRange<Ipv4Net>,
+    ipv6: IpRange<Ipv6Net>,
+}
+
+impl ClientAllowListMatcher {
+    /// Create with networks of allowed clients
+    pub fn new(networks: &[IpNet]) -> ClientAllowListMatcher {
+        let mut ipv4 = IpRange::new();
+        let mut ipv6 = IpRange::new();
+        for net in networks {
+            match *net {
+                IpNet::V4(n) => {
+                    ipv4.add(n);
+                }
+                IpNet::V6(n) => {
+                    ipv6.add(n);
+                }
+            }
+        }
+        ipv4.simplify();
+        ipv6.simplify();
+
+        ClientAllowListMatcher { ipv4, ipv6 }
+    }
+
+    /// Load the list, fetching it if it is a URL
+    pub async fn load(list: &ClientAllowList) -> io::Result<ClientAllowListMatcher> {
+        match *list {
+            ClientAllowList::Networks(ref networks) => Ok(ClientAllowListMatcher::new(networks)),
+            ClientAllowList::Url(ref url) => Self::fetch(url).await,
+        }
+    }
+
+    /// Fetch the list from `url`, which is timed out by the HTTP client
+    #[cfg(feature = "server-allow-list-url")]
+    async fn fetch(url: &str) -> io::Result<ClientAllowListMatcher> {
+        let body = crate::net::http_client::get(url, MAX_FETCH_SIZE).await?;
+        let text = String::from_utf8_lossy(&body);
+        match ClientAllowList::parse_networks(&text) {
+            Ok(networks) => Ok(ClientAllowListMatcher::new(&networks)),
+            Err(err) => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("client_allow_list {url} invalid, {err}"),
+            )),
+        }
+    }
+
+    #[cfg(not(feature = "server-allow-list-url"))]
+    async fn fetch(url: &str) -> io::Result<ClientAllowListMatcher> {
+        Err(io::Error::new(
+            ErrorKind::Other,
+            format!("client_allow_list {url} requires feature \"server-allow-list-url\""),
+        ))
+    }
+
+    /// Check if client `ip` is allowed
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match *ip {
+            IpAddr::V4(v4) => self.ipv4.contains(&v4) || self.ipv6.contains(&v4.to_ipv6_mapped()),
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => self.ipv4.contains(&v4) || self.ipv6.contains(&v6),
+                None => self.ipv6.contains(&v6),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn contains_mapped_addresses() {
+        let networks = ClientAllowList::parse_networks("# office\n192.0.2.0/24\n2001:db8::1\n").unwrap();
+        let matcher = ClientAllowListMatcher::new(&networks);
+
+        assert!(matcher.contains(&"192.0.2.10".parse().unwrap()));
+        assert!(matcher.contains(&"::ffff:192.0.2.10".parse().unwrap()));
+        assert!(matcher.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!matcher.contains(&"198.51.100.1".parse().unwrap()));
+        assert!(!matcher.contains(&"2001:db8::2".parse().unwrap()));
+    }
+}
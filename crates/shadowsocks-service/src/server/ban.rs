@@ -0,0 +1,229 @@
+//! Automatic banning of clients failing handshakes repeatedly
+//!
+//! Active probers and brute-force attackers keep sending data that couldn't be decrypted, or replaying captured
+//! handshakes. Once a client IP failed `threshold` handshakes in a window, it is banned for a while, and every following
+//! ban of the same client doubles the duration, up to `max_duration`.
+
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lru_time_cache::LruCache;
+
+use crate::config::SecurityBanConfig;
+
+/// Least recently seen clients will be forgotten once there are this many clients
+const MAX_TRACKED_CLIENTS: usize = 4096;
+
+/// Key of a client IP in per-client tables
+///
+/// IPv4-mapped IPv6 addresses are the same clients as their IPv4 addresses, and an IPv6 client usually owns the whole
+/// /64 prefix, so it couldn't escape by rotating addresses.
+pub fn client_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(ip.to_bits() & !((1u128 << 64) - 1))),
+        ip => ip,
+    }
+}
+
+#[derive(Debug)]
+struct ClientRecord {
+    window_start: Instant,
+    failures: u32,
+    bans: u32,
+    banned_until: Option<Instant>,
+}
+
+impl ClientRecord {
+    fn new(now: Instant) -> ClientRecord {
+        ClientRecord {
+            window_start: now,
+            failures: 0,
+            bans: 0,
+            banned_until: None,
+        }
+    }
+
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| now < until)
+    }
+
+    /// Check if the record still affects the client, failures in the window or previous bans
+    fn is_active(&self, now: Instant, config: &SecurityBanConfig) -> bool {
+        if now.duration_since(self.window_start) < config.window {
+            return true;
+        }
+        match self.banned_until {
+            Some(until) => now < until || now.duration_since(until) < config.max_duration,
+            None => false,
+        }
+    }
+}
+
+/// Banned clients of a server
+#[derive(Debug)]
+pub struct BanList {
+    config: SecurityBanConfig,
+    clients: Mutex<LruCache<IpAddr, ClientRecord>>,
+}
+
+impl BanList {
+    /// Create an empty ban list
+    pub fn new(config: SecurityBanConfig) -> BanList {
+        BanList {
+            config,
+            clients: Mutex::new(LruCache::with_capacity(MAX_TRACKED_CLIENTS)),
+        }
+    }
+
+    /// Check if `ip` is banned now
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let clients = self.clients.lock().unwrap();
+        match clients.peek(&client_key(ip)) {
+            Some(record) => record.is_banned(Instant::now()),
+            None => false,
+        }
+    }
+
+    /// Count a failed handshake of `ip`, returns the duration of the ban if `ip` was just banned
+    pub fn add_failure(&self, ip: IpAddr) -> Option<Duration> {
+        let ip = ip.to_canonical();
+        if self.config.allowlist.iter().any(|net| net.contains(&ip)) {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+
+        let record = clients.entry(client_key(ip)).or_insert_with(|| ClientRecord::new(now));
+        if record.is_banned(now) {
+            // Connections accepted before the ban
+            return None;
+        }
+
+        if !record.is_active(now, &self.config) {
+            *record = ClientRecord::new(now);
+        } else if now.duration_since(record.window_start) >= self.config.window {
+            record.window_start = now;
+            record.failures = 0;
+        }
+
+        record.failures += 1;
+        if record.failures < self.config.threshold {
+            return None;
+        }
+
+        let duration = self
+            .config
+            .duration
+            .saturating_mul(2u32.saturating_pow(record.bans))
+            .min(self.config.max_duration);
+
+        record.window_start = now;
+        record.failures = 0;
+        record.bans += 1;
+        record.banned_until = Some(now + duration);
+
+        Some(duration)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ban_list(threshold: u32) -> BanList {
+        BanList::new(SecurityBanConfig {
+            threshold,
+            window: Duration::from_secs(60),
+            duration: Duration::from_secs(10),
+            max_duration: Duration::from_secs(25),
+            allowlist: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn ban_after_threshold() {
+        let bans = ban_list(3);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert_eq!(bans.add_failure(ip), None);
+        assert_eq!(bans.add_failure(ip), None);
+        assert!(!bans.is_banned(ip));
+        assert_eq!(bans.add_failure(ip), Some(Duration::from_secs(10)));
+        assert!(bans.is_banned(ip));
+        assert!(!bans.is_banned("192.0.2.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn ban_duration_doubles() {
+        let bans = ban_list(1);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert_eq!(bans.add_failure(ip), Some(Duration::from_secs(10)));
+
+        // Expire the ban
+        bans.clients
+            .lock()
+            .unwrap()
+            .get_mut(&client_key(ip))
+            .unwrap()
+            .banned_until = Some(Instant::now());
+        assert_eq!(bans.add_failure(ip), Some(Duration::from_secs(20)));
+
+        bans.clients
+            .lock()
+            .unwrap()
+            .get_mut(&client_key(ip))
+            .unwrap()
+            .banned_until = Some(Instant::now());
+        assert_eq!(bans.add_failure(ip), Some(Duration::from_secs(25)));
+    }
+
+    #[test]
+    fn allowlist_never_banned() {
+        let bans = ban_list(1);
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+
+        assert_eq!(bans.add_failure(ip), None);
+        assert!(!bans.is_banned(ip));
+
+        // IPv4-mapped IPv6 addresses are in the allowlist too
+        let ip: IpAddr = "::ffff:10.1.2.3".parse().unwrap();
+        assert_eq!(bans.add_failure(ip), None);
+        assert!(!bans.is_banned(ip));
+    }
+
+    #[test]
+    fn ban_canonical_and_prefix() {
+        let bans = ban_list(2);
+
+        assert_eq!(bans.add_failure("192.0.2.1".parse().unwrap()), None);
+        assert_eq!(
+            bans.add_failure("::ffff:192.0.2.1".parse().unwrap()),
+            Some(Duration::from_secs(10))
+        );
+        assert!(bans.is_banned("192.0.2.1".parse().unwrap()));
+        assert!(bans.is_banned("::ffff:192.0.2.1".parse().unwrap()));
+
+        assert_eq!(bans.add_failure("2001:db8::1".parse().unwrap()), None);
+        assert_eq!(
+            bans.add_failure("2001:db8::ffff:2".parse().unwrap()),
+            Some(Duration::from_secs(10))
+        );
+        assert!(bans.is_banned("2001:db8::3".parse().unwrap()));
+        assert!(!bans.is_banned("2001:db8:0:1::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn tracked_clients_capped() {
+        let bans = ban_list(3);
+        for i in 0..(MAX_TRACKED_CLIENTS as u32 + 100) {
+            bans.add_failure(IpAddr::from(i.to_be_bytes()));
+        }
+        assert_eq!(bans.clients.lock().unwrap().len(), MAX_TRACKED_CLIENTS);
+    }
+}
@@ -0,0 +1,103 @@
+//! Enforcing bans in the firewall
+//!
+//! Banned clients are added to an nftables or ipset set with a timeout of the ban, so their packets could be dropped
+//! by Netfilter rules matching the set, instead of being accepted and closed by servers. Elements are removed by the
+//! kernel once expired, so the sets must be created with timeouts enabled.
+
+use std::{
+    io::{self, ErrorKind},
+    net::IpAddr,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use log::{debug, error, warn};
+
+use crate::config::{BanFirewallBackend, SecurityBanFirewallConfig};
+
+/// Sets of the firewall that banned clients are added to
+#[derive(Debug)]
+pub struct BanFirewall {
+    config: SecurityBanFirewallConfig,
+}
+
+impl BanFirewall {
+    /// Create with sets in `config`
+    pub fn new(config: SecurityBanFirewallConfig) -> BanFirewall {
+        BanFirewall { config }
+    }
+
+    /// Add `ip` to the set of its family in background, it will be removed after `duration`
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        // IPv4-mapped IPv6 addresses of dual-stack listeners go to the IPv4 set
+        let ip = ip.to_canonical();
+        let set = match ip {
+            IpAddr::V4(..) => self.config.set.as_ref(),
+            IpAddr::V6(..) => self.config.set6.as_ref(),
+        };
+        let set = match set {
+            Some(s) => s,
+            None => {
+                debug!("ban firewall has no set for {}", ip);
+                return;
+            }
+        };
+
+        // Timeouts of sets are at least 1 second
+        let timeout = duration.as_secs().max(1);
+        let (program, args) = match self.config.backend {
+            BanFirewallBackend::Nftables { ref table } => {
+                let mut args = vec!["add".to_owned(), "element".to_owned()];
+                args.extend(table.split_whitespace().map(ToOwned::to_owned));
+                args.push(set.clone());
+                args.push(format!("{{ {ip} timeout {timeout}s }}"));
+                ("nft", args)
+            }
+            BanFirewallBackend::Ipset => (
+                "ipset",
+                vec![
+                    "add".to_owned(),
+                    set.clone(),
+                    ip.to_string(),
+                    "timeout".to_owned(),
+                    timeout.to_string(),
+                    "-exist".to_owned(),
+                ],
+            ),
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+            let _ = run_command(program, &args);
+        });
+    }
+}
+
+fn run_command(program: &str, args: &[&str]) -> io::Result<()> {
+    debug!("ban firewall: {} {}", program, args.join(" "));
+
+    let output = match Command::new(program).args(args).stdin(Stdio::null()).output() {
+        Ok(o) => o,
+        Err(err) => {
+            error!("ban firewall failed to execute {}, error: {}", program, err);
+            return Err(err);
+        }
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        warn!(
+            "ban firewall: {} {} exited with {}, {}",
+            program,
+            args.join(" "),
+            output.status,
+            stderr.trim()
+        );
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!("{} exited with {}, {}", program, output.status, stderr.trim()),
+        ));
+    }
+
+    Ok(())
+}
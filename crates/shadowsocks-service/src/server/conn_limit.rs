@@ -0,0 +1,337 @@
+//! Limits of active connections of all servers
+//!
+//! TCP connections and UDP associations of all servers are counted together, against maximums of each kind and
+//! file descriptors available to the process. Servers are overloaded once any of them is reached, and new connections
+//! are either rejected, or make room for themselves by shedding the connection idle for the longest time, so servers
+//! degrade gracefully instead of failing randomly with `EMFILE`.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use tokio::sync::Notify;
+
+use crate::{
+    config::{OverloadPolicy, SecurityConnectionLimitConfig},
+    net::ConnectionActivity,
+};
+
+/// Connections transferred data in this duration are never shed
+const SHED_MIN_IDLE: Duration = Duration::from_secs(5);
+/// Interval of counting open file descriptors
+const FD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Kind of limited connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionKind {
+    Tcp,
+    Udp,
+}
+
+impl ConnectionKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            ConnectionKind::Tcp => "tcp connections",
+            ConnectionKind::Udp => "udp associations",
+        }
+    }
+}
+
+struct SlotEntry {
+    activity: Arc<ConnectionActivity>,
+    shed_notify: Arc<Notify>,
+    // Key of this connection in `idle_queue`
+    queued_active: Instant,
+}
+
+/// Active connections of a kind
+#[derive(Default)]
+struct Slots {
+    entries: HashMap<u64, SlotEntry>,
+    // Connections ordered by their last activity when they were queued, which may be older than the actual one, so
+    // connections don't have to be requeued on every transfer
+    idle_queue: BTreeSet<(Instant, u64)>,
+}
+
+impl Slots {
+    fn insert(&mut self, id: u64, activity: Arc<ConnectionActivity>, shed_notify: Arc<Notify>) {
+        let queued_active = activity.last_active();
+        self.idle_queue.insert((queued_active, id));
+        self.entries.insert(
+            id,
+            SlotEntry {
+                activity,
+                shed_notify,
+                queued_active,
+            },
+        );
+    }
+
+    fn remove(&mut self, id: u64) -> Option<SlotEntry> {
+        let entry = self.entries.remove(&id)?;
+        self.idle_queue.remove(&(entry.queued_active, id));
+        Some(entry)
+    }
+
+    /// Connection idle for the longest time, and its idle duration
+    fn idlest(&mut self, now: Instant) -> Option<(u64, Duration)> {
+        while let Some(&(queued_active, id)) = self.idle_queue.first() {
+            let entry = self.entries.get_mut(&id).expect("queued slot");
+            let last_active = entry.activity.last_active();
+            if last_active <= queued_active {
+                // Connections behind are active after `queued_active`
+                return Some((id, now.saturating_duration_since(last_active)));
+            }
+
+            // Active since it was queued
+            self.idle_queue.pop_first();
+            self.idle_queue.insert((last_active, id));
+            entry.queued_active = last_active;
+        }
+        None
+    }
+}
+
+struct FdHeadroom {
+    checked: Option<Instant>,
+    low: bool,
+}
+
+/// Limiter of active connections, shared by all servers
+pub struct ConnectionLimiter {
+    config: SecurityConnectionLimitConfig,
+    next_id: AtomicU64,
+    tcp: Mutex<Slots>,
+    udp: Mutex<Slots>,
+    fd_headroom: Mutex<FdHeadroom>,
+}
+
+impl ConnectionLimiter {
+    /// Create a limiter without any connections
+    pub fn new(config: SecurityConnectionLimitConfig) -> ConnectionLimiter {
+        ConnectionLimiter {
+            config,
+            next_id: AtomicU64::new(0),
+            tcp: Mutex::new(Slots::default()),
+            udp: Mutex::new(Slots::default()),
+            fd_headroom: Mutex::new(FdHeadroom {
+                checked: None,
+                low: false,
+            }),
+        }
+    }
+
+    fn slots(&self, kind: ConnectionKind) -> &Mutex<Slots> {
+        match kind {
+            ConnectionKind::Tcp => &self.tcp,
+            ConnectionKind::Udp => &self.udp,
+        }
+    }
+
+    fn max(&self, kind: ConnectionKind) -> Option<usize> {
+        match kind {
+            ConnectionKind::Tcp => self.config.max_tcp,
+            ConnectionKind::Udp => self.config.max_udp,
+        }
+    }
+
+    /// Number of active connections of `kind`
+    pub fn len(&self, kind: ConnectionKind) -> usize {
+        self.slots(kind).lock().unwrap().entries.len()
+    }
+
+    /// Acquire a slot for a new connection of `kind`, returns `None` if it should be rejected
+    ///
+    /// The slot is released when the returned guard drops.
+    pub fn acquire(self: &Arc<Self>, kind: ConnectionKind) -> Option<ConnectionSlot> {
+        let fd_low = self.check_fd_headroom();
+
+        let mut slots = self.slots(kind).lock().unwrap();
+        let full = self.max(kind).is_some_and(|max| slots.entries.len() >= max);
+
+        if full || fd_low {
+            if self.config.policy == OverloadPolicy::Reject {
+                return None;
+            }
+
+            match slots.idlest(Instant::now()) {
+                // Removed now, so the connection doesn't count while it is being closed
+                Some((id, idle)) if idle >= SHED_MIN_IDLE => {
+                    let entry = slots.remove(id).expect("shed slot");
+                    entry.shed_notify.notify_one();
+                    info!("shed {} idle for {:?}, servers are overloaded", kind.as_str(), idle);
+                }
+                _ => return None,
+            }
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let activity = Arc::new(ConnectionActivity::new());
+        let shed_notify = Arc::new(Notify::new());
+        slots.insert(id, activity.clone(), shed_notify.clone());
+
+        Some(ConnectionSlot {
+            limiter: self.clone(),
+            kind,
+            id,
+            activity,
+            shed_notify,
+        })
+    }
+
+    /// Check if file descriptors available are less than `fd_headroom`, counted at most once per `FD_CHECK_INTERVAL`
+    fn check_fd_headroom(&self) -> bool {
+        let headroom = match self.config.fd_headroom {
+            None => return false,
+            Some(h) => h,
+        };
+
+        let mut state = self.fd_headroom.lock().unwrap();
+        if state.checked.is_some_and(|t| t.elapsed() < FD_CHECK_INTERVAL) {
+            return state.low;
+        }
+        state.checked = Some(Instant::now());
+
+        let low = match available_fds() {
+            Some(available) => available < headroom,
+            // Failed to open the directory of file descriptors, likely `EMFILE`
+            None => cfg!(unix),
+        };
+
+        if low && !state.low {
+            warn!(
+                "servers are overloaded, less than {} file descriptors are available, {} new connections",
+                headroom,
+                match self.config.policy {
+                    OverloadPolicy::Reject => "rejecting",
+                    OverloadPolicy::ShedIdle => "shedding idle connections for",
+                }
+            );
+        } else if !low && state.low {
+            info!("servers recovered, {} file descriptors are available", headroom);
+        }
+        state.low = low;
+
+        low
+    }
+}
+
+/// Number of file descriptors can be opened under `RLIMIT_NOFILE`
+#[cfg(unix)]
+fn available_fds() -> Option<u64> {
+    let limit = crate::sys::get_nofile().ok()?;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let dir = "/proc/self/fd";
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let dir = "/dev/fd";
+
+    // Including the descriptor of the directory itself
+    let opened = std::fs::read_dir(dir).ok()?.count() as u64;
+    Some(limit.saturating_sub(opened))
+}
+
+#[cfg(not(unix))]
+fn available_fds() -> Option<u64> {
+    None
+}
+
+/// Slot of an active connection
+pub struct ConnectionSlot {
+    limiter: Arc<ConnectionLimiter>,
+    kind: ConnectionKind,
+    id: u64,
+    activity: Arc<ConnectionActivity>,
+    shed_notify: Arc<Notify>,
+}
+
+impl ConnectionSlot {
+    /// Activity of this connection, which should be marked by every transfer
+    pub fn activity(&self) -> Arc<ConnectionActivity> {
+        self.activity.clone()
+    }
+
+    /// Mark this connection active now
+    #[inline]
+    pub fn touch(&self) {
+        self.activity.touch();
+    }
+
+    /// Wait until this connection was shed for new connections
+    pub async fn shed(&self) {
+        self.shed_notify.notified().await
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.limiter.slots(self.kind).lock().unwrap().remove(self.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reject_over_maximum() {
+        let limiter = Arc::new(ConnectionLimiter::new(SecurityConnectionLimitConfig {
+            max_tcp: Some(2),
+            ..Default::default()
+        }));
+
+        let a = limiter.acquire(ConnectionKind::Tcp).unwrap();
+        let _b = limiter.acquire(ConnectionKind::Tcp).unwrap();
+        assert!(limiter.acquire(ConnectionKind::Tcp).is_none());
+        assert!(limiter.acquire(ConnectionKind::Udp).is_some());
+
+        drop(a);
+        assert_eq!(limiter.len(ConnectionKind::Tcp), 1);
+        assert!(limiter.acquire(ConnectionKind::Tcp).is_some());
+    }
+
+    #[test]
+    fn shed_only_idle() {
+        let limiter = Arc::new(ConnectionLimiter::new(SecurityConnectionLimitConfig {
+            max_udp: Some(1),
+            policy: OverloadPolicy::ShedIdle,
+            ..Default::default()
+        }));
+
+        let _a = limiter.acquire(ConnectionKind::Udp).unwrap();
+        // Just became active, couldn't be shed
+        assert!(limiter.acquire(ConnectionKind::Udp).is_none());
+        assert_eq!(limiter.len(ConnectionKind::Udp), 1);
+    }
+
+    #[test]
+    fn idlest_by_last_activity() {
+        let mut slots = Slots::default();
+        let activities = (0..3).map(|_| Arc::new(ConnectionActivity::new())).collect::<Vec<_>>();
+        for (id, activity) in activities.iter().enumerate() {
+            slots.insert(id as u64, activity.clone(), Arc::new(Notify::new()));
+        }
+
+        std::thread::sleep(Duration::from_millis(20));
+        activities[0].touch();
+        std::thread::sleep(Duration::from_millis(20));
+        activities[2].touch();
+
+        // 0 was queued first, but it is active after 1
+        let now = Instant::now();
+        assert_eq!(slots.idlest(now).map(|(id, _)| id), Some(1));
+        slots.remove(1);
+        assert_eq!(slots.idlest(now).map(|(id, _)| id), Some(0));
+        slots.remove(0);
+        assert_eq!(slots.idlest(now).map(|(id, _)| id), Some(2));
+        slots.remove(2);
+        assert_eq!(slots.idlest(now), None);
+        assert!(slots.idle_queue.is_empty());
+    }
+}
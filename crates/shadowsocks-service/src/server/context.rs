@@ -1,16 +1,48 @@
 //! Shadowsocks Local Server Context
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
+use arc_swap::ArcSwapOption;
 use shadowsocks::{
-    config::ServerType,
-    context::{Context, SharedContext},
+    config::{ServerType, ServerUserManager},
+    context::{CancellationToken, Context, SharedContext},
     dns_resolver::DnsResolver,
     net::ConnectOpts,
     relay::Address,
 };
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, watch};
+
+use crate::{
+    acl::AccessControl,
+    config::{SecurityConfig, SecurityHandshakeFailureConfig},
+    net::{ConnectionStat, FlowStat},
+    stats::{DnsStats, ErrorClass, ErrorCounters, MemoryStats, ServerStats, UserStats},
+};
 
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
+#[cfg(target_os = "linux")]
+use super::ban_firewall::BanFirewall;
+#[cfg(feature = "server-geoip")]
+use super::geoip::OutboundGeoIp;
+use super::{
+    accounting::TrafficAccounting, allow_list::ClientAllowListMatcher, ban::BanList, conn_limit::ConnectionLimiter,
+    quota::TrafficQuota, rate_limit::AcceptRateLimiter, replay_source::ReplaySources, session::SessionRegistry,
+};
+
+/// Outbound destination checked by [`ServiceContext::check_outbound`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckedOutbound {
+    /// Blocked by ACL rules or countries of its addresses
+    Blocked,
+    /// Allowed, connected by its address as usual
+    Allowed,
+    /// Domain name allowed by countries of its resolved addresses, which should be connected instead of resolving it
+    /// again
+    Resolved(Vec<SocketAddr>),
+}
 
 /// Server Service Context
 #[derive(Clone)]
@@ -18,11 +50,62 @@ pub struct ServiceContext {
     context: SharedContext,
     connect_opts: ConnectOpts,
 
-    // Access Control
-    acl: Option<Arc<AccessControl>>,
+    // Access Control, could be replaced at runtime
+    acl: Arc<ArcSwapOption<AccessControl>>,
+
+    // Outbound destinations restricted by countries
+    #[cfg(feature = "server-geoip")]
+    outbound_geoip: Option<Arc<OutboundGeoIp>>,
+
+    // Clients banned for failing handshakes
+    ban_list: Option<Arc<BanList>>,
+
+    // Clients allowed to connect
+    client_allow_list: Option<Arc<ClientAllowListMatcher>>,
+
+    // Read addresses of clients from PROXY protocol headers
+    proxy_protocol: bool,
+
+    // Serve targets of the bench responder
+    bench_responder: bool,
+
+    // Sets of the firewall that banned clients are added to
+    #[cfg(target_os = "linux")]
+    ban_firewall: Option<Arc<BanFirewall>>,
+
+    // Rate limiting of new connections
+    rate_limiter: Option<Arc<AcceptRateLimiter>>,
+
+    // Limits of active connections of all servers
+    connection_limiter: Option<Arc<ConnectionLimiter>>,
+
+    // Handling connections failed handshakes
+    handshake_failure: Arc<SecurityHandshakeFailureConfig>,
+    rejected_slots: Arc<Semaphore>,
+
+    // Sources of replayed requests
+    replay_sources: Arc<ReplaySources>,
 
     // Flow statistic report
     flow_stat: Arc<FlowStat>,
+
+    // Active connections
+    connection_stat: Arc<ConnectionStat>,
+
+    // Classified failures
+    errors: Arc<ErrorCounters>,
+
+    // Per-user traffic
+    accounting: Arc<TrafficAccounting>,
+
+    // Traffic quota
+    quota: Option<Arc<TrafficQuota>>,
+
+    // Live TCP sessions
+    sessions: Arc<SessionRegistry>,
+
+    // Users replaced at runtime
+    user_manager_tx: Arc<watch::Sender<Option<Arc<ServerUserManager>>>>,
 }
 
 impl Default for ServiceContext {
@@ -30,8 +113,29 @@ impl Default for ServiceContext {
         ServiceContext {
             context: Context::new_shared(ServerType::Server),
             connect_opts: ConnectOpts::default(),
-            acl: None,
+            acl: Arc::new(ArcSwapOption::empty()),
+            #[cfg(feature = "server-geoip")]
+            outbound_geoip: None,
+            client_allow_list: None,
+            proxy_protocol: false,
+            bench_responder: false,
+            ban_list: None,
+            #[cfg(target_os = "linux")]
+            ban_firewall: None,
+            rate_limiter: None,
+            connection_limiter: None,
+            handshake_failure: Arc::new(SecurityHandshakeFailureConfig::default()),
+            rejected_slots: Arc::new(Semaphore::new(
+                SecurityHandshakeFailureConfig::default().max_connections,
+            )),
+            replay_sources: Arc::new(ReplaySources::new()),
             flow_stat: Arc::new(FlowStat::new()),
+            connection_stat: Arc::new(ConnectionStat::new()),
+            errors: Arc::new(ErrorCounters::new()),
+            accounting: Arc::new(TrafficAccounting::new()),
+            quota: None,
+            sessions: Arc::new(SessionRegistry::new()),
+            user_manager_tx: Arc::new(watch::Sender::new(None)),
         }
     }
 }
@@ -52,6 +156,17 @@ impl ServiceContext {
         self.context.as_ref()
     }
 
+    /// Share `token` for shutting down the service
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set cancellation token on a shared context");
+        context.set_cancellation_token(token)
+    }
+
+    /// Get the token for shutting down the service
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        self.context.cancellation_token()
+    }
+
     /// Set `ConnectOpts`
     pub fn set_connect_opts(&mut self, connect_opts: ConnectOpts) {
         self.connect_opts = connect_opts;
@@ -64,12 +179,18 @@ impl ServiceContext {
 
     /// Set Access Control List
     pub fn set_acl(&mut self, acl: Arc<AccessControl>) {
-        self.acl = Some(acl);
+        self.acl = Arc::new(ArcSwapOption::new(Some(acl)));
     }
 
-    /// Get Access Control List reference
-    pub fn acl(&self) -> Option<&AccessControl> {
-        self.acl.as_deref()
+    /// Set Access Control List shared with others, rules stored into `acl` are checked by new connections and packets
+    /// immediately
+    pub fn set_shared_acl(&mut self, acl: Arc<ArcSwapOption<AccessControl>>) {
+        self.acl = acl;
+    }
+
+    /// Get Access Control List
+    pub fn acl(&self) -> Option<Arc<AccessControl>> {
+        self.acl.load_full()
     }
 
     /// Get cloned flow statistic
@@ -82,6 +203,76 @@ impl ServiceContext {
         self.flow_stat.as_ref()
     }
 
+    /// Get cloned active connection statistic
+    pub fn connection_stat(&self) -> Arc<ConnectionStat> {
+        self.connection_stat.clone()
+    }
+
+    /// Get active connection statistic reference
+    pub fn connection_stat_ref(&self) -> &ConnectionStat {
+        self.connection_stat.as_ref()
+    }
+
+    /// Count a classified failure
+    pub fn record_error(&self, class: ErrorClass) {
+        self.errors.record(class);
+    }
+
+    /// Get cloned per-user traffic accounting
+    pub fn accounting(&self) -> Arc<TrafficAccounting> {
+        self.accounting.clone()
+    }
+
+    /// Get per-user traffic accounting reference
+    pub fn accounting_ref(&self) -> &TrafficAccounting {
+        self.accounting.as_ref()
+    }
+
+    /// Set traffic quota
+    pub fn set_quota(&mut self, quota: Arc<TrafficQuota>) {
+        self.quota = Some(quota);
+    }
+
+    /// Get cloned traffic quota
+    pub fn quota(&self) -> Option<Arc<TrafficQuota>> {
+        self.quota.clone()
+    }
+
+    /// Get traffic quota reference
+    pub fn quota_ref(&self) -> Option<&TrafficQuota> {
+        self.quota.as_deref()
+    }
+
+    /// Check if quota of the server, or quota of `user` is exceeded
+    pub fn check_quota_exceeded(&self, user: Option<&str>) -> bool {
+        match self.quota {
+            None => false,
+            Some(ref quota) => quota.is_server_exceeded() || user.is_some_and(|u| quota.is_user_exceeded(u)),
+        }
+    }
+
+    /// Get cloned live sessions registry
+    pub fn sessions(&self) -> Arc<SessionRegistry> {
+        self.sessions.clone()
+    }
+
+    /// Get live sessions registry reference
+    pub fn sessions_ref(&self) -> &Arc<SessionRegistry> {
+        &self.sessions
+    }
+
+    /// Replace users of the running server
+    ///
+    /// Listeners will verify new connections and packets with `user_manager`.
+    pub fn update_user_manager(&self, user_manager: Option<Arc<ServerUserManager>>) {
+        self.user_manager_tx.send_replace(user_manager);
+    }
+
+    /// Subscribe to users replaced by `update_user_manager`
+    pub fn subscribe_user_manager(&self) -> watch::Receiver<Option<Arc<ServerUserManager>>> {
+        self.user_manager_tx.subscribe()
+    }
+
     /// Set customized DNS resolver
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set DNS resolver on a shared context");
@@ -93,22 +284,128 @@ impl ServiceContext {
         self.context.dns_resolver()
     }
 
+    /// Set countries of outbound destinations allowed or blocked
+    #[cfg(feature = "server-geoip")]
+    pub fn set_outbound_geoip(&mut self, geoip: Arc<OutboundGeoIp>) {
+        self.outbound_geoip = Some(geoip);
+    }
+
     /// Check if target should be bypassed
     pub async fn check_outbound_blocked(&self, addr: &Address) -> bool {
-        match self.acl {
-            None => false,
-            Some(ref acl) => acl.check_outbound_blocked(&self.context, addr).await,
+        self.check_outbound(addr).await == CheckedOutbound::Blocked
+    }
+
+    /// Check if target should be bypassed, domain names checked by countries are connected by the returned addresses
+    pub async fn check_outbound(&self, addr: &Address) -> CheckedOutbound {
+        if let Some(acl) = self.acl.load_full() {
+            if acl.check_outbound_blocked(&self.context, addr).await {
+                return CheckedOutbound::Blocked;
+            }
+        }
+
+        #[cfg(feature = "server-geoip")]
+        if let Some(ref geoip) = self.outbound_geoip {
+            return geoip.check_outbound(&self.context, addr).await;
         }
+
+        CheckedOutbound::Allowed
+    }
+
+    /// Set clients allowed to connect, the others are blocked
+    pub fn set_client_allow_list(&mut self, allow_list: Arc<ClientAllowListMatcher>) {
+        self.client_allow_list = Some(allow_list);
+    }
+
+    /// Expect PROXY protocol headers ahead of accepted TCP connections, carrying addresses of the real clients
+    pub fn set_proxy_protocol(&mut self, proxy_protocol: bool) {
+        self.proxy_protocol = proxy_protocol;
+    }
+
+    /// Check if PROXY protocol headers are expected ahead of accepted TCP connections
+    pub fn proxy_protocol(&self) -> bool {
+        self.proxy_protocol
+    }
+
+    /// Serve targets of the domain `bench::BENCH_DOMAIN` in process, for throughput self-tests of clients
+    pub fn set_bench_responder(&mut self, bench_responder: bool) {
+        self.bench_responder = bench_responder;
+    }
+
+    /// Check if targets of the bench responder are served
+    pub fn bench_responder(&self) -> bool {
+        self.bench_responder
     }
 
     /// Check if client should be blocked
     pub fn check_client_blocked(&self, addr: &SocketAddr) -> bool {
-        match self.acl {
+        if let Some(ref allow_list) = self.client_allow_list {
+            if !allow_list.contains(&addr.ip()) {
+                return true;
+            }
+        }
+
+        match *self.acl.load() {
             None => false,
             Some(ref acl) => acl.check_client_blocked(addr),
         }
     }
 
+    /// Check if client is banned for failing handshakes
+    pub fn check_client_banned(&self, ip: IpAddr) -> bool {
+        match self.ban_list {
+            None => false,
+            Some(ref ban_list) => ban_list.is_banned(ip),
+        }
+    }
+
+    /// Count a failed handshake of client, returns the duration of the ban if it was just banned
+    pub fn add_client_handshake_failure(&self, ip: IpAddr) -> Option<Duration> {
+        let duration = match self.ban_list {
+            None => None,
+            Some(ref ban_list) => ban_list.add_failure(ip),
+        };
+
+        #[cfg(target_os = "linux")]
+        if let (Some(duration), Some(firewall)) = (duration, self.ban_firewall.as_ref()) {
+            firewall.ban(ip, duration);
+        }
+
+        duration
+    }
+
+    /// Check if a new connection of client exceeds the rate limits, it is counted if not
+    pub fn check_accept_rate_limited(&self, ip: IpAddr) -> bool {
+        match self.rate_limiter {
+            None => false,
+            Some(ref rate_limiter) => !rate_limiter.check(ip),
+        }
+    }
+
+    /// Set limits of active connections, shared by all servers
+    pub fn set_connection_limiter(&mut self, limiter: Arc<ConnectionLimiter>) {
+        self.connection_limiter = Some(limiter);
+    }
+
+    /// Get limits of active connections
+    pub fn connection_limiter(&self) -> Option<&Arc<ConnectionLimiter>> {
+        self.connection_limiter.as_ref()
+    }
+
+    /// Count a request from `ip` rejected by the replay filter, returns the number of replays recorded from it
+    pub fn add_replay_source(&self, ip: IpAddr) -> u64 {
+        self.replay_sources.add(ip)
+    }
+
+    /// Get the config of handling connections failed handshakes
+    pub fn handshake_failure_config(&self) -> &SecurityHandshakeFailureConfig {
+        &self.handshake_failure
+    }
+
+    /// Take a slot for holding a rejected connection, `None` if there are `max_connections` held already
+    pub fn try_hold_rejected(&self) -> Option<OwnedSemaphorePermit> {
+        self.rejected_slots.clone().try_acquire_owned().ok()
+    }
+
     /// Try to connect IPv6 addresses first if hostname could be resolved to both IPv4 and IPv6
     pub fn set_ipv6_first(&mut self, ipv6_first: bool) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set ipv6_first on a shared context");
@@ -119,5 +416,59 @@ impl ServiceContext {
     pub fn set_security_config(&mut self, security: &SecurityConfig) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set security on a shared context");
         context.set_replay_attack_policy(security.replay_attack.policy);
+
+        self.ban_list = security.ban.clone().map(|config| Arc::new(BanList::new(config)));
+        #[cfg(target_os = "linux")]
+        {
+            self.ban_firewall = security
+                .ban
+                .as_ref()
+                .and_then(|ban| ban.firewall.clone())
+                .map(|config| Arc::new(BanFirewall::new(config)));
+        }
+        self.rate_limiter = security
+            .rate_limit
+            .clone()
+            .map(|config| Arc::new(AcceptRateLimiter::new(config)));
+        self.handshake_failure = Arc::new(security.handshake_failure.clone());
+        self.rejected_slots = Arc::new(Semaphore::new(security.handshake_failure.max_connections));
+    }
+
+    /// Set entries of the replay filter
+    pub fn set_replay_filter_capacity(&mut self, entries: usize) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set replay filter on a shared context");
+        context.set_replay_filter_capacity(entries);
+    }
+
+    /// Take a snapshot of statistics
+    pub fn stats_snapshot(&self) -> ServerStats {
+        let stat = self.context.stat();
+        ServerStats {
+            tx: self.flow_stat.tx(),
+            rx: self.flow_stat.rx(),
+            tcp_connections: self.connection_stat.tcp_connections(),
+            udp_associations: self.connection_stat.udp_associations(),
+            handshake_failures: self.connection_stat.handshake_failures(),
+            replay_detected: stat.replay_detected(),
+            dns: DnsStats::new(stat),
+            memory: MemoryStats::new(self.context_ref()),
+            errors: self.errors.snapshot(Some(stat)),
+            users: self
+                .accounting
+                .current()
+                .users
+                .into_iter()
+                .map(|(user, traffic)| {
+                    (
+                        user,
+                        UserStats {
+                            tx: traffic.tx,
+                            rx: traffic.rx,
+                        },
+                    )
+                })
+                .collect(),
+            replay_sources: self.replay_sources.snapshot(),
+        }
     }
 }
@@ -0,0 +1,251 @@
+//! Outbound destinations restricted by countries
+//!
+//! Countries of destinations are looked up in a MaxMind DB, like GeoLite2-Country or DB-IP, which is loaded into
+//! memory when servers start. Destinations of domain names are resolved, and blocked if any of their addresses is,
+//! otherwise they are connected by the checked addresses.
+
+use std::{
+    collections::HashSet,
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+};
+
+use log::trace;
+use maxminddb::{Reader, geoip2};
+use shadowsocks::{context::Context, relay::socks5::Address};
+
+use crate::config::{OutboundGeoIpConfig, OutboundGeoIpPolicy};
+
+use super::context::CheckedOutbound;
+
+/// Countries of outbound destinations allowed or blocked
+pub struct OutboundGeoIp {
+    reader: Reader<Vec<u8>>,
+    countries: HashSet<String>,
+    allow: bool,
+}
+
+impl OutboundGeoIp {
+    /// Load the database of `config`
+    pub fn open(config: &OutboundGeoIpConfig) -> io::Result<OutboundGeoIp> {
+        let reader = Reader::open_readfile(&config.database).map_err(|err| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("outbound_geoip database {} invalid, {err}", config.database.display()),
+            )
+        })?;
+
+        Ok(OutboundGeoIp::new(reader, &config.policy))
+    }
+
+    fn new(reader: Reader<Vec<u8>>, policy: &OutboundGeoIpPolicy) -> OutboundGeoIp {
+        let (countries, allow) = match *policy {
+            OutboundGeoIpPolicy::Allow(ref countries) => (countries, true),
+            OutboundGeoIpPolicy::Block(ref countries) => (countries, false),
+        };
+
+        OutboundGeoIp {
+            reader,
+            countries: countries.iter().cloned().collect(),
+            allow,
+        }
+    }
+
+    /// ISO 3166-1 alpha-2 code of the country of `ip`, `None` if it is not in the database
+    pub fn country(&self, ip: IpAddr) -> Option<&str> {
+        let country = match self.reader.lookup::<geoip2::Country>(ip) {
+            Ok(Some(c)) => c,
+            Ok(None) => return None,
+            Err(err) => {
+                trace!("outbound_geoip lookup {} failed, {}", ip, err);
+                return None;
+            }
+        };
+
+        // Registered country, for addresses of anycast or mobile networks without a located country
+        country
+            .country
+            .and_then(|c| c.iso_code)
+            .or_else(|| country.registered_country.and_then(|c| c.iso_code))
+    }
+
+    /// Check if `ip` is blocked by its country
+    pub fn check_ip_blocked(&self, ip: IpAddr) -> bool {
+        let matched = match self.country(ip) {
+            Some(country) => self.countries.contains(country),
+            None => false,
+        };
+        matched != self.allow
+    }
+
+    /// Check if `outbound` is blocked by its country, domain names are resolved with `context`
+    ///
+    /// Domain names failed to resolve are blocked, the allowed ones should be connected by the returned addresses
+    /// instead of resolving them again, so a changing DNS answer couldn't bypass the check.
+    pub async fn check_outbound(&self, context: &Context, outbound: &Address) -> CheckedOutbound {
+        match outbound {
+            Address::SocketAddress(saddr) => {
+                if self.check_ip_blocked(saddr.ip()) {
+                    CheckedOutbound::Blocked
+                } else {
+                    CheckedOutbound::Allowed
+                }
+            }
+            Address::DomainNameAddress(host, port) => {
+                let addrs = match context.dns_resolve(host, *port).await {
+                    Ok(vaddr) => vaddr.collect::<Vec<_>>(),
+                    Err(err) => {
+                        trace!("outbound_geoip resolve {}:{} failed, {}", host, port, err);
+                        return CheckedOutbound::Blocked;
+                    }
+                };
+
+                if addrs.is_empty() || addrs.iter().any(|addr| self.check_ip_blocked(addr.ip())) {
+                    CheckedOutbound::Blocked
+                } else {
+                    CheckedOutbound::Resolved(addrs)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    use shadowsocks::{
+        config::ServerType,
+        dns_resolver::{DnsResolve, DnsResolver},
+    };
+
+    use super::*;
+
+    fn push_string(buf: &mut Vec<u8>, s: &str) {
+        assert!(s.len() < 29);
+        buf.push(0x40 | s.len() as u8);
+        buf.extend_from_slice(s.as_bytes());
+    }
+
+    // A MaxMind DB of IPv4 with only 1 node, 0.0.0.0/1 is in US and 128.0.0.0/1 is not found
+    fn test_reader() -> Reader<Vec<u8>> {
+        let mut buf = Vec::new();
+
+        // Search tree, 24 bits records, the left one points to the first data, the right one equals to node_count
+        buf.extend_from_slice(&[0x00, 0x00, 0x11, 0x00, 0x00, 0x01]);
+        buf.extend_from_slice(&[0u8; 16]);
+
+        // {"country": {"iso_code": "US"}}
+        buf.push(0xE1);
+        push_string(&mut buf, "country");
+        buf.push(0xE1);
+        push_string(&mut buf, "iso_code");
+        push_string(&mut buf, "US");
+
+        buf.extend_from_slice(b"\xAB\xCD\xEFMaxMind.com");
+        buf.push(0xE9);
+        push_string(&mut buf, "binary_format_major_version");
+        buf.extend_from_slice(&[0xA1, 0x02]);
+        push_string(&mut buf, "binary_format_minor_version");
+        buf.push(0xA0);
+        push_string(&mut buf, "build_epoch");
+        buf.extend_from_slice(&[0x01, 0x02, 0x01]);
+        push_string(&mut buf, "database_type");
+        push_string(&mut buf, "Test-Country");
+        push_string(&mut buf, "description");
+        buf.push(0xE0);
+        push_string(&mut buf, "ip_version");
+        buf.extend_from_slice(&[0xA1, 0x04]);
+        push_string(&mut buf, "languages");
+        buf.extend_from_slice(&[0x00, 0x04]);
+        push_string(&mut buf, "node_count");
+        buf.extend_from_slice(&[0xC1, 0x01]);
+        push_string(&mut buf, "record_size");
+        buf.extend_from_slice(&[0xA1, 0x18]);
+
+        Reader::from_source(buf).unwrap()
+    }
+
+    fn test_geoip(policy: OutboundGeoIpPolicy) -> OutboundGeoIp {
+        OutboundGeoIp::new(test_reader(), &policy)
+    }
+
+    const US_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+    const UNKNOWN_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(200, 0, 0, 1));
+
+    #[test]
+    fn lookup_country() {
+        let geoip = test_geoip(OutboundGeoIpPolicy::Block(Vec::new()));
+        assert_eq!(geoip.country(US_IP), Some("US"));
+        assert_eq!(geoip.country(UNKNOWN_IP), None);
+    }
+
+    #[test]
+    fn block_countries() {
+        let geoip = test_geoip(OutboundGeoIpPolicy::Block(vec!["US".to_owned()]));
+        assert!(geoip.check_ip_blocked(US_IP));
+        assert!(!geoip.check_ip_blocked(UNKNOWN_IP));
+
+        let geoip = test_geoip(OutboundGeoIpPolicy::Block(vec!["CA".to_owned()]));
+        assert!(!geoip.check_ip_blocked(US_IP));
+    }
+
+    #[test]
+    fn allow_countries() {
+        let geoip = test_geoip(OutboundGeoIpPolicy::Allow(vec!["US".to_owned()]));
+        assert!(!geoip.check_ip_blocked(US_IP));
+        // Destinations not found in the database are blocked
+        assert!(geoip.check_ip_blocked(UNKNOWN_IP));
+
+        let geoip = test_geoip(OutboundGeoIpPolicy::Allow(vec!["CA".to_owned()]));
+        assert!(geoip.check_ip_blocked(US_IP));
+    }
+
+    struct FixedResolver(Vec<SocketAddr>);
+
+    impl DnsResolve for FixedResolver {
+        async fn resolve(&self, _addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(self.0.iter().map(|addr| SocketAddr::new(addr.ip(), port)).collect())
+        }
+    }
+
+    fn resolving_context(ips: &[IpAddr]) -> Context {
+        let mut context = Context::new(ServerType::Server);
+        let addrs = ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect();
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(FixedResolver(addrs))));
+        context
+    }
+
+    #[tokio::test]
+    async fn check_outbound_addresses() {
+        let geoip = test_geoip(OutboundGeoIpPolicy::Allow(vec!["US".to_owned()]));
+        let context = resolving_context(&[US_IP]);
+
+        assert_eq!(
+            geoip
+                .check_outbound(&context, &Address::SocketAddress(SocketAddr::new(US_IP, 443)))
+                .await,
+            CheckedOutbound::Allowed
+        );
+        assert_eq!(
+            geoip
+                .check_outbound(&context, &Address::SocketAddress(SocketAddr::new(UNKNOWN_IP, 443)))
+                .await,
+            CheckedOutbound::Blocked
+        );
+
+        // Connected by the checked addresses
+        let domain = Address::DomainNameAddress("example.com".to_owned(), 443);
+        assert_eq!(
+            geoip.check_outbound(&context, &domain).await,
+            CheckedOutbound::Resolved(vec![SocketAddr::new(US_IP, 443)])
+        );
+
+        // Blocked if any of the addresses is
+        let context = resolving_context(&[US_IP, UNKNOWN_IP]);
+        assert_eq!(geoip.check_outbound(&context, &domain).await, CheckedOutbound::Blocked);
+
+        let context = resolving_context(&[]);
+        assert_eq!(geoip.check_outbound(&context, &domain).await, CheckedOutbound::Blocked);
+    }
+}
@@ -1,10 +1,13 @@
 //! Shadowsocks server
 
-use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use std::{future::Future, io, net::SocketAddr, sync::Arc, time::Duration};
 
 use futures::future;
-use log::trace;
-use shadowsocks::net::{AcceptOpts, ConnectOpts, UdpSocketOpts};
+use log::{info, trace};
+use shadowsocks::{
+    net::{AcceptOpts, ConnectOpts, UdpSocketOpts},
+    relay::tcprelay::utils::set_plain_read_buffer_size_limit,
+};
 
 use crate::{
     config::{Config, ConfigType},
@@ -12,17 +15,38 @@ use crate::{
     utils::ServerHandle,
 };
 
+use self::{allow_list::ClientAllowListMatcher, conn_limit::ConnectionLimiter};
 pub use self::{
     server::{Server, ServerBuilder},
     tcprelay::TcpServer,
     udprelay::UdpServer,
 };
-
+pub use crate::net::proxy_protocol;
+
+pub mod accounting;
+pub mod allow_list;
+pub mod ban;
+#[cfg(target_os = "linux")]
+pub mod ban_firewall;
+pub mod conn_limit;
 pub mod context;
+#[cfg(feature = "server-geoip")]
+pub mod geoip;
+mod port_hopping;
+#[cfg(feature = "server-port-mapping")]
+pub mod port_mapping;
+pub mod quota;
+pub mod rate_limit;
+pub mod replay_source;
 #[allow(clippy::module_inception)]
 pub mod server;
+pub mod session;
+#[cfg(feature = "stats-report")]
+pub mod stats_report;
 mod tcprelay;
 mod udprelay;
+#[cfg(any(feature = "user-store-sqlite", feature = "user-store-redis"))]
+pub mod user_store;
 
 /// Default TCP Keep Alive timeout
 ///
@@ -31,11 +55,27 @@ pub(crate) const SERVER_DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_sec
 
 /// Starts a shadowsocks server
 pub async fn run(config: Config) -> io::Result<()> {
+    start(config).await?.await
+}
+
+/// Binds all listeners of a shadowsocks server, returns the future serving them
+///
+/// Errors of binding are returned before any connections are served.
+pub(crate) async fn start(mut config: Config) -> io::Result<impl Future<Output = io::Result<()>> + Send + 'static> {
     assert_eq!(config.config_type, ConfigType::Server);
     assert!(!config.server.is_empty());
 
+    config.apply_memory_profile();
     trace!("{:?}", config);
 
+    if let Some(size) = config.relay_buffer_size {
+        set_plain_read_buffer_size_limit(Some(size));
+    }
+
+    // Plugins are started while servers are built, before the process drops its own privileges
+    #[cfg(unix)]
+    crate::privilege::drop_plugin_privileges(config.user.as_deref(), config.group.as_deref())?;
+
     // Warning for Stream Ciphers
     #[cfg(feature = "stream-cipher")]
     for inst in config.server.iter() {
@@ -59,6 +99,21 @@ pub async fn run(config: Config) -> io::Result<()> {
         }
     }
 
+    if let Some(ref access_log) = config.access_log {
+        crate::access_log::init(access_log)?;
+    }
+    if let Some(ref flow_export) = config.flow_export {
+        crate::flow_export::init(flow_export)?;
+    }
+
+    // Listeners of the old process must be received before servers are bound
+    #[cfg(unix)]
+    if let Some(ref handover) = config.handover {
+        crate::handover::take_over(handover)?;
+    }
+    #[cfg(unix)]
+    let mut connection_stats = Vec::new();
+
     let mut servers = Vec::new();
 
     let mut connect_opts = ConnectOpts {
@@ -68,8 +123,14 @@ pub async fn run(config: Config) -> io::Result<()> {
         #[cfg(target_os = "android")]
         vpn_protect_path: config.outbound_vpn_protect_path,
 
+        #[cfg(all(any(target_os = "linux", target_os = "android"), feature = "socket-protect"))]
+        socket_protect: config.outbound_socket_protect,
+
+        connector: config.outbound_connector,
+
         bind_local_addr: config.outbound_bind_addr.map(|ip| SocketAddr::new(ip, 0)),
         bind_interface: config.outbound_bind_interface,
+        dscp: config.outbound_dscp,
 
         udp: UdpSocketOpts {
             allow_fragmentation: config.outbound_udp_allow_fragmentation,
@@ -87,6 +148,10 @@ pub async fn run(config: Config) -> io::Result<()> {
     connect_opts.tcp.keepalive = config.keep_alive.or(Some(SERVER_DEFAULT_KEEPALIVE_TIMEOUT));
     connect_opts.tcp.mptcp = config.mptcp;
     connect_opts.udp.mtu = config.udp_mtu;
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    {
+        connect_opts.uring = crate::net::utils::create_uring_driver();
+    }
 
     let mut accept_opts = AcceptOpts {
         ipv6_only: config.ipv6_only,
@@ -106,9 +171,41 @@ pub async fn run(config: Config) -> io::Result<()> {
 
     let acl = config.acl.map(Arc::new);
 
+    let client_allow_list = match config.client_allow_list {
+        None => None,
+        Some(ref allow_list) => Some(Arc::new(ClientAllowListMatcher::load(allow_list).await?)),
+    };
+
+    #[cfg(feature = "server-geoip")]
+    let outbound_geoip = match config.outbound_geoip {
+        None => None,
+        Some(ref geoip) => Some(Arc::new(self::geoip::OutboundGeoIp::open(geoip)?)),
+    };
+
+    let connection_limiter = config
+        .security
+        .connection_limit
+        .clone()
+        .map(|config| Arc::new(ConnectionLimiter::new(config)));
+
+    #[cfg(feature = "stats-report")]
+    let mut stats_reporter = config.stats_report.map(self::stats_report::StatsReporter::new);
+
+    #[cfg(feature = "server-port-mapping")]
+    let mut port_mapper = config.port_mapping.map(self::port_mapping::PortMapper::new);
+
+    // Subscribe before servers start, so their `server_started` events won't be missed
+    #[cfg(feature = "webhook")]
+    let webhooks = if config.webhooks.is_empty() {
+        None
+    } else {
+        Some(crate::webhook::WebhookDispatcher::new(config.webhooks))
+    };
+
     for inst in config.server {
         let svr_cfg = inst.config;
         let mut server_builder = ServerBuilder::new(svr_cfg);
+        server_builder.set_cancellation_token(config.cancellation_token.clone());
 
         if let Some(ref r) = resolver {
             server_builder.set_dns_resolver(r.clone());
@@ -134,9 +231,18 @@ pub async fn run(config: Config) -> io::Result<()> {
             connect_opts.udp.allow_fragmentation = udp_allow_fragmentation;
         }
 
+        if let Some(dscp) = inst.outbound_dscp {
+            connect_opts.dscp = Some(dscp);
+        }
+
         server_builder.set_connect_opts(connect_opts);
         server_builder.set_accept_opts(accept_opts);
 
+        #[cfg(unix)]
+        if let Some(n) = config.reuse_port_listeners {
+            server_builder.set_listener_count(n);
+        }
+
         if let Some(c) = config.udp_max_associations {
             server_builder.set_udp_capacity(c);
         }
@@ -156,27 +262,186 @@ pub async fn run(config: Config) -> io::Result<()> {
             }
         }
 
+        if let Some(ref allow_list) = client_allow_list {
+            server_builder.set_client_allow_list(allow_list.clone());
+        }
+
+        if let Some(ref limiter) = connection_limiter {
+            server_builder.set_connection_limiter(limiter.clone());
+        }
+
+        #[cfg(feature = "server-geoip")]
+        if let Some(ref geoip) = outbound_geoip {
+            server_builder.set_outbound_geoip(geoip.clone());
+        }
+
+        if config.proxy_protocol {
+            server_builder.set_proxy_protocol(config.proxy_protocol);
+        }
+
+        if config.bench_responder {
+            server_builder.set_bench_responder(config.bench_responder);
+        }
+
         if config.ipv6_first {
             server_builder.set_ipv6_first(config.ipv6_first);
         }
 
         server_builder.set_security_config(&config.security);
 
+        if let Some(entries) = config.replay_filter_capacity {
+            server_builder.set_replay_filter_capacity(entries);
+        }
+
+        if let Some(quota) = inst.quota {
+            server_builder.set_quota(quota);
+        }
+
+        #[cfg(any(feature = "user-store-sqlite", feature = "user-store-redis"))]
+        if let Some(user_store) = inst.user_store {
+            server_builder.set_user_store(user_store);
+        }
+
+        #[cfg(feature = "stats-report")]
+        if let Some(ref mut reporter) = stats_reporter {
+            reporter.add_server(
+                server_builder.server_config().addr().to_string(),
+                server_builder.flow_stat(),
+                server_builder.connection_stat(),
+            );
+        }
+
+        #[cfg(feature = "server-port-mapping")]
+        if let Some(ref mut mapper) = port_mapper {
+            use shadowsocks::config::ServerAddr;
+
+            use self::port_mapping::MappedProtocol;
+
+            let svr_cfg = server_builder.server_config();
+            let port = match svr_cfg.addr() {
+                ServerAddr::SocketAddr(sa) => sa.port(),
+                ServerAddr::DomainName(_, port) => *port,
+            };
+            if svr_cfg.port_hopping().is_some() {
+                log::warn!("port mapping skips server {} with `port_hopping`", svr_cfg.addr());
+            } else if svr_cfg.plugin().is_some() {
+                // Plugins may listen on TCP or UDP, whatever `mode` is
+                mapper.add_port(port, MappedProtocol::Tcp);
+                mapper.add_port(port, MappedProtocol::Udp);
+            } else {
+                let extra_ports = svr_cfg.extra_addrs().iter().map(|addr| match addr {
+                    ServerAddr::SocketAddr(sa) => sa.port(),
+                    ServerAddr::DomainName(_, port) => *port,
+                });
+                for port in std::iter::once(port).chain(extra_ports) {
+                    if svr_cfg.mode().enable_tcp() {
+                        mapper.add_port(port, MappedProtocol::Tcp);
+                    }
+                    if svr_cfg.mode().enable_udp() {
+                        mapper.add_port(port, MappedProtocol::Udp);
+                    }
+                }
+            }
+        }
+
+        #[cfg(unix)]
+        connection_stats.push(server_builder.connection_stat());
+
         let server = server_builder.build().await?;
         servers.push(server);
     }
 
-    if servers.len() == 1 {
-        let server = servers.pop().unwrap();
-        return server.run().await;
+    #[cfg(unix)]
+    let handover_listener = match config.handover {
+        Some(ref handover) => Some(crate::handover::HandoverListener::bind(handover)?),
+        None => None,
+    };
+
+    // All listeners are bound, plugins started by then have switched users by `drop_plugin_privileges`
+    #[cfg(unix)]
+    crate::privilege::drop_privileges(config.user.as_deref(), config.group.as_deref())?;
+    #[cfg(any(target_os = "linux", target_os = "openbsd"))]
+    if config.sandbox {
+        crate::sandbox::enable()?;
     }
+    #[cfg(target_os = "linux")]
+    crate::sd_notify::ready();
 
-    let mut vfut = Vec::with_capacity(servers.len());
+    #[cfg(feature = "metrics")]
+    let metrics_addr = config.metrics_addr;
+    #[cfg(unix)]
+    let drain_timeout = config.handover.map(|h| h.drain_timeout).unwrap_or_default();
 
-    for server in servers {
-        vfut.push(ServerHandle(tokio::spawn(async move { server.run().await })));
-    }
+    let cancellation_token = config.cancellation_token.clone();
+
+    Ok(async move {
+        let serve = async move {
+            // Services running alongside servers
+            let mut vfut = Vec::new();
+
+            #[cfg(feature = "metrics")]
+            if let Some(metrics_addr) = metrics_addr {
+                vfut.push(ServerHandle(tokio::spawn(crate::metrics::serve(metrics_addr))));
+            }
 
-    let (res, ..) = future::select_all(vfut).await;
-    res
+            #[cfg(feature = "stats-report")]
+            if let Some(reporter) = stats_reporter {
+                vfut.push(ServerHandle(tokio::spawn(reporter.run())));
+            }
+
+            #[cfg(feature = "server-port-mapping")]
+            if let Some(mapper) = port_mapper {
+                vfut.push(ServerHandle(tokio::spawn(mapper.run())));
+            }
+
+            #[cfg(feature = "webhook")]
+            if let Some(webhooks) = webhooks {
+                vfut.push(ServerHandle(tokio::spawn(webhooks.run())));
+            }
+
+            // A single server runs in this task without being spawned
+            #[cfg(unix)]
+            let single_server = servers.len() == 1 && handover_listener.is_none();
+            #[cfg(not(unix))]
+            let single_server = servers.len() == 1;
+            if single_server {
+                let server = servers.pop().unwrap();
+                if vfut.is_empty() {
+                    return server.run().await;
+                }
+                return tokio::select! {
+                    res = server.run() => res,
+                    (res, ..) = future::select_all(vfut) => res,
+                };
+            }
+
+            for server in servers {
+                vfut.push(ServerHandle(tokio::spawn(async move { server.run().await })));
+            }
+
+            #[cfg(unix)]
+            if let Some(handover_listener) = handover_listener {
+                tokio::select! {
+                    (res, ..) = future::select_all(vfut) => return res,
+                    res = handover_listener.wait_for_successor() => res?,
+                }
+
+                // Servers are stopped, connections accepted are still running
+                crate::handover::drain(&connection_stats, drain_timeout).await;
+                return Ok(());
+            }
+
+            let (res, ..) = future::select_all(vfut).await;
+            res
+        };
+
+        // Servers are aborted when `serve` is dropped
+        match cancellation_token.run_until_cancelled(serve).await {
+            Some(res) => res,
+            None => {
+                info!("server is shutdown by cancellation");
+                Ok(())
+            }
+        }
+    })
 }
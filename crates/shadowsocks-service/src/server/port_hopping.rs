@@ -0,0 +1,142 @@
+//! Port hopping of servers
+//!
+//! Servers with `port_hopping` listen on ports derived from a shared secret and the current time slot, instead of the
+//! port of `server_port`. Clients derive the same ports, so they could follow the server without any signaling. Ports
+//! of adjacent slots are also listened in the overlap around every hop, tolerating clock skews of clients.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, error, info};
+use shadowsocks::{
+    config::{ServerAddr, ServerConfig},
+    net::AcceptOpts,
+};
+use tokio::time;
+
+use crate::utils::ServerHandle;
+
+use super::{context::ServiceContext, tcprelay::TcpServer, udprelay::UdpServer};
+
+/// Interval of retrying ports failed to bind
+const PORT_BIND_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Listeners of a hopping server, following the ports of the current time slot
+pub struct PortHoppingServer {
+    context: Arc<ServiceContext>,
+    svr_cfg: Arc<ServerConfig>,
+    udp_expiry_duration: Option<Duration>,
+    udp_capacity: Option<usize>,
+    accept_opts: AcceptOpts,
+}
+
+impl PortHoppingServer {
+    /// Create with a server configuration which has `port_hopping` enabled
+    pub fn new(
+        context: Arc<ServiceContext>,
+        svr_cfg: Arc<ServerConfig>,
+        udp_expiry_duration: Option<Duration>,
+        udp_capacity: Option<usize>,
+        accept_opts: AcceptOpts,
+    ) -> PortHoppingServer {
+        assert!(svr_cfg.port_hopping().is_some());
+
+        PortHoppingServer {
+            context,
+            svr_cfg,
+            udp_expiry_duration,
+            udp_capacity,
+            accept_opts,
+        }
+    }
+
+    /// Keep listening on ports of the current time slot
+    ///
+    /// Listeners of expired ports are closed, connections accepted by them are kept until they finish.
+    pub async fn run(self) -> io::Result<()> {
+        let port_hopping = self.svr_cfg.port_hopping().expect("port_hopping").clone();
+        let mut listeners: BTreeMap<u16, Vec<ServerHandle>> = BTreeMap::new();
+
+        loop {
+            let (ports, next_change) = port_hopping.listening_ports_at(SystemTime::now());
+            let mut failed = false;
+
+            listeners.retain(|port, _| {
+                let keep = ports.contains(port);
+                if !keep {
+                    debug!(
+                        "server {} stopped listening on hopped port {}",
+                        self.svr_cfg.addr(),
+                        port
+                    );
+                }
+                keep
+            });
+
+            for port in ports {
+                if listeners.contains_key(&port) {
+                    continue;
+                }
+
+                match self.listen(port).await {
+                    Ok(handles) => {
+                        info!("server {} listening on hopped port {}", self.svr_cfg.addr(), port);
+                        listeners.insert(port, handles);
+                    }
+                    Err(err) => {
+                        failed = true;
+                        error!(
+                            "server {} failed to listen on hopped port {}, error: {}",
+                            self.svr_cfg.addr(),
+                            port,
+                            err
+                        );
+                    }
+                }
+            }
+
+            // Ports failed to bind, probably still in use, are retried soon
+            let next_change = if failed {
+                next_change.min(PORT_BIND_RETRY_INTERVAL)
+            } else {
+                next_change
+            };
+            time::sleep(next_change).await;
+        }
+    }
+
+    async fn listen(&self, port: u16) -> io::Result<Vec<ServerHandle>> {
+        let mut svr_cfg = ServerConfig::clone(&self.svr_cfg);
+        svr_cfg.set_addr(match *self.svr_cfg.addr() {
+            ServerAddr::SocketAddr(ref addr) => ServerAddr::SocketAddr(SocketAddr::new(addr.ip(), port)),
+            ServerAddr::DomainName(ref domain, ..) => ServerAddr::DomainName(domain.clone(), port),
+        });
+        let svr_cfg = Arc::new(svr_cfg);
+
+        let mut handles = Vec::new();
+
+        if svr_cfg.mode().enable_tcp() {
+            let server = TcpServer::new(self.context.clone(), svr_cfg.clone(), self.accept_opts.clone()).await?;
+            handles.push(ServerHandle(tokio::spawn(server.run())));
+        }
+
+        if svr_cfg.mode().enable_udp() {
+            let server = UdpServer::new(
+                self.context.clone(),
+                svr_cfg,
+                self.udp_expiry_duration,
+                self.udp_capacity,
+                self.accept_opts.clone(),
+            )
+            .await?;
+            handles.push(ServerHandle(tokio::spawn(server.run())));
+        }
+
+        Ok(handles)
+    }
+}
@@ -0,0 +1,528 @@
+//! Port mappings of servers on the NAT gateway
+//!
+//! Servers hosted behind home routers request mappings of their ports from the gateway, by NAT-PMP (RFC 6886),
+//! PCP (RFC 6887) or UPnP IGD, and keep renewing them before their leases expire. Mappings are left to expire on the
+//! gateway when servers stop, except on UPnP gateways which only accept permanent mappings.
+
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use tokio::{net::UdpSocket, time};
+
+use crate::{
+    config::{PortMappingConfig, PortMappingProtocol},
+    net::http_client,
+};
+
+/// Default lifetime of mappings
+pub const DEFAULT_PORT_MAPPING_LEASE: Duration = Duration::from_secs(3600);
+/// Retry interval after failures
+const PORT_MAPPING_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+const NATPMP_PORT: u16 = 5351;
+const PCP_VERSION: u8 = 2;
+const PCP_OPCODE_MAP: u8 = 1;
+const PCP_RESULT_UNSUPP_VERSION: u8 = 1;
+
+const SSDP_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+const SSDP_TIMEOUT: Duration = Duration::from_secs(3);
+const UPNP_MAX_DESCRIPTION_SIZE: usize = 1024 * 1024;
+const UPNP_SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// Transport protocol of mapped ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappedProtocol {
+    Tcp,
+    Udp,
+}
+
+impl MappedProtocol {
+    fn as_upnp(self) -> &'static str {
+        match self {
+            MappedProtocol::Tcp => "TCP",
+            MappedProtocol::Udp => "UDP",
+        }
+    }
+
+    fn natpmp_opcode(self) -> u8 {
+        match self {
+            MappedProtocol::Udp => 1,
+            MappedProtocol::Tcp => 2,
+        }
+    }
+
+    fn iana_number(self) -> u8 {
+        match self {
+            MappedProtocol::Tcp => 6,
+            MappedProtocol::Udp => 17,
+        }
+    }
+}
+
+/// Gateway found by one of the protocols
+enum Gateway {
+    NatPmp {
+        addr: SocketAddr,
+        local_ip: IpAddr,
+    },
+    Upnp {
+        control_url: String,
+        service_type: &'static str,
+        local_ip: IpAddr,
+    },
+}
+
+/// Requests port mappings of servers from the gateway, and renews them periodically
+pub struct PortMapper {
+    config: PortMappingConfig,
+    ports: Vec<(u16, MappedProtocol)>,
+}
+
+impl PortMapper {
+    /// Create a mapper without any ports
+    pub fn new(config: PortMappingConfig) -> PortMapper {
+        PortMapper {
+            config,
+            ports: Vec::new(),
+        }
+    }
+
+    /// Map `port` of `protocol` to the same port on the gateway
+    pub fn add_port(&mut self, port: u16, protocol: MappedProtocol) {
+        if !self.ports.contains(&(port, protocol)) {
+            self.ports.push((port, protocol));
+        }
+    }
+
+    /// Keep ports mapped until the task is aborted
+    pub async fn run(self) -> io::Result<()> {
+        if self.ports.is_empty() {
+            return Ok(());
+        }
+
+        let lease = self.config.lease.unwrap_or(DEFAULT_PORT_MAPPING_LEASE);
+        loop {
+            let gateway = match self.discover().await {
+                Ok(g) => g,
+                Err(err) => {
+                    warn!("port mapping couldn't find any gateway, error: {}", err);
+                    time::sleep(PORT_MAPPING_RETRY_INTERVAL).await;
+                    continue;
+                }
+            };
+
+            // Renew at half of the shortest lease granted
+            let mut renew = lease / 2;
+            for &(port, protocol) in &self.ports {
+                match map_port(&gateway, port, protocol, lease).await {
+                    Ok(granted) => {
+                        debug!(
+                            "port mapping {} {} granted for {}s",
+                            protocol.as_upnp(),
+                            port,
+                            granted.as_secs()
+                        );
+                        if !granted.is_zero() {
+                            renew = renew.min(granted / 2);
+                        }
+                    }
+                    Err(err) => {
+                        warn!("port mapping {} {} failed, error: {}", protocol.as_upnp(), port, err);
+                        renew = renew.min(PORT_MAPPING_RETRY_INTERVAL);
+                    }
+                }
+            }
+
+            time::sleep(renew.max(Duration::from_secs(1))).await;
+        }
+    }
+
+    async fn discover(&self) -> io::Result<Gateway> {
+        match self.config.protocol {
+            PortMappingProtocol::NatPmp => self.discover_natpmp().await,
+            PortMappingProtocol::Upnp => discover_upnp().await,
+            PortMappingProtocol::Auto => match self.discover_natpmp().await {
+                Ok(g) => Ok(g),
+                Err(err) => {
+                    debug!("port mapping by NAT-PMP unavailable, error: {}, trying UPnP", err);
+                    discover_upnp().await
+                }
+            },
+        }
+    }
+
+    async fn discover_natpmp(&self) -> io::Result<Gateway> {
+        let gateway = match self.config.gateway {
+            Some(ip) => ip,
+            None => default_gateway()?,
+        };
+        let addr = SocketAddr::new(gateway, NATPMP_PORT);
+        let local_ip = local_ip_to(addr).await?;
+
+        // Probe with a request of external address, gateways without NAT-PMP won't respond
+        let socket = connect_udp(addr).await?;
+        let response = natpmp_request(&socket, &[0, 0]).await?;
+        if response.len() < 12 || response[1] != 128 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "invalid NAT-PMP response"));
+        }
+        let external_ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+        info!(
+            "port mapping by NAT-PMP via gateway {}, external address {}",
+            gateway, external_ip
+        );
+
+        Ok(Gateway::NatPmp { addr, local_ip })
+    }
+}
+
+/// Request a mapping from `port` to the same external port, returns the lease granted, 0 if permanent
+async fn map_port(gateway: &Gateway, port: u16, protocol: MappedProtocol, lease: Duration) -> io::Result<Duration> {
+    match *gateway {
+        Gateway::NatPmp { addr, local_ip } => {
+            let socket = connect_udp(addr).await?;
+            match pcp_map(&socket, local_ip, port, protocol, lease).await {
+                Ok(Some(granted)) => Ok(granted),
+                Ok(None) => natpmp_map(&socket, port, protocol, lease).await,
+                Err(err) => Err(err),
+            }
+        }
+        Gateway::Upnp {
+            ref control_url,
+            service_type,
+            local_ip,
+        } => {
+            match upnp_map(control_url, service_type, local_ip, port, protocol, lease).await {
+                // IGDv1 gateways may only accept leases of 0 (permanent), error 725 OnlyPermanentLeasesSupported
+                Err(err) => {
+                    debug!(
+                        "port mapping {} {} failed, error: {}, trying permanent lease",
+                        protocol.as_upnp(),
+                        port,
+                        err
+                    );
+                    upnp_map(control_url, service_type, local_ip, port, protocol, Duration::ZERO).await
+                }
+                result => result,
+            }
+        }
+    }
+}
+
+async fn connect_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let bind_addr = match addr {
+        SocketAddr::V4(..) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(..) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+/// Local address used for reaching `addr`, which is the internal address of mappings
+async fn local_ip_to(addr: SocketAddr) -> io::Result<IpAddr> {
+    Ok(connect_udp(addr).await?.local_addr()?.ip())
+}
+
+/// Send `request` and wait for a response, retransmitted with doubled timeouts from 250ms as RFC 6886 recommends
+async fn natpmp_request(socket: &UdpSocket, request: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buffer = [0u8; 1100];
+    let mut timeout = Duration::from_millis(250);
+    for _ in 0..4 {
+        socket.send(request).await?;
+        match time::timeout(timeout, socket.recv(&mut buffer)).await {
+            Ok(Ok(n)) => return Ok(buffer[..n].to_vec()),
+            Ok(Err(err)) => return Err(err),
+            Err(..) => timeout *= 2,
+        }
+    }
+    Err(io::Error::new(ErrorKind::TimedOut, "gateway didn't respond"))
+}
+
+/// NAT-PMP MAP request, RFC 6886 section 3.3
+async fn natpmp_map(socket: &UdpSocket, port: u16, protocol: MappedProtocol, lease: Duration) -> io::Result<Duration> {
+    let mut request = Vec::with_capacity(12);
+    request.extend_from_slice(&[0, protocol.natpmp_opcode(), 0, 0]);
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&(lease.as_secs().min(u32::MAX as u64) as u32).to_be_bytes());
+
+    let response = natpmp_request(socket, &request).await?;
+    if response.len() < 16 || response[1] != 128 + protocol.natpmp_opcode() {
+        return Err(io::Error::new(ErrorKind::InvalidData, "invalid NAT-PMP response"));
+    }
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    if result != 0 {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!("NAT-PMP result code {result}"),
+        ));
+    }
+    check_external_port(port, u16::from_be_bytes([response[10], response[11]]))?;
+    Ok(Duration::from_secs(
+        u32::from_be_bytes([response[12], response[13], response[14], response[15]]) as u64,
+    ))
+}
+
+/// PCP MAP request, RFC 6887 section 11, returns `None` if the gateway only supports NAT-PMP
+async fn pcp_map(
+    socket: &UdpSocket,
+    local_ip: IpAddr,
+    port: u16,
+    protocol: MappedProtocol,
+    lease: Duration,
+) -> io::Result<Option<Duration>> {
+    let client_ip = match local_ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    let nonce: [u8; 12] = rand::random();
+
+    let mut request = Vec::with_capacity(60);
+    request.extend_from_slice(&[PCP_VERSION, PCP_OPCODE_MAP, 0, 0]);
+    request.extend_from_slice(&(lease.as_secs().min(u32::MAX as u64) as u32).to_be_bytes());
+    request.extend_from_slice(&client_ip.octets());
+    request.extend_from_slice(&nonce);
+    request.extend_from_slice(&[protocol.iana_number(), 0, 0, 0]);
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    let any_ip = match local_ip {
+        IpAddr::V4(..) => Ipv4Addr::UNSPECIFIED.to_ipv6_mapped(),
+        IpAddr::V6(..) => Ipv6Addr::UNSPECIFIED,
+    };
+    request.extend_from_slice(&any_ip.octets());
+
+    let response = natpmp_request(socket, &request).await?;
+    // NAT-PMP gateways respond with version 0, or UNSUPP_VERSION
+    if response.len() >= 4 && (response[0] == 0 || response[3] == PCP_RESULT_UNSUPP_VERSION) {
+        return Ok(None);
+    }
+    if response.len() < 60 || response[1] != (0x80 | PCP_OPCODE_MAP) || response[24..36] != nonce {
+        return Err(io::Error::new(ErrorKind::InvalidData, "invalid PCP response"));
+    }
+    if response[3] != 0 {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!("PCP result code {}", response[3]),
+        ));
+    }
+    check_external_port(port, u16::from_be_bytes([response[42], response[43]]))?;
+    Ok(Some(Duration::from_secs(
+        u32::from_be_bytes([response[4], response[5], response[6], response[7]]) as u64,
+    )))
+}
+
+fn check_external_port(port: u16, external_port: u16) -> io::Result<()> {
+    if port != external_port {
+        // Clients couldn't find the server on another port, leave it to expire
+        return Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            format!("gateway mapped external port {external_port} instead of {port}"),
+        ));
+    }
+    Ok(())
+}
+
+/// Gateway of the default route, from `/proc/net/route`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn default_gateway() -> io::Result<IpAddr> {
+    let routes = std::fs::read_to_string("/proc/net/route")?;
+    for line in routes.lines().skip(1) {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            continue;
+        }
+        if let Ok(gateway) = u32::from_str_radix(fields[2], 16) {
+            if gateway != 0 {
+                // Written in host byte order
+                return Ok(IpAddr::V4(Ipv4Addr::from(gateway.to_ne_bytes())));
+            }
+        }
+    }
+    Err(io::Error::new(ErrorKind::NotFound, "no default gateway"))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn default_gateway() -> io::Result<IpAddr> {
+    Err(io::Error::new(
+        ErrorKind::NotFound,
+        "default gateway is unknown, set `port_mapping.gateway`",
+    ))
+}
+
+/// Search an Internet Gateway Device by SSDP, and find its WAN connection service
+async fn discover_upnp() -> io::Result<Gateway> {
+    let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)).await?;
+    let request = "M-SEARCH * HTTP/1.1\r\n\
+                   HOST: 239.255.255.250:1900\r\n\
+                   MAN: \"ssdp:discover\"\r\n\
+                   MX: 2\r\n\
+                   ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+    socket.send_to(request.as_bytes(), SSDP_ADDR).await?;
+
+    let mut buffer = [0u8; 2048];
+    let (n, peer_addr) = match time::timeout(SSDP_TIMEOUT, socket.recv_from(&mut buffer)).await {
+        Ok(result) => result?,
+        Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "no UPnP gateway responded")),
+    };
+    let response = String::from_utf8_lossy(&buffer[..n]);
+    let location = match http_header(&response, "location") {
+        Some(l) => l.to_owned(),
+        None => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "missing LOCATION in SSDP response",
+            ));
+        }
+    };
+
+    let description = http_client::get(&location, UPNP_MAX_DESCRIPTION_SIZE).await?;
+    let description = String::from_utf8_lossy(&description);
+    let (service_type, control_url) = match find_wan_service(&description) {
+        Some(s) => s,
+        None => {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("gateway {peer_addr} doesn't have WAN connection services"),
+            ));
+        }
+    };
+    let control_url = resolve_url(&location, control_url);
+    let local_ip = local_ip_to(peer_addr).await?;
+    info!("port mapping by UPnP via gateway {}", location);
+
+    Ok(Gateway::Upnp {
+        control_url,
+        service_type,
+        local_ip,
+    })
+}
+
+fn http_header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// `(serviceType, controlURL)` of the first WAN connection service in a device description
+fn find_wan_service(description: &str) -> Option<(&'static str, &str)> {
+    for service_type in UPNP_SERVICE_TYPES {
+        let Some(pos) = description.find(&format!("<serviceType>{service_type}</serviceType>")) else {
+            continue;
+        };
+        let service = &description[pos..];
+        let service = &service[..service.find("</service>").unwrap_or(service.len())];
+        if let Some(control_url) = xml_element(service, "controlURL") {
+            return Some((service_type, control_url));
+        }
+    }
+    None
+}
+
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{name}>"))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{name}>"))?;
+    Some(xml[start..end].trim())
+}
+
+/// Resolve `url` relative to the description's `location`
+fn resolve_url(location: &str, url: &str) -> String {
+    if url.starts_with("http://") {
+        return url.to_owned();
+    }
+    let authority_end = location
+        .strip_prefix("http://")
+        .and_then(|rest| rest.find('/'))
+        .map(|pos| pos + "http://".len())
+        .unwrap_or(location.len());
+    if url.starts_with('/') {
+        format!("{}{}", &location[..authority_end], url)
+    } else {
+        format!("{}/{}", &location[..authority_end], url)
+    }
+}
+
+async fn upnp_map(
+    control_url: &str,
+    service_type: &str,
+    local_ip: IpAddr,
+    port: u16,
+    protocol: MappedProtocol,
+    lease: Duration,
+) -> io::Result<Duration> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:AddPortMapping xmlns:u=\"{service_type}\">\
+         <NewRemoteHost></NewRemoteHost>\
+         <NewExternalPort>{port}</NewExternalPort>\
+         <NewProtocol>{protocol}</NewProtocol>\
+         <NewInternalPort>{port}</NewInternalPort>\
+         <NewInternalClient>{local_ip}</NewInternalClient>\
+         <NewEnabled>1</NewEnabled>\
+         <NewPortMappingDescription>shadowsocks</NewPortMappingDescription>\
+         <NewLeaseDuration>{lease}</NewLeaseDuration>\
+         </u:AddPortMapping></s:Body></s:Envelope>",
+        protocol = protocol.as_upnp(),
+        lease = lease.as_secs().min(u32::MAX as u64),
+    );
+    let action = format!("\"{service_type}#AddPortMapping\"");
+    let headers = [
+        ("content-type", "text/xml; charset=\"utf-8\""),
+        ("soapaction", action.as_str()),
+    ];
+
+    http_client::post(control_url, &headers, body.into_bytes()).await?;
+    Ok(lease)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn upnp_description() {
+        let description = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+  <device>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+        <controlURL>/ctl/L3F</controlURL>
+      </service>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+        <controlURL>/ctl/IPConn</controlURL>
+      </service>
+    </serviceList>
+  </device>
+</root>"#;
+
+        let (service_type, control_url) = find_wan_service(description).unwrap();
+        assert_eq!(service_type, "urn:schemas-upnp-org:service:WANIPConnection:1");
+        assert_eq!(control_url, "/ctl/IPConn");
+        assert_eq!(
+            resolve_url("http://192.168.1.1:5000/rootDesc.xml", control_url),
+            "http://192.168.1.1:5000/ctl/IPConn"
+        );
+
+        let response = "HTTP/1.1 200 OK\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(
+            http_header(response, "location"),
+            Some("http://192.168.1.1:5000/rootDesc.xml")
+        );
+    }
+}
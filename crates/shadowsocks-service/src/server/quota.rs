@@ -0,0 +1,445 @@
+//! Traffic quota enforcement
+//!
+//! Once the traffic of a server or an EIH user exceeds its quota, new connections will be rejected
+//! until the quota is reset. Established connections will also be closed if `terminate` is enabled.
+
+use std::{
+    collections::{HashMap, HashSet},
+    future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::{sync::Notify, time};
+
+use crate::{
+    config::TrafficQuotaConfig,
+    events::{self, Event},
+    net::FlowStat,
+};
+
+use super::accounting::TrafficAccounting;
+
+/// Interval of checking quotas
+pub const QUOTA_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default)]
+struct QuotaState {
+    // Quotas of users, could be replaced at runtime
+    user_quotas: HashMap<String, u64>,
+
+    // Traffic that has been transferred before the last reset
+    server_base: u64,
+    user_base: HashMap<String, u64>,
+
+    // Traffic used by the replaced quota of the same server since the last reset
+    server_carried: u64,
+    user_carried: HashMap<String, u64>,
+
+    server_exceeded: bool,
+    users_exceeded: HashSet<String>,
+}
+
+/// Traffic quota of a server
+#[derive(Debug)]
+pub struct TrafficQuota {
+    config: TrafficQuotaConfig,
+    server: String,
+    flow_stat: Arc<FlowStat>,
+    accounting: Arc<TrafficAccounting>,
+    state: Mutex<QuotaState>,
+    notify: Notify,
+}
+
+impl TrafficQuota {
+    /// Create a quota of `server` that is checked against `flow_stat` and `accounting`
+    pub fn new(
+        config: TrafficQuotaConfig,
+        server: String,
+        flow_stat: Arc<FlowStat>,
+        accounting: Arc<TrafficAccounting>,
+    ) -> TrafficQuota {
+        let state = QuotaState {
+            user_quotas: config.users.clone(),
+            ..Default::default()
+        };
+
+        TrafficQuota {
+            config,
+            server,
+            flow_stat,
+            accounting,
+            state: Mutex::new(state),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Quota configuration
+    ///
+    /// Users' quotas may have been replaced by `set_user_quotas`.
+    pub fn config(&self) -> &TrafficQuotaConfig {
+        &self.config
+    }
+
+    /// Replace quotas of all users
+    ///
+    /// Users that are no longer limited, or whose quotas were raised, won't be rejected anymore.
+    pub fn set_user_quotas(&self, user_quotas: HashMap<String, u64>) {
+        let mut state = self.state.lock().unwrap();
+        let (_, users_used) = self.used_since(&state);
+
+        state.users_exceeded.retain(|name| match user_quotas.get(name) {
+            Some(quota) => users_used.get(name).copied().unwrap_or(0) >= *quota,
+            None => false,
+        });
+        state.user_quotas = user_quotas;
+    }
+
+    /// Check if the server's quota is exceeded
+    pub fn is_server_exceeded(&self) -> bool {
+        self.state.lock().unwrap().server_exceeded
+    }
+
+    /// Check if the quota of `user` is exceeded
+    pub fn is_user_exceeded(&self, user: &str) -> bool {
+        self.state.lock().unwrap().users_exceeded.contains(user)
+    }
+
+    /// Bytes transferred since the last reset, of the whole server and each user
+    pub fn used(&self) -> (u64, HashMap<String, u64>) {
+        let state = self.state.lock().unwrap();
+        self.used_since(&state)
+    }
+
+    fn used_since(&self, state: &QuotaState) -> (u64, HashMap<String, u64>) {
+        let server =
+            (self.flow_stat.tx() + self.flow_stat.rx()).saturating_sub(state.server_base) + state.server_carried;
+
+        let mut users = state.user_carried.clone();
+        for (name, traffic) in self.accounting.current().users {
+            let base = state.user_base.get(&name).copied().unwrap_or(0);
+            *users.entry(name).or_insert(0) += traffic.total().saturating_sub(base);
+        }
+
+        (server, users)
+    }
+
+    /// Continue counting from the traffic used by `previous`, the quota of the same server that is replaced by this one
+    ///
+    /// Exceeded states are kept if the traffic still exceeds quotas of this one, so they won't be published again.
+    pub fn carry_over(&self, previous: &TrafficQuota) {
+        let (server_used, users_used, server_exceeded, users_exceeded) = {
+            let state = previous.state.lock().unwrap();
+            let (server_used, users_used) = previous.used_since(&state);
+            (
+                server_used,
+                users_used,
+                state.server_exceeded,
+                state.users_exceeded.clone(),
+            )
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.server_exceeded = server_exceeded && self.config.server.is_some_and(|quota| server_used >= quota);
+        state.users_exceeded = users_exceeded
+            .into_iter()
+            .filter(|name| match state.user_quotas.get(name) {
+                Some(quota) => users_used.get(name).copied().unwrap_or(0) >= *quota,
+                None => false,
+            })
+            .collect();
+        state.server_carried = server_used;
+        state.user_carried = users_used;
+    }
+
+    /// Check current traffic against quotas
+    pub fn check(&self) {
+        // Traffic is read under the lock, concurrent `reset` or `check` couldn't interleave with it
+        let mut state = self.state.lock().unwrap();
+        let (server_used, users_used) = self.used_since(&state);
+
+        let mut exceeded = false;
+
+        if let Some(quota) = self.config.server {
+            if !state.server_exceeded && server_used >= quota {
+                warn!(
+                    "server {} traffic {} bytes exceeded quota {} bytes",
+                    self.server, server_used, quota
+                );
+                state.server_exceeded = true;
+                exceeded = true;
+
+                events::publish(|| Event::QuotaExceeded {
+                    server: self.server.clone(),
+                    user: None,
+                });
+            }
+        }
+
+        let QuotaState {
+            ref user_quotas,
+            ref mut users_exceeded,
+            ..
+        } = *state;
+        for (name, quota) in user_quotas {
+            let used = users_used.get(name).copied().unwrap_or(0);
+            if used >= *quota && users_exceeded.insert(name.clone()) {
+                warn!(
+                    "server {} user {} traffic {} bytes exceeded quota {} bytes",
+                    self.server, name, used, quota
+                );
+                exceeded = true;
+
+                events::publish(|| Event::QuotaExceeded {
+                    server: self.server.clone(),
+                    user: Some(name.clone()),
+                });
+            }
+        }
+
+        drop(state);
+
+        if exceeded && self.config.terminate {
+            self.notify.notify_waiters();
+        }
+    }
+
+    /// Reset quotas, traffic that has been transferred won't be counted anymore
+    pub fn reset(&self) {
+        let current = self.accounting.current();
+
+        let mut state = self.state.lock().unwrap();
+        state.server_base = self.flow_stat.tx() + self.flow_stat.rx();
+        state.user_base = current
+            .users
+            .into_iter()
+            .map(|(name, traffic)| (name, traffic.total()))
+            .collect();
+        state.server_carried = 0;
+        state.user_carried.clear();
+        state.server_exceeded = false;
+        state.users_exceeded.clear();
+
+        info!("traffic quota reset");
+    }
+
+    /// Wait until the connection of `user` should be terminated
+    ///
+    /// Never completes if `terminate` is not enabled.
+    pub async fn wait_terminate(&self, user: Option<&str>) {
+        if !self.config.terminate {
+            return future::pending().await;
+        }
+
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if self.is_server_exceeded() || user.is_some_and(|u| self.is_user_exceeded(u)) {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Check quotas every `QUOTA_CHECK_INTERVAL`
+    pub async fn run_check(self: Arc<Self>) {
+        let mut interval = time::interval(QUOTA_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.check();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_quota(config: TrafficQuotaConfig) -> (TrafficQuota, Arc<FlowStat>, Arc<TrafficAccounting>) {
+        let flow_stat = Arc::new(FlowStat::new());
+        let accounting = Arc::new(TrafficAccounting::new());
+        let quota = TrafficQuota::new(config, "test".to_owned(), flow_stat.clone(), accounting.clone());
+        (quota, flow_stat, accounting)
+    }
+
+    fn user_quota_config(user: &str, quota: u64) -> TrafficQuotaConfig {
+        TrafficQuotaConfig {
+            users: [(user.to_owned(), quota)].into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn check_server_quota() {
+        let (quota, flow_stat, _) = test_quota(TrafficQuotaConfig {
+            server: Some(100),
+            ..Default::default()
+        });
+
+        flow_stat.incr_tx(60);
+        quota.check();
+        assert!(!quota.is_server_exceeded());
+
+        flow_stat.incr_rx(40);
+        quota.check();
+        assert!(quota.is_server_exceeded());
+        assert_eq!(quota.used().0, 100);
+
+        // Traffic before resetting is not counted anymore
+        quota.reset();
+        assert!(!quota.is_server_exceeded());
+        assert_eq!(quota.used().0, 0);
+
+        flow_stat.incr_tx(99);
+        quota.check();
+        assert!(!quota.is_server_exceeded());
+    }
+
+    #[test]
+    fn check_user_quota() {
+        let (quota, _, accounting) = test_quota(user_quota_config("alice", 100));
+
+        accounting.user_flow_stat("alice").incr_tx(100);
+        accounting.user_flow_stat("bob").incr_tx(1000);
+        quota.check();
+        assert!(quota.is_user_exceeded("alice"));
+        assert!(!quota.is_user_exceeded("bob"));
+        assert!(!quota.is_server_exceeded());
+
+        quota.reset();
+        assert!(!quota.is_user_exceeded("alice"));
+        assert_eq!(quota.used().1.get("alice").copied(), Some(0));
+    }
+
+    #[test]
+    fn set_user_quotas() {
+        let (quota, _, accounting) = test_quota(user_quota_config("alice", 100));
+
+        accounting.user_flow_stat("alice").incr_tx(150);
+        accounting.user_flow_stat("bob").incr_tx(150);
+        quota.check();
+        assert!(quota.is_user_exceeded("alice"));
+
+        // Still exceeded with a lower quota
+        quota.set_user_quotas([("alice".to_owned(), 120)].into_iter().collect());
+        assert!(quota.is_user_exceeded("alice"));
+
+        // Raised quota
+        quota.set_user_quotas(
+            [("alice".to_owned(), 200), ("bob".to_owned(), 100)]
+                .into_iter()
+                .collect(),
+        );
+        assert!(!quota.is_user_exceeded("alice"));
+        assert!(!quota.is_user_exceeded("bob"));
+
+        quota.check();
+        assert!(!quota.is_user_exceeded("alice"));
+        assert!(quota.is_user_exceeded("bob"));
+
+        // No longer limited
+        quota.set_user_quotas(HashMap::new());
+        assert!(!quota.is_user_exceeded("bob"));
+    }
+
+    #[test]
+    fn carry_over() {
+        let config = TrafficQuotaConfig {
+            server: Some(1000),
+            users: [("alice".to_owned(), 100), ("bob".to_owned(), 100)]
+                .into_iter()
+                .collect(),
+            terminate: false,
+        };
+        let (previous, flow_stat, accounting) = test_quota(config.clone());
+        flow_stat.incr_tx(500);
+        accounting.user_flow_stat("alice").incr_tx(100);
+        accounting.user_flow_stat("bob").incr_tx(50);
+        previous.check();
+        assert!(previous.is_user_exceeded("alice"));
+
+        // Server is rebuilt with new statistics
+        let (quota, flow_stat, accounting) = test_quota(config);
+        quota.carry_over(&previous);
+        assert!(quota.is_user_exceeded("alice"));
+        assert!(!quota.is_user_exceeded("bob"));
+
+        let (server_used, users_used) = quota.used();
+        assert_eq!(server_used, 500);
+        assert_eq!(users_used.get("alice").copied(), Some(100));
+        assert_eq!(users_used.get("bob").copied(), Some(50));
+
+        flow_stat.incr_tx(500);
+        accounting.user_flow_stat("bob").incr_tx(50);
+        quota.check();
+        assert!(quota.is_server_exceeded());
+        assert!(quota.is_user_exceeded("bob"));
+
+        // Carried traffic is cleared by resetting
+        quota.reset();
+        let (server_used, users_used) = quota.used();
+        assert_eq!(server_used, 0);
+        assert_eq!(users_used.get("alice").copied(), None);
+        assert_eq!(users_used.get("bob").copied(), Some(0));
+    }
+
+    #[test]
+    fn carry_over_raised_quota() {
+        let (previous, _, accounting) = test_quota(user_quota_config("alice", 100));
+        accounting.user_flow_stat("alice").incr_tx(100);
+        previous.check();
+        assert!(previous.is_user_exceeded("alice"));
+
+        let (quota, _, _) = test_quota(user_quota_config("alice", 200));
+        quota.carry_over(&previous);
+        assert!(!quota.is_user_exceeded("alice"));
+        assert_eq!(quota.used().1.get("alice").copied(), Some(100));
+    }
+
+    #[tokio::test]
+    async fn wait_terminate() {
+        let (quota, flow_stat, _) = test_quota(TrafficQuotaConfig {
+            server: Some(100),
+            users: HashMap::new(),
+            terminate: true,
+        });
+        let quota = Arc::new(quota);
+
+        let waiter = {
+            let quota = quota.clone();
+            tokio::spawn(async move { quota.wait_terminate(None).await })
+        };
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+
+        flow_stat.incr_tx(100);
+        quota.check();
+        time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+
+        // Exceeded already
+        time::timeout(Duration::from_secs(1), quota.wait_terminate(Some("alice")))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn wait_terminate_disabled() {
+        let (quota, flow_stat, _) = test_quota(TrafficQuotaConfig {
+            server: Some(100),
+            ..Default::default()
+        });
+
+        flow_stat.incr_tx(100);
+        quota.check();
+        assert!(quota.is_server_exceeded());
+        assert!(
+            time::timeout(Duration::from_millis(10), quota.wait_terminate(None))
+                .await
+                .is_err()
+        );
+    }
+}
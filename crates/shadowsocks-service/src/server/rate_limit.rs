@@ -0,0 +1,162 @@
+//! Rate limiting of new connections
+//!
+//! Accepted connections are checked against a token bucket of their client IP, and then a global bucket of the server,
+//! before any handshakes are read, so flooding clients couldn't exhaust CPU of the server by decrypting.
+
+use std::{net::IpAddr, sync::Mutex, time::Instant};
+
+use lru_time_cache::LruCache;
+
+use crate::config::SecurityRateLimitConfig;
+
+use super::ban::client_key;
+
+/// Least recently seen clients will be forgotten once there are this many clients
+const MAX_TRACKED_CLIENTS: usize = 4096;
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, burst: u32, now: Instant) -> TokenBucket {
+        let burst = f64::from(burst.max(1));
+        TokenBucket {
+            rate: f64::from(rate),
+            burst,
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    /// Take a token, returns `false` if the bucket is empty
+    fn take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Rate limiter of new connections of a server
+#[derive(Debug)]
+pub struct AcceptRateLimiter {
+    config: SecurityRateLimitConfig,
+    global: Option<Mutex<TokenBucket>>,
+    clients: Mutex<LruCache<IpAddr, TokenBucket>>,
+}
+
+impl AcceptRateLimiter {
+    /// Create a rate limiter with all buckets full
+    pub fn new(config: SecurityRateLimitConfig) -> AcceptRateLimiter {
+        let global = config.global.map(|rate| {
+            let burst = config.global_burst.unwrap_or(rate);
+            Mutex::new(TokenBucket::new(rate, burst, Instant::now()))
+        });
+
+        AcceptRateLimiter {
+            config,
+            global,
+            clients: Mutex::new(LruCache::with_capacity(MAX_TRACKED_CLIENTS)),
+        }
+    }
+
+    /// Check if a new connection from `ip` is allowed, and count it
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+
+        if let Some(rate) = self.config.per_ip {
+            let mut clients = self.clients.lock().unwrap();
+
+            let burst = self.config.per_ip_burst.unwrap_or(rate);
+            let bucket = clients
+                .entry(client_key(ip))
+                .or_insert_with(|| TokenBucket::new(rate, burst, now));
+            if !bucket.take(now) {
+                return false;
+            }
+        }
+
+        match self.global {
+            Some(ref global) => global.lock().unwrap().take(now),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn per_ip_burst() {
+        let limiter = AcceptRateLimiter::new(SecurityRateLimitConfig {
+            per_ip: Some(1),
+            per_ip_burst: Some(3),
+            ..Default::default()
+        });
+
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(limiter.check(ip));
+        assert!(limiter.check(ip));
+        assert!(limiter.check(ip));
+        assert!(!limiter.check(ip));
+
+        // Other clients have their own buckets
+        assert!(limiter.check("192.0.2.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn per_ip_canonical_and_prefix() {
+        let limiter = AcceptRateLimiter::new(SecurityRateLimitConfig {
+            per_ip: Some(1),
+            per_ip_burst: Some(1),
+            ..Default::default()
+        });
+
+        assert!(limiter.check("192.0.2.1".parse().unwrap()));
+        assert!(!limiter.check("::ffff:192.0.2.1".parse().unwrap()));
+
+        assert!(limiter.check("2001:db8::1".parse().unwrap()));
+        assert!(!limiter.check("2001:db8::2".parse().unwrap()));
+        assert!(limiter.check("2001:db8:0:1::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn tracked_clients_capped() {
+        let limiter = AcceptRateLimiter::new(SecurityRateLimitConfig {
+            per_ip: Some(1),
+            ..Default::default()
+        });
+
+        for i in 0..(MAX_TRACKED_CLIENTS as u32 + 100) {
+            limiter.check(IpAddr::from(i.to_be_bytes()));
+        }
+        assert_eq!(limiter.clients.lock().unwrap().len(), MAX_TRACKED_CLIENTS);
+    }
+
+    #[test]
+    fn global_cap() {
+        let limiter = AcceptRateLimiter::new(SecurityRateLimitConfig {
+            global: Some(2),
+            ..Default::default()
+        });
+
+        assert!(limiter.check("192.0.2.1".parse().unwrap()));
+        assert!(limiter.check("192.0.2.2".parse().unwrap()));
+        assert!(!limiter.check("192.0.2.3".parse().unwrap()));
+    }
+}
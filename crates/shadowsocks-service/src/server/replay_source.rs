@@ -0,0 +1,95 @@
+//! Sources of replayed requests
+//!
+//! Requests rejected by the replay filter are recorded by their client IPs, so operators could see whether the server
+//! is being probed with replayed handshakes, and from where. The table is bounded, the least recently seen source is
+//! forgotten once it is full.
+
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::SystemTime};
+
+use crate::stats::ReplaySourceStats;
+
+/// Maximum number of sources recorded
+const MAX_TRACKED_SOURCES: usize = 1024;
+
+#[derive(Debug)]
+struct SourceRecord {
+    count: u64,
+    first_seen: SystemTime,
+    last_seen: SystemTime,
+}
+
+/// Sources of replayed requests of a server
+#[derive(Debug, Default)]
+pub struct ReplaySources {
+    sources: Mutex<HashMap<IpAddr, SourceRecord>>,
+}
+
+impl ReplaySources {
+    /// Create an empty table
+    pub fn new() -> ReplaySources {
+        ReplaySources::default()
+    }
+
+    /// Count a replayed request from `ip`, returns the number of replays recorded from it
+    pub fn add(&self, ip: IpAddr) -> u64 {
+        let now = SystemTime::now();
+        let mut sources = self.sources.lock().unwrap();
+
+        if !sources.contains_key(&ip) && sources.len() >= MAX_TRACKED_SOURCES {
+            let oldest = sources
+                .iter()
+                .min_by_key(|(_, record)| record.last_seen)
+                .map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                sources.remove(&oldest);
+            }
+        }
+
+        let record = sources.entry(ip).or_insert_with(|| SourceRecord {
+            count: 0,
+            first_seen: now,
+            last_seen: now,
+        });
+        record.count += 1;
+        record.last_seen = now;
+        record.count
+    }
+
+    /// Recorded sources, the most recently seen first
+    pub fn snapshot(&self) -> Vec<ReplaySourceStats> {
+        let sources = self.sources.lock().unwrap();
+
+        let mut records = sources.iter().collect::<Vec<_>>();
+        records.sort_by(|(_, a), (_, b)| b.last_seen.cmp(&a.last_seen));
+
+        records
+            .into_iter()
+            .map(|(ip, record)| ReplaySourceStats::new(*ip, record.count, record.first_seen, record.last_seen))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn count_and_evict() {
+        let sources = ReplaySources::new();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert_eq!(sources.add(ip), 1);
+        assert_eq!(sources.add(ip), 2);
+
+        // Make it the least recently seen one
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        for i in 0..MAX_TRACKED_SOURCES as u32 {
+            sources.add(IpAddr::from((0x0a00_0000 + i).to_be_bytes()));
+        }
+
+        let snapshot = sources.snapshot();
+        assert_eq!(snapshot.len(), MAX_TRACKED_SOURCES);
+        assert!(snapshot.iter().all(|s| s.peer_ip != ip));
+    }
+}
@@ -7,20 +7,41 @@ use std::{
     time::Duration,
 };
 
+use arc_swap::ArcSwapOption;
 use futures::future;
-use log::{error, trace};
+use log::{error, trace, warn};
 use shadowsocks::{
     ManagerClient,
     config::{ManagerAddr, ServerConfig},
+    context::CancellationToken,
     dns_resolver::DnsResolver,
     net::{AcceptOpts, ConnectOpts},
-    plugin::{Plugin, PluginMode},
+    plugin::{Plugin, PluginEvent, PluginMode},
+    relay::tcprelay::utils::plain_read_buffer_size,
 };
 use tokio::time;
 
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat, utils::ServerHandle};
+use crate::{
+    acl::AccessControl,
+    config::{SecurityConfig, TrafficQuotaConfig},
+    events::{self, Event},
+    net::{ConnectionStat, FlowStat},
+    stats::{self, ConnectionTable, StatsSnapshot, StatsSource},
+    utils::ServerHandle,
+};
 
-use super::{context::ServiceContext, tcprelay::TcpServer, udprelay::UdpServer};
+#[cfg(any(feature = "user-store-sqlite", feature = "user-store-redis"))]
+use crate::config::UserStoreConfig;
+
+#[cfg(feature = "server-geoip")]
+use super::geoip::OutboundGeoIp;
+#[cfg(any(feature = "user-store-sqlite", feature = "user-store-redis"))]
+use super::user_store::{UserStoreBackend, UserStoreWatcher};
+use super::{
+    accounting::TrafficAccounting, allow_list::ClientAllowListMatcher, conn_limit::ConnectionLimiter,
+    context::ServiceContext, port_hopping::PortHoppingServer, quota::TrafficQuota, session::SessionRegistry,
+    tcprelay::TcpServer, udprelay::UdpServer,
+};
 
 /// Shadowsocks Server Builder
 pub struct ServerBuilder {
@@ -30,6 +51,10 @@ pub struct ServerBuilder {
     udp_capacity: Option<usize>,
     manager_addr: Option<ManagerAddr>,
     accept_opts: AcceptOpts,
+    #[cfg(unix)]
+    listener_count: usize,
+    #[cfg(any(feature = "user-store-sqlite", feature = "user-store-redis"))]
+    user_store: Option<UserStoreConfig>,
 }
 
 impl ServerBuilder {
@@ -47,6 +72,10 @@ impl ServerBuilder {
             udp_capacity: None,
             manager_addr: None,
             accept_opts: AcceptOpts::default(),
+            #[cfg(unix)]
+            listener_count: 1,
+            #[cfg(any(feature = "user-store-sqlite", feature = "user-store-redis"))]
+            user_store: None,
         }
     }
 
@@ -60,6 +89,43 @@ impl ServerBuilder {
         self.context.flow_stat_ref()
     }
 
+    /// Get active connection statistic
+    pub fn connection_stat(&self) -> Arc<ConnectionStat> {
+        self.context.connection_stat()
+    }
+
+    /// Get per-user traffic accounting
+    pub fn accounting(&self) -> Arc<TrafficAccounting> {
+        self.context.accounting()
+    }
+
+    /// Get live TCP sessions
+    pub fn sessions(&self) -> Arc<SessionRegistry> {
+        self.context.sessions()
+    }
+
+    /// Get traffic quota, if it was set
+    pub fn quota(&self) -> Option<Arc<TrafficQuota>> {
+        self.context.quota()
+    }
+
+    /// Set traffic quota of the server and its users
+    pub fn set_quota(&mut self, config: TrafficQuotaConfig) {
+        let quota = TrafficQuota::new(
+            config,
+            self.svr_cfg.addr().to_string(),
+            self.context.flow_stat(),
+            self.context.accounting(),
+        );
+        self.context.set_quota(Arc::new(quota));
+    }
+
+    /// Load users from an external store, instead of `users` of the server's configuration
+    #[cfg(any(feature = "user-store-sqlite", feature = "user-store-redis"))]
+    pub fn set_user_store(&mut self, config: UserStoreConfig) {
+        self.user_store = Some(config);
+    }
+
     /// Set `ConnectOpts`
     pub fn set_connect_opts(&mut self, opts: ConnectOpts) {
         self.context.set_connect_opts(opts)
@@ -85,6 +151,11 @@ impl ServerBuilder {
         &self.svr_cfg
     }
 
+    /// Share `token` for shutting down the server and its connections
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.context.set_cancellation_token(token)
+    }
+
     /// Set customized DNS resolver
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         self.context.set_dns_resolver(resolver)
@@ -95,11 +166,50 @@ impl ServerBuilder {
         self.context.set_acl(acl);
     }
 
+    /// Set access control list shared with others, which could be replaced at runtime
+    pub fn set_shared_acl(&mut self, acl: Arc<ArcSwapOption<AccessControl>>) {
+        self.context.set_shared_acl(acl);
+    }
+
+    /// Set countries of outbound destinations allowed or blocked
+    #[cfg(feature = "server-geoip")]
+    pub fn set_outbound_geoip(&mut self, geoip: Arc<OutboundGeoIp>) {
+        self.context.set_outbound_geoip(geoip);
+    }
+
+    /// Set clients allowed to connect, the others are dropped right after being accepted
+    pub fn set_client_allow_list(&mut self, allow_list: Arc<ClientAllowListMatcher>) {
+        self.context.set_client_allow_list(allow_list);
+    }
+
+    /// Set limits of active connections, shared by all servers
+    pub fn set_connection_limiter(&mut self, limiter: Arc<ConnectionLimiter>) {
+        self.context.set_connection_limiter(limiter);
+    }
+
+    /// Expect PROXY protocol headers ahead of accepted TCP connections, for servers behind L4 load balancers
+    pub fn set_proxy_protocol(&mut self, proxy_protocol: bool) {
+        self.context.set_proxy_protocol(proxy_protocol);
+    }
+
+    /// Serve targets of the bench responder in process, for `ssservice bench`
+    pub fn set_bench_responder(&mut self, bench_responder: bool) {
+        self.context.set_bench_responder(bench_responder);
+    }
+
     /// Set `AcceptOpts` for accepting new connections
     pub fn set_accept_opts(&mut self, opts: AcceptOpts) {
         self.accept_opts = opts;
     }
 
+    /// Bind `count` TCP listeners and UDP sockets with `SO_REUSEPORT`, each of them is served separately
+    ///
+    /// The kernel spreads connections and datagrams between them, instead of accepting in one loop.
+    #[cfg(unix)]
+    pub fn set_listener_count(&mut self, count: usize) {
+        self.listener_count = count;
+    }
+
     /// Try to connect IPv6 addresses first if hostname could be resolved to both IPv4 and IPv6
     pub fn set_ipv6_first(&mut self, ipv6_first: bool) {
         self.context.set_ipv6_first(ipv6_first);
@@ -110,48 +220,182 @@ impl ServerBuilder {
         self.context.set_security_config(security)
     }
 
+    /// Set entries of the replay filter, instead of the default capacity
+    pub fn set_replay_filter_capacity(&mut self, entries: usize) {
+        self.context.set_replay_filter_capacity(entries)
+    }
+
     /// Start the server
     ///
-    /// 1. Starts plugin (subprocess)
-    /// 2. Starts TCP server (listener)
-    /// 3. Starts UDP server (listener)
+    /// 1. Loads users from user store
+    /// 2. Starts plugins (subprocesses)
+    /// 3. Starts TCP server (listener)
+    /// 4. Starts UDP server (listener)
     pub async fn build(mut self) -> io::Result<Server> {
+        #[cfg(any(feature = "user-store-sqlite", feature = "user-store-redis"))]
+        let user_store = match self.user_store.take() {
+            None => None,
+            Some(config) => {
+                let store = UserStoreBackend::open(&config).await?;
+                let mut watcher = UserStoreWatcher::new(store, self.svr_cfg.method(), self.svr_cfg.addr().to_string());
+                let loaded = watcher.load().await?;
+
+                // Quotas of users are kept in the store
+                if self.context.quota().is_none() {
+                    self.set_quota(TrafficQuotaConfig::default());
+                }
+                if let Some(quota) = self.context.quota_ref() {
+                    quota.set_user_quotas(loaded.quotas);
+                }
+
+                log::info!(
+                    "server {} loaded {} users from user store {}",
+                    self.svr_cfg.addr(),
+                    loaded.user_manager.user_count(),
+                    config.url
+                );
+                self.svr_cfg.set_user_manager(loaded.user_manager);
+
+                Some(watcher)
+            }
+        };
+
+        if !self.svr_cfg.extra_addrs().is_empty()
+            && (!self.svr_cfg.plugins().is_empty() || self.svr_cfg.port_hopping().is_some())
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "extra addresses of server {} couldn't be used with plugins or port hopping",
+                    self.svr_cfg.addr()
+                ),
+            ));
+        }
+
         let context = Arc::new(self.context);
 
-        let mut plugin = None;
+        // Builtin plugins run in process, plugin subprocesses are chained in front of the server
+        let mut plugins = Plugin::start_chain(self.svr_cfg.plugins(), self.svr_cfg.addr(), PluginMode::Server)?;
+        for plugin in &plugins {
+            let plugin_cfg = plugin.config();
+            if self.svr_cfg.mode().enable_udp() && !plugin_cfg.plugin_mode.enable_udp() {
+                warn!(
+                    "UDP of server {} bypasses plugin \"{}\", set `plugin_mode` to \"tcp_and_udp\" if it supports SIP003u",
+                    self.svr_cfg.addr(),
+                    plugin_cfg.plugin
+                );
+            }
+        }
 
-        if let Some(plugin_cfg) = self.svr_cfg.plugin() {
-            let plugin_process = Plugin::start(plugin_cfg, self.svr_cfg.addr(), PluginMode::Server)?;
-            self.svr_cfg.set_plugin_addr(plugin_process.local_addr().into());
-            plugin = Some(plugin_process);
+        for plugin in &mut plugins {
+            let timeout = plugin.config().plugin_supervision.startup_timeout;
+            match plugin.check_started(timeout).await {
+                Ok(true) => {}
+                Ok(false) => warn!(
+                    "plugin \"{}\" of server {} isn't listening on {}",
+                    plugin.config().plugin,
+                    self.svr_cfg.addr(),
+                    plugin.local_addr()
+                ),
+                Err(err) => {
+                    error!("plugin of server {} failed to start, {}", self.svr_cfg.addr(), err);
+                    return Err(err);
+                }
+            }
+        }
+        if let Some(plugin) = plugins.first() {
+            self.svr_cfg.set_plugin_addr(plugin.local_addr().into());
         }
 
-        let mut tcp_server = None;
-        if self.svr_cfg.mode().enable_tcp() {
-            let server = TcpServer::new(context.clone(), self.svr_cfg.clone(), self.accept_opts.clone()).await?;
-            tcp_server = Some(server);
+        #[cfg(unix)]
+        let listener_count = if self.listener_count > 1 && self.svr_cfg.tcp_external_addr().port() == 0 {
+            warn!(
+                "server {} listens on a random port, SO_REUSEPORT listeners are disabled",
+                self.svr_cfg.addr()
+            );
+            1
+        } else {
+            self.listener_count.max(1)
+        };
+        #[cfg(not(unix))]
+        let listener_count = 1;
+
+        // Shared by listeners and their connections
+        let svr_cfg = Arc::new(self.svr_cfg);
+
+        let mut accept_opts = self.accept_opts.clone();
+        #[cfg(unix)]
+        if listener_count > 1 {
+            accept_opts.reuse_port = true;
         }
 
-        let mut udp_server = None;
-        if self.svr_cfg.mode().enable_udp() {
-            let server = UdpServer::new(
+        // Listeners of hopping servers are bound in `run`, following the current time slot
+        let port_hopping = svr_cfg.port_hopping().map(|_| {
+            PortHoppingServer::new(
                 context.clone(),
-                self.svr_cfg.clone(),
+                svr_cfg.clone(),
                 self.udp_expiry_duration,
                 self.udp_capacity,
-                self.accept_opts.clone(),
+                accept_opts.clone(),
             )
-            .await?;
-            udp_server = Some(server);
+        });
+
+        // Extra addresses are served with the same context, as parts of this server. Their listeners are bound like the
+        // ones of `addr`, taken from systemd or the old process and handed over to the new process as well.
+        let mut listen_cfgs = vec![svr_cfg.clone()];
+        for addr in svr_cfg.extra_addrs() {
+            let mut listen_cfg = ServerConfig::clone(&svr_cfg);
+            listen_cfg.set_addr(addr.clone());
+            listen_cfgs.push(Arc::new(listen_cfg));
+        }
+
+        let mut tcp_servers = Vec::new();
+        if port_hopping.is_none() && svr_cfg.mode().enable_tcp() {
+            for listen_cfg in &listen_cfgs {
+                for _ in 0..listener_count {
+                    let server = TcpServer::new(context.clone(), listen_cfg.clone(), accept_opts.clone()).await?;
+                    tcp_servers.push(server);
+                }
+            }
+        }
+
+        let mut udp_servers = Vec::new();
+        if port_hopping.is_none() && svr_cfg.mode().enable_udp() {
+            for listen_cfg in &listen_cfgs {
+                for _ in 0..listener_count {
+                    let server = UdpServer::new(
+                        context.clone(),
+                        listen_cfg.clone(),
+                        self.udp_expiry_duration,
+                        self.udp_capacity,
+                        accept_opts.clone(),
+                    )
+                    .await?;
+                    udp_servers.push(server);
+                }
+            }
         }
 
+        let stats_source = Arc::new(ServerStatsSource {
+            server: svr_cfg.addr().to_string(),
+            context: context.clone(),
+            relay_buffer_size: plain_read_buffer_size(svr_cfg.method()),
+            // Each UDP listener has its own NAT map
+            udp_max_associations: self.udp_capacity.map(|c| c * udp_servers.len().max(1)),
+        });
+        stats::register(&stats_source);
+
         Ok(Server {
             context,
-            svr_cfg: self.svr_cfg,
-            tcp_server,
-            udp_server,
+            svr_cfg,
+            tcp_servers,
+            udp_servers,
+            port_hopping,
             manager_addr: self.manager_addr,
-            plugin,
+            plugins,
+            stats_source,
+            #[cfg(any(feature = "user-store-sqlite", feature = "user-store-redis"))]
+            user_store,
         })
     }
 }
@@ -159,11 +403,15 @@ impl ServerBuilder {
 /// Shadowsocks Server instance
 pub struct Server {
     context: Arc<ServiceContext>,
-    svr_cfg: ServerConfig,
-    tcp_server: Option<TcpServer>,
-    udp_server: Option<UdpServer>,
+    svr_cfg: Arc<ServerConfig>,
+    tcp_servers: Vec<TcpServer>,
+    udp_servers: Vec<UdpServer>,
+    port_hopping: Option<PortHoppingServer>,
     manager_addr: Option<ManagerAddr>,
-    plugin: Option<Plugin>,
+    plugins: Vec<Plugin>,
+    stats_source: Arc<ServerStatsSource>,
+    #[cfg(any(feature = "user-store-sqlite", feature = "user-store-redis"))]
+    user_store: Option<UserStoreWatcher<UserStoreBackend>>,
 }
 
 impl Server {
@@ -172,43 +420,98 @@ impl Server {
         &self.svr_cfg
     }
 
-    /// Get TCP server instance
+    /// Get TCP server instance, the first one if there are multiple listeners
+    ///
+    /// Servers with port hopping have no fixed listeners.
     pub fn tcp_server(&self) -> Option<&TcpServer> {
-        self.tcp_server.as_ref()
+        self.tcp_servers.first()
     }
 
-    /// Get UDP server instance
+    /// Get UDP server instance, the first one if there are multiple listeners
     pub fn udp_server(&self) -> Option<&UdpServer> {
-        self.udp_server.as_ref()
+        self.udp_servers.first()
     }
 
     /// Start serving
     pub async fn run(self) -> io::Result<()> {
+        // Keep reporting statistics until the server exits
+        let _stats_source = self.stats_source;
+
+        let server_addr = self.svr_cfg.addr().to_string();
+        events::publish(|| Event::ServerStarted {
+            server: server_addr.clone(),
+        });
+
         let mut vfut = Vec::new();
 
-        if let Some(plugin) = self.plugin {
+        for plugin in self.plugins {
+            let server = server_addr.clone();
+            let plugin_name = plugin.config().plugin.clone();
             vfut.push(ServerHandle(tokio::spawn(async move {
-                match plugin.join().await {
-                    Ok(status) => {
-                        error!("plugin exited with status: {}", status);
-                        Ok(())
-                    }
-                    Err(err) => {
-                        error!("plugin exited with error: {}", err);
-                        Err(err)
-                    }
-                }
+                // Plugin is restarted whenever it exits, this only finishes after giving up restarting it
+                plugin
+                    .supervise(|event| {
+                        if let PluginEvent::Exited(ref result) = event {
+                            let status = match *result {
+                                Ok(ref status) => status.to_string(),
+                                Err(ref err) => format!("error: {err}"),
+                            };
+                            events::publish(|| Event::PluginExited {
+                                server: server.clone(),
+                                plugin: plugin_name.clone(),
+                                status,
+                            });
+                        }
+                    })
+                    .await;
+                Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!("plugin of server {server} exited too many times"),
+                ))
             })));
         }
 
-        if let Some(tcp_server) = self.tcp_server {
+        for tcp_server in self.tcp_servers {
             vfut.push(ServerHandle(tokio::spawn(tcp_server.run())));
         }
 
-        if let Some(udp_server) = self.udp_server {
+        for udp_server in self.udp_servers {
             vfut.push(ServerHandle(tokio::spawn(udp_server.run())));
         }
 
+        if let Some(port_hopping) = self.port_hopping {
+            vfut.push(ServerHandle(tokio::spawn(port_hopping.run())));
+        }
+
+        if let Some(quota) = self.context.quota() {
+            vfut.push(ServerHandle(tokio::spawn(async move {
+                quota.run_check().await;
+                Ok(())
+            })));
+        }
+
+        {
+            let context = self.context.clone();
+            let server = server_addr.clone();
+            vfut.push(ServerHandle(tokio::spawn(async move {
+                publish_replay_events(&context, &server).await;
+                Ok(())
+            })));
+        }
+
+        #[cfg(any(feature = "user-store-sqlite", feature = "user-store-redis"))]
+        if let Some(user_store) = self.user_store {
+            vfut.push(ServerHandle(tokio::spawn(user_store.run(self.context.clone()))));
+        }
+
+        if self.svr_cfg.user_manager().is_some() {
+            let accounting = self.context.accounting();
+            vfut.push(ServerHandle(tokio::spawn(async move {
+                accounting.run_snapshot().await;
+                Ok(())
+            })));
+        }
+
         if let Some(manager_addr) = self.manager_addr {
             vfut.push(ServerHandle(tokio::spawn(async move {
                 loop {
@@ -242,6 +545,34 @@ impl Server {
                                     trace!("report to manager {}, {:?}", manager_addr, req);
                                 }
                             }
+
+                            let snapshot = self.context.accounting_ref().snapshot();
+                            if !snapshot.users.is_empty() {
+                                use shadowsocks::manager::protocol::UserStatRequest;
+
+                                let users = snapshot
+                                    .users
+                                    .iter()
+                                    .map(|(name, traffic)| (name.clone(), traffic.total()))
+                                    .collect();
+
+                                let mut stat = HashMap::new();
+                                stat.insert(self.svr_cfg.addr().port(), users);
+
+                                let req = UserStatRequest { stat };
+
+                                match client.user_stat(&req).await {
+                                    Err(err) => {
+                                        error!(
+                                            "failed to send user-stat to manager {}, error: {}, {:?}",
+                                            manager_addr, err, req
+                                        );
+                                    }
+                                    _ => {
+                                        trace!("report to manager {}, {:?}", manager_addr, req);
+                                    }
+                                }
+                            }
                         }
                     }
 
@@ -255,7 +586,58 @@ impl Server {
             error!("servers exited with error: {}", err);
         }
 
+        events::publish(|| Event::ServerStopped { server: server_addr });
+
         let err = io::Error::new(ErrorKind::Other, "server exited unexpectedly");
         Err(err)
     }
 }
+
+/// Publish `ReplayDetected` events whenever the server detects repeated nonces
+async fn publish_replay_events(context: &ServiceContext, server: &str) {
+    let stat = context.context_ref().stat();
+    let mut last_total = stat.replay_detected();
+    loop {
+        stat.replay_detected_notified().await;
+
+        let total = stat.replay_detected();
+        if total > last_total {
+            events::publish(|| Event::ReplayDetected {
+                server: server.to_owned(),
+                count: total - last_total,
+                total,
+            });
+            last_total = total;
+        }
+    }
+}
+
+/// Statistics of a server instance
+struct ServerStatsSource {
+    server: String,
+    context: Arc<ServiceContext>,
+    relay_buffer_size: usize,
+    udp_max_associations: Option<usize>,
+}
+
+impl StatsSource for ServerStatsSource {
+    fn collect(&self, snapshot: &mut StatsSnapshot) {
+        let mut stats = self.context.stats_snapshot();
+        stats
+            .memory
+            .set_relay_buffers(self.relay_buffer_size, stats.tcp_connections);
+        stats.memory.udp_max_associations = self.udp_max_associations;
+        snapshot.servers.insert(self.server.clone(), stats);
+    }
+
+    fn collect_connections(&self, table: &mut ConnectionTable) {
+        table.insert(
+            self.server.clone(),
+            self.context.connection_stat_ref().active_connections(),
+        );
+    }
+
+    fn clear_caches(&self) {
+        self.context.context_ref().dns_resolver().clear_cache();
+    }
+}
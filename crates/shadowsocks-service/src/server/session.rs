@@ -0,0 +1,214 @@
+//! Live TCP relay sessions
+//!
+//! Every established TCP tunnel is registered here with its target, so sessions could be listed
+//! and forcibly terminated, for example, by the manager's admin API.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+
+use tokio::sync::Notify;
+
+use crate::net::FlowStat;
+
+/// Information of a live session
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    /// Identifier, unique in a server
+    pub id: u64,
+    /// Client address
+    pub peer_addr: SocketAddr,
+    /// Target address requested by client
+    pub target: String,
+    /// EIH user of this session
+    pub user: Option<String>,
+    /// Time that tunnel was established
+    pub established: SystemTime,
+    /// Bytes sent to client
+    pub tx: u64,
+    /// Bytes received from client
+    pub rx: u64,
+}
+
+struct SessionEntry {
+    peer_addr: SocketAddr,
+    target: String,
+    user: Option<String>,
+    established: SystemTime,
+    flow_stat: Arc<FlowStat>,
+    kill_notify: Notify,
+}
+
+/// Live sessions of a server
+#[derive(Default)]
+pub struct SessionRegistry {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, Arc<SessionEntry>>>,
+}
+
+impl SessionRegistry {
+    /// Create an empty registry
+    pub fn new() -> SessionRegistry {
+        SessionRegistry::default()
+    }
+
+    /// Register a new session, which will be removed when the returned guard drops
+    pub fn register(self: &Arc<Self>, peer_addr: SocketAddr, target: String, user: Option<String>) -> SessionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let entry = Arc::new(SessionEntry {
+            peer_addr,
+            target,
+            user,
+            established: SystemTime::now(),
+            // Increased by tasks of this session only
+            flow_stat: Arc::new(FlowStat::with_shards(1)),
+            kill_notify: Notify::new(),
+        });
+
+        self.sessions.lock().unwrap().insert(id, entry.clone());
+
+        SessionGuard {
+            registry: self.clone(),
+            id,
+            entry,
+        }
+    }
+
+    /// Number of live sessions
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// Check if there is no live sessions
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// List all live sessions, ordered by id
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap();
+
+        let mut list = sessions
+            .iter()
+            .map(|(id, entry)| SessionInfo {
+                id: *id,
+                peer_addr: entry.peer_addr,
+                target: entry.target.clone(),
+                user: entry.user.clone(),
+                established: entry.established,
+                tx: entry.flow_stat.tx(),
+                rx: entry.flow_stat.rx(),
+            })
+            .collect::<Vec<_>>();
+        list.sort_by_key(|s| s.id);
+        list
+    }
+
+    /// Terminate session `id`, returns `false` if it doesn't exist
+    pub fn kill(&self, id: u64) -> bool {
+        match self.sessions.lock().unwrap().get(&id) {
+            Some(entry) => {
+                entry.kill_notify.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Terminate all sessions of client `ip`, returns number of sessions terminated
+    pub fn kill_client(&self, ip: IpAddr) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+
+        let mut killed = 0;
+        for entry in sessions.values() {
+            // IPv4 clients may be accepted by dual-stack sockets as IPv4-mapped IPv6 addresses
+            if entry.peer_addr.ip().to_canonical() == ip.to_canonical() {
+                entry.kill_notify.notify_one();
+                killed += 1;
+            }
+        }
+        killed
+    }
+
+    /// Terminate all sessions of EIH `user`, returns number of sessions terminated
+    pub fn kill_user(&self, user: &str) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+
+        let mut killed = 0;
+        for entry in sessions.values() {
+            if entry.user.as_deref() == Some(user) {
+                entry.kill_notify.notify_one();
+                killed += 1;
+            }
+        }
+        killed
+    }
+}
+
+/// Guard of a registered session
+pub struct SessionGuard {
+    registry: Arc<SessionRegistry>,
+    id: u64,
+    entry: Arc<SessionEntry>,
+}
+
+impl SessionGuard {
+    /// Identifier of this session
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Flow statistic of this session
+    pub fn flow_stat(&self) -> Arc<FlowStat> {
+        self.entry.flow_stat.clone()
+    }
+
+    /// Wait until this session was requested to be terminated
+    pub async fn killed(&self) {
+        self.entry.kill_notify.notified().await
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn register_and_kill() {
+        let registry = Arc::new(SessionRegistry::new());
+
+        let a = registry.register("127.0.0.1:1000".parse().unwrap(), "example.com:80".to_owned(), None);
+        let b = registry.register(
+            "[::ffff:127.0.0.1]:1001".parse().unwrap(),
+            "example.com:443".to_owned(),
+            None,
+        );
+        let c = registry.register("127.0.0.2:1002".parse().unwrap(), "example.com:443".to_owned(), None);
+        assert_eq!(registry.len(), 3);
+
+        let list = registry.list();
+        assert_eq!(
+            list.iter().map(|s| s.id).collect::<Vec<_>>(),
+            vec![a.id(), b.id(), c.id()]
+        );
+
+        assert!(registry.kill(c.id()));
+        assert_eq!(registry.kill_client("127.0.0.1".parse().unwrap()), 2);
+
+        drop(c);
+        assert_eq!(registry.len(), 2);
+        assert!(!registry.kill(u64::MAX));
+    }
+}
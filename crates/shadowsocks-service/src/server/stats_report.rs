@@ -0,0 +1,149 @@
+//! Periodic statistic report
+//!
+//! Statistics of all servers are sent to an external collector as a JSON object in every interval:
+//!
+//! ```json
+//! {
+//!     "id": "server-1",
+//!     "timestamp": 1700000000,
+//!     "interval": 10.0,
+//!     "servers": [
+//!         {
+//!             "server": "0.0.0.0:8388",
+//!             "tx": 1024,
+//!             "rx": 2048,
+//!             "tx_delta": 24,
+//!             "rx_delta": 48,
+//!             "tcp_connections": 3,
+//!             "udp_associations": 1
+//!         }
+//!     ]
+//! }
+//! ```
+//!
+//! `tx_delta` and `rx_delta` are bytes transferred since the previous report.
+
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, trace};
+use serde_json::{Value as JsonValue, json};
+use tokio::time;
+
+use crate::{
+    config::{StatsReportConfig, StatsReportTarget},
+    net::{ConnectionStat, FlowStat, http_client},
+};
+
+/// Default interval of reports
+pub const DEFAULT_STATS_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// Timeout of sending a report, at most the interval
+const STATS_REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct ReportedServer {
+    addr: String,
+    flow_stat: Arc<FlowStat>,
+    connection_stat: Arc<ConnectionStat>,
+    last_tx: u64,
+    last_rx: u64,
+}
+
+/// Reporter that sends statistics of servers periodically
+pub struct StatsReporter {
+    config: StatsReportConfig,
+    servers: Vec<ReportedServer>,
+}
+
+impl StatsReporter {
+    /// Create a reporter without any servers
+    pub fn new(config: StatsReportConfig) -> StatsReporter {
+        StatsReporter {
+            config,
+            servers: Vec::new(),
+        }
+    }
+
+    /// Add a server to be reported
+    pub fn add_server(&mut self, addr: String, flow_stat: Arc<FlowStat>, connection_stat: Arc<ConnectionStat>) {
+        self.servers.push(ReportedServer {
+            addr,
+            flow_stat,
+            connection_stat,
+            last_tx: 0,
+            last_rx: 0,
+        });
+    }
+
+    /// Send reports until the task is aborted
+    pub async fn run(mut self) -> io::Result<()> {
+        let interval = self.config.interval.unwrap_or(DEFAULT_STATS_REPORT_INTERVAL);
+        let mut last_report = Instant::now();
+
+        loop {
+            time::sleep(interval).await;
+
+            let now = Instant::now();
+            let report = self.make_report(now - last_report);
+            last_report = now;
+
+            // Slow targets shouldn't delay the next report
+            let body = serde_json::to_vec(&report).expect("serialize json");
+            match time::timeout(STATS_REPORT_TIMEOUT.min(interval), self.send(body)).await {
+                Ok(Ok(..)) => trace!("reported statistic to {}, {}", self.config.target, report),
+                Ok(Err(err)) => error!("failed to report statistic to {}, error: {}", self.config.target, err),
+                Err(..) => error!("failed to report statistic to {}, timed out", self.config.target),
+            }
+        }
+    }
+
+    fn make_report(&mut self, elapsed: Duration) -> JsonValue {
+        let mut servers = Vec::with_capacity(self.servers.len());
+        for server in self.servers.iter_mut() {
+            let tx = server.flow_stat.tx();
+            let rx = server.flow_stat.rx();
+
+            servers.push(json!({
+                "server": server.addr,
+                "tx": tx,
+                "rx": rx,
+                "tx_delta": tx.saturating_sub(server.last_tx),
+                "rx_delta": rx.saturating_sub(server.last_rx),
+                "tcp_connections": server.connection_stat.tcp_connections(),
+                "udp_associations": server.connection_stat.udp_associations(),
+            }));
+
+            server.last_tx = tx;
+            server.last_rx = rx;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        json!({
+            "id": self.config.id,
+            "timestamp": timestamp,
+            "interval": elapsed.as_secs_f64(),
+            "servers": servers,
+        })
+    }
+
+    async fn send(&self, body: Vec<u8>) -> io::Result<()> {
+        match self.config.target {
+            StatsReportTarget::Http(ref url) => http_client::post_json(url, body).await,
+            #[cfg(unix)]
+            StatsReportTarget::UnixSocket(ref path) => {
+                use tokio::{io::AsyncWriteExt, net::UnixStream};
+
+                let mut stream = UnixStream::connect(path).await?;
+                stream.write_all(&body).await?;
+                stream.write_all(b"\n").await?;
+                stream.shutdown().await
+            }
+        }
+    }
+}
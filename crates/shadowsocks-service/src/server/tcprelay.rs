@@ -1,48 +1,117 @@
 //! Shadowsocks TCP server
 
 use std::{
-    future::Future,
+    future::{self, Future},
     io::{self, ErrorKind},
-    net::SocketAddr,
-    sync::Arc,
-    time::Duration,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use log::{debug, error, info, trace, warn};
+use lru_time_cache::LruCache;
 use shadowsocks::{
     ProxyListener, ServerConfig,
-    crypto::CipherKind,
+    config::ServerUserManager,
     net::{AcceptOpts, TcpStream as OutboundTcpStream},
-    relay::tcprelay::{ProxyServerStream, utils::copy_encrypted_bidirectional},
+    relay::tcprelay::{ProxyServerStream, crypto_io, utils::copy_encrypted_bidirectional},
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream as TokioTcpStream,
+    sync::watch,
     time,
 };
+use tracing::Instrument;
+
+use crate::{
+    access_log::{self, AccessLogEntry, CloseReason},
+    config::HandshakeFailurePolicy,
+    events::{self, Event},
+    flow_export::{self, FlowProtocol, FlowRecord, PacketCounter},
+    log_control::{connection_span, record_target_addr},
+    net::{ConnectionGuard, MonProxyStream, proxy_protocol, utils::ignore_until_end},
+    stats::ErrorClass,
+    utils::spawn_cancellable,
+};
+
+use super::{
+    conn_limit::ConnectionKind,
+    context::{CheckedOutbound, ServiceContext},
+};
 
-use crate::net::{MonProxyStream, utils::ignore_until_end};
+/// Handshake failures of a client in this window will be counted together
+const HANDSHAKE_FAILURE_WINDOW: Duration = Duration::from_secs(60);
+/// Report a client once it failed this many handshakes in a window
+const HANDSHAKE_FAILURE_THRESHOLD: u32 = 10;
+/// Timeout of reading PROXY protocol headers of accepted connections
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
 
-use super::context::ServiceContext;
+/// Least recently failed clients will be forgotten once there are this many clients
+const HANDSHAKE_FAILURE_MAX_CLIENTS: usize = 1024;
+
+/// Handshake failures of each client IP
+struct HandshakeFailureTracker {
+    clients: Mutex<LruCache<IpAddr, (Instant, u32)>>,
+}
+
+impl Default for HandshakeFailureTracker {
+    fn default() -> HandshakeFailureTracker {
+        HandshakeFailureTracker {
+            clients: Mutex::new(LruCache::with_capacity(HANDSHAKE_FAILURE_MAX_CLIENTS)),
+        }
+    }
+}
+
+impl HandshakeFailureTracker {
+    /// Count a failure of `ip`, returns the count if it just reached the threshold
+    fn add(&self, ip: IpAddr) -> Option<u32> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+
+        let (start, count) = clients.entry(ip.to_canonical()).or_insert((now, 0));
+        if now - *start >= HANDSHAKE_FAILURE_WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+
+        if *count == HANDSHAKE_FAILURE_THRESHOLD {
+            Some(*count)
+        } else {
+            None
+        }
+    }
+}
 
 /// TCP server instance
 pub struct TcpServer {
     context: Arc<ServiceContext>,
-    svr_cfg: ServerConfig,
+    svr_cfg: Arc<ServerConfig>,
     listener: ProxyListener,
+    acceptor: Arc<ClientAcceptor>,
+    user_manager_rx: watch::Receiver<Option<Arc<ServerUserManager>>>,
 }
 
 impl TcpServer {
     pub(crate) async fn new(
         context: Arc<ServiceContext>,
-        svr_cfg: ServerConfig,
+        svr_cfg: Arc<ServerConfig>,
         accept_opts: AcceptOpts,
     ) -> io::Result<TcpServer> {
         let listener = ProxyListener::bind_with_opts(context.context(), &svr_cfg, accept_opts).await?;
+        let user_manager_rx = context.subscribe_user_manager();
+        let acceptor = Arc::new(ClientAcceptor {
+            context: context.clone(),
+            svr_cfg: svr_cfg.clone(),
+            handshake_failures: Arc::new(HandshakeFailureTracker::default()),
+        });
         Ok(TcpServer {
             context,
             svr_cfg,
             listener,
+            acceptor,
+            user_manager_rx,
         })
     }
 
@@ -57,7 +126,7 @@ impl TcpServer {
     }
 
     /// Start server's accept loop
-    pub async fn run(self) -> io::Result<()> {
+    pub async fn run(mut self) -> io::Result<()> {
         info!(
             "shadowsocks tcp server listening on {}, inbound address {}",
             self.listener.local_addr().expect("listener.local_addr"),
@@ -65,11 +134,27 @@ impl TcpServer {
         );
 
         loop {
+            if self.user_manager_rx.has_changed().unwrap_or(false) {
+                let user_manager = self.user_manager_rx.borrow_and_update().clone();
+                self.listener.set_user_manager(user_manager);
+            }
+
             let flow_stat = self.context.flow_stat();
+            let record_handshake = matches!(
+                self.context.handshake_failure_config().policy,
+                HandshakeFailurePolicy::Decoy(..)
+            );
 
             let (local_stream, peer_addr) = match self
                 .listener
-                .accept_map(|s| MonProxyStream::from_stream(s, flow_stat))
+                .accept_map(|s| {
+                    let mut stream = MonProxyStream::from_stream(s, flow_stat);
+                    if record_handshake {
+                        // Bytes consumed by the handshake will be replayed to the decoy if it failed
+                        stream.start_recording();
+                    }
+                    stream
+                })
                 .await
             {
                 Ok(s) => s,
@@ -80,24 +165,227 @@ impl TcpServer {
                 }
             };
 
-            if self.context.check_client_blocked(&peer_addr) {
-                warn!("access denied from {} by ACL rules", peer_addr);
+            if !self.context.proxy_protocol() {
+                self.acceptor.accept(local_stream, peer_addr);
                 continue;
             }
 
-            let client = TcpServerClient {
-                context: self.context.clone(),
-                method: self.svr_cfg.method(),
-                peer_addr,
-                stream: local_stream,
-                timeout: self.svr_cfg.timeout(),
+            // Read the real client's address without blocking the accept loop
+            let acceptor = self.acceptor.clone();
+            spawn_cancellable(self.context.cancellation_token(), async move {
+                let mut local_stream = local_stream;
+                let header = proxy_protocol::read_proxy_header(local_stream.get_mut().get_mut());
+                let peer_addr = match time::timeout(PROXY_HEADER_TIMEOUT, header).await {
+                    Ok(Ok(Some(addr))) => addr,
+                    // Health checks of the balancer
+                    Ok(Ok(None)) => peer_addr,
+                    Ok(Err(err)) => {
+                        debug!("tcp client {} PROXY protocol header failed, error: {}", peer_addr, err);
+                        return;
+                    }
+                    Err(..) => {
+                        debug!("tcp client {} PROXY protocol header timed out", peer_addr);
+                        return;
+                    }
+                };
+                acceptor.accept(local_stream, peer_addr);
+            });
+        }
+    }
+}
+
+/// Checks accepted clients, and serves the ones allowed
+struct ClientAcceptor {
+    context: Arc<ServiceContext>,
+    svr_cfg: Arc<ServerConfig>,
+    handshake_failures: Arc<HandshakeFailureTracker>,
+}
+
+impl ClientAcceptor {
+    fn accept(&self, mut local_stream: ProxyServerStream<MonProxyStream<TokioTcpStream>>, peer_addr: SocketAddr) {
+        if self.context.check_client_banned(peer_addr.ip()) {
+            debug!("tcp client {} rejected, banned for failing handshakes", peer_addr);
+            self.reject(local_stream, peer_addr);
+            return;
+        }
+
+        if self.context.check_accept_rate_limited(peer_addr.ip()) {
+            debug!("tcp client {} rejected, new connections rate limited", peer_addr);
+            self.reject(local_stream, peer_addr);
+            return;
+        }
+
+        if self.context.check_client_blocked(&peer_addr) {
+            warn!("access denied from {} by ACL rules", peer_addr);
+            self.context.record_error(ErrorClass::AclRejected);
+            self.reject(local_stream, peer_addr);
+            return;
+        }
+
+        if self.context.check_quota_exceeded(None) {
+            debug!("tcp client {} rejected, server quota exceeded", peer_addr);
+            self.reject(local_stream, peer_addr);
+            return;
+        }
+
+        let connection_slot = match self.context.connection_limiter() {
+            None => None,
+            Some(limiter) => match limiter.acquire(ConnectionKind::Tcp) {
+                Some(slot) => {
+                    local_stream.get_mut().set_activity(slot.activity());
+                    Some(slot)
+                }
+                None => {
+                    debug!("tcp client {} rejected, servers are overloaded", peer_addr);
+                    self.context.record_error(ErrorClass::Overloaded);
+                    self.reject(local_stream, peer_addr);
+                    return;
+                }
+            },
+        };
+
+        let connection_guard = self.context.connection_stat().tcp_guard(peer_addr);
+
+        let client = TcpServerClient {
+            context: self.context.clone(),
+            svr_cfg: self.svr_cfg.clone(),
+            peer_addr,
+            stream: local_stream,
+            handshake_failures: self.handshake_failures.clone(),
+            connection_guard,
+        };
+
+        spawn_cancellable(
+            self.context.cancellation_token(),
+            async move {
+                let shed = async {
+                    match connection_slot {
+                        None => future::pending().await,
+                        Some(ref slot) => slot.shed().await,
+                    }
+                };
+
+                tokio::select! {
+                    r = client.serve() => {
+                        if let Err(err) = r {
+                            debug!("tcp server stream aborted with error: {}", err);
+                        }
+                    }
+                    _ = shed => {
+                        debug!("tcp client {} shed, servers are overloaded", peer_addr);
+                    }
+                }
+            }
+            .instrument(connection_span(peer_addr)),
+        );
+    }
+
+    fn reject(&self, local_stream: ProxyServerStream<MonProxyStream<TokioTcpStream>>, peer_addr: SocketAddr) {
+        let context = self.context.clone();
+        spawn_cancellable(self.context.cancellation_token(), async move {
+            reject_client(&context, peer_addr, local_stream.into_inner()).await;
+        });
+    }
+}
+
+/// Close a rejected client connection, the same way whatever the reason is
+///
+/// Probers shouldn't be able to tell failed handshakes, banned, rate limited and overloaded clients, clients blocked by
+/// ACL, nor exceeded quotas apart from the behavior of the server, nor from a server of a different protocol.
+///
+/// Note: AEAD-2022 connections failed handshakes are not reset immediately anymore, they are handled by the same policy.
+async fn reject_client(context: &ServiceContext, peer_addr: SocketAddr, mut stream: MonProxyStream<TokioTcpStream>) {
+    let config = context.handshake_failure_config();
+    let recorded = stream.take_recorded();
+
+    // Connections are held until clients close them, don't let floods of rejected clients exhaust file descriptors
+    let _slot = match context.try_hold_rejected() {
+        Some(slot) => slot,
+        None => {
+            debug!(
+                "tcp rejected peer: {} is reset, too many rejected connections",
+                peer_addr
+            );
+            let _ = stream.get_ref().set_linger(Some(Duration::ZERO));
+            return;
+        }
+    };
+
+    match config.policy {
+        HandshakeFailurePolicy::SilentDrop => {
+            debug!("tcp silent-drop peer: {}", peer_addr);
+
+            // Read until the client closes the connection, or timed out.
+            //
+            // Note: This will drop all data in the decryption buffer, which is no going back.
+            match time::timeout(config.timeout, ignore_until_end(&mut stream)).await {
+                Ok(res) => {
+                    trace!(
+                        "tcp silent-drop peer: {} is now closing with result {:?}",
+                        peer_addr, res
+                    );
+                }
+                Err(..) => {
+                    // Set SO_LINGER(0) for idle clients, which will eventually receive RST. (ECONNRESET)
+                    // This will also prevent the socket entering TIME_WAIT state.
+                    let _ = stream.get_ref().set_linger(Some(Duration::ZERO));
+
+                    trace!("tcp silent-drop peer: {} is now closing, timed out", peer_addr);
+                }
+            }
+        }
+        HandshakeFailurePolicy::Decoy(ref decoy_addr) => {
+            let mut decoy = match OutboundTcpStream::connect_server_with_opts(
+                context.context_ref(),
+                decoy_addr,
+                context.connect_opts_ref(),
+            )
+            .await
+            {
+                Ok(s) => s,
+                Err(err) => {
+                    debug!(
+                        "tcp decoy {} for peer {} connect failed, error: {}",
+                        decoy_addr, peer_addr, err
+                    );
+                    return;
+                }
             };
 
-            tokio::spawn(async move {
-                if let Err(err) = client.serve().await {
-                    debug!("tcp server stream aborted with error: {}", err);
+            debug!("tcp decoy peer: {} -> {}", peer_addr, decoy_addr);
+
+            if config.decoy_proxy_protocol {
+                let header = match stream.get_ref().local_addr() {
+                    Ok(local_addr) => proxy_protocol::make_proxy_header_v2(peer_addr, local_addr),
+                    Err(err) => {
+                        debug!("tcp decoy for peer {} local_addr failed, error: {}", peer_addr, err);
+                        return;
+                    }
+                };
+                if let Err(err) = decoy.write_all(&header).await {
+                    debug!(
+                        "tcp decoy {} for peer {} write failed, error: {}",
+                        decoy_addr, peer_addr, err
+                    );
+                    return;
                 }
-            });
+            }
+
+            if !recorded.is_empty() {
+                if let Err(err) = decoy.write_all(&recorded).await {
+                    debug!(
+                        "tcp decoy {} for peer {} write failed, error: {}",
+                        decoy_addr, peer_addr, err
+                    );
+                    return;
+                }
+            }
+
+            let res = tokio::io::copy_bidirectional(&mut stream, &mut decoy).await;
+            trace!(
+                "tcp decoy peer: {} -> {} is now closing with result {:?}",
+                peer_addr, decoy_addr, res
+            );
         }
     }
 }
@@ -118,16 +406,17 @@ where
 
 struct TcpServerClient {
     context: Arc<ServiceContext>,
-    method: CipherKind,
+    svr_cfg: Arc<ServerConfig>,
     peer_addr: SocketAddr,
     stream: ProxyServerStream<MonProxyStream<TokioTcpStream>>,
-    timeout: Option<Duration>,
+    handshake_failures: Arc<HandshakeFailureTracker>,
+    connection_guard: ConnectionGuard,
 }
 
 impl TcpServerClient {
     async fn serve(mut self) -> io::Result<()> {
         // let target_addr = match Address::read_from(&mut self.stream).await {
-        let target_addr = match timeout_fut(self.timeout, self.stream.handshake()).await {
+        let target_addr = match timeout_fut(self.svr_cfg.timeout(), self.stream.handshake()).await {
             Ok(a) => a,
             // Err(Socks5Error::IoError(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {
             //     debug!(
@@ -153,71 +442,125 @@ impl TcpServerClient {
             Err(err) => {
                 // https://github.com/shadowsocks/shadowsocks-rust/issues/292
                 //
-                // Keep connection open. Except AEAD-2022
+                // Keep connection open, or forward it to the decoy
                 warn!("tcp handshake failed. peer: {}, {}", self.peer_addr, err);
-
-                #[cfg(feature = "aead-cipher-2022")]
-                if self.method.is_aead_2022() {
-                    // Set SO_LINGER(0) for misbehave clients, which will eventually receive RST. (ECONNRESET)
-                    // This will also prevent the socket entering TIME_WAIT state.
-
-                    let stream = self.stream.into_inner().into_inner();
-                    let _ = stream.set_linger(Some(Duration::ZERO));
-
-                    return Ok(());
+                self.context.connection_stat_ref().add_handshake_failure();
+                if crypto_io::is_decrypt_error(&err) {
+                    self.context.record_error(ErrorClass::DecryptFailure);
                 }
 
-                debug!("tcp silent-drop peer: {}", self.peer_addr);
+                let peer_ip = self.peer_addr.ip();
+                if crypto_io::is_replay_error(&err) {
+                    let count = self.context.add_replay_source(peer_ip);
+                    if count.is_power_of_two() {
+                        events::publish(|| Event::ReplaySource {
+                            server: self.svr_cfg.addr().to_string(),
+                            peer_ip,
+                            count,
+                        });
+                    }
+                }
+                if let Some(count) = self.handshake_failures.add(peer_ip) {
+                    events::publish(|| Event::HandshakeFailures {
+                        server: self.svr_cfg.addr().to_string(),
+                        peer_ip,
+                        count,
+                    });
+                }
 
-                // Unwrap and get the plain stream.
-                // Otherwise it will keep reporting decryption error before reaching EOF.
-                //
-                // Note: This will drop all data in the decryption buffer, which is no going back.
-                let mut stream = self.stream.into_inner();
+                if let Some(duration) = self.context.add_client_handshake_failure(peer_ip) {
+                    warn!(
+                        "tcp client {} banned for {}s, failed handshakes repeatedly",
+                        peer_ip,
+                        duration.as_secs()
+                    );
+                    events::publish(|| Event::ClientBanned {
+                        server: self.svr_cfg.addr().to_string(),
+                        peer_ip,
+                        duration: duration.as_secs(),
+                    });
+                }
 
-                let res = ignore_until_end(&mut stream).await;
-
-                trace!(
-                    "tcp silent-drop peer: {} is now closing with result {:?}",
-                    self.peer_addr, res
-                );
+                reject_client(&self.context, self.peer_addr, self.stream.into_inner()).await;
 
                 return Ok(());
             }
         };
 
+        // Stop recording, the handshake succeeded
+        self.stream.get_mut().take_recorded();
+
+        record_target_addr(&target_addr);
+        self.connection_guard.set_target_addr(&target_addr);
         trace!(
             "accepted tcp client connection {}, establishing tunnel to {}",
             self.peer_addr, target_addr
         );
 
-        if self.context.check_outbound_blocked(&target_addr).await {
-            error!(
-                "tcp client {} outbound {} blocked by ACL rules",
+        let user = self.stream.user().cloned();
+        if let Some(ref user) = user {
+            if self.context.check_quota_exceeded(Some(user.name())) {
+                debug!(
+                    "tcp client {} rejected, quota of user {} exceeded",
+                    self.peer_addr,
+                    user.name()
+                );
+                reject_client(&self.context, self.peer_addr, self.stream.into_inner()).await;
+                return Ok(());
+            }
+
+            let user_flow_stat = self.context.accounting_ref().user_flow_stat(user.name());
+            self.stream.get_mut().set_user_flow_stat(user_flow_stat);
+        }
+
+        let resolved = match self.context.check_outbound(&target_addr).await {
+            CheckedOutbound::Blocked => {
+                error!(
+                    "tcp client {} outbound {} blocked by ACL rules",
+                    self.peer_addr, target_addr
+                );
+                self.context.record_error(ErrorClass::AclRejected);
+                return Ok(());
+            }
+            CheckedOutbound::Allowed => None,
+            CheckedOutbound::Resolved(addrs) => Some(addrs),
+        };
+
+        if self.context.bench_responder() && crate::bench::is_bench_target(&target_addr) {
+            debug!(
+                "tcp client {} served by bench responder {}",
                 self.peer_addr, target_addr
             );
-            return Ok(());
+            return crate::bench::serve_tcp(&target_addr, &mut self.stream).await;
         }
 
-        let mut remote_stream = match timeout_fut(
-            self.timeout,
-            OutboundTcpStream::connect_remote_with_opts(
-                self.context.context_ref(),
-                &target_addr,
-                self.context.connect_opts_ref(),
-            ),
-        )
-        .await
-        {
+        let connect_remote = async {
+            let context = self.context.context_ref();
+            let connect_opts = self.context.connect_opts_ref();
+            match resolved {
+                Some(addrs) => {
+                    OutboundTcpStream::connect_remote_resolved_with_opts(context, &target_addr, addrs, connect_opts)
+                        .await
+                }
+                None => OutboundTcpStream::connect_remote_with_opts(context, &target_addr, connect_opts).await,
+            }
+        };
+
+        let mut remote_stream = match timeout_fut(self.svr_cfg.timeout(), connect_remote).await {
             Ok(s) => s,
             Err(err) => {
                 error!(
                     "tcp tunnel {} -> {} connect failed, error: {}",
                     self.peer_addr, target_addr, err
                 );
+                if let Some(class) = ErrorClass::from_connect_error(&err) {
+                    self.context.record_error(class);
+                }
                 return Err(err);
             }
         };
+        let remote_addr = remote_stream.peer_addr().ok();
+        let mut remote_stream = PacketCounter::new(remote_stream);
 
         // https://github.com/shadowsocks/shadowsocks-rust/issues/232
         //
@@ -233,12 +576,12 @@ impl TcpServerClient {
                 }
                 Ok(Ok(n)) => {
                     // Send the first packet.
-                    timeout_fut(self.timeout, remote_stream.write_all(&buffer[..n])).await?;
+                    timeout_fut(self.svr_cfg.timeout(), remote_stream.write_all(&buffer[..n])).await?;
                 }
                 Ok(Err(err)) => return Err(err),
                 Err(..) => {
                     // Timeout. Send handshake to server.
-                    timeout_fut(self.timeout, remote_stream.write(&[])).await?;
+                    timeout_fut(self.svr_cfg.timeout(), remote_stream.write(&[])).await?;
 
                     trace!(
                         "tcp tunnel {} -> {} sent TFO connect without data",
@@ -255,21 +598,272 @@ impl TcpServerClient {
             self.context.connect_opts_ref()
         );
 
-        match copy_encrypted_bidirectional(self.method, &mut self.stream, &mut remote_stream).await {
+        let session = self.context.sessions_ref().register(
+            self.peer_addr,
+            target_addr.to_string(),
+            user.as_ref().map(|u| u.name().to_owned()),
+        );
+        self.stream.get_mut().set_session_flow_stat(session.flow_stat());
+
+        events::publish(|| Event::ConnectionOpened {
+            server: self.svr_cfg.addr().to_string(),
+            peer_addr: self.peer_addr,
+            target: target_addr.to_string(),
+        });
+
+        let established = Instant::now();
+
+        let quota = self.context.quota();
+        let user_name = user.as_ref().map(|u| u.name());
+        let quota_fut = async move {
+            match quota {
+                None => future::pending().await,
+                Some(quota) => quota.wait_terminate(user_name).await,
+            }
+        };
+        let copy_fut = copy_encrypted_bidirectional(self.svr_cfg.method(), &mut self.stream, &mut remote_stream);
+        let result = tokio::select! {
+            r = copy_fut => r,
+            _ = quota_fut => {
+                debug!(
+                    "tcp tunnel {} <-> {} terminated, quota exceeded",
+                    self.peer_addr, target_addr
+                );
+                Err(io::Error::new(ErrorKind::Other, "traffic quota exceeded"))
+            }
+            _ = session.killed() => {
+                info!(
+                    "tcp tunnel {} <-> {} (session {}) killed",
+                    self.peer_addr, target_addr, session.id()
+                );
+                Err(io::Error::new(ErrorKind::Other, "session killed"))
+            }
+        };
+
+        let (rx, tx, reason) = match result {
             Ok((rn, wn)) => {
                 trace!(
                     "tcp tunnel {} <-> {} closed, L2R {} bytes, R2L {} bytes",
                     self.peer_addr, target_addr, rn, wn
                 );
+                (rn, wn, CloseReason::Eof)
             }
-            Err(err) => {
+            Err(ref err) => {
                 trace!(
                     "tcp tunnel {} <-> {} closed with error: {}",
                     self.peer_addr, target_addr, err
                 );
+                // Bytes copied are lost with the error, take them from the session's counters of the client stream
+                let flow_stat = session.flow_stat();
+                (flow_stat.rx(), flow_stat.tx(), CloseReason::Error(err))
             }
-        }
+        };
+
+        access_log::record(|| AccessLogEntry {
+            client: self.peer_addr,
+            target: &target_addr,
+            server: Some(self.svr_cfg.addr()),
+            up: rx,
+            down: tx,
+            duration: established.elapsed(),
+            reason,
+        });
+
+        flow_export::record(|| FlowRecord {
+            protocol: FlowProtocol::Tcp,
+            client: self.peer_addr,
+            target: &target_addr,
+            remote: remote_addr,
+            up: rx,
+            up_packets: remote_stream.write_packets(),
+            down: tx,
+            down_packets: remote_stream.read_packets(),
+            duration: established.elapsed(),
+            idle: Duration::ZERO,
+        });
+
+        events::publish(|| Event::ConnectionClosed {
+            server: self.svr_cfg.addr().to_string(),
+            peer_addr: self.peer_addr,
+            target: target_addr.to_string(),
+            rx,
+            tx,
+        });
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::Ipv4Addr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use shadowsocks::crypto::CipherKind;
+    use tokio::net::TcpListener;
+
+    use crate::{
+        acl::AccessControl,
+        config::{
+            SecurityBanConfig, SecurityConfig, SecurityConnectionLimitConfig, SecurityRateLimitConfig,
+            TrafficQuotaConfig,
+        },
+        server::{conn_limit::ConnectionLimiter, quota::TrafficQuota},
+    };
+
+    use super::*;
+
+    const REJECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+    #[derive(Clone, Copy, Debug)]
+    enum Reason {
+        Banned,
+        RateLimited,
+        Acl,
+        Quota,
+        Overloaded,
+    }
+
+    const REASONS: [Reason; 5] = [
+        Reason::Banned,
+        Reason::RateLimited,
+        Reason::Acl,
+        Reason::Quota,
+        Reason::Overloaded,
+    ];
+
+    /// Context of a server rejecting clients from localhost for `reason`
+    fn rejecting_context(reason: Reason, policy: HandshakeFailurePolicy) -> ServiceContext {
+        let mut security = SecurityConfig::default();
+        security.handshake_failure.policy = policy;
+        security.handshake_failure.timeout = REJECT_TIMEOUT;
+        match reason {
+            Reason::Banned => {
+                security.ban = Some(SecurityBanConfig {
+                    threshold: 1,
+                    ..Default::default()
+                });
+            }
+            Reason::RateLimited => {
+                security.rate_limit = Some(SecurityRateLimitConfig {
+                    per_ip: Some(1),
+                    per_ip_burst: Some(1),
+                    ..Default::default()
+                });
+            }
+            _ => {}
+        }
+
+        let mut context = ServiceContext::new();
+        context.set_security_config(&security);
+
+        let client_ip = IpAddr::from(Ipv4Addr::LOCALHOST);
+        match reason {
+            Reason::Banned => {
+                assert!(context.add_client_handshake_failure(client_ip).is_some());
+            }
+            Reason::RateLimited => {
+                assert!(!context.check_accept_rate_limited(client_ip));
+            }
+            Reason::Acl => {
+                static ACL_ID: AtomicUsize = AtomicUsize::new(0);
+
+                let path = std::env::temp_dir().join(format!(
+                    "shadowsocks-tcprelay-{}-{}.acl",
+                    std::process::id(),
+                    ACL_ID.fetch_add(1, Ordering::Relaxed)
+                ));
+                std::fs::write(&path, "[reject_all]\n").unwrap();
+                let acl = AccessControl::load_from_file(&path);
+                let _ = std::fs::remove_file(&path);
+                context.set_acl(Arc::new(acl.unwrap()));
+            }
+            Reason::Quota => {
+                let config = TrafficQuotaConfig {
+                    server: Some(1),
+                    ..Default::default()
+                };
+                let quota = TrafficQuota::new(config, "test".to_owned(), context.flow_stat(), context.accounting());
+                context.flow_stat_ref().incr_tx(1);
+                quota.check();
+                context.set_quota(Arc::new(quota));
+            }
+            Reason::Overloaded => {
+                context.set_connection_limiter(Arc::new(ConnectionLimiter::new(SecurityConnectionLimitConfig {
+                    max_tcp: Some(0),
+                    ..Default::default()
+                })));
+            }
+        }
+
+        context
+    }
+
+    async fn start_server(context: ServiceContext) -> SocketAddr {
+        let svr_cfg = ServerConfig::new(
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            "test-password",
+            CipherKind::AES_128_GCM,
+        )
+        .unwrap();
+        let server = TcpServer::new(Arc::new(context), Arc::new(svr_cfg), AcceptOpts::default())
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        addr
+    }
+
+    #[tokio::test]
+    async fn reject_silent_drop() {
+        for reason in REASONS {
+            let addr = start_server(rejecting_context(reason, HandshakeFailurePolicy::SilentDrop)).await;
+
+            let mut stream = TokioTcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+
+            // Nothing is sent back, the connection is held until the timeout
+            let mut buf = [0u8; 64];
+            let held = time::timeout(REJECT_TIMEOUT / 2, stream.read(&mut buf)).await;
+            assert!(held.is_err(), "{reason:?} answered {held:?}");
+
+            let closed = time::timeout(REJECT_TIMEOUT * 4, stream.read(&mut buf))
+                .await
+                .expect("connection held after the timeout");
+            let err = closed.expect_err("connection closed without reset");
+            assert_eq!(err.kind(), ErrorKind::ConnectionReset, "{reason:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_decoy() {
+        const REQUEST: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+        const RESPONSE: &[u8] = b"HTTP/1.1 204 No Content\r\n\r\n";
+
+        let decoy = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let decoy_addr = decoy.local_addr().unwrap();
+
+        for reason in REASONS {
+            let policy = HandshakeFailurePolicy::Decoy(decoy_addr.into());
+            let addr = start_server(rejecting_context(reason, policy)).await;
+
+            let mut stream = TokioTcpStream::connect(addr).await.unwrap();
+            stream.write_all(REQUEST).await.unwrap();
+
+            let (mut decoy_stream, _) = time::timeout(REJECT_TIMEOUT, decoy.accept())
+                .await
+                .expect("not forwarded to the decoy")
+                .unwrap();
+            let mut request = vec![0u8; REQUEST.len()];
+            decoy_stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, REQUEST, "{reason:?}");
+
+            decoy_stream.write_all(RESPONSE).await.unwrap();
+            let mut response = vec![0u8; RESPONSE.len()];
+            stream.read_exact(&mut response).await.unwrap();
+            assert_eq!(response, RESPONSE, "{reason:?}");
+        }
+    }
+}
@@ -15,7 +15,7 @@ use lru_time_cache::LruCache;
 use rand::{Rng, SeedableRng, rngs::SmallRng};
 use shadowsocks::{
     ServerConfig,
-    config::ServerUser,
+    config::{ServerUser, ServerUserManager},
     crypto::CipherCategory,
     lookup_then,
     net::{
@@ -27,14 +27,29 @@ use shadowsocks::{
         udprelay::{MAXIMUM_UDP_PAYLOAD_SIZE, ProxySocket, options::UdpSocketControlData},
     },
 };
-use tokio::{runtime::Handle, sync::mpsc, task::JoinHandle, time};
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time,
+};
+use tracing::Instrument;
 
-use crate::net::{
-    MonProxySocket, UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE, UDP_ASSOCIATION_SEND_CHANNEL_SIZE,
-    packet_window::PacketWindowFilter, utils::to_ipv4_mapped,
+use crate::{
+    flow_export::UdpFlows,
+    log_control::{record_target_addr, udp_association_span},
+    net::{
+        ConnectionGuard, FlowStat, MonProxySocket, UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE,
+        UDP_ASSOCIATION_SEND_CHANNEL_SIZE, packet_window::PacketWindowFilter, utils::to_ipv4_mapped,
+    },
+    stats::ErrorClass,
+    utils::spawn_cancellable,
 };
 
-use super::context::ServiceContext;
+use super::{
+    conn_limit::{ConnectionKind, ConnectionSlot},
+    context::{CheckedOutbound, ServiceContext},
+};
 
 #[derive(Debug, Clone, Copy)]
 enum NatKey {
@@ -89,13 +104,14 @@ pub struct UdpServer {
     keepalive_rx: mpsc::Receiver<NatKey>,
     time_to_live: Duration,
     listener: Arc<MonProxySocket<InboundUdpSocket>>,
-    svr_cfg: ServerConfig,
+    svr_cfg: Arc<ServerConfig>,
+    user_manager_rx: watch::Receiver<Option<Arc<ServerUserManager>>>,
 }
 
 impl UdpServer {
     pub(crate) async fn new(
         context: Arc<ServiceContext>,
-        svr_cfg: ServerConfig,
+        svr_cfg: Arc<ServerConfig>,
         time_to_live: Option<Duration>,
         capacity: Option<usize>,
         accept_opts: AcceptOpts,
@@ -128,6 +144,8 @@ impl UdpServer {
         let socket = MonProxySocket::from_socket(socket, context.flow_stat());
         let listener = Arc::new(socket);
 
+        let user_manager_rx = context.subscribe_user_manager();
+
         Ok(UdpServer {
             context,
             assoc_map,
@@ -136,6 +154,7 @@ impl UdpServer {
             time_to_live,
             listener,
             svr_cfg,
+            user_manager_rx,
         })
     }
 
@@ -174,13 +193,21 @@ impl UdpServer {
                 let otx = otx.clone();
                 let listener = self.listener.clone();
                 let context = self.context.clone();
+                let mut user_manager_rx = self.user_manager_rx.clone();
 
-                other_receivers.push(tokio::spawn(async move {
+                other_receivers.push(spawn_cancellable(self.context.cancellation_token(), async move {
                     let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+                    let mut user_manager = listener.get_ref().clone_user_manager();
 
                     loop {
+                        if user_manager_rx.has_changed().unwrap_or(false) {
+                            user_manager = user_manager_rx.borrow_and_update().clone();
+                        }
+
                         let (n, peer_addr, target_addr, control) =
-                            match UdpServer::recv_one_packet(&context, &listener, &mut buffer).await {
+                            match UdpServer::recv_one_packet(&context, &listener, user_manager.as_deref(), &mut buffer)
+                                .await
+                            {
                                 Some(s) => s,
                                 None => continue,
                             };
@@ -199,7 +226,7 @@ impl UdpServer {
         }
 
         struct MulticoreTaskGuard<'a> {
-            tasks: &'a mut Vec<JoinHandle<()>>,
+            tasks: &'a mut Vec<JoinHandle<Option<()>>>,
         }
 
         impl Drop for MulticoreTaskGuard<'_> {
@@ -230,6 +257,8 @@ impl UdpServer {
         let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
         // Make a clone to self.listener to avoid borrowing self
         let listener = self.listener.clone();
+        // Users are cached by every receiver, instead of being locked on every packet
+        let mut user_manager = listener.get_ref().clone_user_manager();
         loop {
             tokio::select! {
                 _ = cleanup_timer.tick() => {
@@ -242,7 +271,11 @@ impl UdpServer {
                     self.assoc_map.keep_alive(&peer_addr);
                 }
 
-                recv_result = UdpServer::recv_one_packet(&self.context, &listener, &mut buffer) => {
+                Ok(..) = self.user_manager_rx.changed() => {
+                    user_manager = self.user_manager_rx.borrow_and_update().clone();
+                }
+
+                recv_result = UdpServer::recv_one_packet(&self.context, &listener, user_manager.as_deref(), &mut buffer) => {
                     let (n, peer_addr, target_addr, control) = match recv_result {
                         Some(s) => s,
                         None => continue,
@@ -278,9 +311,10 @@ impl UdpServer {
     async fn recv_one_packet(
         context: &ServiceContext,
         l: &MonProxySocket<InboundUdpSocket>,
+        user_manager: Option<&ServerUserManager>,
         buffer: &mut [u8],
     ) -> Option<(usize, SocketAddr, Address, Option<UdpSocketControlData>)> {
-        let (n, peer_addr, target_addr, control) = match l.recv_from_with_ctrl(buffer).await {
+        let (n, peer_addr, target_addr, control) = match l.recv_from_with_user_manager(buffer, user_manager).await {
             Ok(s) => s,
             Err(err) => {
                 error!("udp server recv packet failed. {}", err);
@@ -299,16 +333,23 @@ impl UdpServer {
             return None;
         }
 
+        if context.check_client_banned(peer_addr.ip()) {
+            trace!("udp client {} dropped, banned for failing handshakes", peer_addr);
+            return None;
+        }
+
         if context.check_client_blocked(&peer_addr) {
             warn!(
                 "udp client {} outbound {} access denied by ACL rules",
                 peer_addr, target_addr
             );
+            context.record_error(ErrorClass::AclRejected);
             return None;
         }
 
         if context.check_outbound_blocked(&target_addr).await {
             warn!("udp client {} outbound {} blocked by ACL rules", peer_addr, target_addr);
+            context.record_error(ErrorClass::AclRejected);
             return None;
         }
 
@@ -326,14 +367,18 @@ impl UdpServer {
         match self.assoc_map {
             NatMap::Association(ref mut m) => {
                 if let Some(assoc) = m.get(&peer_addr) {
-                    return assoc.try_send((peer_addr, target_addr, data, control));
+                    if !assoc.is_closed() {
+                        return assoc.try_send((peer_addr, target_addr, data, control));
+                    }
                 }
 
+                let connection_slot = acquire_connection_slot(&self.context)?;
                 let assoc = UdpAssociation::new_association(
                     self.context.clone(),
                     listener.clone(),
                     peer_addr,
                     self.keepalive_tx.clone(),
+                    connection_slot,
                 );
 
                 debug!("created udp association for {}", peer_addr);
@@ -354,15 +399,19 @@ impl UdpServer {
                 let client_session_id = xcontrol.client_session_id;
 
                 if let Some(assoc) = m.get(&client_session_id) {
-                    return assoc.try_send((peer_addr, target_addr, data, control));
+                    if !assoc.is_closed() {
+                        return assoc.try_send((peer_addr, target_addr, data, control));
+                    }
                 }
 
+                let connection_slot = acquire_connection_slot(&self.context)?;
                 let assoc = UdpAssociation::new_session(
                     self.context.clone(),
                     listener.clone(),
                     peer_addr,
                     self.keepalive_tx.clone(),
                     client_session_id,
+                    connection_slot,
                 );
 
                 debug!(
@@ -379,11 +428,26 @@ impl UdpServer {
     }
 }
 
+/// Acquire a slot of active connections for a new association, if connections are limited
+fn acquire_connection_slot(context: &ServiceContext) -> io::Result<Option<ConnectionSlot>> {
+    match context.connection_limiter() {
+        None => Ok(None),
+        Some(limiter) => match limiter.acquire(ConnectionKind::Udp) {
+            Some(slot) => Ok(Some(slot)),
+            None => {
+                context.record_error(ErrorClass::Overloaded);
+                Err(io::Error::new(ErrorKind::Other, "servers are overloaded"))
+            }
+        },
+    }
+}
+
 type UdpAssociationSendMessage = (SocketAddr, Address, Bytes, Option<UdpSocketControlData>);
 
 struct UdpAssociation {
-    assoc_handle: JoinHandle<()>,
+    assoc_handle: JoinHandle<Option<()>>,
     sender: mpsc::Sender<UdpAssociationSendMessage>,
+    _connection_guard: ConnectionGuard,
 }
 
 impl Drop for UdpAssociation {
@@ -398,9 +462,16 @@ impl UdpAssociation {
         inbound: Arc<MonProxySocket<InboundUdpSocket>>,
         peer_addr: SocketAddr,
         keepalive_tx: mpsc::Sender<NatKey>,
+        connection_slot: Option<ConnectionSlot>,
     ) -> UdpAssociation {
-        let (assoc_handle, sender) = UdpAssociationContext::create(context, inbound, peer_addr, keepalive_tx, None);
-        UdpAssociation { assoc_handle, sender }
+        let connection_guard = context.connection_stat().udp_guard(peer_addr);
+        let (assoc_handle, sender) =
+            UdpAssociationContext::create(context, inbound, peer_addr, keepalive_tx, None, connection_slot);
+        UdpAssociation {
+            assoc_handle,
+            sender,
+            _connection_guard: connection_guard,
+        }
     }
 
     #[cfg(feature = "aead-cipher-2022")]
@@ -410,10 +481,27 @@ impl UdpAssociation {
         peer_addr: SocketAddr,
         keepalive_tx: mpsc::Sender<NatKey>,
         client_session_id: u64,
+        connection_slot: Option<ConnectionSlot>,
     ) -> UdpAssociation {
-        let (assoc_handle, sender) =
-            UdpAssociationContext::create(context, inbound, peer_addr, keepalive_tx, Some(client_session_id));
-        UdpAssociation { assoc_handle, sender }
+        let connection_guard = context.connection_stat().udp_guard(peer_addr);
+        let (assoc_handle, sender) = UdpAssociationContext::create(
+            context,
+            inbound,
+            peer_addr,
+            keepalive_tx,
+            Some(client_session_id),
+            connection_slot,
+        );
+        UdpAssociation {
+            assoc_handle,
+            sender,
+            _connection_guard: connection_guard,
+        }
+    }
+
+    /// Check if the association was closed, like being shed for new associations
+    fn is_closed(&self) -> bool {
+        self.assoc_handle.is_finished()
     }
 
     fn try_send(&self, data: UdpAssociationSendMessage) -> io::Result<()> {
@@ -429,6 +517,7 @@ struct ClientSessionContext {
     client_session_id: u64,
     packet_window_filter: PacketWindowFilter,
     client_user: Option<Arc<ServerUser>>,
+    client_user_flow_stat: Option<Arc<FlowStat>>,
 }
 
 impl ClientSessionContext {
@@ -437,6 +526,7 @@ impl ClientSessionContext {
             client_session_id,
             packet_window_filter: PacketWindowFilter::new(),
             client_user: None,
+            client_user_flow_stat: None,
         }
     }
 }
@@ -453,11 +543,17 @@ struct UdpAssociationContext {
     client_session: Option<ClientSessionContext>,
     server_session_id: u64,
     server_packet_id: u64,
+    // Slot of limited active connections
+    connection_slot: Option<ConnectionSlot>,
+    flows: UdpFlows,
+    // Target of the first packet was recorded in the association's span
+    target_addr_recorded: bool,
 }
 
 impl Drop for UdpAssociationContext {
     fn drop(&mut self) {
         debug!("udp association for {} is closed", self.peer_addr);
+        self.flows.export(self.peer_addr);
     }
 }
 
@@ -482,7 +578,8 @@ impl UdpAssociationContext {
         peer_addr: SocketAddr,
         keepalive_tx: mpsc::Sender<NatKey>,
         client_session_id: Option<u64>,
-    ) -> (JoinHandle<()>, mpsc::Sender<UdpAssociationSendMessage>) {
+        connection_slot: Option<ConnectionSlot>,
+    ) -> (JoinHandle<Option<()>>, mpsc::Sender<UdpAssociationSendMessage>) {
         // Pending packets UDP_ASSOCIATION_SEND_CHANNEL_SIZE for each association should be good enough for a server.
         // If there are plenty of packets stuck in the channel, dropping excessive packets is a good way to protect the server from
         // being OOM.
@@ -500,8 +597,14 @@ impl UdpAssociationContext {
             // server_session_id must be generated randomly
             server_session_id: generate_server_session_id(),
             server_packet_id: 0,
+            connection_slot,
+            flows: UdpFlows::new(),
+            target_addr_recorded: false,
         };
-        let handle = tokio::spawn(async move { assoc.dispatch_packet(receiver).await });
+        let handle = spawn_cancellable(
+            assoc.context.cancellation_token(),
+            async move { assoc.dispatch_packet(receiver).await }.instrument(udp_association_span(peer_addr)),
+        );
 
         (handle, sender)
     }
@@ -522,6 +625,7 @@ impl UdpAssociationContext {
                         }
                     };
 
+                    self.touch_activity();
                     self.dispatch_received_packet(peer_addr, &target_addr, &data, &control).await;
                 }
 
@@ -537,6 +641,7 @@ impl UdpAssociationContext {
                     };
 
                     let addr = Address::from(addr);
+                    self.touch_activity();
                     self.send_received_respond_packet(addr, &outbound_ipv4_buffer[..n]).await;
                 }
 
@@ -552,9 +657,15 @@ impl UdpAssociationContext {
                     };
 
                     let addr = Address::from(addr);
+                    self.touch_activity();
                     self.send_received_respond_packet(addr, &outbound_ipv6_buffer[..n]).await;
                 }
 
+                _ = wait_shed(&self.connection_slot) => {
+                    debug!("udp association for {} shed, servers are overloaded", self.peer_addr);
+                    break;
+                }
+
                 _ = keepalive_interval.tick() => {
                     if self.keepalive_flag {
                         let nat_key = match self.client_session {
@@ -575,6 +686,14 @@ impl UdpAssociationContext {
             }
         }
 
+        #[inline]
+        async fn wait_shed(slot: &Option<ConnectionSlot>) {
+            match *slot {
+                None => future::pending().await,
+                Some(ref slot) => slot.shed().await,
+            }
+        }
+
         #[inline]
         async fn receive_from_outbound_opt(
             socket: &Option<OutboundUdpSocket>,
@@ -592,6 +711,13 @@ impl UdpAssociationContext {
         }
     }
 
+    #[inline]
+    fn touch_activity(&self) {
+        if let Some(ref slot) = self.connection_slot {
+            slot.touch();
+        }
+    }
+
     async fn dispatch_received_packet(
         &mut self,
         peer_addr: SocketAddr,
@@ -617,9 +743,23 @@ impl UdpAssociationContext {
             control,
         );
 
-        if self.context.check_outbound_blocked(target_addr).await {
-            error!(
-                "udp client {} outbound {} blocked by ACL rules",
+        let resolved = match self.context.check_outbound(target_addr).await {
+            CheckedOutbound::Blocked => {
+                error!(
+                    "udp client {} outbound {} blocked by ACL rules",
+                    self.peer_addr, target_addr
+                );
+                self.context.record_error(ErrorClass::AclRejected);
+                return;
+            }
+            CheckedOutbound::Allowed => None,
+            CheckedOutbound::Resolved(addrs) => Some(addrs),
+        };
+
+        let user = control.as_ref().and_then(|c| c.user.as_ref());
+        if self.context.check_quota_exceeded(user.map(|u| u.name())) {
+            debug!(
+                "udp client {} outbound {} dropped, quota exceeded",
                 self.peer_addr, target_addr
             );
             return;
@@ -642,9 +782,19 @@ impl UdpAssociationContext {
             }
 
             session_context.client_user.clone_from(&control.user);
+
+            if let Some(ref user) = control.user {
+                let user_flow_stat = session_context
+                    .client_user_flow_stat
+                    .get_or_insert_with(|| self.context.accounting_ref().user_flow_stat(user.name()));
+                user_flow_stat.incr_rx(data.len() as u64);
+            }
         }
 
-        if let Err(err) = self.dispatch_received_outbound_packet(target_addr, data).await {
+        if let Err(err) = self
+            .dispatch_received_outbound_packet(target_addr, resolved, data)
+            .await
+        {
             error!(
                 "udp relay {} -> {} with {} bytes, error: {}",
                 self.peer_addr,
@@ -655,10 +805,35 @@ impl UdpAssociationContext {
         }
     }
 
-    async fn dispatch_received_outbound_packet(&mut self, target_addr: &Address, data: &[u8]) -> io::Result<()> {
-        match *target_addr {
-            Address::SocketAddress(sa) => self.send_received_outbound_packet(sa, data).await,
-            Address::DomainNameAddress(ref dname, port) => {
+    async fn dispatch_received_outbound_packet(
+        &mut self,
+        target_addr: &Address,
+        resolved: Option<Vec<SocketAddr>>,
+        data: &[u8],
+    ) -> io::Result<()> {
+        if !self.target_addr_recorded {
+            record_target_addr(target_addr);
+            self.target_addr_recorded = true;
+        }
+
+        if self.context.bench_responder() && crate::bench::is_bench_target(target_addr) {
+            // Only the echo target is served over UDP
+            if target_addr.port() == crate::bench::BENCH_ECHO_PORT {
+                self.send_received_respond_packet(target_addr.clone(), data).await;
+            }
+            return Ok(());
+        }
+
+        match (target_addr, resolved) {
+            (&Address::SocketAddress(sa), _) => self.send_received_outbound_packet(sa, data).await,
+            // Addresses checked by countries are sent to, instead of resolving the domain name again
+            (&Address::DomainNameAddress(..), Some(addrs)) => {
+                lookup_then!(ADDRS @ self.context.context_ref(), addrs, |sa| {
+                    self.send_received_outbound_packet(sa, data).await
+                })
+                .map(|_| ())
+            }
+            (&Address::DomainNameAddress(ref dname, port), None) => {
                 lookup_then!(self.context.context_ref(), dname, port, |sa| {
                     self.send_received_outbound_packet(sa, data).await
                 })
@@ -717,6 +892,9 @@ impl UdpAssociationContext {
 
         match socket.send_to(data, target_addr).await {
             Ok(n) => {
+                let flow_target =
+                    SocketAddr::new(original_target_addr.ip().to_canonical(), original_target_addr.port());
+                self.flows.sent(self.peer_addr, &Address::SocketAddress(flow_target), n);
                 if n != data.len() {
                     warn!(
                         "{} -> {} sent {} bytes != expected {} bytes",
@@ -748,6 +926,7 @@ impl UdpAssociationContext {
                 addr = Address::SocketAddress(SocketAddr::new(v4.into(), v6.port()));
             }
         }
+        self.flows.received(self.peer_addr, &addr, data.len());
 
         match self.client_session {
             None => {
@@ -817,6 +996,10 @@ impl UdpAssociationContext {
                             data.len(),
                             control
                         );
+
+                        if let Some(ref user_flow_stat) = client_session.client_user_flow_stat {
+                            user_flow_stat.incr_tx(data.len() as u64);
+                        }
                     }
                 }
             }
@@ -0,0 +1,325 @@
+//! External stores of server users
+//!
+//! EIH users of a server could be loaded from a database instead of the configuration file.
+//! Users could be added, disabled, or have their quotas changed by writing to the store,
+//! and the running server will pick up the changes on the next refresh.
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use shadowsocks::{
+    config::{ServerUser, ServerUserManager},
+    crypto::CipherKind,
+};
+use tokio::time;
+
+use crate::config::UserStoreConfig;
+
+use super::context::ServiceContext;
+
+#[cfg(feature = "user-store-redis")]
+pub mod redis;
+#[cfg(feature = "user-store-sqlite")]
+pub mod sqlite;
+
+/// Default interval of reloading users from a store
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// User record in a store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredUser {
+    /// User name
+    pub name: String,
+    /// Base64 encoded key of user
+    pub key: String,
+    /// Bytes could be transferred by user, `None` for unlimited
+    pub quota: Option<u64>,
+    /// Disabled users are rejected, and their connections are closed
+    pub disabled: bool,
+}
+
+/// Store of server users
+#[trait_variant::make(Send)]
+pub trait UserStore {
+    /// Load all users from the store
+    async fn load_users(&mut self) -> io::Result<Vec<StoredUser>>;
+
+    /// Wait until users should be reloaded
+    async fn wait_changed(&mut self) -> io::Result<()>;
+}
+
+/// User store created from `UserStoreConfig`
+pub enum UserStoreBackend {
+    #[cfg(feature = "user-store-sqlite")]
+    Sqlite(sqlite::SqliteUserStore),
+    #[cfg(feature = "user-store-redis")]
+    Redis(redis::RedisUserStore),
+}
+
+impl UserStoreBackend {
+    /// Connect to the store in `config`
+    pub async fn open(config: &UserStoreConfig) -> io::Result<UserStoreBackend> {
+        let refresh_interval = config.refresh_interval.unwrap_or(DEFAULT_REFRESH_INTERVAL);
+
+        #[cfg(feature = "user-store-sqlite")]
+        if let Some(path) = config.url.strip_prefix("sqlite://") {
+            let store = sqlite::SqliteUserStore::open(path, config.table.as_deref(), refresh_interval).await?;
+            return Ok(UserStoreBackend::Sqlite(store));
+        }
+
+        #[cfg(feature = "user-store-redis")]
+        if config.url.starts_with("redis://") {
+            let store = redis::RedisUserStore::open(&config.url, config.table.as_deref(), refresh_interval).await?;
+            return Ok(UserStoreBackend::Redis(store));
+        }
+
+        Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("unsupported user store {}", config.url),
+        ))
+    }
+}
+
+impl UserStore for UserStoreBackend {
+    async fn load_users(&mut self) -> io::Result<Vec<StoredUser>> {
+        match *self {
+            #[cfg(feature = "user-store-sqlite")]
+            UserStoreBackend::Sqlite(ref mut s) => s.load_users().await,
+            #[cfg(feature = "user-store-redis")]
+            UserStoreBackend::Redis(ref mut s) => s.load_users().await,
+        }
+    }
+
+    async fn wait_changed(&mut self) -> io::Result<()> {
+        match *self {
+            #[cfg(feature = "user-store-sqlite")]
+            UserStoreBackend::Sqlite(ref mut s) => s.wait_changed().await,
+            #[cfg(feature = "user-store-redis")]
+            UserStoreBackend::Redis(ref mut s) => s.wait_changed().await,
+        }
+    }
+}
+
+/// Users loaded from a store
+pub struct LoadedUsers {
+    /// Enabled users
+    pub user_manager: ServerUserManager,
+    /// Quotas of users
+    pub quotas: HashMap<String, u64>,
+    /// Users that were removed, disabled, or had their keys changed since the previous load
+    pub revoked: Vec<String>,
+}
+
+/// Keeps users of a server in sync with a store
+pub struct UserStoreWatcher<S> {
+    store: S,
+    method: CipherKind,
+    server: String,
+    // Keys of enabled users in the previous load
+    users: HashMap<String, String>,
+}
+
+impl<S> UserStoreWatcher<S>
+where
+    S: UserStore,
+{
+    /// Create a watcher of users of `server`
+    pub fn new(store: S, method: CipherKind, server: String) -> UserStoreWatcher<S> {
+        UserStoreWatcher {
+            store,
+            method,
+            server,
+            users: HashMap::new(),
+        }
+    }
+
+    /// Load users from the store
+    ///
+    /// Users with invalid keys are skipped.
+    pub async fn load(&mut self) -> io::Result<LoadedUsers> {
+        let stored_users = self.store.load_users().await?;
+
+        let mut user_manager = ServerUserManager::new();
+        let mut quotas = HashMap::new();
+        let mut users = HashMap::new();
+
+        for stored in stored_users {
+            if stored.disabled {
+                continue;
+            }
+
+            let user = match ServerUser::with_encoded_key(stored.name.clone(), &stored.key) {
+                Ok(u) => u,
+                Err(..) => {
+                    warn!(
+                        "server {} user {} skipped, key should be base64 encoded",
+                        self.server, stored.name
+                    );
+                    continue;
+                }
+            };
+            if user.key().len() != self.method.key_len() {
+                warn!(
+                    "server {} user {} skipped, key length should be {} for method {}",
+                    self.server,
+                    stored.name,
+                    self.method.key_len(),
+                    self.method
+                );
+                continue;
+            }
+
+            user_manager.add_user(user);
+            if let Some(quota) = stored.quota {
+                quotas.insert(stored.name.clone(), quota);
+            }
+            users.insert(stored.name, stored.key);
+        }
+
+        let revoked = self
+            .users
+            .iter()
+            .filter(|(name, key)| users.get(*name) != Some(*key))
+            .map(|(name, _)| name.clone())
+            .collect();
+        self.users = users;
+
+        Ok(LoadedUsers {
+            user_manager,
+            quotas,
+            revoked,
+        })
+    }
+
+    /// Reload users whenever the store changes, and apply them to the running server
+    pub async fn run(mut self, context: Arc<ServiceContext>) -> io::Result<()> {
+        loop {
+            if let Err(err) = self.store.wait_changed().await {
+                error!("server {} user store failed with error: {}", self.server, err);
+                time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+
+            let loaded = match self.load().await {
+                Ok(l) => l,
+                Err(err) => {
+                    error!("server {} failed to load users, error: {}", self.server, err);
+                    continue;
+                }
+            };
+
+            apply_users(&context, &self.server, loaded);
+        }
+    }
+}
+
+/// Replace users of a running server
+fn apply_users(context: &ServiceContext, server: &str, loaded: LoadedUsers) {
+    let user_count = loaded.user_manager.user_count();
+    context.update_user_manager(Some(Arc::new(loaded.user_manager)));
+
+    if let Some(quota) = context.quota_ref() {
+        quota.set_user_quotas(loaded.quotas);
+    }
+
+    for name in &loaded.revoked {
+        let killed = context.sessions_ref().kill_user(name);
+        info!(
+            "server {} user {} revoked, {} sessions terminated",
+            server, name, killed
+        );
+    }
+
+    debug!("server {} reloaded {} users from user store", server, user_count);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY_A: &str = "AAAAAAAAAAAAAAAAAAAAAA==";
+    const KEY_B: &str = "AQEBAQEBAQEBAQEBAQEBAQ==";
+
+    struct MemoryUserStore {
+        users: Vec<StoredUser>,
+    }
+
+    impl UserStore for MemoryUserStore {
+        async fn load_users(&mut self) -> io::Result<Vec<StoredUser>> {
+            Ok(self.users.clone())
+        }
+
+        async fn wait_changed(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn user(name: &str, key: &str, disabled: bool) -> StoredUser {
+        StoredUser {
+            name: name.to_owned(),
+            key: key.to_owned(),
+            quota: None,
+            disabled,
+        }
+    }
+
+    fn revoked(loaded: &LoadedUsers) -> Vec<&str> {
+        let mut names = loaded.revoked.iter().map(String::as_str).collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+
+    #[tokio::test]
+    async fn revoked_users() {
+        let store = MemoryUserStore {
+            users: vec![
+                user("kept", KEY_A, false),
+                user("removed", KEY_A, false),
+                user("disabled", KEY_A, false),
+                user("rekeyed", KEY_A, false),
+            ],
+        };
+        let mut watcher = UserStoreWatcher::new(store, CipherKind::AEAD2022_BLAKE3_AES_128_GCM, "test".to_owned());
+
+        let loaded = watcher.load().await.unwrap();
+        assert_eq!(loaded.user_manager.user_count(), 4);
+        assert!(loaded.revoked.is_empty());
+
+        watcher.store.users = vec![
+            user("kept", KEY_A, false),
+            user("disabled", KEY_A, true),
+            user("rekeyed", KEY_B, false),
+            user("added", KEY_B, false),
+        ];
+        // Changing quotas doesn't revoke
+        watcher.store.users[0].quota = Some(1024);
+        let loaded = watcher.load().await.unwrap();
+        assert_eq!(loaded.user_manager.user_count(), 3);
+        assert_eq!(revoked(&loaded), ["disabled", "rekeyed", "removed"]);
+        assert_eq!(loaded.quotas.get("kept"), Some(&1024));
+
+        // Nothing changed
+        let loaded = watcher.load().await.unwrap();
+        assert!(loaded.revoked.is_empty());
+    }
+
+    #[tokio::test]
+    async fn invalid_keys_revoked() {
+        let store = MemoryUserStore {
+            users: vec![user("user", KEY_A, false)],
+        };
+        let mut watcher = UserStoreWatcher::new(store, CipherKind::AEAD2022_BLAKE3_AES_128_GCM, "test".to_owned());
+        watcher.load().await.unwrap();
+
+        // Keys of AES-128 are 16 bytes
+        watcher.store.users = vec![user("user", "AAAA", false)];
+        let loaded = watcher.load().await.unwrap();
+        assert_eq!(loaded.user_manager.user_count(), 0);
+        assert_eq!(revoked(&loaded), ["user"]);
+    }
+}
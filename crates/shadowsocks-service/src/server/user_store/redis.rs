@@ -0,0 +1,120 @@
+//! Redis user store
+//!
+//! Users are stored in a hash (`shadowsocks:users` by default), keyed by user name, with JSON values:
+//!
+//! ```plain
+//! HSET shadowsocks:users alice '{"key":"...","quota":1073741824,"disabled":false}'
+//! PUBLISH shadowsocks:users:changed 1
+//! ```
+//!
+//! The hash is reloaded immediately when anything is published to `<hash>:changed`,
+//! or every `refresh_interval`.
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    time::Duration,
+};
+
+use ::redis::{
+    AsyncCommands, Client, RedisError,
+    aio::{MultiplexedConnection, PubSub},
+};
+use futures::StreamExt;
+use log::{debug, warn};
+use serde::Deserialize;
+use tokio::time;
+
+use super::{StoredUser, UserStore};
+
+/// Default hash of users
+pub const DEFAULT_KEY: &str = "shadowsocks:users";
+
+#[derive(Deserialize)]
+struct RedisUser {
+    key: String,
+    #[serde(default)]
+    quota: Option<u64>,
+    #[serde(default)]
+    disabled: bool,
+}
+
+/// Users stored in Redis
+pub struct RedisUserStore {
+    client: Client,
+    connection: MultiplexedConnection,
+    pubsub: Option<PubSub>,
+    key: String,
+    refresh_interval: Duration,
+}
+
+fn redis_error(err: RedisError) -> io::Error {
+    io::Error::new(ErrorKind::Other, err)
+}
+
+impl RedisUserStore {
+    /// Connect to Redis at `url`
+    pub async fn open(url: &str, key: Option<&str>, refresh_interval: Duration) -> io::Result<RedisUserStore> {
+        let client = Client::open(url).map_err(redis_error)?;
+        let connection = client.get_multiplexed_async_connection().await.map_err(redis_error)?;
+
+        Ok(RedisUserStore {
+            client,
+            connection,
+            pubsub: None,
+            key: key.unwrap_or(DEFAULT_KEY).to_owned(),
+            refresh_interval,
+        })
+    }
+
+    async fn subscribe(&self) -> io::Result<PubSub> {
+        let mut pubsub = self.client.get_async_pubsub().await.map_err(redis_error)?;
+        pubsub
+            .subscribe(format!("{}:changed", self.key))
+            .await
+            .map_err(redis_error)?;
+        Ok(pubsub)
+    }
+}
+
+impl UserStore for RedisUserStore {
+    async fn load_users(&mut self) -> io::Result<Vec<StoredUser>> {
+        let values: HashMap<String, String> = self.connection.hgetall(&self.key).await.map_err(redis_error)?;
+
+        let mut users = Vec::with_capacity(values.len());
+        for (name, value) in values {
+            match serde_json::from_str::<RedisUser>(&value) {
+                Ok(u) => users.push(StoredUser {
+                    name,
+                    key: u.key,
+                    quota: u.quota,
+                    disabled: u.disabled,
+                }),
+                Err(err) => {
+                    warn!("redis user {} in {} skipped, error: {}", name, self.key, err);
+                }
+            }
+        }
+        Ok(users)
+    }
+
+    async fn wait_changed(&mut self) -> io::Result<()> {
+        if self.pubsub.is_none() {
+            self.pubsub = Some(self.subscribe().await?);
+        }
+
+        let mut messages = self.pubsub.as_mut().unwrap().on_message();
+        let closed = tokio::select! {
+            message = messages.next() => message.is_none(),
+            _ = time::sleep(self.refresh_interval) => false,
+        };
+        drop(messages);
+
+        if closed {
+            // Reload now, since changes may be missed before subscribing again
+            debug!("redis subscription of {} closed", self.key);
+            self.pubsub = None;
+        }
+        Ok(())
+    }
+}
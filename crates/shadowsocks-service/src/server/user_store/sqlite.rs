@@ -0,0 +1,90 @@
+//! SQLite user store
+//!
+//! Users are stored in a table (`users` by default):
+//!
+//! ```sql
+//! CREATE TABLE users (
+//!     name     TEXT PRIMARY KEY,
+//!     key      TEXT NOT NULL,
+//!     quota    INTEGER,
+//!     disabled INTEGER NOT NULL DEFAULT 0
+//! );
+//! ```
+//!
+//! The table is reloaded every `refresh_interval`.
+
+use std::{
+    io::{self, ErrorKind},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rusqlite::{Connection, OpenFlags};
+use tokio::{task, time};
+
+use super::{StoredUser, UserStore};
+
+/// Default table of users
+pub const DEFAULT_TABLE: &str = "users";
+
+/// Users stored in a SQLite database
+pub struct SqliteUserStore {
+    connection: Arc<Mutex<Connection>>,
+    table: String,
+    refresh_interval: Duration,
+}
+
+impl SqliteUserStore {
+    /// Open database at `path`, read-only
+    pub async fn open(path: &str, table: Option<&str>, refresh_interval: Duration) -> io::Result<SqliteUserStore> {
+        let table = table.unwrap_or(DEFAULT_TABLE);
+        if table.is_empty() || !table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid table name \"{table}\""),
+            ));
+        }
+
+        let path = PathBuf::from(path);
+        let connection = task::spawn_blocking(move || {
+            Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+        })
+        .await?
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+
+        Ok(SqliteUserStore {
+            connection: Arc::new(Mutex::new(connection)),
+            table: table.to_owned(),
+            refresh_interval,
+        })
+    }
+}
+
+impl UserStore for SqliteUserStore {
+    async fn load_users(&mut self) -> io::Result<Vec<StoredUser>> {
+        let connection = self.connection.clone();
+        let query = format!("SELECT name, key, quota, disabled FROM {}", self.table);
+
+        task::spawn_blocking(move || {
+            let connection = connection.lock().unwrap();
+            let mut stmt = connection.prepare(&query)?;
+            let users = stmt.query_map([], |row| {
+                Ok(StoredUser {
+                    name: row.get(0)?,
+                    key: row.get(1)?,
+                    quota: row.get::<_, Option<i64>>(2)?.map(|q| q.max(0) as u64),
+                    disabled: row.get::<_, Option<bool>>(3)?.unwrap_or(false),
+                })
+            })?;
+            users.collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .await?
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))
+    }
+
+    async fn wait_changed(&mut self) -> io::Result<()> {
+        time::sleep(self.refresh_interval).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,656 @@
+//! Aggregated statistics
+//!
+//! Services register themselves as [`StatsSource`]s, and [`snapshot`] collects all registered sources into one
+//! serializable [`StatsSnapshot`]. The admin APIs, the Prometheus exporter and the `SIGUSR1` dump are all built on it:
+//!
+//! ```json
+//! {
+//!     "uptime": 3600.5,
+//!     "memory_usage": null,
+//!     "memory_limit": null,
+//!     "servers": {
+//!         "0.0.0.0:8388": {
+//!             "tx": 1024,
+//!             "rx": 2048,
+//!             "tcp_connections": 3,
+//!             "udp_associations": 1,
+//!             "handshake_failures": 0,
+//!             "replay_detected": 0,
+//!             "dns": { "queries": 10, "failures": 0, "duration_micros": 5230, "cache_entries": null },
+//!             "memory": {
+//!                 "replay_filter_bytes": 3594376,
+//!                 "replay_nonces": 0,
+//!                 "relay_buffer_size": 16383,
+//!                 "relay_buffers_bytes": 98298,
+//!                 "udp_max_associations": null,
+//!                 "reverse_lookup_cache_capacity": null
+//!             },
+//!             "errors": {
+//!                 "dns_failure": 0,
+//!                 "connect_timeout": 2,
+//!                 "connect_refused": 1,
+//!                 "decrypt_failure": 0,
+//!                 "replay_detected": 0,
+//!                 "acl_rejected": 0,
+//!                 "plugin_unavailable": 0,
+//!                 "overloaded": 0
+//!             },
+//!             "users": {},
+//!             "replay_sources": [
+//!                 { "peer_ip": "192.0.2.1", "count": 3, "first_seen": 1700000000, "last_seen": 1700000060 }
+//!             ]
+//!         }
+//!     },
+//!     "local": null
+//! }
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug},
+    io::{self, ErrorKind},
+    net::IpAddr,
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use shadowsocks::context::{Context, ContextStat};
+
+use crate::{
+    memory,
+    metrics_sink::{self, RELAY_ERRORS},
+    net::ActiveConnection,
+};
+
+/// Source of statistics, which will be collected on every snapshot
+pub trait StatsSource: Send + Sync {
+    /// Add statistics of this source into `snapshot`
+    fn collect(&self, snapshot: &mut StatsSnapshot);
+
+    /// Add active connections of this source into `table`
+    fn collect_connections(&self, table: &mut ConnectionTable) {
+        let _ = table;
+    }
+
+    /// Clear caches of this source, like DNS caches
+    fn clear_caches(&self) {}
+}
+
+/// Active connections of servers, keyed by their listening addresses
+pub type ConnectionTable = BTreeMap<String, Vec<ActiveConnection>>;
+
+static SOURCES: Lazy<Mutex<Vec<Weak<dyn StatsSource>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static STARTED: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Register a source of statistics
+///
+/// Only a weak reference is kept, the source will be unregistered automatically when it is dropped.
+pub fn register<S: StatsSource + 'static>(source: &Arc<S>) {
+    Lazy::force(&STARTED);
+
+    let source: Arc<dyn StatsSource> = source.clone();
+    let mut sources = SOURCES.lock().unwrap();
+    sources.retain(|s| s.strong_count() > 0);
+    sources.push(Arc::downgrade(&source));
+}
+
+fn sources() -> Vec<Arc<dyn StatsSource>> {
+    let mut sources = SOURCES.lock().unwrap();
+    sources.retain(|s| s.strong_count() > 0);
+    sources.iter().filter_map(Weak::upgrade).collect()
+}
+
+/// Collect statistics from all registered sources
+pub fn snapshot() -> StatsSnapshot {
+    let sources = sources();
+
+    let mut snapshot = StatsSnapshot {
+        uptime: STARTED.elapsed().as_secs_f64(),
+        memory_usage: memory::memory_usage(),
+        memory_limit: memory::memory_limit(),
+        ..Default::default()
+    };
+    for source in sources {
+        source.collect(&mut snapshot);
+    }
+    snapshot
+}
+
+/// Collect active connections from all registered sources
+pub fn connection_table() -> ConnectionTable {
+    let mut table = ConnectionTable::new();
+    for source in sources() {
+        source.collect_connections(&mut table);
+    }
+    table
+}
+
+/// Clear caches of all registered sources
+pub fn clear_caches() {
+    for source in sources() {
+        source.clear_caches();
+    }
+}
+
+/// Statistics of all services in this process
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatsSnapshot {
+    /// Seconds since the first service started
+    pub uptime: f64,
+    /// Bytes of memory allocated by the process, `None` if it is not reported by the application
+    pub memory_usage: Option<usize>,
+    /// Soft limit of memory usage in bytes, `None` for unlimited
+    pub memory_limit: Option<usize>,
+    /// Servers, keyed by their listening addresses
+    pub servers: BTreeMap<String, ServerStats>,
+    /// Local, `None` if not running
+    pub local: Option<LocalStats>,
+}
+
+impl StatsSnapshot {
+    /// Total active UDP associations of all servers
+    pub fn udp_associations(&self) -> u64 {
+        self.servers.values().map(|s| s.udp_associations).sum()
+    }
+
+    /// Total repeated nonce (iv/salt) detected by all services
+    pub fn replay_detected(&self) -> u64 {
+        let servers = self.servers.values().map(|s| s.replay_detected).sum::<u64>();
+        servers + self.local.as_ref().map_or(0, |l| l.replay_detected)
+    }
+}
+
+/// DNS resolving statistics of a `shadowsocks` Context
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct DnsStats {
+    /// Number of DNS queries
+    pub queries: u64,
+    /// Number of failed DNS queries
+    pub failures: u64,
+    /// Total time spent in DNS queries, in microseconds
+    pub duration_micros: u64,
+    /// Entries in the reverse lookup cache of local DNS relay, `None` if unavailable
+    pub cache_entries: Option<usize>,
+}
+
+impl DnsStats {
+    pub(crate) fn new(stat: &ContextStat) -> DnsStats {
+        DnsStats {
+            queries: stat.dns_queries(),
+            failures: stat.dns_failures(),
+            duration_micros: stat.dns_duration_micros(),
+            cache_entries: None,
+        }
+    }
+}
+
+/// Memory consumers of a service
+///
+/// Sizes are estimated from the capacities and sizes configured, not counted by allocators.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct MemoryStats {
+    /// Bytes of the bloom filter against replay attack of stream and AEAD ciphers, sized by `replay_filter_capacity`
+    pub replay_filter_bytes: usize,
+    /// Nonces of AEAD 2022 ciphers remembered against replay attack
+    pub replay_nonces: usize,
+    /// Bytes of each relay buffer, limited by `relay_buffer_size`
+    pub relay_buffer_size: usize,
+    /// Bytes of relay buffers of active TCP connections, each of them holds two
+    pub relay_buffers_bytes: u64,
+    /// Maximum number of UDP associations, `None` for unlimited
+    pub udp_max_associations: Option<usize>,
+    /// Capacity of the reverse lookup cache of local DNS relay, `None` if unavailable
+    pub reverse_lookup_cache_capacity: Option<usize>,
+}
+
+impl MemoryStats {
+    pub(crate) fn new(context: &Context) -> MemoryStats {
+        MemoryStats {
+            replay_filter_bytes: context.replay_filter_size(),
+            replay_nonces: context.replay_nonce_count(),
+            ..Default::default()
+        }
+    }
+
+    /// Set size of relay buffers, and estimate bytes of them held by `tcp_connections`
+    pub(crate) fn set_relay_buffers(&mut self, relay_buffer_size: usize, tcp_connections: u64) {
+        self.relay_buffer_size = relay_buffer_size;
+        self.relay_buffers_bytes = tcp_connections * 2 * relay_buffer_size as u64;
+    }
+}
+
+/// Traffic of an EIH user
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct UserStats {
+    /// Bytes sent to the user
+    pub tx: u64,
+    /// Bytes received from the user
+    pub rx: u64,
+}
+
+/// Statistics of a server
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ServerStats {
+    /// Bytes sent to clients
+    pub tx: u64,
+    /// Bytes received from clients
+    pub rx: u64,
+    /// Active TCP connections
+    pub tcp_connections: u64,
+    /// Active UDP associations
+    pub udp_associations: u64,
+    /// Failed TCP handshakes
+    pub handshake_failures: u64,
+    /// Repeated nonce (iv/salt) detected
+    pub replay_detected: u64,
+    /// DNS resolving
+    pub dns: DnsStats,
+    /// Memory consumers
+    pub memory: MemoryStats,
+    /// Classified failures
+    pub errors: ErrorStats,
+    /// Traffic of EIH users, keyed by user name
+    pub users: BTreeMap<String, UserStats>,
+    /// Clients sent requests rejected by the replay filter, the most recently seen first
+    pub replay_sources: Vec<ReplaySourceStats>,
+}
+
+/// A client sent requests rejected by the replay filter
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplaySourceStats {
+    /// IP of the client
+    pub peer_ip: IpAddr,
+    /// Replayed requests from the client
+    pub count: u64,
+    /// Unix timestamp in seconds of the first replayed request
+    pub first_seen: u64,
+    /// Unix timestamp in seconds of the last replayed request
+    pub last_seen: u64,
+}
+
+impl ReplaySourceStats {
+    /// Create from times of the first and the last replayed request
+    pub fn new(peer_ip: IpAddr, count: u64, first_seen: SystemTime, last_seen: SystemTime) -> ReplaySourceStats {
+        let unix_secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        ReplaySourceStats {
+            peer_ip,
+            count,
+            first_seen: unix_secs(first_seen),
+            last_seen: unix_secs(last_seen),
+        }
+    }
+}
+
+/// Statistics of local
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LocalStats {
+    /// Bytes sent to remote servers
+    pub tx: u64,
+    /// Bytes received from remote servers
+    pub rx: u64,
+    /// Active TCP connections
+    pub tcp_connections: u64,
+    /// Repeated nonce (iv/salt) detected
+    pub replay_detected: u64,
+    /// DNS resolving
+    pub dns: DnsStats,
+    /// Memory consumers
+    pub memory: MemoryStats,
+    /// Servers in balancer
+    pub balancer: Vec<BalancerServerStats>,
+    /// Destination domains with the most recent traffic, the busiest first
+    pub domains: Vec<DomainTrafficStats>,
+}
+
+/// Traffic of TCP connections to a destination domain
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DomainTrafficStats {
+    /// Domain name, SNI, or IP address if neither is available
+    pub domain: String,
+    /// Bytes sent to the domain
+    pub tx: u64,
+    /// Bytes received from the domain
+    pub rx: u64,
+    /// Connections relayed bytes to the domain
+    pub connections: u64,
+    /// Bytes decayed by their ages, which orders the domains
+    pub recent_bytes: u64,
+}
+
+/// State of a server in balancer
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BalancerServerStats {
+    /// Address of the server
+    pub server: String,
+    /// Whether the server is pinned
+    pub pinned: bool,
+    /// State of TCP
+    pub tcp: BalancerScoreStats,
+    /// State of UDP
+    pub udp: BalancerScoreStats,
+    /// Latency of TCP connections relayed by the server
+    pub latency: ServerLatencyStats,
+    /// Classified failures of connections relayed by the server
+    pub errors: ErrorStats,
+}
+
+/// Latency of TCP connections relayed by a server
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ServerLatencyStats {
+    /// Connecting to the server, or its plugin
+    pub connect: HistogramStats,
+    /// From sending the request header, to receiving the response from the server
+    pub handshake: HistogramStats,
+    /// From starting to connect, to receiving the first byte of data
+    pub first_byte: HistogramStats,
+}
+
+/// Score of a server in balancer, of TCP or UDP
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct BalancerScoreStats {
+    /// Score, lower is better
+    pub score: u32,
+    /// Not marked down by health checks
+    pub healthy: bool,
+    /// Currently selected
+    pub selected: bool,
+    /// Latency of the last successful check in milliseconds
+    pub last_latency: Option<u32>,
+    /// Failed checks and requests
+    pub failures: u64,
+}
+
+/// Class of failures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Connecting timed out
+    ConnectTimeout,
+    /// Connecting was refused
+    ConnectRefused,
+    /// Data couldn't be decrypted, usually caused by a wrong key or probing
+    DecryptFailure,
+    /// Rejected by ACL rules
+    AclRejected,
+    /// Plugin of the server is not running
+    PluginUnavailable,
+    /// Rejected by limits of active connections
+    Overloaded,
+}
+
+impl ErrorClass {
+    /// Class of an error returned by connecting, `None` if it is not classified
+    pub fn from_connect_error(err: &io::Error) -> Option<ErrorClass> {
+        match err.kind() {
+            ErrorKind::TimedOut => Some(ErrorClass::ConnectTimeout),
+            ErrorKind::ConnectionRefused => Some(ErrorClass::ConnectRefused),
+            _ => None,
+        }
+    }
+
+    /// Name of the class, used as labels of metrics
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorClass::ConnectTimeout => "connect_timeout",
+            ErrorClass::ConnectRefused => "connect_refused",
+            ErrorClass::DecryptFailure => "decrypt_failure",
+            ErrorClass::AclRejected => "acl_rejected",
+            ErrorClass::PluginUnavailable => "plugin_unavailable",
+            ErrorClass::Overloaded => "overloaded",
+        }
+    }
+}
+
+/// Counters of classified failures
+///
+/// DNS failures and replay detections are counted by the `shadowsocks` Context, and are merged in snapshots.
+#[derive(Debug, Default)]
+pub struct ErrorCounters {
+    connect_timeout: AtomicU64,
+    connect_refused: AtomicU64,
+    decrypt_failure: AtomicU64,
+    acl_rejected: AtomicU64,
+    plugin_unavailable: AtomicU64,
+    overloaded: AtomicU64,
+}
+
+impl ErrorCounters {
+    /// Create counters with all zeros
+    pub fn new() -> ErrorCounters {
+        ErrorCounters::default()
+    }
+
+    /// Count a failure
+    pub fn record(&self, class: ErrorClass) {
+        let counter = match class {
+            ErrorClass::ConnectTimeout => &self.connect_timeout,
+            ErrorClass::ConnectRefused => &self.connect_refused,
+            ErrorClass::DecryptFailure => &self.decrypt_failure,
+            ErrorClass::AclRejected => &self.acl_rejected,
+            ErrorClass::PluginUnavailable => &self.plugin_unavailable,
+            ErrorClass::Overloaded => &self.overloaded,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        metrics_sink::counter(&RELAY_ERRORS, &[("class", class.name())], 1);
+    }
+
+    /// Take a snapshot of counters, with DNS failures and replay detections of `stat`
+    pub fn snapshot(&self, stat: Option<&ContextStat>) -> ErrorStats {
+        ErrorStats {
+            dns_failure: stat.map_or(0, |s| s.dns_failures()),
+            connect_timeout: self.connect_timeout.load(Ordering::Relaxed),
+            connect_refused: self.connect_refused.load(Ordering::Relaxed),
+            decrypt_failure: self.decrypt_failure.load(Ordering::Relaxed),
+            replay_detected: stat.map_or(0, |s| s.replay_detected()),
+            acl_rejected: self.acl_rejected.load(Ordering::Relaxed),
+            plugin_unavailable: self.plugin_unavailable.load(Ordering::Relaxed),
+            overloaded: self.overloaded.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Snapshot of [`ErrorCounters`]
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ErrorStats {
+    /// Failed DNS queries
+    pub dns_failure: u64,
+    /// Connecting timed out
+    pub connect_timeout: u64,
+    /// Connecting was refused
+    pub connect_refused: u64,
+    /// Data couldn't be decrypted
+    pub decrypt_failure: u64,
+    /// Repeated nonce (iv/salt) detected
+    pub replay_detected: u64,
+    /// Rejected by ACL rules
+    pub acl_rejected: u64,
+    /// Plugin of the server is not running
+    pub plugin_unavailable: u64,
+    /// Rejected by limits of active connections
+    pub overloaded: u64,
+}
+
+impl ErrorStats {
+    /// Counters with their class names
+    pub fn classes(&self) -> [(&'static str, u64); 8] {
+        [
+            ("dns_failure", self.dns_failure),
+            ("connect_timeout", self.connect_timeout),
+            ("connect_refused", self.connect_refused),
+            ("decrypt_failure", self.decrypt_failure),
+            ("replay_detected", self.replay_detected),
+            ("acl_rejected", self.acl_rejected),
+            ("plugin_unavailable", self.plugin_unavailable),
+            ("overloaded", self.overloaded),
+        ]
+    }
+}
+
+// Values below 2^SUB_BUCKET_BITS microseconds have their own buckets, and every power of 2 above is split into
+// 2^SUB_BUCKET_BITS linear buckets, so the relative error is at most 1/2^SUB_BUCKET_BITS (25%), like HdrHistogram
+// with 1 significant digit.
+const SUB_BUCKET_BITS: u32 = 2;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+// Values are clamped to 2^26 microseconds (about 67 seconds)
+const MAX_VALUE_BITS: u32 = 26;
+const HISTOGRAM_BUCKETS: usize = SUB_BUCKETS + (MAX_VALUE_BITS - SUB_BUCKET_BITS) as usize * SUB_BUCKETS;
+
+/// Histogram of latencies, with buckets growing exponentially from 1 microsecond to about 67 seconds
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> LatencyHistogram {
+        LatencyHistogram::new()
+    }
+}
+
+impl Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LatencyHistogram")
+            .field("count", &self.count.load(Ordering::Relaxed))
+            .field("sum_micros", &self.sum_micros.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl LatencyHistogram {
+    /// Create an empty histogram
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            buckets: [const { AtomicU64::new(0) }; HISTOGRAM_BUCKETS],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// Record a latency
+    pub fn record(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        self.buckets[bucket_index(micros)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of recorded latencies
+    pub fn snapshot(&self) -> HistogramStats {
+        let mut buckets = Vec::new();
+        let mut count = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            let n = bucket.load(Ordering::Relaxed);
+            if n > 0 {
+                buckets.push(HistogramBucket {
+                    le_micros: bucket_upper_bound(index),
+                    count: n,
+                });
+                count += n;
+            }
+        }
+
+        HistogramStats {
+            count,
+            sum_micros: self.sum_micros.load(Ordering::Relaxed),
+            p50_micros: percentile(&buckets, count, 0.50),
+            p90_micros: percentile(&buckets, count, 0.90),
+            p99_micros: percentile(&buckets, count, 0.99),
+            buckets,
+        }
+    }
+}
+
+fn bucket_index(micros: u64) -> usize {
+    let micros = micros.min((1 << MAX_VALUE_BITS) - 1);
+    if micros < SUB_BUCKETS as u64 {
+        return micros as usize;
+    }
+    let bits = u64::BITS - micros.leading_zeros();
+    let shift = bits - 1 - SUB_BUCKET_BITS;
+    let sub = (micros >> shift) as usize - SUB_BUCKETS;
+    SUB_BUCKETS + shift as usize * SUB_BUCKETS + sub
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64 + 1;
+    }
+    let shift = (index - SUB_BUCKETS) / SUB_BUCKETS;
+    let sub = (index - SUB_BUCKETS) % SUB_BUCKETS;
+    ((SUB_BUCKETS + sub + 1) as u64) << shift
+}
+
+fn percentile(buckets: &[HistogramBucket], count: u64, quantile: f64) -> Option<u64> {
+    if count == 0 {
+        return None;
+    }
+    let rank = ((count as f64 * quantile).ceil() as u64).max(1);
+    let mut seen = 0;
+    for bucket in buckets {
+        seen += bucket.count;
+        if seen >= rank {
+            return Some(bucket.le_micros);
+        }
+    }
+    buckets.last().map(|b| b.le_micros)
+}
+
+/// Snapshot of a [`LatencyHistogram`]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HistogramStats {
+    /// Number of recorded latencies
+    pub count: u64,
+    /// Sum of recorded latencies in microseconds
+    pub sum_micros: u64,
+    /// Estimated median in microseconds, `None` if empty
+    pub p50_micros: Option<u64>,
+    /// Estimated 90th percentile in microseconds, `None` if empty
+    pub p90_micros: Option<u64>,
+    /// Estimated 99th percentile in microseconds, `None` if empty
+    pub p99_micros: Option<u64>,
+    /// Non-empty buckets, in ascending order
+    pub buckets: Vec<HistogramBucket>,
+}
+
+/// A bucket of [`HistogramStats`]
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct HistogramBucket {
+    /// Exclusive upper bound in microseconds
+    pub le_micros: u64,
+    /// Number of latencies in this bucket, not cumulative
+    pub count: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        for micros in [0, 1, 3, 4, 5, 7, 8, 1000, 1023, 1024, 65_000_000] {
+            let index = bucket_index(micros);
+            assert!(micros < bucket_upper_bound(index), "{micros} in bucket {index}");
+            assert!(
+                index == 0 || micros >= bucket_upper_bound(index - 1),
+                "{micros} in bucket {index}"
+            );
+        }
+        assert_eq!(bucket_index(u64::MAX), HISTOGRAM_BUCKETS - 1);
+
+        let histogram = LatencyHistogram::new();
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        let stats = histogram.snapshot();
+        assert_eq!(stats.count, 100);
+        assert_eq!(stats.sum_micros, 5050 * 1000);
+        let p50 = stats.p50_micros.unwrap();
+        assert!((50_000..=65_536).contains(&p50), "p50 {p50}");
+        assert!(stats.p99_micros.unwrap() >= 99_000);
+    }
+}
@@ -30,6 +30,20 @@ pub fn set_nofile(nofile: u64) -> io::Result<()> {
     Ok(())
 }
 
+/// Get the soft limit of `RLIMIT_NOFILE`
+#[allow(dead_code)]
+pub fn get_nofile() -> io::Result<u64> {
+    use std::io::Error;
+
+    unsafe {
+        let mut lim: libc::rlimit = std::mem::zeroed();
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut lim as *mut _) < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(lim.rlim_cur as u64)
+    }
+}
+
 #[allow(dead_code)]
 #[cfg(target_os = "android")]
 pub fn set_nofile(_nofile: u64) -> io::Result<()> {
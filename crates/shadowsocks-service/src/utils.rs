@@ -8,6 +8,7 @@ use std::{
 };
 
 use futures::ready;
+use shadowsocks::context::CancellationToken;
 use tokio::task::JoinHandle;
 
 /// Wrapper of `tokio::task::JoinHandle`, which links to a server instance.
@@ -34,3 +35,15 @@ impl Future for ServerHandle {
         }
     }
 }
+
+/// Spawn `future` on the current runtime, it is dropped when `token` is cancelled
+///
+/// Tasks of connections are not linked to servers accepting them, they have to be stopped by the token.
+pub fn spawn_cancellable<F>(token: &CancellationToken, future: F) -> JoinHandle<Option<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let token = token.clone();
+    tokio::spawn(async move { token.run_until_cancelled(future).await })
+}
@@ -0,0 +1,134 @@
+//! Webhook notifications of service events
+//!
+//! Each event is POSTed to webhooks as a JSON object, with a human readable `text` field,
+//! so it could be sent to chat services like Slack directly:
+//!
+//! ```json
+//! {
+//!     "event": "quota_exceeded",
+//!     "timestamp": 1700000000,
+//!     "text": "server 0.0.0.0:8388 traffic quota exceeded",
+//!     "server": "0.0.0.0:8388",
+//!     "user": null
+//! }
+//! ```
+//!
+//! Failed requests are retried a few times. Events are dropped if the webhook is too slow.
+
+use std::{
+    io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error, warn};
+use serde_json::json;
+use tokio::{
+    sync::{broadcast, mpsc},
+    time,
+};
+
+use crate::{
+    config::WebhookConfig,
+    events::{self, Event},
+    net::http_client,
+};
+
+/// Events waiting to be sent to each webhook
+const WEBHOOK_QUEUE_SIZE: usize = 64;
+/// Attempts of sending an event
+const WEBHOOK_MAX_ATTEMPTS: u32 = 4;
+/// Delay before the first retry, doubled after each retry
+const WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Kinds of events that are not sent unless listed in `events` explicitly
+const WEBHOOK_NOISY_EVENTS: &[&str] = &["connection_opened", "connection_closed"];
+
+/// Dispatcher of events to webhooks
+pub struct WebhookDispatcher {
+    webhooks: Vec<WebhookConfig>,
+    receiver: broadcast::Receiver<Event>,
+}
+
+impl WebhookDispatcher {
+    /// Create a dispatcher, events published after this call will be sent
+    pub fn new(webhooks: Vec<WebhookConfig>) -> WebhookDispatcher {
+        WebhookDispatcher {
+            webhooks,
+            receiver: events::subscribe(),
+        }
+    }
+
+    /// Send events until the task is aborted
+    pub async fn run(mut self) -> io::Result<()> {
+        let mut queues = Vec::with_capacity(self.webhooks.len());
+        for webhook in self.webhooks {
+            let (tx, rx) = mpsc::channel(WEBHOOK_QUEUE_SIZE);
+            tokio::spawn(send_events(webhook.url.clone(), rx));
+            queues.push((webhook, tx));
+        }
+
+        loop {
+            let event = match self.receiver.recv().await {
+                Ok(e) => e,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("webhook dropped {} events, too many events", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            };
+
+            let kind = event.kind();
+            for (webhook, tx) in &queues {
+                let enabled = match webhook.events {
+                    Some(ref events) => events.iter().any(|e| e == kind),
+                    None => !WEBHOOK_NOISY_EVENTS.contains(&kind),
+                };
+                if !enabled {
+                    continue;
+                }
+
+                if tx.try_send(event.clone()).is_err() {
+                    warn!("webhook {} queue is full, dropped event {}", webhook.url, kind);
+                }
+            }
+        }
+    }
+}
+
+async fn send_events(url: String, mut rx: mpsc::Receiver<Event>) {
+    while let Some(event) = rx.recv().await {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut body = json!(event);
+        body["timestamp"] = json!(timestamp);
+        body["text"] = json!(event.to_string());
+        let body = serde_json::to_vec(&body).expect("serialize json");
+
+        let mut delay = WEBHOOK_RETRY_DELAY;
+        for attempt in 1..=WEBHOOK_MAX_ATTEMPTS {
+            match http_client::post_json(&url, body.clone()).await {
+                Ok(..) => {
+                    debug!("webhook {} sent event {}", url, event);
+                    break;
+                }
+                Err(err) if attempt < WEBHOOK_MAX_ATTEMPTS => {
+                    debug!(
+                        "webhook {} failed to send event {}, attempt {}, error: {}",
+                        url,
+                        event.kind(),
+                        attempt,
+                        err
+                    );
+                    time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(err) => {
+                    error!("webhook {} failed to send event {}, error: {}", url, event.kind(), err);
+                }
+            }
+        }
+    }
+}
@@ -8,7 +8,7 @@ use std::{
     net::SocketAddr,
     str::{self, FromStr},
     sync::Arc,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use base64::Engine as _;
@@ -21,7 +21,11 @@ use url::{self, Url};
 
 #[cfg(any(feature = "stream-cipher", feature = "aead-cipher"))]
 use crate::crypto::v1::openssl_bytes_to_key;
-use crate::{crypto::CipherKind, plugin::PluginConfig, relay::socks5::Address};
+use crate::{
+    crypto::CipherKind,
+    plugin::{BuiltinPlugin, PluginConfig},
+    relay::socks5::Address,
+};
 
 const USER_KEY_BASE64_ENGINE: base64::engine::GeneralPurpose = base64::engine::GeneralPurpose::new(
     &base64::alphabet::STANDARD,
@@ -45,6 +49,23 @@ const URL_PASSWORD_BASE64_ENGINE: base64::engine::GeneralPurpose = base64::engin
         .with_decode_padding_mode(base64::engine::DecodePaddingMode::Indifferent),
 );
 
+// Some implementations encode URLs in the standard alphabet
+const URL_PASSWORD_STANDARD_BASE64_ENGINE: base64::engine::GeneralPurpose = base64::engine::GeneralPurpose::new(
+    &base64::alphabet::STANDARD,
+    base64::engine::GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(base64::engine::DecodePaddingMode::Indifferent),
+);
+
+/// Decode base64 in URLs, in either URL safe or standard alphabet
+fn decode_url_base64(encoded: &str) -> Option<String> {
+    let decoded = URL_PASSWORD_BASE64_ENGINE
+        .decode(encoded)
+        .or_else(|_| URL_PASSWORD_STANDARD_BASE64_ENGINE.decode(encoded))
+        .ok()?;
+    String::from_utf8(decoded).ok()
+}
+
 /// Shadowsocks server type
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ServerType {
@@ -242,6 +263,141 @@ impl ServerWeight {
     }
 }
 
+/// Coordinated port hopping of a server
+///
+/// Time is divided into slots of `interval`, and the port of each slot is derived from the shared `secret`, so servers
+/// and clients compute the same port without communicating. Servers also listen on ports of the adjacent slots within
+/// `overlap` of transitions, tolerating connections in flight and clock skews of clients.
+#[derive(Clone)]
+pub struct PortHopping {
+    start: u16,
+    end: u16,
+    interval: Duration,
+    overlap: Duration,
+    secret: String,
+    key: [u8; 32],
+}
+
+impl Debug for PortHopping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PortHopping")
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .field("interval", &self.interval)
+            .field("overlap", &self.overlap)
+            .finish()
+    }
+}
+
+impl PortHopping {
+    /// Hop in ports `[start, end]` every `interval` (in seconds), with ports derived from `secret`
+    pub fn new(start: u16, end: u16, interval: Duration, secret: String) -> PortHopping {
+        assert!(start <= end, "port hopping range is empty");
+        assert!(
+            interval.as_secs() > 0,
+            "port hopping interval must be at least 1 second"
+        );
+
+        let interval = Duration::from_secs(interval.as_secs());
+        let key = blake3::derive_key("shadowsocks-rust port hopping", secret.as_bytes());
+        PortHopping {
+            start,
+            end,
+            interval,
+            overlap: (interval / 4).min(Duration::from_secs(5)),
+            secret,
+            key,
+        }
+    }
+
+    /// First port of the range
+    pub fn start(&self) -> u16 {
+        self.start
+    }
+
+    /// Last port of the range
+    pub fn end(&self) -> u16 {
+        self.end
+    }
+
+    /// Duration of each slot
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Duration that servers keep listening on ports of the adjacent slots around transitions, 5 seconds by default
+    pub fn overlap(&self) -> Duration {
+        self.overlap
+    }
+
+    /// Set overlap of transitions, must be shorter than half of `interval`
+    pub fn set_overlap(&mut self, overlap: Duration) {
+        assert!(
+            overlap * 2 < self.interval,
+            "port hopping overlap must be shorter than half of interval"
+        );
+        self.overlap = overlap;
+    }
+
+    /// Shared secret of deriving ports
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+
+    /// Port of the `slot`-th interval since Unix epoch
+    pub fn port_of_slot(&self, slot: u64) -> u16 {
+        let hash = blake3::keyed_hash(&self.key, &slot.to_be_bytes());
+        let mut value = [0u8; 8];
+        value.copy_from_slice(&hash.as_bytes()[..8]);
+
+        let range = u64::from(self.end - self.start) + 1;
+        self.start + (u64::from_be_bytes(value) % range) as u16
+    }
+
+    /// Slot of `time`, and the duration since Unix epoch
+    fn slot_at(&self, time: SystemTime) -> (u64, Duration) {
+        let now = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        (now.as_secs() / self.interval.as_secs(), now)
+    }
+
+    /// Port that clients should connect at `time`
+    pub fn port_at(&self, time: SystemTime) -> u16 {
+        self.port_of_slot(self.slot_at(time).0)
+    }
+
+    /// Port that clients should connect now
+    pub fn current_port(&self) -> u16 {
+        self.port_at(SystemTime::now())
+    }
+
+    /// Ports that servers should listen on at `time`, and the duration until they may change
+    pub fn listening_ports_at(&self, time: SystemTime) -> (Vec<u16>, Duration) {
+        let (slot, now) = self.slot_at(time);
+        let slot_start = Duration::from_secs(slot * self.interval.as_secs());
+        let slot_end = slot_start + self.interval;
+
+        let mut ports = vec![self.port_of_slot(slot)];
+        if slot > 0 && now < slot_start + self.overlap {
+            ports.push(self.port_of_slot(slot - 1));
+        }
+        if now >= slot_end - self.overlap {
+            ports.push(self.port_of_slot(slot + 1));
+        }
+        ports.sort_unstable();
+        ports.dedup();
+
+        let next_change = [
+            slot_start + self.overlap,
+            slot_end - self.overlap,
+            slot_end + self.overlap,
+        ]
+        .into_iter()
+        .find(|t| *t > now)
+        .unwrap_or(slot_end);
+        (ports, next_change - now)
+    }
+}
+
 /// Server's user
 #[derive(Clone)]
 pub struct ServerUser {
@@ -371,6 +527,51 @@ impl Default for ServerUserManager {
     }
 }
 
+/// Protocol of connections to a server
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub enum ServerProtocol {
+    /// Shadowsocks
+    #[default]
+    Shadowsocks,
+    /// Trojan over TLS, clients only
+    ///
+    /// `password` of the server is the Trojan password. TLS server name is `sni`, or the host of the server's address.
+    Trojan { sni: Option<String> },
+}
+
+impl ServerProtocol {
+    /// String representation of the protocol
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ServerProtocol::Shadowsocks => "shadowsocks",
+            ServerProtocol::Trojan { .. } => "trojan",
+        }
+    }
+
+    /// Check if it is Shadowsocks
+    pub fn is_shadowsocks(&self) -> bool {
+        matches!(*self, ServerProtocol::Shadowsocks)
+    }
+}
+
+impl fmt::Display for ServerProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ServerProtocol {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shadowsocks" | "ss" => Ok(ServerProtocol::Shadowsocks),
+            "trojan" => Ok(ServerProtocol::Trojan { sni: None }),
+            _ => Err(()),
+        }
+    }
+}
+
 /// The source of the ServerConfig
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum ServerSource {
@@ -420,10 +621,15 @@ pub struct ServerConfig {
     /// For server, support multi-users with EIH
     user_manager: Option<Arc<ServerUserManager>>,
 
-    /// Plugin config
-    plugin: Option<PluginConfig>,
+    /// Plugin configs, chained in order from shadowsocks to network
+    plugins: Vec<PluginConfig>,
     /// Plugin address
     plugin_addr: Option<ServerAddr>,
+    /// Unix domain socket of plugin, preferred to `plugin_addr` for TCP
+    #[cfg(unix)]
+    plugin_unix_addr: Option<PathBuf>,
+    /// Builtin plugin, parsed from `plugin_opts` of builtin plugins
+    builtin_plugin: Option<BuiltinPlugin>,
 
     /// Remark (Profile Name), normally used as an identifier of this erver
     remarks: Option<String>,
@@ -436,6 +642,14 @@ pub struct ServerConfig {
     /// Weight
     weight: ServerWeight,
 
+    /// Coordinated port hopping
+    port_hopping: Option<PortHopping>,
+    /// Additional addresses listened on by servers, along with `addr`
+    extra_addrs: Vec<ServerAddr>,
+
+    /// Protocol
+    protocol: ServerProtocol,
+
     /// Source
     source: ServerSource,
 }
@@ -574,16 +788,52 @@ impl ServerConfig {
             identity_keys: Arc::new(identity_keys),
             user_manager: None,
             timeout: None,
-            plugin: None,
+            plugins: Vec::new(),
             plugin_addr: None,
+            #[cfg(unix)]
+            plugin_unix_addr: None,
+            builtin_plugin: None,
             remarks: None,
             id: None,
             mode: Mode::TcpAndUdp, // Server serves TCP & UDP by default
             weight: ServerWeight::new(),
+            port_hopping: None,
+            extra_addrs: Vec::new(),
+            protocol: ServerProtocol::Shadowsocks,
             source: ServerSource::Default,
         })
     }
 
+    /// Create a new `ServerConfig` of a Trojan server, which only relays TCP
+    pub fn new_trojan<A, P>(addr: A, password: P, sni: Option<String>) -> ServerConfig
+    where
+        A: Into<ServerAddr>,
+        P: Into<String>,
+    {
+        ServerConfig {
+            addr: addr.into(),
+            password: password.into(),
+            method: CipherKind::NONE,
+            enc_key: Vec::new().into_boxed_slice(),
+            identity_keys: Arc::new(Vec::new()),
+            user_manager: None,
+            timeout: None,
+            plugins: Vec::new(),
+            plugin_addr: None,
+            #[cfg(unix)]
+            plugin_unix_addr: None,
+            builtin_plugin: None,
+            remarks: None,
+            id: None,
+            mode: Mode::TcpOnly,
+            weight: ServerWeight::new(),
+            port_hopping: None,
+            extra_addrs: Vec::new(),
+            protocol: ServerProtocol::Trojan { sni },
+            source: ServerSource::Default,
+        }
+    }
+
     /// Set encryption method
     pub fn set_method<P>(&mut self, method: CipherKind, password: P) -> Result<(), ServerConfigError>
     where
@@ -601,8 +851,31 @@ impl ServerConfig {
     }
 
     /// Set plugin
+    ///
+    /// Options of builtin plugins are parsed here, and invalid options are ignored.
+    /// Use `BuiltinPlugin::from_plugin_config` to validate them beforehand.
     pub fn set_plugin(&mut self, p: PluginConfig) {
-        self.plugin = Some(p);
+        self.set_plugins(vec![p]);
+    }
+
+    /// Set chained plugins, in order from shadowsocks to network
+    ///
+    /// Builtin plugins run in process, so they must be placed before plugin subprocesses, and only the first one is used.
+    pub fn set_plugins(&mut self, ps: Vec<PluginConfig>) {
+        self.builtin_plugin = None;
+        for p in &ps {
+            match BuiltinPlugin::from_plugin_config(p) {
+                Ok(None) => {}
+                Ok(Some(b)) => {
+                    self.builtin_plugin = Some(b);
+                    break;
+                }
+                Err(err) => {
+                    error!("builtin plugin {} of server {} disabled, {}", p.plugin, self.addr, err);
+                }
+            }
+        }
+        self.plugins = ps;
     }
 
     /// Set server addr
@@ -658,9 +931,14 @@ impl ServerConfig {
         self.method
     }
 
-    /// Get plugin
+    /// Get plugin, the first one if plugins are chained
     pub fn plugin(&self) -> Option<&PluginConfig> {
-        self.plugin.as_ref()
+        self.plugins.first()
+    }
+
+    /// Get chained plugins
+    pub fn plugins(&self) -> &[PluginConfig] {
+        &self.plugins
     }
 
     /// Set plugin address
@@ -673,9 +951,27 @@ impl ServerConfig {
         self.plugin_addr.as_ref()
     }
 
+    /// Set Unix domain socket of plugin, TCP connections are sent to it instead of `plugin_addr`
+    #[cfg(unix)]
+    pub fn set_plugin_unix_addr(&mut self, p: PathBuf) {
+        self.plugin_unix_addr = Some(p);
+    }
+
+    /// Get Unix domain socket of plugin, if plugin is listening on it
+    #[cfg(unix)]
+    pub fn plugin_unix_addr(&self) -> Option<&PathBuf> {
+        self.plugin_unix_addr.as_ref()
+    }
+
+    /// Get builtin plugin
+    pub fn builtin_plugin(&self) -> Option<&BuiltinPlugin> {
+        self.builtin_plugin.as_ref()
+    }
+
     /// Get server's TCP external address
     pub fn tcp_external_addr(&self) -> &ServerAddr {
-        if let Some(plugin) = self.plugin() {
+        // Builtin plugins are placed before plugin subprocesses, which share the same `plugin_mode`
+        if let Some(plugin) = self.plugins.last() {
             if plugin.plugin_mode.enable_tcp() {
                 return self.plugin_addr.as_ref().unwrap_or(&self.addr);
             }
@@ -685,7 +981,7 @@ impl ServerConfig {
 
     /// Get server's UDP external address
     pub fn udp_external_addr(&self) -> &ServerAddr {
-        if let Some(plugin) = self.plugin() {
+        if let Some(plugin) = self.plugins.last() {
             if plugin.plugin_mode.enable_udp() {
                 return self.plugin_addr.as_ref().unwrap_or(&self.addr);
             }
@@ -739,6 +1035,11 @@ impl ServerConfig {
         self.mode = mode;
     }
 
+    /// Get server's protocol
+    pub fn protocol(&self) -> &ServerProtocol {
+        &self.protocol
+    }
+
     /// Get server's balancer weight
     pub fn weight(&self) -> &ServerWeight {
         &self.weight
@@ -749,6 +1050,37 @@ impl ServerConfig {
         self.weight = weight;
     }
 
+    /// Get server's port hopping
+    pub fn port_hopping(&self) -> Option<&PortHopping> {
+        self.port_hopping.as_ref()
+    }
+
+    /// Set server's port hopping, the port of `addr` is ignored if enabled
+    pub fn set_port_hopping(&mut self, port_hopping: PortHopping) {
+        self.port_hopping = Some(port_hopping);
+    }
+
+    /// Get additional addresses listened on by servers, along with `addr`
+    ///
+    /// Clients only connect to `addr`.
+    pub fn extra_addrs(&self) -> &[ServerAddr] {
+        &self.extra_addrs
+    }
+
+    /// Set additional addresses listened on by servers
+    pub fn set_extra_addrs(&mut self, addrs: Vec<ServerAddr>) {
+        self.extra_addrs = addrs;
+    }
+
+    /// Get server's address with the current port, if port hopping is enabled
+    pub fn hopping_addr(&self) -> Option<ServerAddr> {
+        let port = self.port_hopping.as_ref()?.current_port();
+        Some(match self.addr {
+            ServerAddr::SocketAddr(ref addr) => ServerAddr::SocketAddr(SocketAddr::new(addr.ip(), port)),
+            ServerAddr::DomainName(ref domain, ..) => ServerAddr::DomainName(domain.clone(), port),
+        })
+    }
+
     /// Get server's source
     pub fn source(&self) -> ServerSource {
         self.source
@@ -768,7 +1100,10 @@ impl ServerConfig {
         format!("ss://{}", URL_PASSWORD_BASE64_ENGINE.encode(param))
     }
 
-    /// Get [SIP002](https://github.com/shadowsocks/shadowsocks-org/issues/27) URL
+    /// Get [SIP002](https://shadowsocks.org/doc/sip002.html) URL
+    ///
+    /// `userinfo` is in URL safe base64 without paddings, except AEAD 2022 ciphers, whose `userinfo` is percent-encoded
+    /// plain text. It could be parsed back by [`ServerConfig::from_url`].
     pub fn to_url(&self) -> String {
         cfg_if! {
             if #[cfg(feature = "aead-cipher-2022")] {
@@ -808,12 +1143,20 @@ impl ServerConfig {
         url
     }
 
-    /// Parse from [SIP002](https://github.com/shadowsocks/shadowsocks-org/issues/27) URL
+    /// Parse from [SIP002](https://shadowsocks.org/doc/sip002.html) URL
+    ///
+    /// ```plain
+    /// ss://userinfo@hostname:port[/][?plugin=plugin-name;plugin-options][#tag]
+    /// ```
     ///
-    /// Extended formats:
+    /// - `userinfo` is `method:password` in base64, either URL safe or standard, with or without (`=` or `%3D`)
+    ///   paddings. It could also be `method:password` in plain text with percent-encoding, which is required by
+    ///   AEAD 2022 ciphers, and is also supported by go2-shadowsocks2
+    /// - `hostname` is a domain name, an IPv4 address or an IPv6 address in brackets, `port` is 8388 if missing
+    /// - `plugin` and `tag` (remarks of the server) are percent-encoded
     ///
-    /// 1. QRCode URL supported by shadowsocks-android, https://github.com/shadowsocks/shadowsocks-android/issues/51
-    /// 2. Plain userinfo:password format supported by go2-shadowsocks2
+    /// The QRCode URL of [shadowsocks-android](https://github.com/shadowsocks/shadowsocks-android/issues/51),
+    /// `ss://base64(method:password@hostname:port)[#tag]`, is also supported.
     pub fn from_url(encoded: &str) -> Result<ServerConfig, UrlParseError> {
         let parsed = Url::parse(encoded).map_err(UrlParseError::from)?;
 
@@ -821,31 +1164,57 @@ impl ServerConfig {
             return Err(UrlParseError::InvalidScheme);
         }
 
-        let user_info = parsed.username();
-        if user_info.is_empty() {
-            // This maybe a QRCode URL, which is ss://BASE64-URL-ENCODE(pass:encrypt@hostname:port)
-
-            let encoded = match parsed.host_str() {
-                Some(e) => e,
-                None => return Err(UrlParseError::MissingHost),
-            };
+        let mut svrconfig = if parsed.username().is_empty() {
+            ServerConfig::from_qrcode_url_body(encoded)?
+        } else {
+            ServerConfig::from_sip002_url(&parsed)?
+        };
 
-            let mut decoded_body = match URL_PASSWORD_BASE64_ENGINE.decode(encoded) {
-                Ok(b) => match String::from_utf8(b) {
-                    Ok(b) => b,
-                    Err(..) => return Err(UrlParseError::InvalidServerAddr),
-                },
+        if let Some(q) = parsed.query() {
+            let query = match serde_urlencoded::from_bytes::<Vec<(String, String)>>(q.as_bytes()) {
+                Ok(q) => q,
                 Err(err) => {
-                    error!("failed to parse legacy ss://ENCODED with Base64, err: {}", err);
-                    return Err(UrlParseError::InvalidServerAddr);
+                    error!("failed to parse QueryString, err: {}", err);
+                    return Err(UrlParseError::InvalidQueryString);
                 }
             };
 
-            decoded_body.insert_str(0, "ss://");
-            // Parse it like ss://method:password@host:port
-            return ServerConfig::from_url(&decoded_body);
+            for (key, value) in query {
+                if key != "plugin" {
+                    continue;
+                }
+
+                let mut vsp = value.splitn(2, ';');
+                match vsp.next() {
+                    None | Some("") => {}
+                    Some(p) => {
+                        let plugin = PluginConfig {
+                            plugin: p.to_owned(),
+                            plugin_opts: vsp.next().filter(|opts| !opts.is_empty()).map(ToOwned::to_owned),
+                            plugin_args: Vec::new(), // SIP002 doesn't have arguments for plugins
+                            plugin_mode: Mode::TcpOnly, // SIP002 doesn't support SIP003u
+                            plugin_supervision: Default::default(),
+                        };
+                        svrconfig.set_plugin(plugin);
+                    }
+                }
+            }
         }
 
+        if let Some(frag) = parsed.fragment() {
+            match percent_encoding::percent_decode_str(frag).decode_utf8() {
+                Ok(m) => svrconfig.set_remarks(m),
+                Err(..) => svrconfig.set_remarks(frag),
+            }
+        }
+
+        Ok(svrconfig)
+    }
+
+    // ss://userinfo@hostname:port
+    fn from_sip002_url(parsed: &Url) -> Result<ServerConfig, UrlParseError> {
+        let user_info = parsed.username();
+
         let (method, pwd) = match parsed.password() {
             Some(password) => {
                 // Plain method:password without base64 encoded
@@ -866,7 +1235,7 @@ impl ServerConfig {
                     }
                 };
 
-                (m, p)
+                (m.into_owned(), p.into_owned())
             }
             None => {
                 // userinfo is not required to be percent encoded, but some implementation did.
@@ -880,40 +1249,72 @@ impl ServerConfig {
                     }
                 };
 
-                // reborrow to fit AsRef<[u8]>
-                let decoded_user_info: &str = &decoded_user_info;
-
                 // Some implementation, like outline,
                 // or those with Python (base64 in Python will still have '=' padding for URL safe encode)
-                let account = match URL_PASSWORD_BASE64_ENGINE.decode(decoded_user_info) {
-                    Ok(account) => match String::from_utf8(account) {
-                        Ok(ac) => ac,
-                        Err(..) => return Err(UrlParseError::InvalidAuthInfo),
-                    },
-                    Err(err) => {
-                        error!("failed to parse UserInfo with Base64, err: {}", err);
+                let account = match decode_url_base64(&decoded_user_info) {
+                    Some(account) => account,
+                    None => {
+                        error!("failed to parse UserInfo with Base64");
                         return Err(UrlParseError::InvalidUserInfo);
                     }
                 };
 
-                let mut sp2 = account.splitn(2, ':');
-                let (m, p) = match (sp2.next(), sp2.next()) {
-                    (Some(m), Some(p)) => (m, p),
-                    _ => return Err(UrlParseError::InvalidUserInfo),
-                };
-
-                (m.to_owned().into(), p.to_owned().into())
+                match account.split_once(':') {
+                    Some((m, p)) => (m.to_owned(), p.to_owned()),
+                    None => return Err(UrlParseError::InvalidUserInfo),
+                }
             }
         };
 
+        // IPv6 addresses are in brackets, which are also required by ServerAddr
         let host = match parsed.host_str() {
             Some(host) => host,
             None => return Err(UrlParseError::MissingHost),
         };
 
         let port = parsed.port().unwrap_or(8388);
-        let addr = format!("{host}:{port}");
+        ServerConfig::from_url_parts(&method, pwd, &format!("{host}:{port}"))
+    }
+
+    // ss://base64(method:password@hostname:port), the body may be in either base64 alphabet, which could break URL
+    // parsing, so it is taken from the original URL
+    fn from_qrcode_url_body(encoded: &str) -> Result<ServerConfig, UrlParseError> {
+        let body = &encoded["ss://".len()..];
+        let body = match body.find(['?', '#']) {
+            Some(pos) => &body[..pos],
+            None => body,
+        };
+        let body = body.trim_end_matches('/');
+        if body.is_empty() {
+            return Err(UrlParseError::MissingHost);
+        }
+
+        let decoded_body = match percent_encoding::percent_decode_str(body)
+            .decode_utf8()
+            .ok()
+            .and_then(|b| decode_url_base64(&b))
+        {
+            Some(b) => b,
+            None => {
+                error!("failed to parse legacy ss://ENCODED with Base64");
+                return Err(UrlParseError::InvalidServerAddr);
+            }
+        };
+
+        // Password may contain '@', but address doesn't
+        let (account, addr) = match decoded_body.rsplit_once('@') {
+            Some(x) => x,
+            None => return Err(UrlParseError::MissingHost),
+        };
+        let (method, pwd) = match account.split_once(':') {
+            Some(x) => x,
+            None => return Err(UrlParseError::InvalidUserInfo),
+        };
+
+        ServerConfig::from_url_parts(method, pwd.to_owned(), addr)
+    }
 
+    fn from_url_parts(method: &str, pwd: String, addr: &str) -> Result<ServerConfig, UrlParseError> {
         let addr = match addr.parse::<ServerAddr>() {
             Ok(a) => a,
             Err(err) => {
@@ -929,51 +1330,13 @@ impl ServerConfig {
                 return Err(UrlParseError::InvalidMethod);
             }
         };
-        let mut svrconfig = ServerConfig::new(addr, pwd, method)?;
-
-        if let Some(q) = parsed.query() {
-            let query = match serde_urlencoded::from_bytes::<Vec<(String, String)>>(q.as_bytes()) {
-                Ok(q) => q,
-                Err(err) => {
-                    error!("failed to parse QueryString, err: {}", err);
-                    return Err(UrlParseError::InvalidQueryString);
-                }
-            };
-
-            for (key, value) in query {
-                if key != "plugin" {
-                    continue;
-                }
-
-                let mut vsp = value.splitn(2, ';');
-                match vsp.next() {
-                    None => {}
-                    Some(p) => {
-                        let plugin = PluginConfig {
-                            plugin: p.to_owned(),
-                            plugin_opts: vsp.next().map(ToOwned::to_owned),
-                            plugin_args: Vec::new(), // SIP002 doesn't have arguments for plugins
-                            plugin_mode: Mode::TcpOnly, // SIP002 doesn't support SIP003u
-                        };
-                        svrconfig.set_plugin(plugin);
-                    }
-                }
-            }
-        }
 
-        if let Some(frag) = parsed.fragment() {
-            match percent_encoding::percent_decode_str(frag).decode_utf8() {
-                Ok(m) => svrconfig.set_remarks(m),
-                Err(..) => svrconfig.set_remarks(frag),
-            }
-        }
-
-        Ok(svrconfig)
+        ServerConfig::new(addr, pwd, method).map_err(From::from)
     }
 
     /// Check if it is a basic format server
     pub fn is_basic(&self) -> bool {
-        self.remarks.is_none() && self.id.is_none()
+        self.remarks.is_none() && self.id.is_none() && self.port_hopping.is_none() && self.protocol.is_shadowsocks()
     }
 }
 
@@ -1415,4 +1778,141 @@ mod test {
         let server_config = ServerConfig::from_url("ss://foo:bar@127.0.0.1:9999");
         assert!(matches!(server_config, Err(UrlParseError::InvalidMethod)));
     }
+
+    #[test]
+    fn test_server_config_from_url_base64_userinfo() {
+        let svr_cfg = ServerConfig::from_url(
+            "ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@example.com:8388/?plugin=obfs-local%3Bobfs%3Dhttp%3Bobfs-host%3Dexample.org#Example%20Server",
+        )
+        .unwrap();
+        assert_eq!(svr_cfg.addr(), &ServerAddr::DomainName("example.com".to_owned(), 8388));
+        assert_eq!(svr_cfg.method(), CipherKind::AES_256_GCM);
+        assert_eq!(svr_cfg.password(), "password");
+        let plugin = svr_cfg.plugin().unwrap();
+        assert_eq!(plugin.plugin, "obfs-local");
+        assert_eq!(plugin.plugin_opts.as_deref(), Some("obfs=http;obfs-host=example.org"));
+        assert_eq!(svr_cfg.remarks(), Some("Example Server"));
+
+        // Paddings, percent-encoded paddings and the standard alphabet
+        for url in [
+            "ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ=@127.0.0.1:8388",
+            "ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ%3D@127.0.0.1:8388",
+            "ss://YWVzLTEyOC1nY206dGVzdD4+Pj8=@127.0.0.1:8388",
+            "ss://YWVzLTEyOC1nY206dGVzdD4-Pj8@127.0.0.1:8388",
+        ] {
+            let svr_cfg = ServerConfig::from_url(url).unwrap();
+            assert!(matches!(svr_cfg.password(), "password" | "test>>>?"), "{url}");
+        }
+    }
+
+    #[test]
+    fn test_server_config_from_url_ipv6() {
+        let svr_cfg = ServerConfig::from_url("ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@[::1]:8388").unwrap();
+        assert_eq!(svr_cfg.addr(), &ServerAddr::SocketAddr("[::1]:8388".parse().unwrap()));
+
+        let svr_cfg = ServerConfig::from_url("ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@[2001:db8::1]").unwrap();
+        assert_eq!(
+            svr_cfg.addr(),
+            &ServerAddr::SocketAddr("[2001:db8::1]:8388".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_server_config_from_qrcode_url() {
+        // base64(aes-128-gcm:pass@word@[::1]:8388)
+        let svr_cfg = ServerConfig::from_url("ss://YWVzLTEyOC1nY206cGFzc0B3b3JkQFs6OjFdOjgzODg=#QR%20Code").unwrap();
+        assert_eq!(svr_cfg.addr(), &ServerAddr::SocketAddr("[::1]:8388".parse().unwrap()));
+        assert_eq!(svr_cfg.method(), CipherKind::AES_128_GCM);
+        assert_eq!(svr_cfg.password(), "pass@word");
+        assert_eq!(svr_cfg.remarks(), Some("QR Code"));
+
+        let svr_cfg = ServerConfig::from_url(&svr_cfg.to_qrcode_url()).unwrap();
+        assert_eq!(svr_cfg.password(), "pass@word");
+    }
+
+    #[test]
+    fn test_server_config_url_round_trip() {
+        let mut svr_cfg = ServerConfig::new(
+            "[2001:db8::1]:8443".parse::<ServerAddr>().unwrap(),
+            "p@ss:word/#?",
+            CipherKind::CHACHA20_POLY1305,
+        )
+        .unwrap();
+        svr_cfg.set_plugin(PluginConfig {
+            plugin: "v2ray-plugin".to_owned(),
+            plugin_opts: Some("mode=websocket;host=example.com;path=/ws?ed=2048".to_owned()),
+            plugin_args: Vec::new(),
+            plugin_mode: Mode::TcpOnly,
+            plugin_supervision: Default::default(),
+        });
+        svr_cfg.set_remarks("服务器 #1");
+
+        let parsed = ServerConfig::from_url(&svr_cfg.to_url()).unwrap();
+        assert_eq!(parsed.addr(), svr_cfg.addr());
+        assert_eq!(parsed.method(), svr_cfg.method());
+        assert_eq!(parsed.password(), svr_cfg.password());
+        assert_eq!(parsed.plugin().unwrap().plugin, "v2ray-plugin");
+        assert_eq!(
+            parsed.plugin().unwrap().plugin_opts,
+            svr_cfg.plugin().unwrap().plugin_opts
+        );
+        assert_eq!(parsed.remarks(), svr_cfg.remarks());
+    }
+
+    #[cfg(feature = "aead-cipher-2022")]
+    #[test]
+    fn test_server_config_url_aead2022_plain_userinfo() {
+        let svr_cfg = ServerConfig::from_url(
+            "ss://2022-blake3-aes-128-gcm:AAECAwQFBgcICQoLDA0ODw%3D%3D@example.com:8388#AEAD%202022",
+        )
+        .unwrap();
+        assert_eq!(svr_cfg.method(), CipherKind::AEAD2022_BLAKE3_AES_128_GCM);
+        assert_eq!(svr_cfg.password(), "AAECAwQFBgcICQoLDA0ODw==");
+        assert_eq!(svr_cfg.remarks(), Some("AEAD 2022"));
+
+        let url = svr_cfg.to_url();
+        assert!(url.starts_with("ss://2022-blake3-aes-128-gcm:"), "{url}");
+        assert_eq!(ServerConfig::from_url(&url).unwrap().password(), svr_cfg.password());
+    }
+
+    #[test]
+    fn test_server_config_from_invalid_url() {
+        assert!(matches!(
+            ServerConfig::from_url("http://YWVzLTI1Ni1nY206cGFzc3dvcmQ@127.0.0.1:8388"),
+            Err(UrlParseError::InvalidScheme)
+        ));
+        assert!(matches!(
+            ServerConfig::from_url("ss://bm90LWEtdXNlcmluZm8@127.0.0.1:8388"),
+            Err(UrlParseError::InvalidUserInfo)
+        ));
+        assert!(matches!(
+            ServerConfig::from_url("ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ"),
+            Err(UrlParseError::MissingHost)
+        ));
+    }
+
+    #[test]
+    fn test_port_hopping_overlap() {
+        let hopping = PortHopping::new(20000, 20099, Duration::from_secs(60), "secret".to_owned());
+        let slot_start = UNIX_EPOCH + Duration::from_secs(60 * 1000);
+
+        let current = hopping.port_of_slot(1000);
+        assert!((20000..=20099).contains(&current));
+        assert_eq!(hopping.port_at(slot_start + Duration::from_secs(30)), current);
+
+        // Middle of the slot, only the current port
+        let (ports, next) = hopping.listening_ports_at(slot_start + Duration::from_secs(30));
+        assert_eq!(ports, vec![current]);
+        assert_eq!(next, Duration::from_secs(25));
+
+        // Right after the transition, the previous port is still open
+        let (ports, _) = hopping.listening_ports_at(slot_start + Duration::from_secs(1));
+        assert!(ports.contains(&current));
+        assert!(ports.contains(&hopping.port_of_slot(999)));
+
+        // Right before the transition, the next port is already open
+        let (ports, _) = hopping.listening_ports_at(slot_start + Duration::from_secs(58));
+        assert!(ports.contains(&current));
+        assert!(ports.contains(&hopping.port_of_slot(1001)));
+    }
 }
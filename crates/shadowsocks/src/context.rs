@@ -1,20 +1,37 @@
 //! Shadowsocks service context
 
-use std::{io, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use byte_string::ByteStr;
-use log::warn;
+use log::{debug, info, warn};
+use tokio::sync::Notify;
 
 use crate::{
     config::{ReplayAttackPolicy, ServerType},
     crypto::CipherKind,
     dns_resolver::DnsResolver,
-    security::replay::ReplayProtector,
+    net::ConnectOpts,
+    security::replay::{ReplayError, ReplayProtector},
 };
 
+/// Token for shutting down services and all tasks spawned by them
+pub use tokio_util::sync::CancellationToken;
+
 /// Service context
 #[derive(Debug)]
 pub struct Context {
+    // Client or Server
+    config_type: ServerType,
+
     // Protector against replay attack
     // The actual replay detection behavior is implemented in ReplayProtector
     replay_protector: ReplayProtector,
@@ -23,9 +40,92 @@ pub struct Context {
 
     // hickory-dns resolver, which supports REAL asynchronous resolving, and also customizable
     dns_resolver: Arc<DnsResolver>,
+    // Options of connecting to DNS servers, which `dns_resolver` was created with
+    dns_connect_opts: ConnectOpts,
+    // Resolver bypassing caches of `dns_resolver`, created when it is used for the first time
+    uncached_dns_resolver: OnceLock<Option<DnsResolver>>,
 
     // Connect IPv6 address first
     ipv6_first: bool,
+
+    // Resolved addresses of servers
+    server_addr_cache: ServerAddrCache,
+
+    // Runtime statistic
+    stat: ContextStat,
+
+    // Shutdown of services and their tasks
+    cancellation_token: CancellationToken,
+}
+
+/// Runtime statistic of `Context`
+#[derive(Debug, Default)]
+pub struct ContextStat {
+    replay_detected: AtomicU64,
+    dns_queries: AtomicU64,
+    dns_failures: AtomicU64,
+    dns_duration_micros: AtomicU64,
+    replay_notify: Notify,
+}
+
+impl ContextStat {
+    /// Number of repeated nonce (iv/salt) detected
+    pub fn replay_detected(&self) -> u64 {
+        self.replay_detected.load(Ordering::Relaxed)
+    }
+
+    /// Wait until the next repeated nonce (iv/salt) is detected
+    pub async fn replay_detected_notified(&self) {
+        self.replay_notify.notified().await
+    }
+
+    /// Number of DNS queries
+    pub fn dns_queries(&self) -> u64 {
+        self.dns_queries.load(Ordering::Relaxed)
+    }
+
+    /// Number of failed DNS queries
+    pub fn dns_failures(&self) -> u64 {
+        self.dns_failures.load(Ordering::Relaxed)
+    }
+
+    /// Total time spent in DNS queries, in microseconds
+    pub fn dns_duration_micros(&self) -> u64 {
+        self.dns_duration_micros.load(Ordering::Relaxed)
+    }
+}
+
+/// Default interval of resolving domain names of servers again
+pub const DEFAULT_SERVER_RESOLVE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Consecutive connection failures of a server before its domain name is resolved bypassing DNS caches
+const SERVER_RESOLVE_BYPASS_CACHE_FAILURES: u32 = 3;
+
+#[derive(Debug)]
+struct ServerAddrCache {
+    // Zero disables the cache
+    refresh_interval: Duration,
+    entries: Mutex<HashMap<(String, u16), CachedServerAddr>>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedServerAddr {
+    // `None` if it has to be resolved again
+    resolved: Option<Instant>,
+    addrs: Vec<SocketAddr>,
+    // Consecutive connection failures
+    failures: u32,
+    // Increased whenever addresses of the server change
+    generation: u64,
+}
+
+impl ServerAddrCache {
+    fn new(refresh_interval: Duration) -> ServerAddrCache {
+        ServerAddrCache {
+            refresh_interval,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
 }
 
 /// `Context` for sharing between services
@@ -35,10 +135,16 @@ impl Context {
     /// Create a new `Context` for `Client` or `Server`
     pub fn new(config_type: ServerType) -> Context {
         Context {
+            config_type,
             replay_protector: ReplayProtector::new(config_type),
             replay_policy: ReplayAttackPolicy::Default,
             dns_resolver: Arc::new(DnsResolver::system_resolver()),
+            dns_connect_opts: ConnectOpts::default(),
+            uncached_dns_resolver: OnceLock::new(),
             ipv6_first: false,
+            server_addr_cache: ServerAddrCache::new(DEFAULT_SERVER_RESOLVE_INTERVAL),
+            stat: ContextStat::default(),
+            cancellation_token: CancellationToken::new(),
         }
     }
 
@@ -105,13 +211,17 @@ impl Context {
             ReplayAttackPolicy::Default | ReplayAttackPolicy::Ignore => Ok(()),
             ReplayAttackPolicy::Detect => {
                 if self.replay_protector.check_nonce_and_set(method, nonce) {
+                    self.stat.replay_detected.fetch_add(1, Ordering::Relaxed);
+                    self.stat.replay_notify.notify_waiters();
                     warn!("detected repeated nonce (iv/salt) {:?}", ByteStr::new(nonce));
                 }
                 Ok(())
             }
             ReplayAttackPolicy::Reject => {
                 if self.replay_protector.check_nonce_and_set(method, nonce) {
-                    let err = io::Error::new(io::ErrorKind::Other, "detected repeated nonce (iv/salt)");
+                    self.stat.replay_detected.fetch_add(1, Ordering::Relaxed);
+                    self.stat.replay_notify.notify_waiters();
+                    let err = io::Error::new(io::ErrorKind::Other, ReplayError);
                     Err(err)
                 } else {
                     Ok(())
@@ -125,6 +235,15 @@ impl Context {
     /// The resolver should be wrapped in an `Arc`, because it could be shared with the other servers
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         self.dns_resolver = resolver;
+        self.uncached_dns_resolver = OnceLock::new();
+    }
+
+    /// Set options of connecting to DNS servers, which the DNS resolver was created with
+    ///
+    /// They are used by the resolver bypassing caches of the DNS resolver, see [`Context::resolve_server_addr`].
+    pub fn set_dns_connect_opts(&mut self, connect_opts: ConnectOpts) {
+        self.dns_connect_opts = connect_opts;
+        self.uncached_dns_resolver = OnceLock::new();
     }
 
     /// Get the DNS resolver
@@ -138,7 +257,158 @@ impl Context {
         addr: &'a str,
         port: u16,
     ) -> io::Result<impl Iterator<Item = SocketAddr> + 'a + use<'a>> {
-        self.dns_resolver.resolve(addr, port).await
+        let start = Instant::now();
+        let result = self.dns_resolver.resolve(addr, port).await;
+        self.record_dns_query(start, result.is_err());
+        result
+    }
+
+    fn record_dns_query(&self, start: Instant, failed: bool) {
+        let elapsed = start.elapsed().as_micros() as u64;
+        self.stat.dns_queries.fetch_add(1, Ordering::Relaxed);
+        self.stat.dns_duration_micros.fetch_add(elapsed, Ordering::Relaxed);
+        if failed {
+            self.stat.dns_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Resolves domain name of a server to `SocketAddr`s
+    ///
+    /// Resolved addresses are cached, and resolved again after the refresh interval, or after
+    /// [`Context::invalidate_server_addr`] is called for connection failures. Cached addresses are kept if resolving
+    /// again fails. After consecutive connection failures, the server is resolved bypassing caches of the DNS resolver,
+    /// so servers on dynamic IPs are found at their new addresses before the TTLs of their records expire.
+    pub async fn resolve_server_addr(&self, addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let cache = &self.server_addr_cache;
+        if cache.refresh_interval.is_zero() {
+            return self.dns_resolve(addr, port).await.map(Iterator::collect);
+        }
+
+        let key = (addr.to_owned(), port);
+        let cached = cache.entries.lock().unwrap().get(&key).cloned();
+        if let Some(CachedServerAddr {
+            resolved: Some(resolved),
+            ref addrs,
+            ..
+        }) = cached
+        {
+            if resolved.elapsed() < cache.refresh_interval {
+                return Ok(addrs.clone());
+            }
+        }
+
+        let failures = cached.as_ref().map_or(0, |c| c.failures);
+        let generation = cached.as_ref().map_or(0, |c| c.generation);
+        let bypass_cache = failures >= SERVER_RESOLVE_BYPASS_CACHE_FAILURES;
+        let result: io::Result<Vec<SocketAddr>> = if bypass_cache {
+            debug!(
+                "server {}:{} failed {} connections, resolving it again without DNS caches",
+                addr, port, failures
+            );
+
+            let uncached_resolver = self
+                .uncached_dns_resolver
+                .get_or_init(|| self.dns_resolver.uncached_resolver(self.dns_connect_opts.clone()));
+            match *uncached_resolver {
+                Some(ref resolver) => {
+                    let start = Instant::now();
+                    let result = resolver.resolve(addr, port).await.map(Iterator::collect);
+                    self.record_dns_query(start, result.is_err());
+                    result
+                }
+                None => self.dns_resolve(addr, port).await.map(Iterator::collect),
+            }
+        } else {
+            self.dns_resolve(addr, port).await.map(Iterator::collect)
+        };
+
+        match result {
+            Ok(addrs) => {
+                if !addrs.is_empty() {
+                    debug!("server {}:{} resolved to {:?}", addr, port, addrs);
+
+                    let mut changed = false;
+                    if let Some(CachedServerAddr {
+                        addrs: ref old_addrs, ..
+                    }) = cached
+                    {
+                        if !old_addrs.iter().all(|a| addrs.contains(a)) || !addrs.iter().all(|a| old_addrs.contains(a))
+                        {
+                            info!(
+                                "server {}:{} changed addresses from {:?} to {:?}",
+                                addr, port, old_addrs, addrs
+                            );
+                            changed = true;
+                        }
+                    }
+
+                    cache.entries.lock().unwrap().insert(
+                        key,
+                        CachedServerAddr {
+                            resolved: Some(Instant::now()),
+                            addrs: addrs.clone(),
+                            // Another fresh resolve needs another run of failures
+                            failures: if changed || bypass_cache { 0 } else { failures },
+                            generation: if changed {
+                                generation.wrapping_add(1)
+                            } else {
+                                generation
+                            },
+                        },
+                    );
+                }
+                Ok(addrs)
+            }
+            Err(err) => match cached {
+                Some(CachedServerAddr { addrs, .. }) => {
+                    warn!(
+                        "failed to resolve server {}:{} again, keep using {:?}, error: {}",
+                        addr, port, addrs, err
+                    );
+                    Ok(addrs)
+                }
+                _ => Err(err),
+            },
+        }
+    }
+
+    /// Resolve domain name of a server again in the next connection, usually after failing to connect to it
+    pub fn invalidate_server_addr(&self, addr: &str, port: u16) {
+        let mut entries = self.server_addr_cache.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&(addr.to_owned(), port)) {
+            // Keep addresses in case of resolving fails
+            entry.resolved = None;
+            entry.failures = entry.failures.saturating_add(1);
+        }
+    }
+
+    /// Reset connection failures of a server, after connecting to it successfully
+    pub fn server_addr_connected(&self, addr: &str, port: u16) {
+        let mut entries = self.server_addr_cache.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&(addr.to_owned(), port)) {
+            entry.failures = 0;
+        }
+    }
+
+    /// Generation of cached addresses of server `addr:port`, increased whenever its resolved addresses change
+    ///
+    /// Sockets connected to resolved addresses of the server for long, like UDP associations, should be connected again
+    /// if it changes.
+    pub fn server_addr_generation(&self, addr: &str, port: u16) -> u64 {
+        let entries = self.server_addr_cache.entries.lock().unwrap();
+        entries.get(&(addr.to_owned(), port)).map_or(0, |c| c.generation)
+    }
+
+    /// Clear cached addresses of servers
+    pub fn clear_server_addr_cache(&self) {
+        self.server_addr_cache.entries.lock().unwrap().clear();
+    }
+
+    /// Set the interval of resolving domain names of servers again, 5 minutes by default
+    ///
+    /// Zero disables the cache, domain names of servers are resolved for every connection.
+    pub fn set_server_resolve_interval(&mut self, interval: Duration) {
+        self.server_addr_cache = ServerAddrCache::new(interval);
     }
 
     /// Try to connect IPv6 addresses first if hostname could be resolved to both IPv4 and IPv6
@@ -160,4 +430,143 @@ impl Context {
     pub fn replay_attack_policy(&self) -> ReplayAttackPolicy {
         self.replay_policy
     }
+
+    /// Hold `entries` nonces in the bloom filter against replay attack, instead of the default capacity
+    pub fn set_replay_filter_capacity(&mut self, entries: usize) {
+        self.replay_protector = ReplayProtector::with_capacity(self.config_type, entries);
+    }
+
+    /// Bytes of the bloom filter against replay attack of stream and AEAD ciphers
+    pub fn replay_filter_size(&self) -> usize {
+        self.replay_protector.bloom_filter_size()
+    }
+
+    /// Number of nonces of AEAD 2022 ciphers remembered against replay attack
+    pub fn replay_nonce_count(&self) -> usize {
+        self.replay_protector.nonce_count()
+    }
+
+    /// Get runtime statistic
+    pub fn stat(&self) -> &ContextStat {
+        &self.stat
+    }
+
+    /// Share `token` for shutting down services of this context, instead of the default one
+    ///
+    /// Services with this context are shutdown when `token` is cancelled, for example by the embedding application.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation_token = token;
+    }
+
+    /// Get the token for shutting down services of this context
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancellation_token
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use crate::dns_resolver::DnsResolve;
+
+    // Resolves names to addresses that could be changed by tests
+    #[derive(Clone, Default)]
+    struct TestResolver(Arc<Mutex<HashMap<String, IpAddr>>>);
+
+    impl TestResolver {
+        fn set(&self, name: &str, ip: [u8; 4]) {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(name.to_owned(), Ipv4Addr::from(ip).into());
+        }
+    }
+
+    impl DnsResolve for TestResolver {
+        async fn resolve(&self, addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            match self.0.lock().unwrap().get(addr) {
+                Some(ip) => Ok(vec![SocketAddr::new(*ip, port)]),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "not found")),
+            }
+        }
+    }
+
+    fn test_context() -> (Context, TestResolver) {
+        let resolver = TestResolver::default();
+        let mut context = Context::new(ServerType::Local);
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(resolver.clone())));
+        (context, resolver)
+    }
+
+    fn server_addr_failures(context: &Context, addr: &str, port: u16) -> u32 {
+        let entries = context.server_addr_cache.entries.lock().unwrap();
+        entries.get(&(addr.to_owned(), port)).map_or(0, |c| c.failures)
+    }
+
+    #[tokio::test]
+    async fn server_addr_failures() {
+        let (context, resolver) = test_context();
+        resolver.set("a.example", [192, 0, 2, 1]);
+
+        let addrs = context.resolve_server_addr("a.example", 8388).await.unwrap();
+        assert_eq!(addrs, ["192.0.2.1:8388".parse::<SocketAddr>().unwrap()]);
+
+        // Failures are kept if addresses are not changed
+        context.invalidate_server_addr("a.example", 8388);
+        context.invalidate_server_addr("a.example", 8388);
+        context.resolve_server_addr("a.example", 8388).await.unwrap();
+        assert_eq!(server_addr_failures(&context, "a.example", 8388), 2);
+
+        // Resolving bypassing caches resets failures, even if addresses are not changed
+        context.invalidate_server_addr("a.example", 8388);
+        context.resolve_server_addr("a.example", 8388).await.unwrap();
+        assert_eq!(server_addr_failures(&context, "a.example", 8388), 0);
+        assert_eq!(context.server_addr_generation("a.example", 8388), 0);
+
+        // Changed addresses reset failures
+        context.invalidate_server_addr("a.example", 8388);
+        resolver.set("a.example", [192, 0, 2, 2]);
+        let addrs = context.resolve_server_addr("a.example", 8388).await.unwrap();
+        assert_eq!(addrs, ["192.0.2.2:8388".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(server_addr_failures(&context, "a.example", 8388), 0);
+
+        context.invalidate_server_addr("a.example", 8388);
+        context.server_addr_connected("a.example", 8388);
+        assert_eq!(server_addr_failures(&context, "a.example", 8388), 0);
+    }
+
+    #[tokio::test]
+    async fn server_addr_generation() {
+        let (context, resolver) = test_context();
+        resolver.set("a.example", [192, 0, 2, 1]);
+        resolver.set("b.example", [192, 0, 2, 10]);
+
+        context.resolve_server_addr("a.example", 8388).await.unwrap();
+        context.resolve_server_addr("b.example", 8388).await.unwrap();
+        assert_eq!(context.server_addr_generation("a.example", 8388), 0);
+        assert_eq!(context.server_addr_generation("b.example", 8388), 0);
+
+        resolver.set("a.example", [192, 0, 2, 2]);
+        for name in ["a.example", "b.example"] {
+            context.invalidate_server_addr(name, 8388);
+            context.resolve_server_addr(name, 8388).await.unwrap();
+        }
+        assert_eq!(context.server_addr_generation("a.example", 8388), 1);
+        assert_eq!(context.server_addr_generation("b.example", 8388), 0);
+
+        // Not changed again
+        context.invalidate_server_addr("a.example", 8388);
+        context.resolve_server_addr("a.example", 8388).await.unwrap();
+        assert_eq!(context.server_addr_generation("a.example", 8388), 1);
+
+        // Cached addresses are kept if resolving fails
+        resolver.0.lock().unwrap().clear();
+        context.invalidate_server_addr("a.example", 8388);
+        let addrs = context.resolve_server_addr("a.example", 8388).await.unwrap();
+        assert_eq!(addrs, ["192.0.2.2:8388".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(context.server_addr_generation("a.example", 8388), 1);
+    }
 }
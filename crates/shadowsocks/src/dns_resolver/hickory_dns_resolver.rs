@@ -182,3 +182,15 @@ pub async fn create_resolver(
         }
     }
 }
+
+/// Create a resolver with the same configuration as `resolver`, without caching any records
+pub fn create_uncached_resolver(resolver: &DnsResolver, connect_opts: ConnectOpts) -> DnsResolver {
+    let mut builder = DnsResolver::builder_with_config(
+        resolver.config().clone(),
+        ShadowDnsConnectionProvider::new(ShadowDnsRuntimeProvider::new(connect_opts)),
+    );
+    let opts = builder.options_mut();
+    *opts = resolver.options().clone();
+    opts.cache_size = 0;
+    builder.build()
+}
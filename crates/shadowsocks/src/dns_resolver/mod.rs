@@ -10,7 +10,20 @@ mod resolver;
 /// Helper macro for resolving host and then process each addresses
 #[macro_export]
 macro_rules! lookup_then {
-    ($context:expr_2021, $addr:expr_2021, $port:expr_2021, |$resolved_addr:ident| $body:block) => {{
+    ($context:expr_2021, $addr:expr_2021, $port:expr_2021, |$resolved_addr:ident| $body:block) => {
+        $crate::lookup_then!(ADDRS @ $context, $context.dns_resolve($addr, $port).await?, |$resolved_addr| $body)
+    };
+
+    // Addresses of servers, cached by `Context`
+    (SERVER @ $context:expr_2021, $addr:expr_2021, $port:expr_2021, |$resolved_addr:ident| $body:block) => {{
+        let result = $crate::lookup_then!(ADDRS @ $context, $context.resolve_server_addr($addr, $port).await?, |$resolved_addr| $body);
+        if result.is_err() {
+            $context.invalidate_server_addr($addr, $port);
+        }
+        result
+    }};
+
+    (ADDRS @ $context:expr_2021, $addrs:expr_2021, |$resolved_addr:ident| $body:block) => {{
         use std::net::SocketAddr;
 
         let ipv6_first = $context.ipv6_first();
@@ -18,7 +31,7 @@ macro_rules! lookup_then {
         let mut v4_addrs = Vec::new();
         let mut v6_addrs = Vec::new();
 
-        for addr in $context.dns_resolve($addr, $port).await? {
+        for addr in $addrs {
             match addr {
                 SocketAddr::V4(..) => v4_addrs.push(addr),
                 SocketAddr::V6(..) => v6_addrs.push(addr),
@@ -70,7 +83,21 @@ macro_rules! lookup_then {
 
 #[macro_export]
 macro_rules! lookup_then_connect {
-    ($context:expr_2021, $addr:expr_2021, $port:expr_2021, |$resolved_addr:ident| $body:block) => {{
+    ($context:expr_2021, $addr:expr_2021, $port:expr_2021, |$resolved_addr:ident| $body:block) => {
+        $crate::lookup_then_connect!(ADDRS @ $context, $context.dns_resolve($addr, $port).await?, $addr, $port, |$resolved_addr| $body)
+    };
+
+    // Addresses of servers, cached by `Context`, and resolved again after failures
+    (SERVER @ $context:expr_2021, $addr:expr_2021, $port:expr_2021, |$resolved_addr:ident| $body:block) => {{
+        let result = $crate::lookup_then_connect!(ADDRS @ $context, $context.resolve_server_addr($addr, $port).await?, $addr, $port, |$resolved_addr| $body);
+        match result {
+            Ok(..) => $context.server_addr_connected($addr, $port),
+            Err(..) => $context.invalidate_server_addr($addr, $port),
+        }
+        result
+    }};
+
+    (ADDRS @ $context:expr_2021, $addrs:expr_2021, $addr:expr_2021, $port:expr_2021, |$resolved_addr:ident| $body:block) => {{
         use futures::future::{self, Either};
         use log::trace;
         use std::{net::SocketAddr, time::Duration};
@@ -81,7 +108,7 @@ macro_rules! lookup_then_connect {
         let mut v4_addrs = Vec::new();
         let mut v6_addrs = Vec::new();
 
-        for addr in $context.dns_resolve($addr, $port).await? {
+        for addr in $addrs {
             match addr {
                 SocketAddr::V4(..) => v4_addrs.push(addr),
                 SocketAddr::V6(..) => v6_addrs.push(addr),
@@ -4,7 +4,7 @@
 use std::sync::Arc;
 use std::{
     fmt::{self, Debug},
-    io::{self, Error, ErrorKind},
+    io::{self, Error},
     net::SocketAddr,
     time::Instant,
 };
@@ -23,7 +23,6 @@ use tokio::net::lookup_host;
 #[cfg(all(feature = "hickory-dns", unix, not(target_os = "android")))]
 use tokio::task::JoinHandle;
 
-#[cfg(feature = "hickory-dns")]
 use crate::net::ConnectOpts;
 
 #[cfg(feature = "hickory-dns")]
@@ -376,7 +375,7 @@ impl DnsResolver {
             DnsResolver::System => match lookup_host((addr, port)).await {
                 Ok(v) => Ok(EitherResolved::Tokio(v)),
                 Err(err) => {
-                    let err = Error::new(ErrorKind::Other, format!("dns resolve {addr}:{port} error: {err}"));
+                    let err = Error::from(crate::Error::Dns(format!("resolve {addr}:{port} error: {err}").into()));
                     Err(err)
                 }
             },
@@ -386,7 +385,7 @@ impl DnsResolver {
                     lookup_result.into_iter().map(move |ip| SocketAddr::new(ip, port)),
                 )),
                 Err(err) => {
-                    let err = Error::new(ErrorKind::Other, format!("dns resolve {addr}:{port} error: {err}"));
+                    let err = Error::from(crate::Error::Dns(format!("resolve {addr}:{port} error: {err}").into()));
                     Err(err)
                 }
             },
@@ -396,22 +395,62 @@ impl DnsResolver {
                     lookup_result.into_iter().map(move |ip| SocketAddr::new(ip, port)),
                 )),
                 Err(err) => {
-                    let err = Error::new(ErrorKind::Other, format!("dns resolve {addr}:{port} error: {err}"));
+                    let err = Error::from(crate::Error::Dns(format!("resolve {addr}:{port} error: {err}").into()));
                     Err(err)
                 }
             },
             DnsResolver::Custom(ref resolver) => match resolver.resolve(addr, port).await {
                 Ok(v) => Ok(EitherResolved::Custom(v.into_iter())),
                 Err(err) => {
-                    let err = Error::new(ErrorKind::Other, format!("dns resolve {addr}:{port} error: {err}"));
+                    let err = Error::from(crate::Error::Dns(format!("resolve {addr}:{port} error: {err}").into()));
                     Err(err)
                 }
             },
         }
     }
 
+    /// Create a resolver of the same configuration that doesn't cache records, `None` if this resolver has no caches
+    ///
+    /// Only hickory-dns resolvers have their own caches. `connect_opts` should be the same as this resolver was created
+    /// with.
+    pub fn uncached_resolver(&self, connect_opts: ConnectOpts) -> Option<DnsResolver> {
+        match *self {
+            DnsResolver::System | DnsResolver::Custom(..) => {
+                let _ = connect_opts;
+                None
+            }
+            #[cfg(feature = "hickory-dns")]
+            DnsResolver::HickoryDnsSystem { ref inner, .. } => {
+                use super::hickory_dns_resolver::create_uncached_resolver;
+                Some(DnsResolver::HickoryDns(create_uncached_resolver(
+                    &inner.resolver.load(),
+                    connect_opts,
+                )))
+            }
+            #[cfg(feature = "hickory-dns")]
+            DnsResolver::HickoryDns(ref resolver) => {
+                use super::hickory_dns_resolver::create_uncached_resolver;
+                Some(DnsResolver::HickoryDns(create_uncached_resolver(
+                    resolver,
+                    connect_opts,
+                )))
+            }
+        }
+    }
+
     /// Check if currently using system resolver
     pub fn is_system_resolver(&self) -> bool {
         matches!(*self, DnsResolver::System)
     }
+
+    /// Clear cached records, only hickory-dns resolvers have their own caches
+    pub fn clear_cache(&self) {
+        match *self {
+            DnsResolver::System | DnsResolver::Custom(..) => {}
+            #[cfg(feature = "hickory-dns")]
+            DnsResolver::HickoryDnsSystem { ref inner, .. } => inner.resolver.load().clear_cache(),
+            #[cfg(feature = "hickory-dns")]
+            DnsResolver::HickoryDns(ref resolver) => resolver.clear_cache(),
+        }
+    }
 }
@@ -0,0 +1,152 @@
+//! Errors of shadowsocks
+//!
+//! Streams and sockets report errors with `io::Error` for being used as I/O objects, the categories of errors are
+//! carried inside them. `Error::from(io::Error)` recovers the category, so applications could tell a wrong password
+//! (`Error::Crypto`) from an unreachable network (`Error::Io`):
+//!
+//! ```
+//! use std::io;
+//!
+//! fn report(err: io::Error) {
+//!     match shadowsocks::Error::from(err) {
+//!         shadowsocks::Error::Crypto(err) => eprintln!("check the password and method, {}", err),
+//!         shadowsocks::Error::Io(err) => eprintln!("network error, {}", err),
+//!         err => eprintln!("{}", err),
+//!     }
+//! }
+//! ```
+
+use std::io::{self, ErrorKind};
+
+use crate::{
+    config::{ServerAddrError, ServerConfigError, UrlParseError},
+    relay::{socks5, tcprelay::crypto_io as tcp_crypto_io, udprelay::proxy_socket::ProxySocketError},
+};
+
+/// Boxed source of `Error`
+pub type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Categories of errors
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// Invalid configuration
+    #[error("config error: {0}")]
+    Config(BoxError),
+    /// Data couldn't be decrypted, usually the peer is using a different password or method
+    #[error("crypto error: {0}")]
+    Crypto(BoxError),
+    /// Peer violated the shadowsocks or SOCKS5 protocol
+    #[error("protocol error: {0}")]
+    Protocol(BoxError),
+    /// Hostname couldn't be resolved
+    #[error("dns error: {0}")]
+    Dns(BoxError),
+    /// Invalid access control list
+    #[error("acl error: {0}")]
+    Acl(BoxError),
+    /// Plugin failed to start or exited
+    #[error("plugin error: {0}")]
+    Plugin(BoxError),
+    /// Other I/O errors, including network errors
+    #[error(transparent)]
+    Io(io::Error),
+}
+
+/// Result of shadowsocks' APIs
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Kind of the `io::Error` converted from this error
+    ///
+    /// Kind of the source is kept if it is an `io::Error`.
+    pub fn io_kind(&self) -> ErrorKind {
+        let (source, default_kind) = match *self {
+            Error::Config(ref e) | Error::Acl(ref e) => (e, ErrorKind::InvalidInput),
+            Error::Crypto(ref e) | Error::Protocol(ref e) => (e, ErrorKind::InvalidData),
+            Error::Dns(ref e) | Error::Plugin(ref e) => (e, ErrorKind::Other),
+            Error::Io(ref err) => return err.kind(),
+        };
+
+        match source.downcast_ref::<io::Error>() {
+            Some(err) => err.kind(),
+            None => default_kind,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            err => io::Error::new(err.io_kind(), err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        // Categorized by shadowsocks
+        if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            let inner = err.into_inner().expect("io::Error without inner error");
+            return *inner.downcast::<Error>().expect("io::Error inner isn't Error");
+        }
+
+        if tcp_crypto_io::is_decrypt_error(&err) {
+            return Error::Crypto(err.into());
+        }
+        if tcp_crypto_io::is_protocol_error(&err) {
+            return Error::Protocol(err.into());
+        }
+
+        let is_socks5_error = err.get_ref().is_some_and(|inner| inner.is::<socks5::Error>());
+        if is_socks5_error {
+            return Error::Protocol(err.into());
+        }
+
+        let proxy_socket_error = err.get_ref().and_then(|inner| inner.downcast_ref::<ProxySocketError>());
+        match proxy_socket_error {
+            Some(ProxySocketError::InvalidServerUser(..)) => Error::Crypto(err.into()),
+            Some(ProxySocketError::ProtocolError(e) | ProxySocketError::ProtocolErrorWithPeer(_, e)) => {
+                if e.is_decrypt_error() {
+                    Error::Crypto(err.into())
+                } else {
+                    Error::Protocol(err.into())
+                }
+            }
+            _ => Error::Io(err),
+        }
+    }
+}
+
+impl From<socks5::Error> for Error {
+    fn from(err: socks5::Error) -> Error {
+        match err {
+            socks5::Error::IoError(err) => Error::from(err),
+            err => Error::Protocol(err.into()),
+        }
+    }
+}
+
+impl From<ProxySocketError> for Error {
+    fn from(err: ProxySocketError) -> Error {
+        Error::from(io::Error::from(err))
+    }
+}
+
+impl From<ServerConfigError> for Error {
+    fn from(err: ServerConfigError) -> Error {
+        Error::Config(err.into())
+    }
+}
+
+impl From<UrlParseError> for Error {
+    fn from(err: UrlParseError) -> Error {
+        Error::Config(err.into())
+    }
+}
+
+impl From<ServerAddrError> for Error {
+    fn from(err: ServerAddrError) -> Error {
+        Error::Config(err.to_string().into())
+    }
+}
@@ -4,11 +4,18 @@
 
 pub use self::{
     config::{ManagerAddr, ServerAddr, ServerConfig},
+    error::Error,
+    relay::udprelay::proxy_socket::ProxySocket,
+};
+#[cfg(feature = "server")]
+pub use self::{
     manager::{ManagerClient, ManagerListener},
-    relay::{
-        tcprelay::{proxy_listener::ProxyListener, proxy_stream::ProxyClientStream},
-        udprelay::proxy_socket::ProxySocket,
-    },
+    relay::tcprelay::proxy_listener::ProxyListener,
+};
+#[cfg(feature = "local")]
+pub use self::relay::{
+    tcprelay::{proxy_client::ProxyClient, proxy_stream::ProxyClientStream},
+    udprelay::proxy_client::ProxyClientSocket,
 };
 
 pub use shadowsocks_crypto as crypto;
@@ -16,6 +23,8 @@ pub use shadowsocks_crypto as crypto;
 pub mod config;
 pub mod context;
 pub mod dns_resolver;
+pub mod error;
+#[cfg(feature = "server")]
 pub mod manager;
 pub mod net;
 pub mod plugin;
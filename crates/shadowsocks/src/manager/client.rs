@@ -9,7 +9,7 @@ use super::{
     error::Error,
     protocol::{
         AddRequest, AddResponse, ListRequest, ListResponse, ManagerProtocol, PingRequest, PingResponse, RemoveRequest,
-        RemoveResponse, StatRequest,
+        RemoveResponse, StatRequest, UserStatRequest,
     },
 };
 
@@ -58,6 +58,16 @@ impl ManagerClient {
         Ok(())
     }
 
+    /// Send `user-stat` report
+    pub async fn user_stat(&mut self, req: &UserStatRequest) -> Result<(), Error> {
+        let buf = req.to_bytes()?;
+        let n = self.socket.send(&buf).await?;
+        if n != buf.len() {
+            warn!("manager send {} bytes != buffer {} bytes", n, buf.len());
+        }
+        Ok(())
+    }
+
     async fn request<S, R>(&mut self, req: &S) -> Result<R, Error>
     where
         S: ManagerProtocol,
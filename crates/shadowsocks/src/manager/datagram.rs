@@ -15,7 +15,7 @@ use crate::{
 };
 
 /// Address accepted from Manager
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ManagerSocketAddr {
     SocketAddr(SocketAddr),
     #[cfg(unix)]
@@ -21,6 +21,8 @@ pub trait ManagerProtocol: Sized {
 pub struct ServerUserConfig {
     pub name: String,
     pub password: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
 }
 
 /// Server's configuration
@@ -42,6 +44,12 @@ pub struct ServerConfig {
     pub mode: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub users: Option<Vec<ServerUserConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota_terminate: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_ports: Option<Vec<u16>>,
 }
 
 /// `add` request
@@ -265,6 +273,42 @@ impl ManagerProtocol for StatRequest {
     }
 }
 
+/// `user-stat` request
+///
+/// Traffic of each EIH user of servers, `{ server_port: { user_name: bytes } }`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
+pub struct UserStatRequest {
+    pub stat: HashMap<u16, HashMap<String, u64>>,
+}
+
+impl ManagerProtocol for UserStatRequest {
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        let mut nsplit = buf.splitn(2, |b| *b == b':');
+
+        let cmd = nsplit.next().expect("first element shouldn't be None");
+        let cmd = str::from_utf8(cmd)?.trim();
+        if cmd != "user-stat" {
+            return Err(Error::UnrecognizedCommand(cmd.to_owned()));
+        }
+
+        match nsplit.next() {
+            None => Err(Error::MissingParameter),
+            Some(param) => {
+                let req = serde_json::from_slice(param)?;
+                Ok(req)
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = b"user-stat: ".to_vec();
+        serde_json::to_writer(&mut buf, self)?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+}
+
 /// Server's error message
 #[derive(Debug, Clone)]
 pub struct ErrorResponse<E: ToString>(pub E);
@@ -289,6 +333,7 @@ pub enum ManagerRequest {
     List(ListRequest),
     Ping(PingRequest),
     Stat(StatRequest),
+    UserStat(UserStatRequest),
 }
 
 impl ManagerRequest {
@@ -300,6 +345,7 @@ impl ManagerRequest {
             ManagerRequest::List(..) => "list",
             ManagerRequest::Ping(..) => "ping",
             ManagerRequest::Stat(..) => "stat",
+            ManagerRequest::UserStat(..) => "user-stat",
         }
     }
 }
@@ -312,6 +358,7 @@ impl ManagerProtocol for ManagerRequest {
             ManagerRequest::List(ref req) => req.to_bytes(),
             ManagerRequest::Ping(ref req) => req.to_bytes(),
             ManagerRequest::Stat(ref req) => req.to_bytes(),
+            ManagerRequest::UserStat(ref req) => req.to_bytes(),
         }
     }
 
@@ -353,6 +400,13 @@ impl ManagerProtocol for ManagerRequest {
                     Ok(ManagerRequest::Stat(req))
                 }
             },
+            "user-stat" => match nsplit.next() {
+                None => Err(Error::MissingParameter),
+                Some(param) => {
+                    let req = serde_json::from_slice(param)?;
+                    Ok(ManagerRequest::UserStat(req))
+                }
+            },
             cmd => Err(Error::UnrecognizedCommand(cmd.to_owned())),
         }
     }
@@ -0,0 +1,61 @@
+//! Customized dialer of outbound connections
+
+use std::{
+    fmt::{self, Debug},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use tokio::net::{TcpStream as TokioTcpStream, UdpSocket as TokioUdpSocket};
+
+use super::{AddrFamily, ConnectOpts};
+
+/// Abstract dialer of outbound connections
+///
+/// It replaces the builtin dialer for connecting outbound TCP streams and creating outbound UDP sockets, for example
+/// protecting sockets from VPN routing and binding them to networks on mobile platforms, or dialing through another
+/// proxy. Other options in `ConnectOpts` should be applied by the dialer itself if they are supported.
+#[trait_variant::make(Send)]
+#[dynosaur::dynosaur(DynOutboundConnect)]
+pub trait OutboundConnect {
+    /// Connects a TCP stream to `addr`
+    async fn connect_tcp(&self, addr: SocketAddr, opts: &ConnectOpts) -> io::Result<TokioTcpStream>;
+
+    /// Creates an unconnected UDP socket for sending to addresses of family `af`
+    async fn create_udp_socket(&self, af: AddrFamily, opts: &ConnectOpts) -> io::Result<TokioUdpSocket>;
+}
+
+// Equivalent to (dyn OutboundConnect + Send + Sync)
+unsafe impl Send for DynOutboundConnect<'_> {}
+unsafe impl Sync for DynOutboundConnect<'_> {}
+
+/// Outbound dialer set in `ConnectOpts::connector`
+#[derive(Clone)]
+pub struct OutboundConnector(Arc<DynOutboundConnect<'static>>);
+
+impl OutboundConnector {
+    /// Create with a customized dialer
+    pub fn new<C>(connector: C) -> OutboundConnector
+    where
+        C: OutboundConnect + Send + Sync + 'static,
+    {
+        OutboundConnector(Arc::from(DynOutboundConnect::boxed(connector)))
+    }
+
+    /// Connects a TCP stream to `addr`
+    pub async fn connect_tcp(&self, addr: SocketAddr, opts: &ConnectOpts) -> io::Result<TokioTcpStream> {
+        self.0.connect_tcp(addr, opts).await
+    }
+
+    /// Creates an unconnected UDP socket for sending to addresses of family `af`
+    pub async fn create_udp_socket(&self, af: AddrFamily, opts: &ConnectOpts) -> io::Result<TokioUdpSocket> {
+        self.0.create_udp_socket(af, opts).await
+    }
+}
+
+impl Debug for OutboundConnector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OutboundConnector(..)")
+    }
+}
@@ -0,0 +1,386 @@
+//! Listener handover between processes
+//!
+//! For upgrading binaries without closing listeners, inbound listeners (TCP listeners and UDP sockets) of the old
+//! process are sent to the new process by `SCM_RIGHTS` over a connected Unix stream socket. Listeners received are
+//! taken by inbound listeners bound to the same addresses, instead of binding new sockets.
+//!
+//! Each listener is sent in a message with a 1 byte payload [`HANDOVER_LISTENER`], and the last message is
+//! [`HANDOVER_END`] without any file descriptors.
+//!
+//! Both sides check that the peer is running as the same user (or root), so listeners couldn't be stolen by, or
+//! injected from other users of the host.
+
+use std::{
+    io::{self, ErrorKind},
+    mem,
+    net::{SocketAddr, TcpListener, UdpSocket},
+    os::unix::{
+        io::{AsRawFd, BorrowedFd, FromRawFd, RawFd},
+        net::UnixStream,
+    },
+    ptr,
+    sync::Mutex,
+};
+
+use log::{debug, trace};
+use once_cell::sync::Lazy;
+use socket2::{SockRef, Socket, Type};
+
+/// Payload of messages with a listener
+pub const HANDOVER_LISTENER: u8 = b'L';
+/// Payload of the last message
+pub const HANDOVER_END: u8 = b'E';
+
+// Inbound listeners bound by this process, they are not owned and must be checked before being sent
+static LISTENER_FDS: Lazy<Mutex<Vec<(RawFd, SocketAddr, Type)>>> = Lazy::new(|| Mutex::new(Vec::new()));
+// Listeners received from the old process
+static RECEIVED_SOCKETS: Lazy<Mutex<Vec<Socket>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Address and type of `fd` if it is a listening TCP socket, or a bound and unconnected UDP socket
+fn listener_addr(fd: RawFd) -> Option<(SocketAddr, Type)> {
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    let socket = SockRef::from(&fd);
+    let addr = socket.local_addr().ok()?.as_socket()?;
+    let ty = socket.r#type().ok()?;
+
+    if addr.port() == 0 {
+        return None;
+    }
+
+    if ty == Type::DGRAM {
+        // Outbound UDP sockets are connected to their targets
+        if socket.peer_addr().is_ok() {
+            return None;
+        }
+    } else if ty == Type::STREAM {
+        // fd may be reused by an accepted connection after the listener is closed
+        let mut accept_conn: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_ACCEPTCONN,
+                &mut accept_conn as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 || accept_conn == 0 {
+            return None;
+        }
+    } else {
+        return None;
+    }
+
+    Some((addr, ty))
+}
+
+/// Check that the peer of `stream` is running as the same user as this process, or root
+fn check_peer_user(stream: &UnixStream) -> io::Result<()> {
+    let uid = peer_uid(stream)?;
+    let euid = unsafe { libc::geteuid() };
+    if uid != euid && uid != 0 {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("handover peer is running as uid {uid}, expecting {euid}"),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+#[cfg(any(
+    target_vendor = "apple",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_vendor = "apple",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+)))]
+fn peer_uid(_stream: &UnixStream) -> io::Result<libc::uid_t> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "credentials of handover peers couldn't be checked on this platform",
+    ))
+}
+
+/// Register an inbound listener, which would be sent by [`send_listeners`]
+pub(crate) fn register_listener(fd: RawFd) {
+    let Some((addr, ty)) = listener_addr(fd) else {
+        return;
+    };
+
+    let mut fds = LISTENER_FDS.lock().unwrap();
+    // Listeners are not unregistered when closed, so forget the closed ones here
+    fds.retain(|&(registered, ..)| registered != fd && listener_addr(registered).is_some());
+    fds.push((fd, addr, ty));
+}
+
+/// Send all inbound listeners to `stream`, returns the number of listeners sent
+///
+/// Listeners are still open in this process after being sent, they should be closed by stopping the servers.
+pub fn send_listeners(stream: &UnixStream) -> io::Result<usize> {
+    check_peer_user(stream)?;
+
+    let fds: Vec<(RawFd, SocketAddr)> = {
+        let fds = LISTENER_FDS.lock().unwrap();
+        // fd may be reused by another socket after the listener is closed
+        fds.iter()
+            .filter(|&&(fd, addr, ty)| listener_addr(fd) == Some((addr, ty)))
+            .map(|&(fd, addr, ..)| (fd, addr))
+            .collect()
+    };
+
+    for &(fd, addr) in &fds {
+        trace!("handing over listener {} bound to {}", fd, addr);
+        send_message(stream, HANDOVER_LISTENER, Some(fd))?;
+    }
+    send_message(stream, HANDOVER_END, None)?;
+
+    Ok(fds.len())
+}
+
+/// Receive listeners from `stream` until the end, returns the number of listeners received
+pub fn receive_listeners(stream: &UnixStream) -> io::Result<usize> {
+    check_peer_user(stream)?;
+
+    let mut count = 0;
+    loop {
+        match recv_message(stream)? {
+            (HANDOVER_LISTENER, Some(socket)) => {
+                // Plugins shouldn't inherit them
+                socket.set_cloexec(true)?;
+                match listener_addr(socket.as_raw_fd()) {
+                    Some((addr, ..)) => debug!("received listener bound to {}", addr),
+                    None => {
+                        debug!("received socket is not a listener, closing");
+                        continue;
+                    }
+                }
+                RECEIVED_SOCKETS.lock().unwrap().push(socket);
+                count += 1;
+            }
+            (HANDOVER_END, None) => return Ok(count),
+            (payload, ..) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid handover message {payload:#x}"),
+                ));
+            }
+        }
+    }
+}
+
+fn send_message(stream: &UnixStream, payload: u8, fd: Option<RawFd>) -> io::Result<()> {
+    let mut payload = [payload];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    // Aligned for cmsghdr
+    let mut control = [0u64; 8];
+
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+
+        if let Some(fd) = fd {
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as _;
+
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+        }
+
+        if libc::sendmsg(stream.as_raw_fd(), &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+fn recv_message(stream: &UnixStream) -> io::Result<(u8, Option<Socket>)> {
+    let mut payload = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let mut control = [0u64; 8];
+
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let n = libc::recvmsg(stream.as_raw_fd(), &mut msg, 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if n == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "handover connection closed before the end",
+            ));
+        }
+
+        // Take all file descriptors received, so they are closed if the message is invalid
+        let mut sockets = Vec::new();
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    let fd = ptr::read_unaligned(data.add(i));
+                    sockets.push(Socket::from_raw_fd(fd));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "handover message control data truncated",
+            ));
+        }
+        if sockets.len() > 1 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("handover message carries {} file descriptors", sockets.len()),
+            ));
+        }
+
+        Ok((payload[0], sockets.pop()))
+    }
+}
+
+/// Take the socket bound to `addr` with type `ty` from `sockets`
+///
+/// Unspecified addresses of IPv4 and IPv6 are treated as the same, because dual-stack listeners are bound to `[::]`.
+pub(crate) fn take_bound_socket(sockets: &mut Vec<Socket>, addr: &SocketAddr, ty: Type) -> Option<Socket> {
+    let index = sockets.iter().position(|socket| {
+        if socket.r#type().ok() != Some(ty) {
+            return false;
+        }
+        match socket.local_addr().ok().and_then(|a| a.as_socket()) {
+            Some(bound) => {
+                bound == *addr
+                    || (bound.port() == addr.port() && bound.ip().is_unspecified() && addr.ip().is_unspecified())
+            }
+            None => false,
+        }
+    })?;
+    Some(sockets.swap_remove(index))
+}
+
+/// Take the TCP listener received from the old process, which is bound to `addr`
+pub fn take_tcp_listener(addr: &SocketAddr) -> Option<TcpListener> {
+    let mut sockets = RECEIVED_SOCKETS.lock().unwrap();
+    take_bound_socket(&mut sockets, addr, Type::STREAM).map(Into::into)
+}
+
+/// Take the UDP socket received from the old process, which is bound to `addr`
+pub fn take_udp_socket(addr: &SocketAddr) -> Option<UdpSocket> {
+    let mut sockets = RECEIVED_SOCKETS.lock().unwrap();
+    take_bound_socket(&mut sockets, addr, Type::DGRAM).map(Into::into)
+}
+
+/// Close listeners received but not taken by any inbound listeners, returns the number of them
+pub fn close_unused_listeners() -> usize {
+    let mut sockets = RECEIVED_SOCKETS.lock().unwrap();
+    let count = sockets.len();
+    sockets.clear();
+    count
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn handover_round_trip() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let udp_addr = udp.local_addr().unwrap();
+        register_listener(tcp.as_raw_fd());
+        register_listener(udp.as_raw_fd());
+
+        // Not a listener, never registered
+        let connected = UdpSocket::bind("127.0.0.1:0").unwrap();
+        connected.connect(udp_addr).unwrap();
+        register_listener(connected.as_raw_fd());
+
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let sending = thread::spawn(move || send_listeners(&sender).unwrap());
+        let received = receive_listeners(&receiver).unwrap();
+        let sent = sending.join().unwrap();
+
+        // Listeners of other tests may be sent too
+        assert_eq!(received, sent);
+        assert!(received >= 2);
+
+        let tcp_received = take_tcp_listener(&tcp_addr).unwrap();
+        assert_eq!(tcp_received.local_addr().unwrap(), tcp_addr);
+        let udp_received = take_udp_socket(&udp_addr).unwrap();
+        assert_eq!(udp_received.local_addr().unwrap(), udp_addr);
+        assert!(take_udp_socket(&connected.local_addr().unwrap()).is_none());
+    }
+
+    #[test]
+    fn handover_rejects_non_listeners() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+
+        let connected = UdpSocket::bind("127.0.0.1:0").unwrap();
+        connected.connect("127.0.0.1:9").unwrap();
+        send_message(&sender, HANDOVER_LISTENER, Some(connected.as_raw_fd())).unwrap();
+        send_message(&sender, HANDOVER_END, None).unwrap();
+
+        assert_eq!(receive_listeners(&receiver).unwrap(), 0);
+    }
+}
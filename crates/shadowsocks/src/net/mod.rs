@@ -4,17 +4,33 @@ use std::net::SocketAddr;
 
 #[cfg(unix)]
 pub use self::sys::uds::{UnixListener, UnixStream};
+#[cfg(all(any(target_os = "linux", target_os = "android"), feature = "socket-protect"))]
+pub use self::option::SocketProtect;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub use self::uring::{UringDriver, UringStream};
 pub use self::{
+    connector::{OutboundConnect, OutboundConnector},
     option::{AcceptOpts, ConnectOpts, TcpSocketOpts, UdpSocketOpts},
+    outbound_proxy::{OutboundProxy, OutboundProxyError, OutboundProxyProtocol},
     sys::{IpStackCapabilities, get_ip_stack_capabilities, set_tcp_fastopen, socket_bind_dual_stack},
     tcp::{TcpListener, TcpStream},
     udp::UdpSocket,
 };
 
+mod connector;
+#[cfg(unix)]
+pub mod handover;
 mod option;
+mod outbound_proxy;
 mod sys;
+#[cfg(target_os = "linux")]
+pub mod systemd;
 pub mod tcp;
+#[cfg(feature = "tokio-rustls")]
+pub mod tls;
 pub mod udp;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 
 /// Address family `AF_INET`, `AF_INET6`
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
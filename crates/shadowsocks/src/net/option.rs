@@ -1,7 +1,11 @@
 //! Options for connecting to remote server
 
+#[cfg(all(any(target_os = "linux", target_os = "android"), feature = "socket-protect"))]
+use std::{fmt, io, os::unix::io::RawFd, path::PathBuf, sync::Arc};
 use std::{net::SocketAddr, time::Duration};
 
+use super::{OutboundConnector, OutboundProxy};
+
 /// Options for connecting to TCP remote server
 #[derive(Debug, Clone, Default)]
 pub struct TcpSocketOpts {
@@ -42,6 +46,42 @@ pub struct UdpSocketOpts {
     pub allow_fragmentation: bool,
 }
 
+/// Hook called with outbound sockets before they are connected
+///
+/// VPN apps, like the ones built on Android's `VpnService`, have to exclude sockets of the tunnel from routing to the
+/// VPN interface, otherwise connections to remote servers will loop back into the tunnel.
+#[cfg(all(any(target_os = "linux", target_os = "android"), feature = "socket-protect"))]
+#[derive(Clone)]
+pub enum SocketProtect {
+    /// Sends the file descriptor to this unix socket path, and waits for the result in 3 seconds
+    ///
+    /// This is the `protect_path` protocol of [shadowsocks-android](https://github.com/shadowsocks/shadowsocks-android)
+    Path(PathBuf),
+    /// Calls this closure with the file descriptor, the socket won't be connected if it returns an error
+    Callback(Arc<dyn Fn(RawFd) -> io::Result<()> + Send + Sync>),
+}
+
+#[cfg(all(any(target_os = "linux", target_os = "android"), feature = "socket-protect"))]
+impl SocketProtect {
+    /// Create a hook calling `f`
+    pub fn callback<F>(f: F) -> SocketProtect
+    where
+        F: Fn(RawFd) -> io::Result<()> + Send + Sync + 'static,
+    {
+        SocketProtect::Callback(Arc::new(f))
+    }
+}
+
+#[cfg(all(any(target_os = "linux", target_os = "android"), feature = "socket-protect"))]
+impl fmt::Debug for SocketProtect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SocketProtect::Path(ref path) => f.debug_tuple("Path").field(path).finish(),
+            SocketProtect::Callback(..) => f.debug_tuple("Callback").finish_non_exhaustive(),
+        }
+    }
+}
+
 /// Options for connecting to remote server
 #[derive(Debug, Clone, Default)]
 pub struct ConnectOpts {
@@ -60,6 +100,10 @@ pub struct ConnectOpts {
     #[cfg(target_os = "android")]
     pub vpn_protect_path: Option<std::path::PathBuf>,
 
+    /// Hook called with every outbound socket before connecting, except the ones connecting to loopback addresses
+    #[cfg(all(any(target_os = "linux", target_os = "android"), feature = "socket-protect"))]
+    pub socket_protect: Option<SocketProtect>,
+
     /// Outbound socket binds to this IP address, mostly for choosing network interfaces
     ///
     /// It only affects sockets that trying to connect to addresses with the same family
@@ -68,6 +112,21 @@ pub struct ConnectOpts {
     /// Outbound socket binds to interface
     pub bind_interface: Option<String>,
 
+    /// DSCP of packets sent by outbound sockets, for QoS policies of routers
+    ///
+    /// It is set in the upper 6 bits of `IP_TOS` (IPv4) or `IPV6_TCLASS` (IPv6). Failures of setting it are only logged.
+    pub dscp: Option<u8>,
+
+    /// Customized dialer of outbound sockets, except the ones connecting to loopback addresses
+    pub connector: Option<OutboundConnector>,
+
+    /// Upstream SOCKS5 or HTTP proxy of TCP connections to servers, except the ones connecting to loopback addresses
+    pub outbound_proxy: Option<OutboundProxy>,
+
+    /// Reads and writes outbound TCP streams by this io_uring instance, except the ones with TFO enabled
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub uring: Option<std::sync::Arc<super::UringDriver>>,
+
     /// TCP options
     pub tcp: TcpSocketOpts,
 
@@ -86,4 +145,9 @@ pub struct AcceptOpts {
 
     /// Enable IPV6_V6ONLY option for socket
     pub ipv6_only: bool,
+
+    /// Enable `SO_REUSEPORT` option for socket, so multiple sockets could be bound to the same address, and the
+    /// kernel spreads connections (or datagrams) between them
+    #[cfg(unix)]
+    pub reuse_port: bool,
 }
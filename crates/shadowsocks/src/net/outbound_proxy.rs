@@ -0,0 +1,352 @@
+//! Upstream SOCKS5 or HTTP proxy of connections to servers
+//!
+//! Networks that block direct egress, like most corporate networks, only allow connections through their proxies.
+//! Servers are connected by `CONNECT` commands of the proxy, and domain names of servers are resolved by the proxy.
+
+use std::{
+    fmt::{self, Debug, Display, Write as _},
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    str::{self, FromStr},
+};
+
+use base64::Engine as _;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use url::Url;
+
+use crate::{
+    config::ServerAddr,
+    relay::socks5::{
+        self, Command, HandshakeRequest, HandshakeResponse, PasswdAuthRequest, PasswdAuthResponse, Reply,
+        TcpRequestHeader, TcpResponseHeader,
+    },
+};
+
+/// Maximum size of HTTP response headers of `CONNECT` requests
+const HTTP_RESPONSE_MAX_SIZE: usize = 8192;
+
+/// Protocol of outbound proxies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutboundProxyProtocol {
+    /// SOCKS5, 1080 is the default port
+    Socks5,
+    /// HTTP `CONNECT` with Basic authentication, 80 is the default port
+    Http,
+}
+
+impl OutboundProxyProtocol {
+    /// URL scheme of the protocol
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutboundProxyProtocol::Socks5 => "socks5",
+            OutboundProxyProtocol::Http => "http",
+        }
+    }
+
+    fn default_port(self) -> u16 {
+        match self {
+            OutboundProxyProtocol::Socks5 => 1080,
+            OutboundProxyProtocol::Http => 80,
+        }
+    }
+}
+
+/// Proxy of outbound connections, `socks5://[username:password@]host[:port]` or
+/// `http://[username:password@]host[:port]`
+#[derive(Clone, PartialEq, Eq)]
+pub struct OutboundProxy {
+    protocol: OutboundProxyProtocol,
+    addr: ServerAddr,
+    auth: Option<(String, String)>,
+}
+
+impl OutboundProxy {
+    /// Proxy at `addr` without authentication
+    pub fn new<A: Into<ServerAddr>>(protocol: OutboundProxyProtocol, addr: A) -> OutboundProxy {
+        OutboundProxy {
+            protocol,
+            addr: addr.into(),
+            auth: None,
+        }
+    }
+
+    /// Protocol of the proxy
+    pub fn protocol(&self) -> OutboundProxyProtocol {
+        self.protocol
+    }
+
+    /// Authenticate with username and password
+    pub fn set_auth<U: Into<String>, P: Into<String>>(&mut self, username: U, password: P) {
+        self.auth = Some((username.into(), password.into()));
+    }
+
+    /// Address of the proxy
+    pub fn addr(&self) -> &ServerAddr {
+        &self.addr
+    }
+
+    /// URL of the proxy, with username and password
+    pub fn to_url(&self) -> String {
+        match self.auth {
+            Some((ref username, ref password)) => format!(
+                "{}://{}:{}@{}",
+                self.protocol.as_str(),
+                percent_encoding::utf8_percent_encode(username, percent_encoding::NON_ALPHANUMERIC),
+                percent_encoding::utf8_percent_encode(password, percent_encoding::NON_ALPHANUMERIC),
+                self.addr
+            ),
+            None => format!("{}://{}", self.protocol.as_str(), self.addr),
+        }
+    }
+
+    /// Make `stream` connected to the proxy a tunnel to `target`
+    pub async fn handshake<S>(&self, stream: &mut S, target: &ServerAddr) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self.protocol {
+            OutboundProxyProtocol::Socks5 => self.handshake_socks5(stream, target).await,
+            OutboundProxyProtocol::Http => self.handshake_http(stream, target).await,
+        }
+    }
+
+    async fn handshake_socks5<S>(&self, stream: &mut S, target: &ServerAddr) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let methods = match self.auth {
+            Some(..) => vec![socks5::SOCKS5_AUTH_METHOD_NONE, socks5::SOCKS5_AUTH_METHOD_PASSWORD],
+            None => vec![socks5::SOCKS5_AUTH_METHOD_NONE],
+        };
+        HandshakeRequest::new(methods).write_to(stream).await?;
+
+        let rsp = HandshakeResponse::read_from(stream).await?;
+        match (rsp.chosen_method, &self.auth) {
+            (socks5::SOCKS5_AUTH_METHOD_NONE, _) => {}
+            (socks5::SOCKS5_AUTH_METHOD_PASSWORD, Some((username, password))) => {
+                PasswdAuthRequest::new(username.as_str(), password.as_str())
+                    .write_to(stream)
+                    .await?;
+                let rsp = PasswdAuthResponse::read_from(stream).await?;
+                if rsp.status != 0 {
+                    return Err(io::Error::new(
+                        ErrorKind::PermissionDenied,
+                        format!("outbound proxy {} rejected username and password", self.addr),
+                    ));
+                }
+            }
+            (method, _) => {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!(
+                        "outbound proxy {} requires unsupported authentication method {:#x}",
+                        self.addr, method
+                    ),
+                ));
+            }
+        }
+
+        TcpRequestHeader::new(Command::TcpConnect, target.into())
+            .write_to(stream)
+            .await?;
+        let rsp = TcpResponseHeader::read_from(stream).await?;
+        match rsp.reply {
+            Reply::Succeeded => Ok(()),
+            Reply::ConnectionRefused => Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!(
+                    "outbound proxy {} couldn't connect {}, connection refused",
+                    self.addr, target
+                ),
+            )),
+            reply => Err(io::Error::new(
+                ErrorKind::Other,
+                format!("outbound proxy {} couldn't connect {}, {}", self.addr, target, reply),
+            )),
+        }
+    }
+
+    async fn handshake_http<S>(&self, stream: &mut S, target: &ServerAddr) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some((ref username, ref password)) = self.auth {
+            let credentials = base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+            let _ = write!(request, "Proxy-Authorization: Basic {credentials}\r\n");
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read byte by byte, data of the tunnel after headers must not be consumed
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= HTTP_RESPONSE_MAX_SIZE {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("outbound proxy {} responded too large headers", self.addr),
+                ));
+            }
+            response.push(stream.read_u8().await?);
+        }
+
+        let status_line = response.split(|b| *b == b'\n').next().unwrap_or_default();
+        let status_line = str::from_utf8(status_line).unwrap_or_default().trim_end();
+        let status = match status_line.split(' ').collect::<Vec<_>>()[..] {
+            [version, status, ..] if version.starts_with("HTTP/1.") => status,
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "outbound proxy {} responded invalid status line {:?}",
+                        self.addr, status_line
+                    ),
+                ));
+            }
+        };
+
+        match status {
+            s if s.len() == 3 && s.starts_with('2') => Ok(()),
+            "407" => Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("outbound proxy {} requires authentication, {}", self.addr, status_line),
+            )),
+            _ => Err(io::Error::new(
+                ErrorKind::Other,
+                format!(
+                    "outbound proxy {} couldn't connect {}, {}",
+                    self.addr, target, status_line
+                ),
+            )),
+        }
+    }
+}
+
+impl Debug for OutboundProxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OutboundProxy")
+            .field("protocol", &self.protocol)
+            .field("addr", &self.addr)
+            .field("auth", &self.auth.as_ref().map(|(username, _)| username))
+            .finish()
+    }
+}
+
+impl Display for OutboundProxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.auth {
+            Some((ref username, ..)) => write!(f, "{}://{}@{}", self.protocol.as_str(), username, self.addr),
+            None => write!(f, "{}://{}", self.protocol.as_str(), self.addr),
+        }
+    }
+}
+
+/// Error of parsing `OutboundProxy`
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum OutboundProxyError {
+    #[error("{0}")]
+    InvalidUrl(#[from] url::ParseError),
+    #[error("unsupported scheme \"{0}\", only socks5 and http are supported")]
+    UnsupportedScheme(String),
+    #[error("missing host")]
+    MissingHost,
+    #[error("username and password should be UTF-8 encoded, in 1 to 255 bytes")]
+    InvalidAuth,
+}
+
+impl FromStr for OutboundProxy {
+    type Err = OutboundProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s)?;
+        let protocol = match url.scheme() {
+            "socks5" => OutboundProxyProtocol::Socks5,
+            "http" => OutboundProxyProtocol::Http,
+            scheme => return Err(OutboundProxyError::UnsupportedScheme(scheme.to_owned())),
+        };
+
+        let port = url.port().unwrap_or(protocol.default_port());
+        let addr = match url.host() {
+            Some(url::Host::Ipv4(ip)) => ServerAddr::from(SocketAddr::new(ip.into(), port)),
+            Some(url::Host::Ipv6(ip)) => ServerAddr::from(SocketAddr::new(ip.into(), port)),
+            // IPv4 addresses are not parsed in URLs of non-special schemes
+            Some(url::Host::Domain(domain)) => match domain.parse::<IpAddr>() {
+                Ok(ip) => ServerAddr::from(SocketAddr::new(ip, port)),
+                Err(..) => ServerAddr::DomainName(domain.to_owned(), port),
+            },
+            None => return Err(OutboundProxyError::MissingHost),
+        };
+
+        let mut proxy = OutboundProxy::new(protocol, addr);
+        if !url.username().is_empty() {
+            let decode = |s: &str| match percent_encoding::percent_decode_str(s).decode_utf8() {
+                Ok(s) if !s.is_empty() && s.len() <= u8::MAX as usize => Ok(s.into_owned()),
+                _ => Err(OutboundProxyError::InvalidAuth),
+            };
+            proxy.set_auth(decode(url.username())?, decode(url.password().unwrap_or_default())?);
+        }
+
+        Ok(proxy)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_url() {
+        let proxy = "socks5://proxy.corp.example:3128".parse::<OutboundProxy>().unwrap();
+        assert_eq!(
+            proxy.addr(),
+            &ServerAddr::DomainName("proxy.corp.example".to_owned(), 3128)
+        );
+        assert!(proxy.auth.is_none());
+
+        let proxy = "socks5://alice:p%40ss@[::1]".parse::<OutboundProxy>().unwrap();
+        assert_eq!(proxy.addr(), &"[::1]:1080".parse::<ServerAddr>().unwrap());
+        assert_eq!(proxy.auth, Some(("alice".to_owned(), "p@ss".to_owned())));
+        assert_eq!(proxy.to_string(), "socks5://alice@[::1]:1080");
+        assert_eq!(proxy.to_url(), "socks5://alice:p%40ss@[::1]:1080");
+
+        let proxy = "socks5://127.0.0.1".parse::<OutboundProxy>().unwrap();
+        assert_eq!(proxy.addr(), &"127.0.0.1:1080".parse::<ServerAddr>().unwrap());
+
+        let proxy = "http://proxy.corp.example".parse::<OutboundProxy>().unwrap();
+        assert_eq!(proxy.protocol(), OutboundProxyProtocol::Http);
+        assert_eq!(
+            proxy.addr(),
+            &ServerAddr::DomainName("proxy.corp.example".to_owned(), 80)
+        );
+
+        assert!("https://proxy.corp.example:3128".parse::<OutboundProxy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn http_connect() {
+        let mut proxy = "http://127.0.0.1:3128".parse::<OutboundProxy>().unwrap();
+        proxy.set_auth("alice", "secret");
+        let target = ServerAddr::DomainName("ss.example.com".to_owned(), 8388);
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let handshake = tokio::spawn(async move {
+            proxy.handshake(&mut client, &target).await?;
+            // Data after response headers belongs to the tunnel
+            client.read_u8().await
+        });
+
+        let mut request = vec![0u8; 1024];
+        let n = server.read(&mut request).await.unwrap();
+        assert_eq!(
+            &request[..n],
+            b"CONNECT ss.example.com:8388 HTTP/1.1\r\nHost: ss.example.com:8388\r\n\
+              Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n"
+        );
+        server
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n\x42")
+            .await
+            .unwrap();
+
+        assert_eq!(handshake.await.unwrap().unwrap(), 0x42);
+    }
+}
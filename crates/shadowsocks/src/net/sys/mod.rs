@@ -6,10 +6,10 @@ use std::{
 use cfg_if::cfg_if;
 use log::{debug, warn};
 use once_cell::sync::Lazy;
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
 use tokio::net::TcpSocket;
 
-use super::ConnectOpts;
+use super::{AddrFamily, ConnectOpts};
 
 cfg_if! {
     if #[cfg(unix)] {
@@ -56,9 +56,54 @@ fn set_common_sockopt_for_connect(addr: SocketAddr, socket: &TcpSocket, opts: &C
         socket.set_recv_buffer_size(buf_size)?;
     }
 
+    set_common_sockopt_dscp(SockRef::from(socket), AddrFamily::from(&addr), opts);
+
     Ok(())
 }
 
+/// Set `IP_TOS` or `IPV6_TCLASS` of an outbound socket of `af` if `dscp` is configured
+///
+/// Packets are still sent without it, so failures are only logged.
+fn set_common_sockopt_dscp(socket: SockRef<'_>, af: AddrFamily, opts: &ConnectOpts) {
+    let dscp = match opts.dscp {
+        Some(d) => d,
+        None => return,
+    };
+
+    if let Err(err) = set_socket_dscp(&socket, af, dscp) {
+        warn!("failed to set DSCP {} of outbound socket, error: {}", dscp, err);
+    }
+}
+
+fn set_socket_dscp(socket: &SockRef<'_>, af: AddrFamily, dscp: u8) -> io::Result<()> {
+    // DSCP is the upper 6 bits of the field, the lower 2 bits are ECN which is owned by the kernel
+    let tos = u32::from(dscp) << 2;
+
+    match af {
+        AddrFamily::Ipv4 => socket.set_tos(tos),
+        AddrFamily::Ipv6 => {
+            cfg_if! {
+                if #[cfg(any(
+                    target_os = "android",
+                    target_os = "dragonfly",
+                    target_os = "freebsd",
+                    target_os = "linux",
+                    target_os = "macos",
+                    target_os = "netbsd",
+                    target_os = "openbsd"
+                ))] {
+                    socket.set_tclass_v6(tos)?;
+                    // Dual-stack sockets send packets to IPv4-mapped addresses with `IP_TOS`, which may not be allowed
+                    let _ = socket.set_tos(tos);
+                    Ok(())
+                } else {
+                    Err(io::Error::new(ErrorKind::Other, "IPV6_TCLASS is not supported in this platform"))
+                }
+            }
+        }
+    }
+}
+
 #[cfg(all(not(windows), not(unix)))]
 #[inline]
 fn set_common_sockopt_after_connect_sys(_: &tokio::net::TcpStream, _: &ConnectOpts) -> io::Result<()> {
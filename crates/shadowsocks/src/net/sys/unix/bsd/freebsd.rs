@@ -10,7 +10,7 @@ use std::{
 
 use log::{debug, error, warn};
 use pin_project::pin_project;
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
     net::{TcpSocket, TcpStream as TokioTcpStream, UdpSocket},
@@ -19,7 +19,10 @@ use tokio_tfo::TfoStream;
 
 use crate::net::{
     AcceptOpts, AddrFamily, ConnectOpts,
-    sys::{set_common_sockopt_after_connect, set_common_sockopt_for_connect, socket_bind_dual_stack, io::Error},
+    sys::{
+        set_common_sockopt_after_connect, set_common_sockopt_dscp, set_common_sockopt_for_connect, socket_bind_dual_stack,
+        io::Error,
+    },
     udp::{BatchRecvMessage, BatchSendMessage},
 };
 
@@ -268,6 +271,8 @@ pub async fn bind_outbound_udp_socket(bind_addr: &SocketAddr, config: &ConnectOp
         }
     }
 
+    set_common_sockopt_dscp(SockRef::from(&socket), af, config);
+
     Ok(socket)
 }
 
@@ -14,7 +14,7 @@ use std::{
 
 use log::{debug, error, warn};
 use pin_project::pin_project;
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
 use tokio::{
     io::{AsyncRead, AsyncWrite, Interest, ReadBuf},
     net::{TcpSocket, TcpStream as TokioTcpStream, UdpSocket},
@@ -23,7 +23,10 @@ use tokio_tfo::TfoStream;
 
 use crate::net::{
     AcceptOpts, AddrFamily, ConnectOpts,
-    sys::{io::Error, set_common_sockopt_after_connect, set_common_sockopt_for_connect, socket_bind_dual_stack},
+    sys::{
+        io::Error, set_common_sockopt_after_connect, set_common_sockopt_dscp, set_common_sockopt_for_connect,
+        socket_bind_dual_stack,
+    },
     udp::{BatchRecvMessage, BatchSendMessage},
 };
 
@@ -396,6 +399,8 @@ pub async fn bind_outbound_udp_socket(bind_addr: &SocketAddr, config: &ConnectOp
         }
     }
 
+    set_common_sockopt_dscp(SockRef::from(&socket), af, config);
+
     // Set IP_BOUND_IF for BSD-like
     if let Some(ref iface) = config.bind_interface {
         set_ip_bound_if(&socket, bind_addr, iface)?;
@@ -13,16 +13,21 @@ use std::{
 use cfg_if::cfg_if;
 use log::{debug, error, warn};
 use pin_project::pin_project;
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
     net::{TcpSocket, TcpStream as TokioTcpStream, UdpSocket},
 };
 use tokio_tfo::TfoStream;
 
+#[cfg(feature = "socket-protect")]
+use crate::net::SocketProtect;
 use crate::net::{
     AcceptOpts, AddrFamily, ConnectOpts,
-    sys::{io::Error, set_common_sockopt_after_connect, set_common_sockopt_for_connect, socket_bind_dual_stack},
+    sys::{
+        io::Error, set_common_sockopt_after_connect, set_common_sockopt_dscp, set_common_sockopt_for_connect,
+        socket_bind_dual_stack,
+    },
     udp::{BatchRecvMessage, BatchSendMessage},
 };
 
@@ -55,20 +60,9 @@ impl TcpStream {
     async fn connect_with_socket(socket: TcpSocket, addr: SocketAddr, opts: &ConnectOpts) -> io::Result<TcpStream> {
         // Any traffic to localhost should not be protected
         // This is a workaround for VPNService
-        #[cfg(target_os = "android")]
+        #[cfg(any(target_os = "android", feature = "socket-protect"))]
         if !addr.ip().is_loopback() {
-            use std::{io::ErrorKind, time::Duration};
-            use tokio::time;
-
-            if let Some(ref path) = opts.vpn_protect_path {
-                // RPC calls to `VpnService.protect()`
-                // Timeout in 3 seconds like shadowsocks-libev
-                match time::timeout(Duration::from_secs(3), vpn_protect(path, socket.as_raw_fd())).await {
-                    Ok(Ok(..)) => {}
-                    Ok(Err(err)) => return Err(err),
-                    Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "protect() timeout")),
-                }
-            }
+            protect_outbound_socket(socket.as_raw_fd(), opts).await?;
         }
 
         // Set SO_MARK for mark-based routing on Linux (since 2.6.25)
@@ -329,23 +323,12 @@ pub async fn bind_outbound_udp_socket(bind_addr: &SocketAddr, config: &ConnectOp
         }
     }
 
+    set_common_sockopt_dscp(SockRef::from(&socket), af, config);
+
     // Any traffic except localhost should be protected
     // This is a workaround for VPNService
-    #[cfg(target_os = "android")]
-    {
-        use std::{io::ErrorKind, time::Duration};
-        use tokio::time;
-
-        if let Some(ref path) = config.vpn_protect_path {
-            // RPC calls to `VpnService.protect()`
-            // Timeout in 3 seconds like shadowsocks-libev
-            match time::timeout(Duration::from_secs(3), vpn_protect(path, socket.as_raw_fd())).await {
-                Ok(Ok(..)) => {}
-                Ok(Err(err)) => return Err(err),
-                Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "protect() timeout")),
-            }
-        }
-    }
+    #[cfg(any(target_os = "android", feature = "socket-protect"))]
+    protect_outbound_socket(socket.as_raw_fd(), config).await?;
 
     // Set SO_MARK for mark-based routing on Linux (since 2.6.25)
     // NOTE: This will require CAP_NET_ADMIN capability (root in most cases)
@@ -397,15 +380,38 @@ fn set_bindtodevice<S: AsRawFd>(socket: &S, iface: &str) -> io::Result<()> {
 }
 
 cfg_if! {
-    if #[cfg(target_os = "android")] {
-        use std::{
-            io::ErrorKind,
-            path::Path,
-        };
-        use tokio::io::AsyncReadExt;
+    if #[cfg(any(target_os = "android", feature = "socket-protect"))] {
+        use std::{path::Path, time::Duration};
+        use tokio::{io::AsyncReadExt, time};
 
         use super::uds::UnixStream;
 
+        /// Protect an outbound socket from VPN routing, by `vpn_protect_path` of Android and `socket_protect`
+        async fn protect_outbound_socket(fd: RawFd, opts: &ConnectOpts) -> io::Result<()> {
+            #[cfg(target_os = "android")]
+            if let Some(ref path) = opts.vpn_protect_path {
+                vpn_protect_with_timeout(path, fd).await?;
+            }
+
+            #[cfg(feature = "socket-protect")]
+            match opts.socket_protect {
+                Some(SocketProtect::Path(ref path)) => vpn_protect_with_timeout(path, fd).await?,
+                Some(SocketProtect::Callback(ref f)) => f(fd)?,
+                None => {}
+            }
+
+            Ok(())
+        }
+
+        async fn vpn_protect_with_timeout(protect_path: &Path, fd: RawFd) -> io::Result<()> {
+            // RPC calls to `VpnService.protect()`
+            // Timeout in 3 seconds like shadowsocks-libev
+            match time::timeout(Duration::from_secs(3), vpn_protect(protect_path, fd)).await {
+                Ok(r) => r,
+                Err(..) => Err(io::Error::new(ErrorKind::TimedOut, "protect() timeout")),
+            }
+        }
+
         /// This is a RPC for Android to `protect()` socket for connecting to remote servers
         ///
         /// https://developer.android.com/reference/android/net/VpnService#protect(java.net.Socket)
@@ -34,14 +34,21 @@ cfg_if! {
 pub mod uds;
 
 /// Create a `UdpSocket` binded to `addr`
-pub async fn create_inbound_udp_socket(addr: &SocketAddr, ipv6_only: bool) -> io::Result<UdpSocket> {
+pub async fn create_inbound_udp_socket(addr: &SocketAddr, accept_opts: &AcceptOpts) -> io::Result<UdpSocket> {
     let set_dual_stack = is_dual_stack_addr(addr);
 
-    let socket = if !set_dual_stack {
+    let socket = if !set_dual_stack && !accept_opts.reuse_port {
         UdpSocket::bind(addr).await?
     } else {
         let socket = Socket::new(Domain::for_address(*addr), Type::DGRAM, Some(Protocol::UDP))?;
-        socket_bind_dual_stack(&socket, addr, ipv6_only)?;
+        if accept_opts.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+        if set_dual_stack {
+            socket_bind_dual_stack(&socket, addr, accept_opts.ipv6_only)?;
+        } else {
+            socket.bind(&(*addr).into())?;
+        }
 
         // UdpSocket::from_std requires socket to be non-blocked
         socket.set_nonblocking(true)?;
@@ -18,7 +18,7 @@ use std::{
 use bytes::BytesMut;
 use log::{error, warn};
 use pin_project::pin_project;
-use socket2::{Domain, Protocol, SockAddr, Socket, TcpKeepalive, Type};
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, TcpKeepalive, Type};
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
     net::{TcpSocket, TcpStream as TokioTcpStream, UdpSocket},
@@ -45,7 +45,7 @@ const FALSE: BOOL = 0;
 
 use crate::net::{
     AcceptOpts, AddrFamily, ConnectOpts, is_dual_stack_addr,
-    sys::{set_common_sockopt_for_connect, socket_bind_dual_stack},
+    sys::{set_common_sockopt_dscp, set_common_sockopt_for_connect, socket_bind_dual_stack},
 };
 
 /// A `TcpStream` that supports TFO (TCP Fast Open)
@@ -437,14 +437,14 @@ pub fn set_disable_ip_fragmentation<S: AsRawSocket>(af: AddrFamily, socket: &S)
 /// Create a `UdpSocket` binded to `addr`
 ///
 /// It also disables `WSAECONNRESET` for UDP socket
-pub async fn create_inbound_udp_socket(addr: &SocketAddr, ipv6_only: bool) -> io::Result<UdpSocket> {
+pub async fn create_inbound_udp_socket(addr: &SocketAddr, accept_opts: &AcceptOpts) -> io::Result<UdpSocket> {
     let set_dual_stack = is_dual_stack_addr(addr);
 
     let socket = if !set_dual_stack {
         UdpSocket::bind(addr).await?
     } else {
         let socket = Socket::new(Domain::for_address(*addr), Type::DGRAM, Some(Protocol::UDP))?;
-        socket_bind_dual_stack(&socket, addr, ipv6_only)?;
+        socket_bind_dual_stack(&socket, addr, accept_opts.ipv6_only)?;
 
         // UdpSocket::from_std requires socket to be non-blocked
         socket.set_nonblocking(true)?;
@@ -513,6 +513,8 @@ pub async fn bind_outbound_udp_socket(bind_addr: &SocketAddr, opts: &ConnectOpts
             warn!("failed to disable IP fragmentation, error: {}", err);
         }
     }
+
+    set_common_sockopt_dscp(SockRef::from(&socket), af, opts);
     disable_connection_reset(&socket)?;
 
     Ok(socket)
@@ -0,0 +1,76 @@
+//! systemd socket activation
+//!
+//! Sockets passed by systemd (`LISTEN_PID` and `LISTEN_FDS`) are taken by inbound listeners bound to the same
+//! addresses, instead of binding new sockets.
+//!
+//! <https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html>
+
+use std::{
+    env,
+    net::{SocketAddr, TcpListener, UdpSocket},
+    os::unix::io::{FromRawFd, IntoRawFd, RawFd},
+    process,
+    sync::Mutex,
+};
+
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use socket2::{Socket, Type};
+
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+static ACTIVATED_SOCKETS: Lazy<Mutex<Vec<Socket>>> = Lazy::new(|| Mutex::new(listen_fds()));
+
+fn listen_fds() -> Vec<Socket> {
+    // Environments are also inherited by plugins, which are not the receiver of sockets
+    match env::var("LISTEN_PID").ok().and_then(|p| p.parse::<u32>().ok()) {
+        Some(pid) if pid == process::id() => {}
+        _ => return Vec::new(),
+    }
+    let count = match env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<RawFd>().ok()) {
+        Some(n) => n,
+        None => return Vec::new(),
+    };
+
+    let mut sockets = Vec::new();
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START.saturating_add(count) {
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+
+        // Plugins shouldn't inherit them
+        if let Err(err) = socket.set_cloexec(true) {
+            warn!(
+                "failed to set FD_CLOEXEC on systemd activated socket {}, error: {}",
+                fd, err
+            );
+        }
+
+        match socket.local_addr().ok().and_then(|a| a.as_socket()) {
+            Some(addr) => {
+                debug!("found systemd activated socket {} bound to {}", fd, addr);
+                sockets.push(socket);
+            }
+            None => {
+                debug!("ignored systemd activated socket {}, not an IP socket", fd);
+                let _ = socket.into_raw_fd();
+            }
+        }
+    }
+    sockets
+}
+
+fn take_activated_socket(addr: &SocketAddr, ty: Type) -> Option<Socket> {
+    let mut sockets = ACTIVATED_SOCKETS.lock().unwrap();
+    super::handover::take_bound_socket(&mut sockets, addr, ty)
+}
+
+/// Take the TCP listener passed by systemd, which is bound to `addr`
+///
+/// Unspecified addresses of IPv4 and IPv6 are treated as the same, because systemd listens on `[::]` for `ListenStream=<port>`.
+pub fn take_tcp_listener(addr: &SocketAddr) -> Option<TcpListener> {
+    take_activated_socket(addr, Type::STREAM).map(Into::into)
+}
+
+/// Take the UDP socket passed by systemd, which is bound to `addr`
+pub fn take_udp_socket(addr: &SocketAddr) -> Option<UdpSocket> {
+    take_activated_socket(addr, Type::DGRAM).map(Into::into)
+}
@@ -4,9 +4,11 @@
 use std::os::unix::io::{AsRawFd, RawFd};
 #[cfg(windows)]
 use std::os::windows::io::{AsRawSocket, RawSocket};
+#[cfg(unix)]
+use std::path::Path;
 use std::{
-    io,
-    net::SocketAddr,
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, SocketAddr},
     ops::{Deref, DerefMut},
     pin::Pin,
     task::{self, Poll},
@@ -19,6 +21,11 @@ use tokio::{
     net::{TcpListener as TokioTcpListener, TcpStream as TokioTcpStream},
 };
 
+#[cfg(unix)]
+use tokio::net::UnixStream as TokioUnixStream;
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use super::UringStream;
 use crate::{ServerAddr, context::Context, relay::socks5::Address};
 
 use super::{
@@ -29,15 +36,58 @@ use super::{
     },
 };
 
+// Transports of outbound streams, the system TCP stream is used unless configured otherwise by `ConnectOpts` or
+// plugins of servers
+#[pin_project(project = OutboundStreamProj)]
+enum OutboundStream {
+    Tcp(#[pin] SysTcpStream),
+    // Connected by `ConnectOpts::connector`
+    Custom(#[pin] TokioTcpStream),
+    // Plugins listening on Unix domain sockets
+    #[cfg(unix)]
+    Unix(#[pin] TokioUnixStream),
+    // Read and written by `ConnectOpts::uring`
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring(UringStream<SysTcpStream>),
+}
+
+impl OutboundStream {
+    async fn connect(addr: SocketAddr, opts: &ConnectOpts) -> io::Result<OutboundStream> {
+        if let Some(ref connector) = opts.connector {
+            if !addr.ip().is_loopback() {
+                return connector.connect_tcp(addr, opts).await.map(OutboundStream::Custom);
+            }
+        }
+        let stream = SysTcpStream::connect(addr, opts).await?;
+
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Some(ref driver) = opts.uring {
+            // TFO streams send data with SYN in the first write
+            if !opts.tcp.fastopen {
+                return UringStream::new(stream, driver.clone()).map(OutboundStream::Uring);
+            }
+        }
+
+        Ok(OutboundStream::Tcp(stream))
+    }
+}
+
 /// TcpStream for outbound connections
 #[pin_project]
-pub struct TcpStream(#[pin] SysTcpStream);
+pub struct TcpStream(#[pin] OutboundStream);
 
 impl TcpStream {
     /// Connects to address
     pub async fn connect_with_opts(addr: &SocketAddr, opts: &ConnectOpts) -> io::Result<TcpStream> {
-        // tcp_stream_connect(addr, opts).await.map(TcpStream)
-        SysTcpStream::connect(*addr, opts).await.map(TcpStream)
+        OutboundStream::connect(*addr, opts).await.map(TcpStream)
+    }
+
+    /// Connects to a plugin listening on Unix domain socket `path`
+    #[cfg(unix)]
+    pub async fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<TcpStream> {
+        TokioUnixStream::connect(path)
+            .await
+            .map(|s| TcpStream(OutboundStream::Unix(s)))
     }
 
     /// Connects shadowsocks server
@@ -46,11 +96,24 @@ impl TcpStream {
         addr: &ServerAddr,
         opts: &ConnectOpts,
     ) -> io::Result<TcpStream> {
+        if let Some(ref proxy) = opts.outbound_proxy {
+            // Plugins listening on loopback addresses are connected directly
+            if !matches!(*addr, ServerAddr::SocketAddr(ref sa) if sa.ip().is_loopback()) {
+                let mut stream = TcpStream::connect_direct(context, proxy.addr(), opts).await?;
+                proxy.handshake(&mut stream, addr).await?;
+                return Ok(stream);
+            }
+        }
+
+        TcpStream::connect_direct(context, addr, opts).await
+    }
+
+    async fn connect_direct(context: &Context, addr: &ServerAddr, opts: &ConnectOpts) -> io::Result<TcpStream> {
         let stream = match *addr {
-            ServerAddr::SocketAddr(ref addr) => SysTcpStream::connect(*addr, opts).await?,
+            ServerAddr::SocketAddr(ref addr) => OutboundStream::connect(*addr, opts).await?,
             ServerAddr::DomainName(ref domain, port) => {
-                lookup_then_connect!(context, domain, port, |addr| {
-                    SysTcpStream::connect(addr, opts).await
+                lookup_then_connect!(SERVER @ context, domain, port, |addr| {
+                    OutboundStream::connect(addr, opts).await
                 })?
                 .1
             }
@@ -66,10 +129,37 @@ impl TcpStream {
         opts: &ConnectOpts,
     ) -> io::Result<TcpStream> {
         let stream = match *addr {
-            Address::SocketAddress(ref addr) => SysTcpStream::connect(*addr, opts).await?,
+            Address::SocketAddress(ref addr) => OutboundStream::connect(*addr, opts).await?,
             Address::DomainNameAddress(ref domain, port) => {
                 lookup_then_connect!(context, domain, port, |addr| {
-                    SysTcpStream::connect(addr, opts).await
+                    OutboundStream::connect(addr, opts).await
+                })?
+                .1
+            }
+        };
+
+        Ok(TcpStream(stream))
+    }
+
+    /// Connects proxy remote target, with addresses of its domain name `resolved` before, like for checking them
+    pub async fn connect_remote_resolved_with_opts(
+        context: &Context,
+        addr: &Address,
+        resolved: Vec<SocketAddr>,
+        opts: &ConnectOpts,
+    ) -> io::Result<TcpStream> {
+        let stream = match *addr {
+            Address::SocketAddress(ref addr) => OutboundStream::connect(*addr, opts).await?,
+            Address::DomainNameAddress(ref domain, port) => {
+                if resolved.is_empty() {
+                    return Err(io::Error::new(
+                        ErrorKind::AddrNotAvailable,
+                        format!("{domain}:{port} resolved empty address"),
+                    ));
+                }
+
+                lookup_then_connect!(ADDRS @ context, resolved, domain, port, |addr| {
+                    OutboundStream::connect(addr, opts).await
                 })?
                 .1
             }
@@ -79,43 +169,106 @@ impl TcpStream {
     }
 
     /// Returns the local address that this stream is bound to.
+    ///
+    /// Unix domain socket connections don't have socket addresses, the unspecified address is returned.
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.0.local_addr()
+        match self.0 {
+            OutboundStream::Tcp(ref s) => s.local_addr(),
+            OutboundStream::Custom(ref s) => s.local_addr(),
+            #[cfg(unix)]
+            OutboundStream::Unix(..) => Ok(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            OutboundStream::Uring(ref s) => s.get_ref().local_addr(),
+        }
     }
 
     /// Returns the remote address that this stream is connected to.
     pub fn peer_addr(&self) -> io::Result<SocketAddr> {
-        self.0.peer_addr()
+        match self.0 {
+            OutboundStream::Tcp(ref s) => s.peer_addr(),
+            OutboundStream::Custom(ref s) => s.peer_addr(),
+            #[cfg(unix)]
+            OutboundStream::Unix(..) => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "unix domain socket doesn't have peer socket address",
+            )),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            OutboundStream::Uring(ref s) => s.get_ref().peer_addr(),
+        }
     }
 
     /// Gets the value of the `TCP_NODELAY` option on this socket.
     pub fn nodelay(&self) -> io::Result<bool> {
-        self.0.nodelay()
+        match self.0 {
+            OutboundStream::Tcp(ref s) => s.nodelay(),
+            OutboundStream::Custom(ref s) => s.nodelay(),
+            #[cfg(unix)]
+            OutboundStream::Unix(..) => Ok(true),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            OutboundStream::Uring(ref s) => s.get_ref().nodelay(),
+        }
     }
 
     /// Sets the value of the `TCP_NODELAY` option on this socket.
+    ///
+    /// It is ignored by Unix domain socket connections.
     pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
-        self.0.set_nodelay(nodelay)
+        match self.0 {
+            OutboundStream::Tcp(ref s) => s.set_nodelay(nodelay),
+            OutboundStream::Custom(ref s) => s.set_nodelay(nodelay),
+            #[cfg(unix)]
+            OutboundStream::Unix(..) => Ok(()),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            OutboundStream::Uring(ref s) => s.get_ref().set_nodelay(nodelay),
+        }
     }
 }
 
 impl AsyncRead for TcpStream {
     fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
-        self.project().0.poll_read(cx, buf)
+        match self.project().0.project() {
+            OutboundStreamProj::Tcp(s) => s.poll_read(cx, buf),
+            OutboundStreamProj::Custom(s) => s.poll_read(cx, buf),
+            #[cfg(unix)]
+            OutboundStreamProj::Unix(s) => s.poll_read(cx, buf),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            OutboundStreamProj::Uring(s) => Pin::new(s).poll_read(cx, buf),
+        }
     }
 }
 
 impl AsyncWrite for TcpStream {
     fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
-        self.project().0.poll_write(cx, buf)
+        match self.project().0.project() {
+            OutboundStreamProj::Tcp(s) => s.poll_write(cx, buf),
+            OutboundStreamProj::Custom(s) => s.poll_write(cx, buf),
+            #[cfg(unix)]
+            OutboundStreamProj::Unix(s) => s.poll_write(cx, buf),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            OutboundStreamProj::Uring(s) => Pin::new(s).poll_write(cx, buf),
+        }
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
-        self.project().0.poll_flush(cx)
+        match self.project().0.project() {
+            OutboundStreamProj::Tcp(s) => s.poll_flush(cx),
+            OutboundStreamProj::Custom(s) => s.poll_flush(cx),
+            #[cfg(unix)]
+            OutboundStreamProj::Unix(s) => s.poll_flush(cx),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            OutboundStreamProj::Uring(s) => Pin::new(s).poll_flush(cx),
+        }
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
-        self.project().0.poll_shutdown(cx)
+        match self.project().0.project() {
+            OutboundStreamProj::Tcp(s) => s.poll_shutdown(cx),
+            OutboundStreamProj::Custom(s) => s.poll_shutdown(cx),
+            #[cfg(unix)]
+            OutboundStreamProj::Unix(s) => s.poll_shutdown(cx),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            OutboundStreamProj::Uring(s) => Pin::new(s).poll_shutdown(cx),
+        }
     }
 }
 
@@ -128,7 +281,30 @@ pub struct TcpListener {
 
 impl TcpListener {
     /// Creates a new TcpListener, which will be bound to the specified address.
+    ///
+    /// On Linux, the listener passed by systemd socket activation is taken if it is bound to `addr`. On *nix systems,
+    /// the listener handed over by the old process is also taken.
     pub async fn bind_with_opts(addr: &SocketAddr, accept_opts: AcceptOpts) -> io::Result<TcpListener> {
+        let listener = TcpListener::bind_inbound(addr, accept_opts).await?;
+        // Inbound listeners could be handed over to a new process
+        #[cfg(unix)]
+        super::handover::register_listener(listener.inner.as_raw_fd());
+        Ok(listener)
+    }
+
+    async fn bind_inbound(addr: &SocketAddr, accept_opts: AcceptOpts) -> io::Result<TcpListener> {
+        #[cfg(unix)]
+        if let Some(listener) = super::handover::take_tcp_listener(addr) {
+            listener.set_nonblocking(true)?;
+            return TcpListener::from_listener(TokioTcpListener::from_std(listener)?, accept_opts);
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(listener) = super::systemd::take_tcp_listener(addr) {
+            listener.set_nonblocking(true)?;
+            return TcpListener::from_listener(TokioTcpListener::from_std(listener)?, accept_opts);
+        }
+
         let socket = create_inbound_tcp_socket(addr, &accept_opts).await?;
 
         if let Some(size) = accept_opts.tcp.send_buffer_size {
@@ -149,6 +325,11 @@ impl TcpListener {
         #[cfg(not(windows))]
         socket.set_reuseaddr(true)?;
 
+        #[cfg(unix)]
+        if accept_opts.reuse_port {
+            socket.set_reuseport(true)?;
+        }
+
         let set_dual_stack = is_dual_stack_addr(addr);
 
         if set_dual_stack {
@@ -224,13 +405,22 @@ impl From<TcpListener> for TokioTcpListener {
 #[cfg(unix)]
 impl AsRawFd for TcpStream {
     fn as_raw_fd(&self) -> RawFd {
-        self.0.as_raw_fd()
+        match self.0 {
+            OutboundStream::Tcp(ref s) => s.as_raw_fd(),
+            OutboundStream::Custom(ref s) => s.as_raw_fd(),
+            OutboundStream::Unix(ref s) => s.as_raw_fd(),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            OutboundStream::Uring(ref s) => s.get_ref().as_raw_fd(),
+        }
     }
 }
 
 #[cfg(windows)]
 impl AsRawSocket for TcpStream {
     fn as_raw_socket(&self) -> RawSocket {
-        self.0.as_raw_socket()
+        match self.0 {
+            OutboundStream::Tcp(ref s) => s.as_raw_socket(),
+            OutboundStream::Custom(ref s) => s.as_raw_socket(),
+        }
     }
 }
@@ -0,0 +1,23 @@
+//! Certificates and private keys of TLS servers, loaded from PEM files
+
+use std::{
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+
+/// Load the certificate chain in the PEM file `path`
+pub fn load_certs<P: AsRef<Path>>(path: P) -> io::Result<Vec<CertificateDer<'static>>> {
+    let path = path.as_ref();
+    CertificateDer::pem_file_iter(path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, format!("{}: {err}", path.display())))
+}
+
+/// Load the private key in the PEM file `path`
+pub fn load_private_key<P: AsRef<Path>>(path: P) -> io::Result<PrivateKeyDer<'static>> {
+    let path = path.as_ref();
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, format!("{}: {err}", path.display())))
+}
@@ -8,6 +8,8 @@
     target_os = "freebsd"
 ))]
 use std::io::{ErrorKind, IoSlice, IoSliceMut};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
 use std::{
     io,
     net::SocketAddr,
@@ -76,6 +78,13 @@ pub struct BatchRecvMessage<'a> {
     pub data_len: usize,
 }
 
+async fn create_outbound_socket(af: AddrFamily, opts: &ConnectOpts) -> io::Result<tokio::net::UdpSocket> {
+    match opts.connector {
+        Some(ref connector) => connector.create_udp_socket(af, opts).await,
+        None => create_outbound_udp_socket(af, opts).await,
+    }
+}
+
 #[inline]
 fn make_mtu_error(packet_size: usize, mtu: usize) -> io::Error {
     io::Error::new(
@@ -100,13 +109,13 @@ impl UdpSocket {
     ) -> io::Result<UdpSocket> {
         let socket = match *addr {
             ServerAddr::SocketAddr(ref remote_addr) => {
-                let socket = create_outbound_udp_socket(From::from(remote_addr), opts).await?;
+                let socket = create_outbound_socket(From::from(remote_addr), opts).await?;
                 socket.connect(remote_addr).await?;
                 socket
             }
             ServerAddr::DomainName(ref dname, port) => {
-                lookup_then!(context, dname, port, |remote_addr| {
-                    let s = create_outbound_udp_socket(From::from(&remote_addr), opts).await?;
+                lookup_then!(SERVER @ context, dname, port, |remote_addr| {
+                    let s = create_outbound_socket(From::from(&remote_addr), opts).await?;
                     s.connect(remote_addr).await.map(|_| s)
                 })?
                 .1
@@ -127,13 +136,13 @@ impl UdpSocket {
     ) -> io::Result<UdpSocket> {
         let socket = match *addr {
             Address::SocketAddress(ref remote_addr) => {
-                let socket = create_outbound_udp_socket(From::from(remote_addr), opts).await?;
+                let socket = create_outbound_socket(From::from(remote_addr), opts).await?;
                 socket.connect(remote_addr).await?;
                 socket
             }
             Address::DomainNameAddress(ref dname, port) => {
                 lookup_then!(context, dname, port, |remote_addr| {
-                    let s = create_outbound_udp_socket(From::from(&remote_addr), opts).await?;
+                    let s = create_outbound_socket(From::from(&remote_addr), opts).await?;
                     s.connect(remote_addr).await.map(|_| s)
                 })?
                 .1
@@ -148,7 +157,7 @@ impl UdpSocket {
 
     /// Connects to shadowsocks server
     pub async fn connect_with_opts(addr: &SocketAddr, opts: &ConnectOpts) -> io::Result<UdpSocket> {
-        let socket = create_outbound_udp_socket(From::from(addr), opts).await?;
+        let socket = create_outbound_socket(From::from(addr), opts).await?;
         socket.connect(addr).await?;
         Ok(UdpSocket {
             socket,
@@ -158,12 +167,10 @@ impl UdpSocket {
 
     /// Binds to a specific address with opts
     pub async fn connect_any_with_opts<AF: Into<AddrFamily>>(af: AF, opts: &ConnectOpts) -> io::Result<UdpSocket> {
-        create_outbound_udp_socket(af.into(), opts)
-            .await
-            .map(|socket| UdpSocket {
-                socket,
-                mtu: opts.udp.mtu,
-            })
+        create_outbound_socket(af.into(), opts).await.map(|socket| UdpSocket {
+            socket,
+            mtu: opts.udp.mtu,
+        })
     }
 
     /// Binds to a specific address as an outbound socket
@@ -187,8 +194,37 @@ impl UdpSocket {
     }
 
     /// Binds to a specific address (inbound)
+    ///
+    /// On Linux, the socket passed by systemd socket activation is taken if it is bound to `addr`. On *nix systems,
+    /// the socket handed over by the old process is also taken.
     pub async fn listen_with_opts(addr: &SocketAddr, opts: AcceptOpts) -> io::Result<UdpSocket> {
-        let socket = create_inbound_udp_socket(addr, opts.ipv6_only).await?;
+        let socket = UdpSocket::listen_inbound(addr, opts).await?;
+        // Inbound sockets could be handed over to a new process
+        #[cfg(unix)]
+        super::handover::register_listener(socket.socket.as_raw_fd());
+        Ok(socket)
+    }
+
+    async fn listen_inbound(addr: &SocketAddr, opts: AcceptOpts) -> io::Result<UdpSocket> {
+        #[cfg(unix)]
+        if let Some(socket) = super::handover::take_udp_socket(addr) {
+            socket.set_nonblocking(true)?;
+            return Ok(UdpSocket {
+                socket: tokio::net::UdpSocket::from_std(socket)?,
+                mtu: opts.udp.mtu,
+            });
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(socket) = super::systemd::take_udp_socket(addr) {
+            socket.set_nonblocking(true)?;
+            return Ok(UdpSocket {
+                socket: tokio::net::UdpSocket::from_std(socket)?,
+                mtu: opts.udp.mtu,
+            });
+        }
+
+        let socket = create_inbound_udp_socket(addr, &opts).await?;
         Ok(UdpSocket {
             socket,
             mtu: opts.udp.mtu,
@@ -0,0 +1,430 @@
+//! io_uring backend of TCP streams (Linux)
+//!
+//! Relaying at multi-gigabit rates is bound by `recv` / `send` syscalls, one for every read and write of every
+//! connection. With an [`UringDriver`] set in `ConnectOpts`, outbound TCP streams submit their reads and writes to
+//! a shared io_uring instead, and submissions of all connections are flushed to the kernel together by one task,
+//! with one `io_uring_enter` call.
+//!
+//! [`UringDriver::new`] fails on kernels without io_uring (or without `IORING_FEAT_FAST_POLL`, Linux 5.7), or if it
+//! is disabled by seccomp, and streams are relayed with epoll as usual.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{self, Poll, Waker},
+};
+
+use futures::ready;
+use io_uring::{IoUring, opcode, squeue, types};
+use log::{debug, error};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf, unix::AsyncFd},
+    sync::Notify,
+    task::JoinHandle,
+};
+
+/// Default number of submission queue entries
+const DEFAULT_RING_ENTRIES: u32 = 4096;
+/// Size of buffers of every read
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+/// Maximum size of every write
+const MAX_WRITE_SIZE: usize = 64 * 1024;
+/// `user_data` of cancellations, whose completions are ignored
+const CANCEL_USER_DATA: u64 = u64::MAX;
+
+struct Operation {
+    // Buffer lent to the kernel until the operation completes
+    buf: Vec<u8>,
+    // Socket of the entry, kept open until the operation completes, so its number couldn't be reused by another
+    // connection before the entry is submitted
+    _fd: Arc<OwnedFd>,
+    result: Option<i32>,
+    waker: Option<Waker>,
+    // Owner of the operation has gone, dropped on completion
+    orphaned: bool,
+}
+
+struct RingState {
+    ring: IoUring,
+    operations: HashMap<u64, Operation>,
+    next_id: u64,
+    unsubmitted: bool,
+}
+
+impl RingState {
+    fn push(&mut self, entry: &squeue::Entry) -> io::Result<()> {
+        // SAFETY: Buffers of entries are owned by `operations` until they complete
+        if unsafe { self.ring.submission().push(entry) }.is_ok() {
+            self.unsubmitted = true;
+            return Ok(());
+        }
+
+        // Submission queue is full
+        self.ring.submit()?;
+        match unsafe { self.ring.submission().push(entry) } {
+            Ok(()) => {
+                self.unsubmitted = true;
+                Ok(())
+            }
+            Err(..) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "io_uring submission queue is full",
+            )),
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<RingState>,
+    eventfd: AsyncFd<OwnedFd>,
+    notify: Notify,
+}
+
+impl Shared {
+    /// Submit queued entries, and complete operations of completed entries
+    fn submit_and_complete(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.unsubmitted {
+            state.unsubmitted = false;
+            if let Err(err) = state.ring.submit() {
+                error!("io_uring submit failed, error: {}", err);
+            }
+        }
+
+        let RingState {
+            ref mut ring,
+            ref mut operations,
+            ..
+        } = *state;
+        for cqe in ring.completion() {
+            let id = cqe.user_data();
+            if id == CANCEL_USER_DATA {
+                continue;
+            }
+
+            let orphaned = match operations.get_mut(&id) {
+                Some(op) => {
+                    op.result = Some(cqe.result());
+                    if let Some(waker) = op.waker.take() {
+                        waker.wake();
+                    }
+                    op.orphaned
+                }
+                None => false,
+            };
+            if orphaned {
+                operations.remove(&id);
+            }
+        }
+    }
+
+    async fn run(self: Arc<Shared>) {
+        loop {
+            self.submit_and_complete();
+
+            tokio::select! {
+                r = self.eventfd.readable() => {
+                    let mut guard = match r {
+                        Ok(g) => g,
+                        Err(err) => {
+                            error!("io_uring eventfd failed, error: {}", err);
+                            return;
+                        }
+                    };
+
+                    let mut count = [0u8; 8];
+                    // SAFETY: Reading 8 bytes into `count`
+                    let n = unsafe { libc::read(self.eventfd.as_raw_fd(), count.as_mut_ptr() as *mut _, count.len()) };
+                    if n < 0 {
+                        guard.clear_ready();
+                    }
+                }
+                _ = self.notify.notified() => {}
+            }
+        }
+    }
+}
+
+/// Shared io_uring instance, driven by a task of the current tokio runtime
+pub struct UringDriver {
+    shared: Arc<Shared>,
+    task: JoinHandle<()>,
+}
+
+impl fmt::Debug for UringDriver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UringDriver").finish()
+    }
+}
+
+impl Drop for UringDriver {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl UringDriver {
+    /// Create an io_uring instance, and spawn its driving task in the current tokio runtime
+    ///
+    /// Fails if io_uring is not supported by the kernel, or not allowed.
+    pub fn new() -> io::Result<Arc<UringDriver>> {
+        UringDriver::with_entries(DEFAULT_RING_ENTRIES)
+    }
+
+    /// Create with `entries` of the submission queue
+    pub fn with_entries(entries: u32) -> io::Result<Arc<UringDriver>> {
+        let ring = IoUring::new(entries)?;
+        if !ring.params().is_feature_fast_poll() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "io_uring without IORING_FEAT_FAST_POLL is not supported",
+            ));
+        }
+
+        // SAFETY: Creating a new file descriptor
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is owned from now on
+        let eventfd = unsafe { OwnedFd::from_raw_fd(fd) };
+        ring.submitter().register_eventfd(eventfd.as_raw_fd())?;
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(RingState {
+                ring,
+                operations: HashMap::new(),
+                next_id: 0,
+                unsubmitted: false,
+            }),
+            eventfd: AsyncFd::new(eventfd)?,
+            notify: Notify::new(),
+        });
+        let task = tokio::spawn(shared.clone().run());
+
+        debug!("io_uring driver started with {} entries", entries);
+        Ok(Arc::new(UringDriver { shared, task }))
+    }
+
+    fn submit<F>(&self, fd: &Arc<OwnedFd>, buf: Vec<u8>, make_entry: F) -> io::Result<u64>
+    where
+        F: FnOnce(types::Fd, *mut u8, u32) -> squeue::Entry,
+    {
+        let mut state = self.shared.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id = state.next_id.wrapping_add(1) % CANCEL_USER_DATA;
+
+        let mut op = Operation {
+            buf,
+            _fd: fd.clone(),
+            result: None,
+            waker: None,
+            orphaned: false,
+        };
+        let entry = make_entry(types::Fd(fd.as_raw_fd()), op.buf.as_mut_ptr(), op.buf.len() as u32).user_data(id);
+        state.operations.insert(id, op);
+
+        if let Err(err) = state.push(&entry) {
+            state.operations.remove(&id);
+            return Err(err);
+        }
+        drop(state);
+
+        self.shared.notify.notify_one();
+        Ok(id)
+    }
+
+    fn submit_recv(&self, fd: &Arc<OwnedFd>, len: usize) -> io::Result<u64> {
+        self.submit(fd, vec![0u8; len], |fd, ptr, len| {
+            opcode::Recv::new(fd, ptr, len).build()
+        })
+    }
+
+    fn submit_send(&self, fd: &Arc<OwnedFd>, data: &[u8]) -> io::Result<u64> {
+        self.submit(fd, data.to_vec(), |fd, ptr, len| {
+            opcode::Send::new(fd, ptr, len).build()
+        })
+    }
+
+    /// Poll the operation `id`, returns its result and buffer once completed
+    fn poll_complete(&self, id: u64, cx: &mut task::Context<'_>) -> Poll<(i32, Vec<u8>)> {
+        let mut state = self.shared.state.lock().unwrap();
+        let op = state.operations.get_mut(&id).expect("io_uring operation");
+        match op.result {
+            Some(result) => {
+                let op = state.operations.remove(&id).expect("io_uring operation");
+                Poll::Ready((result, op.buf))
+            }
+            None => {
+                op.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Cancel the operation `id`, its buffer is kept until it completes
+    fn cancel(&self, id: u64) {
+        let mut state = self.shared.state.lock().unwrap();
+        let completed = match state.operations.get_mut(&id) {
+            Some(op) if op.result.is_some() => true,
+            Some(op) => {
+                op.orphaned = true;
+                false
+            }
+            None => return,
+        };
+
+        if completed {
+            state.operations.remove(&id);
+        } else {
+            let entry = opcode::AsyncCancel::new(id).build().user_data(CANCEL_USER_DATA);
+            let _ = state.push(&entry);
+            drop(state);
+            self.shared.notify.notify_one();
+        }
+    }
+}
+
+fn result_to_io(result: i32) -> io::Result<usize> {
+    if result < 0 {
+        Err(io::Error::from_raw_os_error(-result))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// Stream reading and writing by io_uring
+pub struct UringStream<S: AsRawFd> {
+    stream: S,
+    // Duplicate of `stream`'s fd, shared with in-flight operations
+    ring_fd: Arc<OwnedFd>,
+    driver: Arc<UringDriver>,
+    read_op: Option<u64>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    write_op: Option<u64>,
+}
+
+impl<S: AsRawFd> fmt::Debug for UringStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UringStream")
+            .field("fd", &self.stream.as_raw_fd())
+            .finish()
+    }
+}
+
+impl<S: AsRawFd> UringStream<S> {
+    /// Relay `stream` by `driver`
+    pub fn new(stream: S, driver: Arc<UringDriver>) -> io::Result<UringStream<S>> {
+        // SAFETY: Duplicating an open fd into a new one
+        let fd = unsafe { libc::fcntl(stream.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is owned from now on
+        let ring_fd = Arc::new(unsafe { OwnedFd::from_raw_fd(fd as RawFd) });
+
+        Ok(UringStream {
+            stream,
+            ring_fd,
+            driver,
+            read_op: None,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            write_op: None,
+        })
+    }
+
+    /// Get reference to the underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S: AsRawFd> Drop for UringStream<S> {
+    fn drop(&mut self) {
+        // In-flight operations keep their own references of `ring_fd` until they complete
+        if let Some(id) = self.read_op.take() {
+            self.driver.cancel(id);
+        }
+        if let Some(id) = self.write_op.take() {
+            self.driver.cancel(id);
+        }
+    }
+}
+
+impl<S> AsyncRead for UringStream<S>
+where
+    S: AsRawFd + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.read_pos >= this.read_buf.len() {
+            let id = match this.read_op {
+                Some(id) => id,
+                None => {
+                    let id = this.driver.submit_recv(&this.ring_fd, READ_BUFFER_SIZE)?;
+                    this.read_op = Some(id);
+                    id
+                }
+            };
+
+            let (result, mut data) = ready!(this.driver.poll_complete(id, cx));
+            this.read_op = None;
+
+            let n = result_to_io(result)?;
+            data.truncate(n);
+            this.read_buf = data;
+            this.read_pos = 0;
+        }
+
+        let n = (this.read_buf.len() - this.read_pos).min(buf.remaining());
+        buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+        this.read_pos += n;
+        Ok(()).into()
+    }
+}
+
+impl<S> AsyncWrite for UringStream<S>
+where
+    S: AsRawFd + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Ok(0).into();
+        }
+
+        // Data of the previous call is being sent, callers are required to call again with the same `buf`
+        let id = match this.write_op {
+            Some(id) => id,
+            None => {
+                let data = &buf[..buf.len().min(MAX_WRITE_SIZE)];
+                let id = this.driver.submit_send(&this.ring_fd, data)?;
+                this.write_op = Some(id);
+                id
+            }
+        };
+
+        let (result, ..) = ready!(this.driver.poll_complete(id, cx));
+        this.write_op = None;
+        result_to_io(result).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Ok(()).into()
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(id) = this.write_op {
+            let _ = ready!(this.driver.poll_complete(id, cx));
+            this.write_op = None;
+        }
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
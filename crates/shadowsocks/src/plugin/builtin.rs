@@ -0,0 +1,301 @@
+//! Builtin plugins, which run in process instead of plugin subprocesses
+
+use std::{
+    fmt, io,
+    pin::Pin,
+    task::{self, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::config::ServerConfig;
+
+#[cfg(feature = "plugin-grpc")]
+use super::grpc::{GrpcConfig, GrpcConfigError, GrpcStream};
+#[cfg(feature = "plugin-shadow-tls")]
+use super::shadow_tls::{ShadowTlsConfig, ShadowTlsConfigError, ShadowTlsStream};
+#[cfg(feature = "plugin-websocket")]
+use super::websocket::{WebSocketConfig, WebSocketConfigError, WebSocketStream};
+use super::{
+    PluginConfig,
+    obfs::{BUILTIN_OBFS_PLUGIN, ObfsConfig, ObfsConfigError, ObfsStream},
+    transport::{self, RegisteredTransport, StreamWrapper},
+};
+
+/// Name of the builtin WebSocket plugin, compatible with v2ray-plugin
+pub const BUILTIN_WEBSOCKET_PLUGIN: &str = "builtin-websocket";
+/// Name of the builtin TLS camouflage plugin, in the style of ShadowTLS
+pub const BUILTIN_SHADOW_TLS_PLUGIN: &str = "builtin-shadow-tls";
+/// Name of the builtin gRPC plugin, compatible with the gun transport of v2ray
+pub const BUILTIN_GRPC_PLUGIN: &str = "builtin-grpc";
+
+/// Errors of parsing builtin plugins
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum BuiltinPluginError {
+    #[error("{0}")]
+    Obfs(#[from] ObfsConfigError),
+    #[cfg(feature = "plugin-websocket")]
+    #[error("{0}")]
+    WebSocket(#[from] WebSocketConfigError),
+    #[cfg(feature = "plugin-shadow-tls")]
+    #[error("{0}")]
+    ShadowTls(#[from] ShadowTlsConfigError),
+    #[cfg(feature = "plugin-grpc")]
+    #[error("{0}")]
+    Grpc(#[from] GrpcConfigError),
+    /// Builtin plugin disabled at compile time
+    #[error("builtin plugin \"{0}\" is not supported, consider enable it by feature \"{1}\"")]
+    Unsupported(&'static str, &'static str),
+    /// Registered transport failed to create
+    #[error("transport \"{0}\" failed, {1}")]
+    Transport(String, String),
+}
+
+/// Builtin plugin with parsed options
+#[derive(Debug, Clone)]
+pub enum BuiltinPlugin {
+    /// simple-obfs
+    Obfs(ObfsConfig),
+    /// v2ray-plugin's WebSocket transport
+    #[cfg(feature = "plugin-websocket")]
+    WebSocket(WebSocketConfig),
+    /// TLS camouflage, in the style of ShadowTLS
+    #[cfg(feature = "plugin-shadow-tls")]
+    ShadowTls(ShadowTlsConfig),
+    /// gRPC transport of v2ray, named gun
+    #[cfg(feature = "plugin-grpc")]
+    Grpc(GrpcConfig),
+    /// Transport registered by `register_transport`
+    Registered(RegisteredTransport),
+}
+
+impl BuiltinPlugin {
+    /// Parse the builtin plugin of `plugin`, `None` if it is a plugin subprocess
+    pub fn from_plugin_config(plugin: &PluginConfig) -> Result<Option<BuiltinPlugin>, BuiltinPluginError> {
+        let opts = plugin.plugin_opts.as_deref();
+        match plugin.plugin.as_str() {
+            BUILTIN_OBFS_PLUGIN => Ok(Some(BuiltinPlugin::Obfs(ObfsConfig::from_plugin_opts(opts)?))),
+            #[cfg(feature = "plugin-websocket")]
+            BUILTIN_WEBSOCKET_PLUGIN => Ok(Some(BuiltinPlugin::WebSocket(WebSocketConfig::from_plugin_opts(opts)?))),
+            #[cfg(not(feature = "plugin-websocket"))]
+            BUILTIN_WEBSOCKET_PLUGIN => Err(BuiltinPluginError::Unsupported(
+                BUILTIN_WEBSOCKET_PLUGIN,
+                "plugin-websocket",
+            )),
+            #[cfg(feature = "plugin-shadow-tls")]
+            BUILTIN_SHADOW_TLS_PLUGIN => Ok(Some(BuiltinPlugin::ShadowTls(ShadowTlsConfig::from_plugin_opts(opts)?))),
+            #[cfg(not(feature = "plugin-shadow-tls"))]
+            BUILTIN_SHADOW_TLS_PLUGIN => Err(BuiltinPluginError::Unsupported(
+                BUILTIN_SHADOW_TLS_PLUGIN,
+                "plugin-shadow-tls",
+            )),
+            #[cfg(feature = "plugin-grpc")]
+            BUILTIN_GRPC_PLUGIN => Ok(Some(BuiltinPlugin::Grpc(GrpcConfig::from_plugin_opts(opts)?))),
+            #[cfg(not(feature = "plugin-grpc"))]
+            BUILTIN_GRPC_PLUGIN => Err(BuiltinPluginError::Unsupported(BUILTIN_GRPC_PLUGIN, "plugin-grpc")),
+            name => match transport::make_transport(name, opts) {
+                None => Ok(None),
+                Some(Ok(t)) => Ok(Some(BuiltinPlugin::Registered(RegisteredTransport {
+                    name: name.to_owned(),
+                    transport: t,
+                }))),
+                Some(Err(err)) => Err(BuiltinPluginError::Transport(name.to_owned(), err.to_string())),
+            },
+        }
+    }
+}
+
+/// Stream wrapped by the builtin plugin of server
+pub enum PluginStream<S> {
+    Plain(S),
+    Obfs(ObfsStream<S>),
+    #[cfg(feature = "plugin-websocket")]
+    WebSocket(WebSocketStream<S>),
+    #[cfg(feature = "plugin-shadow-tls")]
+    ShadowTls(ShadowTlsStream<S>),
+    #[cfg(feature = "plugin-grpc")]
+    Grpc(GrpcStream<S>),
+    Registered(S, Box<dyn StreamWrapper>),
+}
+
+impl<S> fmt::Debug for PluginStream<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            PluginStream::Plain(ref s) => f.debug_tuple("Plain").field(s).finish(),
+            PluginStream::Obfs(ref s) => f.debug_tuple("Obfs").field(s).finish(),
+            #[cfg(feature = "plugin-websocket")]
+            PluginStream::WebSocket(ref s) => f.debug_tuple("WebSocket").field(s).finish(),
+            #[cfg(feature = "plugin-shadow-tls")]
+            PluginStream::ShadowTls(ref s) => f.debug_tuple("ShadowTls").field(s).finish(),
+            #[cfg(feature = "plugin-grpc")]
+            PluginStream::Grpc(ref s) => f.debug_tuple("Grpc").field(s).finish(),
+            PluginStream::Registered(ref s, ..) => f.debug_tuple("Registered").field(s).finish(),
+        }
+    }
+}
+
+impl<S> PluginStream<S> {
+    /// Wrap a stream connected to the server `svr_cfg`
+    pub fn new_client(stream: S, svr_cfg: &ServerConfig) -> PluginStream<S> {
+        match svr_cfg.builtin_plugin() {
+            None => PluginStream::Plain(stream),
+            Some(BuiltinPlugin::Obfs(config)) => {
+                PluginStream::Obfs(ObfsStream::new_client(stream, config, svr_cfg.addr()))
+            }
+            #[cfg(feature = "plugin-websocket")]
+            Some(BuiltinPlugin::WebSocket(config)) => {
+                PluginStream::WebSocket(WebSocketStream::new_client(stream, config))
+            }
+            #[cfg(feature = "plugin-shadow-tls")]
+            Some(BuiltinPlugin::ShadowTls(config)) => {
+                PluginStream::ShadowTls(ShadowTlsStream::new_client(stream, config))
+            }
+            #[cfg(feature = "plugin-grpc")]
+            Some(BuiltinPlugin::Grpc(config)) => {
+                PluginStream::Grpc(GrpcStream::new_client(stream, config, svr_cfg.addr()))
+            }
+            Some(BuiltinPlugin::Registered(t)) => {
+                PluginStream::Registered(stream, t.transport.client_wrapper(svr_cfg.addr()))
+            }
+        }
+    }
+
+    /// Wrap a stream accepted from clients
+    pub fn new_server(stream: S, plugin: Option<&BuiltinPlugin>) -> PluginStream<S> {
+        match plugin {
+            None => PluginStream::Plain(stream),
+            Some(BuiltinPlugin::Obfs(config)) => PluginStream::Obfs(ObfsStream::new_server(stream, config)),
+            #[cfg(feature = "plugin-websocket")]
+            Some(BuiltinPlugin::WebSocket(config)) => {
+                PluginStream::WebSocket(WebSocketStream::new_server(stream, config))
+            }
+            #[cfg(feature = "plugin-shadow-tls")]
+            Some(BuiltinPlugin::ShadowTls(config)) => {
+                PluginStream::ShadowTls(ShadowTlsStream::new_server(stream, config))
+            }
+            #[cfg(feature = "plugin-grpc")]
+            Some(BuiltinPlugin::Grpc(config)) => PluginStream::Grpc(GrpcStream::new_server(stream, config)),
+            Some(BuiltinPlugin::Registered(t)) => PluginStream::Registered(stream, t.transport.server_wrapper()),
+        }
+    }
+
+    /// Get reference to the underlying stream
+    ///
+    /// NOTE: Panics if the TLS handshake of the transport has failed
+    pub fn get_ref(&self) -> &S {
+        match *self {
+            PluginStream::Plain(ref s) => s,
+            PluginStream::Obfs(ref s) => s.get_ref(),
+            #[cfg(feature = "plugin-websocket")]
+            PluginStream::WebSocket(ref s) => s.stream.get_ref().expect("tls handshake failed"),
+            #[cfg(feature = "plugin-shadow-tls")]
+            PluginStream::ShadowTls(ref s) => s.get_ref().expect("shadow-tls handshake failed"),
+            #[cfg(feature = "plugin-grpc")]
+            PluginStream::Grpc(ref s) => s.stream.get_ref().expect("tls handshake failed"),
+            PluginStream::Registered(ref s, ..) => s,
+        }
+    }
+
+    /// Get mutable reference to the underlying stream
+    ///
+    /// NOTE: Panics if the TLS handshake of the transport has failed
+    pub fn get_mut(&mut self) -> &mut S {
+        match *self {
+            PluginStream::Plain(ref mut s) => s,
+            PluginStream::Obfs(ref mut s) => s.get_mut(),
+            #[cfg(feature = "plugin-websocket")]
+            PluginStream::WebSocket(ref mut s) => s.stream.get_mut().expect("tls handshake failed"),
+            #[cfg(feature = "plugin-shadow-tls")]
+            PluginStream::ShadowTls(ref mut s) => s.get_mut().expect("shadow-tls handshake failed"),
+            #[cfg(feature = "plugin-grpc")]
+            PluginStream::Grpc(ref mut s) => s.stream.get_mut().expect("tls handshake failed"),
+            PluginStream::Registered(ref mut s, ..) => s,
+        }
+    }
+
+    /// Consumes the `PluginStream` and return the underlying stream
+    ///
+    /// NOTE: Panics if the TLS handshake of the transport is in progress or has failed
+    pub fn into_inner(self) -> S {
+        match self {
+            PluginStream::Plain(s) => s,
+            PluginStream::Obfs(s) => s.into_inner(),
+            #[cfg(feature = "plugin-websocket")]
+            PluginStream::WebSocket(s) => s.stream.into_inner().expect("tls handshake is not finished"),
+            #[cfg(feature = "plugin-shadow-tls")]
+            PluginStream::ShadowTls(s) => s.into_inner().expect("shadow-tls handshake is not finished"),
+            #[cfg(feature = "plugin-grpc")]
+            PluginStream::Grpc(s) => s.stream.into_inner().expect("tls handshake is not finished"),
+            PluginStream::Registered(s, ..) => s,
+        }
+    }
+}
+
+impl<S> AsyncRead for PluginStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match *self.get_mut() {
+            PluginStream::Plain(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            PluginStream::Obfs(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "plugin-websocket")]
+            PluginStream::WebSocket(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "plugin-shadow-tls")]
+            PluginStream::ShadowTls(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "plugin-grpc")]
+            PluginStream::Grpc(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            PluginStream::Registered(ref mut s, ref mut w) => w.poll_read(s, cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for PluginStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match *self.get_mut() {
+            PluginStream::Plain(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            PluginStream::Obfs(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "plugin-websocket")]
+            PluginStream::WebSocket(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "plugin-shadow-tls")]
+            PluginStream::ShadowTls(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "plugin-grpc")]
+            PluginStream::Grpc(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            PluginStream::Registered(ref mut s, ref mut w) => w.poll_write(s, cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match *self.get_mut() {
+            PluginStream::Plain(ref mut s) => Pin::new(s).poll_flush(cx),
+            PluginStream::Obfs(ref mut s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "plugin-websocket")]
+            PluginStream::WebSocket(ref mut s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "plugin-shadow-tls")]
+            PluginStream::ShadowTls(ref mut s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "plugin-grpc")]
+            PluginStream::Grpc(ref mut s) => Pin::new(s).poll_flush(cx),
+            PluginStream::Registered(ref mut s, ref mut w) => w.poll_flush(s, cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match *self.get_mut() {
+            PluginStream::Plain(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            PluginStream::Obfs(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "plugin-websocket")]
+            PluginStream::WebSocket(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "plugin-shadow-tls")]
+            PluginStream::ShadowTls(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "plugin-grpc")]
+            PluginStream::Grpc(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            PluginStream::Registered(ref mut s, ref mut w) => w.poll_shutdown(s, cx),
+        }
+    }
+}
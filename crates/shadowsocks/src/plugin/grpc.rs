@@ -0,0 +1,837 @@
+//! Builtin gRPC transport, compatible with the `gun` transport of v2ray / Xray
+//!
+//! Wraps connections in bidirectional streaming gRPC calls of `/<serviceName>/Tun` over HTTP/2, optionally over TLS.
+//! CDNs and middleboxes proxying gRPC usually handle long-lived streams better than WebSocket upgrades.
+//! Enabled by `"plugin": "builtin-grpc"`, with `plugin_opts` like:
+//!
+//! ```plain
+//! tls;host=www.example.com;serviceName=GunService
+//! server;tls;serviceName=GunService;cert=/path/to/fullchain.pem;key=/path/to/key.pem
+//! ```
+//!
+//! Each connection is an HTTP/2 connection carrying exactly one stream, and data are sent in `Hunk` messages
+//! (`message Hunk { bytes data = 1; }`). Multiplexing is never used, and servers don't check paths of streams.
+
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    pin::Pin,
+    sync::Arc,
+    task::{self, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::ready;
+use once_cell::sync::Lazy;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::{
+    TlsAcceptor, TlsConnector,
+    rustls::{ClientConfig, RootCertStore, pki_types::ServerName},
+};
+
+use super::tls::{TlsState, poll_fill_read_buf};
+use crate::config::ServerAddr;
+
+/// Default service name of v2ray
+const DEFAULT_SERVICE_NAME: &str = "GunService";
+/// Connection preface of HTTP/2 clients, RFC 7540 3.5
+const CONNECTION_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_LEN: usize = 9;
+/// Maximum payload size of frames, the initial `SETTINGS_MAX_FRAME_SIZE` which is never changed
+const MAX_FRAME_SIZE: usize = 16384;
+/// Initial window size of connections and streams, RFC 7540 6.9.2
+const DEFAULT_WINDOW_SIZE: i64 = 65535;
+/// Window size of receiving, for both the connection and the stream
+const RECV_WINDOW_SIZE: u32 = 1 << 20;
+/// Maximum size of messages received
+const MAX_RECV_MESSAGE_SIZE: usize = 1 << 20;
+/// Message header of gRPC (5 bytes), tag (1 byte) and length (2 bytes) of `Hunk.data`
+const MESSAGE_OVERHEAD: usize = 8;
+/// Maximum data size in each message sent, which fits in a frame with `MESSAGE_OVERHEAD`
+const MAX_SEND_DATA_SIZE: usize = MAX_FRAME_SIZE - MESSAGE_OVERHEAD;
+
+const FRAME_DATA: u8 = 0x0;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_RST_STREAM: u8 = 0x3;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_PUSH_PROMISE: u8 = 0x5;
+const FRAME_PING: u8 = 0x6;
+const FRAME_GOAWAY: u8 = 0x7;
+const FRAME_WINDOW_UPDATE: u8 = 0x8;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+
+/// Errors of parsing `GrpcConfig`
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum GrpcConfigError {
+    /// `mode` other than `grpc`
+    #[error("unsupported mode \"{0}\", only \"grpc\" is supported")]
+    InvalidMode(String),
+    /// Unknown option
+    #[error("unrecognized grpc option \"{0}\"")]
+    InvalidOption(String),
+    /// `host` couldn't be used as SNI
+    #[error("invalid host \"{0}\"")]
+    InvalidHost(String),
+    /// TLS server without certificate
+    #[error("cert and key are required for tls in server")]
+    MissingCertificate,
+    /// Failed to load certificate
+    #[error("failed to load cert and key, {0}")]
+    InvalidCertificate(String),
+}
+
+/// Configuration of builtin gRPC transport
+#[derive(Clone)]
+pub struct GrpcConfig {
+    /// Works as server, which accepts gRPC streams
+    pub server: bool,
+    /// Authority of requests and SNI of TLS, the server's address by default
+    pub host: Option<String>,
+    /// Service name, streams are called at `/<service_name>/Tun`
+    pub service_name: String,
+    /// Enable TLS
+    pub tls: bool,
+    tls_connector: Option<TlsConnector>,
+    tls_acceptor: Option<TlsAcceptor>,
+}
+
+impl fmt::Debug for GrpcConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcConfig")
+            .field("server", &self.server)
+            .field("host", &self.host)
+            .field("service_name", &self.service_name)
+            .field("tls", &self.tls)
+            .finish()
+    }
+}
+
+impl GrpcConfig {
+    /// Parse from `plugin_opts`, like `tls;host=www.example.com;serviceName=GunService`
+    ///
+    /// Certificates are loaded here if TLS is enabled in server.
+    pub fn from_plugin_opts(opts: Option<&str>) -> Result<GrpcConfig, GrpcConfigError> {
+        let mut server = false;
+        let mut tls = false;
+        let mut host = None;
+        let mut service_name = None;
+        let mut cert = None;
+        let mut key = None;
+
+        for opt in opts.unwrap_or_default().split(';') {
+            let opt = opt.trim();
+            if opt.is_empty() {
+                continue;
+            }
+
+            match opt.split_once('=') {
+                None if opt == "server" => server = true,
+                None if opt == "tls" => tls = true,
+                // TCP Fast Open is configured by `fast_open` of shadowsocks
+                None if opt == "fast-open" => {}
+                Some(("mode", "grpc")) => {}
+                Some(("mode", mode)) => return Err(GrpcConfigError::InvalidMode(mode.to_owned())),
+                Some(("host", value)) => host = Some(value.to_owned()),
+                Some(("serviceName", value)) => service_name = Some(value.to_owned()),
+                Some(("cert", value)) => cert = Some(value.to_owned()),
+                Some(("key", value)) => key = Some(value.to_owned()),
+                Some(("loglevel", ..)) => {}
+                _ => return Err(GrpcConfigError::InvalidOption(opt.to_owned())),
+            }
+        }
+
+        if let Some(ref host) = host {
+            if ServerName::try_from(host.as_str()).is_err() {
+                return Err(GrpcConfigError::InvalidHost(host.clone()));
+            }
+        }
+
+        let (tls_connector, tls_acceptor) = match (tls, server) {
+            (false, ..) => (None, None),
+            (true, false) => (Some(TlsConnector::from(TLS_CLIENT_CONFIG.clone())), None),
+            (true, true) => match (cert, key) {
+                (Some(cert), Some(key)) => (None, Some(make_acceptor(&cert, &key)?)),
+                _ => return Err(GrpcConfigError::MissingCertificate),
+            },
+        };
+
+        Ok(GrpcConfig {
+            server,
+            host,
+            service_name: service_name.unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_owned()),
+            tls,
+            tls_connector,
+            tls_acceptor,
+        })
+    }
+}
+
+static TLS_CLIENT_CONFIG: Lazy<Arc<ClientConfig>> = Lazy::new(|| {
+    let mut store = RootCertStore::empty();
+    store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    let mut config = ClientConfig::builder()
+        .with_root_certificates(store)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    Arc::new(config)
+});
+
+fn make_acceptor(cert: &str, key: &str) -> Result<TlsAcceptor, GrpcConfigError> {
+    super::tls::make_acceptor(cert, key, b"h2").map_err(GrpcConfigError::InvalidCertificate)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HandshakeState {
+    /// Host of the server couldn't be used as SNI
+    ClientInvalidHost,
+    ServerReadPreface,
+    /// Waiting for the stream opened by client
+    ServerWaitStream,
+    Established,
+}
+
+/// Stream wrapped in a gRPC stream of `Hunk` messages over HTTP/2
+pub struct GrpcStream<S> {
+    pub(crate) stream: TlsState<S>,
+    is_client: bool,
+    authority: String,
+    handshake: HandshakeState,
+    stream_id: Option<u32>,
+    // Received bytes not decoded yet
+    read_buf: BytesMut,
+    // Payloads of DATA frames not decoded into messages yet
+    message_buf: BytesMut,
+    // Decoded data
+    data_buf: BytesMut,
+    read_closed: bool,
+    // Bytes of DATA frames received, and bytes of them have been given back to the peer's windows
+    recv_total: u64,
+    recv_credited: u64,
+    // Windows of sending, which are given by the peer
+    send_initial_window: i64,
+    send_conn_window: i64,
+    send_stream_window: i64,
+    // Encoded frames not sent yet, and the length of data in the last DATA frame
+    send_buf: BytesMut,
+    write_len: Option<usize>,
+    end_sent: bool,
+}
+
+impl<S> fmt::Debug for GrpcStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcStream")
+            .field("is_client", &self.is_client)
+            .field("authority", &self.authority)
+            .field("handshake", &self.handshake)
+            .field("stream_id", &self.stream_id)
+            .finish()
+    }
+}
+
+impl<S> GrpcStream<S> {
+    /// Create a client stream connected to `svr_addr`, handshakes on the first read or write
+    pub fn new_client(stream: S, config: &GrpcConfig, svr_addr: &ServerAddr) -> GrpcStream<S> {
+        let mut handshake = HandshakeState::Established;
+        let stream = match config.tls_connector {
+            Some(ref connector) => {
+                let server_name = match (&config.host, svr_addr) {
+                    (Some(host), ..) => ServerName::try_from(host.clone()),
+                    (None, ServerAddr::SocketAddr(sa)) => Ok(ServerName::from(sa.ip())),
+                    (None, ServerAddr::DomainName(domain, ..)) => ServerName::try_from(domain.clone()),
+                };
+                match server_name {
+                    Ok(server_name) => TlsState::Connecting(connector.connect(server_name, stream)),
+                    Err(..) => {
+                        handshake = HandshakeState::ClientInvalidHost;
+                        TlsState::Plain(stream)
+                    }
+                }
+            }
+            None => TlsState::Plain(stream),
+        };
+        let authority = match config.host {
+            Some(ref host) => host.clone(),
+            None => svr_addr.to_string(),
+        };
+
+        let mut grpc = GrpcStream::new(stream, true, authority, handshake);
+        grpc.stream_id = Some(1);
+
+        grpc.send_buf.put_slice(CONNECTION_PREFACE);
+        grpc.put_settings();
+        let path = format!("/{}/Tun", config.service_name);
+        let scheme = if config.tls { "https" } else { "http" };
+        let headers = encode_headers(&[
+            (":method", "POST"),
+            (":scheme", scheme),
+            (":path", path.as_str()),
+            (":authority", grpc.authority.as_str()),
+            ("content-type", "application/grpc"),
+            ("te", "trailers"),
+            ("user-agent", "grpc-go/1.60.1"),
+        ]);
+        grpc.put_frame(FRAME_HEADERS, FLAG_END_HEADERS, 1, &headers);
+        grpc
+    }
+
+    /// Create a server stream, handshakes on the first read or write
+    pub fn new_server(stream: S, config: &GrpcConfig) -> GrpcStream<S> {
+        let stream = match config.tls_acceptor {
+            Some(ref acceptor) => TlsState::Accepting(acceptor.accept(stream)),
+            None => TlsState::Plain(stream),
+        };
+
+        let mut grpc = GrpcStream::new(stream, false, String::new(), HandshakeState::ServerReadPreface);
+        grpc.put_settings();
+        grpc
+    }
+
+    fn new(stream: TlsState<S>, is_client: bool, authority: String, handshake: HandshakeState) -> GrpcStream<S> {
+        GrpcStream {
+            stream,
+            is_client,
+            authority,
+            handshake,
+            stream_id: None,
+            read_buf: BytesMut::new(),
+            message_buf: BytesMut::new(),
+            data_buf: BytesMut::new(),
+            read_closed: false,
+            recv_total: 0,
+            recv_credited: 0,
+            send_initial_window: DEFAULT_WINDOW_SIZE,
+            send_conn_window: DEFAULT_WINDOW_SIZE,
+            send_stream_window: DEFAULT_WINDOW_SIZE,
+            send_buf: BytesMut::new(),
+            write_len: None,
+            end_sent: false,
+        }
+    }
+
+    fn put_frame(&mut self, frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+        put_frame_header(&mut self.send_buf, payload.len(), frame_type, flags, stream_id);
+        self.send_buf.put_slice(payload);
+    }
+
+    /// Settings of receiving, and the window of the connection enlarged as the same as streams
+    fn put_settings(&mut self) {
+        let mut settings = BytesMut::with_capacity(12);
+        if self.is_client {
+            settings.put_u16(SETTINGS_ENABLE_PUSH);
+            settings.put_u32(0);
+        }
+        settings.put_u16(SETTINGS_INITIAL_WINDOW_SIZE);
+        settings.put_u32(RECV_WINDOW_SIZE);
+        self.put_frame(FRAME_SETTINGS, 0, 0, &settings);
+
+        let increment = RECV_WINDOW_SIZE - DEFAULT_WINDOW_SIZE as u32;
+        self.put_frame(FRAME_WINDOW_UPDATE, 0, 0, &increment.to_be_bytes());
+    }
+
+    /// Encode `data` in a message of a DATA frame
+    fn put_data(&mut self, data: &[u8]) -> usize {
+        let mut length = BytesMut::with_capacity(2);
+        put_varint(&mut length, data.len() as u64);
+        let message_len = 1 + length.len() + data.len();
+        let payload_len = 5 + message_len;
+
+        let stream_id = self.stream_id.expect("grpc stream is not opened");
+        put_frame_header(&mut self.send_buf, payload_len, FRAME_DATA, 0, stream_id);
+        // Uncompressed message, field 1 with length-delimited wire type
+        self.send_buf.put_u8(0);
+        self.send_buf.put_u32(message_len as u32);
+        self.send_buf.put_u8(0x0a);
+        self.send_buf.put_slice(&length);
+        self.send_buf.put_slice(data);
+        payload_len
+    }
+
+    /// Decode a frame in `read_buf`, returns `false` if more bytes are required
+    fn decode_frame(&mut self) -> io::Result<bool> {
+        if self.read_buf.len() < FRAME_HEADER_LEN {
+            return Ok(false);
+        }
+        let length = u32::from_be_bytes([0, self.read_buf[0], self.read_buf[1], self.read_buf[2]]) as usize;
+        if length > MAX_FRAME_SIZE {
+            return Err(io::Error::new(ErrorKind::InvalidData, "grpc frame too large"));
+        }
+        if self.read_buf.len() < FRAME_HEADER_LEN + length {
+            return Ok(false);
+        }
+
+        let frame_type = self.read_buf[3];
+        let flags = self.read_buf[4];
+        let stream_id =
+            u32::from_be_bytes([self.read_buf[5], self.read_buf[6], self.read_buf[7], self.read_buf[8]]) & 0x7fff_ffff;
+        self.read_buf.advance(FRAME_HEADER_LEN);
+        let payload = self.read_buf.split_to(length);
+        let is_our_stream = stream_id != 0 && self.stream_id == Some(stream_id);
+
+        match frame_type {
+            FRAME_DATA => {
+                // Padding is also counted in windows
+                self.recv_total += length as u64;
+                if is_our_stream {
+                    let data = strip_padding(&payload, flags)?;
+                    self.message_buf.put_slice(data);
+                    self.decode_messages()?;
+                    if flags & FLAG_END_STREAM != 0 {
+                        self.read_closed = true;
+                    }
+                }
+                self.update_recv_window();
+            }
+            FRAME_HEADERS => {
+                if !self.is_client && self.stream_id.is_none() && stream_id % 2 == 1 {
+                    self.stream_id = Some(stream_id);
+                    let headers = encode_headers(&[(":status", "200"), ("content-type", "application/grpc")]);
+                    self.put_frame(FRAME_HEADERS, FLAG_END_HEADERS, stream_id, &headers);
+                } else if is_our_stream && flags & FLAG_END_STREAM != 0 {
+                    // Trailers
+                    self.read_closed = true;
+                }
+            }
+            FRAME_RST_STREAM if is_our_stream => {
+                let code = read_u32(&payload)?;
+                if code != 0 {
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionReset,
+                        format!("grpc stream reset, error code {code:#x}"),
+                    ));
+                }
+                self.read_closed = true;
+            }
+            FRAME_SETTINGS if flags & FLAG_ACK == 0 => {
+                for setting in payload.chunks_exact(6) {
+                    let id = u16::from_be_bytes([setting[0], setting[1]]);
+                    let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]) as i64;
+                    if id == SETTINGS_INITIAL_WINDOW_SIZE {
+                        self.send_stream_window += value - self.send_initial_window;
+                        self.send_initial_window = value;
+                    }
+                }
+                self.put_frame(FRAME_SETTINGS, FLAG_ACK, 0, &[]);
+            }
+            FRAME_PING if flags & FLAG_ACK == 0 => self.put_frame(FRAME_PING, FLAG_ACK, 0, &payload),
+            FRAME_GOAWAY => {
+                let code = read_u32(payload.get(4..).unwrap_or_default())?;
+                if code != 0 {
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionAborted,
+                        format!("grpc connection went away, error code {code:#x}"),
+                    ));
+                }
+            }
+            FRAME_WINDOW_UPDATE => {
+                let increment = (read_u32(&payload)? & 0x7fff_ffff) as i64;
+                if stream_id == 0 {
+                    self.send_conn_window += increment;
+                } else if is_our_stream {
+                    self.send_stream_window += increment;
+                }
+            }
+            FRAME_PUSH_PROMISE => {
+                return Err(io::Error::new(ErrorKind::InvalidData, "grpc unexpected push promise"));
+            }
+            // PRIORITY, CONTINUATION, acknowledgements and unknown frames
+            _ => {}
+        }
+
+        Ok(true)
+    }
+
+    /// Decode complete messages in `message_buf` into `data_buf`
+    fn decode_messages(&mut self) -> io::Result<()> {
+        while self.message_buf.len() >= 5 {
+            if self.message_buf[0] != 0 {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "grpc compressed messages are not supported",
+                ));
+            }
+            let length = read_u32(&self.message_buf[1..5])? as usize;
+            if length > MAX_RECV_MESSAGE_SIZE {
+                return Err(io::Error::new(ErrorKind::InvalidData, "grpc message too large"));
+            }
+            if self.message_buf.len() < 5 + length {
+                break;
+            }
+
+            self.message_buf.advance(5);
+            let message = self.message_buf.split_to(length);
+            decode_hunk(&message, &mut self.data_buf)?;
+        }
+        Ok(())
+    }
+
+    /// Give back bytes that are no longer buffered to windows of the peer
+    fn update_recv_window(&mut self) {
+        let buffered = (self.message_buf.len() + self.data_buf.len()) as u64;
+        let consumed = self.recv_total - buffered;
+        let increment = consumed - self.recv_credited;
+        if increment < RECV_WINDOW_SIZE as u64 / 2 {
+            return;
+        }
+
+        self.recv_credited = consumed;
+        let increment = (increment as u32).to_be_bytes();
+        self.put_frame(FRAME_WINDOW_UPDATE, 0, 0, &increment);
+        if let Some(stream_id) = self.stream_id {
+            if !self.read_closed {
+                self.put_frame(FRAME_WINDOW_UPDATE, 0, stream_id, &increment);
+            }
+        }
+    }
+}
+
+impl<S> GrpcStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_send_buf(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        while !self.send_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.send_buf))?;
+            if n == 0 {
+                return Err(ErrorKind::WriteZero.into()).into();
+            }
+            self.send_buf.advance(n);
+        }
+        Ok(()).into()
+    }
+
+    /// Try to send acknowledgements and window updates, without waiting for them
+    fn send_pending(&mut self, cx: &mut task::Context<'_>) -> io::Result<()> {
+        if self.send_buf.is_empty() {
+            return Ok(());
+        }
+        if let Poll::Ready(result) = self.poll_send_buf(cx) {
+            result?;
+            if let Poll::Ready(Err(err)) = Pin::new(&mut self.stream).poll_flush(cx) {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_handshake(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match self.handshake {
+                HandshakeState::Established => return Ok(()).into(),
+                HandshakeState::ClientInvalidHost => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("grpc invalid host \"{}\"", self.authority),
+                    ))
+                    .into();
+                }
+                HandshakeState::ServerReadPreface => {
+                    if self.read_buf.len() >= CONNECTION_PREFACE.len() {
+                        if !self.read_buf.starts_with(CONNECTION_PREFACE) {
+                            return Err(io::Error::new(
+                                ErrorKind::InvalidData,
+                                "grpc invalid http/2 connection preface",
+                            ))
+                            .into();
+                        }
+                        self.read_buf.advance(CONNECTION_PREFACE.len());
+                        self.handshake = HandshakeState::ServerWaitStream;
+                        continue;
+                    }
+                }
+                HandshakeState::ServerWaitStream => {
+                    if self.stream_id.is_some() {
+                        self.handshake = HandshakeState::Established;
+                        continue;
+                    }
+                    if self.decode_frame()? {
+                        continue;
+                    }
+                    self.send_pending(cx)?;
+                }
+            }
+
+            if !ready!(poll_fill_read_buf(Pin::new(&mut self.stream), cx, &mut self.read_buf))? {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "grpc closed during handshake")).into();
+            }
+        }
+    }
+}
+
+impl<S> AsyncRead for GrpcStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+
+        loop {
+            if !this.data_buf.is_empty() {
+                let n = this.data_buf.len().min(buf.remaining());
+                buf.put_slice(&this.data_buf[..n]);
+                this.data_buf.advance(n);
+                this.update_recv_window();
+                this.send_pending(cx)?;
+                return Ok(()).into();
+            }
+
+            if this.read_closed {
+                return Ok(()).into();
+            }
+
+            if this.decode_frame()? {
+                continue;
+            }
+
+            // Request headers of clients, acknowledgements and window updates are sent while waiting for frames
+            this.send_pending(cx)?;
+            if !ready!(poll_fill_read_buf(Pin::new(&mut this.stream), cx, &mut this.read_buf))? {
+                if this.read_buf.is_empty() && this.message_buf.is_empty() {
+                    return Ok(()).into();
+                }
+                return Err(ErrorKind::UnexpectedEof.into()).into();
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for GrpcStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+
+        // The DATA frame of the previous call must be sent before accepting new data.
+        // Callers are required to call again with the same `buf` after `Poll::Pending`.
+        if this.write_len.is_none() {
+            if buf.is_empty() {
+                return Ok(0).into();
+            }
+            if this.end_sent {
+                return Err(ErrorKind::BrokenPipe.into()).into();
+            }
+
+            loop {
+                let window = this.send_conn_window.min(this.send_stream_window) - MESSAGE_OVERHEAD as i64;
+                if window > 0 {
+                    let n = buf.len().min(MAX_SEND_DATA_SIZE).min(window as usize);
+                    let payload_len = this.put_data(&buf[..n]) as i64;
+                    this.send_conn_window -= payload_len;
+                    this.send_stream_window -= payload_len;
+                    this.write_len = Some(n);
+                    break;
+                }
+
+                // Wait for WINDOW_UPDATE of the peer
+                if this.decode_frame()? {
+                    continue;
+                }
+                this.send_pending(cx)?;
+                if !ready!(poll_fill_read_buf(Pin::new(&mut this.stream), cx, &mut this.read_buf))? {
+                    return Err(io::Error::new(ErrorKind::BrokenPipe, "grpc connection closed")).into();
+                }
+            }
+        }
+
+        ready!(this.poll_send_buf(cx))?;
+        Ok(this.write_len.take().expect("grpc write_len")).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+        ready!(this.poll_send_buf(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+        ready!(this.poll_send_buf(cx))?;
+        if !this.end_sent {
+            this.end_sent = true;
+            let stream_id = this.stream_id.expect("grpc stream is not opened");
+            if this.is_client {
+                this.put_frame(FRAME_DATA, FLAG_END_STREAM, stream_id, &[]);
+            } else {
+                let trailers = encode_headers(&[("grpc-status", "0")]);
+                this.put_frame(FRAME_HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, stream_id, &trailers);
+            }
+            ready!(this.poll_send_buf(cx))?;
+        }
+        // The connection is still used for receiving, it is closed after dropped
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+}
+
+fn put_frame_header(buf: &mut BytesMut, length: usize, frame_type: u8, flags: u8, stream_id: u32) {
+    buf.put_slice(&(length as u32).to_be_bytes()[1..]);
+    buf.put_u8(frame_type);
+    buf.put_u8(flags);
+    buf.put_u32(stream_id);
+}
+
+/// Encode header fields as literals without indexing, which never touch dynamic tables of HPACK
+fn encode_headers(fields: &[(&str, &str)]) -> BytesMut {
+    let mut block = BytesMut::new();
+    for (name, value) in fields {
+        block.put_u8(0x00);
+        put_hpack_string(&mut block, name);
+        put_hpack_string(&mut block, value);
+    }
+    block
+}
+
+/// String literal without Huffman coding, its length is an integer with 7-bit prefix, RFC 7541 5.1
+fn put_hpack_string(buf: &mut BytesMut, s: &str) {
+    if s.len() < 0x7f {
+        buf.put_u8(s.len() as u8);
+    } else {
+        buf.put_u8(0x7f);
+        put_varint(buf, (s.len() - 0x7f) as u64);
+    }
+    buf.put_slice(s.as_bytes());
+}
+
+/// Base 128 varint, which is shared by protobuf and HPACK integers
+fn put_varint(buf: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        buf.put_u8((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn get_varint(buf: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&b, rest) = buf
+            .split_first()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "grpc invalid message"))?;
+        *buf = rest;
+        value |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(ErrorKind::InvalidData, "grpc invalid message"))
+}
+
+fn read_u32(buf: &[u8]) -> io::Result<u32> {
+    match buf.get(..4) {
+        Some(b) => Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err(io::Error::new(ErrorKind::InvalidData, "grpc frame too short")),
+    }
+}
+
+fn strip_padding(payload: &[u8], flags: u8) -> io::Result<&[u8]> {
+    if flags & FLAG_PADDED == 0 {
+        return Ok(payload);
+    }
+    match payload.split_first() {
+        Some((&pad_len, rest)) if (pad_len as usize) <= rest.len() => Ok(&rest[..rest.len() - pad_len as usize]),
+        _ => Err(io::Error::new(ErrorKind::InvalidData, "grpc invalid padding")),
+    }
+}
+
+/// Decode `Hunk`, or `MultiHunk` of Xray which repeats field 1, into `data`
+fn decode_hunk(mut message: &[u8], data: &mut BytesMut) -> io::Result<()> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "grpc invalid message");
+
+    while !message.is_empty() {
+        let tag = get_varint(&mut message)?;
+        let skip = match tag & 0x7 {
+            0 => {
+                get_varint(&mut message)?;
+                0
+            }
+            1 => 8,
+            2 => {
+                let length = get_varint(&mut message)? as usize;
+                let field = message.get(..length).ok_or_else(invalid)?;
+                if tag >> 3 == 1 {
+                    data.put_slice(field);
+                }
+                length
+            }
+            5 => 4,
+            _ => return Err(invalid()),
+        };
+        message = message.get(skip..).ok_or_else(invalid)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn parse_plugin_opts() {
+        let config = GrpcConfig::from_plugin_opts(Some("host=www.example.com;serviceName=Tunnel")).unwrap();
+        assert!(!config.server);
+        assert!(!config.tls);
+        assert_eq!(config.host.as_deref(), Some("www.example.com"));
+        assert_eq!(config.service_name, "Tunnel");
+
+        let config = GrpcConfig::from_plugin_opts(Some("server;mode=grpc")).unwrap();
+        assert!(config.server);
+        assert_eq!(config.host, None);
+        assert_eq!(config.service_name, DEFAULT_SERVICE_NAME);
+
+        assert!(GrpcConfig::from_plugin_opts(Some("mode=websocket")).is_err());
+        assert!(GrpcConfig::from_plugin_opts(Some("server;tls")).is_err());
+    }
+
+    #[test]
+    fn multi_hunk() {
+        let mut data = BytesMut::new();
+        decode_hunk(b"\x0a\x03abc\x10\x01\x0a\x02de", &mut data).unwrap();
+        assert_eq!(&data[..], b"abcde");
+
+        assert!(decode_hunk(b"\x0a\x05abc", &mut data).is_err());
+    }
+
+    #[tokio::test]
+    async fn grpc_roundtrip() {
+        let client_config = GrpcConfig::from_plugin_opts(Some("host=www.example.com")).unwrap();
+        let server_config = GrpcConfig::from_plugin_opts(Some("server")).unwrap();
+        let svr_addr = ServerAddr::DomainName("www.example.com".to_owned(), 443);
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = GrpcStream::new_client(client, &client_config, &svr_addr);
+        let mut server = GrpcStream::new_server(server, &server_config);
+
+        // Larger than the initial windows of HTTP/2
+        const DATA_SIZE: usize = 300 * 1024;
+
+        let server_task = tokio::spawn(async move {
+            let mut buf = vec![0u8; DATA_SIZE];
+            server.read_exact(&mut buf).await.unwrap();
+            server.write_all(&buf).await.unwrap();
+            server.shutdown().await.unwrap();
+        });
+
+        let data = (0..DATA_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        client.write_all(&data).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+
+        server_task.await.unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+//! Builtin KCP transport
+//!
+//! Relays connections over KCP, a reliable transport over UDP, for links where TCP is heavily shaped.
+//! It runs in process as a plugin, which listens on `local_addr` like plugin subprocesses.
+//! Enabled by `"plugin": "builtin-kcp"`, with `plugin_opts` in the same form of kcptun:
+//!
+//! ```plain
+//! mode=fast2;mtu=1350;sndwnd=1024;rcvwnd=1024
+//! ```
+//!
+//! - `mode`: `normal`, `fast` (default), `fast2`, `fast3` or `manual`, presets of `nodelay`, `interval`, `resend` and `nc`
+//! - `mtu`: Maximum size of UDP packets, 1350 by default
+//! - `sndwnd`, `rcvwnd`: Send and receive window size in packets, 1024 by default
+//! - `nodelay`, `interval`, `resend`, `nc`: Override values of `mode`
+//! - `datashard`, `parityshard`: Accepted only as 0 (FEC disabled), for sharing options with kcptun
+//!
+//! Only KCP is implemented. FEC, encryption and stream multiplexing of kcptun are out of scope of this transport,
+//! so both sides have to use `builtin-kcp`, and links needing FEC should use the `kcptun` plugin instead.
+
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    str::FromStr,
+    time::Duration,
+};
+
+use log::{debug, trace};
+use tokio::net::{TcpListener, TcpStream, lookup_host};
+use tokio_kcp::{KcpConfig, KcpListener, KcpNoDelayConfig, KcpStream};
+
+use super::PluginMode;
+use crate::config::ServerAddr;
+
+/// Name of the builtin KCP plugin
+pub const BUILTIN_KCP_PLUGIN: &str = "builtin-kcp";
+
+/// Errors of parsing `KcpPluginConfig`
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum KcpConfigError {
+    /// Unknown `mode`
+    #[error("invalid kcp mode \"{0}\", should be one of \"normal\", \"fast\", \"fast2\", \"fast3\" and \"manual\"")]
+    InvalidMode(String),
+    /// Unknown option or invalid value
+    #[error("invalid kcp option \"{0}\"")]
+    InvalidOption(String),
+    /// FEC is out of scope of the builtin transport
+    #[error("builtin-kcp doesn't support FEC, datashard and parityshard must be 0, use the kcptun plugin for FEC")]
+    FecNotSupported,
+}
+
+/// Configuration of builtin KCP transport
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KcpPluginConfig {
+    /// Maximum size of UDP packets
+    pub mtu: usize,
+    /// Send window size in packets
+    pub sndwnd: u16,
+    /// Receive window size in packets
+    pub rcvwnd: u16,
+    /// Send ACKs immediately
+    pub nodelay: bool,
+    /// Interval of internal updates in milliseconds
+    pub interval: i32,
+    /// Fast retransmit after this many duplicated ACKs, 0 disables it
+    pub resend: i32,
+    /// Disable congestion control
+    pub nc: bool,
+}
+
+impl Default for KcpPluginConfig {
+    fn default() -> KcpPluginConfig {
+        // mode=fast
+        KcpPluginConfig {
+            mtu: 1350,
+            sndwnd: 1024,
+            rcvwnd: 1024,
+            nodelay: false,
+            interval: 30,
+            resend: 2,
+            nc: true,
+        }
+    }
+}
+
+fn parse_value<T: FromStr>(opt: &str, value: &str) -> Result<T, KcpConfigError> {
+    value
+        .parse::<T>()
+        .map_err(|_| KcpConfigError::InvalidOption(opt.to_owned()))
+}
+
+impl KcpPluginConfig {
+    /// Parse from `plugin_opts`, like `mode=fast2;sndwnd=1024;rcvwnd=1024`
+    pub fn from_plugin_opts(opts: Option<&str>) -> Result<KcpPluginConfig, KcpConfigError> {
+        let mut config = KcpPluginConfig::default();
+
+        // Presets of mode are applied before other options
+        let mut overrides = Vec::new();
+
+        for opt in opts.unwrap_or_default().split(';') {
+            let opt = opt.trim();
+            if opt.is_empty() {
+                continue;
+            }
+
+            match opt.split_once('=') {
+                Some(("mode", value)) => config.set_mode(value)?,
+                Some((key, value)) => overrides.push((opt, key, value)),
+                None => return Err(KcpConfigError::InvalidOption(opt.to_owned())),
+            }
+        }
+
+        for (opt, key, value) in overrides {
+            match key {
+                "mtu" => config.mtu = parse_value(opt, value)?,
+                "sndwnd" => config.sndwnd = parse_value(opt, value)?,
+                "rcvwnd" => config.rcvwnd = parse_value(opt, value)?,
+                "nodelay" => config.nodelay = parse_value::<u8>(opt, value)? != 0,
+                "interval" => config.interval = parse_value(opt, value)?,
+                "resend" => config.resend = parse_value(opt, value)?,
+                "nc" => config.nc = parse_value::<u8>(opt, value)? != 0,
+                "datashard" | "ds" | "parityshard" | "ps" => {
+                    if parse_value::<u32>(opt, value)? != 0 {
+                        return Err(KcpConfigError::FecNotSupported);
+                    }
+                }
+                _ => return Err(KcpConfigError::InvalidOption(opt.to_owned())),
+            }
+        }
+
+        Ok(config)
+    }
+
+    // Same presets as kcptun
+    fn set_mode(&mut self, mode: &str) -> Result<(), KcpConfigError> {
+        let (nodelay, interval, resend, nc) = match mode {
+            "normal" => (false, 40, 2, true),
+            "fast" => (false, 30, 2, true),
+            "fast2" => (true, 20, 2, true),
+            "fast3" => (true, 10, 2, true),
+            "manual" => return Ok(()),
+            _ => return Err(KcpConfigError::InvalidMode(mode.to_owned())),
+        };
+        self.nodelay = nodelay;
+        self.interval = interval;
+        self.resend = resend;
+        self.nc = nc;
+        Ok(())
+    }
+
+    fn kcp_config(&self) -> KcpConfig {
+        KcpConfig {
+            mtu: self.mtu,
+            nodelay: KcpNoDelayConfig {
+                nodelay: self.nodelay,
+                interval: self.interval,
+                resend: self.resend,
+                nc: self.nc,
+            },
+            wnd_size: (self.sndwnd, self.rcvwnd),
+            session_expire: Some(Duration::from_secs(90)),
+            stream: true,
+            ..Default::default()
+        }
+    }
+}
+
+async fn resolve(addr: &ServerAddr) -> io::Result<SocketAddr> {
+    match *addr {
+        ServerAddr::SocketAddr(sa) => Ok(sa),
+        ServerAddr::DomainName(ref dname, port) => match lookup_host((dname.as_str(), port)).await?.next() {
+            Some(sa) => Ok(sa),
+            None => Err(io::Error::new(
+                ErrorKind::Other,
+                format!("kcp couldn't resolve server {dname}"),
+            )),
+        },
+    }
+}
+
+/// Run KCP transport like a plugin subprocess, only returns on errors
+///
+/// `PluginMode::Client`: Listens on TCP `local_addr`, and relays connections to KCP `remote_addr`
+/// `PluginMode::Server`: Listens on KCP `remote_addr`, and relays connections to TCP `local_addr`
+pub(crate) async fn run(
+    config: KcpPluginConfig,
+    remote_addr: ServerAddr,
+    local_addr: SocketAddr,
+    mode: PluginMode,
+) -> io::Result<()> {
+    let kcp_config = config.kcp_config();
+
+    match mode {
+        PluginMode::Client => {
+            let listener = TcpListener::bind(local_addr).await?;
+            loop {
+                let (mut stream, peer_addr) = listener.accept().await?;
+                let remote_addr = remote_addr.clone();
+                tokio::spawn(async move {
+                    let result = async {
+                        let addr = resolve(&remote_addr).await?;
+                        let mut kcp = KcpStream::connect(&kcp_config, addr).await?;
+                        tokio::io::copy_bidirectional(&mut stream, &mut kcp).await
+                    };
+                    match result.await {
+                        Ok((tx, rx)) => trace!("kcp {} <-> {} closed, tx: {}, rx: {}", peer_addr, remote_addr, tx, rx),
+                        Err(err) => debug!("kcp {} <-> {} closed with error: {}", peer_addr, remote_addr, err),
+                    }
+                });
+            }
+        }
+        PluginMode::Server => {
+            let addr = resolve(&remote_addr).await?;
+            let mut listener = KcpListener::bind(kcp_config, addr).await?;
+            loop {
+                let (mut kcp, peer_addr) = listener.accept().await?;
+                tokio::spawn(async move {
+                    let result = async {
+                        let mut stream = TcpStream::connect(local_addr).await?;
+                        tokio::io::copy_bidirectional(&mut kcp, &mut stream).await
+                    };
+                    match result.await {
+                        Ok((tx, rx)) => trace!("kcp {} <-> {} closed, tx: {}, rx: {}", peer_addr, local_addr, tx, rx),
+                        Err(err) => debug!("kcp {} <-> {} closed with error: {}", peer_addr, local_addr, err),
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_plugin_opts() {
+        let config = KcpPluginConfig::from_plugin_opts(Some("sndwnd=128;mode=fast3;resend=0")).unwrap();
+        assert_eq!(config.sndwnd, 128);
+        assert_eq!(config.interval, 10);
+        assert_eq!(config.resend, 0);
+        assert!(config.nodelay);
+
+        assert_eq!(
+            KcpPluginConfig::from_plugin_opts(None).unwrap(),
+            KcpPluginConfig::default()
+        );
+        assert!(KcpPluginConfig::from_plugin_opts(Some("mode=turbo")).is_err());
+        assert!(KcpPluginConfig::from_plugin_opts(Some("datashard=10;parityshard=3")).is_err());
+        assert!(KcpPluginConfig::from_plugin_opts(Some("datashard=0")).is_ok());
+    }
+}
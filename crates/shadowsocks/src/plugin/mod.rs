@@ -13,19 +13,63 @@
 //! ```
 
 use std::{
-    io,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener},
-    process::ExitStatus,
+    collections::VecDeque,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
     time::{Duration, Instant},
 };
 
-use log::{debug, error};
-use tokio::{net::TcpStream, process::Child, time};
+use log::{Level, debug, error, info, log, trace, warn};
+use socket2::{Domain, Socket, Type};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    net::TcpStream,
+    process::Child,
+    time,
+};
 
 use crate::config::{Mode, ServerAddr};
 
+#[cfg(feature = "plugin-grpc")]
+pub use self::grpc::{GrpcConfig, GrpcConfigError, GrpcStream};
+#[cfg(feature = "plugin-kcp")]
+pub use self::kcp::{BUILTIN_KCP_PLUGIN, KcpConfigError, KcpPluginConfig};
+#[cfg(feature = "plugin-quic")]
+pub use self::quic::{BUILTIN_QUIC_PLUGIN, QuicConfigError, QuicCongestion, QuicPluginConfig};
+#[cfg(feature = "plugin-shadow-tls")]
+pub use self::shadow_tls::{ShadowTlsConfig, ShadowTlsConfigError, ShadowTlsStream};
+#[cfg(feature = "plugin-websocket")]
+pub use self::websocket::{WebSocketConfig, WebSocketConfigError, WebSocketStream};
+pub use self::{
+    builtin::{
+        BUILTIN_GRPC_PLUGIN, BUILTIN_SHADOW_TLS_PLUGIN, BUILTIN_WEBSOCKET_PLUGIN, BuiltinPlugin, BuiltinPluginError,
+        PluginStream,
+    },
+    obfs::{BUILTIN_OBFS_PLUGIN, ObfsConfig, ObfsConfigError, ObfsMode, ObfsStream},
+    transport::{
+        RegisteredTransport, StreamWrapper, Transport, TransportIo, is_transport_registered, register_transport,
+    },
+};
+
+mod builtin;
+#[cfg(feature = "plugin-grpc")]
+mod grpc;
+#[cfg(feature = "plugin-kcp")]
+mod kcp;
+mod obfs;
 mod obfs_proxy;
+#[cfg(feature = "plugin-quic")]
+mod quic;
+#[cfg(feature = "plugin-shadow-tls")]
+mod shadow_tls;
 mod ss_plugin;
+#[cfg(any(feature = "plugin-websocket", feature = "plugin-grpc", feature = "plugin-shadow-tls"))]
+mod tls;
+mod transport;
+#[cfg(feature = "plugin-websocket")]
+mod websocket;
 
 /// Config for plugin
 #[derive(Debug, Clone)]
@@ -34,6 +78,54 @@ pub struct PluginConfig {
     pub plugin_opts: Option<String>,
     pub plugin_args: Vec<String>,
     pub plugin_mode: Mode,
+    pub plugin_supervision: PluginSupervision,
+}
+
+/// Supervision of plugin subprocesses
+#[derive(Debug, Clone)]
+pub struct PluginSupervision {
+    /// Timeout of waiting for plugin to listen after started
+    pub startup_timeout: Duration,
+    /// Give up restarting if plugin has been restarted `max_restarts` times in `restart_window`
+    pub max_restarts: Option<u32>,
+    /// Window of counting restarts
+    pub restart_window: Duration,
+    /// Niceness of plugin process (Unix only)
+    pub nice: Option<i32>,
+    /// Maximum number of open files of plugin process, `RLIMIT_NOFILE` (Unix only)
+    pub max_open_files: Option<u64>,
+    /// Maximum size of virtual memory of plugin process in bytes, `RLIMIT_AS` (Unix only)
+    pub max_memory: Option<u64>,
+    /// Start plugin when the server is used for the first time, instead of at startup (clients only)
+    pub lazy: bool,
+    /// Stop lazily started plugin after it hasn't been used for this long, it is started again on demand (clients only)
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for PluginSupervision {
+    fn default() -> PluginSupervision {
+        PluginSupervision {
+            startup_timeout: Duration::from_secs(3),
+            max_restarts: None,
+            restart_window: Duration::from_secs(600),
+            nice: None,
+            max_open_files: None,
+            max_memory: None,
+            lazy: false,
+            idle_timeout: None,
+        }
+    }
+}
+
+impl PluginConfig {
+    /// Check if this is a builtin plugin or a registered transport, which runs in process instead of a subprocess
+    pub fn is_builtin(&self) -> bool {
+        self.plugin == BUILTIN_OBFS_PLUGIN
+            || self.plugin == BUILTIN_WEBSOCKET_PLUGIN
+            || self.plugin == BUILTIN_SHADOW_TLS_PLUGIN
+            || self.plugin == BUILTIN_GRPC_PLUGIN
+            || is_transport_registered(&self.plugin)
+    }
 }
 
 /// Mode of Plugin
@@ -57,12 +149,120 @@ pub enum PluginMode {
     Client,
 }
 
+/// Delay of restarting a plugin after its first exit
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+/// Maximum delay of restarting a plugin that exits repeatedly
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// Plugins that have been running for this long are considered recovered, and the delay is reset
+const RESTART_BACKOFF_RESET: Duration = Duration::from_secs(60);
+/// Interval of connecting to a starting plugin
+const CHECK_STARTED_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Event of a supervised plugin
+#[derive(Debug)]
+pub enum PluginEvent {
+    /// Plugin process exited, with its exit status or error of waiting for it
+    Exited(io::Result<ExitStatus>),
+    /// Plugin process is restarted and listening again
+    Restarted,
+    /// Plugin has been restarted too many times, and won't be restarted again
+    GaveUp,
+}
+
+/// Plugin subprocess, or builtin transport running in process like a plugin subprocess
+#[derive(Debug)]
+enum PluginProcess {
+    Child(Child),
+    #[cfg(any(feature = "plugin-kcp", feature = "plugin-quic"))]
+    Task {
+        handle: tokio::task::JoinHandle<io::Result<()>>,
+        finished: bool,
+    },
+}
+
+impl PluginProcess {
+    fn id(&self) -> Option<u32> {
+        match *self {
+            PluginProcess::Child(ref c) => c.id(),
+            #[cfg(any(feature = "plugin-kcp", feature = "plugin-quic"))]
+            PluginProcess::Task { .. } => None,
+        }
+    }
+
+    async fn wait(&mut self) -> io::Result<ExitStatus> {
+        match *self {
+            PluginProcess::Child(ref mut c) => c.wait().await,
+            #[cfg(any(feature = "plugin-kcp", feature = "plugin-quic"))]
+            PluginProcess::Task {
+                ref mut handle,
+                ref mut finished,
+            } => {
+                if *finished {
+                    return Err(task_exited_error(Ok(Ok(()))));
+                }
+                let result = handle.await;
+                *finished = true;
+                Err(task_exited_error(result))
+            }
+        }
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        match *self {
+            PluginProcess::Child(ref mut c) => c.try_wait(),
+            #[cfg(any(feature = "plugin-kcp", feature = "plugin-quic"))]
+            PluginProcess::Task {
+                ref mut handle,
+                ref mut finished,
+            } => {
+                if *finished {
+                    return Err(task_exited_error(Ok(Ok(()))));
+                }
+                match futures::FutureExt::now_or_never(handle) {
+                    None => Ok(None),
+                    Some(result) => {
+                        *finished = true;
+                        Err(task_exited_error(result))
+                    }
+                }
+            }
+        }
+    }
+
+    fn start_kill(&mut self) -> io::Result<()> {
+        match *self {
+            PluginProcess::Child(ref mut c) => c.start_kill(),
+            #[cfg(any(feature = "plugin-kcp", feature = "plugin-quic"))]
+            PluginProcess::Task { ref handle, .. } => {
+                handle.abort();
+                Ok(())
+            }
+        }
+    }
+}
+
+// Builtin transports don't have exit status, they only stop on errors
+#[cfg(any(feature = "plugin-kcp", feature = "plugin-quic"))]
+fn task_exited_error(result: Result<io::Result<()>, tokio::task::JoinError>) -> io::Error {
+    match result {
+        Ok(Ok(())) => crate::Error::Plugin("builtin transport stopped".into()).into(),
+        Ok(Err(err)) => crate::Error::Plugin(err.into()).into(),
+        Err(err) => crate::Error::Plugin(err.into()).into(),
+    }
+}
+
 /// A shadowsocks SIP004 Plugin
 #[derive(Debug)]
 pub struct Plugin {
-    process: Child,
+    process: PluginProcess,
     local_addr: SocketAddr,
     mode: Mode,
+    config: PluginConfig,
+    remote_addr: ServerAddr,
+    plugin_mode: PluginMode,
+    // Unix domain socket offered to plugin, and whether plugin is listening on it
+    unix_addr: Option<PathBuf>,
+    unix_listening: bool,
 }
 
 impl Plugin {
@@ -71,23 +271,97 @@ impl Plugin {
     /// `PluginMode::Client`: Plugin listens to `local_addr` and send data to `remote_addr`, client should send data to `local_addr`
     /// `PluginMode::Server`: Plugin listens to `remote_addr` and send data to `local_addr`, server should listen to `local_addr`
     pub fn start(c: &PluginConfig, remote_addr: &ServerAddr, mode: PluginMode) -> io::Result<Plugin> {
-        let loop_ip = match remote_addr {
-            ServerAddr::SocketAddr(sa) => match sa.ip() {
-                IpAddr::V4(..) => Ipv4Addr::LOCALHOST.into(),
-                IpAddr::V6(..) => Ipv6Addr::LOCALHOST.into(),
-            },
-            ServerAddr::DomainName(..) => Ipv4Addr::LOCALHOST.into(),
+        Plugin::start_with_unix(c, remote_addr, mode, true)
+    }
+
+    /// Start subprocesses of chained plugins, builtin plugins in `plugins` are skipped
+    ///
+    /// `plugins` are chained in order, from shadowsocks to network. The last plugin works with `remote_addr`,
+    /// and the others take `local_addr` of the plugin after them as their `remote_addr`.
+    /// Returns started plugins in the same order, shadowsocks should work with `local_addr` of the first one.
+    pub fn start_chain(
+        plugins: &[PluginConfig],
+        remote_addr: &ServerAddr,
+        mode: PluginMode,
+    ) -> io::Result<Vec<Plugin>> {
+        let external = plugins.iter().filter(|p| !p.is_builtin()).collect::<Vec<_>>();
+
+        let mut started = Vec::with_capacity(external.len());
+        let mut remote_addr = remote_addr.clone();
+        for (idx, c) in external.iter().enumerate().rev() {
+            // Plugins after the first one are connected by plugins, which only support TCP
+            let plugin = Plugin::start_with_unix(c, &remote_addr, mode, idx == 0)?;
+            remote_addr = plugin.local_addr().into();
+            started.push(plugin);
+        }
+        started.reverse();
+
+        Ok(started)
+    }
+
+    /// Find a free local address for a plugin working with `remote_addr`, to be started later by `start_on`
+    pub fn reserve_local_addr(c: &PluginConfig, remote_addr: &ServerAddr) -> io::Result<SocketAddr> {
+        get_local_port(get_loop_ip(remote_addr), c.plugin_mode)
+    }
+
+    /// Reserve a free local address for a plugin working with `remote_addr`, to be started later by `start_on`
+    ///
+    /// Other processes couldn't take the address until the reservation is dropped, which should be right before the
+    /// plugin is started.
+    pub fn reserve_local_port(c: &PluginConfig, remote_addr: &ServerAddr) -> io::Result<ReservedLocalAddr> {
+        const MAX_RETRIES: usize = 16;
+
+        let loop_ip = get_loop_ip(remote_addr);
+        let mut last_err = None;
+        for _ in 0..MAX_RETRIES {
+            match ReservedLocalAddr::reserve(SocketAddr::new(loop_ip, 0), c.plugin_mode) {
+                Ok(r) => return Ok(r),
+                Err(err) => {
+                    trace!("plugin local port is not available, error: {}", err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("last_err"))
+    }
+
+    /// Start a plugin subprocess on `local_addr`, reserved by `reserve_local_addr`
+    ///
+    /// Unix domain sockets are not offered to the plugin, shadowsocks should always work with `local_addr`.
+    pub fn start_on(
+        c: &PluginConfig,
+        remote_addr: &ServerAddr,
+        local_addr: SocketAddr,
+        mode: PluginMode,
+    ) -> io::Result<Plugin> {
+        Plugin::start_at(c, remote_addr, local_addr, None, mode)
+    }
+
+    fn start_with_unix(c: &PluginConfig, remote_addr: &ServerAddr, mode: PluginMode, unix: bool) -> io::Result<Plugin> {
+        let local_addr = Plugin::reserve_local_addr(c, remote_addr)?;
+        let unix_addr = if unix {
+            get_unix_addr(c, &local_addr, mode)
+        } else {
+            None
         };
 
-        let local_addr = get_local_port(loop_ip)?;
+        Plugin::start_at(c, remote_addr, local_addr, unix_addr, mode)
+    }
 
-        match start_plugin(c, remote_addr, &local_addr, mode) {
+    fn start_at(
+        c: &PluginConfig,
+        remote_addr: &ServerAddr,
+        local_addr: SocketAddr,
+        unix_addr: Option<PathBuf>,
+        mode: PluginMode,
+    ) -> io::Result<Plugin> {
+        match start_plugin(c, remote_addr, &local_addr, unix_addr.as_deref(), mode) {
             Err(err) => {
                 error!(
                     "failed to start plugin \"{}\" for server {}, err: {}",
                     c.plugin, remote_addr, err
                 );
-                Err(err)
+                Err(crate::Error::Plugin(err.into()).into())
             }
             Ok(process) => {
                 match mode {
@@ -117,6 +391,11 @@ impl Plugin {
                     process,
                     local_addr,
                     mode: c.plugin_mode,
+                    config: c.clone(),
+                    remote_addr: remote_addr.clone(),
+                    plugin_mode: mode,
+                    unix_addr,
+                    unix_listening: false,
                 })
             }
         }
@@ -127,6 +406,111 @@ impl Plugin {
         self.process.wait().await
     }
 
+    /// Keep plugin running, restart it with exponential backoff whenever it exits
+    ///
+    /// The plugin is restarted on the same `local_addr`. `on_event` is called when the plugin exits,
+    /// and when it is listening again after restart. Returns only after giving up restarting,
+    /// when it has exceeded `max_restarts` of its `PluginSupervision`.
+    pub async fn supervise<F>(mut self, mut on_event: F)
+    where
+        F: FnMut(PluginEvent),
+    {
+        let supervision = self.config.plugin_supervision.clone();
+        let mut backoff = RESTART_BACKOFF_MIN;
+        let mut restarts = VecDeque::new();
+
+        loop {
+            let started_time = Instant::now();
+            let result = self.process.wait().await;
+            match result {
+                Ok(ref status) => error!(
+                    "plugin \"{}\" for server {} exited with status: {}",
+                    self.config.plugin, self.remote_addr, status
+                ),
+                Err(ref err) => error!(
+                    "plugin \"{}\" for server {} exited with error: {}",
+                    self.config.plugin, self.remote_addr, err
+                ),
+            }
+            on_event(PluginEvent::Exited(result));
+
+            if started_time.elapsed() >= RESTART_BACKOFF_RESET {
+                backoff = RESTART_BACKOFF_MIN;
+            }
+
+            loop {
+                if let Some(max_restarts) = supervision.max_restarts {
+                    let now = Instant::now();
+                    while restarts
+                        .front()
+                        .is_some_and(|t| now.duration_since(*t) >= supervision.restart_window)
+                    {
+                        restarts.pop_front();
+                    }
+
+                    if restarts.len() >= max_restarts as usize {
+                        error!(
+                            "plugin \"{}\" for server {} has been restarted {} times in {:?}, giving up",
+                            self.config.plugin,
+                            self.remote_addr,
+                            restarts.len(),
+                            supervision.restart_window
+                        );
+                        on_event(PluginEvent::GaveUp);
+                        return;
+                    }
+                    restarts.push_back(now);
+                }
+
+                debug!(
+                    "restarting plugin \"{}\" for server {} in {:?}",
+                    self.config.plugin, self.remote_addr, backoff
+                );
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+
+                match start_plugin(
+                    &self.config,
+                    &self.remote_addr,
+                    &self.local_addr,
+                    self.unix_addr.as_deref(),
+                    self.plugin_mode,
+                ) {
+                    Ok(process) => {
+                        self.process = process;
+                        break;
+                    }
+                    Err(err) => {
+                        error!(
+                            "failed to restart plugin \"{}\" for server {}, err: {}",
+                            self.config.plugin, self.remote_addr, err
+                        );
+                    }
+                }
+            }
+
+            match self.check_started(supervision.startup_timeout).await {
+                Ok(true) => {}
+                Ok(false) => warn!(
+                    "plugin \"{}\" for server {} isn't listening on {} after restart",
+                    self.config.plugin, self.remote_addr, self.local_addr
+                ),
+                Err(err) => {
+                    // Exit status will be reported by the next wait()
+                    error!("restarted plugin for server {} failed, {}", self.remote_addr, err);
+                    continue;
+                }
+            }
+            info!(
+                "restarted plugin \"{}\" for server {} ({})",
+                self.config.plugin,
+                self.remote_addr,
+                self.process.id().unwrap_or(0)
+            );
+            on_event(PluginEvent::Restarted);
+        }
+    }
+
     /// Check if plugin have been started
     pub async fn wait_started(&self, timeout: Duration) -> bool {
         // Only test started with TCP connect()
@@ -158,10 +542,77 @@ impl Plugin {
         }
     }
 
+    /// Check if plugin is listening, and is still running
+    ///
+    /// Returns error if plugin exits before listening, which is usually caused by invalid `plugin_opts`.
+    /// Returns `false` if plugin isn't listening on `local_addr` in `timeout`.
+    pub async fn check_started(&mut self, timeout: Duration) -> io::Result<bool> {
+        let start_time = Instant::now();
+
+        loop {
+            if let Some(status) = self.process.try_wait()? {
+                let err = io::Error::from(crate::Error::Plugin(
+                    format!(
+                        "plugin \"{}\" exited with status {} before listening on {}, check its plugin_opts",
+                        self.config.plugin, status, self.local_addr
+                    )
+                    .into(),
+                ));
+                return Err(err);
+            }
+
+            // UDP ports couldn't be tested
+            if !self.mode.enable_tcp() {
+                return Ok(true);
+            }
+
+            #[cfg(unix)]
+            if let Some(ref unix_addr) = self.unix_addr {
+                if tokio::net::UnixStream::connect(unix_addr).await.is_ok() {
+                    debug!(
+                        "plugin \"{}\" for server {} is listening on {}",
+                        self.config.plugin,
+                        self.remote_addr,
+                        unix_addr.display()
+                    );
+                    self.unix_listening = true;
+                    return Ok(true);
+                }
+            }
+
+            let elapsed_time = start_time.elapsed();
+            if elapsed_time >= timeout {
+                return Ok(false);
+            }
+
+            match time::timeout(timeout - elapsed_time, TcpStream::connect(self.local_addr)).await {
+                Ok(Ok(..)) => return Ok(true),
+                Ok(Err(..)) => time::sleep(CHECK_STARTED_INTERVAL).await,
+                Err(..) => return Ok(false),
+            }
+        }
+    }
+
+    /// Get config of plugin
+    pub fn config(&self) -> &PluginConfig {
+        &self.config
+    }
+
     /// Get listen address of plugin
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
+
+    /// Get Unix domain socket that plugin is listening on
+    ///
+    /// It is only available after `check_started` succeeded, and if plugin supports listening on `SS_LOCAL_PATH`.
+    pub fn unix_addr(&self) -> Option<&Path> {
+        if self.unix_listening {
+            self.unix_addr.as_deref()
+        } else {
+            None
+        }
+    }
 }
 
 impl Drop for Plugin {
@@ -231,21 +682,330 @@ impl Drop for Plugin {
         if !terminated && self.process.start_kill().is_ok() {
             debug!("killed plugin process {:?}", self.process.id());
         }
+
+        if let Some(ref unix_addr) = self.unix_addr {
+            let _ = std::fs::remove_file(unix_addr);
+        }
     }
 }
 
-fn start_plugin(plugin: &PluginConfig, remote: &ServerAddr, local: &SocketAddr, mode: PluginMode) -> io::Result<Child> {
+fn start_plugin(
+    plugin: &PluginConfig,
+    remote: &ServerAddr,
+    local: &SocketAddr,
+    unix: Option<&Path>,
+    mode: PluginMode,
+) -> io::Result<PluginProcess> {
+    #[cfg(feature = "plugin-kcp")]
+    if plugin.plugin == kcp::BUILTIN_KCP_PLUGIN {
+        let config = kcp::KcpPluginConfig::from_plugin_opts(plugin.plugin_opts.as_deref())
+            .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+        let handle = tokio::spawn(kcp::run(config, remote.clone(), *local, mode));
+        return Ok(PluginProcess::Task {
+            handle,
+            finished: false,
+        });
+    }
+
+    #[cfg(feature = "plugin-quic")]
+    if plugin.plugin == quic::BUILTIN_QUIC_PLUGIN {
+        let config = quic::QuicPluginConfig::from_plugin_opts(plugin.plugin_opts.as_deref())
+            .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+        let handle = tokio::spawn(quic::run(config, remote.clone(), *local, mode, plugin.plugin_mode));
+        return Ok(PluginProcess::Task {
+            handle,
+            finished: false,
+        });
+    }
+
     let mut cmd = if plugin.plugin == "obfsproxy" {
         obfs_proxy::plugin_cmd(plugin, remote, local, mode)
     } else {
-        ss_plugin::plugin_cmd(plugin, remote, local, mode)
+        if let Some(unix) = unix {
+            // Stale socket left by the previous plugin process
+            let _ = std::fs::remove_file(unix);
+        }
+        ss_plugin::plugin_cmd(plugin, remote, local, unix, mode)
+    };
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    #[cfg(unix)]
+    set_process_limits(&mut cmd, &plugin.plugin_supervision);
+    #[cfg(unix)]
+    set_process_credentials(&mut cmd);
+
+    let mut process = cmd.spawn()?;
+
+    // Outputs of plugins are logged with target `shadowsocks::plugin::<name>`
+    let name = Path::new(&plugin.plugin)
+        .file_stem()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| plugin.plugin.clone());
+    let target = format!("shadowsocks::plugin::{name}");
+    if let Some(stdout) = process.stdout.take() {
+        tokio::spawn(log_output(stdout, target.clone(), Level::Info));
+    }
+    if let Some(stderr) = process.stderr.take() {
+        tokio::spawn(log_output(stderr, target, Level::Warn));
+    }
+
+    Ok(PluginProcess::Child(process))
+}
+
+/// Apply niceness and resource limits to the plugin process before exec
+#[cfg(unix)]
+fn set_process_limits(cmd: &mut tokio::process::Command, supervision: &PluginSupervision) {
+    let nice = supervision.nice;
+    let limits = [
+        (libc::RLIMIT_NOFILE, supervision.max_open_files),
+        (libc::RLIMIT_AS, supervision.max_memory),
+    ];
+    if nice.is_none() && limits.iter().all(|(_, l)| l.is_none()) {
+        return;
+    }
+
+    // SAFETY: Only async-signal-safe functions are called between fork() and exec()
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(nice) = nice {
+                if libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            for (resource, limit) in limits {
+                if let Some(limit) = limit {
+                    let rlim = libc::rlimit {
+                        rlim_cur: limit as libc::rlim_t,
+                        rlim_max: limit as libc::rlim_t,
+                    };
+                    if libc::setrlimit(resource, &rlim) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            }
+
+            Ok(())
+        });
+    }
+}
+
+/// User and groups which plugin subprocesses are switched to before exec
+#[cfg(unix)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginCredentials {
+    /// User ID, `None` for keeping the current user
+    pub uid: Option<libc::uid_t>,
+    /// Primary group ID
+    pub gid: libc::gid_t,
+    /// Supplementary group IDs
+    pub groups: Vec<libc::gid_t>,
+}
+
+#[cfg(unix)]
+static PLUGIN_CREDENTIALS: std::sync::Mutex<Option<PluginCredentials>> = std::sync::Mutex::new(None);
+
+/// Switch plugin subprocesses started afterwards to `credentials`
+///
+/// Plugins are started before the process drops its own privileges (after listeners are bound), so they have to
+/// switch by themselves. Switching is skipped if the process is not running as root anymore.
+#[cfg(unix)]
+pub fn set_plugin_credentials(credentials: Option<PluginCredentials>) {
+    *PLUGIN_CREDENTIALS.lock().unwrap_or_else(|err| err.into_inner()) = credentials;
+}
+
+#[cfg(unix)]
+fn set_process_credentials(cmd: &mut tokio::process::Command) {
+    let credentials = match *PLUGIN_CREDENTIALS.lock().unwrap_or_else(|err| err.into_inner()) {
+        Some(ref c) => c.clone(),
+        None => return,
+    };
+
+    // SAFETY: Only async-signal-safe functions are called between fork() and exec(), `credentials` is not
+    // allocated or freed in the child
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::geteuid() != 0 {
+                return Ok(());
+            }
+
+            // Groups must be changed first, because it is not permitted anymore after setuid
+            if libc::setgroups(credentials.groups.len() as _, credentials.groups.as_ptr()) != 0
+                || libc::setgid(credentials.gid) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            if let Some(uid) = credentials.uid {
+                if libc::setuid(uid) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            Ok(())
+        });
+    }
+}
+
+async fn log_output<R>(output: R, target: String, level: Level)
+where
+    R: AsyncRead + Unpin,
+{
+    // Plugins will be blocked if their output is not drained, so read until EOF, even if it isn't UTF-8
+    let mut reader = BufReader::new(output);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line).await {
+            Ok(0) => break,
+            Ok(..) => {
+                let line = String::from_utf8_lossy(&line);
+                log!(target: &target, level, "{}", line.trim_end_matches(['\r', '\n']));
+            }
+            Err(err) => {
+                debug!("failed to read output of plugin {}, error: {}", target, err);
+                let _ = tokio::io::copy(&mut reader, &mut tokio::io::sink()).await;
+                break;
+            }
+        }
+    }
+}
+
+/// Unix domain socket offered to plugins of clients, which are used instead of `local_addr` if plugins listen on them
+#[cfg(unix)]
+fn get_unix_addr(plugin: &PluginConfig, local_addr: &SocketAddr, mode: PluginMode) -> Option<PathBuf> {
+    // obfsproxy and builtin transports don't support `SS_LOCAL_PATH`
+    if !matches!(mode, PluginMode::Client)
+        || !plugin.plugin_mode.enable_tcp()
+        || plugin.plugin == "obfsproxy"
+        || plugin.plugin.starts_with("builtin-")
+    {
+        return None;
+    }
+
+    let dir = match get_runtime_dir() {
+        Ok(d) => d,
+        Err(err) => {
+            warn!(
+                "plugin \"{}\" couldn't be offered a unix domain socket, error: {}",
+                plugin.plugin, err
+            );
+            return None;
+        }
+    };
+
+    let name = format!("plugin-{}-{}.sock", std::process::id(), local_addr.port());
+    Some(dir.join(name))
+}
+
+/// Directory for sockets of plugins, only accessible by the current user
+///
+/// `$XDG_RUNTIME_DIR/shadowsocks`, or `shadowsocks-<uid>` in the temporary directory.
+#[cfg(unix)]
+fn get_runtime_dir() -> io::Result<PathBuf> {
+    use std::{
+        fs::{self, DirBuilder},
+        os::unix::fs::{DirBuilderExt, MetadataExt},
     };
-    cmd.spawn()
+
+    let uid = unsafe { libc::geteuid() };
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(d) if !d.is_empty() => PathBuf::from(d).join("shadowsocks"),
+        _ => std::env::temp_dir().join(format!("shadowsocks-{uid}")),
+    };
+
+    match DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(err),
+    }
+
+    // The temporary directory is shared, the directory may be created by others in advance
+    let metadata = fs::symlink_metadata(&dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{} is not a private directory of the current user", dir.display()),
+        ));
+    }
+
+    Ok(dir)
+}
+
+#[cfg(not(unix))]
+fn get_unix_addr(_: &PluginConfig, _: &SocketAddr, _: PluginMode) -> Option<PathBuf> {
+    None
+}
+
+fn get_loop_ip(remote_addr: &ServerAddr) -> IpAddr {
+    match remote_addr {
+        ServerAddr::SocketAddr(sa) => match sa.ip() {
+            IpAddr::V4(..) => Ipv4Addr::LOCALHOST.into(),
+            IpAddr::V6(..) => Ipv6Addr::LOCALHOST.into(),
+        },
+        ServerAddr::DomainName(..) => Ipv4Addr::LOCALHOST.into(),
+    }
+}
+
+/// Local address held for a plugin started later
+///
+/// Sockets are bound without listening, so connections to the address are refused until the plugin is started.
+#[derive(Debug)]
+pub struct ReservedLocalAddr {
+    addr: SocketAddr,
+    _sockets: Vec<Socket>,
 }
 
-fn get_local_port(loop_ip: IpAddr) -> io::Result<SocketAddr> {
-    let listener = TcpListener::bind(SocketAddr::new(loop_ip, 0))?;
-    listener.local_addr()
+impl ReservedLocalAddr {
+    /// Bind `addr` for TCP, and also UDP if `mode` enables it, a random port is chosen if the port is `0`
+    pub fn reserve(addr: SocketAddr, mode: Mode) -> io::Result<ReservedLocalAddr> {
+        let tcp = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        tcp.bind(&addr.into())?;
+        let addr = tcp
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| io::Error::new(ErrorKind::Other, "reserved address is not an inet address"))?;
+
+        let mut sockets = vec![tcp];
+        if mode.enable_udp() {
+            let udp = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+            udp.bind(&addr.into())?;
+            sockets.push(udp);
+        }
+
+        Ok(ReservedLocalAddr {
+            addr,
+            _sockets: sockets,
+        })
+    }
+
+    /// The address reserved
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// Find a free port for plugin to listen on
+///
+/// SIP003u plugins listen on the same port for both TCP and UDP, so the port must be free in both.
+fn get_local_port(loop_ip: IpAddr, mode: Mode) -> io::Result<SocketAddr> {
+    const MAX_RETRIES: usize = 16;
+
+    let mut last_err = None;
+    for _ in 0..MAX_RETRIES {
+        let listener = TcpListener::bind(SocketAddr::new(loop_ip, 0))?;
+        let addr = listener.local_addr()?;
+        if !mode.enable_udp() {
+            return Ok(addr);
+        }
+
+        match UdpSocket::bind(addr) {
+            Ok(..) => return Ok(addr),
+            Err(err) => {
+                trace!("plugin local port {} is not available for UDP, error: {}", addr, err);
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.expect("last_err"))
 }
 
 #[cfg(test)]
@@ -255,7 +1015,38 @@ mod test {
     #[test]
     fn generate_random_port() {
         let loop_ip = Ipv4Addr::LOCALHOST.into();
-        let addr = get_local_port(loop_ip).unwrap();
+        let addr = get_local_port(loop_ip, Mode::TcpOnly).unwrap();
         println!("{addr:?}");
     }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn plugin_credentials() {
+        // Only root could switch to another user
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        set_plugin_credentials(Some(PluginCredentials {
+            uid: Some(65534),
+            gid: 65534,
+            groups: vec![65534],
+        }));
+        let mut cmd = tokio::process::Command::new("id");
+        cmd.arg("-u").stdout(Stdio::piped());
+        set_process_credentials(&mut cmd);
+        set_plugin_credentials(None);
+
+        let output = cmd.output().await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "65534");
+    }
+
+    #[test]
+    fn generate_random_port_for_udp() {
+        let loop_ip = Ipv4Addr::LOCALHOST.into();
+        let addr = get_local_port(loop_ip, Mode::TcpAndUdp).unwrap();
+        let _tcp = TcpListener::bind(addr).unwrap();
+        let _udp = UdpSocket::bind(addr).unwrap();
+    }
 }
@@ -0,0 +1,759 @@
+//! Builtin simple-obfs
+//!
+//! Obfuscates connections in process, instead of running `obfs-local` / `obfs-server` as plugin subprocesses.
+//! Enabled by `"plugin": "builtin-obfs"`, with `plugin_opts` in the same form of simple-obfs:
+//!
+//! ```plain
+//! obfs=http;obfs-host=www.bing.com;obfs-uri=/
+//! obfs=tls;obfs-host=www.bing.com
+//! ```
+//!
+//! - `http`: The first request and response are disguised as a WebSocket upgrade
+//! - `tls`: The first request and response are disguised as a TLS handshake, and data are sent as TLS application data
+
+use std::{
+    fmt::{self, Write},
+    io::{self, ErrorKind},
+    pin::Pin,
+    str::FromStr,
+    task::{self, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::Engine as _;
+use bytes::{Buf, BufMut, BytesMut};
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{config::ServerAddr, crypto::utils::random_iv_or_salt};
+
+/// Name of the builtin obfs plugin
+pub const BUILTIN_OBFS_PLUGIN: &str = "builtin-obfs";
+
+/// Maximum payload size of TLS records
+const TLS_MAX_RECORD_SIZE: usize = 16 * 1024;
+/// Maximum size of the HTTP header
+const HTTP_MAX_HEADER_SIZE: usize = 8 * 1024;
+
+const TLS_CONTENT_CHANGE_CIPHER_SPEC: u8 = 0x14;
+const TLS_CONTENT_HANDSHAKE: u8 = 0x16;
+const TLS_CONTENT_APPLICATION_DATA: u8 = 0x17;
+
+const TLS_HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXT_SESSION_TICKET: u16 = 0x0023;
+
+// Cipher suites of the ClientHello sent by simple-obfs
+const TLS_CIPHER_SUITES: [u16; 28] = [
+    0xc02c, 0xc030, 0x009f, 0xcca9, 0xcca8, 0xccaa, 0xc02b, 0xc02f, 0x009e, 0xc024, 0xc028, 0x006b, 0xc023, 0xc027,
+    0x0067, 0xc00a, 0xc014, 0x0039, 0xc009, 0xc013, 0x0033, 0x009d, 0x009c, 0x003d, 0x003c, 0x0035, 0x002f, 0x00ff,
+];
+
+// ec_point_formats, elliptic_curves, signature_algorithms, encrypt_then_mac, extended_master_secret
+const TLS_CLIENT_HELLO_OTHER_EXTS: [u8; 66] = [
+    0x00, 0x0b, 0x00, 0x04, 0x03, 0x01, 0x00, 0x02, //
+    0x00, 0x0a, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x19, 0x00, 0x18, //
+    0x00, 0x0d, 0x00, 0x20, 0x00, 0x1e, 0x06, 0x01, 0x06, 0x02, 0x06, 0x03, 0x05, 0x01, 0x05, 0x02, 0x05, 0x03, 0x04,
+    0x01, 0x04, 0x02, 0x04, 0x03, 0x03, 0x01, 0x03, 0x02, 0x03, 0x03, 0x02, 0x01, 0x02, 0x02, 0x02, 0x03, //
+    0x00, 0x16, 0x00, 0x00, //
+    0x00, 0x17, 0x00, 0x00,
+];
+
+// renegotiation_info, extended_master_secret, ec_point_formats
+const TLS_SERVER_HELLO_EXTS: [u8; 15] = [
+    0xff, 0x01, 0x00, 0x01, 0x00, //
+    0x00, 0x17, 0x00, 0x00, //
+    0x00, 0x0b, 0x00, 0x02, 0x01, 0x00,
+];
+
+/// Obfuscating method
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObfsMode {
+    /// Disguised as a WebSocket upgrade
+    Http,
+    /// Disguised as TLS 1.2
+    Tls,
+}
+
+impl ObfsMode {
+    /// String representation
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ObfsMode::Http => "http",
+            ObfsMode::Tls => "tls",
+        }
+    }
+}
+
+impl fmt::Display for ObfsMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ObfsMode {
+    type Err = ObfsConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(ObfsMode::Http),
+            "tls" => Ok(ObfsMode::Tls),
+            _ => Err(ObfsConfigError::InvalidMode(s.to_owned())),
+        }
+    }
+}
+
+/// Errors of parsing `ObfsConfig`
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ObfsConfigError {
+    /// `obfs` is not specified
+    #[error("missing obfs in plugin_opts, should be \"http\" or \"tls\"")]
+    MissingMode,
+    /// Unknown `obfs`
+    #[error("invalid obfs \"{0}\", should be \"http\" or \"tls\"")]
+    InvalidMode(String),
+    /// Unknown option
+    #[error("unrecognized obfs option \"{0}\"")]
+    InvalidOption(String),
+}
+
+/// Configuration of builtin obfs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObfsConfig {
+    /// Obfuscating method
+    pub mode: ObfsMode,
+    /// Host in HTTP requests or TLS SNI, server's host by default
+    pub host: Option<String>,
+    /// Path of HTTP requests, `/` by default
+    pub uri: Option<String>,
+}
+
+impl ObfsConfig {
+    /// Parse from `plugin_opts`, like `obfs=http;obfs-host=www.bing.com`
+    pub fn from_plugin_opts(opts: Option<&str>) -> Result<ObfsConfig, ObfsConfigError> {
+        let mut mode = None;
+        let mut host = None;
+        let mut uri = None;
+
+        for opt in opts.unwrap_or_default().split(';') {
+            let opt = opt.trim();
+            if opt.is_empty() {
+                continue;
+            }
+
+            match opt.split_once('=') {
+                Some(("obfs", value)) => mode = Some(value.parse::<ObfsMode>()?),
+                Some(("obfs-host", value)) => host = Some(value.to_owned()),
+                Some(("obfs-uri", value)) => uri = Some(value.to_owned()),
+                _ => return Err(ObfsConfigError::InvalidOption(opt.to_owned())),
+            }
+        }
+
+        Ok(ObfsConfig {
+            mode: mode.ok_or(ObfsConfigError::MissingMode)?,
+            host,
+            uri,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ObfsSide {
+    Client,
+    Server,
+}
+
+#[derive(Debug)]
+struct ObfsState {
+    mode: ObfsMode,
+    side: ObfsSide,
+    host: String,
+    uri: String,
+    // Handshake of the peer is received
+    read_handshaked: bool,
+    // Count of writes, handshakes are sent in the first writes
+    write_count: usize,
+    // Received bytes not decoded yet
+    read_buf: BytesMut,
+    // Decoded data
+    data_buf: BytesMut,
+    // Encoded bytes not sent yet, and the length of data they carry
+    write_buf: BytesMut,
+    write_len: usize,
+    session_id: [u8; 32],
+}
+
+/// Stream obfuscated by builtin obfs
+#[derive(Debug)]
+pub struct ObfsStream<S> {
+    stream: S,
+    obfs: Box<ObfsState>,
+}
+
+impl<S> ObfsStream<S> {
+    /// Create a client stream connected to `server_addr`
+    pub fn new_client(stream: S, config: &ObfsConfig, server_addr: &ServerAddr) -> ObfsStream<S> {
+        let host = match config.host {
+            Some(ref host) => host.clone(),
+            None => server_addr.host(),
+        };
+        let port = server_addr.port();
+        // simple-obfs sends port in Host if it is not the default one
+        let host = if config.mode == ObfsMode::Http && port != 80 {
+            format!("{host}:{port}")
+        } else {
+            host
+        };
+
+        ObfsStream::new(stream, config, ObfsSide::Client, host)
+    }
+
+    /// Create a server stream accepted from clients
+    pub fn new_server(stream: S, config: &ObfsConfig) -> ObfsStream<S> {
+        ObfsStream::new(stream, config, ObfsSide::Server, String::new())
+    }
+
+    fn new(stream: S, config: &ObfsConfig, side: ObfsSide, host: String) -> ObfsStream<S> {
+        let mut session_id = [0u8; 32];
+        random_iv_or_salt(&mut session_id);
+
+        ObfsStream {
+            stream,
+            obfs: Box::new(ObfsState {
+                mode: config.mode,
+                side,
+                host,
+                uri: config.uri.clone().unwrap_or_else(|| "/".to_owned()),
+                read_handshaked: false,
+                write_count: 0,
+                read_buf: BytesMut::new(),
+                data_buf: BytesMut::new(),
+                write_buf: BytesMut::new(),
+                write_len: 0,
+                session_id,
+            }),
+        }
+    }
+
+    /// Get reference to the underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Get mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consumes the `ObfsStream` and return the underlying stream
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl ObfsState {
+    fn read_passthrough(&self) -> bool {
+        self.mode == ObfsMode::Http && self.read_handshaked && self.read_buf.is_empty()
+    }
+
+    fn write_passthrough(&self) -> bool {
+        self.mode == ObfsMode::Http && self.write_count > 0
+    }
+
+    /// Decode received bytes into `data_buf`, returns `false` if more bytes are required
+    fn decode(&mut self) -> io::Result<bool> {
+        match self.mode {
+            ObfsMode::Http => self.decode_http(),
+            ObfsMode::Tls => self.decode_tls(),
+        }
+    }
+
+    fn decode_http(&mut self) -> io::Result<bool> {
+        if self.read_buf.is_empty() {
+            return Ok(false);
+        }
+
+        if !self.read_handshaked {
+            let header_len = match self.read_buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(pos) => pos + 4,
+                None => {
+                    if self.read_buf.len() > HTTP_MAX_HEADER_SIZE {
+                        return Err(io::Error::new(ErrorKind::InvalidData, "obfs http header too long"));
+                    }
+                    return Ok(false);
+                }
+            };
+
+            let expected: &[u8] = match self.side {
+                ObfsSide::Client => b"HTTP/1.1 101",
+                ObfsSide::Server => b"GET ",
+            };
+            if !self.read_buf.starts_with(expected) {
+                return Err(io::Error::new(ErrorKind::InvalidData, "obfs http header invalid"));
+            }
+
+            self.read_buf.advance(header_len);
+            self.read_handshaked = true;
+        }
+
+        let data = self.read_buf.split();
+        self.data_buf.unsplit(data);
+        Ok(true)
+    }
+
+    fn decode_tls(&mut self) -> io::Result<bool> {
+        if self.read_buf.len() < 5 {
+            return Ok(false);
+        }
+
+        let content_type = self.read_buf[0];
+        let length = u16::from_be_bytes([self.read_buf[3], self.read_buf[4]]) as usize;
+        if length > TLS_MAX_RECORD_SIZE + 2048 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "obfs tls record too long"));
+        }
+        if self.read_buf.len() < 5 + length {
+            return Ok(false);
+        }
+
+        let mut record = self.read_buf.split_to(5 + length);
+        record.advance(5);
+
+        match content_type {
+            TLS_CONTENT_APPLICATION_DATA => {
+                self.data_buf.unsplit(record);
+            }
+            TLS_CONTENT_HANDSHAKE if self.side == ObfsSide::Server && !self.read_handshaked => {
+                let (session_id, ticket) = match parse_client_hello(&record) {
+                    Some(h) => h,
+                    None => return Err(io::Error::new(ErrorKind::InvalidData, "obfs tls invalid client hello")),
+                };
+                if session_id.len() == self.session_id.len() {
+                    self.session_id.copy_from_slice(session_id);
+                }
+                self.data_buf.extend_from_slice(ticket);
+                self.read_handshaked = true;
+            }
+            TLS_CONTENT_HANDSHAKE | TLS_CONTENT_CHANGE_CIPHER_SPEC => {
+                // ServerHello, ChangeCipherSpec and Finished carry no data
+                self.read_handshaked = true;
+            }
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("obfs tls unexpected content type {content_type:#x}"),
+                ));
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Encode `data` into `write_buf`, returns length of `data` encoded
+    fn encode(&mut self, data: &[u8]) -> usize {
+        let data = &data[..data.len().min(TLS_MAX_RECORD_SIZE)];
+
+        match (self.mode, self.side, self.write_count) {
+            (ObfsMode::Http, ObfsSide::Client, 0) => self.encode_http_request(data),
+            (ObfsMode::Http, ObfsSide::Server, 0) => self.encode_http_response(data),
+            (ObfsMode::Http, ..) => self.write_buf.extend_from_slice(data),
+            (ObfsMode::Tls, ObfsSide::Client, 0) => self.encode_tls_client_hello(data),
+            (ObfsMode::Tls, ObfsSide::Client, 1) => {
+                self.encode_tls_finished();
+                encode_tls_record(&mut self.write_buf, TLS_CONTENT_APPLICATION_DATA, data);
+            }
+            (ObfsMode::Tls, ObfsSide::Server, 0) => {
+                self.encode_tls_server_hello();
+                self.encode_tls_finished();
+                encode_tls_record(&mut self.write_buf, TLS_CONTENT_APPLICATION_DATA, data);
+            }
+            (ObfsMode::Tls, ..) => encode_tls_record(&mut self.write_buf, TLS_CONTENT_APPLICATION_DATA, data),
+        }
+
+        self.write_count += 1;
+        self.write_len = data.len();
+        data.len()
+    }
+
+    fn encode_http_request(&mut self, data: &[u8]) {
+        let mut key = [0u8; 16];
+        random_iv_or_salt(&mut key);
+        let key = base64::engine::general_purpose::STANDARD.encode(key);
+
+        let mut header = String::new();
+        let _ = write!(
+            header,
+            "GET {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             User-Agent: curl/7.{}.{}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Content-Length: {}\r\n\
+             \r\n",
+            self.uri,
+            self.host,
+            random_u8() % 51,
+            random_u8() % 2,
+            key,
+            data.len()
+        );
+
+        self.write_buf.extend_from_slice(header.as_bytes());
+        self.write_buf.extend_from_slice(data);
+    }
+
+    fn encode_http_response(&mut self, data: &[u8]) {
+        let mut key = [0u8; 20];
+        random_iv_or_salt(&mut key);
+        let key = base64::engine::general_purpose::STANDARD.encode(key);
+
+        let mut header = String::new();
+        let _ = write!(
+            header,
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Server: nginx/1.{}.{}\r\n\
+             Date: {}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\
+             \r\n",
+            random_u8() % 11,
+            random_u8() % 12,
+            http_date(SystemTime::now()),
+            key
+        );
+
+        self.write_buf.extend_from_slice(header.as_bytes());
+        self.write_buf.extend_from_slice(data);
+    }
+
+    fn encode_tls_client_hello(&mut self, data: &[u8]) {
+        let host = self.host.as_bytes();
+
+        // session_ticket, server_name, and the others
+        let ext_len = (4 + data.len()) + (9 + host.len()) + TLS_CLIENT_HELLO_OTHER_EXTS.len();
+        let hello_len = 2 + 32 + 1 + 32 + 2 + TLS_CIPHER_SUITES.len() * 2 + 2 + 2 + ext_len;
+
+        let buf = &mut self.write_buf;
+        buf.reserve(9 + hello_len);
+
+        buf.put_u8(TLS_CONTENT_HANDSHAKE);
+        buf.put_u16(0x0301);
+        buf.put_u16((4 + hello_len) as u16);
+
+        buf.put_u8(TLS_HANDSHAKE_CLIENT_HELLO);
+        buf.put_u8(0);
+        buf.put_u16(hello_len as u16);
+        buf.put_u16(0x0303);
+        put_tls_random(buf);
+        buf.put_u8(self.session_id.len() as u8);
+        buf.put_slice(&self.session_id);
+        buf.put_u16((TLS_CIPHER_SUITES.len() * 2) as u16);
+        for suite in TLS_CIPHER_SUITES {
+            buf.put_u16(suite);
+        }
+        // compression methods: null
+        buf.put_u8(1);
+        buf.put_u8(0);
+
+        buf.put_u16(ext_len as u16);
+        buf.put_u16(TLS_EXT_SESSION_TICKET);
+        buf.put_u16(data.len() as u16);
+        buf.put_slice(data);
+        buf.put_u16(0x0000);
+        buf.put_u16((host.len() + 5) as u16);
+        buf.put_u16((host.len() + 3) as u16);
+        buf.put_u8(0);
+        buf.put_u16(host.len() as u16);
+        buf.put_slice(host);
+        buf.put_slice(&TLS_CLIENT_HELLO_OTHER_EXTS);
+    }
+
+    fn encode_tls_server_hello(&mut self) {
+        let hello_len = 2 + 32 + 1 + 32 + 2 + 1 + 2 + TLS_SERVER_HELLO_EXTS.len();
+
+        let buf = &mut self.write_buf;
+        buf.put_u8(TLS_CONTENT_HANDSHAKE);
+        buf.put_u16(0x0301);
+        buf.put_u16((4 + hello_len) as u16);
+
+        buf.put_u8(0x02);
+        buf.put_u8(0);
+        buf.put_u16(hello_len as u16);
+        buf.put_u16(0x0303);
+        put_tls_random(buf);
+        buf.put_u8(self.session_id.len() as u8);
+        buf.put_slice(&self.session_id);
+        // TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256, without compression
+        buf.put_u16(0xcca8);
+        buf.put_u8(0);
+        buf.put_u16(TLS_SERVER_HELLO_EXTS.len() as u16);
+        buf.put_slice(&TLS_SERVER_HELLO_EXTS);
+    }
+
+    fn encode_tls_finished(&mut self) {
+        encode_tls_record(&mut self.write_buf, TLS_CONTENT_CHANGE_CIPHER_SPEC, &[0x01]);
+
+        let mut finished = [0u8; 32];
+        random_iv_or_salt(&mut finished);
+        encode_tls_record(&mut self.write_buf, TLS_CONTENT_HANDSHAKE, &finished);
+    }
+}
+
+fn encode_tls_record(buf: &mut BytesMut, content_type: u8, data: &[u8]) {
+    buf.reserve(5 + data.len());
+    buf.put_u8(content_type);
+    buf.put_u16(0x0303);
+    buf.put_u16(data.len() as u16);
+    buf.put_slice(data);
+}
+
+fn put_tls_random(buf: &mut BytesMut) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    buf.put_u32(now.as_secs() as u32);
+
+    let mut random = [0u8; 28];
+    random_iv_or_salt(&mut random);
+    buf.put_slice(&random);
+}
+
+/// Get session ID and session ticket from a ClientHello
+fn parse_client_hello(hello: &[u8]) -> Option<(&[u8], &[u8])> {
+    fn take<'a>(buf: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+        if buf.len() < n {
+            return None;
+        }
+        let (data, remaining) = buf.split_at(n);
+        *buf = remaining;
+        Some(data)
+    }
+
+    fn take_u16(buf: &mut &[u8]) -> Option<usize> {
+        take(buf, 2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+    }
+
+    let mut buf = hello;
+    if take(&mut buf, 1)?[0] != TLS_HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    // length, version, random
+    take(&mut buf, 3 + 2 + 32)?;
+    let session_id_len = take(&mut buf, 1)?[0] as usize;
+    let session_id = take(&mut buf, session_id_len)?;
+    let cipher_suites_len = take_u16(&mut buf)?;
+    take(&mut buf, cipher_suites_len)?;
+    let compression_len = take(&mut buf, 1)?[0] as usize;
+    take(&mut buf, compression_len)?;
+
+    let ext_len = take_u16(&mut buf)?;
+    let mut exts = take(&mut buf, ext_len)?;
+    while !exts.is_empty() {
+        let ext_type = take_u16(&mut exts)? as u16;
+        let len = take_u16(&mut exts)?;
+        let data = take(&mut exts, len)?;
+        if ext_type == TLS_EXT_SESSION_TICKET {
+            return Some((session_id, data));
+        }
+    }
+
+    None
+}
+
+fn random_u8() -> u8 {
+    let mut b = [0u8; 1];
+    random_iv_or_salt(&mut b);
+    b[0]
+}
+
+/// Format time as IMF-fixdate, like `Sun, 06 Nov 1994 08:49:37 GMT`
+fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    // Civil date from days since 1970-01-01
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+impl<S> AsyncRead for ObfsStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let obfs = &mut this.obfs;
+
+        loop {
+            if !obfs.data_buf.is_empty() {
+                let n = obfs.data_buf.len().min(buf.remaining());
+                buf.put_slice(&obfs.data_buf[..n]);
+                obfs.data_buf.advance(n);
+                return Ok(()).into();
+            }
+
+            if obfs.read_passthrough() {
+                return Pin::new(&mut this.stream).poll_read(cx, buf);
+            }
+
+            if obfs.decode()? {
+                continue;
+            }
+
+            let mut incoming = [0u8; 8192];
+            let mut incoming_buf = ReadBuf::new(&mut incoming);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut incoming_buf))?;
+
+            let n = incoming_buf.filled().len();
+            if n == 0 {
+                if obfs.read_buf.is_empty() {
+                    return Ok(()).into();
+                }
+                return Err(ErrorKind::UnexpectedEof.into()).into();
+            }
+            obfs.read_buf.extend_from_slice(incoming_buf.filled());
+        }
+    }
+}
+
+impl<S> ObfsStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write_pending(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        while !self.obfs.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.obfs.write_buf))?;
+            if n == 0 {
+                return Err(ErrorKind::WriteZero.into()).into();
+            }
+            self.obfs.write_buf.advance(n);
+        }
+        Ok(()).into()
+    }
+}
+
+impl<S> AsyncWrite for ObfsStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let obfs = &mut this.obfs;
+
+        // Encoded bytes of the previous call must be sent before accepting new data.
+        // Callers are required to call again with the same `buf` after `Poll::Pending`.
+        if obfs.write_buf.is_empty() {
+            if buf.is_empty() {
+                return Ok(0).into();
+            }
+            if obfs.write_passthrough() {
+                return Pin::new(&mut this.stream).poll_write(cx, buf);
+            }
+            obfs.encode(buf);
+        }
+
+        ready!(this.poll_write_pending(cx))?;
+        Ok(this.obfs.write_len).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn parse_plugin_opts() {
+        let config = ObfsConfig::from_plugin_opts(Some("obfs=tls;obfs-host=www.bing.com")).unwrap();
+        assert_eq!(config.mode, ObfsMode::Tls);
+        assert_eq!(config.host.as_deref(), Some("www.bing.com"));
+        assert_eq!(config.uri, None);
+
+        assert!(ObfsConfig::from_plugin_opts(None).is_err());
+        assert!(ObfsConfig::from_plugin_opts(Some("obfs=ws")).is_err());
+        assert!(ObfsConfig::from_plugin_opts(Some("obfs=http;foo=bar")).is_err());
+    }
+
+    #[test]
+    fn format_http_date() {
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(784111777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+
+    async fn echo_through(mode: ObfsMode) {
+        let config = ObfsConfig {
+            mode,
+            host: Some("www.example.com".to_owned()),
+            uri: None,
+        };
+        let server_addr = ServerAddr::DomainName("example.com".to_owned(), 8388);
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = ObfsStream::new_client(client, &config, &server_addr);
+        let mut server = ObfsStream::new_server(server, &config);
+
+        let server_task = tokio::spawn(async move {
+            let mut buf = vec![0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            server.write_all(&buf).await.unwrap();
+            server.read_exact(&mut buf).await.unwrap();
+            server.write_all(&buf).await.unwrap();
+            server.flush().await.unwrap();
+        });
+
+        let mut buf = [0u8; 5];
+        client.write_all(b"hello").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        client.write_all(b"world").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+
+        server_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn obfs_http_roundtrip() {
+        echo_through(ObfsMode::Http).await;
+    }
+
+    #[tokio::test]
+    async fn obfs_tls_roundtrip() {
+        echo_through(ObfsMode::Tls).await;
+    }
+}
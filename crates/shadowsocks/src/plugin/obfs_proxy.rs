@@ -1,5 +1,6 @@
 use super::{PluginConfig, PluginMode};
 use crate::config::ServerAddr;
+use log::warn;
 use std::{net::SocketAddr, process::Stdio};
 use tokio::process::Command;
 
@@ -28,6 +29,13 @@ use tokio::process::Command;
 /// Some old obfsproxy will not be supported as it doesn't even support
 /// "--data-dir" option
 pub fn plugin_cmd(plugin: &PluginConfig, remote: &ServerAddr, local: &SocketAddr, mode: PluginMode) -> Command {
+    if plugin.plugin_mode.enable_udp() {
+        warn!(
+            "obfsproxy doesn't support UDP, plugin_mode {} ignored",
+            plugin.plugin_mode
+        );
+    }
+
     let mut cmd = Command::new(&plugin.plugin);
     cmd.stdin(Stdio::null())
         .kill_on_drop(true)
@@ -0,0 +1,685 @@
+//! Builtin QUIC transport
+//!
+//! Relays connections over QUIC, for lossy links where TCP over TCP collapses. All TCP connections to a server are
+//! multiplexed in streams of one QUIC connection, and UDP packets are carried in QUIC datagrams if `plugin_mode` relays
+//! UDP. Data are still encrypted by ciphers of shadowsocks inside.
+//! It runs in process as a plugin, which listens on `local_addr` like plugin subprocesses.
+//! Enabled by `"plugin": "builtin-quic"`, with `plugin_opts` like:
+//!
+//! ```plain
+//! host=www.example.com;congestion=bbr
+//! server;cert=/path/to/fullchain.pem;key=/path/to/key.pem;congestion=bbr
+//! ```
+//!
+//! - `server`: Works as server, which listens on UDP of the server address
+//! - `host`: SNI of TLS, the server's address by default
+//! - `ca`: PEM certificates trusted by clients, instead of Mozilla's root certificates, for self-signed certificates
+//! - `cert`, `key`: PEM certificate chain and private key of servers, required in servers
+//! - `congestion`: Congestion controller, `cubic` (default), `bbr` or `new_reno`
+//! - `zero_rtt`: Resume connections with 0-RTT, `1` (default) or `0`
+//!
+//! Data sent in 0-RTT could be replayed by attackers, set `zero_rtt=0` to disable it if it matters.
+//! UDP packets larger than the maximum size of QUIC datagrams on the path are dropped.
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use log::{debug, trace};
+use quinn::{
+    ClientConfig, Connection, ConnectionError, Endpoint, IdleTimeout, RecvStream, SendStream, ServerConfig,
+    TransportConfig,
+    congestion::{BbrConfig, CubicConfig, NewRenoConfig},
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket, lookup_host},
+    time,
+};
+use tokio_rustls::rustls::{self, RootCertStore};
+
+use super::PluginMode;
+use crate::{
+    config::{Mode, ServerAddr},
+    net::tls::{load_certs, load_private_key},
+};
+
+/// Name of the builtin QUIC plugin
+pub const BUILTIN_QUIC_PLUGIN: &str = "builtin-quic";
+
+/// ALPN of connections, the same as HTTP/3
+const QUIC_ALPN: &[u8] = b"h3";
+/// Connections are closed after idle for this long
+const QUIC_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Keep connections alive while they are idle, for NATs on the path
+const QUIC_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// UDP sessions are removed after idle for this long
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(300);
+/// Length of session ID in the header of datagrams
+const UDP_SESSION_ID_LEN: usize = 4;
+
+/// Errors of parsing `QuicPluginConfig`
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum QuicConfigError {
+    /// Unknown option or invalid value
+    #[error("invalid quic option \"{0}\"")]
+    InvalidOption(String),
+    /// Unknown congestion controller
+    #[error("invalid quic congestion \"{0}\", should be one of \"cubic\", \"bbr\" and \"new_reno\"")]
+    InvalidCongestion(String),
+    /// Server without certificate
+    #[error("cert and key are required for quic in server")]
+    MissingCertificate,
+}
+
+/// Congestion controller of QUIC connections
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuicCongestion {
+    #[default]
+    Cubic,
+    Bbr,
+    NewReno,
+}
+
+/// Configuration of builtin QUIC transport
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuicPluginConfig {
+    /// Works as server, which listens on UDP of the server address
+    pub server: bool,
+    /// SNI of TLS, the server's address by default
+    pub host: Option<String>,
+    /// PEM certificates trusted by clients, Mozilla's root certificates by default
+    pub ca: Option<String>,
+    /// PEM certificate chain of servers
+    pub cert: Option<String>,
+    /// PEM private key of servers
+    pub key: Option<String>,
+    /// Congestion controller
+    pub congestion: QuicCongestion,
+    /// Resume connections with 0-RTT
+    pub zero_rtt: bool,
+}
+
+impl Default for QuicPluginConfig {
+    fn default() -> QuicPluginConfig {
+        QuicPluginConfig {
+            server: false,
+            host: None,
+            ca: None,
+            cert: None,
+            key: None,
+            congestion: QuicCongestion::Cubic,
+            zero_rtt: true,
+        }
+    }
+}
+
+impl QuicPluginConfig {
+    /// Parse from `plugin_opts`, like `host=www.example.com;congestion=bbr`
+    pub fn from_plugin_opts(opts: Option<&str>) -> Result<QuicPluginConfig, QuicConfigError> {
+        let mut config = QuicPluginConfig::default();
+
+        for opt in opts.unwrap_or_default().split(';') {
+            let opt = opt.trim();
+            if opt.is_empty() {
+                continue;
+            }
+
+            match opt.split_once('=') {
+                None if opt == "server" => config.server = true,
+                Some(("host", value)) => config.host = Some(value.to_owned()),
+                Some(("ca", value)) => config.ca = Some(value.to_owned()),
+                Some(("cert", value)) => config.cert = Some(value.to_owned()),
+                Some(("key", value)) => config.key = Some(value.to_owned()),
+                Some(("congestion", value)) => {
+                    config.congestion = match value {
+                        "cubic" => QuicCongestion::Cubic,
+                        "bbr" => QuicCongestion::Bbr,
+                        "new_reno" => QuicCongestion::NewReno,
+                        _ => return Err(QuicConfigError::InvalidCongestion(value.to_owned())),
+                    };
+                }
+                Some(("zero_rtt", "0")) => config.zero_rtt = false,
+                Some(("zero_rtt", "1")) => config.zero_rtt = true,
+                _ => return Err(QuicConfigError::InvalidOption(opt.to_owned())),
+            }
+        }
+
+        if config.server && (config.cert.is_none() || config.key.is_none()) {
+            return Err(QuicConfigError::MissingCertificate);
+        }
+
+        Ok(config)
+    }
+
+    fn transport_config(&self) -> Arc<TransportConfig> {
+        let mut transport = TransportConfig::default();
+        transport
+            .max_idle_timeout(Some(
+                IdleTimeout::try_from(QUIC_IDLE_TIMEOUT).expect("quic idle timeout"),
+            ))
+            .keep_alive_interval(Some(QUIC_KEEP_ALIVE_INTERVAL));
+        match self.congestion {
+            QuicCongestion::Cubic => transport.congestion_controller_factory(Arc::new(CubicConfig::default())),
+            QuicCongestion::Bbr => transport.congestion_controller_factory(Arc::new(BbrConfig::default())),
+            QuicCongestion::NewReno => transport.congestion_controller_factory(Arc::new(NewRenoConfig::default())),
+        };
+        Arc::new(transport)
+    }
+
+    fn client_config(&self) -> io::Result<ClientConfig> {
+        let mut store = RootCertStore::empty();
+        match self.ca {
+            Some(ref ca) => {
+                for cert in load_certs(ca)? {
+                    store
+                        .add(cert)
+                        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, format!("{ca}: {err}")))?;
+                }
+            }
+            None => store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+
+        let mut crypto = rustls::ClientConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
+            .with_root_certificates(store)
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+        crypto.enable_early_data = self.zero_rtt;
+
+        let crypto = QuicClientConfig::try_from(crypto).map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+        let mut config = ClientConfig::new(Arc::new(crypto));
+        config.transport_config(self.transport_config());
+        Ok(config)
+    }
+
+    fn server_config(&self) -> io::Result<ServerConfig> {
+        let (cert, key) = match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => (cert, key),
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    QuicConfigError::MissingCertificate,
+                ));
+            }
+        };
+        let certs = load_certs(cert)?;
+        let key = load_private_key(key)?;
+
+        let mut crypto = rustls::ServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+        crypto.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+        // QUIC only allows 0 or unlimited
+        crypto.max_early_data_size = if self.zero_rtt { u32::MAX } else { 0 };
+
+        let crypto = QuicServerConfig::try_from(crypto).map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+        let mut config = ServerConfig::with_crypto(Arc::new(crypto));
+        config.transport_config(self.transport_config());
+        Ok(config)
+    }
+}
+
+async fn resolve(addr: &ServerAddr) -> io::Result<SocketAddr> {
+    match *addr {
+        ServerAddr::SocketAddr(sa) => Ok(sa),
+        ServerAddr::DomainName(ref dname, port) => match lookup_host((dname.as_str(), port)).await?.next() {
+            Some(sa) => Ok(sa),
+            None => Err(io::Error::new(
+                ErrorKind::Other,
+                format!("quic couldn't resolve server {dname}"),
+            )),
+        },
+    }
+}
+
+/// Run QUIC transport like a plugin subprocess, only returns on errors
+///
+/// `PluginMode::Client`: Listens on TCP and UDP `local_addr`, and relays them to QUIC `remote_addr`
+/// `PluginMode::Server`: Listens on QUIC `remote_addr`, and relays streams and datagrams to `local_addr`
+///
+/// UDP is only relayed if `plugin_mode` enables UDP.
+pub(crate) async fn run(
+    config: QuicPluginConfig,
+    remote_addr: ServerAddr,
+    local_addr: SocketAddr,
+    mode: PluginMode,
+    plugin_mode: Mode,
+) -> io::Result<()> {
+    match mode {
+        PluginMode::Client => run_client(config, remote_addr, local_addr, plugin_mode).await,
+        PluginMode::Server => run_server(config, remote_addr, local_addr, plugin_mode).await,
+    }
+}
+
+async fn run_client(
+    config: QuicPluginConfig,
+    remote_addr: ServerAddr,
+    local_addr: SocketAddr,
+    plugin_mode: Mode,
+) -> io::Result<()> {
+    let server_name = match config.host {
+        Some(ref host) => host.clone(),
+        None => match remote_addr {
+            ServerAddr::SocketAddr(ref sa) => sa.ip().to_string(),
+            ServerAddr::DomainName(ref dname, ..) => dname.clone(),
+        },
+    };
+
+    let udp_socket = if plugin_mode.enable_udp() {
+        Some(Arc::new(UdpSocket::bind(local_addr).await?))
+    } else {
+        None
+    };
+
+    let client = Arc::new(QuicClient {
+        config: config.client_config()?,
+        remote_addr,
+        server_name,
+        zero_rtt: config.zero_rtt,
+        connection: tokio::sync::Mutex::new(None),
+        udp: udp_socket.clone().map(|socket| (socket, Arc::new(UdpSessions::new()))),
+    });
+
+    if let Some(socket) = udp_socket {
+        let client = client.clone();
+        tokio::spawn(async move {
+            if let Err(err) = client_udp_relay(client, socket).await {
+                debug!("quic udp relay on {} stopped with error: {}", local_addr, err);
+            }
+        });
+    }
+
+    let listener = TcpListener::bind(local_addr).await?;
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let client = client.clone();
+        tokio::spawn(async move {
+            let result = async {
+                let connection = client.connection().await?;
+                let (send, recv) = connection.open_bi().await.map_err(io::Error::from)?;
+                relay(stream, send, recv).await
+            };
+            match result.await {
+                Ok((tx, rx)) => trace!(
+                    "quic {} <-> {} closed, tx: {}, rx: {}",
+                    peer_addr, client.remote_addr, tx, rx
+                ),
+                Err(err) => debug!(
+                    "quic {} <-> {} closed with error: {}",
+                    peer_addr, client.remote_addr, err
+                ),
+            }
+        });
+    }
+}
+
+/// Shared QUIC connection of a client, which is connected again after closed
+struct QuicClient {
+    config: ClientConfig,
+    remote_addr: ServerAddr,
+    server_name: String,
+    zero_rtt: bool,
+    connection: tokio::sync::Mutex<Option<Connection>>,
+    udp: Option<(Arc<UdpSocket>, Arc<UdpSessions<SocketAddr>>)>,
+}
+
+impl QuicClient {
+    async fn connection(&self) -> io::Result<Connection> {
+        let mut connection = self.connection.lock().await;
+        if let Some(ref c) = *connection {
+            if c.close_reason().is_none() {
+                return Ok(c.clone());
+            }
+        }
+
+        let addr = resolve(&self.remote_addr).await?;
+        let bind_addr = match addr {
+            SocketAddr::V4(..) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            SocketAddr::V6(..) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        };
+        let mut endpoint = Endpoint::client(bind_addr)?;
+        endpoint.set_default_client_config(self.config.clone());
+
+        let connecting = endpoint
+            .connect(addr, &self.server_name)
+            .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+        let c = if self.zero_rtt {
+            match connecting.into_0rtt() {
+                Ok((c, accepted)) => {
+                    let remote_addr = self.remote_addr.clone();
+                    tokio::spawn(async move {
+                        if !accepted.await {
+                            debug!("quic 0-rtt data to {} were rejected", remote_addr);
+                        }
+                    });
+                    c
+                }
+                Err(connecting) => connecting.await.map_err(io::Error::from)?,
+            }
+        } else {
+            connecting.await.map_err(io::Error::from)?
+        };
+        debug!("quic connected to {} ({})", self.remote_addr, addr);
+
+        if let Some((ref socket, ref sessions)) = self.udp {
+            tokio::spawn(client_udp_receive(c.clone(), socket.clone(), sessions.clone()));
+        }
+
+        *connection = Some(c.clone());
+        Ok(c)
+    }
+}
+
+/// UDP packets from shadowsocks are sent in datagrams of the current connection, tagged with IDs of their sources
+async fn client_udp_relay(client: Arc<QuicClient>, socket: Arc<UdpSocket>) -> io::Result<()> {
+    let sessions = match client.udp {
+        Some((_, ref sessions)) => sessions.clone(),
+        None => return Ok(()),
+    };
+
+    let mut buffer = vec![0u8; 65536];
+    loop {
+        let (n, peer_addr) = socket.recv_from(&mut buffer).await?;
+        let id = sessions.id_of(peer_addr);
+
+        let connection = match client.connection().await {
+            Ok(c) => c,
+            Err(err) => {
+                debug!("quic udp {} dropped a packet, {}", peer_addr, err);
+                continue;
+            }
+        };
+        if let Err(err) = connection.send_datagram(make_datagram(id, &buffer[..n])) {
+            trace!("quic udp {} dropped a packet of {} bytes, {}", peer_addr, n, err);
+        }
+    }
+}
+
+async fn client_udp_receive(connection: Connection, socket: Arc<UdpSocket>, sessions: Arc<UdpSessions<SocketAddr>>) {
+    loop {
+        let datagram = match connection.read_datagram().await {
+            Ok(d) => d,
+            Err(err) => {
+                trace!("quic udp of {} stopped, {}", connection.remote_address(), err);
+                return;
+            }
+        };
+        let Some((id, payload)) = parse_datagram(&datagram) else {
+            continue;
+        };
+        if let Some(peer_addr) = sessions.get(id) {
+            if let Err(err) = socket.send_to(payload, peer_addr).await {
+                trace!("quic udp failed to send to {}, {}", peer_addr, err);
+            }
+        }
+    }
+}
+
+async fn run_server(
+    config: QuicPluginConfig,
+    remote_addr: ServerAddr,
+    local_addr: SocketAddr,
+    plugin_mode: Mode,
+) -> io::Result<()> {
+    let addr = resolve(&remote_addr).await?;
+    let endpoint = Endpoint::server(config.server_config()?, addr)?;
+
+    while let Some(incoming) = endpoint.accept().await {
+        let peer_addr = incoming.remote_address();
+        tokio::spawn(async move {
+            let result = async {
+                let connecting = incoming.accept().map_err(io::Error::from)?;
+                let connection = match connecting.into_0rtt() {
+                    Ok((c, ..)) => c,
+                    Err(connecting) => connecting.await.map_err(io::Error::from)?,
+                };
+                serve_connection(connection, local_addr, plugin_mode).await
+            };
+            match result.await {
+                Ok(()) => trace!("quic connection from {} closed", peer_addr),
+                Err(err) => debug!("quic connection from {} closed with error: {}", peer_addr, err),
+            }
+        });
+    }
+
+    Err(io::Error::new(ErrorKind::Other, "quic endpoint closed"))
+}
+
+async fn serve_connection(connection: Connection, local_addr: SocketAddr, plugin_mode: Mode) -> io::Result<()> {
+    let peer_addr = connection.remote_address();
+
+    if plugin_mode.enable_udp() {
+        tokio::spawn(server_udp_relay(connection.clone(), local_addr));
+    }
+
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(s) => s,
+            Err(ConnectionError::ApplicationClosed(..)) | Err(ConnectionError::TimedOut) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        tokio::spawn(async move {
+            let result = async {
+                let stream = TcpStream::connect(local_addr).await?;
+                relay(stream, send, recv).await
+            };
+            match result.await {
+                Ok((tx, rx)) => trace!("quic {} <-> {} closed, tx: {}, rx: {}", peer_addr, local_addr, tx, rx),
+                Err(err) => debug!("quic {} <-> {} closed with error: {}", peer_addr, local_addr, err),
+            }
+        });
+    }
+}
+
+/// Datagrams are sent to `local_addr` from a socket of each session, like NAT
+async fn server_udp_relay(connection: Connection, local_addr: SocketAddr) {
+    let sockets = Arc::new(Mutex::new(HashMap::<u32, Arc<UdpSocket>>::new()));
+
+    loop {
+        let datagram = match connection.read_datagram().await {
+            Ok(d) => d,
+            Err(err) => {
+                trace!("quic udp of {} stopped, {}", connection.remote_address(), err);
+                return;
+            }
+        };
+        let Some((id, payload)) = parse_datagram(&datagram) else {
+            continue;
+        };
+
+        let socket = sockets.lock().unwrap().get(&id).cloned();
+        let socket = match socket {
+            Some(s) => s,
+            None => {
+                let bind_addr = match local_addr {
+                    SocketAddr::V4(..) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+                    SocketAddr::V6(..) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+                };
+                let socket = match UdpSocket::bind(bind_addr).await {
+                    Ok(s) => Arc::new(s),
+                    Err(err) => {
+                        debug!("quic udp failed to bind socket for {}, {}", local_addr, err);
+                        continue;
+                    }
+                };
+                if let Err(err) = socket.connect(local_addr).await {
+                    debug!("quic udp failed to connect {}, {}", local_addr, err);
+                    continue;
+                }
+                sockets.lock().unwrap().insert(id, socket.clone());
+                tokio::spawn(server_udp_receive(
+                    connection.clone(),
+                    socket.clone(),
+                    id,
+                    sockets.clone(),
+                ));
+                socket
+            }
+        };
+
+        if let Err(err) = socket.send(payload).await {
+            trace!("quic udp failed to send to {}, {}", local_addr, err);
+        }
+    }
+}
+
+async fn server_udp_receive(
+    connection: Connection,
+    socket: Arc<UdpSocket>,
+    id: u32,
+    sockets: Arc<Mutex<HashMap<u32, Arc<UdpSocket>>>>,
+) {
+    let mut buffer = vec![0u8; 65536];
+    loop {
+        let n = match time::timeout(UDP_SESSION_TIMEOUT, socket.recv(&mut buffer)).await {
+            Ok(Ok(n)) => n,
+            Ok(Err(err)) => {
+                trace!("quic udp session {} failed to receive, {}", id, err);
+                break;
+            }
+            Err(..) => break,
+        };
+        if let Err(err) = connection.send_datagram(make_datagram(id, &buffer[..n])) {
+            trace!("quic udp session {} dropped a packet of {} bytes, {}", id, n, err);
+            if connection.close_reason().is_some() {
+                break;
+            }
+        }
+    }
+    sockets.lock().unwrap().remove(&id);
+}
+
+/// IDs of UDP sessions, which are removed after idle for `UDP_SESSION_TIMEOUT`
+struct UdpSessions<K> {
+    inner: Mutex<UdpSessionsInner<K>>,
+}
+
+struct UdpSessionsInner<K> {
+    next_id: u32,
+    ids: HashMap<K, u32>,
+    keys: HashMap<u32, (K, Instant)>,
+}
+
+impl<K> UdpSessions<K>
+where
+    K: Copy + Eq + std::hash::Hash,
+{
+    fn new() -> UdpSessions<K> {
+        UdpSessions {
+            inner: Mutex::new(UdpSessionsInner {
+                next_id: 0,
+                ids: HashMap::new(),
+                keys: HashMap::new(),
+            }),
+        }
+    }
+
+    /// ID of session `key`, a new ID is assigned if it is a new session
+    fn id_of(&self, key: K) -> u32 {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        if let Some(&id) = inner.ids.get(&key) {
+            inner.keys.insert(id, (key, now));
+            return id;
+        }
+
+        let UdpSessionsInner { ids, keys, .. } = &mut *inner;
+        keys.retain(|_, (key, active)| {
+            let alive = now.duration_since(*active) < UDP_SESSION_TIMEOUT;
+            if !alive {
+                ids.remove(key);
+            }
+            alive
+        });
+
+        let id = inner.next_id;
+        inner.next_id = inner.next_id.wrapping_add(1);
+        inner.ids.insert(key, id);
+        inner.keys.insert(id, (key, now));
+        id
+    }
+
+    fn get(&self, id: u32) -> Option<K> {
+        self.inner.lock().unwrap().keys.get(&id).map(|(key, ..)| *key)
+    }
+}
+
+fn make_datagram(id: u32, payload: &[u8]) -> Bytes {
+    let mut datagram = BytesMut::with_capacity(UDP_SESSION_ID_LEN + payload.len());
+    datagram.put_u32(id);
+    datagram.put_slice(payload);
+    datagram.freeze()
+}
+
+fn parse_datagram(datagram: &[u8]) -> Option<(u32, &[u8])> {
+    if datagram.len() < UDP_SESSION_ID_LEN {
+        return None;
+    }
+    let (id, payload) = datagram.split_at(UDP_SESSION_ID_LEN);
+    Some((u32::from_be_bytes([id[0], id[1], id[2], id[3]]), payload))
+}
+
+/// Copy between a TCP stream and a QUIC stream, until both directions are finished
+async fn relay(mut stream: TcpStream, mut send: SendStream, mut recv: RecvStream) -> io::Result<(u64, u64)> {
+    let (mut reader, mut writer) = stream.split();
+    tokio::try_join!(
+        copy_and_shutdown(&mut reader, &mut send),
+        copy_and_shutdown(&mut recv, &mut writer)
+    )
+}
+
+async fn copy_and_shutdown<R, W>(reader: &mut R, writer: &mut W) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let n = tokio::io::copy(reader, writer).await?;
+    writer.shutdown().await?;
+    Ok(n)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_plugin_opts() {
+        let config =
+            QuicPluginConfig::from_plugin_opts(Some("host=www.example.com;congestion=bbr;zero_rtt=0")).unwrap();
+        assert!(!config.server);
+        assert_eq!(config.host.as_deref(), Some("www.example.com"));
+        assert_eq!(config.congestion, QuicCongestion::Bbr);
+        assert!(!config.zero_rtt);
+
+        assert_eq!(
+            QuicPluginConfig::from_plugin_opts(None).unwrap(),
+            QuicPluginConfig::default()
+        );
+        assert!(QuicPluginConfig::from_plugin_opts(Some("server")).is_err());
+        assert!(QuicPluginConfig::from_plugin_opts(Some("congestion=vegas")).is_err());
+    }
+
+    #[test]
+    fn udp_sessions() {
+        let sessions = UdpSessions::new();
+        let a = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+        let b = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 2000);
+        let id = sessions.id_of(a);
+        assert_eq!(sessions.id_of(a), id);
+        assert_ne!(sessions.id_of(b), id);
+        assert_eq!(sessions.get(id), Some(a));
+
+        let datagram = make_datagram(id, b"payload");
+        assert_eq!(parse_datagram(&datagram), Some((id, &b"payload"[..])));
+        assert_eq!(parse_datagram(b"\x00\x01"), None);
+    }
+}
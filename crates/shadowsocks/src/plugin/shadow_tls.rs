@@ -0,0 +1,851 @@
+//! Builtin TLS camouflage, in the style of ShadowTLS
+//!
+//! Clients perform real TLS handshakes with a trusted site, like `www.example.com`, through the server. The server
+//! relays the handshake to the real site, so active probers only see the certificate of that site. Once the handshake
+//! has finished, clients stop using TLS and send shadowsocks data in TLS application data records, tagging the first
+//! record with a keyed hash of the handshake. The server switches the connection to shadowsocks when it sees the tag,
+//! and keeps relaying connections without tags to the real site.
+//!
+//! Enabled by `"plugin": "builtin-shadow-tls"`, with `plugin_opts` like:
+//!
+//! ```plain
+//! host=www.example.com;password=secret
+//! server;host=www.example.com;password=secret;handshake=www.example.com:443
+//! ```
+//!
+//! `handshake` is the real site relayed by servers, `host` with port 443 by default.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    future::Future,
+    io::{self, ErrorKind},
+    mem,
+    pin::Pin,
+    sync::Arc,
+    task::{self, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::ready;
+use once_cell::sync::Lazy;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    Connect, TlsConnector,
+    rustls::{ClientConfig, RootCertStore, pki_types::ServerName},
+};
+
+use super::tls::poll_fill_read_buf;
+use crate::config::ServerAddr;
+
+/// Context of deriving keys from passwords
+const KEY_CONTEXT: &str = "shadowsocks-rust shadow-tls";
+/// Length of tags prefixed to the first application data record
+const TAG_LEN: usize = 8;
+/// Size of TLS record headers
+const RECORD_HEADER_LEN: usize = 5;
+/// Maximum payload size of sending records
+const MAX_SEND_RECORD_SIZE: usize = 16 * 1024;
+/// Maximum payload size of receiving records, TLS 1.2 allows 2048 bytes of expansion
+const MAX_RECORD_SIZE: usize = 16 * 1024 + 2048;
+/// Tags of this many latest records from the real site are matched with the client's
+const MAX_HANDSHAKE_TAGS: usize = 32;
+
+const CONTENT_TYPE_ALERT: u8 = 0x15;
+const CONTENT_TYPE_APPLICATION_DATA: u8 = 0x17;
+
+type Tag = [u8; TAG_LEN];
+
+/// Errors of parsing `ShadowTlsConfig`
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ShadowTlsConfigError {
+    /// Unknown option
+    #[error("unrecognized shadow-tls option \"{0}\"")]
+    InvalidOption(String),
+    /// `host` is required
+    #[error("host is required")]
+    MissingHost,
+    /// `host` couldn't be used as SNI
+    #[error("invalid host \"{0}\"")]
+    InvalidHost(String),
+    /// `password` is required
+    #[error("password is required")]
+    MissingPassword,
+    /// `handshake` is not an address with port
+    #[error("invalid handshake server \"{0}\"")]
+    InvalidHandshakeServer(String),
+}
+
+/// Configuration of builtin TLS camouflage
+#[derive(Clone)]
+pub struct ShadowTlsConfig {
+    /// Works as server, which relays handshakes to the real site
+    pub server: bool,
+    /// SNI of TLS handshakes
+    pub host: String,
+    /// Address of the real site, used by servers only
+    pub handshake: ServerAddr,
+    key: [u8; 32],
+    tls_connector: Option<(TlsConnector, ServerName<'static>)>,
+}
+
+impl fmt::Debug for ShadowTlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShadowTlsConfig")
+            .field("server", &self.server)
+            .field("host", &self.host)
+            .field("handshake", &self.handshake)
+            .finish()
+    }
+}
+
+static TLS_CLIENT_CONFIG: Lazy<Arc<ClientConfig>> = Lazy::new(|| {
+    let mut store = RootCertStore::empty();
+    store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    let mut config = ClientConfig::builder()
+        .with_root_certificates(store)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Arc::new(config)
+});
+
+impl ShadowTlsConfig {
+    /// Parse from `plugin_opts`, like `host=www.example.com;password=secret`
+    pub fn from_plugin_opts(opts: Option<&str>) -> Result<ShadowTlsConfig, ShadowTlsConfigError> {
+        let mut server = false;
+        let mut host = None;
+        let mut password = None;
+        let mut handshake = None;
+
+        for opt in opts.unwrap_or_default().split(';') {
+            let opt = opt.trim();
+            if opt.is_empty() {
+                continue;
+            }
+
+            match opt.split_once('=') {
+                None if opt == "server" => server = true,
+                Some(("host", value)) => host = Some(value.to_owned()),
+                Some(("password", value)) => password = Some(value.to_owned()),
+                Some(("handshake", value)) => handshake = Some(value.to_owned()),
+                _ => return Err(ShadowTlsConfigError::InvalidOption(opt.to_owned())),
+            }
+        }
+
+        let host = match host {
+            Some(h) if !h.is_empty() => h,
+            _ => return Err(ShadowTlsConfigError::MissingHost),
+        };
+        let key = match password {
+            Some(p) if !p.is_empty() => blake3::derive_key(KEY_CONTEXT, p.as_bytes()),
+            _ => return Err(ShadowTlsConfigError::MissingPassword),
+        };
+
+        let handshake = handshake.unwrap_or_else(|| format!("{host}:443"));
+        let handshake = handshake
+            .parse::<ServerAddr>()
+            .map_err(|_| ShadowTlsConfigError::InvalidHandshakeServer(handshake.clone()))?;
+
+        let tls_connector = if server {
+            None
+        } else {
+            let server_name =
+                ServerName::try_from(host.clone()).map_err(|_| ShadowTlsConfigError::InvalidHost(host.clone()))?;
+            Some((TlsConnector::from(TLS_CLIENT_CONFIG.clone()), server_name))
+        };
+
+        Ok(ShadowTlsConfig {
+            server,
+            host,
+            handshake,
+            key,
+            tls_connector,
+        })
+    }
+}
+
+/// Check the header of the TLS record at the beginning of `buf`, returns the length of the whole record if completed
+fn record_len(buf: &[u8]) -> io::Result<Option<usize>> {
+    if buf.len() < RECORD_HEADER_LEN {
+        return Ok(None);
+    }
+
+    let payload_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    if !(0x14..=0x17).contains(&buf[0]) || buf[1] != 0x03 || payload_len > MAX_RECORD_SIZE {
+        return Err(io::Error::new(ErrorKind::InvalidData, "shadow-tls invalid tls record"));
+    }
+
+    let len = RECORD_HEADER_LEN + payload_len;
+    Ok(if buf.len() >= len { Some(len) } else { None })
+}
+
+fn server_tag(key: &[u8; 32], client_tag: &Tag) -> Tag {
+    let mut hasher = blake3::Hasher::new_keyed(key);
+    hasher.update(client_tag);
+    hasher.update(b"server");
+
+    let mut tag = [0u8; TAG_LEN];
+    tag.copy_from_slice(&hasher.finalize().as_bytes()[..TAG_LEN]);
+    tag
+}
+
+/// Keyed hash of complete records sent from the real site
+struct RecordTracker {
+    hasher: blake3::Hasher,
+    pending: BytesMut,
+    tags: VecDeque<Tag>,
+}
+
+impl RecordTracker {
+    fn new(key: &[u8; 32]) -> RecordTracker {
+        RecordTracker {
+            hasher: blake3::Hasher::new_keyed(key),
+            pending: BytesMut::new(),
+            tags: VecDeque::with_capacity(MAX_HANDSHAKE_TAGS),
+        }
+    }
+
+    fn tag(&self) -> Tag {
+        let mut tag = [0u8; TAG_LEN];
+        tag.copy_from_slice(&self.hasher.finalize().as_bytes()[..TAG_LEN]);
+        tag
+    }
+
+    /// Feed received bytes, returns records completed by them
+    fn feed(&mut self, data: &[u8]) -> io::Result<BytesMut> {
+        self.pending.extend_from_slice(data);
+
+        let mut records = BytesMut::new();
+        while let Some(len) = record_len(&self.pending)? {
+            let record = self.pending.split_to(len);
+            self.hasher.update(&record);
+
+            if self.tags.len() >= MAX_HANDSHAKE_TAGS {
+                self.tags.pop_front();
+            }
+            self.tags.push_back(self.tag());
+
+            records.unsplit(record);
+        }
+        Ok(records)
+    }
+}
+
+/// Stream of clients' TLS handshakes, hashing records received from the server
+struct HandshakeRecorder<S> {
+    stream: S,
+    records: RecordTracker,
+}
+
+impl<S> AsyncRead for HandshakeRecorder<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        // Invalid records will fail the TLS handshake
+        let _ = this.records.feed(&buf.filled()[filled..]);
+        Ok(()).into()
+    }
+}
+
+impl<S> AsyncWrite for HandshakeRecorder<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+type ConnectFuture = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+
+/// Relay between a client and the real site, until the client's tagged record is received
+struct HandshakeRelay {
+    connect: Option<ConnectFuture>,
+    stream: Option<TcpStream>,
+    // Records received from the client not completed yet
+    client_buf: BytesMut,
+    // Records received from the real site
+    site_records: RecordTracker,
+    to_site: BytesMut,
+    to_client: BytesMut,
+    site_closed: bool,
+    // Connections not in TLS are relayed as is
+    passthrough: bool,
+    // Tag and data of the client's tagged record
+    matched: Option<(Tag, BytesMut)>,
+}
+
+impl HandshakeRelay {
+    fn new(key: &[u8; 32], handshake: &ServerAddr) -> HandshakeRelay {
+        let connect: ConnectFuture = match *handshake {
+            ServerAddr::SocketAddr(addr) => Box::pin(TcpStream::connect(addr)),
+            ServerAddr::DomainName(ref domain, port) => {
+                let domain = domain.clone();
+                Box::pin(async move { TcpStream::connect((domain.as_str(), port)).await })
+            }
+        };
+
+        HandshakeRelay {
+            connect: Some(connect),
+            stream: None,
+            client_buf: BytesMut::new(),
+            site_records: RecordTracker::new(key),
+            to_site: BytesMut::new(),
+            to_client: BytesMut::new(),
+            site_closed: false,
+            passthrough: false,
+            matched: None,
+        }
+    }
+
+    /// Move complete records from the client to `to_site`, until the tagged one
+    fn process_client_records(&mut self) {
+        if self.passthrough {
+            self.to_site.unsplit(self.client_buf.split());
+            return;
+        }
+
+        loop {
+            let len = match record_len(&self.client_buf) {
+                Ok(Some(len)) => len,
+                Ok(None) => return,
+                Err(..) => {
+                    self.passthrough = true;
+                    self.to_site.unsplit(self.client_buf.split());
+                    return;
+                }
+            };
+
+            let mut record = self.client_buf.split_to(len);
+            if record[0] == CONTENT_TYPE_APPLICATION_DATA && len >= RECORD_HEADER_LEN + TAG_LEN {
+                let tag = &record[RECORD_HEADER_LEN..RECORD_HEADER_LEN + TAG_LEN];
+                if let Some(tag) = self.site_records.tags.iter().find(|t| t[..] == *tag).copied() {
+                    record.advance(RECORD_HEADER_LEN + TAG_LEN);
+                    self.matched = Some((tag, record));
+                    return;
+                }
+            }
+            self.to_site.unsplit(record);
+        }
+    }
+
+    fn process_site_data(&mut self, data: &[u8]) {
+        if self.passthrough {
+            self.to_client.extend_from_slice(data);
+            return;
+        }
+
+        match self.site_records.feed(data) {
+            Ok(records) => self.to_client.unsplit(records),
+            Err(..) => {
+                self.passthrough = true;
+                self.to_client.unsplit(self.site_records.pending.split());
+            }
+        }
+    }
+}
+
+/// Write `buf` into `stream`, returns `true` if anything was written
+fn poll_write_buf<W>(stream: &mut W, cx: &mut task::Context<'_>, buf: &mut BytesMut) -> io::Result<bool>
+where
+    W: AsyncWrite + Unpin,
+{
+    if buf.is_empty() {
+        return Ok(false);
+    }
+    match Pin::new(stream).poll_write(cx, buf) {
+        Poll::Ready(Ok(0)) => Err(ErrorKind::WriteZero.into()),
+        Poll::Ready(Ok(n)) => {
+            buf.advance(n);
+            Ok(true)
+        }
+        Poll::Ready(Err(err)) => Err(err),
+        Poll::Pending => Ok(false),
+    }
+}
+
+/// Read from `stream`, returns the number of bytes read, or `None` if nothing is available
+fn poll_read_some<R>(stream: &mut R, cx: &mut task::Context<'_>, buf: &mut [u8]) -> io::Result<Option<usize>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut read_buf = ReadBuf::new(buf);
+    match Pin::new(stream).poll_read(cx, &mut read_buf) {
+        Poll::Ready(Ok(())) => Ok(Some(read_buf.filled().len())),
+        Poll::Ready(Err(err)) => Err(err),
+        Poll::Pending => Ok(None),
+    }
+}
+
+enum StreamState<S> {
+    Start(S),
+    Connecting(Connect<HandshakeRecorder<S>>),
+    Relaying(S, Box<HandshakeRelay>),
+    Established(S),
+    Poisoned,
+}
+
+/// Stream camouflaged as TLS connections to the real site
+pub struct ShadowTlsStream<S> {
+    state: StreamState<S>,
+    is_client: bool,
+    key: [u8; 32],
+    tls_connector: Option<(TlsConnector, ServerName<'static>)>,
+    // Received bytes not decoded yet
+    read_buf: BytesMut,
+    // Decoded data
+    data_buf: BytesMut,
+    read_closed: bool,
+    // Tag of the first record received from the peer, records before it are from the real site
+    peer_tag: Option<Tag>,
+    // Tag of the first record sent to the peer
+    local_tag: Option<Tag>,
+    // Encoded records not sent yet, and the length of data they carry
+    write_buf: BytesMut,
+    write_len: usize,
+}
+
+impl<S> fmt::Debug for ShadowTlsStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.state {
+            StreamState::Start(..) => "Start",
+            StreamState::Connecting(..) => "Connecting",
+            StreamState::Relaying(..) => "Relaying",
+            StreamState::Established(..) => "Established",
+            StreamState::Poisoned => "Poisoned",
+        };
+        f.debug_struct("ShadowTlsStream")
+            .field("is_client", &self.is_client)
+            .field("state", &state)
+            .finish()
+    }
+}
+
+impl<S> ShadowTlsStream<S> {
+    /// Create a client stream, handshakes on the first read or write
+    pub fn new_client(stream: S, config: &ShadowTlsConfig) -> ShadowTlsStream<S> {
+        assert!(
+            config.tls_connector.is_some(),
+            "shadow-tls client config without tls connector"
+        );
+        ShadowTlsStream::new(StreamState::Start(stream), true, config)
+    }
+
+    /// Create a server stream, relays the handshake to the real site on the first read or write
+    pub fn new_server(stream: S, config: &ShadowTlsConfig) -> ShadowTlsStream<S> {
+        let relay = HandshakeRelay::new(&config.key, &config.handshake);
+        ShadowTlsStream::new(StreamState::Relaying(stream, Box::new(relay)), false, config)
+    }
+
+    fn new(state: StreamState<S>, is_client: bool, config: &ShadowTlsConfig) -> ShadowTlsStream<S> {
+        ShadowTlsStream {
+            state,
+            is_client,
+            key: config.key,
+            tls_connector: config.tls_connector.clone(),
+            read_buf: BytesMut::new(),
+            data_buf: BytesMut::new(),
+            read_closed: false,
+            peer_tag: None,
+            local_tag: None,
+            write_buf: BytesMut::new(),
+            write_len: 0,
+        }
+    }
+
+    /// Get reference to the underlying stream, `None` if the handshake has failed
+    pub fn get_ref(&self) -> Option<&S> {
+        match self.state {
+            StreamState::Connecting(ref c) => c.get_ref().map(|recorder| &recorder.stream),
+            StreamState::Start(ref s) | StreamState::Relaying(ref s, ..) | StreamState::Established(ref s) => Some(s),
+            StreamState::Poisoned => None,
+        }
+    }
+
+    /// Get mutable reference to the underlying stream, `None` if the handshake has failed
+    pub fn get_mut(&mut self) -> Option<&mut S> {
+        match self.state {
+            StreamState::Connecting(ref mut c) => c.get_mut().map(|recorder| &mut recorder.stream),
+            StreamState::Start(ref mut s)
+            | StreamState::Relaying(ref mut s, ..)
+            | StreamState::Established(ref mut s) => Some(s),
+            StreamState::Poisoned => None,
+        }
+    }
+
+    /// Consumes the `ShadowTlsStream` and return the underlying stream, `None` if the handshake is in progress or has
+    /// failed
+    pub fn into_inner(self) -> Option<S> {
+        match self.state {
+            StreamState::Start(s) | StreamState::Relaying(s, ..) | StreamState::Established(s) => Some(s),
+            StreamState::Connecting(..) | StreamState::Poisoned => None,
+        }
+    }
+
+    /// Decode received records into `data_buf`, returns `false` if more bytes are required
+    fn decode(&mut self) -> io::Result<bool> {
+        let len = match record_len(&self.read_buf)? {
+            Some(len) => len,
+            None => return Ok(false),
+        };
+
+        let mut record = self.read_buf.split_to(len);
+        let content_type = record[0];
+        record.advance(RECORD_HEADER_LEN);
+
+        match self.peer_tag {
+            // Records sent by the real site before the server switched to data
+            Some(tag) => {
+                if content_type == CONTENT_TYPE_APPLICATION_DATA && record.starts_with(&tag) {
+                    record.advance(TAG_LEN);
+                    self.data_buf.unsplit(record);
+                    self.peer_tag = None;
+                } else if content_type == CONTENT_TYPE_ALERT {
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionRefused,
+                        "shadow-tls handshake rejected by the real site, the password may be wrong",
+                    ));
+                }
+            }
+            None => match content_type {
+                CONTENT_TYPE_APPLICATION_DATA => self.data_buf.unsplit(record),
+                CONTENT_TYPE_ALERT => self.read_closed = true,
+                content_type => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("shadow-tls unexpected record type {content_type:#x}"),
+                    ));
+                }
+            },
+        }
+        Ok(true)
+    }
+}
+
+impl<S> ShadowTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_handshake(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        if let StreamState::Start(..) = self.state {
+            if let StreamState::Start(stream) = mem::replace(&mut self.state, StreamState::Poisoned) {
+                let (connector, server_name) = self.tls_connector.clone().expect("shadow-tls tls connector");
+                let recorder = HandshakeRecorder {
+                    stream,
+                    records: RecordTracker::new(&self.key),
+                };
+                self.state = StreamState::Connecting(connector.connect(server_name, recorder));
+            }
+        }
+
+        match self.state {
+            StreamState::Connecting(ref mut c) => {
+                let stream = ready!(Pin::new(c).poll(cx))?;
+                let (recorder, _) = stream.into_inner();
+
+                let tag = recorder.records.tag();
+                self.local_tag = Some(tag);
+                self.peer_tag = Some(server_tag(&self.key, &tag));
+                // Following records start from the partial one read in the handshake
+                self.read_buf = recorder.records.pending;
+                self.state = StreamState::Established(recorder.stream);
+            }
+            StreamState::Relaying(..) => ready!(self.poll_relay(cx))?,
+            StreamState::Start(..) | StreamState::Established(..) => {}
+            StreamState::Poisoned => {
+                return Err(io::Error::new(ErrorKind::Other, "shadow-tls handshake failed")).into();
+            }
+        }
+        Ok(()).into()
+    }
+
+    fn poll_relay(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let (client, relay) = match self.state {
+            StreamState::Relaying(ref mut s, ref mut r) => (s, r),
+            _ => return Ok(()).into(),
+        };
+
+        if let Some(ref mut connect) = relay.connect {
+            let stream = match ready!(connect.as_mut().poll(cx)) {
+                Ok(s) => s,
+                Err(err) => {
+                    return Err(io::Error::new(
+                        err.kind(),
+                        format!("shadow-tls failed to connect to the handshake server, {err}"),
+                    ))
+                    .into();
+                }
+            };
+            relay.connect = None;
+            relay.stream = Some(stream);
+        }
+
+        let mut incoming = [0u8; 8192];
+        loop {
+            let site = relay.stream.as_mut().expect("shadow-tls handshake server");
+            let mut progress = poll_write_buf(site, cx, &mut relay.to_site)?;
+            progress |= poll_write_buf(client, cx, &mut relay.to_client)?;
+
+            if relay.to_client.is_empty() {
+                // Data of the tagged record follows records of the real site
+                if relay.matched.is_some() {
+                    break;
+                }
+                if relay.site_closed {
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "shadow-tls connection closed by the handshake server",
+                    ))
+                    .into();
+                }
+            }
+
+            if relay.to_site.is_empty() && relay.matched.is_none() {
+                if let Some(n) = poll_read_some(client, cx, &mut incoming)? {
+                    if n == 0 {
+                        return Err(io::Error::new(
+                            ErrorKind::UnexpectedEof,
+                            "shadow-tls connection closed during handshake",
+                        ))
+                        .into();
+                    }
+                    relay.client_buf.extend_from_slice(&incoming[..n]);
+                    relay.process_client_records();
+                    progress = true;
+                }
+            }
+
+            if relay.to_client.is_empty() && relay.matched.is_none() && !relay.site_closed {
+                let site = relay.stream.as_mut().expect("shadow-tls handshake server");
+                if let Some(n) = poll_read_some(site, cx, &mut incoming)? {
+                    if n == 0 {
+                        relay.site_closed = true;
+                    } else {
+                        relay.process_site_data(&incoming[..n]);
+                    }
+                    progress = true;
+                }
+            }
+
+            if !progress {
+                return Poll::Pending;
+            }
+        }
+
+        let (tag, data) = relay.matched.take().expect("shadow-tls tagged record");
+        self.read_buf = relay.client_buf.split();
+        self.data_buf = data;
+        self.local_tag = Some(server_tag(&self.key, &tag));
+
+        // The connection to the real site is closed here
+        if let StreamState::Relaying(s, ..) = mem::replace(&mut self.state, StreamState::Poisoned) {
+            self.state = StreamState::Established(s);
+        }
+        Ok(()).into()
+    }
+
+    fn stream_mut(&mut self) -> &mut S {
+        match self.state {
+            StreamState::Established(ref mut s) => s,
+            _ => unreachable!("shadow-tls handshake is not finished"),
+        }
+    }
+
+    /// Read more bytes into `read_buf`, returns `false` on EOF
+    fn poll_fill_read_buf(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<bool>> {
+        match self.state {
+            StreamState::Established(ref mut s) => poll_fill_read_buf(Pin::new(s), cx, &mut self.read_buf),
+            _ => unreachable!("shadow-tls handshake is not finished"),
+        }
+    }
+
+    fn poll_send_buf(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = match self.state {
+                StreamState::Established(ref mut s) => ready!(Pin::new(s).poll_write(cx, &self.write_buf))?,
+                _ => unreachable!("shadow-tls handshake is not finished"),
+            };
+            if n == 0 {
+                return Err(ErrorKind::WriteZero.into()).into();
+            }
+            self.write_buf.advance(n);
+        }
+        Ok(()).into()
+    }
+}
+
+impl<S> AsyncRead for ShadowTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+
+        loop {
+            if !this.data_buf.is_empty() {
+                let n = this.data_buf.len().min(buf.remaining());
+                buf.put_slice(&this.data_buf[..n]);
+                this.data_buf.advance(n);
+                return Ok(()).into();
+            }
+
+            if this.read_closed {
+                return Ok(()).into();
+            }
+
+            if this.decode()? {
+                continue;
+            }
+
+            if !ready!(this.poll_fill_read_buf(cx))? {
+                if this.read_buf.is_empty() && this.peer_tag.is_none() {
+                    return Ok(()).into();
+                }
+                return Err(ErrorKind::UnexpectedEof.into()).into();
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for ShadowTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+
+        // Encoded records of the previous call must be sent before accepting new data.
+        // Callers are required to call again with the same `buf` after `Poll::Pending`.
+        if this.write_buf.is_empty() {
+            if buf.is_empty() {
+                return Ok(0).into();
+            }
+
+            let tag = this.local_tag.take();
+            let tag_len = if tag.is_some() { TAG_LEN } else { 0 };
+            let data = &buf[..buf.len().min(MAX_SEND_RECORD_SIZE - tag_len)];
+
+            let mut record = BytesMut::with_capacity(RECORD_HEADER_LEN + tag_len + data.len());
+            record.put_u8(CONTENT_TYPE_APPLICATION_DATA);
+            record.put_u16(0x0303);
+            record.put_u16((tag_len + data.len()) as u16);
+            if let Some(ref tag) = tag {
+                record.put_slice(tag);
+            }
+            record.put_slice(data);
+
+            this.write_buf = record;
+            this.write_len = data.len();
+        }
+
+        ready!(this.poll_send_buf(cx))?;
+        Ok(this.write_len).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+        ready!(this.poll_send_buf(cx))?;
+        Pin::new(this.stream_mut()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+        ready!(this.poll_send_buf(cx))?;
+        Pin::new(this.stream_mut()).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_plugin_opts() {
+        let config = ShadowTlsConfig::from_plugin_opts(Some("host=www.example.com;password=secret")).unwrap();
+        assert!(!config.server);
+        assert_eq!(config.host, "www.example.com");
+        assert_eq!(config.handshake.to_string(), "www.example.com:443");
+
+        let config = ShadowTlsConfig::from_plugin_opts(Some(
+            "server;host=www.example.com;password=secret;handshake=127.0.0.1:8443",
+        ))
+        .unwrap();
+        assert!(config.server);
+        assert_eq!(config.handshake.to_string(), "127.0.0.1:8443");
+
+        assert!(ShadowTlsConfig::from_plugin_opts(Some("host=www.example.com")).is_err());
+        assert!(ShadowTlsConfig::from_plugin_opts(Some("password=secret")).is_err());
+        assert!(ShadowTlsConfig::from_plugin_opts(Some("host=www.example.com;password=secret;tls")).is_err());
+    }
+
+    fn record(content_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut record = vec![content_type, 0x03, 0x03];
+        record.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        record.extend_from_slice(payload);
+        record
+    }
+
+    #[test]
+    fn relay_tagged_record() {
+        let key = blake3::derive_key(KEY_CONTEXT, b"secret");
+        let mut relay = HandshakeRelay::new(&key, &"127.0.0.1:443".parse().unwrap());
+
+        // Records of the real site, the last one split across reads
+        let site_records = [record(0x16, b"server hello"), record(0x17, b"finished")];
+        relay.process_site_data(&site_records[0]);
+        relay.process_site_data(&site_records[1][..3]);
+        assert_eq!(relay.to_client, site_records[0]);
+        relay.process_site_data(&site_records[1][3..]);
+        assert_eq!(relay.to_client.len(), site_records[0].len() + site_records[1].len());
+
+        // The client hashes the same records
+        let mut client_records = RecordTracker::new(&key);
+        client_records.feed(&site_records.concat()).unwrap();
+        let tag = client_records.tag();
+
+        let mut tagged = tag.to_vec();
+        tagged.extend_from_slice(b"data");
+        relay.client_buf.extend_from_slice(&record(0x17, b"client finished"));
+        relay.client_buf.extend_from_slice(&record(0x17, &tagged));
+        relay.client_buf.extend_from_slice(b"\x17\x03");
+        relay.process_client_records();
+
+        assert_eq!(relay.to_site, record(0x17, b"client finished"));
+        let (matched, data) = relay.matched.take().unwrap();
+        assert_eq!(matched, tag);
+        assert_eq!(&data[..], b"data");
+        assert_eq!(&relay.client_buf[..], b"\x17\x03");
+    }
+
+    #[test]
+    fn relay_non_tls() {
+        let key = blake3::derive_key(KEY_CONTEXT, b"secret");
+        let mut relay = HandshakeRelay::new(&key, &"127.0.0.1:443".parse().unwrap());
+
+        relay.client_buf.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        relay.process_client_records();
+        assert!(relay.passthrough);
+        assert!(relay.matched.is_none());
+        assert_eq!(&relay.to_site[..], b"GET / HTTP/1.1\r\n\r\n");
+    }
+}
@@ -1,10 +1,16 @@
 use super::{PluginConfig, PluginMode};
 use crate::config::ServerAddr;
 use log::trace;
-use std::{net::SocketAddr, process::Stdio};
+use std::{net::SocketAddr, path::Path, process::Stdio};
 use tokio::process::Command;
 
-pub fn plugin_cmd(plugin: &PluginConfig, remote: &ServerAddr, local: &SocketAddr, _mode: PluginMode) -> Command {
+pub fn plugin_cmd(
+    plugin: &PluginConfig,
+    remote: &ServerAddr,
+    local: &SocketAddr,
+    unix: Option<&Path>,
+    _mode: PluginMode,
+) -> Command {
     trace!(
         "Starting plugin \"{}\", opt: {:?}, arg: {:?}, remote: {}, local: {}",
         plugin.plugin, plugin.plugin_opts, plugin.plugin_args, remote, local
@@ -15,9 +21,16 @@ pub fn plugin_cmd(plugin: &PluginConfig, remote: &ServerAddr, local: &SocketAddr
         .env("SS_REMOTE_PORT", remote.port().to_string())
         .env("SS_LOCAL_HOST", local.ip().to_string())
         .env("SS_LOCAL_PORT", local.port().to_string())
+        // SIP003u, plugins supporting UDP should also relay UDP on `SS_LOCAL_PORT` if mode is `tcp_and_udp` or `udp_only`
+        .env("SS_PLUGIN_MODE", plugin.plugin_mode.as_str())
         .stdin(Stdio::null())
         .kill_on_drop(true);
 
+    // Plugins supporting Unix domain socket could listen on it instead of SS_LOCAL_HOST:SS_LOCAL_PORT
+    if let Some(unix) = unix {
+        cmd.env("SS_LOCAL_PATH", unix);
+    }
+
     if let Some(ref opt) = plugin.plugin_opts {
         cmd.env("SS_PLUGIN_OPTIONS", opt);
     }
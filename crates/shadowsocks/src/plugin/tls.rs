@@ -0,0 +1,224 @@
+//! Helpers shared by builtin transports
+//!
+//! WebSocket and gRPC transports are optionally wrapped in TLS, which handshakes on the first read or write of their
+//! streams, and all framed transports buffer received bytes before decoding them.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{self, Poll},
+};
+
+use bytes::BytesMut;
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+use tokio_rustls::{TlsAcceptor, rustls::ServerConfig};
+
+#[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+use crate::net::tls::{load_certs, load_private_key};
+
+/// Read more bytes from `reader` into `buf`, returns `false` on EOF
+pub fn poll_fill_read_buf<R>(
+    reader: Pin<&mut R>,
+    cx: &mut task::Context<'_>,
+    buf: &mut BytesMut,
+) -> Poll<io::Result<bool>>
+where
+    R: AsyncRead + ?Sized,
+{
+    let mut incoming = [0u8; 8192];
+    let mut incoming_buf = ReadBuf::new(&mut incoming);
+    ready!(reader.poll_read(cx, &mut incoming_buf))?;
+
+    let n = incoming_buf.filled().len();
+    buf.extend_from_slice(incoming_buf.filled());
+    Ok(n > 0).into()
+}
+
+/// Create a `TlsAcceptor` from PEM files of the certificate chain `cert` and the private key `key`, negotiating `alpn`
+#[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+pub fn make_acceptor(cert: &str, key: &str, alpn: &[u8]) -> Result<TlsAcceptor, String> {
+    let certs = load_certs(cert).map_err(|err| err.to_string())?;
+    let key = load_private_key(key).map_err(|err| err.to_string())?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| err.to_string())?;
+    config.alpn_protocols = vec![alpn.to_vec()];
+    Ok(TlsAcceptor::from(std::sync::Arc::new(config)))
+}
+
+/// Optional TLS layer of transports, handshakes on the first read or write
+#[cfg(any(feature = "plugin-websocket", feature = "plugin-grpc"))]
+pub enum TlsState<S> {
+    Plain(S),
+    #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+    Connecting(tokio_rustls::Connect<S>),
+    #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+    Accepting(tokio_rustls::Accept<S>),
+    #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+    Client(tokio_rustls::client::TlsStream<S>),
+    #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+    Server(tokio_rustls::server::TlsStream<S>),
+    // Handshake failed, the stream is closed
+    #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+    Failed,
+}
+
+#[cfg(any(feature = "plugin-websocket", feature = "plugin-grpc"))]
+impl<S> TlsState<S> {
+    /// Get reference to the underlying stream, `None` if the handshake has failed
+    pub fn get_ref(&self) -> Option<&S> {
+        match *self {
+            TlsState::Plain(ref s) => Some(s),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Connecting(ref c) => c.get_ref(),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Accepting(ref a) => a.get_ref(),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Client(ref s) => Some(s.get_ref().0),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Server(ref s) => Some(s.get_ref().0),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Failed => None,
+        }
+    }
+
+    /// Get mutable reference to the underlying stream, `None` if the handshake has failed
+    pub fn get_mut(&mut self) -> Option<&mut S> {
+        match *self {
+            TlsState::Plain(ref mut s) => Some(s),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Connecting(ref mut c) => c.get_mut(),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Accepting(ref mut a) => a.get_mut(),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Client(ref mut s) => Some(s.get_mut().0),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Server(ref mut s) => Some(s.get_mut().0),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Failed => None,
+        }
+    }
+
+    /// Consumes the `TlsState` and return the underlying stream, `None` if the handshake is in progress or has failed
+    pub fn into_inner(self) -> Option<S> {
+        match self {
+            TlsState::Plain(s) => Some(s),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Connecting(..) | TlsState::Accepting(..) | TlsState::Failed => None,
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Client(s) => Some(s.into_inner().0),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Server(s) => Some(s.into_inner().0),
+        }
+    }
+}
+
+#[cfg(any(feature = "plugin-websocket", feature = "plugin-grpc"))]
+impl<S> TlsState<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_handshake(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+        {
+            use std::future::Future;
+
+            match *self {
+                TlsState::Connecting(ref mut c) => match ready!(Pin::new(c).poll(cx)) {
+                    Ok(stream) => *self = TlsState::Client(stream),
+                    Err(err) => {
+                        *self = TlsState::Failed;
+                        return Err(err).into();
+                    }
+                },
+                TlsState::Accepting(ref mut a) => match ready!(Pin::new(a).poll(cx)) {
+                    Ok(stream) => *self = TlsState::Server(stream),
+                    Err(err) => {
+                        *self = TlsState::Failed;
+                        return Err(err).into();
+                    }
+                },
+                TlsState::Failed => {
+                    return Err(io::Error::new(io::ErrorKind::NotConnected, "tls handshake failed")).into();
+                }
+                _ => {}
+            }
+        }
+        #[cfg(not(any(feature = "plugin-websocket-tls", feature = "plugin-grpc")))]
+        let _ = cx;
+
+        Ok(()).into()
+    }
+}
+
+#[cfg(any(feature = "plugin-websocket", feature = "plugin-grpc"))]
+impl<S> AsyncRead for TlsState<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+        match *this {
+            TlsState::Plain(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Client(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Server(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            _ => unreachable!("tls handshake is not finished"),
+        }
+    }
+}
+
+#[cfg(any(feature = "plugin-websocket", feature = "plugin-grpc"))]
+impl<S> AsyncWrite for TlsState<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+        match *this {
+            TlsState::Plain(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Client(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Server(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            _ => unreachable!("tls handshake is not finished"),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+        match *this {
+            TlsState::Plain(ref mut s) => Pin::new(s).poll_flush(cx),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Client(ref mut s) => Pin::new(s).poll_flush(cx),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Server(ref mut s) => Pin::new(s).poll_flush(cx),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            _ => unreachable!("tls handshake is not finished"),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+        match *this {
+            TlsState::Plain(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Client(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            TlsState::Server(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(any(feature = "plugin-websocket-tls", feature = "plugin-grpc"))]
+            _ => unreachable!("tls handshake is not finished"),
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! In-process transports registered by library users
+//!
+//! Applications embedding shadowsocks could link their own obfuscation layers into the process,
+//! instead of shipping plugin executables. Register a factory by name, and set `plugin` to that name:
+//!
+//! ```ignore
+//! shadowsocks::plugin::register_transport("my-obfs", |opts| Ok(Arc::new(MyObfs::new(opts)?) as Arc<dyn Transport>));
+//! ```
+//!
+//! Transports must be registered before configurations are loaded.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    sync::Arc,
+    task::{self, Poll},
+};
+
+use once_cell::sync::Lazy;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::config::ServerAddr;
+
+/// Stream wrapped by `StreamWrapper`s
+pub trait TransportIo: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T> TransportIo for T where T: AsyncRead + AsyncWrite + Unpin {}
+
+/// Wraps a connection, created for each connection
+///
+/// The underlying stream is passed as `io` in every call, data should be read from or written to it with
+/// the transformation of this transport.
+pub trait StreamWrapper: Send {
+    /// Read and unwrap data from `io` into `buf`
+    fn poll_read(
+        &mut self,
+        io: &mut dyn TransportIo,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>>;
+
+    /// Wrap and write `buf` into `io`, returns length of `buf` consumed
+    fn poll_write(
+        &mut self,
+        io: &mut dyn TransportIo,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>>;
+
+    /// Flush data buffered in this wrapper and `io`
+    fn poll_flush(&mut self, io: &mut dyn TransportIo, cx: &mut task::Context<'_>) -> Poll<io::Result<()>>;
+
+    /// Shutdown `io`, after flushing data buffered in this wrapper
+    fn poll_shutdown(&mut self, io: &mut dyn TransportIo, cx: &mut task::Context<'_>) -> Poll<io::Result<()>>;
+}
+
+/// Transport created from `plugin_opts`, shared by all connections of a server
+pub trait Transport: Send + Sync {
+    /// Create a wrapper for a connection to the server `server_addr`
+    fn client_wrapper(&self, server_addr: &ServerAddr) -> Box<dyn StreamWrapper>;
+
+    /// Create a wrapper for a connection accepted by server
+    fn server_wrapper(&self) -> Box<dyn StreamWrapper>;
+}
+
+type TransportFactory = Arc<dyn Fn(Option<&str>) -> io::Result<Arc<dyn Transport>> + Send + Sync>;
+
+static TRANSPORTS: Lazy<spin::RwLock<HashMap<String, TransportFactory>>> = Lazy::new(Default::default);
+
+/// Register a transport `factory` as plugin `name`, which replaces the transport registered with the same name
+///
+/// `factory` is called with `plugin_opts` of each server using this transport.
+pub fn register_transport<F>(name: &str, factory: F)
+where
+    F: Fn(Option<&str>) -> io::Result<Arc<dyn Transport>> + Send + Sync + 'static,
+{
+    TRANSPORTS.write().insert(name.to_owned(), Arc::new(factory));
+}
+
+/// Check if a transport is registered as plugin `name`
+pub fn is_transport_registered(name: &str) -> bool {
+    TRANSPORTS.read().contains_key(name)
+}
+
+/// Create transport `name` with `opts`, `None` if it is not registered
+pub(crate) fn make_transport(name: &str, opts: Option<&str>) -> Option<io::Result<Arc<dyn Transport>>> {
+    let factory = TRANSPORTS.read().get(name).cloned()?;
+    Some(factory(opts))
+}
+
+/// Transport registered by name
+#[derive(Clone)]
+pub struct RegisteredTransport {
+    pub(crate) name: String,
+    pub(crate) transport: Arc<dyn Transport>,
+}
+
+impl RegisteredTransport {
+    /// Name of the transport
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The transport
+    pub fn transport(&self) -> &Arc<dyn Transport> {
+        &self.transport
+    }
+}
+
+impl fmt::Debug for RegisteredTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegisteredTransport").field("name", &self.name).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::pin::Pin;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::plugin::{BuiltinPlugin, PluginConfig, PluginStream};
+
+    struct XorTransport(u8);
+
+    struct XorWrapper {
+        key: u8,
+        // Wrapped data of the previous pending write
+        pending: Vec<u8>,
+    }
+
+    impl Transport for XorTransport {
+        fn client_wrapper(&self, _: &ServerAddr) -> Box<dyn StreamWrapper> {
+            self.server_wrapper()
+        }
+
+        fn server_wrapper(&self) -> Box<dyn StreamWrapper> {
+            Box::new(XorWrapper {
+                key: self.0,
+                pending: Vec::new(),
+            })
+        }
+    }
+
+    impl StreamWrapper for XorWrapper {
+        fn poll_read(
+            &mut self,
+            io: &mut dyn TransportIo,
+            cx: &mut task::Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let filled = buf.filled().len();
+            futures::ready!(Pin::new(io).poll_read(cx, buf))?;
+            buf.filled_mut()[filled..].iter_mut().for_each(|b| *b ^= self.key);
+            Ok(()).into()
+        }
+
+        fn poll_write(
+            &mut self,
+            io: &mut dyn TransportIo,
+            cx: &mut task::Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.pending.is_empty() {
+                self.pending = buf.iter().map(|b| b ^ self.key).collect();
+            }
+            let n = futures::ready!(Pin::new(io).poll_write(cx, &self.pending))?;
+            self.pending.drain(..n);
+            Ok(n).into()
+        }
+
+        fn poll_flush(&mut self, io: &mut dyn TransportIo, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(io).poll_flush(cx)
+        }
+
+        fn poll_shutdown(&mut self, io: &mut dyn TransportIo, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(io).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn registered_transport_roundtrip() {
+        register_transport("test-xor", |opts| {
+            let key = opts.and_then(|o| o.parse().ok()).unwrap_or(0x5a);
+            Ok(Arc::new(XorTransport(key)) as Arc<dyn Transport>)
+        });
+        assert!(is_transport_registered("test-xor"));
+
+        let plugin = PluginConfig {
+            plugin: "test-xor".to_owned(),
+            plugin_opts: Some("42".to_owned()),
+            plugin_args: Vec::new(),
+            plugin_mode: crate::config::Mode::TcpOnly,
+            plugin_supervision: Default::default(),
+        };
+        assert!(plugin.is_builtin());
+        let transport = BuiltinPlugin::from_plugin_config(&plugin).unwrap().unwrap();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server = PluginStream::new_server(server, Some(&transport));
+        let mut client = client;
+
+        server.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [b'h' ^ 42, b'e' ^ 42, b'l' ^ 42, b'l' ^ 42, b'o' ^ 42]);
+
+        client.write_all(&buf).await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
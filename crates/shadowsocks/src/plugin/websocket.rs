@@ -0,0 +1,793 @@
+//! Builtin WebSocket transport, compatible with v2ray-plugin
+//!
+//! Wraps connections in WebSocket, optionally over TLS, instead of running `v2ray-plugin` as plugin subprocesses.
+//! Enabled by `"plugin": "builtin-websocket"`, with `plugin_opts` in the same form of v2ray-plugin:
+//!
+//! ```plain
+//! tls;host=www.example.com;path=/ws
+//! server;tls;host=www.example.com;path=/ws;cert=/path/to/fullchain.pem;key=/path/to/key.pem
+//! ```
+//!
+//! Only `mode=websocket` is supported. Multiplexing is never used, so v2ray-plugin clients must set `mux=0`.
+
+use std::{
+    fmt::{self, Write},
+    io::{self, ErrorKind},
+    pin::Pin,
+    task::{self, Poll},
+};
+
+use base64::Engine as _;
+use bytes::{Buf, BufMut, BytesMut};
+use futures::ready;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::tls::{TlsState, poll_fill_read_buf};
+use crate::crypto::utils::random_iv_or_salt;
+
+/// Default host of v2ray-plugin
+const DEFAULT_HOST: &str = "cloudfront.com";
+/// Magic string of `Sec-WebSocket-Accept`, RFC 6455
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Maximum payload size of sending frames
+const MAX_SEND_FRAME_SIZE: usize = 16 * 1024;
+/// Maximum size of the HTTP header
+const HTTP_MAX_HEADER_SIZE: usize = 8 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Errors of parsing `WebSocketConfig`
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum WebSocketConfigError {
+    /// `mode` other than `websocket`
+    #[error("unsupported mode \"{0}\", only \"websocket\" is supported")]
+    InvalidMode(String),
+    /// Unknown option
+    #[error("unrecognized websocket option \"{0}\"")]
+    InvalidOption(String),
+    /// `host` couldn't be used as SNI
+    #[error("invalid host \"{0}\"")]
+    InvalidHost(String),
+    /// Built without TLS support
+    #[error("tls is not supported, consider enable it by feature \"plugin-websocket-tls\"")]
+    TlsNotSupported,
+    /// TLS server without certificate
+    #[error("cert and key are required for tls in server")]
+    MissingCertificate,
+    /// Failed to load certificate
+    #[error("failed to load cert and key, {0}")]
+    InvalidCertificate(String),
+}
+
+/// Configuration of builtin WebSocket transport
+#[derive(Clone)]
+pub struct WebSocketConfig {
+    /// Works as server, which accepts WebSocket connections
+    pub server: bool,
+    /// Host in HTTP requests and SNI of TLS
+    pub host: String,
+    /// Path of HTTP requests
+    pub path: String,
+    /// Enable TLS
+    pub tls: bool,
+    #[cfg(feature = "plugin-websocket-tls")]
+    tls_connector: Option<(
+        tokio_rustls::TlsConnector,
+        tokio_rustls::rustls::pki_types::ServerName<'static>,
+    )>,
+    #[cfg(feature = "plugin-websocket-tls")]
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+}
+
+impl fmt::Debug for WebSocketConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebSocketConfig")
+            .field("server", &self.server)
+            .field("host", &self.host)
+            .field("path", &self.path)
+            .field("tls", &self.tls)
+            .finish()
+    }
+}
+
+impl WebSocketConfig {
+    /// Parse from `plugin_opts`, like `tls;host=www.example.com;path=/ws`
+    ///
+    /// Certificates are loaded here if TLS is enabled in server.
+    pub fn from_plugin_opts(opts: Option<&str>) -> Result<WebSocketConfig, WebSocketConfigError> {
+        let mut server = false;
+        let mut tls = false;
+        let mut host = None;
+        let mut path = None;
+        let mut cert = None;
+        let mut key = None;
+
+        for opt in opts.unwrap_or_default().split(';') {
+            let opt = opt.trim();
+            if opt.is_empty() {
+                continue;
+            }
+
+            match opt.split_once('=') {
+                None if opt == "server" => server = true,
+                None if opt == "tls" => tls = true,
+                // TCP Fast Open is configured by `fast_open` of shadowsocks
+                None if opt == "fast-open" => {}
+                Some(("mode", "websocket")) => {}
+                Some(("mode", mode)) => return Err(WebSocketConfigError::InvalidMode(mode.to_owned())),
+                Some(("host", value)) => host = Some(value.to_owned()),
+                Some(("path", value)) => path = Some(value.to_owned()),
+                Some(("cert", value)) => cert = Some(value.to_owned()),
+                Some(("key", value)) => key = Some(value.to_owned()),
+                // Options of v2ray's own
+                Some(("mux", ..)) | Some(("loglevel", ..)) => {}
+                _ => return Err(WebSocketConfigError::InvalidOption(opt.to_owned())),
+            }
+        }
+
+        let host = host.unwrap_or_else(|| DEFAULT_HOST.to_owned());
+        let path = match path {
+            Some(p) if p.starts_with('/') => p,
+            Some(p) => format!("/{p}"),
+            None => "/".to_owned(),
+        };
+
+        if !tls {
+            return Ok(WebSocketConfig {
+                server,
+                host,
+                path,
+                tls,
+                #[cfg(feature = "plugin-websocket-tls")]
+                tls_connector: None,
+                #[cfg(feature = "plugin-websocket-tls")]
+                tls_acceptor: None,
+            });
+        }
+
+        #[cfg(not(feature = "plugin-websocket-tls"))]
+        {
+            let _ = (cert, key);
+            Err(WebSocketConfigError::TlsNotSupported)
+        }
+
+        #[cfg(feature = "plugin-websocket-tls")]
+        {
+            let (tls_connector, tls_acceptor) = if server {
+                let (cert, key) = match (cert, key) {
+                    (Some(c), Some(k)) => (c, k),
+                    _ => return Err(WebSocketConfigError::MissingCertificate),
+                };
+                (None, Some(tls::make_acceptor(&cert, &key)?))
+            } else {
+                (Some(tls::make_connector(&host)?), None)
+            };
+
+            Ok(WebSocketConfig {
+                server,
+                host,
+                path,
+                tls,
+                tls_connector,
+                tls_acceptor,
+            })
+        }
+    }
+}
+
+#[cfg(feature = "plugin-websocket-tls")]
+mod tls {
+    use std::sync::Arc;
+
+    use once_cell::sync::Lazy;
+    use tokio_rustls::{
+        TlsAcceptor, TlsConnector,
+        rustls::{ClientConfig, RootCertStore, pki_types::ServerName},
+    };
+
+    use super::WebSocketConfigError;
+
+    static TLS_CLIENT_CONFIG: Lazy<Arc<ClientConfig>> = Lazy::new(|| {
+        let mut store = RootCertStore::empty();
+        store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+        let mut config = ClientConfig::builder()
+            .with_root_certificates(store)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Arc::new(config)
+    });
+
+    pub fn make_connector(host: &str) -> Result<(TlsConnector, ServerName<'static>), WebSocketConfigError> {
+        let server_name =
+            ServerName::try_from(host.to_owned()).map_err(|_| WebSocketConfigError::InvalidHost(host.to_owned()))?;
+        Ok((TlsConnector::from(TLS_CLIENT_CONFIG.clone()), server_name))
+    }
+
+    pub fn make_acceptor(cert: &str, key: &str) -> Result<TlsAcceptor, WebSocketConfigError> {
+        crate::plugin::tls::make_acceptor(cert, key, b"http/1.1").map_err(WebSocketConfigError::InvalidCertificate)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HandshakeState {
+    ClientSendRequest,
+    ClientReadResponse,
+    ServerReadRequest,
+    ServerSendResponse,
+    ServerReject,
+    Established,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FrameHeader {
+    opcode: u8,
+    remaining: u64,
+    mask: Option<[u8; 4]>,
+    mask_offset: usize,
+}
+
+/// Stream wrapped in WebSocket binary frames
+pub struct WebSocketStream<S> {
+    pub(crate) stream: TlsState<S>,
+    is_client: bool,
+    host: String,
+    path: String,
+    handshake: HandshakeState,
+    // Handshake request or response not sent yet
+    handshake_buf: BytesMut,
+    // Received bytes not decoded yet
+    read_buf: BytesMut,
+    // Decoded data
+    data_buf: BytesMut,
+    frame: Option<FrameHeader>,
+    read_closed: bool,
+    // Encoded frames not sent yet, and the length of data they carry
+    write_buf: BytesMut,
+    write_len: usize,
+    // Pong and close frames not sent yet
+    control_buf: BytesMut,
+    close_sent: bool,
+}
+
+impl<S> fmt::Debug for WebSocketStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebSocketStream")
+            .field("is_client", &self.is_client)
+            .field("host", &self.host)
+            .field("path", &self.path)
+            .field("handshake", &self.handshake)
+            .finish()
+    }
+}
+
+impl<S> WebSocketStream<S> {
+    /// Create a client stream, handshakes on the first read or write
+    pub fn new_client(stream: S, config: &WebSocketConfig) -> WebSocketStream<S> {
+        #[cfg(feature = "plugin-websocket-tls")]
+        let stream = match config.tls_connector {
+            Some((ref connector, ref server_name)) => {
+                TlsState::Connecting(connector.connect(server_name.clone(), stream))
+            }
+            None => TlsState::Plain(stream),
+        };
+        #[cfg(not(feature = "plugin-websocket-tls"))]
+        let stream = TlsState::Plain(stream);
+
+        WebSocketStream::new(stream, true, config, HandshakeState::ClientSendRequest)
+    }
+
+    /// Create a server stream, handshakes on the first read or write
+    pub fn new_server(stream: S, config: &WebSocketConfig) -> WebSocketStream<S> {
+        #[cfg(feature = "plugin-websocket-tls")]
+        let stream = match config.tls_acceptor {
+            Some(ref acceptor) => TlsState::Accepting(acceptor.accept(stream)),
+            None => TlsState::Plain(stream),
+        };
+        #[cfg(not(feature = "plugin-websocket-tls"))]
+        let stream = TlsState::Plain(stream);
+
+        WebSocketStream::new(stream, false, config, HandshakeState::ServerReadRequest)
+    }
+
+    fn new(
+        stream: TlsState<S>,
+        is_client: bool,
+        config: &WebSocketConfig,
+        handshake: HandshakeState,
+    ) -> WebSocketStream<S> {
+        let mut ws = WebSocketStream {
+            stream,
+            is_client,
+            host: config.host.clone(),
+            path: config.path.clone(),
+            handshake,
+            handshake_buf: BytesMut::new(),
+            read_buf: BytesMut::new(),
+            data_buf: BytesMut::new(),
+            frame: None,
+            read_closed: false,
+            write_buf: BytesMut::new(),
+            write_len: 0,
+            control_buf: BytesMut::new(),
+            close_sent: false,
+        };
+        if is_client {
+            ws.make_request();
+        }
+        ws
+    }
+
+    fn make_request(&mut self) {
+        let mut key = [0u8; 16];
+        random_iv_or_salt(&mut key);
+        let key = base64::engine::general_purpose::STANDARD.encode(key);
+
+        let _ = write!(
+            HeaderWriter(&mut self.handshake_buf),
+            "GET {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             User-Agent: Go-http-client/1.1\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: websocket\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             \r\n",
+            self.path,
+            self.host,
+            key
+        );
+    }
+
+    /// Parse the handshake request of client, and make the response
+    fn accept_request(&mut self, header: &[u8]) -> bool {
+        let header = match std::str::from_utf8(header) {
+            Ok(h) => h,
+            Err(..) => return self.reject("400 Bad Request"),
+        };
+
+        let mut lines = header.split("\r\n");
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split(' ');
+        let (method, uri) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+        if method != "GET" {
+            return self.reject("405 Method Not Allowed");
+        }
+        let path = uri.split('?').next().unwrap_or_default();
+        if path != self.path {
+            return self.reject("404 Not Found");
+        }
+
+        let mut key = None;
+        let mut upgrade = false;
+        for line in lines {
+            if let Some((name, value)) = line.split_once(':') {
+                let value = value.trim();
+                if name.eq_ignore_ascii_case("Upgrade") {
+                    upgrade = value.eq_ignore_ascii_case("websocket");
+                } else if name.eq_ignore_ascii_case("Sec-WebSocket-Key") {
+                    key = Some(value);
+                }
+            }
+        }
+
+        let key = match key {
+            Some(k) if upgrade => k,
+            _ => return self.reject("400 Bad Request"),
+        };
+
+        let accept = websocket_accept(key);
+        let _ = write!(
+            HeaderWriter(&mut self.handshake_buf),
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {accept}\r\n\
+             \r\n"
+        );
+        true
+    }
+
+    fn reject(&mut self, status: &str) -> bool {
+        let _ = write!(
+            HeaderWriter(&mut self.handshake_buf),
+            "HTTP/1.1 {status}\r\n\
+             Content-Length: 0\r\n\
+             Connection: close\r\n\
+             \r\n"
+        );
+        false
+    }
+
+    /// Decode received frames into `data_buf`, returns `false` if more bytes are required
+    fn decode(&mut self) -> io::Result<bool> {
+        let mut frame = match self.frame {
+            Some(f) => f,
+            None => match self.decode_frame_header()? {
+                Some(f) => f,
+                None => return Ok(false),
+            },
+        };
+
+        let is_control = frame.opcode & 0x8 != 0;
+        let available = if is_control {
+            // Control frames are handled as a whole
+            if (self.read_buf.len() as u64) < frame.remaining {
+                self.frame = Some(frame);
+                return Ok(false);
+            }
+            frame.remaining as usize
+        } else {
+            (self.read_buf.len() as u64).min(frame.remaining) as usize
+        };
+        if available == 0 && frame.remaining > 0 {
+            self.frame = Some(frame);
+            return Ok(false);
+        }
+
+        let mut payload = self.read_buf.split_to(available);
+        if let Some(mask) = frame.mask {
+            for b in payload.iter_mut() {
+                *b ^= mask[frame.mask_offset % 4];
+                frame.mask_offset += 1;
+            }
+        }
+        frame.remaining -= available as u64;
+
+        match frame.opcode {
+            OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => self.data_buf.unsplit(payload),
+            OPCODE_CLOSE => {
+                self.read_closed = true;
+                if !self.close_sent {
+                    self.close_sent = true;
+                    self.control_buf.put_slice(&encode_frame(
+                        OPCODE_CLOSE,
+                        &payload[..payload.len().min(2)],
+                        self.is_client,
+                    ));
+                }
+            }
+            OPCODE_PING => {
+                self.control_buf
+                    .put_slice(&encode_frame(OPCODE_PONG, &payload, self.is_client));
+            }
+            OPCODE_PONG => {}
+            opcode => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("websocket unexpected opcode {opcode:#x}"),
+                ));
+            }
+        }
+
+        self.frame = if frame.remaining > 0 { Some(frame) } else { None };
+        Ok(true)
+    }
+
+    fn decode_frame_header(&mut self) -> io::Result<Option<FrameHeader>> {
+        if self.read_buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let opcode = self.read_buf[0] & 0x0f;
+        let masked = self.read_buf[1] & 0x80 != 0;
+        let (length, mut header_len) = match self.read_buf[1] & 0x7f {
+            126 => {
+                if self.read_buf.len() < 4 {
+                    return Ok(None);
+                }
+                (u16::from_be_bytes([self.read_buf[2], self.read_buf[3]]) as u64, 4)
+            }
+            127 => {
+                if self.read_buf.len() < 10 {
+                    return Ok(None);
+                }
+                let mut len = [0u8; 8];
+                len.copy_from_slice(&self.read_buf[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            len => (len as u64, 2),
+        };
+
+        if opcode & 0x8 != 0 && length > 125 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "websocket control frame too long",
+            ));
+        }
+
+        let mask = if masked {
+            if self.read_buf.len() < header_len + 4 {
+                return Ok(None);
+            }
+            let mut mask = [0u8; 4];
+            mask.copy_from_slice(&self.read_buf[header_len..header_len + 4]);
+            header_len += 4;
+            Some(mask)
+        } else {
+            None
+        };
+
+        self.read_buf.advance(header_len);
+        Ok(Some(FrameHeader {
+            opcode,
+            remaining: length,
+            mask,
+            mask_offset: 0,
+        }))
+    }
+}
+
+impl<S> WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_send_buf(&mut self, cx: &mut task::Context<'_>, handshake: bool) -> Poll<io::Result<()>> {
+        loop {
+            let buf = if handshake {
+                &mut self.handshake_buf
+            } else if !self.control_buf.is_empty() {
+                &mut self.control_buf
+            } else {
+                &mut self.write_buf
+            };
+            if buf.is_empty() {
+                return Ok(()).into();
+            }
+
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, buf))?;
+            if n == 0 {
+                return Err(ErrorKind::WriteZero.into()).into();
+            }
+            buf.advance(n);
+        }
+    }
+
+    fn poll_handshake(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match self.handshake {
+                HandshakeState::Established => return Ok(()).into(),
+                HandshakeState::ClientSendRequest => {
+                    ready!(self.poll_send_buf(cx, true))?;
+                    ready!(Pin::new(&mut self.stream).poll_flush(cx))?;
+                    self.handshake = HandshakeState::ClientReadResponse;
+                }
+                HandshakeState::ClientReadResponse | HandshakeState::ServerReadRequest => {
+                    let header_len = match self.read_buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                        Some(pos) => pos + 4,
+                        None => {
+                            if self.read_buf.len() > HTTP_MAX_HEADER_SIZE {
+                                return Err(io::Error::new(ErrorKind::InvalidData, "websocket http header too long"))
+                                    .into();
+                            }
+                            if !ready!(poll_fill_read_buf(Pin::new(&mut self.stream), cx, &mut self.read_buf))? {
+                                return Err(io::Error::new(
+                                    ErrorKind::UnexpectedEof,
+                                    "websocket closed during handshake",
+                                ))
+                                .into();
+                            }
+                            continue;
+                        }
+                    };
+
+                    let header = self.read_buf.split_to(header_len);
+                    if self.handshake == HandshakeState::ClientReadResponse {
+                        if !header.starts_with(b"HTTP/1.1 101") {
+                            let status = header.split(|b| *b == b'\r').next().unwrap_or_default();
+                            return Err(io::Error::new(
+                                ErrorKind::InvalidData,
+                                format!("websocket handshake failed, {}", String::from_utf8_lossy(status)),
+                            ))
+                            .into();
+                        }
+                        self.handshake = HandshakeState::Established;
+                    } else if self.accept_request(&header) {
+                        self.handshake = HandshakeState::ServerSendResponse;
+                    } else {
+                        self.handshake = HandshakeState::ServerReject;
+                    }
+                }
+                HandshakeState::ServerSendResponse => {
+                    ready!(self.poll_send_buf(cx, true))?;
+                    ready!(Pin::new(&mut self.stream).poll_flush(cx))?;
+                    self.handshake = HandshakeState::Established;
+                }
+                HandshakeState::ServerReject => {
+                    ready!(self.poll_send_buf(cx, true))?;
+                    let _ = ready!(Pin::new(&mut self.stream).poll_flush(cx));
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "websocket invalid handshake request",
+                    ))
+                    .into();
+                }
+            }
+        }
+    }
+}
+
+impl<S> AsyncRead for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+
+        loop {
+            if !this.data_buf.is_empty() {
+                let n = this.data_buf.len().min(buf.remaining());
+                buf.put_slice(&this.data_buf[..n]);
+                this.data_buf.advance(n);
+                return Ok(()).into();
+            }
+
+            if this.read_closed {
+                return Ok(()).into();
+            }
+
+            if this.decode()? {
+                continue;
+            }
+
+            if !ready!(poll_fill_read_buf(Pin::new(&mut this.stream), cx, &mut this.read_buf))? {
+                if this.read_buf.is_empty() && this.frame.is_none() {
+                    return Ok(()).into();
+                }
+                return Err(ErrorKind::UnexpectedEof.into()).into();
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+
+        // Encoded frames of the previous call must be sent before accepting new data.
+        // Callers are required to call again with the same `buf` after `Poll::Pending`.
+        if this.write_buf.is_empty() {
+            if buf.is_empty() {
+                return Ok(0).into();
+            }
+            let data = &buf[..buf.len().min(MAX_SEND_FRAME_SIZE)];
+            this.write_buf = encode_frame(OPCODE_BINARY, data, this.is_client);
+            this.write_len = data.len();
+        }
+
+        ready!(this.poll_send_buf(cx, false))?;
+        Ok(this.write_len).into()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+        ready!(this.poll_send_buf(cx, false))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_handshake(cx))?;
+        ready!(this.poll_send_buf(cx, false))?;
+        if !this.close_sent {
+            this.close_sent = true;
+            // 1000, normal closure
+            this.control_buf = encode_frame(OPCODE_CLOSE, &1000u16.to_be_bytes(), this.is_client);
+            ready!(this.poll_send_buf(cx, false))?;
+        }
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+/// Encode a frame, frames sent from clients are masked
+fn encode_frame(opcode: u8, payload: &[u8], masked: bool) -> BytesMut {
+    let mut frame = BytesMut::with_capacity(14 + payload.len());
+    frame.put_u8(0x80 | opcode);
+
+    let mask_bit = if masked { 0x80 } else { 0 };
+    if payload.len() < 126 {
+        frame.put_u8(mask_bit | payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        frame.put_u8(mask_bit | 126);
+        frame.put_u16(payload.len() as u16);
+    } else {
+        frame.put_u8(mask_bit | 127);
+        frame.put_u64(payload.len() as u64);
+    }
+
+    if masked {
+        let mut mask = [0u8; 4];
+        random_iv_or_salt(&mut mask);
+        frame.put_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    } else {
+        frame.put_slice(payload);
+    }
+    frame
+}
+
+fn websocket_accept(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(hasher.finalize())
+}
+
+struct HeaderWriter<'a>(&'a mut BytesMut);
+
+impl Write for HeaderWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.put_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn parse_plugin_opts() {
+        let config = WebSocketConfig::from_plugin_opts(Some("host=www.example.com;path=ws;mux=0")).unwrap();
+        assert!(!config.server);
+        assert!(!config.tls);
+        assert_eq!(config.host, "www.example.com");
+        assert_eq!(config.path, "/ws");
+
+        let config = WebSocketConfig::from_plugin_opts(Some("server")).unwrap();
+        assert!(config.server);
+        assert_eq!(config.host, DEFAULT_HOST);
+        assert_eq!(config.path, "/");
+
+        assert!(WebSocketConfig::from_plugin_opts(Some("mode=quic")).is_err());
+        assert!(WebSocketConfig::from_plugin_opts(Some("server;tls")).is_err());
+    }
+
+    #[test]
+    fn accept_key() {
+        // Example of RFC 6455
+        assert_eq!(
+            websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaITy0m4eEjSy6aSwY="
+        );
+    }
+
+    #[tokio::test]
+    async fn websocket_roundtrip() {
+        let client_config = WebSocketConfig::from_plugin_opts(Some("host=www.example.com;path=/ws")).unwrap();
+        let server_config = WebSocketConfig::from_plugin_opts(Some("server;path=/ws")).unwrap();
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = WebSocketStream::new_client(client, &client_config);
+        let mut server = WebSocketStream::new_server(server, &server_config);
+
+        let server_task = tokio::spawn(async move {
+            let mut buf = vec![0u8; 300];
+            server.read_exact(&mut buf).await.unwrap();
+            server.write_all(&buf).await.unwrap();
+            server.shutdown().await.unwrap();
+        });
+
+        let data = (0..300).map(|i| i as u8).collect::<Vec<_>>();
+        client.write_all(&data).await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+
+        server_task.await.unwrap();
+    }
+}
@@ -65,7 +65,7 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use super::{crypto_io::StreamType, proxy_stream::protocol::v2::SERVER_STREAM_TIMESTAMP_MAX_DIFF};
 use crate::{
-    config::{ServerUserManager, method_support_eih},
+    config::{ServerUser, ServerUserManager, method_support_eih},
     context::Context,
     crypto::{CipherKind, v2::tcp::TcpCipher},
 };
@@ -136,7 +136,7 @@ pub struct DecryptedReader {
     request_salt: Option<Bytes>,
     data_chunk_count: u64,
     user_manager: Option<Arc<ServerUserManager>>,
-    user_key: Option<Bytes>,
+    user: Option<Arc<ServerUser>>,
     has_handshaked: bool,
 }
 
@@ -164,7 +164,7 @@ impl DecryptedReader {
                 request_salt: None,
                 data_chunk_count: 0,
                 user_manager,
-                user_key: None,
+                user: None,
                 has_handshaked: false,
             }
         } else {
@@ -180,7 +180,7 @@ impl DecryptedReader {
                 request_salt: None,
                 data_chunk_count: 0,
                 user_manager,
-                user_key: None,
+                user: None,
                 has_handshaked: false,
             }
         }
@@ -331,14 +331,15 @@ impl DecryptedReader {
                         ByteStr::new(user_hash)
                     );
 
-                    match user_manager.get_user_by_hash(user_hash) {
+                    match user_manager.clone_user_by_hash(user_hash) {
                         None => {
                             return Err(ProtocolError::InvalidClientUser(Bytes::copy_from_slice(user_hash))).into();
                         }
                         Some(user) => {
                             trace!("{:?} chosen by EIH", user);
-                            self.user_key = Some(Bytes::copy_from_slice(user.key()));
-                            TcpCipher::new(self.method, user.key(), salt)
+                            let cipher = TcpCipher::new(self.method, user.key(), salt);
+                            self.user = Some(user);
+                            cipher
                         }
                     }
                 }
@@ -503,7 +504,12 @@ impl DecryptedReader {
 
     /// Get authenticated user key
     pub fn user_key(&self) -> Option<&[u8]> {
-        self.user_key.as_deref()
+        self.user.as_ref().map(|u| u.key())
+    }
+
+    /// Get authenticated user
+    pub fn user(&self) -> Option<&Arc<ServerUser>> {
+        self.user.as_ref()
     }
 
     /// Check if handshake finished
@@ -17,9 +17,10 @@ use log::trace;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use crate::{
-    config::ServerUserManager,
+    config::{ServerUser, ServerUserManager},
     context::Context,
     crypto::{CipherCategory, CipherKind},
+    security::replay::ReplayError,
 };
 
 #[cfg(feature = "aead-cipher")]
@@ -62,6 +63,79 @@ impl From<ProtocolError> for io::Error {
     }
 }
 
+/// Check if `err` is caused by data that couldn't be decrypted
+///
+/// It usually means the peer is using a different key, or is not a shadowsocks peer at all.
+pub fn is_decrypt_error(err: &io::Error) -> bool {
+    #[allow(unused_variables)]
+    let inner = match err.get_ref() {
+        Some(e) => e,
+        None => return false,
+    };
+
+    #[cfg(feature = "stream-cipher")]
+    if let Some(super::stream::ProtocolError::DecryptError) = inner.downcast_ref() {
+        return true;
+    }
+
+    #[cfg(feature = "aead-cipher")]
+    if let Some(super::aead::ProtocolError::DecryptDataError | super::aead::ProtocolError::DecryptLengthError) =
+        inner.downcast_ref()
+    {
+        return true;
+    }
+
+    #[cfg(feature = "aead-cipher-2022")]
+    if let Some(
+        super::aead_2022::ProtocolError::DecryptHeaderChunkError
+        | super::aead_2022::ProtocolError::DecryptDataError
+        | super::aead_2022::ProtocolError::DecryptLengthError,
+    ) = inner.downcast_ref()
+    {
+        return true;
+    }
+
+    false
+}
+
+/// Check if `err` is caused by data violating the shadowsocks protocol, other than the ones couldn't be decrypted
+pub fn is_protocol_error(err: &io::Error) -> bool {
+    if is_decrypt_error(err) {
+        return false;
+    }
+
+    #[allow(unused_variables)]
+    let inner = match err.get_ref() {
+        Some(e) => e,
+        None => return false,
+    };
+
+    #[cfg(feature = "stream-cipher")]
+    if inner.is::<super::stream::ProtocolError>() {
+        return true;
+    }
+
+    #[cfg(feature = "aead-cipher")]
+    if inner.is::<super::aead::ProtocolError>() {
+        return true;
+    }
+
+    #[cfg(feature = "aead-cipher-2022")]
+    if inner.is::<super::aead_2022::ProtocolError>() {
+        return true;
+    }
+
+    false
+}
+
+/// Check if `err` is caused by a request with repeated nonce (iv/salt), rejected by the replay filter
+pub fn is_replay_error(err: &io::Error) -> bool {
+    match err.get_ref() {
+        Some(e) => e.is::<ReplayError>(),
+        None => false,
+    }
+}
+
 /// The type of TCP stream
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum StreamType {
@@ -192,6 +266,19 @@ impl DecryptedReader {
         }
     }
 
+    /// Get authenticated user (AEAD2022)
+    pub fn user(&self) -> Option<&Arc<ServerUser>> {
+        match *self {
+            #[cfg(feature = "stream-cipher")]
+            DecryptedReader::Stream(..) => None,
+            #[cfg(feature = "aead-cipher")]
+            DecryptedReader::Aead(..) => None,
+            DecryptedReader::None => None,
+            #[cfg(feature = "aead-cipher-2022")]
+            DecryptedReader::Aead2022(ref reader) => reader.user(),
+        }
+    }
+
     pub fn handshaked(&self) -> bool {
         match *self {
             #[cfg(feature = "stream-cipher")]
@@ -477,6 +564,12 @@ impl<S> CryptoStream<S> {
         self.dec.request_nonce()
     }
 
+    /// User authenticated by EIH (AEAD2022)
+    #[inline]
+    pub fn received_user(&self) -> Option<&Arc<ServerUser>> {
+        self.dec.user()
+    }
+
     /// Set request nonce (for server stream of AEAD2022)
     #[inline]
     pub fn set_request_nonce(&mut self, request_nonce: &[u8]) {
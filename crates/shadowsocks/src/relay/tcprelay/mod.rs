@@ -1,15 +1,18 @@
 //! TCP relay
 
-pub use self::{
-    proxy_listener::ProxyListener,
-    proxy_stream::{ProxyClientStream, ProxyServerStream},
-};
+#[cfg(feature = "local")]
+pub use self::{proxy_client::ProxyClient, proxy_stream::ProxyClientStream};
+#[cfg(feature = "server")]
+pub use self::{proxy_listener::ProxyListener, proxy_stream::ProxyServerStream};
 
 #[cfg(feature = "aead-cipher")]
 mod aead;
 #[cfg(feature = "aead-cipher-2022")]
 mod aead_2022;
 pub mod crypto_io;
+#[cfg(feature = "local")]
+pub mod proxy_client;
+#[cfg(feature = "server")]
 pub mod proxy_listener;
 pub mod proxy_stream;
 #[cfg(feature = "stream-cipher")]
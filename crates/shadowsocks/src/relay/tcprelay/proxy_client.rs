@@ -0,0 +1,80 @@
+//! A client for opening connections through shadowsocks' servers
+
+use crate::{
+    config::{ServerConfig, ServerType},
+    context::{Context, SharedContext},
+    error::{Error, Result},
+    net::{ConnectOpts, TcpStream as OutboundTcpStream},
+    relay::{socks5::Address, tcprelay::proxy_stream::client::ProxyClientStream},
+};
+
+/// A client for opening TCP connections to targets through shadowsocks' servers, without running any local servers
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use shadowsocks::{ProxyClient, ServerConfig, crypto::CipherKind, relay::socks5::Address};
+/// use tokio::io::AsyncWriteExt;
+///
+/// let server_addr: std::net::SocketAddr = "127.0.0.1:8388".parse().unwrap();
+/// let svr_cfg = ServerConfig::new(server_addr, "password", CipherKind::AES_256_GCM).unwrap();
+/// let client = ProxyClient::new();
+///
+/// let mut stream = client
+///     .connect(&svr_cfg, Address::DomainNameAddress("example.com".to_owned(), 80))
+///     .await?;
+/// stream.write_all(b"GET / HTTP/1.0\r\nHost: example.com\r\n\r\n").await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Plugins of servers are not started by the client, they should be started by the caller, and `svr_cfg` must have
+/// the address of the plugin set.
+#[derive(Debug, Clone)]
+pub struct ProxyClient {
+    context: SharedContext,
+    connect_opts: ConnectOpts,
+}
+
+impl Default for ProxyClient {
+    fn default() -> ProxyClient {
+        ProxyClient::new()
+    }
+}
+
+impl ProxyClient {
+    /// Create a client with a new `Context`
+    pub fn new() -> ProxyClient {
+        ProxyClient::with_context(Context::new_shared(ServerType::Local))
+    }
+
+    /// Create a client sharing `context` with the other clients and servers
+    pub fn with_context(context: SharedContext) -> ProxyClient {
+        ProxyClient {
+            context,
+            connect_opts: ConnectOpts::default(),
+        }
+    }
+
+    /// Set options for connecting to servers
+    pub fn set_connect_opts(&mut self, connect_opts: ConnectOpts) {
+        self.connect_opts = connect_opts;
+    }
+
+    /// Get the `Context` of this client
+    pub fn context(&self) -> &SharedContext {
+        &self.context
+    }
+
+    /// Connect to target `addr` through the shadowsocks' server configured by `svr_cfg`
+    ///
+    /// The returned stream reads and writes plain data of the target. The target address is sent to the server with
+    /// the first data written. Errors of the returned stream could be categorized by [`Error::from`].
+    pub async fn connect<A>(&self, svr_cfg: &ServerConfig, addr: A) -> Result<ProxyClientStream<OutboundTcpStream>>
+    where
+        A: Into<Address>,
+    {
+        ProxyClientStream::connect_with_opts(self.context.clone(), svr_cfg, addr, &self.connect_opts)
+            .await
+            .map_err(Error::from)
+    }
+}
@@ -13,6 +13,7 @@ use crate::{
     context::SharedContext,
     crypto::CipherKind,
     net::{AcceptOpts, TcpListener},
+    plugin::BuiltinPlugin,
     relay::tcprelay::proxy_stream::server::ProxyServerStream,
 };
 
@@ -24,6 +25,7 @@ pub struct ProxyListener {
     key: Box<[u8]>,
     context: SharedContext,
     user_manager: Option<Arc<ServerUserManager>>,
+    builtin_plugin: Option<BuiltinPlugin>,
 }
 
 static DEFAULT_ACCEPT_OPTS: Lazy<AcceptOpts> = Lazy::new(Default::default);
@@ -60,9 +62,15 @@ impl ProxyListener {
             key: svr_cfg.key().to_vec().into_boxed_slice(),
             context,
             user_manager: svr_cfg.clone_user_manager(),
+            builtin_plugin: svr_cfg.builtin_plugin().cloned(),
         }
     }
 
+    /// Replace users, connections accepted after this call will be verified with `user_manager`
+    pub fn set_user_manager(&mut self, user_manager: Option<Arc<ServerUserManager>>) {
+        self.user_manager = user_manager;
+    }
+
     /// Accepts a shadowsocks' client connection
     #[inline]
     pub async fn accept(&self) -> io::Result<(ProxyServerStream<TcpStream>, SocketAddr)> {
@@ -79,12 +87,13 @@ impl ProxyListener {
         let stream = map_fn(stream);
 
         // Create a ProxyServerStream and read the target address from it
-        let stream = ProxyServerStream::from_stream_with_user_manager(
+        let stream = ProxyServerStream::from_stream_with_plugin(
             self.context.clone(),
             stream,
             self.method,
             &self.key,
             self.user_manager.clone(),
+            self.builtin_plugin.as_ref(),
         );
 
         Ok((stream, peer_addr))
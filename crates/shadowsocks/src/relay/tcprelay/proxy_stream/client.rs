@@ -21,9 +21,10 @@ use tokio::{
 use crate::relay::get_aead_2022_padding_size;
 use crate::{
     config::ServerConfig,
-    context::SharedContext,
+    context::{Context, SharedContext},
     crypto::CipherKind,
     net::{ConnectOpts, TcpStream as OutboundTcpStream},
+    plugin::PluginStream,
     relay::{
         socks5::Address,
         tcprelay::crypto_io::{CryptoRead, CryptoStream, CryptoWrite, StreamType},
@@ -49,7 +50,7 @@ enum ProxyClientStreamReadState {
 #[pin_project]
 pub struct ProxyClientStream<S> {
     #[pin]
-    stream: CryptoStream<S>,
+    stream: CryptoStream<PluginStream<S>>,
     writer_state: ProxyClientStreamWriteState,
     reader_state: ProxyClientStreamReadState,
     context: SharedContext,
@@ -115,24 +116,17 @@ where
         F: FnOnce(OutboundTcpStream) -> S,
     {
         let stream = match svr_cfg.timeout() {
-            Some(d) => {
-                match time::timeout(
-                    d,
-                    OutboundTcpStream::connect_server_with_opts(&context, svr_cfg.tcp_external_addr(), opts),
-                )
-                .await
-                {
-                    Ok(Ok(s)) => s,
-                    Ok(Err(e)) => return Err(e),
-                    Err(..) => {
-                        return Err(io::Error::new(
-                            ErrorKind::TimedOut,
-                            format!("connect {} timeout", svr_cfg.addr()),
-                        ));
-                    }
+            Some(d) => match time::timeout(d, connect_server(&context, svr_cfg, opts)).await {
+                Ok(Ok(s)) => s,
+                Ok(Err(e)) => return Err(e),
+                Err(..) => {
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        format!("connect {} timeout", svr_cfg.addr()),
+                    ));
                 }
-            }
-            None => OutboundTcpStream::connect_server_with_opts(&context, svr_cfg.tcp_external_addr(), opts).await?,
+            },
+            None => connect_server(&context, svr_cfg, opts).await?,
         };
 
         trace!(
@@ -153,6 +147,7 @@ where
         A: Into<Address>,
     {
         let addr = addr.into();
+        let stream = PluginStream::new_client(stream, svr_cfg);
         let stream = CryptoStream::from_stream_with_identity(
             &context,
             stream,
@@ -184,18 +179,36 @@ where
 
     /// Get reference to the underlying stream
     pub fn get_ref(&self) -> &S {
-        self.stream.get_ref()
+        self.stream.get_ref().get_ref()
     }
 
     /// Get mutable reference to the underlying stream
     pub fn get_mut(&mut self) -> &mut S {
-        self.stream.get_mut()
+        self.stream.get_mut().get_mut()
     }
 
     /// Consumes the `ProxyClientStream` and return the underlying stream
     pub fn into_inner(self) -> S {
-        self.stream.into_inner()
+        self.stream.into_inner().into_inner()
+    }
+}
+
+/// Connects to the server, or its plugin listening on Unix domain socket
+async fn connect_server(
+    context: &Context,
+    svr_cfg: &ServerConfig,
+    opts: &ConnectOpts,
+) -> io::Result<OutboundTcpStream> {
+    #[cfg(unix)]
+    if let Some(path) = svr_cfg.plugin_unix_addr() {
+        return OutboundTcpStream::connect_unix(path).await;
+    }
+
+    if let Some(addr) = svr_cfg.hopping_addr() {
+        return OutboundTcpStream::connect_server_with_opts(context, &addr, opts).await;
     }
+
+    OutboundTcpStream::connect_server_with_opts(context, svr_cfg.tcp_external_addr(), opts).await
 }
 
 impl<S> AsyncRead for ProxyClientStream<S>
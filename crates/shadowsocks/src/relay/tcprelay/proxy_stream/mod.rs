@@ -5,8 +5,13 @@
 //     server::{ProxyServerStream, ProxyServerStreamReadHalf, ProxyServerStreamWriteHalf},
 // };
 
-pub use self::{client::ProxyClientStream, server::ProxyServerStream};
+#[cfg(feature = "local")]
+pub use self::client::ProxyClientStream;
+#[cfg(feature = "server")]
+pub use self::server::ProxyServerStream;
 
+#[cfg(feature = "local")]
 pub mod client;
 pub mod protocol;
+#[cfg(feature = "server")]
 pub mod server;
@@ -13,9 +13,10 @@ use pin_project::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use crate::{
-    config::ServerUserManager,
+    config::{ServerUser, ServerUserManager},
     context::SharedContext,
     crypto::CipherKind,
+    plugin::{BuiltinPlugin, PluginStream},
     relay::{
         socks5::Address,
         tcprelay::{
@@ -37,7 +38,7 @@ enum ProxyServerStreamWriteState {
 #[pin_project]
 pub struct ProxyServerStream<S> {
     #[pin]
-    stream: CryptoStream<S>,
+    stream: CryptoStream<PluginStream<S>>,
     context: SharedContext,
     writer_state: ProxyServerStreamWriteState,
     has_handshaked: bool,
@@ -58,6 +59,20 @@ impl<S> ProxyServerStream<S> {
         method: CipherKind,
         key: &[u8],
         user_manager: Option<Arc<ServerUserManager>>,
+    ) -> ProxyServerStream<S> {
+        ProxyServerStream::from_stream_with_plugin(context, stream, method, key, user_manager, None)
+    }
+
+    /// Create a `ProxyServerStream` from a connection stream
+    ///
+    /// Set `plugin` to accept clients wrapped by builtin plugins.
+    pub fn from_stream_with_plugin(
+        context: SharedContext,
+        stream: S,
+        method: CipherKind,
+        key: &[u8],
+        user_manager: Option<Arc<ServerUserManager>>,
+        plugin: Option<&BuiltinPlugin>,
     ) -> ProxyServerStream<S> {
         #[cfg(feature = "aead-cipher-2022")]
         let writer_state = if method.is_aead_2022() {
@@ -73,7 +88,7 @@ impl<S> ProxyServerStream<S> {
         ProxyServerStream {
             stream: CryptoStream::from_stream_with_identity(
                 &context,
-                stream,
+                PluginStream::new_server(stream, plugin),
                 StreamType::Server,
                 method,
                 key,
@@ -88,17 +103,24 @@ impl<S> ProxyServerStream<S> {
 
     /// Get reference of the internal stream
     pub fn get_ref(&self) -> &S {
-        self.stream.get_ref()
+        self.stream.get_ref().get_ref()
     }
 
     /// Get mutable reference of the internal stream
     pub fn get_mut(&mut self) -> &mut S {
-        self.stream.get_mut()
+        self.stream.get_mut().get_mut()
     }
 
     /// Consumes the object and return the internal stream
     pub fn into_inner(self) -> S {
-        self.stream.into_inner()
+        self.stream.into_inner().into_inner()
+    }
+
+    /// Get the user authenticated by EIH (AEAD2022)
+    ///
+    /// Available after `handshake`
+    pub fn user(&self) -> Option<&Arc<ServerUser>> {
+        self.stream.received_user()
     }
 }
 
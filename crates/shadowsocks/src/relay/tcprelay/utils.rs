@@ -8,6 +8,7 @@ use std::{
     future::Future,
     io,
     pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
     task::{Context, Poll},
 };
 
@@ -18,6 +19,18 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use crate::crypto::{CipherCategory, CipherKind};
 
+/// Maximum size of buffers for reading from plain channels, 0 for unlimited
+static PLAIN_READ_BUFFER_SIZE_LIMIT: AtomicUsize = AtomicUsize::new(0);
+
+/// Limit size of buffers for reading from plain channels of all relays in this process, `None` for unlimited
+///
+/// Buffers are sized to the maximum payload of the cipher by default, 64KB for AEAD 2022 ciphers, and each relayed
+/// connection holds two of them. Smaller buffers save memory of processes with strict memory limits, like iOS
+/// `NetworkExtension`s, at the cost of more chunks and more CPU time. It only applies to relays started afterwards.
+pub fn set_plain_read_buffer_size_limit(limit: Option<usize>) {
+    PLAIN_READ_BUFFER_SIZE_LIMIT.store(limit.unwrap_or(0), Ordering::Relaxed);
+}
+
 struct CopyBuffer {
     read_done: bool,
     pos: usize,
@@ -153,8 +166,9 @@ where
     .await
 }
 
-fn plain_read_buffer_size(method: CipherKind) -> usize {
-    match method.category() {
+/// Size of buffers for reading from plain channels of relays with `method`, each relayed connection holds two of them
+pub fn plain_read_buffer_size(method: CipherKind) -> usize {
+    let size = match method.category() {
         #[cfg(feature = "aead-cipher")]
         CipherCategory::Aead => super::aead::MAX_PACKET_SIZE,
         #[cfg(feature = "stream-cipher")]
@@ -162,6 +176,11 @@ fn plain_read_buffer_size(method: CipherKind) -> usize {
         CipherCategory::None => 1 << 14,
         #[cfg(feature = "aead-cipher-2022")]
         CipherCategory::Aead2022 => super::aead_2022::MAX_PACKET_SIZE,
+    };
+
+    match PLAIN_READ_BUFFER_SIZE_LIMIT.load(Ordering::Relaxed) {
+        0 => size,
+        limit => size.min(limit),
     }
 }
 
@@ -60,6 +60,24 @@ pub enum ProtocolError {
 /// UDP shadowsocks protocol errors
 pub type ProtocolResult<T> = Result<T, ProtocolError>;
 
+impl ProtocolError {
+    /// Check if the packet couldn't be decrypted
+    ///
+    /// It usually means the peer is using a different key, or is not a shadowsocks peer at all.
+    pub fn is_decrypt_error(&self) -> bool {
+        match *self {
+            #[cfg(feature = "aead-cipher")]
+            ProtocolError::AeadError(super::aead::ProtocolError::DecryptPayloadError) => true,
+            #[cfg(feature = "aead-cipher-2022")]
+            ProtocolError::Aead2022Error(
+                super::aead_2022::ProtocolError::DecryptPayloadError
+                | super::aead_2022::ProtocolError::InvalidClientUser(..),
+            ) => true,
+            _ => false,
+        }
+    }
+}
+
 /// Encrypt `Client -> Server` payload into ShadowSocks UDP encrypted packet
 #[allow(clippy::too_many_arguments)]
 pub fn encrypt_client_payload(
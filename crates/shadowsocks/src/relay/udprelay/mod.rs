@@ -49,6 +49,8 @@
 
 use std::time::Duration;
 
+#[cfg(feature = "local")]
+pub use self::proxy_client::ProxyClientSocket;
 pub use self::proxy_socket::ProxySocket;
 pub use compat::{DatagramReceive, DatagramReceiveExt, DatagramSend, DatagramSendExt, DatagramSocket};
 
@@ -59,6 +61,8 @@ mod aead_2022;
 mod compat;
 pub mod crypto_io;
 pub mod options;
+#[cfg(feature = "local")]
+pub mod proxy_client;
 pub mod proxy_socket;
 #[cfg(feature = "stream-cipher")]
 mod stream;
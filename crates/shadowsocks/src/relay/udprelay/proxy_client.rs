@@ -0,0 +1,149 @@
+//! A client for relaying datagrams through shadowsocks' servers
+
+use std::{sync::Mutex, time::Duration};
+
+use crate::{
+    config::{ServerConfig, ServerType},
+    context::{Context, SharedContext},
+    error::Result,
+    net::{ConnectOpts, UdpSocket as ShadowUdpSocket},
+    relay::{
+        socks5::Address,
+        udprelay::{options::UdpSocketControlData, proxy_socket::ProxySocket},
+    },
+};
+
+/// A client socket for relaying UDP packets to targets through a shadowsocks' server, without running any local servers
+///
+/// The socket is one association of the server. Session ID and packet ID required by AEAD-2022 ciphers are managed by
+/// the socket, they are simply ignored by the other ciphers.
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use shadowsocks::{
+///     ProxyClientSocket, ServerConfig, crypto::CipherKind, relay::{socks5::Address, udprelay::MAXIMUM_UDP_PAYLOAD_SIZE},
+/// };
+///
+/// let server_addr: std::net::SocketAddr = "127.0.0.1:8388".parse().unwrap();
+/// let svr_cfg = ServerConfig::new(server_addr, "password", CipherKind::AES_256_GCM).unwrap();
+/// let socket = ProxyClientSocket::connect(&svr_cfg).await?;
+///
+/// let query = [0u8; 32];
+/// socket.send_to(&Address::SocketAddress("8.8.8.8:53".parse().unwrap()), &query).await?;
+///
+/// let mut buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+/// let (n, addr) = socket.recv_from(&mut buf).await?;
+/// println!("{} bytes from {}", n, addr);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ProxyClientSocket {
+    socket: ProxySocket<ShadowUdpSocket>,
+    session: Mutex<ClientSession>,
+}
+
+#[derive(Debug)]
+struct ClientSession {
+    client_session_id: u64,
+    packet_id: u64,
+}
+
+impl ClientSession {
+    fn new() -> ClientSession {
+        ClientSession {
+            client_session_id: generate_client_session_id(),
+            packet_id: 0,
+        }
+    }
+
+    /// Control data of the next packet
+    fn next_control(&mut self) -> UdpSocketControlData {
+        self.packet_id = match self.packet_id.checked_add(1) {
+            Some(id) => id,
+            None => {
+                // Packet ID couldn't be reused in the same session, so start a new session
+                *self = ClientSession::new();
+                1
+            }
+        };
+
+        UdpSocketControlData {
+            client_session_id: self.client_session_id,
+            packet_id: self.packet_id,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(feature = "aead-cipher-2022")]
+fn generate_client_session_id() -> u64 {
+    loop {
+        let id: u64 = rand::random();
+        if id != 0 {
+            break id;
+        }
+    }
+}
+
+#[cfg(not(feature = "aead-cipher-2022"))]
+fn generate_client_session_id() -> u64 {
+    0
+}
+
+impl ProxyClientSocket {
+    /// Create a socket for relaying through the server configured by `svr_cfg`, with a new `Context`
+    pub async fn connect(svr_cfg: &ServerConfig) -> Result<ProxyClientSocket> {
+        ProxyClientSocket::connect_with_opts(Context::new_shared(ServerType::Local), svr_cfg, &ConnectOpts::default())
+            .await
+    }
+
+    /// Create a socket for relaying through the server configured by `svr_cfg`, sharing `context` with the other
+    /// clients and servers
+    pub async fn connect_with_opts(
+        context: SharedContext,
+        svr_cfg: &ServerConfig,
+        opts: &ConnectOpts,
+    ) -> Result<ProxyClientSocket> {
+        let socket = ProxySocket::connect_with_opts(context, svr_cfg, opts).await?;
+
+        Ok(ProxyClientSocket {
+            socket,
+            session: Mutex::new(ClientSession::new()),
+        })
+    }
+
+    /// Set `send` timeout, `None` will clear timeout
+    pub fn set_send_timeout(&mut self, t: Option<Duration>) {
+        self.socket.set_send_timeout(t);
+    }
+
+    /// Set `recv` timeout, `None` will clear timeout
+    pub fn set_recv_timeout(&mut self, t: Option<Duration>) {
+        self.socket.set_recv_timeout(t);
+    }
+
+    /// Send `payload` to target `addr` through the server
+    ///
+    /// Returns the length of the encrypted packet sent to the server.
+    pub async fn send_to(&self, addr: &Address, payload: &[u8]) -> Result<usize> {
+        let control = self.session.lock().unwrap().next_control();
+        let n = self.socket.send_with_ctrl(addr, &control, payload).await?;
+        Ok(n)
+    }
+
+    /// Receive a packet relayed back from target, returns the length of payload and the address of target
+    ///
+    /// The payload is stored at the beginning of `buf`, which is also used for decrypting, so it has to be big enough
+    /// to store the whole shadowsocks' packet. It is recommended to allocate a buffer with at least
+    /// [`MAXIMUM_UDP_PAYLOAD_SIZE`](super::MAXIMUM_UDP_PAYLOAD_SIZE) bytes.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Address)> {
+        let (n, addr, ..) = self.socket.recv(buf).await?;
+        Ok((n, addr))
+    }
+
+    /// Get the underlying `ProxySocket`
+    pub fn get_ref(&self) -> &ProxySocket<ShadowUdpSocket> {
+        &self.socket
+    }
+}
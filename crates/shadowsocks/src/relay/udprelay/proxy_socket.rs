@@ -103,7 +103,10 @@ impl ProxySocket<ShadowUdpSocket> {
     ) -> ProxySocketResult<ProxySocket<ShadowUdpSocket>> {
         // Note: Plugins doesn't support UDP relay
 
-        let socket = ShadowUdpSocket::connect_server_with_opts(&context, svr_cfg.udp_external_addr(), opts).await?;
+        let socket = match svr_cfg.hopping_addr() {
+            Some(addr) => ShadowUdpSocket::connect_server_with_opts(&context, &addr, opts).await?,
+            None => ShadowUdpSocket::connect_server_with_opts(&context, svr_cfg.udp_external_addr(), opts).await?,
+        };
 
         trace!(
             "connected udp remote {} (outbound: {}) with {:?}",
@@ -184,6 +187,11 @@ impl<S> ProxySocket<S> {
         }
     }
 
+    /// Get users of a server socket, which are verified by `recv*` functions
+    pub fn clone_user_manager(&self) -> Option<Arc<ServerUserManager>> {
+        self.user_manager.clone()
+    }
+
     /// Set `send` timeout, `None` will clear timeout
     pub fn set_send_timeout(&mut self, t: Option<Duration>) {
         self.send_timeout = t;
@@ -507,6 +515,19 @@ where
     pub async fn recv_from_with_ctrl(
         &self,
         recv_buf: &mut [u8],
+    ) -> ProxySocketResult<(usize, SocketAddr, Address, usize, Option<UdpSocketControlData>)> {
+        self.recv_from_with_user_manager(recv_buf, self.user_manager.as_deref())
+            .await
+    }
+
+    /// Receive packet from Shadowsocks' UDP client, verified with `user_manager` instead of users of this socket
+    ///
+    /// Receivers could keep their own copy of users, which are replaced without locking this socket.
+    #[allow(clippy::type_complexity)]
+    pub async fn recv_from_with_user_manager(
+        &self,
+        recv_buf: &mut [u8],
+        user_manager: Option<&ServerUserManager>,
     ) -> ProxySocketResult<(usize, SocketAddr, Address, usize, Option<UdpSocketControlData>)> {
         // Waiting for response from server SERVER -> CLIENT
         let (recv_n, target_addr) = match self.recv_timeout {
@@ -518,7 +539,7 @@ where
             },
         };
 
-        let (n, addr, control) = match self.decrypt_recv_buffer(&mut recv_buf[..recv_n], self.user_manager.as_deref()) {
+        let (n, addr, control) = match self.decrypt_recv_buffer(&mut recv_buf[..recv_n], user_manager) {
             Ok(x) => x,
             Err(err) => return Err(ProxySocketError::ProtocolErrorWithPeer(target_addr, err)),
         };
@@ -17,6 +17,11 @@ use self::ppbloom::PingPongBloom;
 #[cfg(feature = "security-replay-attack-detect")]
 mod ppbloom;
 
+/// Error of requests rejected for their repeated nonces (iv/salt)
+#[derive(thiserror::Error, Debug)]
+#[error("detected repeated nonce (iv/salt)")]
+pub struct ReplayError;
+
 /// A Bloom Filter based protector against replay attack
 pub struct ReplayProtector {
     // Check for duplicated IV/Nonce, for prevent replay attack
@@ -51,6 +56,43 @@ impl ReplayProtector {
         }
     }
 
+    /// Create a new ReplayProtector, whose bloom filter holds `entries` nonces of stream and AEAD ciphers
+    ///
+    /// Smaller filters use less memory, but replayed nonces are forgotten sooner.
+    #[allow(unused_variables)]
+    pub fn with_capacity(config_type: ServerType, entries: usize) -> ReplayProtector {
+        ReplayProtector {
+            #[cfg(feature = "security-replay-attack-detect")]
+            nonce_ppbloom: spin::Mutex::new(PingPongBloom::with_capacity(config_type, entries)),
+            #[cfg(feature = "aead-cipher-2022")]
+            nonce_set: spin::Mutex::new(LruCache::with_expiry_duration(Duration::from_secs(
+                SERVER_STREAM_TIMESTAMP_MAX_DIFF * 2,
+            ))),
+        }
+    }
+
+    /// Bytes of the bloom filter of stream and AEAD ciphers, 0 if it is disabled
+    pub fn bloom_filter_size(&self) -> usize {
+        cfg_if! {
+            if #[cfg(feature = "security-replay-attack-detect")] {
+                self.nonce_ppbloom.lock().memory_size()
+            } else {
+                0
+            }
+        }
+    }
+
+    /// Number of nonces of AEAD 2022 ciphers remembered, including expired ones that are not removed yet
+    pub fn nonce_count(&self) -> usize {
+        cfg_if! {
+            if #[cfg(feature = "aead-cipher-2022")] {
+                self.nonce_set.lock().len()
+            } else {
+                0
+            }
+        }
+    }
+
     /// Check if nonce exist or not
     #[inline(always)]
     pub fn check_nonce_and_set(&self, method: CipherKind, nonce: &[u8]) -> bool {
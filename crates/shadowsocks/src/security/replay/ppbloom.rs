@@ -33,17 +33,32 @@ pub struct PingPongBloom {
     bloom_count: [usize; 2],
     item_count: usize,
     current: usize,
+    bitmap_size: usize,
 }
 
 impl PingPongBloom {
     pub fn new(ty: ServerType) -> PingPongBloom {
-        let (mut item_count, fp_p) = if ty.is_local() {
-            (BF_NUM_ENTRIES_FOR_CLIENT, BF_ERROR_RATE_FOR_CLIENT)
+        let item_count = if ty.is_local() {
+            BF_NUM_ENTRIES_FOR_CLIENT
         } else {
-            (BF_NUM_ENTRIES_FOR_SERVER, BF_ERROR_RATE_FOR_SERVER)
+            BF_NUM_ENTRIES_FOR_SERVER
+        };
+        PingPongBloom::with_capacity(ty, item_count)
+    }
+
+    // Hold `item_count` entries, at least 2
+    pub fn with_capacity(ty: ServerType, item_count: usize) -> PingPongBloom {
+        let fp_p = if ty.is_local() {
+            BF_ERROR_RATE_FOR_CLIENT
+        } else {
+            BF_ERROR_RATE_FOR_SERVER
         };
 
-        item_count /= 2;
+        let item_count = (item_count / 2).max(1);
+
+        // Same as the bitmap allocated by `Bloom::new_for_fp_rate`
+        let ln2 = std::f64::consts::LN_2;
+        let bitmap_size = ((item_count as f64) * fp_p.ln() / (-8.0 * ln2 * ln2)).ceil() as usize;
 
         PingPongBloom {
             blooms: [
@@ -53,9 +68,15 @@ impl PingPongBloom {
             bloom_count: [0, 0],
             item_count,
             current: 0,
+            bitmap_size,
         }
     }
 
+    // Bytes of bitmaps of both bloom filters
+    pub fn memory_size(&self) -> usize {
+        self.bitmap_size * self.blooms.len()
+    }
+
     // Check if data in `buf` exist.
     //
     // Set into the current bloom filter if not exist.
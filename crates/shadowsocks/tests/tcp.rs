@@ -1,3 +1,5 @@
+#![cfg(all(feature = "local", feature = "server"))]
+
 use std::{
     io::{self},
     net::SocketAddr,
@@ -8,13 +10,13 @@ use byte_string::ByteStr;
 use futures::future;
 use log::info;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     net::{TcpListener, TcpStream},
     sync::Barrier,
 };
 
 use shadowsocks::{
-    ProxyClientStream, ProxyListener,
+    Error, ProxyClient, ProxyClientStream, ProxyListener,
     config::{ServerConfig, ServerType},
     context::Context,
     crypto::CipherKind,
@@ -219,3 +221,66 @@ async fn tcp_tunnel_aead_2022_chacha20() {
     .await
     .unwrap();
 }
+
+#[tokio::test]
+async fn tcp_proxy_client() {
+    let _ = env_logger::try_init();
+
+    let server_addr = "127.0.0.1:36001".parse::<SocketAddr>().unwrap();
+    let svr_cfg = ServerConfig::new(server_addr, "", CipherKind::NONE).unwrap();
+
+    let echo_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo_listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = echo_listener.accept().await {
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = tokio::io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+
+    let listener = ProxyListener::bind(Context::new_shared(ServerType::Server), &svr_cfg)
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(handle_tcp_tunnel_server_client(CipherKind::NONE, stream));
+        }
+    });
+
+    let client = ProxyClient::new();
+    let mut stream = client.connect(&svr_cfg, echo_addr).await.unwrap();
+
+    const MESSAGE: &[u8] = b"hello shadowsocks";
+    stream.write_all(MESSAGE).await.unwrap();
+
+    let mut buffer = [0u8; MESSAGE.len()];
+    stream.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, MESSAGE);
+}
+
+#[cfg(feature = "aead-cipher")]
+#[tokio::test]
+async fn tcp_wrong_password_crypto_error() {
+    let _ = env_logger::try_init();
+
+    let server_addr = "127.0.0.1:36101".parse::<SocketAddr>().unwrap();
+    let svr_cfg = ServerConfig::new(server_addr, "server-password", CipherKind::AES_256_GCM).unwrap();
+    let client_cfg = ServerConfig::new(server_addr, "client-password", CipherKind::AES_256_GCM).unwrap();
+
+    let listener = ProxyListener::bind(Context::new_shared(ServerType::Server), &svr_cfg)
+        .await
+        .unwrap();
+
+    let client = ProxyClient::new();
+    let mut stream = client
+        .connect(&client_cfg, Address::DomainNameAddress("example.com".to_owned(), 80))
+        .await
+        .unwrap();
+    stream.write_all(b"hello shadowsocks").await.unwrap();
+
+    let (mut server_stream, _) = listener.accept().await.unwrap();
+    let err = server_stream.handshake().await.unwrap_err();
+    assert!(matches!(Error::from(err), Error::Crypto(..)));
+}
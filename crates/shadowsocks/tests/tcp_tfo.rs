@@ -1,3 +1,4 @@
+#![cfg(all(feature = "local", feature = "server"))]
 #![cfg(any(
     windows,
     target_os = "linux",
@@ -1,3 +1,5 @@
+#![cfg(all(feature = "local", feature = "server"))]
+
 use std::{io, net::SocketAddr, sync::Arc};
 
 use byte_string::ByteStr;
@@ -9,7 +11,10 @@ use shadowsocks::{
     context::{Context, SharedContext},
     crypto::CipherKind,
     net::UdpSocket as ShadowUdpSocket,
-    relay::{socks5::Address, udprelay::ProxySocket},
+    relay::{
+        socks5::Address,
+        udprelay::{ProxyClientSocket, ProxySocket},
+    },
 };
 
 async fn handle_udp_server_client(
@@ -222,3 +227,41 @@ async fn udp_tunnel_aead_2022_chacha20() {
     .await
     .unwrap();
 }
+
+#[tokio::test]
+async fn udp_proxy_client_socket() {
+    let _ = env_logger::try_init();
+
+    let server_addr = "127.0.0.1:26001".parse::<SocketAddr>().unwrap();
+    let svr_cfg = ServerConfig::new(server_addr, "", CipherKind::NONE).unwrap();
+
+    let echo_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo_socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; 65536];
+        while let Ok((n, peer_addr)) = echo_socket.recv_from(&mut buffer).await {
+            let _ = echo_socket.send_to(&buffer[..n], peer_addr).await;
+        }
+    });
+
+    let server_socket = ProxySocket::bind(Context::new_shared(ServerType::Server), &svr_cfg)
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        let mut recv_buf = vec![0u8; 65536];
+        loop {
+            let (n, peer_addr, remote_addr, ..) = server_socket.recv_from(&mut recv_buf).await.unwrap();
+            let _ = handle_udp_server_client(peer_addr, remote_addr, &recv_buf[..n], &server_socket).await;
+        }
+    });
+
+    let socket = ProxyClientSocket::connect(&svr_cfg).await.unwrap();
+
+    const SEND_PAYLOAD: &[u8] = b"HELLO WORLD. \x0012345";
+    socket.send_to(&echo_addr.into(), SEND_PAYLOAD).await.unwrap();
+
+    let mut buffer = vec![0u8; 65536];
+    let (n, addr) = socket.recv_from(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..n], SEND_PAYLOAD);
+    assert_eq!(addr, Address::SocketAddress(echo_addr));
+}
@@ -1,7 +1,7 @@
 //! Common configuration utilities
 
 use std::{
-    env,
+    env, fmt,
     fs::OpenOptions,
     io::{self, Read},
     path::{Path, PathBuf},
@@ -102,6 +102,18 @@ pub struct Config {
 
     /// Runtime configuration
     pub runtime: RuntimeConfig,
+
+    /// Run in background, detached from the terminal
+    #[cfg(unix)]
+    pub daemonize: bool,
+
+    /// Write PID of the running process to this file, and remove it when exits
+    #[cfg(unix)]
+    pub pid_file: Option<PathBuf>,
+
+    /// Write statistics and active connections dumped by `SIGUSR1` to this file, instead of logs
+    #[cfg(unix)]
+    pub dump_path: Option<PathBuf>,
 }
 
 impl Config {
@@ -137,6 +149,9 @@ impl Config {
                 if let Some(without_time) = format.without_time {
                     nformat.without_time = without_time;
                 }
+                if let Some(json) = format.json {
+                    nformat.json = json;
+                }
                 nlog.format = nformat;
             }
 
@@ -144,6 +159,58 @@ impl Config {
                 nlog.config_path = Some(PathBuf::from(config_path));
             }
 
+            if let Some(syslog) = log.syslog {
+                let mut nsyslog = SyslogConfig::default();
+                if let Some(address) = syslog.address {
+                    match address.parse::<SyslogAddress>() {
+                        Ok(a) => nsyslog.address = a,
+                        Err(..) => return Err(ConfigError::InvalidValue(address)),
+                    }
+                }
+                if let Some(facility) = syslog.facility {
+                    match facility.parse::<SyslogFacility>() {
+                        Ok(f) => nsyslog.facility = f,
+                        Err(..) => return Err(ConfigError::InvalidValue(facility)),
+                    }
+                }
+                nlog.syslog = Some(nsyslog);
+            }
+
+            #[cfg(windows)]
+            if let Some(event_log) = log.event_log {
+                nlog.event_log = Some(event_log);
+            }
+
+            if let Some(file) = log.file {
+                let mut nfile = LogFileConfig::new(PathBuf::from(file.path));
+                if let Some(rotation) = file.rotation {
+                    match rotation.parse::<LogRotation>() {
+                        Ok(r) => nfile.rotation = r,
+                        Err(..) => return Err(ConfigError::InvalidValue(rotation)),
+                    }
+                }
+                if let Some(max_size) = file.max_size {
+                    if max_size == 0 {
+                        return Err(ConfigError::InvalidValue(max_size.to_string()));
+                    }
+                    nfile.max_size = Some(max_size);
+                }
+                if let Some(max_files) = file.max_files {
+                    nfile.max_files = max_files;
+                }
+                if let Some(compress) = file.compress {
+                    nfile.compress = compress;
+                }
+                nlog.file = Some(nfile);
+            }
+
+            #[cfg(feature = "tracing-otlp")]
+            if let Some(otlp) = log.otlp {
+                nlog.otlp = Some(OtlpConfig {
+                    endpoint: otlp.endpoint,
+                });
+            }
+
             config.log = nlog;
         }
 
@@ -162,9 +229,32 @@ impl Config {
                 }
             }
 
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            if let Some(cpu_affinity) = runtime.cpu_affinity {
+                if cpu_affinity.is_empty() {
+                    return Err(ConfigError::InvalidValue("cpu_affinity".to_owned()));
+                }
+                nruntime.cpu_affinity = Some(cpu_affinity);
+            }
+
             config.runtime = nruntime;
         }
 
+        #[cfg(unix)]
+        if let Some(daemonize) = ssconfig.daemonize {
+            config.daemonize = daemonize;
+        }
+
+        #[cfg(unix)]
+        if let Some(pid_file) = ssconfig.pid_file {
+            config.pid_file = Some(PathBuf::from(pid_file));
+        }
+
+        #[cfg(unix)]
+        if let Some(dump_path) = ssconfig.dump_path {
+            config.dump_path = Some(PathBuf::from(dump_path));
+        }
+
         Ok(config)
     }
 
@@ -181,9 +271,44 @@ impl Config {
                 self.log.format.without_time = true;
             }
 
+            if matches.get_flag("LOG_JSON") {
+                self.log.format.json = true;
+            }
+
             if let Some(log_config) = matches.get_one::<PathBuf>("LOG_CONFIG").cloned() {
                 self.log.config_path = Some(log_config);
             }
+
+            if let Some(address) = matches.get_one::<SyslogAddress>("LOG_SYSLOG").cloned() {
+                let facility = matches.get_one::<SyslogFacility>("LOG_SYSLOG_FACILITY").copied();
+                let syslog = self.log.syslog.get_or_insert_with(SyslogConfig::default);
+                syslog.address = address;
+                if let Some(facility) = facility {
+                    syslog.facility = facility;
+                }
+            }
+
+            #[cfg(windows)]
+            if let Some(source) = matches.get_one::<String>("LOG_EVENT_LOG").cloned() {
+                self.log.event_log = Some(source);
+            }
+
+            if let Some(path) = matches.get_one::<PathBuf>("LOG_FILE").cloned() {
+                let file = self.log.file.get_or_insert_with(|| LogFileConfig::new(path.clone()));
+                file.path = path;
+                if let Some(rotation) = matches.get_one::<LogRotation>("LOG_FILE_ROTATION") {
+                    file.rotation = *rotation;
+                }
+                if let Some(max_size) = matches.get_one::<u64>("LOG_FILE_MAX_SIZE") {
+                    file.max_size = Some(*max_size);
+                }
+                if let Some(max_files) = matches.get_one::<usize>("LOG_FILE_MAX_FILES") {
+                    file.max_files = *max_files;
+                }
+                if matches.get_flag("LOG_FILE_COMPRESS") {
+                    file.compress = true;
+                }
+            }
         }
 
         #[cfg(feature = "multi-threaded")]
@@ -196,6 +321,28 @@ impl Config {
             self.runtime.worker_count = Some(*worker_count);
         }
 
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(cpu_affinity) = matches.get_many::<usize>("CPU_AFFINITY") {
+            self.runtime.cpu_affinity = Some(cpu_affinity.copied().collect());
+        }
+
+        #[cfg(unix)]
+        if matches.get_flag("DAEMONIZE") {
+            self.daemonize = true;
+        }
+
+        #[cfg(unix)]
+        if let Some(pid_file) = matches.get_one::<PathBuf>("DAEMONIZE_PID_PATH").cloned() {
+            // --daemonize-pid implies --daemonize
+            self.daemonize = true;
+            self.pid_file = Some(pid_file);
+        }
+
+        #[cfg(unix)]
+        if let Some(dump_path) = matches.get_one::<PathBuf>("DUMP_PATH").cloned() {
+            self.dump_path = Some(dump_path);
+        }
+
         let _ = matches;
     }
 }
@@ -210,6 +357,25 @@ pub struct LogConfig {
     pub format: LogFormatConfig,
     /// Logging configuration file path
     pub config_path: Option<PathBuf>,
+    /// Send logs to syslog instead of stdout
+    pub syslog: Option<SyslogConfig>,
+    /// Report logs to Windows Event Log with this source name instead of stdout, ignored if `syslog` is set
+    #[cfg(windows)]
+    pub event_log: Option<String>,
+    /// Write logs to a file instead of stdout, ignored if `syslog` or `event_log` is set
+    pub file: Option<LogFileConfig>,
+    /// Export spans of connections by OpenTelemetry Protocol
+    #[cfg(feature = "tracing-otlp")]
+    pub otlp: Option<OtlpConfig>,
+}
+
+/// OpenTelemetry Protocol exporter configuration
+#[cfg(feature = "tracing-otlp")]
+#[derive(Debug, Clone, Default)]
+pub struct OtlpConfig {
+    /// OTLP/HTTP traces endpoint, like `http://127.0.0.1:4318/v1/traces`.
+    /// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` or the default endpoint will be used if not set
+    pub endpoint: Option<String>,
 }
 
 /// Logger format configuration
@@ -217,6 +383,230 @@ pub struct LogConfig {
 #[derive(Debug, Clone, Default)]
 pub struct LogFormatConfig {
     pub without_time: bool,
+    /// Emit one JSON object per line, instead of the human readable format
+    pub json: bool,
+}
+
+/// Address of syslog
+#[cfg(feature = "logging")]
+#[derive(Debug, Clone)]
+pub enum SyslogAddress {
+    /// Unix datagram socket, like `/dev/log`
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// Remote server by UDP, like `udp://192.168.1.1:514`
+    Udp(String),
+}
+
+#[cfg(all(feature = "logging", unix))]
+impl Default for SyslogAddress {
+    fn default() -> SyslogAddress {
+        SyslogAddress::Unix(PathBuf::from("/dev/log"))
+    }
+}
+
+#[cfg(all(feature = "logging", not(unix)))]
+impl Default for SyslogAddress {
+    fn default() -> SyslogAddress {
+        SyslogAddress::Udp("127.0.0.1:514".to_owned())
+    }
+}
+
+/// Parse `SyslogAddress` error
+#[cfg(feature = "logging")]
+#[derive(Debug, Clone)]
+pub struct SyslogAddressError;
+
+#[cfg(feature = "logging")]
+impl fmt::Display for SyslogAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid syslog address, should be udp://host:port or a unix socket path")
+    }
+}
+
+#[cfg(feature = "logging")]
+impl std::error::Error for SyslogAddressError {}
+
+#[cfg(feature = "logging")]
+impl FromStr for SyslogAddress {
+    type Err = SyslogAddressError;
+
+    fn from_str(s: &str) -> Result<SyslogAddress, Self::Err> {
+        if let Some(addr) = s.strip_prefix("udp://") {
+            if addr.rsplit_once(':').is_none() {
+                return Err(SyslogAddressError);
+            }
+            return Ok(SyslogAddress::Udp(addr.to_owned()));
+        }
+
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix://") {
+            return Ok(SyslogAddress::Unix(PathBuf::from(path)));
+        }
+
+        #[cfg(unix)]
+        if s.starts_with('/') {
+            return Ok(SyslogAddress::Unix(PathBuf::from(s)));
+        }
+
+        Err(SyslogAddressError)
+    }
+}
+
+#[cfg(feature = "logging")]
+impl fmt::Display for SyslogAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            #[cfg(unix)]
+            SyslogAddress::Unix(ref path) => write!(f, "{}", path.display()),
+            SyslogAddress::Udp(ref addr) => write!(f, "udp://{addr}"),
+        }
+    }
+}
+
+/// Syslog facility
+#[cfg(feature = "logging")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyslogFacility(u8);
+
+/// Parse `SyslogFacility` error
+#[cfg(feature = "logging")]
+#[derive(Debug, Clone)]
+pub struct SyslogFacilityError;
+
+#[cfg(feature = "logging")]
+impl fmt::Display for SyslogFacilityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid syslog facility, should be one of user, daemon, local0 ~ local7")
+    }
+}
+
+#[cfg(feature = "logging")]
+impl std::error::Error for SyslogFacilityError {}
+
+#[cfg(feature = "logging")]
+impl SyslogFacility {
+    /// `daemon`
+    pub const DAEMON: SyslogFacility = SyslogFacility(3);
+
+    /// Facility code
+    pub fn code(self) -> u8 {
+        self.0
+    }
+}
+
+#[cfg(feature = "logging")]
+impl FromStr for SyslogFacility {
+    type Err = SyslogFacilityError;
+
+    fn from_str(s: &str) -> Result<SyslogFacility, Self::Err> {
+        let code = match s {
+            "user" => 1,
+            "daemon" => 3,
+            "local0" => 16,
+            "local1" => 17,
+            "local2" => 18,
+            "local3" => 19,
+            "local4" => 20,
+            "local5" => 21,
+            "local6" => 22,
+            "local7" => 23,
+            _ => return Err(SyslogFacilityError),
+        };
+        Ok(SyslogFacility(code))
+    }
+}
+
+/// Syslog configuration
+#[cfg(feature = "logging")]
+#[derive(Debug, Clone)]
+pub struct SyslogConfig {
+    /// Where messages are sent to, `/dev/log` by default
+    pub address: SyslogAddress,
+    /// Facility of messages, `daemon` by default
+    pub facility: SyslogFacility,
+}
+
+#[cfg(feature = "logging")]
+impl Default for SyslogConfig {
+    fn default() -> SyslogConfig {
+        SyslogConfig {
+            address: SyslogAddress::default(),
+            facility: SyslogFacility::DAEMON,
+        }
+    }
+}
+
+/// When log files are rotated by time
+#[cfg(feature = "logging")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogRotation {
+    /// Only rotated by size
+    #[default]
+    Never,
+    /// At the beginning of every hour
+    Hourly,
+    /// At midnight of every day
+    Daily,
+}
+
+/// Parse `LogRotation` error
+#[cfg(feature = "logging")]
+#[derive(Debug, Clone)]
+pub struct LogRotationError;
+
+#[cfg(feature = "logging")]
+impl fmt::Display for LogRotationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid log rotation, should be one of never, hourly, daily")
+    }
+}
+
+#[cfg(feature = "logging")]
+impl std::error::Error for LogRotationError {}
+
+#[cfg(feature = "logging")]
+impl FromStr for LogRotation {
+    type Err = LogRotationError;
+
+    fn from_str(s: &str) -> Result<LogRotation, Self::Err> {
+        match s {
+            "never" => Ok(LogRotation::Never),
+            "hourly" => Ok(LogRotation::Hourly),
+            "daily" => Ok(LogRotation::Daily),
+            _ => Err(LogRotationError),
+        }
+    }
+}
+
+/// Log file configuration
+#[cfg(feature = "logging")]
+#[derive(Debug, Clone)]
+pub struct LogFileConfig {
+    /// Path of the active log file
+    pub path: PathBuf,
+    /// Rotate by time, `never` by default
+    pub rotation: LogRotation,
+    /// Rotate when the file would exceed this size in bytes
+    pub max_size: Option<u64>,
+    /// Rotated files to keep, 7 by default. 0 keeps all of them
+    pub max_files: usize,
+    /// Compress rotated files with gzip
+    pub compress: bool,
+}
+
+#[cfg(feature = "logging")]
+impl LogFileConfig {
+    /// Create a configuration of `path`, never rotated
+    pub fn new(path: PathBuf) -> LogFileConfig {
+        LogFileConfig {
+            path,
+            rotation: LogRotation::Never,
+            max_size: None,
+            max_files: 7,
+            compress: false,
+        }
+    }
 }
 
 /// Runtime mode (Tokio)
@@ -256,6 +646,9 @@ pub struct RuntimeConfig {
     pub worker_count: Option<usize>,
     /// Runtime Mode, single-thread, multi-thread
     pub mode: RuntimeMode,
+    /// Pin threads of the runtime to these CPU cores in turn, the main thread is pinned to the first one
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub cpu_affinity: Option<Vec<usize>>,
 }
 
 #[derive(Deserialize)]
@@ -263,6 +656,12 @@ struct SSConfig {
     #[cfg(feature = "logging")]
     log: Option<SSLogConfig>,
     runtime: Option<SSRuntimeConfig>,
+    #[cfg(unix)]
+    daemonize: Option<bool>,
+    #[cfg(unix)]
+    pid_file: Option<String>,
+    #[cfg(unix)]
+    dump_path: Option<String>,
 }
 
 #[cfg(feature = "logging")]
@@ -271,17 +670,71 @@ struct SSLogConfig {
     level: Option<u32>,
     format: Option<SSLogFormat>,
     config_path: Option<String>,
+    syslog: Option<SSSyslogConfig>,
+    #[cfg(windows)]
+    event_log: Option<String>,
+    file: Option<SSLogFileConfig>,
+    #[cfg(feature = "tracing-otlp")]
+    otlp: Option<SSOtlpConfig>,
+}
+
+#[cfg(feature = "tracing-otlp")]
+#[derive(Deserialize)]
+struct SSOtlpConfig {
+    endpoint: Option<String>,
+}
+
+#[cfg(feature = "logging")]
+#[derive(Deserialize)]
+struct SSSyslogConfig {
+    address: Option<String>,
+    facility: Option<String>,
+}
+
+#[cfg(feature = "logging")]
+#[derive(Deserialize)]
+struct SSLogFileConfig {
+    path: String,
+    rotation: Option<String>,
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+    compress: Option<bool>,
 }
 
 #[cfg(feature = "logging")]
 #[derive(Deserialize)]
 struct SSLogFormat {
     without_time: Option<bool>,
+    json: Option<bool>,
 }
 
 #[derive(Deserialize)]
 struct SSRuntimeConfig {
     #[cfg(feature = "multi-threaded")]
+    #[serde(alias = "worker_threads")]
     worker_count: Option<usize>,
     mode: Option<String>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    cpu_affinity: Option<Vec<usize>>,
+}
+
+#[cfg(all(test, feature = "logging"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_syslog_config() {
+        assert!(
+            matches!("udp://127.0.0.1:514".parse::<SyslogAddress>(), Ok(SyslogAddress::Udp(ref a)) if a == "127.0.0.1:514")
+        );
+        #[cfg(unix)]
+        assert!(matches!(
+            "/dev/log".parse::<SyslogAddress>(),
+            Ok(SyslogAddress::Unix(..))
+        ));
+        assert!("127.0.0.1:514".parse::<SyslogAddress>().is_err());
+
+        assert_eq!("local3".parse::<SyslogFacility>().unwrap(), SyslogFacility(19));
+        assert!("kern".parse::<SyslogFacility>().is_err());
+    }
 }
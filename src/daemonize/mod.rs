@@ -5,7 +5,7 @@ use cfg_if::cfg_if;
 cfg_if! {
     if #[cfg(unix)] {
         mod unix;
-        pub use self::unix::daemonize;
+        pub use self::unix::{daemonize, write_pid_file};
     } else {
         compile_error!("Process daemonization is not supported by the current platform");
     }
@@ -1,24 +1,134 @@
-use std::{env::current_dir, path::Path};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Read, Seek, Write},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    process,
+    sync::OnceLock,
+};
 
-use daemonize::Daemonize;
-use log::error;
+use log::{debug, warn};
+
+// PID file of the running process, locked until it exits
+static PID_FILE: OnceLock<PidFile> = OnceLock::new();
+
+struct PidFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Open and lock `path`, fails if it is locked by another running process
+    fn lock(path: &Path) -> io::Result<PidFile> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(path)?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != ErrorKind::WouldBlock {
+                return Err(err);
+            }
+
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!(
+                    "pid file {} is locked by another running process {}",
+                    path.display(),
+                    pid.trim()
+                ),
+            ));
+        }
+
+        Ok(PidFile {
+            file,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Write PID of the current process, and keep it locked until exits
+    fn write_pid(mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        writeln!(self.file, "{}", process::id())?;
+        self.file.sync_all()?;
+        debug!("wrote pid {} to {}", process::id(), self.path.display());
+
+        if PID_FILE.set(self).is_ok() {
+            unsafe {
+                libc::atexit(remove_pid_file);
+            }
+        }
+        Ok(())
+    }
+}
+
+extern "C" fn remove_pid_file() {
+    if let Some(pid_file) = PID_FILE.get() {
+        // It may not be permitted after privileges are dropped
+        if let Err(err) = fs::remove_file(&pid_file.path) {
+            warn!("failed to remove pid file {}, error: {}", pid_file.path.display(), err);
+        }
+    }
+}
+
+/// Fork, and exit in the parent process
+fn fork_and_exit_parent() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/// Redirect `stdin`, `stdout`, `stderr` to `/dev/null`
+fn redirect_stdio() -> io::Result<()> {
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
 
 /// Daemonize a server process in a *nix standard way
 ///
-/// This function will redirect `stdout`, `stderr` to `/dev/null`,
-/// and follow the exact behavior in shadowsocks-libev
-pub fn daemonize<F: AsRef<Path>>(pid_path: Option<F>) {
-    let pwd = current_dir()
-        .unwrap_or_else(|err| panic!("cannot get current working directory, {err:?}"))
-        .canonicalize()
-        .unwrap_or_else(|err| panic!("cannot get absolute path to working directory, {err:?}"));
-    let mut d = Daemonize::new().umask(0).working_directory(pwd);
+/// The process forks twice with `setsid` in between, so it is detached from the controlling terminal and is not a
+/// session leader. `stdin`, `stdout`, `stderr` are redirected to `/dev/null`, and the working directory is not
+/// changed, following the exact behavior in shadowsocks-libev.
+///
+/// The PID file is locked before forking, so an error is returned to the terminal if another process is running with
+/// the same PID file.
+pub fn daemonize(pid_path: Option<&Path>) -> io::Result<()> {
+    let pid_file = pid_path.map(PidFile::lock).transpose()?;
+
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    fork_and_exit_parent()?;
 
-    if let Some(p) = pid_path {
-        d = d.pid_file(p);
+    unsafe {
+        libc::umask(0);
     }
+    redirect_stdio()?;
 
-    if let Err(err) = d.start() {
-        error!("failed to daemonize, {:?} ({})", err, err);
+    if let Some(pid_file) = pid_file {
+        pid_file.write_pid()?;
     }
+    Ok(())
+}
+
+/// Write PID of the current process to `pid_path` without daemonizing, for supervisors running it in foreground
+///
+/// The PID file is removed when the process exits.
+pub fn write_pid_file(pid_path: &Path) -> io::Result<()> {
+    PidFile::lock(pid_path)?.write_pid()
 }
@@ -0,0 +1,120 @@
+//! Logging to Windows Event Log
+//!
+//! Each event is reported to the Application log, with the type mapped from its level.
+
+use std::{io, os::windows::ffi::OsStrExt, ptr};
+
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+use windows_sys::Win32::System::EventLog::{
+    DeregisterEventSource, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE, REPORT_EVENT_TYPE,
+    RegisterEventSourceW, ReportEventW,
+};
+
+/// Reports messages formatted by `tracing_subscriber::fmt` to Windows Event Log
+pub struct EventLogWriter {
+    handle: isize,
+}
+
+impl EventLogWriter {
+    /// Register event source `source` on the local computer
+    pub fn register(source: &str) -> io::Result<EventLogWriter> {
+        let source = to_wide(source);
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), source.as_ptr()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventLogWriter {
+            handle: handle as isize,
+        })
+    }
+
+    fn report(&self, event_type: REPORT_EVENT_TYPE, message: &[u8]) -> io::Result<()> {
+        let message = String::from_utf8_lossy(message.trim_ascii_end());
+        let message = to_wide(&message);
+        let strings = [message.as_ptr()];
+
+        let ret = unsafe {
+            ReportEventW(
+                self.handle as _,
+                event_type,
+                0,
+                0,
+                ptr::null_mut(),
+                strings.len() as u16,
+                0,
+                strings.as_ptr(),
+                ptr::null(),
+            )
+        };
+        if ret == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EventLogWriter {
+    fn drop(&mut self) {
+        unsafe {
+            DeregisterEventSource(self.handle as _);
+        }
+    }
+}
+
+fn to_wide(s: &str) -> Vec<u16> {
+    std::ffi::OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
+
+/// One event, reported when dropped
+pub struct EventLogMessage<'a> {
+    writer: &'a EventLogWriter,
+    event_type: REPORT_EVENT_TYPE,
+    buffer: Vec<u8>,
+}
+
+impl io::Write for EventLogMessage<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EventLogMessage<'_> {
+    fn drop(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        // Logs couldn't be logged
+        let _ = self.writer.report(self.event_type, &self.buffer);
+    }
+}
+
+impl<'a> MakeWriter<'a> for EventLogWriter {
+    type Writer = EventLogMessage<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        EventLogMessage {
+            writer: self,
+            event_type: EVENTLOG_INFORMATION_TYPE,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let event_type = match *meta.level() {
+            Level::ERROR => EVENTLOG_ERROR_TYPE,
+            Level::WARN => EVENTLOG_WARNING_TYPE,
+            Level::INFO | Level::DEBUG | Level::TRACE => EVENTLOG_INFORMATION_TYPE,
+        };
+        EventLogMessage {
+            writer: self,
+            event_type,
+            buffer: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,280 @@
+//! Logging to a file with rotation
+//!
+//! The active file is renamed to `<path>.<yyyymmdd-hhmmss.mmm>` when it is rotated, and a new file is created at
+//! `<path>`. Rotated files are optionally compressed with gzip, and the oldest ones beyond `max_files` are removed,
+//! both in a background thread.
+//!
+//! Rotation could also be requested by [`request_rotation`]. If the active file has been moved by others (like
+//! `logrotate`) by then, it is reopened instead.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+
+use flate2::{Compression, write::GzEncoder};
+use time::{OffsetDateTime, Time, UtcOffset};
+
+use crate::config::{LogFileConfig, LogRotation};
+
+static ROTATION_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Rotate log files before the next write
+pub fn request_rotation() {
+    ROTATION_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Log file, rotated before writes by time or size
+pub struct RotatingFile {
+    config: LogFileConfig,
+    file: File,
+    size: u64,
+    offset: UtcOffset,
+    next_rotation: Option<OffsetDateTime>,
+}
+
+impl RotatingFile {
+    /// Open `config.path` for appending
+    pub fn open(config: &LogFileConfig) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let size = file.metadata()?.len();
+
+        // Local offset could only be determined soundly before other threads are started
+        let offset = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
+        let now = OffsetDateTime::now_utc().to_offset(offset);
+
+        Ok(RotatingFile {
+            config: config.clone(),
+            file,
+            size,
+            offset,
+            next_rotation: next_rotation_time(config.rotation, now),
+        })
+    }
+
+    fn rotate_if_needed(&mut self, incoming: usize) {
+        let now = OffsetDateTime::now_utc().to_offset(self.offset);
+
+        let by_request = ROTATION_REQUESTED.swap(false, Ordering::Relaxed);
+        if by_request && self.is_moved() {
+            if let Err(err) = self.reopen() {
+                eprintln!(
+                    "failed to reopen log file {}, error: {}",
+                    self.config.path.display(),
+                    err
+                );
+            }
+            return;
+        }
+
+        let by_time = self.next_rotation.is_some_and(|t| now >= t);
+        let by_size = self
+            .config
+            .max_size
+            .is_some_and(|max_size| self.size > 0 && self.size + incoming as u64 > max_size);
+        if !by_request && !by_time && !by_size {
+            return;
+        }
+
+        if by_time {
+            self.next_rotation = next_rotation_time(self.config.rotation, now);
+        }
+
+        if let Err(err) = self.rotate(now) {
+            // Logger couldn't log its own errors
+            eprintln!(
+                "failed to rotate log file {}, error: {}",
+                self.config.path.display(),
+                err
+            );
+        }
+    }
+
+    /// Whether the active file is renamed or removed by others
+    fn is_moved(&self) -> bool {
+        let path_metadata = match fs::metadata(&self.config.path) {
+            Ok(m) => m,
+            Err(..) => return true,
+        };
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            match self.file.metadata() {
+                Ok(file_metadata) => {
+                    path_metadata.dev() != file_metadata.dev() || path_metadata.ino() != file_metadata.ino()
+                }
+                Err(..) => false,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = path_metadata;
+            false
+        }
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file = OpenOptions::new().create(true).append(true).open(&self.config.path)?;
+        self.size = self.file.metadata()?.len();
+        Ok(())
+    }
+
+    fn rotate(&mut self, now: OffsetDateTime) -> io::Result<()> {
+        let rotated = rotated_path(&self.config.path, now);
+        fs::rename(&self.config.path, &rotated)?;
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.config.path)?;
+        self.size = 0;
+
+        let path = self.config.path.clone();
+        let max_files = self.config.max_files;
+        let compress = self.config.compress;
+        thread::Builder::new().name("log-rotate".to_owned()).spawn(move || {
+            let result = if compress { compress_file(&rotated) } else { Ok(()) };
+            if let Err(err) = result {
+                eprintln!("failed to compress log file {}, error: {}", rotated.display(), err);
+            }
+            if let Err(err) = remove_old_files(&path, max_files) {
+                eprintln!("failed to remove old log files of {}, error: {}", path.display(), err);
+            }
+        })?;
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.rotate_if_needed(buf.len());
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn next_rotation_time(rotation: LogRotation, now: OffsetDateTime) -> Option<OffsetDateTime> {
+    let midnight = now.replace_time(Time::MIDNIGHT);
+    match rotation {
+        LogRotation::Never => None,
+        LogRotation::Hourly => Some(midnight + time::Duration::hours(i64::from(now.hour()) + 1)),
+        LogRotation::Daily => Some(midnight + time::Duration::days(1)),
+    }
+}
+
+fn rotated_path(path: &Path, now: OffsetDateTime) -> PathBuf {
+    let suffix = format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}.{:03}",
+        now.year(),
+        now.month() as u8,
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second(),
+        now.millisecond()
+    );
+
+    let mut index = 0;
+    loop {
+        let mut name = OsString::from(path.as_os_str());
+        name.push(".");
+        name.push(&suffix);
+        if index > 0 {
+            name.push(format!("-{index}"));
+        }
+
+        let rotated = PathBuf::from(name);
+        let mut compressed = rotated.clone().into_os_string();
+        compressed.push(".gz");
+        if !rotated.exists() && !Path::new(&compressed).exists() {
+            return rotated;
+        }
+        index += 1;
+    }
+}
+
+fn compress_file(path: &Path) -> io::Result<()> {
+    let mut compressed = path.as_os_str().to_owned();
+    compressed.push(".gz");
+
+    let mut encoder = GzEncoder::new(File::create(&compressed)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+
+    fs::remove_file(path)
+}
+
+fn remove_old_files(path: &Path, max_files: usize) -> io::Result<()> {
+    if max_files == 0 {
+        return Ok(());
+    }
+
+    let file_name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(n) => format!("{n}."),
+        None => return Ok(()),
+    };
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    // Suffixes start with timestamps, so names are sorted from the oldest
+    let mut rotated = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let is_rotated = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix(&file_name))
+            .is_some_and(|suffix| suffix.starts_with(|c: char| c.is_ascii_digit()));
+        if is_rotated {
+            rotated.push(entry.path());
+        }
+    }
+    rotated.sort();
+
+    let remove_count = rotated.len().saturating_sub(max_files);
+    for path in &rotated[..remove_count] {
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use time::{Date, Month};
+
+    #[test]
+    fn rotation_time() {
+        let offset = UtcOffset::from_hms(8, 0, 0).unwrap();
+        let now = Date::from_calendar_date(2024, Month::December, 31)
+            .unwrap()
+            .with_hms(23, 15, 42)
+            .unwrap()
+            .assume_offset(offset);
+
+        assert_eq!(next_rotation_time(LogRotation::Never, now), None);
+
+        let hourly = next_rotation_time(LogRotation::Hourly, now).unwrap();
+        assert_eq!(
+            (hourly.year(), hourly.day(), hourly.hour(), hourly.minute()),
+            (2025, 1, 0, 0)
+        );
+        assert_eq!(hourly.offset(), offset);
+
+        let daily = next_rotation_time(LogRotation::Daily, now).unwrap();
+        assert_eq!(daily, hourly);
+    }
+}
@@ -6,7 +6,11 @@ use log::warn;
 
 use crate::config::LogConfig;
 
+#[cfg(windows)]
+mod eventlog;
+mod file;
 mod log4rs;
+mod syslog;
 mod tracing;
 
 /// Initialize logger ([log4rs](https://crates.io/crates/log4rs), [trace4rs](https://crates.io/crates/trace4rs)) from yaml configuration file
@@ -23,12 +27,33 @@ where
     );
 }
 
+/// Initialize logger from `config.config_path` if it is set, otherwise with `config`
+pub fn init(bin_name: &str, config: &LogConfig) {
+    match config.config_path {
+        Some(ref path) => init_with_file(path),
+        None => init_with_config(bin_name, config),
+    }
+}
+
 /// Initialize logger with provided configuration
 pub fn init_with_config(bin_name: &str, config: &LogConfig) {
     // log4rs::init_with_config(bin_name, config);
     tracing::init_with_config(bin_name, config);
 }
 
+/// Flush logs buffered by exporters before the process exits
+pub fn shutdown() {
+    tracing::shutdown();
+}
+
+/// Flush stdout, and rotate the log file before the next write
+pub fn rotate() {
+    use std::io::Write;
+
+    file::request_rotation();
+    let _ = std::io::stdout().flush();
+}
+
 /// Init a default logger
 pub fn init_with_default(bin_name: &str) {
     init_with_config(bin_name, &LogConfig::default());
@@ -0,0 +1,298 @@
+//! Logging to syslog
+//!
+//! Each event is sent as one RFC 5424 message to a local unix datagram socket (`/dev/log`), or a remote server by UDP.
+
+use std::{
+    io::{self, Write},
+    net::{ToSocketAddrs, UdpSocket},
+};
+#[cfg(unix)]
+use std::{
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::config::{SyslogAddress, SyslogConfig, SyslogFacility};
+
+enum SyslogSocket {
+    #[cfg(unix)]
+    Unix {
+        path: PathBuf,
+        socket: Mutex<UnixDatagram>,
+    },
+    Udp(UdpSocket),
+}
+
+/// Sends messages formatted by `tracing_subscriber::fmt` to syslog
+pub struct SyslogWriter {
+    socket: SyslogSocket,
+    facility: SyslogFacility,
+    hostname: String,
+    app_name: String,
+    pid: u32,
+}
+
+impl SyslogWriter {
+    /// Connect to syslog `config.address`
+    pub fn connect(app_name: &str, config: &SyslogConfig) -> io::Result<SyslogWriter> {
+        let socket = match config.address {
+            #[cfg(unix)]
+            SyslogAddress::Unix(ref path) => SyslogSocket::Unix {
+                path: path.clone(),
+                socket: Mutex::new(connect_unix(path)?),
+            },
+            SyslogAddress::Udp(ref addr) => {
+                let addr = match addr.to_socket_addrs()?.next() {
+                    Some(a) => a,
+                    None => return Err(io::Error::new(io::ErrorKind::Other, "syslog address not resolved")),
+                };
+                let socket = if addr.is_ipv4() {
+                    UdpSocket::bind(("0.0.0.0", 0))?
+                } else {
+                    UdpSocket::bind(("::", 0))?
+                };
+                socket.connect(addr)?;
+                SyslogSocket::Udp(socket)
+            }
+        };
+
+        Ok(SyslogWriter {
+            socket,
+            facility: config.facility,
+            hostname: hostname(),
+            app_name: app_name.to_owned(),
+            pid: std::process::id(),
+        })
+    }
+
+    fn send(&self, severity: u8, message: &[u8]) -> io::Result<()> {
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_else(|_| "-".to_owned());
+        let priority = self.facility.code() * 8 + severity;
+
+        // <PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG
+        let mut packet = format!(
+            "<{}>1 {} {} {} {} - - ",
+            priority, timestamp, self.hostname, self.app_name, self.pid
+        )
+        .into_bytes();
+        packet.extend_from_slice(message.trim_ascii_end());
+
+        match self.socket {
+            #[cfg(unix)]
+            SyslogSocket::Unix { ref path, ref socket } => {
+                let mut socket = socket.lock().unwrap_or_else(|err| err.into_inner());
+                match socket.send(&packet) {
+                    Ok(..) => Ok(()),
+                    // syslogd has been restarted and the socket is bound again, the old peer is gone
+                    Err(ref err)
+                        if matches!(
+                            err.kind(),
+                            io::ErrorKind::ConnectionRefused
+                                | io::ErrorKind::ConnectionReset
+                                | io::ErrorKind::NotConnected
+                        ) =>
+                    {
+                        *socket = connect_unix(path)?;
+                        socket.send(&packet).map(|_| ())
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            SyslogSocket::Udp(ref s) => s.send(&packet).map(|_| ()),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &Path) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        // SAFETY: `buf` is valid for writes of `buf.len()` bytes. The name may be truncated without a trailing NUL,
+        // which is handled by taking the whole buffer below.
+        let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+        if ret == 0 {
+            let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+            if len > 0 {
+                return String::from_utf8_lossy(&buf[..len]).into_owned();
+            }
+        }
+    }
+
+    // NILVALUE
+    "-".to_owned()
+}
+
+/// One message, sent when dropped
+pub struct SyslogMessage<'a> {
+    writer: &'a SyslogWriter,
+    severity: u8,
+    buffer: Vec<u8>,
+}
+
+impl Write for SyslogMessage<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogMessage<'_> {
+    fn drop(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        // Logs couldn't be logged
+        let _ = self.writer.send(self.severity, &self.buffer);
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogWriter {
+    type Writer = SyslogMessage<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        // notice
+        SyslogMessage {
+            writer: self,
+            severity: 5,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let severity = match *meta.level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        SyslogMessage {
+            writer: self,
+            severity,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    use std::{fs, time::Duration};
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("shadowsocks-syslog-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn bind_unix(path: &Path) -> UnixDatagram {
+        let socket = UnixDatagram::bind(path).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket
+    }
+
+    fn recv_unix(socket: &UnixDatagram) -> String {
+        let mut buf = [0u8; 1024];
+        let n = socket.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn unix_message() {
+        let path = socket_path("message");
+        let server = bind_unix(&path);
+
+        let config = SyslogConfig {
+            address: SyslogAddress::Unix(path.clone()),
+            facility: SyslogFacility::DAEMON,
+        };
+        let writer = SyslogWriter::connect("sslocal", &config).unwrap();
+        {
+            let mut message = writer.make_writer();
+            message.write_all(b"hello ").unwrap();
+            message.write_all(b"world\n").unwrap();
+        }
+
+        let packet = recv_unix(&server);
+        // daemon.notice
+        assert!(packet.starts_with("<29>1 "), "{packet}");
+        assert!(
+            packet.ends_with(&format!(" sslocal {} - - hello world", std::process::id())),
+            "{packet}"
+        );
+
+        // Empty messages are not sent
+        drop(writer.make_writer());
+        server.set_nonblocking(true).unwrap();
+        assert_eq!(
+            server.recv(&mut [0u8; 16]).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unix_reconnect() {
+        let path = socket_path("reconnect");
+        let server = bind_unix(&path);
+
+        let config = SyslogConfig {
+            address: SyslogAddress::Unix(path.clone()),
+            facility: SyslogFacility::DAEMON,
+        };
+        let writer = SyslogWriter::connect("sslocal", &config).unwrap();
+        writer.send(6, b"first").unwrap();
+        assert!(recv_unix(&server).ends_with(" - - first"));
+
+        // syslogd is stopped
+        drop(server);
+        fs::remove_file(&path).unwrap();
+        assert!(writer.send(6, b"lost").is_err());
+
+        // syslogd is started again
+        let server = bind_unix(&path);
+        writer.send(6, b"second").unwrap();
+        assert!(recv_unix(&server).ends_with(" - - second"));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn udp_message() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let config = SyslogConfig {
+            address: SyslogAddress::Udp(server.local_addr().unwrap().to_string()),
+            facility: "local0".parse().unwrap(),
+        };
+        let writer = SyslogWriter::connect("ssserver", &config).unwrap();
+        writer.send(3, b"failed\n").unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = server.recv(&mut buf).unwrap();
+        let packet = std::str::from_utf8(&buf[..n]).unwrap();
+        // local0.err
+        assert!(packet.starts_with("<131>1 "), "{packet}");
+        assert!(packet.ends_with(" - - failed"), "{packet}");
+    }
+}
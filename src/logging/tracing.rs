@@ -1,47 +1,39 @@
 //! Logging facilities with tracing
 
-use std::io::IsTerminal;
+use std::{
+    io::{self, IsTerminal},
+    sync::Mutex,
+};
 
-use time::UtcOffset;
+use shadowsocks_service::log_control::{self, CONNECTION_SPAN_NAME, LogFilterControl};
+use time::{UtcOffset, format_description::well_known::Rfc3339};
 use tracing::level_filters::LevelFilter;
-use tracing_subscriber::{EnvFilter, FmtSubscriber, fmt::time::OffsetTime};
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry,
+    filter::filter_fn,
+    fmt::{self, MakeWriter, format::FmtSpan, time::OffsetTime},
+    layer::{Layered, SubscriberExt},
+    reload,
+    util::SubscriberInitExt,
+};
 
 use crate::config::LogConfig;
 
-/// Initialize logger with provided configuration
-pub fn init_with_config(bin_name: &str, config: &LogConfig) {
-    let debug_level = config.level;
-    let without_time = config.format.without_time;
+#[cfg(windows)]
+use super::eventlog::EventLogWriter;
+use super::{file::RotatingFile, syslog::SyslogWriter};
+#[cfg(feature = "tracing-otlp")]
+use crate::config::OtlpConfig;
 
-    let mut builder = FmtSubscriber::builder()
-        .with_level(true)
-        .with_timer(match OffsetTime::local_rfc_3339() {
-            Ok(t) => t,
-            Err(..) => {
-                // Reinit with UTC time
-                OffsetTime::new(UtcOffset::UTC, time::format_description::well_known::Rfc3339)
-            }
-        });
-
-    // NOTE: ansi is enabled by default.
-    // Could be disabled by `NO_COLOR` environment variable.
-    // https://no-color.org/
-    if !std::io::stdout().is_terminal() {
-        builder = builder.with_ansi(false);
-    }
+// Provider of the OTLP exporter, shut down before exiting to export buffered spans
+#[cfg(feature = "tracing-otlp")]
+static OTLP_PROVIDER: Mutex<Option<opentelemetry_sdk::trace::SdkTracerProvider>> = Mutex::new(None);
 
-    if debug_level >= 1 {
-        builder = builder.with_target(true).with_thread_ids(true).with_thread_names(true);
+type LoggerSubscriber = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
 
-        if debug_level >= 3 {
-            builder = builder.with_file(true).with_line_number(true);
-        }
-    } else {
-        builder = builder
-            .with_target(false)
-            .with_thread_ids(false)
-            .with_thread_names(false);
-    }
+/// Initialize logger with provided configuration
+pub fn init_with_config(bin_name: &str, config: &LogConfig) {
+    let debug_level = config.level;
 
     let filter = match EnvFilter::try_from_default_env() {
         Ok(f) => f,
@@ -72,11 +64,273 @@ pub fn init_with_config(bin_name: &str, config: &LogConfig) {
                 .parse_lossy(""),
         },
     };
-    let builder = builder.with_env_filter(filter);
 
-    if without_time {
-        builder.without_time().init();
+    // Filter could be replaced at runtime
+    let (filter, handle) = reload::Layer::new(filter);
+    let registry = tracing_subscriber::registry().with(filter);
+
+    let layer = writer_layer(bin_name, config);
+
+    #[cfg(feature = "tracing-otlp")]
+    let layer = layer.and_then(config.otlp.as_ref().and_then(|otlp| otlp_layer(bin_name, otlp)));
+
+    registry.with(layer).init();
+
+    log_control::set_log_filter_control(TracingFilterControl { handle });
+}
+
+fn writer_layer(bin_name: &str, config: &LogConfig) -> Box<dyn Layer<LoggerSubscriber> + Send + Sync> {
+    if let Some(ref syslog) = config.syslog {
+        match SyslogWriter::connect(bin_name, syslog) {
+            Ok(writer) => {
+                // Messages of syslog have their own timestamps
+                let mut config = config.clone();
+                config.format.without_time = true;
+                return output_layer(&config, writer, false);
+            }
+            Err(err) => {
+                // Logger is not ready yet
+                eprintln!(
+                    "failed to connect to syslog {}, error: {}, logging to stdout",
+                    syslog.address, err
+                );
+                return stdout_layer(config);
+            }
+        }
+    }
+
+    #[cfg(windows)]
+    if let Some(ref source) = config.event_log {
+        match EventLogWriter::register(source) {
+            Ok(writer) => {
+                // Events have their own timestamps
+                let mut config = config.clone();
+                config.format.without_time = true;
+                return output_layer(&config, writer, false);
+            }
+            Err(err) => {
+                // Logger is not ready yet
+                eprintln!(
+                    "failed to register event source {}, error: {}, logging to stdout",
+                    source, err
+                );
+                return stdout_layer(config);
+            }
+        }
+    }
+
+    if let Some(ref file) = config.file {
+        match RotatingFile::open(file) {
+            Ok(file) => return output_layer(config, Mutex::new(file), false),
+            Err(err) => {
+                // Logger is not ready yet
+                eprintln!(
+                    "failed to open log file {}, error: {}, logging to stdout",
+                    file.path.display(),
+                    err
+                );
+                return stdout_layer(config);
+            }
+        }
+    }
+
+    stdout_layer(config)
+}
+
+fn stdout_layer(config: &LogConfig) -> Box<dyn Layer<LoggerSubscriber> + Send + Sync> {
+    // NOTE: ansi is enabled by default.
+    // Could be disabled by `NO_COLOR` environment variable.
+    // https://no-color.org/
+    output_layer(config, io::stdout, io::stdout().is_terminal())
+}
+
+fn local_timer() -> OffsetTime<Rfc3339> {
+    match OffsetTime::local_rfc_3339() {
+        Ok(t) => t,
+        Err(..) => {
+            // Reinit with UTC time
+            OffsetTime::new(UtcOffset::UTC, Rfc3339)
+        }
+    }
+}
+
+fn output_layer<W>(config: &LogConfig, writer: W, ansi: bool) -> Box<dyn Layer<LoggerSubscriber> + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    if config.format.json {
+        json_layer(config, writer)
+    } else {
+        human_layer(config, writer, ansi)
+    }
+}
+
+fn human_layer<W>(config: &LogConfig, writer: W, ansi: bool) -> Box<dyn Layer<LoggerSubscriber> + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let debug_level = config.level;
+
+    let mut layer = fmt::layer()
+        .with_writer(writer)
+        .with_level(true)
+        .with_timer(local_timer());
+
+    if !ansi {
+        layer = layer.with_ansi(false);
+    }
+
+    if debug_level >= 1 {
+        layer = layer.with_target(true).with_thread_ids(true).with_thread_names(true);
+
+        if debug_level >= 3 {
+            layer = layer.with_file(true).with_line_number(true);
+        }
+    } else {
+        layer = layer.with_target(false).with_thread_ids(false).with_thread_names(false);
+    }
+
+    // Connection spans are only for structured logs, human readable logs already have peers in messages
+    let filter = filter_fn(|metadata| !metadata.is_span() || metadata.name() != CONNECTION_SPAN_NAME);
+
+    if config.format.without_time {
+        layer.without_time().with_filter(filter).boxed()
+    } else {
+        layer.with_filter(filter).boxed()
+    }
+}
+
+fn json_layer<W>(config: &LogConfig, writer: W) -> Box<dyn Layer<LoggerSubscriber> + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let debug_level = config.level;
+
+    // Fields of events are flattened, and fields of the connection span (id, peer, target address) are in "span"
+    // Closing a connection span emits an event with its durations in "time.busy" and "time.idle"
+    let mut layer = fmt::layer()
+        .json()
+        .with_writer(writer)
+        .with_timer(local_timer())
+        .with_level(true)
+        .with_target(true)
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_span_events(FmtSpan::CLOSE)
+        .flatten_event(true)
+        .with_ansi(false);
+
+    if debug_level >= 1 {
+        layer = layer.with_thread_ids(true).with_thread_names(true);
+
+        if debug_level >= 3 {
+            layer = layer.with_file(true).with_line_number(true);
+        }
+    }
+
+    if config.format.without_time {
+        layer.without_time().boxed()
     } else {
-        builder.init();
+        layer.boxed()
+    }
+}
+
+#[cfg(feature = "tracing-otlp")]
+fn otlp_layer(bin_name: &str, config: &OtlpConfig) -> Option<Box<dyn Layer<LoggerSubscriber> + Send + Sync>> {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+
+    let mut builder = SpanExporter::builder().with_http();
+    if let Some(ref endpoint) = config.endpoint {
+        builder = builder.with_endpoint(endpoint);
+    }
+
+    let exporter = match builder.build() {
+        Ok(e) => e,
+        Err(err) => {
+            // Logger is not ready yet
+            eprintln!("failed to create OTLP exporter, error: {err}");
+            return None;
+        }
+    };
+
+    // Batch exporter runs in its own thread, logger is initialized before the tokio runtime
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(otlp_resource(bin_name))
+        .build();
+    let tracer = provider.tracer(bin_name.to_owned());
+    opentelemetry::global::set_tracer_provider(provider.clone());
+    *OTLP_PROVIDER.lock().unwrap() = Some(provider);
+
+    Some(tracing_opentelemetry::layer().with_tracer(tracer).boxed())
+}
+
+/// Spans are exported as service `bin_name`, like `ssserver`
+#[cfg(feature = "tracing-otlp")]
+fn otlp_resource(bin_name: &str) -> opentelemetry_sdk::Resource {
+    opentelemetry_sdk::Resource::builder()
+        .with_service_name(bin_name.to_owned())
+        .build()
+}
+
+/// Flush and stop exporters running in background
+pub fn shutdown() {
+    #[cfg(feature = "tracing-otlp")]
+    if let Some(provider) = OTLP_PROVIDER.lock().unwrap().take() {
+        if let Err(err) = provider.shutdown() {
+            eprintln!("failed to shutdown OTLP exporter, error: {err}");
+        }
+    }
+}
+
+struct TracingFilterControl {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogFilterControl for TracingFilterControl {
+    fn current(&self) -> String {
+        self.handle.with_current(|f| f.to_string()).unwrap_or_default()
+    }
+
+    fn set(&self, directives: &str) -> io::Result<()> {
+        let filter = EnvFilter::builder()
+            .with_regex(true)
+            .with_default_directive(LevelFilter::ERROR.into())
+            .parse(directives)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        // Records from `log` are dropped by its global max level before reaching the filter
+        let max_level = match filter.max_level_hint() {
+            Some(LevelFilter::OFF) => log::LevelFilter::Off,
+            Some(LevelFilter::ERROR) => log::LevelFilter::Error,
+            Some(LevelFilter::WARN) => log::LevelFilter::Warn,
+            Some(LevelFilter::INFO) => log::LevelFilter::Info,
+            Some(LevelFilter::DEBUG) => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        };
+
+        self.handle
+            .reload(filter)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        log::set_max_level(max_level);
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "tracing-otlp"))]
+mod test {
+    use super::*;
+
+    use opentelemetry::{Key, Value};
+
+    #[test]
+    fn otlp_service_name() {
+        for bin_name in ["sslocal", "ssserver", "ssmanager"] {
+            let resource = otlp_resource(bin_name);
+            assert_eq!(resource.get(&Key::new("service.name")), Some(Value::from(bin_name)));
+        }
     }
 }
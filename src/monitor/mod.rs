@@ -13,3 +13,5 @@ mod imp;
 mod imp;
 
 pub use self::imp::create_signal_monitor;
+#[cfg(unix)]
+pub use self::imp::set_dump_path;
@@ -1,22 +1,86 @@
 use futures::future::{self, Either, FutureExt};
-use log::info;
-use std::io;
+use log::{error, info};
+use serde_json::json;
+use shadowsocks_service::stats;
+use std::{fs, io, path::PathBuf, sync::OnceLock};
 use tokio::signal::unix::{SignalKind, signal};
 
+static DUMP_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Write dumps of `SIGUSR1` to `path` instead of logs
+pub fn set_dump_path(path: PathBuf) {
+    let _ = DUMP_PATH.set(path);
+}
+
 /// Create a monitor future for signals
 ///
-/// It will exit when received `SIGTERM` or `SIGINT`.
+/// It will exit when received `SIGTERM` or `SIGINT` (notifying systemd `STOPPING=1`), dumps a statistics snapshot and
+/// the active connection table when received `SIGUSR1`, and rotates logs and clears caches when received `SIGUSR2`.
 pub async fn create_signal_monitor() -> io::Result<()> {
     // Future resolving to two signal streams. Can fail if setting up signal monitoring fails
     let mut sigterm = signal(SignalKind::terminate())?;
     let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigusr1 = signal(SignalKind::user_defined1())?;
+    let mut sigusr2 = signal(SignalKind::user_defined2())?;
 
-    let signal_name = match future::select(sigterm.recv().boxed(), sigint.recv().boxed()).await {
-        Either::Left(..) => "SIGTERM",
-        Either::Right(..) => "SIGINT",
+    let mut exit_signal = future::select(sigterm.recv().boxed(), sigint.recv().boxed()).fuse();
+
+    let signal_name = loop {
+        futures::select! {
+            r = exit_signal => {
+                break match r {
+                    Either::Left(..) => "SIGTERM",
+                    Either::Right(..) => "SIGINT",
+                };
+            }
+            _ = sigusr1.recv().fuse() => dump_stats(),
+            _ = sigusr2.recv().fuse() => rotate_and_clear(),
+        }
     };
 
     info!("received {}, exiting", signal_name);
 
+    #[cfg(target_os = "linux")]
+    shadowsocks_service::sd_notify::stopping();
+
     Ok(())
 }
+
+fn dump_stats() {
+    let snapshot = stats::snapshot();
+    let connections = stats::connection_table();
+
+    match DUMP_PATH.get() {
+        Some(path) => {
+            let dump = json!({
+                "statistics": snapshot,
+                "connections": connections,
+            });
+            let result = serde_json::to_vec_pretty(&dump)
+                .map_err(io::Error::from)
+                .and_then(|dump| fs::write(path, dump));
+            match result {
+                Ok(..) => info!("received SIGUSR1, dumped statistics to {}", path.display()),
+                Err(err) => error!("failed to dump statistics to {}, error: {}", path.display(), err),
+            }
+        }
+        None => {
+            match serde_json::to_string(&snapshot) {
+                Ok(snapshot) => info!("received SIGUSR1, statistics: {}", snapshot),
+                Err(err) => error!("failed to serialize statistics, error: {}", err),
+            }
+            match serde_json::to_string(&connections) {
+                Ok(connections) => info!("received SIGUSR1, active connections: {}", connections),
+                Err(err) => error!("failed to serialize active connections, error: {}", err),
+            }
+        }
+    }
+}
+
+fn rotate_and_clear() {
+    #[cfg(feature = "logging")]
+    crate::logging::rotate();
+    stats::clear_caches();
+
+    info!("received SIGUSR2, rotated logs and cleared caches");
+}
@@ -0,0 +1,196 @@
+//! Throughput self-test of a server
+//!
+//! The server has to be started with `--bench-responder`.
+
+use std::{process::ExitCode, time::Duration};
+
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command, ValueHint, builder::PossibleValuesParser};
+
+use shadowsocks_service::{
+    bench::{BenchConfig, run_bench},
+    config::read_variable_field_value,
+    shadowsocks::{
+        config::{Mode, ServerAddr, ServerConfig},
+        crypto::{CipherKind, available_ciphers},
+        plugin::PluginConfig,
+    },
+};
+
+use crate::vparser;
+
+/// Defines command line options
+pub fn define_command_line_options(mut app: Command) -> Command {
+    app = app
+        .arg(
+            Arg::new("SERVER_ADDR")
+                .short('s')
+                .long("server-addr")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .requires("ENCRYPT_METHOD")
+                .help("Server address"),
+        )
+        .arg(
+            Arg::new("PASSWORD")
+                .short('k')
+                .long("password")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .requires("SERVER_ADDR")
+                .help("Server's password"),
+        )
+        .arg(
+            Arg::new("ENCRYPT_METHOD")
+                .short('m')
+                .long("encrypt-method")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .requires("SERVER_ADDR")
+                .value_parser(PossibleValuesParser::new(available_ciphers()))
+                .help("Server's encryption method"),
+        )
+        .arg(
+            Arg::new("PLUGIN")
+                .long("plugin")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::CommandName)
+                .requires("SERVER_ADDR")
+                .help("SIP003 (https://shadowsocks.org/doc/sip003.html) plugin"),
+        )
+        .arg(
+            Arg::new("PLUGIN_OPT")
+                .long("plugin-opts")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .requires("PLUGIN")
+                .help("Set SIP003 plugin options"),
+        )
+        .arg(
+            Arg::new("SERVER_URL")
+                .long("server-url")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Url)
+                .value_parser(vparser::parse_server_url)
+                .help("Server address in SIP002 (https://shadowsocks.org/doc/sip002.html) URL"),
+        )
+        .group(
+            ArgGroup::new("SERVER_CONFIG")
+                .arg("SERVER_ADDR")
+                .arg("SERVER_URL")
+                .required(true),
+        )
+        .arg(
+            Arg::new("DURATION")
+                .long("duration")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64))
+                .default_value("10")
+                .help("Seconds of each throughput test"),
+        )
+        .arg(
+            Arg::new("CONNECTIONS")
+                .long("connections")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1")
+                .help("Concurrent TCP connections of throughput tests"),
+        )
+        .arg(
+            Arg::new("UDP")
+                .long("udp")
+                .action(ArgAction::SetTrue)
+                .help("Also test UDP relay, the server has to be in tcp_and_udp mode"),
+        )
+        .arg(
+            Arg::new("UDP_PAYLOAD_SIZE")
+                .long("udp-payload-size")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1200")
+                .help("Bytes of UDP payloads"),
+        )
+        .arg(
+            Arg::new("JSON")
+                .long("json")
+                .action(ArgAction::SetTrue)
+                .help("Print results in JSON"),
+        );
+
+    app
+}
+
+fn server_config(matches: &ArgMatches) -> Result<ServerConfig, String> {
+    if let Some(svr_cfg) = matches.get_one::<ServerConfig>("SERVER_URL") {
+        return Ok(svr_cfg.clone());
+    }
+
+    let svr_addr = matches.get_one::<String>("SERVER_ADDR").expect("server-addr");
+    let svr_addr = svr_addr
+        .parse::<ServerAddr>()
+        .map_err(|err| format!("invalid server-addr, {err}"))?;
+    let method = matches
+        .get_one::<String>("ENCRYPT_METHOD")
+        .map(|x| x.parse::<CipherKind>().expect("method"))
+        .expect("`method` is required");
+    let password = matches
+        .get_one::<String>("PASSWORD")
+        .map(|pwd| read_variable_field_value(pwd).into_owned())
+        .unwrap_or_default();
+
+    let mut svr_cfg = ServerConfig::new(svr_addr, password, method).map_err(|err| err.to_string())?;
+    if let Some(p) = matches.get_one::<String>("PLUGIN").cloned() {
+        svr_cfg.set_plugin(PluginConfig {
+            plugin: p,
+            plugin_opts: matches.get_one::<String>("PLUGIN_OPT").cloned(),
+            plugin_args: Vec::new(),
+            plugin_mode: Mode::TcpOnly,
+            plugin_supervision: Default::default(),
+        });
+    }
+
+    Ok(svr_cfg)
+}
+
+/// Program entrance `main`
+pub fn main(matches: &ArgMatches) -> ExitCode {
+    let svr_cfg = match server_config(matches) {
+        Ok(c) => c,
+        Err(err) => {
+            eprintln!("{err}");
+            return sysexits::ExitCode::Config.into();
+        }
+    };
+
+    let mut config = BenchConfig::new(svr_cfg);
+    config.duration = Duration::from_secs(*matches.get_one::<u64>("DURATION").expect("duration"));
+    config.connections = *matches.get_one::<usize>("CONNECTIONS").expect("connections");
+    config.udp = matches.get_flag("UDP");
+    config.udp_payload_size = *matches.get_one::<usize>("UDP_PAYLOAD_SIZE").expect("udp-payload-size");
+
+    #[cfg(feature = "multi-threaded")]
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    #[cfg(not(feature = "multi-threaded"))]
+    let mut builder = tokio::runtime::Builder::new_current_thread();
+
+    let runtime = builder.enable_all().build().expect("create tokio Runtime");
+
+    match runtime.block_on(run_bench(config)) {
+        Ok(report) => {
+            if matches.get_flag("JSON") {
+                println!("{}", serde_json::to_string_pretty(&report).expect("serialize report"));
+            } else {
+                println!("{report}");
+            }
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("bench failed, error: {err}");
+            sysexits::ExitCode::Software.into()
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Connectivity check of servers
+
+use std::{path::PathBuf, process::ExitCode, time::Duration};
+
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command, ValueHint};
+
+use shadowsocks_service::{
+    check::{CheckConfig, format_table, run_check},
+    config::{BalancerCheckUrl, Config, ConfigType},
+    shadowsocks::ServerConfig,
+};
+
+use crate::vparser;
+
+/// Defines command line options
+pub fn define_command_line_options(mut app: Command) -> Command {
+    app = app
+        .arg(
+            Arg::new("CONFIG")
+                .short('c')
+                .long("config")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_hint(ValueHint::FilePath)
+                .help("Check all servers in the sslocal configuration file"),
+        )
+        .arg(
+            Arg::new("SERVER_URL")
+                .long("server-url")
+                .num_args(1)
+                .action(ArgAction::Append)
+                .value_hint(ValueHint::Url)
+                .value_parser(vparser::parse_server_url)
+                .help("Server address in SIP002 (https://shadowsocks.org/doc/sip002.html) URL, could be repeated"),
+        )
+        .group(
+            ArgGroup::new("SERVER_CONFIG")
+                .arg("CONFIG")
+                .arg("SERVER_URL")
+                .multiple(true)
+                .required(true),
+        )
+        .arg(
+            Arg::new("URL")
+                .long("url")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::Url)
+                .value_parser(vparser::parse_check_url)
+                .help("http:// URL fetched through servers, http://www.gstatic.com/generate_204 by default"),
+        )
+        .arg(
+            Arg::new("TIMEOUT")
+                .long("timeout")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64))
+                .default_value("10")
+                .help("Timeout of each step in seconds"),
+        )
+        .arg(
+            Arg::new("JSON")
+                .long("json")
+                .action(ArgAction::SetTrue)
+                .help("Print results in JSON"),
+        );
+
+    app
+}
+
+/// Program entrance `main`
+pub fn main(matches: &ArgMatches) -> ExitCode {
+    let mut servers = Vec::new();
+    if let Some(config_path) = matches.get_one::<PathBuf>("CONFIG") {
+        match Config::load_from_file(config_path, ConfigType::Local) {
+            Ok(config) => servers.extend(config.server.into_iter().map(|s| s.config)),
+            Err(err) => {
+                eprintln!("loading config {}, {err}", config_path.display());
+                return sysexits::ExitCode::Config.into();
+            }
+        }
+    }
+    if let Some(urls) = matches.get_many::<ServerConfig>("SERVER_URL") {
+        servers.extend(urls.cloned());
+    }
+    if servers.is_empty() {
+        eprintln!("no servers to check");
+        return sysexits::ExitCode::Config.into();
+    }
+
+    let mut config = CheckConfig::new(servers);
+    if let Some(url) = matches.get_one::<BalancerCheckUrl>("URL") {
+        if url.https {
+            eprintln!("--url should be an http:// URL");
+            return sysexits::ExitCode::Usage.into();
+        }
+        config.url = url.clone();
+    }
+    config.timeout = Duration::from_secs(*matches.get_one::<u64>("TIMEOUT").expect("timeout"));
+
+    #[cfg(feature = "multi-threaded")]
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    #[cfg(not(feature = "multi-threaded"))]
+    let mut builder = tokio::runtime::Builder::new_current_thread();
+
+    let runtime = builder.enable_all().build().expect("create tokio Runtime");
+
+    match runtime.block_on(run_check(config)) {
+        Ok(reports) => {
+            if matches.get_flag("JSON") {
+                println!("{}", serde_json::to_string_pretty(&reports).expect("serialize reports"));
+            } else {
+                print!("{}", format_table(&reports));
+            }
+
+            if reports.iter().all(|r| r.is_ok()) {
+                ExitCode::SUCCESS
+            } else {
+                sysexits::ExitCode::Unavailable.into()
+            }
+        }
+        Err(err) => {
+            eprintln!("check failed, error: {err}");
+            sysexits::ExitCode::Software.into()
+        }
+    }
+}
@@ -4,7 +4,7 @@
 use std::sync::Arc;
 use std::{
     future::Future,
-    net::IpAddr,
+    net::{IpAddr, SocketAddr},
     path::PathBuf,
     process::ExitCode,
     time::{Duration, Instant},
@@ -25,24 +25,28 @@ use shadowsocks_service::shadowsocks::relay::socks5::Address;
 use shadowsocks_service::{
     acl::AccessControl,
     config::{
-        Config, ConfigType, LocalConfig, LocalInstanceConfig, ProtocolType, ServerInstanceConfig,
-        read_variable_field_value,
+        AccessLogConfig, Config, ConfigType, FlowExportConfig, FlowExportVersion, LocalConfig, LocalInstanceConfig,
+        MemoryProfile, ProtocolType, ServerInstanceConfig, read_variable_field_value,
     },
     local::{Server, loadbalancing::PingBalancer},
     shadowsocks::{
         config::{Mode, ServerAddr, ServerConfig, ServerSource},
         crypto::{CipherKind, available_ciphers},
+        net::OutboundProxy,
         plugin::PluginConfig,
     },
 };
 
-#[cfg(feature = "logging")]
-use crate::logging;
 use crate::{
     config::{Config as ServiceConfig, RuntimeMode},
     error::{ShadowsocksError, ShadowsocksResult},
     monitor, vparser,
 };
+#[cfg(feature = "logging")]
+use crate::{
+    config::{LogRotation, SyslogAddress, SyslogFacility},
+    logging,
+};
 
 #[cfg(feature = "local-dns")]
 mod local_value_parser {
@@ -224,18 +228,25 @@ pub fn define_command_line_options(mut app: Command) -> Command {
     )
     .arg(Arg::new("DNS").long("dns").num_args(1).action(ArgAction::Set).help("DNS nameservers, formatted like [(tcp|udp)://]host[:port][,host[:port]]..., or unix:///path/to/dns, or predefined keys like \"google\", \"cloudflare\""))
     .arg(Arg::new("DNS_CACHE_SIZE").long("dns-cache-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(usize)).help("DNS cache size in number of records. Works when trust-dns DNS backend is enabled."))
+    .arg(Arg::new("SERVER_RESOLVE_INTERVAL").long("server-resolve-interval").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u64)).help("Resolve domain names of servers again after this many seconds, 0 resolves for every connection"))
     .arg(Arg::new("TCP_NO_DELAY").long("tcp-no-delay").alias("no-delay").action(ArgAction::SetTrue).help("Set TCP_NODELAY option for sockets"))
     .arg(Arg::new("TCP_FAST_OPEN").long("tcp-fast-open").alias("fast-open").action(ArgAction::SetTrue).help("Enable TCP Fast Open (TFO)"))
     .arg(Arg::new("TCP_KEEP_ALIVE").long("tcp-keep-alive").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u64)).help("Set TCP keep alive timeout seconds"))
     .arg(Arg::new("TCP_MULTIPATH").long("tcp-multipath").alias("mptcp").action(ArgAction::SetTrue).help("Enable Multipath-TCP (MPTCP)"))
+    .arg(Arg::new("ACCESS_LOG").long("access-log").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(PathBuf)).value_hint(ValueHint::FilePath).help("Append one line for each completed TCP relay to this file"))
+    .arg(Arg::new("FLOW_EXPORT_COLLECTOR").long("flow-export-collector").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(SocketAddr)).help("Export completed TCP relays and UDP associations to this NetFlow v9 / IPFIX collector by UDP"))
+    .arg(Arg::new("FLOW_EXPORT_VERSION").long("flow-export-version").num_args(1).action(ArgAction::Set).value_parser(PossibleValuesParser::new(["9", "10"])).requires("FLOW_EXPORT_COLLECTOR").help("Export NetFlow v9 (9) or IPFIX (10) messages, IPFIX by default"))
     .arg(Arg::new("UDP_TIMEOUT").long("udp-timeout").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u64)).help("Timeout seconds for UDP relay"))
     .arg(Arg::new("UDP_MAX_ASSOCIATIONS").long("udp-max-associations").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(usize)).help("Maximum associations to be kept simultaneously for UDP relay"))
+        .arg(Arg::new("MEMORY_PROFILE").long("memory-profile").num_args(1).action(ArgAction::Set).value_parser(vparser::parse_memory_profile).help("Memory usage preset, default, low (for embedded devices like routers) or constrained (for processes with hard memory limits)"))
     .arg(Arg::new("INBOUND_SEND_BUFFER_SIZE").long("inbound-send-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set inbound sockets' SO_SNDBUF option"))
     .arg(Arg::new("INBOUND_RECV_BUFFER_SIZE").long("inbound-recv-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set inbound sockets' SO_RCVBUF option"))
     .arg(Arg::new("OUTBOUND_SEND_BUFFER_SIZE").long("outbound-send-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set outbound sockets' SO_SNDBUF option"))
     .arg(Arg::new("OUTBOUND_RECV_BUFFER_SIZE").long("outbound-recv-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set outbound sockets' SO_RCVBUF option"))
     .arg(Arg::new("OUTBOUND_BIND_ADDR").long("outbound-bind-addr").num_args(1).alias("bind-addr").action(ArgAction::Set).value_parser(vparser::parse_ip_addr).help("Bind address, outbound socket will bind this address"))
     .arg(Arg::new("OUTBOUND_BIND_INTERFACE").long("outbound-bind-interface").num_args(1).action(ArgAction::Set).help("Set SO_BINDTODEVICE / IP_BOUND_IF / IP_UNICAST_IF option for outbound socket"))
+    .arg(Arg::new("OUTBOUND_DSCP").long("outbound-dscp").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u8).range(0..=63)).help("Set DSCP (0-63) of connections to servers, in IP_TOS / IPV6_TCLASS option"))
+    .arg(Arg::new("OUTBOUND_PROXY").long("outbound-proxy").num_args(1).action(ArgAction::Set).value_hint(ValueHint::Url).value_parser(vparser::parse_outbound_proxy).help("Connect to servers through this SOCKS5 or HTTP proxy, socks5://[username:password@]host[:port] or http://[username:password@]host[:port]"))
     .arg(
         Arg::new("IPV6_FIRST")
             .short('6')
@@ -258,6 +269,72 @@ pub fn define_command_line_options(mut app: Command) -> Command {
                     .action(ArgAction::SetTrue)
                     .help("Log without datetime prefix"),
             )
+            .arg(
+                Arg::new("LOG_JSON")
+                    .long("log-json")
+                    .action(ArgAction::SetTrue)
+                    .help("Log in JSON, one object per line"),
+            )
+            .arg(
+                Arg::new("LOG_SYSLOG")
+                    .long("log-syslog")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(SyslogAddress))
+                    .help("Send logs to syslog, unix socket path like /dev/log, or udp://host:port"),
+            )
+            .arg(
+                Arg::new("LOG_SYSLOG_FACILITY")
+                    .long("log-syslog-facility")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(SyslogFacility))
+                    .requires("LOG_SYSLOG")
+                    .help("Facility of syslog, user, daemon (default), local0 ~ local7"),
+            )
+            .arg(
+                Arg::new("LOG_FILE")
+                    .long("log-file")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .value_hint(ValueHint::FilePath)
+                    .help("Write logs to this file instead of stdout"),
+            )
+            .arg(
+                Arg::new("LOG_FILE_ROTATION")
+                    .long("log-file-rotation")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(LogRotation))
+                    .requires("LOG_FILE")
+                    .help("Rotate the log file by time, never (default), hourly, daily"),
+            )
+            .arg(
+                Arg::new("LOG_FILE_MAX_SIZE")
+                    .long("log-file-max-size")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .requires("LOG_FILE")
+                    .help("Rotate the log file when it would exceed this size in bytes"),
+            )
+            .arg(
+                Arg::new("LOG_FILE_MAX_FILES")
+                    .long("log-file-max-files")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(usize))
+                    .requires("LOG_FILE")
+                    .help("Rotated log files to keep, 7 by default, 0 keeps all of them"),
+            )
+            .arg(
+                Arg::new("LOG_FILE_COMPRESS")
+                    .long("log-file-compress")
+                    .action(ArgAction::SetTrue)
+                    .requires("LOG_FILE")
+                    .help("Compress rotated log files with gzip"),
+            )
             .arg(
                 Arg::new("LOG_CONFIG")
                     .long("log-config")
@@ -269,6 +346,17 @@ pub fn define_command_line_options(mut app: Command) -> Command {
             );
     }
 
+    #[cfg(all(feature = "logging", windows))]
+    {
+        app = app.arg(
+            Arg::new("LOG_EVENT_LOG")
+                .long("log-event-log")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .help("Report logs to Windows Event Log with this source name instead of stdout"),
+        );
+    }
+
     #[cfg(feature = "local-tunnel")]
     {
         app = app.arg(
@@ -284,6 +372,39 @@ pub fn define_command_line_options(mut app: Command) -> Command {
         );
     }
 
+    #[cfg(feature = "metrics")]
+    {
+        app = app.arg(
+            Arg::new("METRICS_ADDR")
+                .long("metrics-addr")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(vparser::parse_socket_addr)
+                .help("Listen address of Prometheus metrics endpoint (GET /metrics)"),
+        );
+    }
+
+    #[cfg(feature = "local-admin")]
+    {
+        app = app
+            .arg(
+                Arg::new("LOCAL_ADMIN_ADDR")
+                    .long("local-admin-addr")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(vparser::parse_socket_addr)
+                    .help("Listen address of local's HTTP admin API"),
+            )
+            .arg(
+                Arg::new("LOCAL_ADMIN_TOKEN")
+                    .long("local-admin-token")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .requires("LOCAL_ADMIN_ADDR")
+                    .help("Token for authenticating local's HTTP admin API requests"),
+            );
+    }
+
     #[cfg(all(unix, not(target_os = "android")))]
     {
         app = app.arg(
@@ -346,6 +467,28 @@ pub fn define_command_line_options(mut app: Command) -> Command {
                     .help("UDP redir (transparent proxy) type"),
             );
         }
+
+        #[cfg(target_os = "linux")]
+        {
+            app = app.arg(
+                Arg::new("REDIR_FIREWALL")
+                    .long("redir-firewall")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .requires("LOCAL_ADDR")
+                    .value_parser(["iptables", "nftables"])
+                    .help("Install (and remove on exit) firewall rules for redir (transparent proxy) automatically"),
+            )
+            .arg(
+                Arg::new("REDIR_FIREWALL_BYPASS")
+                    .long("redir-firewall-bypass")
+                    .num_args(1)
+                    .action(ArgAction::Append)
+                    .requires("REDIR_FIREWALL")
+                    .value_parser(vparser::parse_ipnet)
+                    .help("Destination network that won't be redirected by --redir-firewall, default is private and reserved networks"),
+            );
+        }
     }
 
     #[cfg(target_os = "android")]
@@ -358,30 +501,36 @@ pub fn define_command_line_options(mut app: Command) -> Command {
         );
     }
 
-    #[cfg(feature = "local-flow-stat")]
+    #[cfg(unix)]
     {
-        #[cfg(unix)]
-        {
-            app = app.arg(
-                Arg::new("STAT_PATH")
-                    .long("stat-path")
-                    .num_args(1)
-                    .action(ArgAction::Set)
-                    .value_hint(ValueHint::FilePath)
-                    .conflicts_with("STAT_ADDR")
-                    .help("Specify socket path (unix domain socket) for sending traffic statistic"),
-            );
-        }
-
         app = app.arg(
+            Arg::new("STAT_PATH")
+                .long("stat-path")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_hint(ValueHint::FilePath)
+                .conflicts_with("STAT_ADDR")
+                .help("Specify socket path (unix domain socket) for sending traffic statistic"),
+        );
+    }
+
+    app = app
+        .arg(
             Arg::new("STAT_ADDR")
                 .long("stat-addr")
                 .num_args(1)
                 .action(ArgAction::Set)
                 .value_parser(vparser::parse_socket_addr)
                 .help("Specify socket address IP:PORT (TCP) for sending traffic statistic"),
+        )
+        .arg(
+            Arg::new("STAT_STATSD_ADDR")
+                .long("stat-statsd-addr")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(vparser::parse_socket_addr)
+                .help("Emit traffic statistic as statsd counters to IP:PORT (UDP)"),
         );
-    }
 
     #[cfg(feature = "local-dns")]
     {
@@ -517,6 +666,17 @@ pub fn define_command_line_options(mut app: Command) -> Command {
                     .value_parser(clap::value_parser!(PathBuf))
                     .value_hint(ValueHint::FilePath)
                     .help("File path to store daemonized process's PID"),
+            )
+            .arg(
+                Arg::new("DUMP_PATH")
+                    .long("dump-path")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .value_hint(ValueHint::FilePath)
+                    .help(
+                        "Write statistics and active connections to this file when received SIGUSR1, instead of logs",
+                    ),
             );
     }
 
@@ -539,16 +699,47 @@ pub fn define_command_line_options(mut app: Command) -> Command {
             );
     }
 
-    #[cfg(unix)]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
     {
         app = app.arg(
-            Arg::new("USER")
-                .long("user")
-                .short('a')
+            Arg::new("CPU_AFFINITY")
+                .long("cpu-affinity")
                 .num_args(1)
                 .action(ArgAction::Set)
-                .value_hint(ValueHint::Username)
-                .help("Run as another user"),
+                .value_delimiter(',')
+                .value_parser(clap::value_parser!(usize))
+                .help("Pin threads of the `Runtime` to these CPU cores in turn, like \"2,3,4,5\""),
+        );
+    }
+
+    #[cfg(unix)]
+    {
+        app = app
+            .arg(
+                Arg::new("USER")
+                    .long("user")
+                    .short('a')
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_hint(ValueHint::Username)
+                    .help("Run as another user after listeners are bound"),
+            )
+            .arg(
+                Arg::new("GROUP")
+                    .long("group")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .help("Run as another group after listeners are bound, the primary group of --user by default"),
+            );
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "openbsd"))]
+    {
+        app = app.arg(
+            Arg::new("SANDBOX")
+                .long("sandbox")
+                .action(ArgAction::SetTrue)
+                .help("Restrict system calls by seccomp (Linux) or pledge (OpenBSD) after initialization"),
         );
     }
 
@@ -601,18 +792,18 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
         };
         service_config.set_options(matches);
 
+        // Logging starts threads (like the OTLP exporter) which don't survive forking, so the logger of a daemon is
+        // initialized after `daemonize`
+        #[cfg(all(feature = "logging", unix))]
+        let run_as_daemon = service_config.daemonize;
+        #[cfg(all(feature = "logging", not(unix)))]
+        let run_as_daemon = false;
+
         #[cfg(feature = "logging")]
-        match service_config.log.config_path {
-            Some(ref path) => {
-                logging::init_with_file(path);
-            }
-            None => {
-                logging::init_with_config("sslocal", &service_config.log);
-            }
+        if !run_as_daemon {
+            logging::init("sslocal", &service_config.log);
         }
 
-        trace!("{:?}", service_config);
-
         let mut config = match config_path_opt {
             Some(cpath) => Config::load_from_file(&cpath, ConfigType::Local)
                 .map_err(|err| ShadowsocksError::LoadConfigFailure(format!("loading config {cpath:?}, {err}")))?,
@@ -667,6 +858,7 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
                                 .expect("plugin-mode must be one of `tcp_only` (default), `udp_only` and `tcp_and_udp`")
                         })
                         .unwrap_or(Mode::TcpOnly),
+                    plugin_supervision: Default::default(),
                 };
 
                 sc.set_plugin(plugin);
@@ -680,9 +872,8 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             config.server.push(ServerInstanceConfig::with_server_config(svr_addr));
         }
 
-        #[cfg(feature = "local-flow-stat")]
         {
-            use shadowsocks_service::config::LocalFlowStatAddress;
+            use shadowsocks_service::config::{LocalFlowStatAddress, LocalFlowStatsdConfig};
             use std::net::SocketAddr;
 
             #[cfg(unix)]
@@ -693,6 +884,13 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             if let Some(stat_addr) = matches.get_one::<SocketAddr>("STAT_ADDR").cloned() {
                 config.local_stat_addr = Some(LocalFlowStatAddress::TcpStreamAddr(stat_addr));
             }
+
+            if let Some(statsd_addr) = matches.get_one::<SocketAddr>("STAT_STATSD_ADDR").cloned() {
+                match config.local_stat_statsd {
+                    Some(ref mut statsd) => statsd.addr = statsd_addr,
+                    None => config.local_stat_statsd = Some(LocalFlowStatsdConfig::new(statsd_addr)),
+                }
+            }
         }
 
         #[cfg(target_os = "android")]
@@ -758,6 +956,19 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
                         local_config.udp_redir = udp_redir.parse::<RedirType>().expect("udp-redir");
                     }
                 }
+
+                #[cfg(target_os = "linux")]
+                if let Some(redir_firewall) = matches.get_one::<String>("REDIR_FIREWALL") {
+                    use ipnet::IpNet;
+                    use shadowsocks_service::config::RedirFirewallType;
+
+                    local_config.redir_firewall =
+                        Some(redir_firewall.parse::<RedirFirewallType>().expect("redir-firewall"));
+
+                    if let Some(bypass) = matches.get_many::<IpNet>("REDIR_FIREWALL_BYPASS") {
+                        local_config.redir_firewall_bypass = Some(bypass.cloned().collect());
+                    }
+                }
             }
 
             #[cfg(feature = "local-dns")]
@@ -858,6 +1069,21 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             config.mptcp = true;
         }
 
+        if let Some(path) = matches.get_one::<PathBuf>("ACCESS_LOG").cloned() {
+            config.access_log = Some(AccessLogConfig { path: Some(path) });
+        }
+
+        if let Some(collector) = matches.get_one::<SocketAddr>("FLOW_EXPORT_COLLECTOR").cloned() {
+            let mut flow_export = FlowExportConfig::new(collector);
+            if let Some(version) = matches.get_one::<String>("FLOW_EXPORT_VERSION") {
+                flow_export.version = match version.as_str() {
+                    "9" => FlowExportVersion::NetFlowV9,
+                    _ => FlowExportVersion::Ipfix,
+                };
+            }
+            config.flow_export = Some(flow_export);
+        }
+
         #[cfg(any(target_os = "linux", target_os = "android"))]
         if let Some(mark) = matches.get_one::<u32>("OUTBOUND_FWMARK") {
             config.outbound_fwmark = Some(*mark);
@@ -872,6 +1098,14 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             config.outbound_bind_interface = Some(iface);
         }
 
+        if let Some(dscp) = matches.get_one::<u8>("OUTBOUND_DSCP") {
+            config.outbound_dscp = Some(*dscp);
+        }
+
+        if let Some(proxy) = matches.get_one::<OutboundProxy>("OUTBOUND_PROXY").cloned() {
+            config.outbound_proxy = Some(proxy);
+        }
+
         #[cfg(all(unix, not(target_os = "android")))]
         match matches.get_one::<u64>("NOFILE") {
             Some(nofile) => config.nofile = Some(*nofile),
@@ -888,6 +1122,20 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             config.acl = Some(acl);
         }
 
+        #[cfg(feature = "metrics")]
+        if let Some(metrics_addr) = matches.get_one::<std::net::SocketAddr>("METRICS_ADDR") {
+            config.metrics_addr = Some(*metrics_addr);
+        }
+
+        #[cfg(feature = "local-admin")]
+        if let Some(addr) = matches.get_one::<std::net::SocketAddr>("LOCAL_ADMIN_ADDR").cloned() {
+            use shadowsocks_service::config::LocalAdminConfig;
+
+            let mut admin = LocalAdminConfig::new(addr);
+            admin.token = matches.get_one::<String>("LOCAL_ADMIN_TOKEN").cloned();
+            config.local_admin = Some(admin);
+        }
+
         if let Some(dns) = matches.get_one::<String>("DNS") {
             config.set_dns_formatted(dns).expect("dns");
         }
@@ -896,6 +1144,10 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             config.dns_cache_size = Some(*dns_cache_size);
         }
 
+        if let Some(interval) = matches.get_one::<u64>("SERVER_RESOLVE_INTERVAL") {
+            config.server_resolve_interval = Some(Duration::from_secs(*interval));
+        }
+
         if matches.get_flag("IPV6_FIRST") {
             config.ipv6_first = true;
         }
@@ -908,6 +1160,10 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             config.udp_max_associations = Some(*udp_max_assoc);
         }
 
+        if let Some(profile) = matches.get_one::<MemoryProfile>("MEMORY_PROFILE") {
+            config.memory_profile = *profile;
+        }
+
         if let Some(bs) = matches.get_one::<u32>("INBOUND_SEND_BUFFER_SIZE") {
             config.inbound_send_buffer_size = Some(*bs);
         }
@@ -936,6 +1192,19 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             });
         }
 
+        #[cfg(unix)]
+        if let Some(user) = matches.get_one::<String>("USER") {
+            config.user = Some(user.clone());
+        }
+        #[cfg(unix)]
+        if let Some(group) = matches.get_one::<String>("GROUP") {
+            config.group = Some(group.clone());
+        }
+        #[cfg(any(target_os = "linux", target_os = "openbsd"))]
+        if matches.get_flag("SANDBOX") {
+            config.sandbox = true;
+        }
+
         // DONE READING options
 
         if config.local.is_empty() {
@@ -951,16 +1220,30 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             .map_err(|err| ShadowsocksError::LoadConfigFailure(format!("config integrity check failed, {err}")))?;
 
         #[cfg(unix)]
-        if matches.get_flag("DAEMONIZE") || matches.get_raw("DAEMONIZE_PID_PATH").is_some() {
+        {
             use crate::daemonize;
-            daemonize::daemonize(matches.get_one::<PathBuf>("DAEMONIZE_PID_PATH"));
+
+            let pid_file = service_config.pid_file.as_deref();
+            let result = if service_config.daemonize {
+                daemonize::daemonize(pid_file)
+            } else if let Some(pid_file) = pid_file {
+                daemonize::write_pid_file(pid_file)
+            } else {
+                Ok(())
+            };
+            result.map_err(|err| ShadowsocksError::ServerAborted(format!("failed to daemonize, {err}")))?;
         }
 
+        #[cfg(feature = "logging")]
+        if run_as_daemon {
+            logging::init("sslocal", &service_config.log);
+        }
+
+        trace!("{:?}", service_config);
+
         #[cfg(unix)]
-        if let Some(uname) = matches.get_one::<String>("USER") {
-            crate::sys::run_as_user(uname).map_err(|err| {
-                ShadowsocksError::InsufficientParams(format!("failed to change as user, error: {err}"))
-            })?;
+        if let Some(ref dump_path) = service_config.dump_path {
+            monitor::set_dump_path(dump_path.clone());
         }
 
         info!("shadowsocks local {} build {}", crate::VERSION, crate::BUILD_TIME);
@@ -978,6 +1261,11 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             }
         };
 
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(cpu_affinity) = service_config.runtime.cpu_affinity.clone() {
+            crate::sys::set_runtime_cpu_affinity(&mut builder, cpu_affinity);
+        }
+
         let runtime = builder.enable_all().build().expect("create tokio Runtime");
 
         (config, service_config, runtime)
@@ -1041,7 +1329,10 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
 /// Program entrance `main`
 #[inline]
 pub fn main(matches: &ArgMatches) -> ExitCode {
-    match create(matches).and_then(|(runtime, main_fut)| runtime.block_on(main_fut)) {
+    let result = create(matches).and_then(|(runtime, main_fut)| runtime.block_on(main_fut));
+    #[cfg(feature = "logging")]
+    logging::shutdown();
+    match result {
         Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
             eprintln!("{err}");
@@ -1109,7 +1400,13 @@ impl ServerReloader {
         debug!("server-loader task is now listening USR1");
 
         while sigusr1.recv().await.is_some() {
+            #[cfg(target_os = "linux")]
+            shadowsocks_service::sd_notify::reloading();
+
             let _ = self.run_once().await;
+
+            #[cfg(target_os = "linux")]
+            shadowsocks_service::sd_notify::ready();
         }
     }
 
@@ -1,6 +1,12 @@
 //! Server Manager launchers
 
-use std::{future::Future, net::IpAddr, path::PathBuf, process::ExitCode, time::Duration};
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    process::ExitCode,
+    time::Duration,
+};
 
 use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command, ValueHint, builder::PossibleValuesParser};
 use futures::future::{self, Either};
@@ -14,7 +20,10 @@ use tokio::{
 use shadowsocks_service::config::ManagerServerMode;
 use shadowsocks_service::{
     acl::AccessControl,
-    config::{Config, ConfigType, ManagerConfig, ManagerServerHost},
+    config::{
+        AccessLogConfig, Config, ConfigType, FlowExportConfig, FlowExportVersion, ManagerConfig, ManagerServerHost,
+        MemoryProfile,
+    },
     run_manager,
     shadowsocks::{
         config::{ManagerAddr, Mode},
@@ -23,13 +32,16 @@ use shadowsocks_service::{
     },
 };
 
-#[cfg(feature = "logging")]
-use crate::logging;
 use crate::{
     config::{Config as ServiceConfig, RuntimeMode},
     error::{ShadowsocksError, ShadowsocksResult},
     monitor, vparser,
 };
+#[cfg(feature = "logging")]
+use crate::{
+    config::{LogRotation, SyslogAddress, SyslogFacility},
+    logging,
+};
 
 /// Defines command line options
 pub fn define_command_line_options(mut app: Command) -> Command {
@@ -74,6 +86,14 @@ pub fn define_command_line_options(mut app: Command) -> Command {
                 .action(ArgAction::Set)
                 .help("Set SO_BINDTODEVICE / IP_BOUND_IF / IP_UNICAST_IF option for outbound socket"),
         )
+        .arg(
+            Arg::new("OUTBOUND_DSCP")
+                .long("outbound-dscp")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u8).range(0..=63))
+                .help("Set DSCP (0-63) of outbound sockets, in IP_TOS / IPV6_TCLASS option"),
+        )
         .arg(Arg::new("SERVER_HOST").short('s').long("server-host").num_args(1).action(ArgAction::Set).value_parser(vparser::parse_manager_server_host).help("Host name or IP address of your remote server"))
         .arg(
             Arg::new("MANAGER_ADDR")
@@ -117,8 +137,13 @@ pub fn define_command_line_options(mut app: Command) -> Command {
         .arg(Arg::new("TCP_FAST_OPEN").long("tcp-fast-open").alias("fast-open").action(ArgAction::SetTrue).help("Enable TCP Fast Open (TFO)"))
         .arg(Arg::new("TCP_KEEP_ALIVE").long("tcp-keep-alive").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u64)).help("Set TCP keep alive timeout seconds"))
         .arg(Arg::new("TCP_MULTIPATH").long("tcp-multipath").alias("mptcp").action(ArgAction::SetTrue).help("Enable Multipath-TCP (MPTCP)"))
+        .arg(Arg::new("PROXY_PROTOCOL").long("proxy-protocol").action(ArgAction::SetTrue).help("Expect PROXY protocol (v1 or v2) headers ahead of accepted TCP connections, for servers behind L4 load balancers"))
+        .arg(Arg::new("ACCESS_LOG").long("access-log").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(PathBuf)).value_hint(ValueHint::FilePath).help("Append one line for each completed TCP relay to this file"))
+        .arg(Arg::new("FLOW_EXPORT_COLLECTOR").long("flow-export-collector").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(SocketAddr)).help("Export completed TCP relays and UDP associations to this NetFlow v9 / IPFIX collector by UDP"))
+        .arg(Arg::new("FLOW_EXPORT_VERSION").long("flow-export-version").num_args(1).action(ArgAction::Set).value_parser(PossibleValuesParser::new(["9", "10"])).requires("FLOW_EXPORT_COLLECTOR").help("Export NetFlow v9 (9) or IPFIX (10) messages, IPFIX by default"))
         .arg(Arg::new("UDP_TIMEOUT").long("udp-timeout").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u64)).help("Timeout seconds for UDP relay"))
         .arg(Arg::new("UDP_MAX_ASSOCIATIONS").long("udp-max-associations").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(usize)).help("Maximum associations to be kept simultaneously for UDP relay"))
+        .arg(Arg::new("MEMORY_PROFILE").long("memory-profile").num_args(1).action(ArgAction::Set).value_parser(vparser::parse_memory_profile).help("Memory usage preset, default, low (for embedded devices like routers) or constrained (for processes with hard memory limits)"))
         .arg(Arg::new("INBOUND_SEND_BUFFER_SIZE").long("inbound-send-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set inbound sockets' SO_SNDBUF option"))
         .arg(Arg::new("INBOUND_RECV_BUFFER_SIZE").long("inbound-recv-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set inbound sockets' SO_RCVBUF option"))
         .arg(Arg::new("OUTBOUND_SEND_BUFFER_SIZE").long("outbound-send-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set outbound sockets' SO_SNDBUF option"))
@@ -130,6 +155,48 @@ pub fn define_command_line_options(mut app: Command) -> Command {
                 .help("Resolve hostname to IPv6 address first"),
         );
 
+    #[cfg(feature = "manager-admin")]
+    {
+        app = app
+            .arg(
+                Arg::new("MANAGER_ADMIN_ADDR")
+                    .long("manager-admin-addr")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(vparser::parse_socket_addr)
+                    .help("Listen address of manager's HTTP admin API"),
+            )
+            .arg(
+                Arg::new("MANAGER_ADMIN_TOKEN")
+                    .long("manager-admin-token")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .requires("MANAGER_ADMIN_ADDR")
+                    .help("Token for authenticating manager's HTTP admin API requests"),
+            );
+    }
+
+    #[cfg(feature = "manager-grpc")]
+    {
+        app = app
+            .arg(
+                Arg::new("MANAGER_GRPC_ADDR")
+                    .long("manager-grpc-addr")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(vparser::parse_socket_addr)
+                    .help("Listen address of manager's gRPC API"),
+            )
+            .arg(
+                Arg::new("MANAGER_GRPC_TOKEN")
+                    .long("manager-grpc-token")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .requires("MANAGER_GRPC_ADDR")
+                    .help("Token for authenticating manager's gRPC API requests"),
+            );
+    }
+
     #[cfg(feature = "logging")]
     {
         app = app
@@ -145,6 +212,72 @@ pub fn define_command_line_options(mut app: Command) -> Command {
                     .action(ArgAction::SetTrue)
                     .help("Log without datetime prefix"),
             )
+            .arg(
+                Arg::new("LOG_JSON")
+                    .long("log-json")
+                    .action(ArgAction::SetTrue)
+                    .help("Log in JSON, one object per line"),
+            )
+            .arg(
+                Arg::new("LOG_SYSLOG")
+                    .long("log-syslog")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(SyslogAddress))
+                    .help("Send logs to syslog, unix socket path like /dev/log, or udp://host:port"),
+            )
+            .arg(
+                Arg::new("LOG_SYSLOG_FACILITY")
+                    .long("log-syslog-facility")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(SyslogFacility))
+                    .requires("LOG_SYSLOG")
+                    .help("Facility of syslog, user, daemon (default), local0 ~ local7"),
+            )
+            .arg(
+                Arg::new("LOG_FILE")
+                    .long("log-file")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .value_hint(ValueHint::FilePath)
+                    .help("Write logs to this file instead of stdout"),
+            )
+            .arg(
+                Arg::new("LOG_FILE_ROTATION")
+                    .long("log-file-rotation")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(LogRotation))
+                    .requires("LOG_FILE")
+                    .help("Rotate the log file by time, never (default), hourly, daily"),
+            )
+            .arg(
+                Arg::new("LOG_FILE_MAX_SIZE")
+                    .long("log-file-max-size")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .requires("LOG_FILE")
+                    .help("Rotate the log file when it would exceed this size in bytes"),
+            )
+            .arg(
+                Arg::new("LOG_FILE_MAX_FILES")
+                    .long("log-file-max-files")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(usize))
+                    .requires("LOG_FILE")
+                    .help("Rotated log files to keep, 7 by default, 0 keeps all of them"),
+            )
+            .arg(
+                Arg::new("LOG_FILE_COMPRESS")
+                    .long("log-file-compress")
+                    .action(ArgAction::SetTrue)
+                    .requires("LOG_FILE")
+                    .help("Compress rotated log files with gzip"),
+            )
             .arg(
                 Arg::new("LOG_CONFIG")
                     .long("log-config")
@@ -156,6 +289,17 @@ pub fn define_command_line_options(mut app: Command) -> Command {
             );
     }
 
+    #[cfg(all(feature = "logging", windows))]
+    {
+        app = app.arg(
+            Arg::new("LOG_EVENT_LOG")
+                .long("log-event-log")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .help("Report logs to Windows Event Log with this source name instead of stdout"),
+        );
+    }
+
     #[cfg(unix)]
     {
         app = app
@@ -175,6 +319,17 @@ pub fn define_command_line_options(mut app: Command) -> Command {
                     .value_hint(ValueHint::FilePath)
                     .help("File path to store daemonized process's PID"),
             )
+            .arg(
+                Arg::new("DUMP_PATH")
+                    .long("dump-path")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(PathBuf))
+                    .value_hint(ValueHint::FilePath)
+                    .help(
+                        "Write statistics and active connections to this file when received SIGUSR1, instead of logs",
+                    ),
+            )
             .arg(
                 Arg::new("MANAGER_SERVER_MODE")
                     .long("manager-server-mode")
@@ -195,6 +350,18 @@ pub fn define_command_line_options(mut app: Command) -> Command {
             );
     }
 
+    #[cfg(feature = "metrics")]
+    {
+        app = app.arg(
+            Arg::new("METRICS_ADDR")
+                .long("metrics-addr")
+                .num_args(1)
+                .action(ArgAction::Set)
+                .value_parser(vparser::parse_socket_addr)
+                .help("Listen address of Prometheus metrics endpoint (GET /metrics)"),
+        );
+    }
+
     #[cfg(all(unix, not(target_os = "android")))]
     {
         app = app.arg(
@@ -251,16 +418,47 @@ pub fn define_command_line_options(mut app: Command) -> Command {
             );
     }
 
-    #[cfg(unix)]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
     {
         app = app.arg(
-            Arg::new("USER")
-                .long("user")
-                .short('a')
+            Arg::new("CPU_AFFINITY")
+                .long("cpu-affinity")
                 .num_args(1)
                 .action(ArgAction::Set)
-                .value_hint(ValueHint::Username)
-                .help("Run as another user"),
+                .value_delimiter(',')
+                .value_parser(clap::value_parser!(usize))
+                .help("Pin threads of the `Runtime` to these CPU cores in turn, like \"2,3,4,5\""),
+        );
+    }
+
+    #[cfg(unix)]
+    {
+        app = app
+            .arg(
+                Arg::new("USER")
+                    .long("user")
+                    .short('a')
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_hint(ValueHint::Username)
+                    .help("Run as another user after listeners are bound"),
+            )
+            .arg(
+                Arg::new("GROUP")
+                    .long("group")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .help("Run as another group after listeners are bound, the primary group of --user by default"),
+            );
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "openbsd"))]
+    {
+        app = app.arg(
+            Arg::new("SANDBOX")
+                .long("sandbox")
+                .action(ArgAction::SetTrue)
+                .help("Restrict system calls by seccomp (Linux) or pledge (OpenBSD) after initialization"),
         );
     }
 
@@ -291,18 +489,18 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
         };
         service_config.set_options(matches);
 
+        // Logging starts threads (like the OTLP exporter) which don't survive forking, so the logger of a daemon is
+        // initialized after `daemonize`
+        #[cfg(all(feature = "logging", unix))]
+        let run_as_daemon = service_config.daemonize;
+        #[cfg(all(feature = "logging", not(unix)))]
+        let run_as_daemon = false;
+
         #[cfg(feature = "logging")]
-        match service_config.log.config_path {
-            Some(ref path) => {
-                logging::init_with_file(path);
-            }
-            None => {
-                logging::init_with_config("sslocal", &service_config.log);
-            }
+        if !run_as_daemon {
+            logging::init("ssmanager", &service_config.log);
         }
 
-        trace!("{:?}", service_config);
-
         let mut config = match config_path_opt {
             Some(cpath) => Config::load_from_file(&cpath, ConfigType::Manager)
                 .map_err(|err| ShadowsocksError::LoadConfigFailure(format!("loading config {cpath:?}, {err}")))?,
@@ -325,6 +523,25 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             config.mptcp = true;
         }
 
+        if matches.get_flag("PROXY_PROTOCOL") {
+            config.proxy_protocol = true;
+        }
+
+        if let Some(path) = matches.get_one::<PathBuf>("ACCESS_LOG").cloned() {
+            config.access_log = Some(AccessLogConfig { path: Some(path) });
+        }
+
+        if let Some(collector) = matches.get_one::<SocketAddr>("FLOW_EXPORT_COLLECTOR").cloned() {
+            let mut flow_export = FlowExportConfig::new(collector);
+            if let Some(version) = matches.get_one::<String>("FLOW_EXPORT_VERSION") {
+                flow_export.version = match version.as_str() {
+                    "9" => FlowExportVersion::NetFlowV9,
+                    _ => FlowExportVersion::Ipfix,
+                };
+            }
+            config.flow_export = Some(flow_export);
+        }
+
         #[cfg(any(target_os = "linux", target_os = "android"))]
         if let Some(mark) = matches.get_one::<u32>("OUTBOUND_FWMARK") {
             config.outbound_fwmark = Some(*mark);
@@ -339,6 +556,10 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             config.outbound_bind_interface = Some(iface);
         }
 
+        if let Some(dscp) = matches.get_one::<u8>("OUTBOUND_DSCP") {
+            config.outbound_dscp = Some(*dscp);
+        }
+
         if let Some(addr) = matches.get_one::<ManagerAddr>("MANAGER_ADDR").cloned() {
             match config.manager {
                 Some(ref mut manager_config) => {
@@ -385,9 +606,28 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
                                 .expect("plugin-mode must be one of `tcp_only` (default), `udp_only` and `tcp_and_udp`")
                         })
                         .unwrap_or(Mode::TcpOnly),
+                    plugin_supervision: Default::default(),
                 });
             }
 
+            #[cfg(feature = "manager-admin")]
+            if let Some(addr) = matches.get_one::<std::net::SocketAddr>("MANAGER_ADMIN_ADDR").cloned() {
+                use shadowsocks_service::config::ManagerAdminConfig;
+
+                let mut admin = ManagerAdminConfig::new(addr);
+                admin.token = matches.get_one::<String>("MANAGER_ADMIN_TOKEN").cloned();
+                manager_config.admin = Some(admin);
+            }
+
+            #[cfg(feature = "manager-grpc")]
+            if let Some(addr) = matches.get_one::<std::net::SocketAddr>("MANAGER_GRPC_ADDR").cloned() {
+                use shadowsocks_service::config::ManagerGrpcConfig;
+
+                let mut grpc = ManagerGrpcConfig::new(addr);
+                grpc.token = matches.get_one::<String>("MANAGER_GRPC_TOKEN").cloned();
+                manager_config.grpc = Some(grpc);
+            }
+
             #[cfg(unix)]
             if let Some(server_mode) = matches.get_one::<ManagerServerMode>("MANAGER_SERVER_MODE").cloned() {
                 manager_config.server_mode = server_mode;
@@ -420,6 +660,11 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             config.acl = Some(acl);
         }
 
+        #[cfg(feature = "metrics")]
+        if let Some(metrics_addr) = matches.get_one::<std::net::SocketAddr>("METRICS_ADDR") {
+            config.metrics_addr = Some(*metrics_addr);
+        }
+
         if let Some(dns) = matches.get_one::<String>("DNS") {
             config.set_dns_formatted(dns).expect("dns");
         }
@@ -440,6 +685,10 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             config.udp_max_associations = Some(*udp_max_assoc);
         }
 
+        if let Some(profile) = matches.get_one::<MemoryProfile>("MEMORY_PROFILE") {
+            config.memory_profile = *profile;
+        }
+
         if let Some(bs) = matches.get_one::<u32>("INBOUND_SEND_BUFFER_SIZE") {
             config.inbound_send_buffer_size = Some(*bs);
         }
@@ -457,6 +706,19 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             config.outbound_bind_addr = Some(*bind_addr);
         }
 
+        #[cfg(unix)]
+        if let Some(user) = matches.get_one::<String>("USER") {
+            config.user = Some(user.clone());
+        }
+        #[cfg(unix)]
+        if let Some(group) = matches.get_one::<String>("GROUP") {
+            config.group = Some(group.clone());
+        }
+        #[cfg(any(target_os = "linux", target_os = "openbsd"))]
+        if matches.get_flag("SANDBOX") {
+            config.sandbox = true;
+        }
+
         // DONE reading options
 
         config.manager.as_ref().ok_or_else(|| {
@@ -472,16 +734,30 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             .map_err(|err| ShadowsocksError::LoadConfigFailure(format!("config integrity check failed, {err}")))?;
 
         #[cfg(unix)]
-        if matches.get_flag("DAEMONIZE") || matches.get_raw("DAEMONIZE_PID_PATH").is_some() {
+        {
             use crate::daemonize;
-            daemonize::daemonize(matches.get_one::<PathBuf>("DAEMONIZE_PID_PATH"));
+
+            let pid_file = service_config.pid_file.as_deref();
+            let result = if service_config.daemonize {
+                daemonize::daemonize(pid_file)
+            } else if let Some(pid_file) = pid_file {
+                daemonize::write_pid_file(pid_file)
+            } else {
+                Ok(())
+            };
+            result.map_err(|err| ShadowsocksError::ServerAborted(format!("failed to daemonize, {err}")))?;
         }
 
+        #[cfg(feature = "logging")]
+        if run_as_daemon {
+            logging::init("ssmanager", &service_config.log);
+        }
+
+        trace!("{:?}", service_config);
+
         #[cfg(unix)]
-        if let Some(uname) = matches.get_one::<String>("USER") {
-            crate::sys::run_as_user(uname).map_err(|err| {
-                ShadowsocksError::InsufficientParams(format!("failed to change as user, error: {err}"))
-            })?;
+        if let Some(ref dump_path) = service_config.dump_path {
+            monitor::set_dump_path(dump_path.clone());
         }
 
         info!("shadowsocks manager {} build {}", crate::VERSION, crate::BUILD_TIME);
@@ -499,6 +775,11 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
             }
         };
 
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(cpu_affinity) = service_config.runtime.cpu_affinity.clone() {
+            crate::sys::set_runtime_cpu_affinity(&mut builder, cpu_affinity);
+        }
+
         let runtime = builder.enable_all().build().expect("create tokio Runtime");
 
         (config, runtime)
@@ -529,7 +810,10 @@ pub fn create(matches: &ArgMatches) -> ShadowsocksResult<(Runtime, impl Future<O
 /// Program entrance `main`
 #[inline]
 pub fn main(matches: &ArgMatches) -> ExitCode {
-    match create(matches).and_then(|(runtime, main_fut)| runtime.block_on(main_fut)) {
+    let result = create(matches).and_then(|(runtime, main_fut)| runtime.block_on(main_fut));
+    #[cfg(feature = "logging")]
+    logging::shutdown();
+    match result {
         Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
             eprintln!("{err}");
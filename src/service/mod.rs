@@ -1,5 +1,9 @@
 //! Service launchers
 
+#[cfg(feature = "local")]
+pub mod bench;
+#[cfg(feature = "local")]
+pub mod check;
 pub mod genkey;
 #[cfg(feature = "local")]
 pub mod local;
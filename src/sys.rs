@@ -75,75 +75,53 @@ pub fn adjust_nofile() {
     }
 }
 
-/// setuid(), setgid() for a specific user or uid
-#[cfg(unix)]
-pub fn run_as_user(uname: &str) -> std::io::Result<()> {
-    use log::error;
+/// Pin the current thread to CPU `core`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_thread_affinity(core: usize) -> std::io::Result<()> {
     use std::{
-        ffi::{CStr, CString},
         io::{Error, ErrorKind},
+        mem,
     };
 
     unsafe {
-        let pwd = match uname.parse::<libc::uid_t>() {
-            Ok(uid) => {
-                let mut pwd = libc::getpwuid(uid);
-                if pwd.is_null() {
-                    let uname = CString::new(uname).expect("username");
-                    pwd = libc::getpwnam(uname.as_ptr())
-                }
-                pwd
-            }
-            Err(..) => {
-                let uname = CString::new(uname).expect("username");
-                libc::getpwnam(uname.as_ptr())
-            }
-        };
-
-        if pwd.is_null() {
-            return Err(Error::new(ErrorKind::InvalidInput, format!("user {} not found", uname)));
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        if core >= mem::size_of_val(&set) * 8 {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("invalid CPU core {core}")));
         }
+        libc::CPU_SET(core, &mut set);
 
-        let pwd = &*pwd;
-
-        // setgid first, because we may not allowed to do it anymore after setuid
-        if libc::setgid(pwd.pw_gid as libc::gid_t) != 0 {
-            let err = Error::last_os_error();
-
-            error!(
-                "could not change group id to user {:?}'s gid: {}, uid: {}, error: {}",
-                CStr::from_ptr(pwd.pw_name),
-                pwd.pw_gid,
-                pwd.pw_uid,
-                err
-            );
-            return Err(err);
+        let ret = libc::sched_setaffinity(0, mem::size_of_val(&set), &set);
+        if ret < 0 {
+            return Err(Error::last_os_error());
         }
+    }
 
-        if libc::initgroups(pwd.pw_name, pwd.pw_gid as _) != 0 {
-            let err = Error::last_os_error();
-            error!(
-                "could not change supplementary groups to user {:?}'s gid: {}, uid: {}, error: {}",
-                CStr::from_ptr(pwd.pw_name),
-                pwd.pw_gid,
-                pwd.pw_uid,
-                err
-            );
-            return Err(err);
-        }
+    Ok(())
+}
 
-        if libc::setuid(pwd.pw_uid) != 0 {
-            let err = Error::last_os_error();
-            error!(
-                "could not change user id to user {:?}'s gid: {}, uid: {}, error: {}",
-                CStr::from_ptr(pwd.pw_name),
-                pwd.pw_gid,
-                pwd.pw_uid,
-                err
-            );
-            return Err(err);
-        }
+/// Pin threads of the runtime built by `builder`, and the current thread, to CPU `cores` in turn
+///
+/// Worker threads are started first, so with as many cores as worker threads, each of them runs on its own core.
+/// Threads of blocking tasks continue the rotation.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_runtime_cpu_affinity(builder: &mut tokio::runtime::Builder, cores: Vec<usize>) {
+    use log::warn;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    if cores.is_empty() {
+        return;
     }
 
-    Ok(())
+    // The current thread drives the main future, and all tasks of the single-thread runtime
+    if let Err(err) = set_thread_affinity(cores[0]) {
+        warn!("failed to pin main thread to CPU {}, error: {}", cores[0], err);
+    }
+
+    let next = AtomicUsize::new(0);
+    builder.on_thread_start(move || {
+        let core = cores[next.fetch_add(1, Ordering::Relaxed) % cores.len()];
+        if let Err(err) = set_thread_affinity(core) {
+            warn!("failed to pin runtime thread to CPU {}, error: {}", core, err);
+        }
+    });
 }
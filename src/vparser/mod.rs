@@ -4,15 +4,23 @@
 
 use std::net::{IpAddr, SocketAddr};
 
-#[cfg(any(feature = "local-tun", feature = "local-fake-dns"))]
+#[cfg(any(
+    feature = "local-tun",
+    feature = "local-fake-dns",
+    all(feature = "local-redir", target_os = "linux")
+))]
 use ipnet::IpNet;
+#[cfg(feature = "local")]
+use shadowsocks_service::config::BalancerCheckUrl;
 #[cfg(feature = "local-redir")]
 use shadowsocks_service::config::RedirType;
 #[cfg(feature = "local-dns")]
 use shadowsocks_service::local::dns::NameServerAddr;
 use shadowsocks_service::{
-    config::{ManagerServerHost, ManagerServerMode},
-    shadowsocks::{ManagerAddr, ServerAddr, ServerConfig, crypto::CipherKind, relay::socks5::Address},
+    config::{ManagerServerHost, ManagerServerMode, MemoryProfile},
+    shadowsocks::{
+        ManagerAddr, ServerAddr, ServerConfig, crypto::CipherKind, net::OutboundProxy, relay::socks5::Address,
+    },
 };
 
 macro_rules! value_parser_type {
@@ -48,15 +56,37 @@ value_parser_type!(
     "should be either ip:port or a path to unix domain socket"
 );
 value_parser_type!(parse_cipher_kind, CipherKind, "invalid cipher");
+#[cfg(feature = "local")]
+value_parser_type!(parse_check_url, BalancerCheckUrl, "should be an http:// URL");
+value_parser_type!(
+    parse_memory_profile,
+    MemoryProfile,
+    "should be \"default\", \"low\" or \"constrained\""
+);
+
+pub fn parse_outbound_proxy(v: &str) -> Result<OutboundProxy, String> {
+    match v.parse::<OutboundProxy>() {
+        Ok(t) => Ok(t),
+        Err(err) => Err(format!(
+            "should be socks5://[username:password@]host[:port] or http://[username:password@]host[:port], {err}"
+        )),
+    }
+}
 
 pub fn parse_server_url(v: &str) -> Result<ServerConfig, String> {
     match ServerConfig::from_url(v) {
         Ok(t) => Ok(t),
-        Err(..) => Err("should be SIP002 (https://shadowsocks.org/doc/sip002.html) format".to_owned()),
+        Err(err) => Err(format!(
+            "should be SIP002 (https://shadowsocks.org/doc/sip002.html) format, {err}"
+        )),
     }
 }
 
-#[cfg(any(feature = "local-tun", feature = "local-fake-dns"))]
+#[cfg(any(
+    feature = "local-tun",
+    feature = "local-fake-dns",
+    all(feature = "local-redir", target_os = "linux")
+))]
 pub fn parse_ipnet(v: &str) -> Result<IpNet, String> {
     match v.parse::<IpNet>() {
         Err(..) => Err("should be a CIDR address like 10.1.2.3/24".to_owned()),
@@ -66,3 +96,10 @@ pub fn parse_ipnet(v: &str) -> Result<IpNet, String> {
 
 #[cfg(feature = "local-redir")]
 value_parser_type!(parse_redir_type, RedirType, "invalid redir-type");
+
+#[cfg(feature = "stats-report")]
+value_parser_type!(
+    parse_stats_report_target,
+    shadowsocks_service::config::StatsReportTarget,
+    "should be either http://host:port/path or unix:///path/to/unix.sock"
+);
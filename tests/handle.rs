@@ -0,0 +1,73 @@
+#![cfg(all(feature = "local", feature = "server"))]
+
+use std::net::SocketAddr;
+
+use tokio::net::TcpStream;
+
+use shadowsocks_service::{
+    config::{Config, ConfigType, LocalConfig, LocalInstanceConfig, ProtocolType, ServerInstanceConfig},
+    shadowsocks::{ServerConfig, crypto::CipherKind},
+    start_local, start_server,
+};
+
+const SERVER_ADDR: &str = "127.0.0.1:8120";
+const RELOADED_SERVER_ADDR: &str = "127.0.0.1:8121";
+const LOCAL_ADDR: &str = "127.0.0.1:8220";
+
+const PASSWORD: &str = "test-password";
+const METHOD: CipherKind = CipherKind::AES_128_GCM;
+
+fn get_svr_config(addr: &str) -> Config {
+    let mut cfg = Config::new(ConfigType::Server);
+    cfg.server = vec![ServerInstanceConfig::with_server_config(
+        ServerConfig::new(addr.parse::<SocketAddr>().unwrap(), PASSWORD.to_owned(), METHOD).unwrap(),
+    )];
+    cfg
+}
+
+fn get_cli_config() -> Config {
+    let mut cfg = Config::new(ConfigType::Local);
+    cfg.local = vec![LocalInstanceConfig::with_local_config(LocalConfig::new_with_addr(
+        LOCAL_ADDR.parse().unwrap(),
+        ProtocolType::Socks,
+    ))];
+    cfg.server = vec![ServerInstanceConfig::with_server_config(
+        ServerConfig::new(SERVER_ADDR.parse::<SocketAddr>().unwrap(), PASSWORD.to_owned(), METHOD).unwrap(),
+    )];
+    cfg
+}
+
+async fn is_listening(addr: &str) -> bool {
+    TcpStream::connect(addr).await.is_ok()
+}
+
+#[tokio::test]
+async fn handle_start_shutdown_reload() {
+    let _ = env_logger::try_init();
+
+    let mut server = start_server(get_svr_config(SERVER_ADDR)).await.unwrap();
+    assert!(!server.is_finished());
+    assert!(is_listening(SERVER_ADDR).await);
+
+    let mut local = start_local(get_cli_config()).await.unwrap();
+    assert!(is_listening(LOCAL_ADDR).await);
+
+    local.shutdown().await.unwrap();
+    assert!(local.is_finished());
+    assert!(!is_listening(LOCAL_ADDR).await);
+
+    // Shutdown twice is a no-op
+    local.shutdown().await.unwrap();
+
+    server.reload(get_svr_config(RELOADED_SERVER_ADDR)).await.unwrap();
+    assert!(!server.is_finished());
+    assert!(!server.cancellation_token().is_cancelled());
+    assert!(!is_listening(SERVER_ADDR).await);
+    assert!(is_listening(RELOADED_SERVER_ADDR).await);
+
+    // Cancelled by the application
+    server.cancellation_token().cancel();
+    server.wait().await.unwrap();
+    assert!(server.is_finished());
+    assert!(!is_listening(RELOADED_SERVER_ADDR).await);
+}